@@ -1,73 +1,297 @@
 use crate::config::AppConfig;
-use crate::error::DnsProxyResult;
-use crate::metrics::Metrics;
+use crate::dns::cache::ResponseCache;
+use crate::error::{DnsProxyError, DnsProxyResult};
+use crate::metrics::{Metrics, MetricsSnapshot};
+use crate::readers::{self, Reader};
 use crate::rewrite::{SniRewriterType, create_rewriter};
-use crate::server::{ServerResources, ServerStarter};
+use crate::server::{ReadySignal, ServerReady, ServerResources, ServerStarter, check_bind};
+use crate::tls_utils;
+use crate::upstream::default_upstream::DnsUpstream;
+use crate::upstream::health::UpstreamHealth;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::task::JoinHandle;
-use tracing::info;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+/// Time in-flight requests get to finish on shutdown before being aborted
+pub const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// DNS Proxy application that manages all protocol servers
 pub struct App {
     config: Arc<AppConfig>,
     pub rewriter: SniRewriterType,
     pub metrics: Arc<Metrics>,
+    /// Health of the configured upstreams, shared by all servers
+    pub upstream_health: Arc<UpstreamHealth>,
+    /// Answers cached for the UDP, TCP, DoQ, DoH and DoH3 listeners, shared by
+    /// all of them
+    response_cache: Arc<ResponseCache>,
+    /// Replaces the configured default upstream of the plain UDP/TCP listeners
+    pub(crate) upstream: Option<Arc<dyn DnsUpstream>>,
+    /// DNS servers to start, handed over to their tasks by `start`
+    readers: Vec<Box<dyn Reader>>,
     handles: Vec<JoinHandle<()>>,
+    /// Tells every server to stop accepting and drain its connections
+    shutdown: CancellationToken,
 }
 
 impl App {
     /// Create a new App instance with the given configuration
     pub fn new(config: AppConfig) -> Self {
-        let config = Arc::new(config);
         let rewriter = create_rewriter(config.rewrite.clone());
-        let metrics = Arc::new(Metrics::new());
+        Self::with_rewriter(config, rewriter)
+    }
+
+    /// Create an App that records its metrics in `metrics` instead of a
+    /// collector of its own
+    ///
+    /// The host application keeps its handle, e.g. to export the proxy's
+    /// metrics through its own endpoint or share one collector between apps.
+    pub fn new_with_metrics(config: AppConfig, metrics: Arc<Metrics>) -> Self {
+        let rewriter = create_rewriter(config.rewrite.clone());
+        Self::build(config, rewriter, metrics)
+    }
+
+    /// Create an App that routes hostnames with `rewriter` instead of the
+    /// one built from the `[rewrite]` config section
+    pub fn with_rewriter(config: AppConfig, rewriter: SniRewriterType) -> Self {
+        Self::build(config, rewriter, Arc::new(Metrics::new()))
+    }
+
+    fn build(config: AppConfig, rewriter: SniRewriterType, metrics: Arc<Metrics>) -> Self {
+        let config = Arc::new(config);
+        let upstream_health = Arc::new(UpstreamHealth::from_config(&config));
+        let response_cache = Arc::new(ResponseCache::from_config(&config.cache));
         Self {
             config,
             rewriter,
             metrics,
+            upstream_health,
+            response_cache,
+            upstream: None,
+            readers: readers::all(),
             handles: Vec::new(),
+            shutdown: CancellationToken::new(),
         }
     }
 
-    /// Start all enabled servers and return handles for graceful shutdown
-    pub fn start(&mut self) -> DnsProxyResult<()> {
+    /// Metrics collector shared by all servers
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Handle to the metrics collector that stays valid after the App is
+    /// consumed by [`run`](Self::run)
+    pub fn metrics_handle(&self) -> Arc<Metrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Current metrics snapshot, for host applications embedding the proxy
+    pub async fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.metrics().snapshot().await
+    }
+
+    /// Start all enabled servers, returning once every one of them is
+    /// listening
+    ///
+    /// When a server fails to start, the ones already started are stopped and
+    /// its error is returned.
+    pub async fn start(&mut self) -> DnsProxyResult<()> {
         info!("Starting DNS Proxy Server...");
+        if self.config.proxy.dry_run {
+            warn!("Dry-run mode: queries are answered without contacting any upstream");
+        }
 
-        self.start_healthcheck_server();
-        self.start_dot_server();
-        self.start_doh_server();
-        self.start_doq_server();
-        self.start_doh3_server();
+        self.check_listen_addrs()?;
+        self.check_certificates().await?;
+        self.start_upstream_prober();
+        let mut started = vec![self.start_healthcheck_server()];
+        for reader in std::mem::take(&mut self.readers) {
+            started.push(self.start_reader(reader));
+        }
+        for ready in started.into_iter().flatten() {
+            if let Err(e) = ready.wait().await {
+                for handle in self.handles.drain(..) {
+                    handle.abort();
+                }
+                return Err(e);
+            }
+        }
 
-        info!("All enabled servers started ({} tasks)", self.handles.len());
+        info!(
+            "All enabled servers listening ({} tasks)",
+            self.handles.len()
+        );
         Ok(())
     }
 
+    /// Run the proxy until Ctrl+C (or SIGTERM on Unix), then shut down
+    /// gracefully
+    ///
+    /// This is [`start`](Self::start), [`shutdown_signal`] and
+    /// [`shutdown`](Self::shutdown) in one call, for host applications that
+    /// don't need finer control.
+    pub async fn run(self) -> DnsProxyResult<()> {
+        self.run_until(shutdown_signal()).await
+    }
+
+    /// Run the proxy until `signal` completes, then shut down gracefully
+    ///
+    /// When `signal` fails (e.g. a signal handler could not be installed), the
+    /// servers are still shut down before its error is returned.
+    pub async fn run_until<F>(mut self, signal: F) -> DnsProxyResult<()>
+    where
+        F: Future<Output = std::io::Result<()>>,
+    {
+        self.start().await?;
+        info!("DNS Proxy Server started successfully. Press Ctrl+C to shutdown.");
+
+        let result = signal.await;
+        match &result {
+            Ok(()) => info!("Shutdown signal received, shutting down gracefully..."),
+            Err(e) => error!("Failed to listen for shutdown signal, shutting down: {}", e),
+        }
+        self.shutdown().await;
+
+        let snapshot = self.metrics_snapshot().await;
+        info!(
+            "Served {} requests ({} failed, {} upstream errors)",
+            snapshot.total_requests, snapshot.failed_requests, snapshot.upstream_errors
+        );
+        Ok(result?)
+    }
+
+    /// Gracefully shut down all servers
+    ///
+    /// Listeners stop accepting immediately; in-flight requests get up to
+    /// [`SHUTDOWN_DRAIN_TIMEOUT`] to finish before their tasks are aborted.
+    pub async fn shutdown(&mut self) {
+        self.shutdown_with_timeout(SHUTDOWN_DRAIN_TIMEOUT).await;
+    }
+
     /// Wait for all server tasks to complete (for graceful shutdown)
+    #[deprecated(note = "use `shutdown`, which drains in-flight requests first")]
     pub async fn wait_for_shutdown(&mut self) {
-        info!("Waiting for all servers to shutdown...");
-        for handle in self.handles.drain(..) {
-            handle.abort();
+        self.shutdown().await;
+    }
+
+    /// Gracefully shut down all servers, aborting whatever is still running
+    /// after `drain_timeout`
+    pub async fn shutdown_with_timeout(&mut self, drain_timeout: Duration) {
+        info!(
+            "Shutting down, draining in-flight requests (up to {:?})...",
+            drain_timeout
+        );
+        self.shutdown.cancel();
+
+        let deadline = tokio::time::Instant::now() + drain_timeout;
+        let mut aborted = 0;
+        for mut handle in self.handles.drain(..) {
+            if tokio::time::timeout_at(deadline, &mut handle)
+                .await
+                .is_err()
+            {
+                handle.abort();
+                // Wait for the task to be dropped, which closes its
+                // connections and logs what was cut short
+                let _ = handle.await;
+                aborted += 1;
+            }
+        }
+        if aborted > 0 {
+            warn!(
+                "Aborted {} server(s) that did not drain within {:?}",
+                aborted, drain_timeout
+            );
         }
         info!("All servers shutdown complete");
     }
 
-    fn start_healthcheck_server(&mut self) {
+    fn start_upstream_prober(&mut self) {
+        let prober = self
+            .upstream_health
+            .spawn_prober(Arc::clone(&self.config), self.shutdown.clone());
+        if let Some(handle) = prober {
+            self.handles.push(handle);
+            info!(
+                "Upstream health prober started (every {}s)",
+                self.config.upstream.health_check_interval_secs
+            );
+        }
+    }
+
+    /// Fail before any server starts when one of their listen addresses is
+    /// taken or cannot be bound
+    fn check_listen_addrs(&self) -> DnsProxyResult<()> {
+        let servers = &self.config.servers;
+        if servers.healthcheck.enabled {
+            let addrs = servers
+                .healthcheck
+                .socket_addrs()
+                .map_err(|e| DnsProxyError::Config(e.to_string()))?;
+            check_bind("Healthcheck", &addrs, false)?;
+        }
+        for reader in &self.readers {
+            let protocol = reader.protocol();
+            let server = protocol.listen_config(&self.config);
+            if !server.enabled || server.uds_path.is_some() {
+                continue;
+            }
+            let addrs = server
+                .socket_addrs()
+                .map_err(|e| DnsProxyError::Config(e.to_string()))?;
+            check_bind(protocol.name(), &addrs, protocol.is_udp())?;
+        }
+        Ok(())
+    }
+
+    /// Fail before any server starts when a certificate, key or client CA
+    /// file of the TLS servers is missing or doesn't parse
+    async fn check_certificates(&self) -> DnsProxyResult<()> {
+        if !self.config.servers.terminates_tls() {
+            return Ok(());
+        }
+        tls_utils::check_certificates(&self.config.tls).await
+    }
+
+    /// Resources handed to every server, sharing this app's state
+    fn server_resources(&self) -> ServerResources {
+        let resources = ServerResources::new(
+            Arc::clone(&self.config),
+            Arc::clone(&self.rewriter),
+            Arc::clone(&self.metrics),
+            Arc::clone(&self.upstream_health),
+            Arc::clone(&self.response_cache),
+            self.shutdown.clone(),
+        );
+        match &self.upstream {
+            Some(upstream) => resources.with_upstream(Arc::clone(upstream)),
+            None => resources,
+        }
+    }
+
+    fn start_healthcheck_server(&mut self) -> Option<ServerReady> {
         use crate::readers::HealthcheckServer;
         if !self.config.servers.healthcheck.enabled {
-            return;
+            return None;
         }
 
-        let config = Arc::clone(&self.config);
-        let metrics = Arc::clone(&self.metrics);
         let bind_addr = format!(
             "{}:{}",
-            self.config.servers.healthcheck.bind_address, self.config.servers.healthcheck.port
+            self.config.servers.healthcheck.bind_address.join(", "),
+            self.config.servers.healthcheck.port
         );
         let path = self.config.servers.healthcheck.path.clone();
+        let (signal, ready) = ReadySignal::channel("Healthcheck");
+        let mut resources = self.server_resources();
+        resources.ready = signal.clone();
         let handle = tokio::spawn(async move {
-            let server = HealthcheckServer::new(config, metrics);
-            if let Err(e) = server.start().await {
+            let server = HealthcheckServer::from_resources(resources);
+            if let Err(e) = server.start().await
+                && let Some(e) = signal.failed(e)
+            {
                 tracing::error!("Healthcheck server error: {}", e);
             }
         });
@@ -76,89 +300,29 @@ impl App {
             "Healthcheck server started on {} at path {}",
             bind_addr, path
         );
+        Some(ready)
     }
 
-    fn start_dot_server(&mut self) {
-        use crate::readers::DoTServer;
-        let resources = ServerResources::new(
-            Arc::clone(&self.config),
-            Arc::clone(&self.rewriter),
-            Arc::clone(&self.metrics),
-        );
-        if let Some(handle) = ServerStarter::start_server(
-            "DoT",
-            &self.config.servers.dot,
-            resources,
-            |resources| async move {
-                let server =
-                    DoTServer::new(resources.config, resources.rewriter, resources.metrics);
-                server.start().await
-            },
-        ) {
-            self.handles.push(handle);
-        }
-    }
-
-    fn start_doh_server(&mut self) {
-        use crate::readers::DoHServer;
-        let resources = ServerResources::new(
-            Arc::clone(&self.config),
-            Arc::clone(&self.rewriter),
-            Arc::clone(&self.metrics),
-        );
-        if let Some(handle) = ServerStarter::start_server(
-            "DoH",
-            &self.config.servers.doh,
-            resources,
-            |resources| async move {
-                let server =
-                    DoHServer::new(resources.config, resources.rewriter, resources.metrics);
-                server.start().await
-            },
-        ) {
-            self.handles.push(handle);
-        }
+    /// Start the server of `reader` when it is enabled
+    fn start_reader(&mut self, reader: Box<dyn Reader>) -> Option<ServerReady> {
+        let (handle, ready) = ServerStarter::start(reader, self.server_resources())?;
+        self.handles.push(handle);
+        Some(ready)
     }
+}
 
-    fn start_doq_server(&mut self) {
-        use crate::readers::DoQServer;
-        let resources = ServerResources::new(
-            Arc::clone(&self.config),
-            Arc::clone(&self.rewriter),
-            Arc::clone(&self.metrics),
-        );
-        if let Some(handle) = ServerStarter::start_server(
-            "DoQ",
-            &self.config.servers.doq,
-            resources,
-            |resources| async move {
-                let server =
-                    DoQServer::new(resources.config, resources.rewriter, resources.metrics);
-                server.start().await
-            },
-        ) {
-            self.handles.push(handle);
-        }
-    }
+/// Wait for Ctrl+C, or SIGTERM on Unix
+pub async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
 
-    fn start_doh3_server(&mut self) {
-        use crate::readers::DoH3Server;
-        let resources = ServerResources::new(
-            Arc::clone(&self.config),
-            Arc::clone(&self.rewriter),
-            Arc::clone(&self.metrics),
-        );
-        if let Some(handle) = ServerStarter::start_server(
-            "DoH3",
-            &self.config.servers.doh3,
-            resources,
-            |resources| async move {
-                let server =
-                    DoH3Server::new(resources.config, resources.rewriter, resources.metrics);
-                server.start().await
-            },
-        ) {
-            self.handles.push(handle);
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
         }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
 }
@@ -1,10 +1,13 @@
+use crate::dns;
+use crate::rewriters::{RegexSniRewriter, StaticMapRewriter};
 use anyhow::{Context, Result};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::fs;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
+use std::time::Duration;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct AppConfig {
     pub rewrite: RewriteConfig,
     pub servers: ServersConfig,
@@ -13,73 +16,676 @@ pub struct AppConfig {
     pub tls: TlsConfig,
     #[serde(default)]
     pub logging: LoggingConfig,
+    #[serde(default)]
+    pub edns: EdnsConfig,
+    #[serde(default)]
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub filter: FilterConfig,
+    #[serde(default)]
+    pub ratelimit: RateLimitConfig,
+    #[serde(default)]
+    pub quic: QuicConfig,
+    #[serde(default)]
+    pub proxy: ProxyConfig,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RewriteConfig {
     /// Base domains to match (e.g., ["example.com", "example.org"])
     /// The rewriter will extract prefix from hostnames matching these base domains
+    #[serde(default)]
     pub base_domains: Vec<String>,
     /// Target suffix for upstream (e.g., ".example.cn")
     /// The extracted prefix will be combined with this suffix to form the target hostname
+    #[serde(default)]
     pub target_suffix: String,
+    /// Base domains rewritten to their own target suffix, e.g. example.org
+    /// to ".example.net"; tried before `base_domains`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<BaseDomainTarget>,
     /// Strategy for handling SNI rewrite failures
     /// - "error": Return error when rewrite fails (default)
     /// - "passthrough": Use original hostname when rewrite fails
+    /// - "passthrough_warn": Like "passthrough", but also log a warning and
+    ///   count each passed-through SNI
     #[serde(default = "default_rewrite_failure_strategy")]
     pub rewrite_failure_strategy: String,
+    /// Rewriter implementation
+    /// - "base": Swap the base domain for `target_suffix` (default)
+    /// - "regex": Apply `rules` in order
+    /// - "static_map": Look hostnames up in `mapping_file`
+    #[serde(default = "default_rewriter_type")]
+    pub rewriter_type: String,
+    /// Rules of the "regex" rewriter; the first matching pattern wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<RewriteRule>,
+    /// CSV or TOML `source_sni -> target_hostname` table of the "static_map"
+    /// rewriter, reloaded when the file changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mapping_file: Option<String>,
 }
 
 fn default_rewrite_failure_strategy() -> String {
     "error".to_string()
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+fn default_rewriter_type() -> String {
+    "base".to_string()
+}
+
+/// A base domain whose hostnames are rewritten to `target_suffix`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BaseDomainTarget {
+    pub base_domain: String,
+    pub target_suffix: String,
+}
+
+impl RewriteConfig {
+    /// Base domains of the base rewriter with the target suffix each one is
+    /// rewritten to: every `targets` entry, then every `base_domains` entry
+    /// with the shared `target_suffix`
+    pub fn base_domain_targets(&self) -> impl Iterator<Item = (&str, &str)> {
+        let targets = self
+            .targets
+            .iter()
+            .map(|target| (target.base_domain.as_str(), target.target_suffix.as_str()));
+        let shared = self
+            .base_domains
+            .iter()
+            .map(|base_domain| (base_domain.as_str(), self.target_suffix.as_str()));
+        targets.chain(shared)
+    }
+}
+
+/// A regex rewrite rule: a hostname matching `pattern` is rewritten to
+/// `replacement`, in which `$1`, `${name}` etc. expand to its capture groups
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RewriteRule {
+    pub pattern: String,
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ServersConfig {
     pub dot: ServerPortConfig,
     pub doh: ServerPortConfig,
     pub doq: ServerPortConfig,
     pub doh3: ServerPortConfig,
+    /// Plain DNS over UDP (disabled unless configured)
+    #[serde(default = "default_udp_server")]
+    pub udp: ServerPortConfig,
+    /// Plain DNS over TCP (disabled unless configured)
+    #[serde(default = "default_tcp_dns_server")]
+    pub tcp_dns: ServerPortConfig,
     #[serde(default = "HealthcheckConfig::default")]
     pub healthcheck: HealthcheckConfig,
+    /// Largest DNS message accepted from a DoT, DoQ, DoH, DoH3, UDP or TCP client, in bytes
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    /// Path the DoH and DoH3 servers answer RFC 8484 queries on
+    #[serde(default = "default_doh_path")]
+    pub doh_path: String,
+    /// Path requested from the upstream a DoH or DoH3 query's Host rewrites
+    /// to, in place of the client's (default: the path of `upstream.doh` or
+    /// `upstream.doh3` when it has one, else the client's)
+    #[serde(default)]
+    pub doh_upstream_path: Option<String>,
+    /// Time a DoH3 client has to send a POST body, in milliseconds; a
+    /// stalled request is cancelled with `H3_REQUEST_CANCELLED`
+    #[serde(default = "default_request_body_timeout_ms")]
+    pub request_body_timeout_ms: u64,
+    /// Most headers a DoH or DoH3 request may carry; more are answered 431
+    #[serde(default = "default_max_header_count")]
+    pub max_header_count: usize,
+    /// Largest total size of a DoH or DoH3 request's header names and
+    /// values, in bytes; larger headers are answered 431
+    #[serde(default = "default_max_header_bytes")]
+    pub max_header_bytes: usize,
+    /// First pause of a server's accept loop after a failed accept (e.g. out
+    /// of file descriptors), in milliseconds; it doubles with each failure in
+    /// a row
+    #[serde(default = "default_accept_backoff_base_ms")]
+    pub accept_backoff_base_ms: u64,
+    /// Longest pause of a server's accept loop after failed accepts, in
+    /// milliseconds
+    #[serde(default = "default_accept_backoff_max_ms")]
+    pub accept_backoff_max_ms: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+impl ServersConfig {
+    /// Time a DoH3 client has to send a POST body
+    pub fn request_body_timeout(&self) -> Duration {
+        Duration::from_millis(self.request_body_timeout_ms)
+    }
+
+    /// First pause of an accept loop after a failed accept
+    pub fn accept_backoff_base(&self) -> Duration {
+        Duration::from_millis(self.accept_backoff_base_ms)
+    }
+
+    /// Longest pause of an accept loop after failed accepts
+    pub fn accept_backoff_max(&self) -> Duration {
+        Duration::from_millis(self.accept_backoff_max_ms)
+    }
+
+    /// Whether any enabled server terminates TLS, and so needs `[tls]`
+    /// certificates
+    pub fn terminates_tls(&self) -> bool {
+        self.dot.enabled
+            || (self.doh.enabled && self.doh.tls)
+            || self.doq.enabled
+            || self.doh3.enabled
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ServerPortConfig {
     pub enabled: bool,
-    pub bind_address: String,
+    /// Address or list of addresses to listen on; `::` accepts both IPv6 and
+    /// IPv4 clients
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub bind_address: Vec<String>,
+    #[serde(default)]
     pub port: u16,
+    /// Unix domain socket to listen on instead of `bind_address` and `port`,
+    /// for a reverse proxy on the same host. Only supported by the DoH server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uds_path: Option<String>,
+    /// Read a PROXY protocol (v1 or v2) header from each accepted connection
+    /// and use the client address it carries instead of the peer's, for
+    /// servers behind a TCP load balancer (default: false). Only supported by
+    /// the DoT, DoH and TCP DNS servers
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Connections (UDP: queries) handled at once; further ones wait to be
+    /// accepted until one finishes (0 = unlimited)
+    #[serde(default)]
+    pub max_concurrent_connections: usize,
+    /// Answer only queries carrying a valid DNS server cookie (RFC 7873);
+    /// only supported by the UDP server
+    #[serde(default)]
+    pub require_cookies: bool,
+    /// Forward every query over this upstream protocol (`dot`, `doq`, `doh`
+    /// or `doh3`) instead of the server's own; not supported by the UDP and
+    /// TCP servers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_protocol: Option<String>,
+    /// Terminate TLS on the listener (default: true); turn off to serve
+    /// plain HTTP behind a TLS-terminating proxy. Only supported by the DoH
+    /// server
+    #[serde(default = "default_true")]
+    pub tls: bool,
+    /// Requests served on one connection before it is asked to close with a
+    /// GOAWAY; later requests are refused with `H3_REQUEST_REJECTED`
+    /// (0 = unlimited). Only supported by the DoH3 server
+    #[serde(default)]
+    pub max_requests_per_connection: usize,
+    /// Requests handled at once on one connection; further ones are refused
+    /// with `H3_REQUEST_REJECTED` (0 = unlimited). Only supported by the DoH3
+    /// server
+    #[serde(default)]
+    pub max_concurrent_streams_per_connection: usize,
+}
+
+fn default_max_message_size() -> usize {
+    65535
+}
+
+fn default_doh_path() -> String {
+    "/dns-query".to_string()
+}
+
+fn default_request_body_timeout_ms() -> u64 {
+    10000
+}
+
+fn default_max_header_count() -> usize {
+    64
+}
+
+fn default_max_header_bytes() -> usize {
+    8192
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+fn default_accept_backoff_base_ms() -> u64 {
+    100
+}
+
+fn default_accept_backoff_max_ms() -> u64 {
+    5000
+}
+
+fn default_udp_server() -> ServerPortConfig {
+    ServerPortConfig {
+        enabled: false,
+        bind_address: vec!["0.0.0.0".to_string()],
+        port: 53,
+        max_concurrent_connections: 0,
+        require_cookies: false,
+        upstream_protocol: None,
+        tls: true,
+        uds_path: None,
+        proxy_protocol: false,
+        max_requests_per_connection: 0,
+        max_concurrent_streams_per_connection: 0,
+    }
+}
+
+fn default_tcp_dns_server() -> ServerPortConfig {
+    ServerPortConfig {
+        enabled: false,
+        bind_address: vec!["0.0.0.0".to_string()],
+        port: 53,
+        max_concurrent_connections: 0,
+        require_cookies: false,
+        upstream_protocol: None,
+        tls: true,
+        uds_path: None,
+        proxy_protocol: false,
+        max_requests_per_connection: 0,
+        max_concurrent_streams_per_connection: 0,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct HealthcheckConfig {
     pub enabled: bool,
-    pub bind_address: String,
+    /// Address or list of addresses to listen on; `::` accepts both IPv6 and
+    /// IPv4 clients
+    #[serde(deserialize_with = "one_or_many")]
+    pub bind_address: Vec<String>,
     pub port: u16,
+    /// Liveness path, answered 200 whenever the process is up
     pub path: String,
+    /// Readiness path, answered 503 while no upstream passes its health probe
+    #[serde(default = "default_ready_path")]
+    pub ready_path: String,
+}
+
+fn default_ready_path() -> String {
+    "/ready".to_string()
+}
+
+impl ServerPortConfig {
+    /// Socket addresses the server listens on: every `bind_address` at `port`
+    pub fn socket_addrs(&self) -> Result<Vec<SocketAddr>> {
+        bind_socket_addrs(&self.bind_address, self.port)
+    }
+}
+
+impl HealthcheckConfig {
+    /// Socket addresses the server listens on: every `bind_address` at `port`
+    pub fn socket_addrs(&self) -> Result<Vec<SocketAddr>> {
+        bind_socket_addrs(&self.bind_address, self.port)
+    }
+}
+
+/// Combine bind addresses, given as IP addresses (IPv6 optionally in
+/// brackets), with a port
+fn bind_socket_addrs(bind_addresses: &[String], port: u16) -> Result<Vec<SocketAddr>> {
+    if bind_addresses.is_empty() {
+        anyhow::bail!("bind_address must list at least one address");
+    }
+    bind_addresses
+        .iter()
+        .map(|address| {
+            address
+                .trim_matches(['[', ']'])
+                .parse::<IpAddr>()
+                .map(|ip| SocketAddr::new(ip, port))
+                .map_err(|e| anyhow::anyhow!("Invalid bind address {}: {}", address, e))
+        })
+        .collect()
 }
 
 impl Default for HealthcheckConfig {
     fn default() -> Self {
         HealthcheckConfig {
             enabled: true,
-            bind_address: "0.0.0.0".to_string(),
+            bind_address: vec!["0.0.0.0".to_string()],
             port: 8080,
             path: "/health".to_string(),
+            ready_path: default_ready_path(),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Each upstream accepts a single address or a list of addresses; queries
+/// are spread round-robin over the ones the health prober reports as up
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct UpstreamConfig {
-    pub default: String,
-    pub dot: Option<String>,
-    pub doh: Option<String>,
-    pub doq: Option<String>,
-    pub doh3: Option<String>,
+    #[serde(deserialize_with = "one_or_many")]
+    pub default: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub dot: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub doh: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub doq: Vec<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub doh3: Vec<String>,
+    /// Plain UDP upstream for the UDP listener (e.g. "8.8.8.8:53")
+    /// When unset, UDP queries are forwarded over DoT (or the protocol ladder)
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub udp: Vec<String>,
+    /// Ordered upstream protocol preference for wire-format forwarding
+    /// (e.g. ["doh3", "doq", "dot"]). When a protocol fails the next one is tried.
+    /// Empty (default) keeps each ingress on its own upstream protocol.
+    #[serde(default)]
+    pub protocol_ladder: Vec<String>,
+    /// Timeout for a single upstream request in milliseconds (default: 5000)
+    #[serde(default = "default_upstream_timeout_ms")]
+    pub upstream_timeout_ms: u64,
+    /// Number of times a request is retried after a transient upstream failure
+    /// (connection error or 5xx response) before giving up (default: 2)
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    /// Maximum concurrent DoH/DoH3 requests to a single upstream host (default: 0 = unlimited)
+    /// Requests beyond the cap wait for a free slot until the upstream timeout expires
+    #[serde(default)]
+    pub doh_max_conns_per_host: usize,
+    /// HTTP connection pool tuning for DoH/DoH3 upstreams
+    #[serde(default)]
+    pub pool: UpstreamPoolConfig,
+    /// Pooled TLS connections to DoT upstreams
+    #[serde(default)]
+    pub dot_pool: DotPoolConfig,
+    /// Pooled QUIC connections to DoQ upstreams
+    #[serde(default)]
+    pub doq_pool: DoqPoolConfig,
+    /// Stop forwarding to DoH, DoT and DoQ upstreams that keep failing
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+    /// Interval between health probes of each upstream in seconds
+    /// (default: 30, 0 = never probe and treat every upstream as up)
+    #[serde(default = "default_health_check_interval_secs")]
+    pub health_check_interval_secs: u64,
+    /// Longest interval between probes of an upstream that keeps failing them,
+    /// in seconds (default: 300)
+    /// Each consecutive failure doubles the upstream's probe interval up to
+    /// this cap; a successful probe resets it to `health_check_interval_secs`
+    #[serde(default = "default_health_check_max_interval_secs")]
+    pub health_check_max_interval_secs: u64,
+    /// PEM file of CA certificates trusted for DoT upstreams in addition to
+    /// the system roots (optional)
+    #[serde(default)]
+    pub upstream_ca_file: Option<String>,
+    /// Skip certificate verification of DoT upstreams (default: false)
+    /// Only meant for testing against upstreams with self-signed certificates
+    #[serde(default)]
+    pub danger_accept_invalid_certs: bool,
+    /// Pass clients' `Forwarded` and `X-Forwarded-*` headers on to DoH/DoH3
+    /// upstreams (default: false, they are dropped)
+    #[serde(default)]
+    pub forward_proxy_headers: bool,
+    /// Headers added to every DoH/DoH3 upstream request, replacing any the
+    /// client sent (e.g. an `Authorization` token for a private resolver)
+    /// `${VAR}` in a value is replaced with the environment variable `VAR`,
+    /// so secrets need not be written to the config file
+    #[serde(default)]
+    pub extra_headers: std::collections::HashMap<String, String>,
+    /// Redirects followed for a single DoH/DoH3 upstream request
+    /// (default: 0, 3xx responses are passed on to the client)
+    #[serde(default)]
+    pub max_redirects: u32,
+}
+
+/// Deserialize a single string or a list of strings into a list
+fn one_or_many<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    30
+}
+
+fn default_health_check_max_interval_secs() -> u64 {
+    300
+}
+
+fn default_upstream_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_max_retries() -> u32 {
+    2
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamPoolConfig {
+    /// How long idle upstream connections (and per-target clients) are kept, in seconds (default: 60)
+    #[serde(default = "default_pool_keepalive_secs")]
+    pub keepalive_secs: u64,
+    /// Timeout for establishing an upstream connection in seconds (default: 10)
+    #[serde(default = "default_pool_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    /// Maximum idle connections kept per upstream target (default: 10)
+    #[serde(default = "default_pool_max_idle_per_host")]
+    pub max_idle_per_host: usize,
+}
+
+fn default_pool_keepalive_secs() -> u64 {
+    60
+}
+
+fn default_pool_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_pool_max_idle_per_host() -> usize {
+    10
+}
+
+impl Default for UpstreamPoolConfig {
+    fn default() -> Self {
+        Self {
+            keepalive_secs: default_pool_keepalive_secs(),
+            connect_timeout_secs: default_pool_connect_timeout_secs(),
+            max_idle_per_host: default_pool_max_idle_per_host(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DotPoolConfig {
+    /// How long an idle DoT upstream connection is kept for reuse, in seconds (default: 30)
+    #[serde(default = "default_upstream_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// Maximum idle connections kept per upstream (default: 4, 0 = a new
+    /// connection per query)
+    #[serde(default = "default_dot_pool_max_per_upstream")]
+    pub max_per_upstream: usize,
+}
+
+fn default_upstream_idle_timeout_secs() -> u64 {
+    30
+}
+
+fn default_dot_pool_max_per_upstream() -> usize {
+    4
+}
+
+impl Default for DotPoolConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: default_upstream_idle_timeout_secs(),
+            max_per_upstream: default_dot_pool_max_per_upstream(),
+        }
+    }
+}
+
+impl DotPoolConfig {
+    /// How long an idle connection is kept
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DoqPoolConfig {
+    /// QUIC idle timeout of upstream connections in seconds; a connection
+    /// without traffic for this long is closed and replaced on the next
+    /// query (default: 30)
+    #[serde(default = "default_upstream_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// QUIC connections opened per upstream, with queries spread across them
+    /// (default: 1)
+    #[serde(default = "default_doq_pool_max_per_upstream")]
+    pub max_per_upstream: usize,
+    /// Interval of QUIC keep-alive packets on upstream connections in seconds,
+    /// keeping idle ones open (default: 0 = none)
+    #[serde(default)]
+    pub keep_alive_interval_secs: u64,
+}
+
+fn default_doq_pool_max_per_upstream() -> usize {
+    1
+}
+
+impl Default for DoqPoolConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: default_upstream_idle_timeout_secs(),
+            max_per_upstream: default_doq_pool_max_per_upstream(),
+            keep_alive_interval_secs: 0,
+        }
+    }
+}
+
+impl DoqPoolConfig {
+    /// QUIC idle timeout of upstream connections
+    pub fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_secs)
+    }
+
+    /// Interval of QUIC keep-alive packets, if enabled
+    pub fn keep_alive_interval(&self) -> Option<Duration> {
+        (self.keep_alive_interval_secs > 0)
+            .then(|| Duration::from_secs(self.keep_alive_interval_secs))
+    }
+}
+
+/// Per-upstream circuit breaker (see [`crate::upstream::circuit_breaker`])
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CircuitBreakerConfig {
+    /// Failures in a row that open an upstream's circuit (default: 0 = no
+    /// circuit breaker)
+    #[serde(default)]
+    pub failure_threshold: u32,
+    /// Seconds within which the failures must happen; an older first failure
+    /// starts the count over (default: 30)
+    #[serde(default = "default_circuit_failure_window_secs")]
+    pub failure_window_secs: u64,
+    /// Seconds an open circuit fails requests at once before letting one
+    /// through to probe whether the upstream recovered (default: 30)
+    #[serde(default = "default_circuit_cooldown_secs")]
+    pub cooldown_secs: u64,
+}
+
+fn default_circuit_failure_window_secs() -> u64 {
+    30
+}
+
+fn default_circuit_cooldown_secs() -> u64 {
+    30
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 0,
+            failure_window_secs: default_circuit_failure_window_secs(),
+            cooldown_secs: default_circuit_cooldown_secs(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+impl CircuitBreakerConfig {
+    /// Window within which failures in a row open a circuit
+    pub fn failure_window(&self) -> Duration {
+        Duration::from_secs(self.failure_window_secs)
+    }
+
+    /// How long an open circuit fails requests at once
+    pub fn cooldown(&self) -> Duration {
+        Duration::from_secs(self.cooldown_secs)
+    }
+}
+
+impl UpstreamConfig {
+    /// Timeout for a single upstream request
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.upstream_timeout_ms)
+    }
+
+    /// Probe interval of a healthy upstream
+    pub fn health_check_interval(&self) -> Duration {
+        Duration::from_secs(self.health_check_interval_secs)
+    }
+
+    /// Longest probe interval of an upstream that keeps failing its probes
+    pub fn health_check_max_interval(&self) -> Duration {
+        Duration::from_secs(self.health_check_max_interval_secs)
+    }
+
+    /// `extra_headers` with their `${VAR}` references replaced from the
+    /// environment
+    ///
+    /// Errors name the offending header but never include its value, which
+    /// may hold a secret.
+    pub fn resolved_extra_headers(&self) -> Result<hyper::HeaderMap> {
+        let mut headers = hyper::HeaderMap::new();
+        for (name, value) in &self.extra_headers {
+            let header_name =
+                hyper::header::HeaderName::from_bytes(name.as_bytes()).with_context(|| {
+                    format!("invalid header name in upstream.extra_headers: {:?}", name)
+                })?;
+            let value = expand_env_vars(value)
+                .with_context(|| format!("upstream.extra_headers.{}", name))?;
+            let mut header_value = hyper::header::HeaderValue::from_str(&value).map_err(|_| {
+                anyhow::anyhow!("invalid value for upstream.extra_headers.{}", name)
+            })?;
+            header_value.set_sensitive(true);
+            headers.insert(header_name, header_value);
+        }
+        Ok(headers)
+    }
+}
+
+/// Replace every `${VAR}` in `value` with the environment variable `VAR`
+fn expand_env_vars(value: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            anyhow::bail!("unterminated ${{ in value");
+        };
+        let var = &rest[start + 2..start + 2 + len];
+        let resolved = std::env::var(var)
+            .with_context(|| format!("environment variable {} is not set", var))?;
+        expanded.push_str(&resolved);
+        rest = &rest[start + 3 + len..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TlsConfig {
     /// Default certificate configuration (used when no domain-specific cert is found)
     #[serde(default)]
@@ -88,9 +694,90 @@ pub struct TlsConfig {
     /// Key is the domain name (e.g., "example.com"), value is the certificate config
     #[serde(default)]
     pub certs: std::collections::HashMap<String, CertificateConfig>,
+    /// Seconds between reloads of every cached certificate (default: 0,
+    /// certificates are only reloaded when a lookup sees their files changed)
+    #[serde(default)]
+    pub reload_interval_secs: u64,
+    /// ALPN protocol identifiers offered by each TLS server
+    #[serde(default)]
+    pub alpn: AlpnConfig,
+    /// Let clients resume earlier TLS sessions instead of doing a full
+    /// handshake (default: true); disable for strict forward secrecy
+    #[serde(default = "default_true")]
+    pub session_resumption: bool,
+    /// Longest time a session ticket is accepted, in seconds (default: 43200)
+    #[serde(default = "default_session_ticket_lifetime_secs")]
+    pub session_ticket_lifetime_secs: u32,
+    /// Serve a self-signed certificate generated at startup when no
+    /// `tls.default` is configured (default: false); for local testing only,
+    /// since clients don't trust it
+    #[serde(default)]
+    pub generate_self_signed: bool,
+}
+
+fn default_session_ticket_lifetime_secs() -> u32 {
+    12 * 60 * 60
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            default: None,
+            certs: std::collections::HashMap::new(),
+            reload_interval_secs: 0,
+            alpn: AlpnConfig::default(),
+            session_resumption: true,
+            session_ticket_lifetime_secs: default_session_ticket_lifetime_secs(),
+            generate_self_signed: false,
+        }
+    }
+}
+
+/// ALPN protocol identifiers each TLS server negotiates, in preference order
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AlpnConfig {
+    /// DoT (default: ["dot"], RFC 7858); empty disables ALPN
+    #[serde(default = "default_dot_alpn")]
+    pub dot: Vec<String>,
+    /// DoH (default: ["h2", "http/1.1"], RFC 8484)
+    #[serde(default = "default_doh_alpn")]
+    pub doh: Vec<String>,
+    /// DoQ (default: ["doq"], RFC 9250)
+    #[serde(default = "default_doq_alpn")]
+    pub doq: Vec<String>,
+    /// DoH3 (default: ["h3"], RFC 9114)
+    #[serde(default = "default_doh3_alpn")]
+    pub doh3: Vec<String>,
+}
+
+fn default_dot_alpn() -> Vec<String> {
+    vec!["dot".to_string()]
+}
+
+fn default_doh_alpn() -> Vec<String> {
+    vec!["h2".to_string(), "http/1.1".to_string()]
+}
+
+fn default_doq_alpn() -> Vec<String> {
+    vec!["doq".to_string()]
+}
+
+fn default_doh3_alpn() -> Vec<String> {
+    vec!["h3".to_string()]
+}
+
+impl Default for AlpnConfig {
+    fn default() -> Self {
+        Self {
+            dot: default_dot_alpn(),
+            doh: default_doh_alpn(),
+            doq: default_doq_alpn(),
+            doh3: default_doh3_alpn(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LoggingConfig {
     /// Log level: trace, debug, info, warn, error (default: info)
     #[serde(default = "default_log_level")]
@@ -110,6 +797,23 @@ pub struct LoggingConfig {
     /// Number of log files to keep (default: 5)
     #[serde(default = "default_max_files")]
     pub max_files: usize,
+    /// Log one in every N rejected connections per reason (default: 1 = log all, 0 = never)
+    /// Rejections are always counted in metrics regardless of sampling
+    #[serde(default = "default_rejected_log_sample_rate")]
+    pub rejected_log_sample_rate: u64,
+    /// Write one access log line per completed request (default: false)
+    #[serde(default)]
+    pub access_log: bool,
+    /// Access log file path (optional, if not set, access lines go to stdout)
+    #[serde(default)]
+    pub access_log_file: Option<String>,
+    /// Access log line format: combined or json (default: combined)
+    #[serde(default = "default_access_log_format")]
+    pub access_log_format: String,
+}
+
+fn default_access_log_format() -> String {
+    "combined".to_string()
 }
 
 fn default_log_level() -> String {
@@ -128,6 +832,10 @@ fn default_max_files() -> usize {
     5
 }
 
+fn default_rejected_log_sample_rate() -> u64 {
+    1
+}
+
 impl Default for LoggingConfig {
     fn default() -> Self {
         Self {
@@ -137,21 +845,253 @@ impl Default for LoggingConfig {
             rotation: default_true(),
             max_file_size: default_max_file_size(),
             max_files: default_max_files(),
+            rejected_log_sample_rate: default_rejected_log_sample_rate(),
+            access_log: false,
+            access_log_file: None,
+            access_log_format: default_access_log_format(),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EdnsConfig {
+    /// Handling of unknown EDNS options in forwarded queries
+    /// - "forward": Pass unknown options through untouched (default)
+    /// - "strip": Remove unknown options, keeping well-known ones (cookie, keepalive, padding, ...)
+    #[serde(default = "default_unknown_options")]
+    pub unknown_options: String,
+    /// Set the DO bit on every forwarded query so upstreams return DNSSEC records,
+    /// adding an OPT record when the client sent none
+    #[serde(default)]
+    pub force_do_bit: bool,
+    /// Clear the DO bit on every forwarded query for clients that can't handle DNSSEC records
+    #[serde(default)]
+    pub clear_do_bit: bool,
+}
+
+fn default_unknown_options() -> String {
+    "forward".to_string()
+}
+
+impl Default for EdnsConfig {
+    fn default() -> Self {
+        Self {
+            unknown_options: default_unknown_options(),
+            force_do_bit: false,
+            clear_do_bit: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheConfig {
+    /// Cache upstream answers of the UDP, TCP, DoQ, DoH and DoH3 listeners
+    #[serde(default)]
+    pub enabled: bool,
+    /// Maximum number of cached answers
+    #[serde(default = "default_cache_max_entries")]
+    pub max_entries: usize,
+    /// Query types that bypass the response cache: always forwarded, never stored
+    /// Accepts mnemonics (e.g. "TXT", "SOA") or numeric types (e.g. "16", "TYPE65")
+    #[serde(default)]
+    pub no_cache_qtypes: Vec<String>,
+}
+
+fn default_cache_max_entries() -> usize {
+    10000
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_entries: default_cache_max_entries(),
+            no_cache_qtypes: Vec::new(),
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Parse `no_cache_qtypes` into numeric query types
+    pub fn no_cache_qtype_codes(&self) -> Result<Vec<u16>> {
+        self.no_cache_qtypes
+            .iter()
+            .map(|name| {
+                crate::dns::qtype_from_name(name).ok_or_else(|| {
+                    anyhow::anyhow!("Invalid qtype in cache.no_cache_qtypes: {}", name)
+                })
+            })
+            .collect()
+    }
+}
+
+/// Domains the proxy will serve, matched against the Host header (DoH/DoH3)
+/// or the queried name (DNS transports)
+///
+/// A pattern matches the domain and its subdomains ("example.com"), only its
+/// subdomains ("*.example.com"), or everything ("*").
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct FilterConfig {
+    /// Only these domains are served; empty allows all
+    #[serde(default)]
+    pub allow_domains: Vec<String>,
+    /// These domains are refused, even when also allowed
+    #[serde(default)]
+    pub deny_domains: Vec<String>,
+}
+
+impl FilterConfig {
+    /// Whether requests for `domain` may be forwarded
+    pub fn is_allowed(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if self
+            .deny_domains
+            .iter()
+            .any(|pattern| domain_matches(pattern, &domain))
+        {
+            return false;
+        }
+        self.allow_domains.is_empty()
+            || self
+                .allow_domains
+                .iter()
+                .any(|pattern| domain_matches(pattern, &domain))
+    }
+}
+
+/// Match a lowercase domain against a filter pattern (see [`FilterConfig`])
+fn domain_matches(pattern: &str, domain: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+    let is_subdomain_of = |parent: &str| {
+        domain
+            .strip_suffix(parent)
+            .is_some_and(|rest| rest.ends_with('.'))
+    };
+    match pattern.strip_prefix("*.") {
+        _ if pattern == "*" => true,
+        Some(parent) => is_subdomain_of(parent),
+        None => domain == pattern || is_subdomain_of(&pattern),
+    }
+}
+
+/// Per-client-IP rate limiting, applied by every listener separately
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct RateLimitConfig {
+    /// Sustained requests (or connections) per second per client IP; 0 disables limiting
+    #[serde(default)]
+    pub requests_per_sec: u32,
+    /// Requests a client may make at once before being limited (0 = `requests_per_sec`)
+    #[serde(default)]
+    pub burst: u32,
+    /// Client IPs that are never limited
+    #[serde(default)]
+    pub allowlist: Vec<String>,
+}
+
+impl RateLimitConfig {
+    /// Parse `allowlist` into IP addresses
+    pub fn allowlist_ips(&self) -> Result<Vec<IpAddr>> {
+        self.allowlist
+            .iter()
+            .map(|ip| {
+                ip.trim()
+                    .parse()
+                    .with_context(|| format!("Invalid IP in ratelimit.allowlist: {}", ip))
+            })
+            .collect()
+    }
+}
+
+/// QUIC transport parameters of the DoQ and DoH3 server endpoints
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuicConfig {
+    /// Close a client connection after this long without traffic, in
+    /// milliseconds (default: 30000, 1000 to 600000)
+    #[serde(default = "default_quic_max_idle_timeout_ms")]
+    pub max_idle_timeout_ms: u64,
+    /// Streams (queries) a client may have open at once on one connection
+    /// (default: 256, 1 to 65535)
+    #[serde(default = "default_quic_max_concurrent_bidi_streams")]
+    pub max_concurrent_bidi_streams: u32,
+    /// Interval of keep-alive packets sent to clients in milliseconds
+    /// (default: 0 = none, otherwise less than `max_idle_timeout_ms`)
+    #[serde(default)]
+    pub keep_alive_interval_ms: u64,
+    /// Accept 0-RTT (early) data from DoQ clients resuming a session
+    /// (default: false)
+    ///
+    /// Early data saves a round trip on resumed connections, but it is not
+    /// protected against replay: an attacker who captured it can send it to
+    /// the server again. Replaying a query only repeats the lookup, so only
+    /// QUERY and NOTIFY messages (RFC 9250 section 4.5) are answered from
+    /// 0-RTT streams; any other opcode gets REFUSED. DoH3 never accepts 0-RTT,
+    /// since requests can't be told apart from those sent after the handshake.
+    #[serde(default)]
+    pub allow_0rtt: bool,
+}
+
+fn default_quic_max_idle_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_quic_max_concurrent_bidi_streams() -> u32 {
+    256
+}
+
+impl Default for QuicConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_timeout_ms: default_quic_max_idle_timeout_ms(),
+            max_concurrent_bidi_streams: default_quic_max_concurrent_bidi_streams(),
+            keep_alive_interval_ms: 0,
+            allow_0rtt: false,
+        }
+    }
+}
+
+impl QuicConfig {
+    /// Idle timeout of client connections
+    pub fn max_idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.max_idle_timeout_ms)
+    }
+
+    /// Interval of keep-alive packets, if enabled
+    pub fn keep_alive_interval(&self) -> Option<Duration> {
+        (self.keep_alive_interval_ms > 0)
+            .then(|| Duration::from_millis(self.keep_alive_interval_ms))
+    }
+}
+
+/// Request handling shared by every listener
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+pub struct ProxyConfig {
+    /// Rewrite and log where each query would be forwarded, but answer it
+    /// with an empty NOERROR response instead of contacting any upstream
+    /// (default: false). Upstream health probing is skipped as well.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CertificateConfig {
     /// Certificate file path (PEM format)
     pub cert_file: String,
     /// Private key file path (PEM format)
     pub key_file: String,
+    /// Passphrase of an encrypted (`ENCRYPTED PRIVATE KEY`) key file
+    /// (optional); `${VAR}` references are replaced from the environment
+    #[serde(default)]
+    pub key_passphrase: Option<String>,
     /// CA certificate file path for client verification (optional)
     pub ca_file: Option<String>,
-    /// Whether to require client certificate
+    /// Whether to reject clients without a certificate issued by `ca_file`
+    /// (when unset, a client certificate is verified only if presented)
     #[serde(default)]
     pub require_client_cert: bool,
+    /// DER-encoded OCSP response stapled to the certificate in handshakes
+    /// (optional); reloaded along with the certificate when it changes
+    #[serde(default)]
+    pub ocsp_file: Option<String>,
 }
 
 impl Default for AppConfig {
@@ -160,40 +1100,108 @@ impl Default for AppConfig {
             rewrite: RewriteConfig {
                 base_domains: vec!["example.com".to_string(), "example.org".to_string()],
                 target_suffix: ".example.cn".to_string(),
+                targets: Vec::new(),
                 rewrite_failure_strategy: default_rewrite_failure_strategy(),
+                rewriter_type: default_rewriter_type(),
+                mapping_file: None,
+                rules: Vec::new(),
             },
             servers: ServersConfig {
                 dot: ServerPortConfig {
                     enabled: true,
-                    bind_address: "0.0.0.0".to_string(),
+                    bind_address: vec!["0.0.0.0".to_string()],
                     port: 853,
+                    max_concurrent_connections: 0,
+                    require_cookies: false,
+                    upstream_protocol: None,
+                    tls: true,
+                    uds_path: None,
+                    proxy_protocol: false,
+                    max_requests_per_connection: 0,
+                    max_concurrent_streams_per_connection: 0,
                 },
                 doh: ServerPortConfig {
                     enabled: true,
-                    bind_address: "0.0.0.0".to_string(),
+                    bind_address: vec!["0.0.0.0".to_string()],
                     port: 443,
+                    max_concurrent_connections: 0,
+                    require_cookies: false,
+                    upstream_protocol: None,
+                    tls: true,
+                    uds_path: None,
+                    proxy_protocol: false,
+                    max_requests_per_connection: 0,
+                    max_concurrent_streams_per_connection: 0,
                 },
                 doq: ServerPortConfig {
                     enabled: true,
-                    bind_address: "0.0.0.0".to_string(),
+                    bind_address: vec!["0.0.0.0".to_string()],
                     port: 853,
+                    max_concurrent_connections: 0,
+                    require_cookies: false,
+                    upstream_protocol: None,
+                    tls: true,
+                    uds_path: None,
+                    proxy_protocol: false,
+                    max_requests_per_connection: 0,
+                    max_concurrent_streams_per_connection: 0,
                 },
                 doh3: ServerPortConfig {
                     enabled: false,
-                    bind_address: "0.0.0.0".to_string(),
+                    bind_address: vec!["0.0.0.0".to_string()],
                     port: 443,
+                    max_concurrent_connections: 0,
+                    require_cookies: false,
+                    upstream_protocol: None,
+                    tls: true,
+                    uds_path: None,
+                    proxy_protocol: false,
+                    max_requests_per_connection: 0,
+                    max_concurrent_streams_per_connection: 0,
                 },
+                udp: default_udp_server(),
+                tcp_dns: default_tcp_dns_server(),
                 healthcheck: HealthcheckConfig::default(),
+                max_message_size: default_max_message_size(),
+                doh_path: default_doh_path(),
+                doh_upstream_path: None,
+                request_body_timeout_ms: default_request_body_timeout_ms(),
+                max_header_count: default_max_header_count(),
+                max_header_bytes: default_max_header_bytes(),
+                accept_backoff_base_ms: default_accept_backoff_base_ms(),
+                accept_backoff_max_ms: default_accept_backoff_max_ms(),
             },
             upstream: UpstreamConfig {
-                default: "8.8.8.8:853".to_string(),
-                dot: Some("8.8.8.8:853".to_string()),
-                doh: Some("https://dns.google/dns-query".to_string()),
-                doq: Some("8.8.8.8:853".to_string()),
-                doh3: Some("https://dns.google/dns-query".to_string()),
+                default: vec!["8.8.8.8:853".to_string()],
+                dot: vec!["8.8.8.8:853".to_string()],
+                doh: vec!["https://dns.google/dns-query".to_string()],
+                doq: vec!["8.8.8.8:853".to_string()],
+                doh3: vec!["https://dns.google/dns-query".to_string()],
+                udp: Vec::new(),
+                protocol_ladder: Vec::new(),
+                upstream_timeout_ms: default_upstream_timeout_ms(),
+                max_retries: default_max_retries(),
+                doh_max_conns_per_host: 0,
+                pool: UpstreamPoolConfig::default(),
+                dot_pool: DotPoolConfig::default(),
+                doq_pool: DoqPoolConfig::default(),
+                circuit_breaker: CircuitBreakerConfig::default(),
+                health_check_interval_secs: default_health_check_interval_secs(),
+                health_check_max_interval_secs: default_health_check_max_interval_secs(),
+                upstream_ca_file: None,
+                danger_accept_invalid_certs: false,
+                forward_proxy_headers: false,
+                extra_headers: std::collections::HashMap::new(),
+                max_redirects: 0,
             },
             tls: TlsConfig::default(),
             logging: LoggingConfig::default(),
+            edns: EdnsConfig::default(),
+            cache: CacheConfig::default(),
+            filter: FilterConfig::default(),
+            ratelimit: RateLimitConfig::default(),
+            quic: QuicConfig::default(),
+            proxy: ProxyConfig::default(),
         }
     }
 }
@@ -208,71 +1216,106 @@ impl AppConfig {
         Ok(config)
     }
 
-    /// Load configuration from file or use default
+    /// Serialize the configuration as TOML; parsing the result yields an
+    /// equal config
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string_pretty(self).context("Failed to serialize config")
+    }
+
+    /// Load configuration from file or use default, with environment
+    /// overrides applied
     pub fn load_or_default<P: AsRef<Path>>(path: P) -> Self {
-        Self::from_file(path).unwrap_or_else(|e| {
-            tracing::warn!("Failed to load config file, using defaults: {}", e);
-            Self::default()
-        })
+        Self::load(path, false).unwrap_or_default()
     }
 
-    /// Get upstream address for DoT
-    /// Returns the configured DoT upstream or default upstream as SocketAddr
-    pub fn dot_upstream(&self) -> Result<SocketAddr> {
-        self.upstream
-            .dot
-            .as_deref()
-            .or(Some(self.upstream.default.as_str()))
-            .and_then(|s| s.parse().ok())
-            .ok_or_else(|| {
-                anyhow::anyhow!(
-                    "Invalid upstream address for DoT: {:?} or default: {}",
-                    self.upstream.dot,
-                    self.upstream.default
-                )
-            })
+    /// Load configuration from file, then apply environment overrides (see
+    /// [`apply_env_overrides`])
+    ///
+    /// With `require` set, a missing or unreadable file or an invalid override
+    /// is an error. Otherwise the built-in defaults are used (or the override
+    /// ignored) and a warning is emitted; it goes to stderr when no tracing
+    /// subscriber is installed yet, so the fallback is never silent.
+    pub fn load<P: AsRef<Path>>(path: P, require: bool) -> Result<Self> {
+        let mut config = match Self::from_file(path.as_ref()) {
+            Ok(config) => config,
+            Err(e) if require => {
+                return Err(e)
+                    .with_context(|| format!("Config file {:?} is required", path.as_ref()));
+            }
+            Err(e) => {
+                warn_before_logging(&format!(
+                    "Failed to load config file {:?}, falling back to built-in defaults: {:#}",
+                    path.as_ref(),
+                    e
+                ));
+                Self::default()
+            }
+        };
+        if let Err(e) = apply_env_overrides(&mut config) {
+            if require {
+                return Err(e);
+            }
+            warn_before_logging(&format!("Ignoring environment overrides: {:#}", e));
+        }
+        Ok(config)
     }
 
-    /// Get upstream address for DoQ
-    /// Returns the configured DoQ upstream or default upstream as SocketAddr
-    pub fn doq_upstream(&self) -> Result<SocketAddr> {
-        self.upstream
-            .doq
-            .as_deref()
-            .or(Some(self.upstream.default.as_str()))
-            .and_then(|s| s.parse().ok())
-            .ok_or_else(|| {
-                anyhow::anyhow!(
-                    "Invalid upstream address for DoQ: {:?} or default: {}",
-                    self.upstream.doq,
-                    self.upstream.default
-                )
-            })
+    /// Get the first DoT upstream address
+    pub fn dot_upstream(&self) -> Result<SocketAddr> {
+        self.dot_upstreams()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("No DoT upstream configured"))
     }
 
-    /// Get upstream hostname for DoT/DoQ (extracted from address or default)
-    /// This is used for SNI in TLS connections
-    pub fn dot_upstream_hostname(&self) -> String {
-        // Try to extract hostname from configured upstream
-        if let Some(addr) = &self.upstream.dot {
-            if let Ok(parsed) = addr.parse::<SocketAddr>() {
-                return parsed.ip().to_string();
+    /// Check that `protocol`, named in the `field` option, is an upstream
+    /// protocol with an upstream configured
+    fn validate_upstream_protocol(&self, protocol: &str, field: &str) -> Result<()> {
+        match protocol {
+            "dot" => {
+                self.dot_upstreams()?;
+            }
+            "doq" => {
+                self.doq_upstreams()?;
+            }
+            "doh" if self.upstream.doh.is_empty() => {
+                anyhow::bail!("No DoH upstream URL configured")
             }
-            // If not a SocketAddr, try to extract hostname from URL-like string
-            if let Some(host) = addr.split(':').next() {
-                return host.to_string();
+            "doh3" if self.upstream.doh3.is_empty() => {
+                anyhow::bail!("No DoH3 upstream URL configured")
             }
+            "doh" | "doh3" => {}
+            other => anyhow::bail!(
+                "Invalid protocol in {}: {} (expected dot, doq, doh or doh3)",
+                field,
+                other
+            ),
         }
-        // Fallback to default
-        if let Ok(parsed) = self.upstream.default.parse::<SocketAddr>() {
-            parsed.ip().to_string()
+        Ok(())
+    }
+
+    /// Get all DoT upstream addresses (`upstream.dot`, or `upstream.default`
+    /// when unset)
+    pub fn dot_upstreams(&self) -> Result<Vec<SocketAddr>> {
+        parse_upstream_addrs("DoT", self.upstream_or_default(&self.upstream.dot))
+    }
+
+    /// Get all DoQ upstream addresses (`upstream.doq`, or `upstream.default`
+    /// when unset)
+    pub fn doq_upstreams(&self) -> Result<Vec<SocketAddr>> {
+        parse_upstream_addrs("DoQ", self.upstream_or_default(&self.upstream.doq))
+    }
+
+    /// Get the plain UDP upstream addresses (empty when none is configured)
+    pub fn udp_upstreams(&self) -> Result<Vec<SocketAddr>> {
+        parse_upstream_addrs("UDP", &self.upstream.udp)
+    }
+
+    fn upstream_or_default<'a>(&'a self, upstreams: &'a [String]) -> &'a [String] {
+        if upstreams.is_empty() {
+            &self.upstream.default
         } else {
-            self.upstream
-                .default
-                .split(':')
-                .next()
-                .unwrap_or("dns.google")
-                .to_string()
+            upstreams
         }
     }
 
@@ -280,7 +1323,7 @@ impl AppConfig {
     pub fn validate(&self) -> Result<()> {
         use std::collections::HashSet;
 
-        // Check for port conflicts
+        // Check for port conflicts; TCP and UDP listeners may share a port
         let mut ports = HashSet::new();
 
         // Check standard server ports
@@ -289,81 +1332,507 @@ impl AppConfig {
             ("doh", &self.servers.doh),
             ("doq", &self.servers.doq),
             ("doh3", &self.servers.doh3),
+            ("udp", &self.servers.udp),
+            ("tcp_dns", &self.servers.tcp_dns),
         ];
 
         for (name, config) in standard_servers {
-            if config.enabled {
-                let addr = format!("{}:{}", config.bind_address, config.port);
-                if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
-                    if !ports.insert((socket_addr.ip(), socket_addr.port())) {
-                        anyhow::bail!(
-                            "Port conflict: {} is already used by another server",
-                            socket_addr.port()
-                        );
-                    }
-                } else {
-                    anyhow::bail!("Invalid bind address for {}: {}", name, addr);
+            if !config.tls && *name != "doh" {
+                anyhow::bail!(
+                    "servers.{}.tls can only be turned off for the DoH server",
+                    name
+                );
+            }
+            if config.require_cookies && *name != "udp" {
+                anyhow::bail!(
+                    "servers.{}.require_cookies is only supported by the UDP server",
+                    name
+                );
+            }
+            if *name != "doh3" {
+                if config.max_requests_per_connection != 0 {
+                    anyhow::bail!(
+                        "servers.{}.max_requests_per_connection is only supported by the DoH3 server",
+                        name
+                    );
+                }
+                if config.max_concurrent_streams_per_connection != 0 {
+                    anyhow::bail!(
+                        "servers.{}.max_concurrent_streams_per_connection is only supported by the DoH3 server",
+                        name
+                    );
+                }
+            }
+            if let Some(protocol) = &config.upstream_protocol {
+                if matches!(*name, "udp" | "tcp_dns") {
+                    anyhow::bail!(
+                        "servers.{}.upstream_protocol is not supported by the UDP and TCP servers",
+                        name
+                    );
                 }
+                self.validate_upstream_protocol(
+                    protocol,
+                    &format!("servers.{}.upstream_protocol", name),
+                )?;
+            }
+            if config.proxy_protocol && matches!(*name, "doq" | "doh3" | "udp") {
+                anyhow::bail!(
+                    "servers.{}.proxy_protocol is only supported by the DoT, DoH and TCP DNS servers",
+                    name
+                );
+            }
+            if config.uds_path.is_some() {
+                if *name != "doh" {
+                    anyhow::bail!(
+                        "servers.{}.uds_path is only supported by the DoH server",
+                        name
+                    );
+                }
+                if !config.bind_address.is_empty() || config.port != 0 {
+                    anyhow::bail!(
+                        "servers.doh listens on either bind_address and port or uds_path, not both"
+                    );
+                }
+            } else if config.enabled {
+                if config.port == 0 {
+                    anyhow::bail!("servers.{}.port must be set", name);
+                }
+                let socket_addrs = config
+                    .socket_addrs()
+                    .with_context(|| format!("Invalid servers.{}.bind_address", name))?;
+                let transport = if matches!(*name, "doq" | "doh3" | "udp") {
+                    "udp"
+                } else {
+                    "tcp"
+                };
+                claim_ports(&mut ports, &socket_addrs, transport)?;
             }
         }
 
         // Check healthcheck server port
         if self.servers.healthcheck.enabled {
-            let addr = format!(
-                "{}:{}",
-                self.servers.healthcheck.bind_address, self.servers.healthcheck.port
+            let socket_addrs = self
+                .servers
+                .healthcheck
+                .socket_addrs()
+                .context("Invalid servers.healthcheck.bind_address")?;
+            claim_ports(&mut ports, &socket_addrs, "tcp")?;
+            if self.servers.healthcheck.ready_path == self.servers.healthcheck.path {
+                anyhow::bail!(
+                    "Healthcheck ready_path must differ from path ({})",
+                    self.servers.healthcheck.path
+                );
+            }
+        }
+
+        if !(dns::HEADER_LEN..=65535).contains(&self.servers.max_message_size) {
+            anyhow::bail!(
+                "servers.max_message_size must be between {} and 65535 bytes, got {}",
+                dns::HEADER_LEN,
+                self.servers.max_message_size
+            );
+        }
+        if !self.servers.doh_path.starts_with('/') {
+            anyhow::bail!(
+                "servers.doh_path must start with '/', got {:?}",
+                self.servers.doh_path
+            );
+        }
+        if let Some(path) = &self.servers.doh_upstream_path
+            && !path.starts_with('/')
+        {
+            anyhow::bail!(
+                "servers.doh_upstream_path must start with '/', got {:?}",
+                path
             );
-            if let Ok(socket_addr) = addr.parse::<SocketAddr>() {
-                if !ports.insert((socket_addr.ip(), socket_addr.port())) {
+        }
+        if self.servers.request_body_timeout_ms == 0 {
+            anyhow::bail!("servers.request_body_timeout_ms must be greater than 0");
+        }
+        if self.servers.max_header_count == 0 || self.servers.max_header_bytes == 0 {
+            anyhow::bail!(
+                "servers.max_header_count and servers.max_header_bytes must be greater than 0"
+            );
+        }
+        if self.servers.accept_backoff_base_ms == 0 {
+            anyhow::bail!("servers.accept_backoff_base_ms must be greater than 0");
+        }
+        if self.servers.accept_backoff_max_ms < self.servers.accept_backoff_base_ms {
+            anyhow::bail!(
+                "servers.accept_backoff_max_ms ({}) must be at least servers.accept_backoff_base_ms ({})",
+                self.servers.accept_backoff_max_ms,
+                self.servers.accept_backoff_base_ms
+            );
+        }
+
+        // Check that no listener forwards to itself
+        let upstreams = self.upstream_socket_addrs();
+        for (name, config) in standard_servers {
+            if !config.enabled {
+                continue;
+            }
+            let Ok(listens) = config.socket_addrs() else {
+                continue;
+            };
+            for (listen, (upstream_name, upstream)) in listens
+                .iter()
+                .flat_map(|listen| upstreams.iter().map(move |upstream| (listen, upstream)))
+            {
+                let same_host = listen.ip() == upstream.ip()
+                    || (listen.ip().is_unspecified()
+                        && (upstream.ip().is_loopback() || upstream.ip().is_unspecified()));
+                if same_host && listen.port() == upstream.port() {
                     anyhow::bail!(
-                        "Port conflict: {} is already used by another server",
-                        socket_addr.port()
+                        "Forwarding loop: {} server listens on {} which is also the {} upstream {}",
+                        name,
+                        listen,
+                        upstream_name,
+                        upstream
                     );
                 }
-            } else {
-                anyhow::bail!("Invalid bind address for healthcheck: {}", addr);
             }
         }
 
-        // Validate TLS certificate files exist
-        if let Some(default_cert) = &self.tls.default {
-            std::fs::metadata(&default_cert.cert_file).with_context(|| {
-                format!(
-                    "Default certificate file not found: {}",
-                    default_cert.cert_file
-                )
-            })?;
-            std::fs::metadata(&default_cert.key_file).with_context(|| {
-                format!("Default key file not found: {}", default_cert.key_file)
-            })?;
+        // Validate TLS certificate files exist; they are only read when an
+        // enabled server terminates TLS
+        if self.servers.terminates_tls() {
+            if let Some(default_cert) = &self.tls.default {
+                std::fs::metadata(&default_cert.cert_file).with_context(|| {
+                    format!(
+                        "Default certificate file not found: {}",
+                        default_cert.cert_file
+                    )
+                })?;
+                std::fs::metadata(&default_cert.key_file).with_context(|| {
+                    format!("Default key file not found: {}", default_cert.key_file)
+                })?;
+                default_cert.validate_client_auth("default")?;
+                default_cert.validate_ocsp_file("default")?;
+                default_cert
+                    .resolved_key_passphrase()
+                    .context("tls.default")?;
+            }
+
+            for (domain, cert_config) in &self.tls.certs {
+                std::fs::metadata(&cert_config.cert_file).with_context(|| {
+                    format!(
+                        "Certificate file not found for {}: {}",
+                        domain, cert_config.cert_file
+                    )
+                })?;
+                std::fs::metadata(&cert_config.key_file).with_context(|| {
+                    format!(
+                        "Key file not found for {}: {}",
+                        domain, cert_config.key_file
+                    )
+                })?;
+                cert_config.validate_client_auth(domain)?;
+                cert_config.validate_ocsp_file(domain)?;
+                cert_config
+                    .resolved_key_passphrase()
+                    .with_context(|| format!("tls.certs.{}", domain))?;
+            }
         }
 
-        for (domain, cert_config) in &self.tls.certs {
-            std::fs::metadata(&cert_config.cert_file).with_context(|| {
-                format!(
-                    "Certificate file not found for {}: {}",
-                    domain, cert_config.cert_file
-                )
-            })?;
-            std::fs::metadata(&cert_config.key_file).with_context(|| {
-                format!(
-                    "Key file not found for {}: {}",
-                    domain, cert_config.key_file
-                )
-            })?;
+        // QUIC requires the client and server to agree on an ALPN protocol
+        if self.servers.doq.enabled && self.tls.alpn.doq.is_empty() {
+            anyhow::bail!("tls.alpn.doq must list at least one protocol");
+        }
+        if self.servers.doh3.enabled && self.tls.alpn.doh3.is_empty() {
+            anyhow::bail!("tls.alpn.doh3 must list at least one protocol");
+        }
+        if self.tls.session_resumption && self.tls.session_ticket_lifetime_secs == 0 {
+            anyhow::bail!(
+                "tls.session_ticket_lifetime_secs must be greater than 0 when session resumption is enabled"
+            );
+        }
+        if !matches!(self.logging.access_log_format.as_str(), "combined" | "json") {
+            anyhow::bail!(
+                "Invalid logging.access_log_format: {} (expected combined or json)",
+                self.logging.access_log_format
+            );
         }
 
         // Validate rewrite configuration
-        if self.rewrite.base_domains.is_empty() {
-            anyhow::bail!("At least one base domain must be configured for SNI rewriting");
+        if !matches!(
+            self.rewrite.rewrite_failure_strategy.as_str(),
+            "error" | "passthrough" | "passthrough_warn"
+        ) {
+            anyhow::bail!(
+                "Invalid rewrite.rewrite_failure_strategy: {} (expected error, passthrough or passthrough_warn)",
+                self.rewrite.rewrite_failure_strategy
+            );
+        }
+        match self.rewrite.rewriter_type.as_str() {
+            "base" => {
+                if self.rewrite.base_domains.is_empty() && self.rewrite.targets.is_empty() {
+                    anyhow::bail!("At least one base domain must be configured for SNI rewriting");
+                }
+
+                if !self.rewrite.base_domains.is_empty()
+                    && !self.rewrite.target_suffix.starts_with('.')
+                {
+                    anyhow::bail!("Target suffix must start with '.' (e.g., '.example.cn')");
+                }
+                for target in &self.rewrite.targets {
+                    if target.base_domain.is_empty() {
+                        anyhow::bail!("rewrite.targets entries must set a base_domain");
+                    }
+                    if !target.target_suffix.starts_with('.') {
+                        anyhow::bail!(
+                            "Target suffix of base domain {} must start with '.' (e.g., '.example.cn')",
+                            target.base_domain
+                        );
+                    }
+                }
+            }
+            "regex" => {
+                if self.rewrite.rules.is_empty() {
+                    anyhow::bail!(
+                        "rewrite.rules must list at least one rule for the regex rewriter"
+                    );
+                }
+                RegexSniRewriter::new(&self.rewrite)?;
+            }
+            "static_map" => {
+                StaticMapRewriter::new(&self.rewrite)?;
+            }
+            other => anyhow::bail!(
+                "Invalid rewrite.rewriter_type: {} (expected base, regex or static_map)",
+                other
+            ),
         }
 
-        if !self.rewrite.target_suffix.starts_with('.') {
-            anyhow::bail!("Target suffix must start with '.' (e.g., '.example.cn')");
+        if self.upstream.default.is_empty() {
+            anyhow::bail!("upstream.default must list at least one upstream");
+        }
+        self.udp_upstreams()?;
+
+        if let Some(ca_file) = &self.upstream.upstream_ca_file {
+            std::fs::metadata(ca_file)
+                .with_context(|| format!("Upstream CA file not found: {}", ca_file))?;
+        }
+
+        if self.upstream.upstream_timeout_ms == 0 {
+            anyhow::bail!("upstream.upstream_timeout_ms must be greater than 0");
+        }
+        if self.upstream.health_check_interval_secs > 0
+            && self.upstream.health_check_max_interval_secs
+                < self.upstream.health_check_interval_secs
+        {
+            anyhow::bail!(
+                "upstream.health_check_max_interval_secs must not be less than upstream.health_check_interval_secs"
+            );
+        }
+        if self.upstream.pool.keepalive_secs == 0 {
+            anyhow::bail!("upstream.pool.keepalive_secs must be greater than 0");
+        }
+        if self.upstream.pool.connect_timeout_secs == 0 {
+            anyhow::bail!("upstream.pool.connect_timeout_secs must be greater than 0");
+        }
+        if self.upstream.dot_pool.idle_timeout_secs == 0 {
+            anyhow::bail!("upstream.dot_pool.idle_timeout_secs must be greater than 0");
+        }
+        if self.upstream.doq_pool.idle_timeout_secs == 0 {
+            anyhow::bail!("upstream.doq_pool.idle_timeout_secs must be greater than 0");
+        }
+        if self.upstream.doq_pool.max_per_upstream == 0 {
+            anyhow::bail!("upstream.doq_pool.max_per_upstream must be greater than 0");
+        }
+        let doq_pool = &self.upstream.doq_pool;
+        if doq_pool.keep_alive_interval_secs > 0
+            && doq_pool.keep_alive_interval_secs >= doq_pool.idle_timeout_secs
+        {
+            anyhow::bail!(
+                "upstream.doq_pool.keep_alive_interval_secs must be less than upstream.doq_pool.idle_timeout_secs"
+            );
+        }
+        let circuit_breaker = &self.upstream.circuit_breaker;
+        if circuit_breaker.failure_threshold > 0 {
+            if circuit_breaker.failure_window_secs == 0 {
+                anyhow::bail!(
+                    "upstream.circuit_breaker.failure_window_secs must be greater than 0"
+                );
+            }
+            if circuit_breaker.cooldown_secs == 0 {
+                anyhow::bail!("upstream.circuit_breaker.cooldown_secs must be greater than 0");
+            }
+        }
+        self.upstream.resolved_extra_headers()?;
+
+        // Validate upstream protocol ladder
+        for protocol in &self.upstream.protocol_ladder {
+            self.validate_upstream_protocol(protocol, "upstream.protocol_ladder")?;
+        }
+
+        // Validate EDNS configuration
+        if !matches!(self.edns.unknown_options.as_str(), "forward" | "strip") {
+            anyhow::bail!(
+                "Invalid edns.unknown_options: {} (expected 'forward' or 'strip')",
+                self.edns.unknown_options
+            );
+        }
+
+        if self.edns.force_do_bit && self.edns.clear_do_bit {
+            anyhow::bail!("edns.force_do_bit and edns.clear_do_bit cannot both be enabled");
+        }
+
+        // Validate cache configuration
+        self.cache.no_cache_qtype_codes()?;
+        if self.cache.enabled && self.cache.max_entries == 0 {
+            anyhow::bail!("cache.max_entries must be greater than 0 when the cache is enabled");
+        }
+
+        // Validate rate limit allowlist
+        self.ratelimit.allowlist_ips()?;
+
+        // Validate QUIC transport parameters
+        if !(1_000..=600_000).contains(&self.quic.max_idle_timeout_ms) {
+            anyhow::bail!("quic.max_idle_timeout_ms must be between 1000 and 600000");
+        }
+        if !(1..=65_535).contains(&self.quic.max_concurrent_bidi_streams) {
+            anyhow::bail!("quic.max_concurrent_bidi_streams must be between 1 and 65535");
+        }
+        if self.quic.keep_alive_interval_ms >= self.quic.max_idle_timeout_ms {
+            anyhow::bail!("quic.keep_alive_interval_ms must be less than quic.max_idle_timeout_ms");
+        }
+        if self.quic.allow_0rtt && !self.tls.session_resumption {
+            anyhow::bail!("quic.allow_0rtt requires tls.session_resumption");
+        }
+
+        // Validate domain filter patterns; '*' is only allowed as a leading label
+        for pattern in self
+            .filter
+            .allow_domains
+            .iter()
+            .chain(&self.filter.deny_domains)
+        {
+            let rest = pattern.strip_prefix("*.").unwrap_or(pattern);
+            if pattern != "*" && (rest.is_empty() || rest.contains('*')) {
+                anyhow::bail!("Invalid domain pattern in filter: {:?}", pattern);
+            }
         }
 
         Ok(())
     }
+
+    /// Configured upstreams that resolve to a literal socket address
+    /// Hostname-based upstreams are skipped since they can't be compared without DNS
+    fn upstream_socket_addrs(&self) -> Vec<(&'static str, SocketAddr)> {
+        let mut addrs = Vec::new();
+
+        let socket_upstreams = [
+            ("default", &self.upstream.default),
+            ("dot", &self.upstream.dot),
+            ("doq", &self.upstream.doq),
+            ("udp", &self.upstream.udp),
+        ];
+        for (name, upstreams) in socket_upstreams {
+            for upstream in upstreams {
+                if let Ok(addr) = upstream.parse::<SocketAddr>() {
+                    addrs.push((name, addr));
+                }
+            }
+        }
+
+        let url_upstreams = [("doh", &self.upstream.doh), ("doh3", &self.upstream.doh3)];
+        for (name, upstreams) in url_upstreams {
+            for upstream in upstreams {
+                let Ok(uri) = upstream.parse::<hyper::Uri>() else {
+                    continue;
+                };
+                let host = uri.host().unwrap_or_default().trim_matches(['[', ']']);
+                if let Ok(ip) = host.parse::<std::net::IpAddr>() {
+                    addrs.push((name, SocketAddr::new(ip, uri.port_u16().unwrap_or(443))));
+                }
+            }
+        }
+
+        addrs
+    }
+}
+
+/// Record the addresses a server listens on over `transport`, failing when
+/// another server already listens on one of them
+///
+/// A wildcard IPv6 address also takes the IPv4 wildcard address, since it is
+/// bound dual-stack.
+fn claim_ports(
+    ports: &mut std::collections::HashSet<(IpAddr, u16, &'static str)>,
+    socket_addrs: &[SocketAddr],
+    transport: &'static str,
+) -> Result<()> {
+    for socket_addr in socket_addrs {
+        let mut ips = vec![socket_addr.ip()];
+        if socket_addr.ip() == IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED) {
+            ips.push(IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED));
+        }
+        for ip in ips {
+            if !ports.insert((ip, socket_addr.port(), transport)) {
+                anyhow::bail!(
+                    "Port conflict: {} is already used by another server",
+                    socket_addr.port()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse a list of upstream socket addresses, naming the protocol on error
+fn parse_upstream_addrs(protocol: &str, upstreams: &[String]) -> Result<Vec<SocketAddr>> {
+    upstreams
+        .iter()
+        .map(|addr| {
+            addr.parse().map_err(|e| {
+                anyhow::anyhow!("Invalid upstream address for {}: {}: {}", protocol, addr, e)
+            })
+        })
+        .collect()
+}
+
+impl CertificateConfig {
+    /// `key_passphrase` with its `${VAR}` references replaced from the
+    /// environment
+    pub fn resolved_key_passphrase(&self) -> Result<Option<String>> {
+        self.key_passphrase
+            .as_deref()
+            .map(expand_env_vars)
+            .transpose()
+            .context("key_passphrase")
+    }
+
+    /// Check that the OCSP response to staple, if any, exists
+    fn validate_ocsp_file(&self, name: &str) -> Result<()> {
+        if let Some(ocsp_file) = &self.ocsp_file {
+            std::fs::metadata(ocsp_file).with_context(|| {
+                format!(
+                    "OCSP file not found for {} certificate: {}",
+                    name, ocsp_file
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Check that client certificate verification has a CA to verify against
+    fn validate_client_auth(&self, name: &str) -> Result<()> {
+        match &self.ca_file {
+            Some(ca_file) => {
+                std::fs::metadata(ca_file).with_context(|| {
+                    format!("CA file not found for {} certificate: {}", name, ca_file)
+                })?;
+            }
+            None if self.require_client_cert => {
+                anyhow::bail!(
+                    "require_client_cert is set for {} certificate but no ca_file is configured",
+                    name
+                );
+            }
+            None => {}
+        }
+        Ok(())
+    }
 }
 
 impl TlsConfig {
@@ -380,3 +1849,119 @@ impl TlsConfig {
         })
     }
 }
+
+/// Warn through tracing, or on stderr when no subscriber is installed yet
+fn warn_before_logging(message: &str) {
+    if tracing::dispatcher::has_been_set() {
+        tracing::warn!("{}", message);
+    } else {
+        eprintln!("WARNING: {}", message);
+    }
+}
+
+/// Prefix of the environment variables that override config values
+pub const ENV_PREFIX: &str = "DNS_PROXY_";
+
+/// Override config values from `DNS_PROXY_*` environment variables
+///
+/// Env vars take precedence over the config file; lists are comma-separated.
+/// Every valid override is applied; the error lists the invalid ones.
+/// Supported variables:
+/// - `DNS_PROXY_BASE_DOMAINS`, `DNS_PROXY_TARGET_SUFFIX`
+/// - `DNS_PROXY_<SERVER>_ENABLED`, `DNS_PROXY_<SERVER>_BIND_ADDRESS` and
+///   `DNS_PROXY_<SERVER>_PORT`, where `<SERVER>` is `DOT`, `DOH`, `DOQ`, `DOH3`,
+///   `UDP`, `TCP_DNS` or `HEALTHCHECK`
+/// - `DNS_PROXY_UPSTREAM_<PROTOCOL>`, where `<PROTOCOL>` is `DEFAULT`, `DOT`,
+///   `DOH`, `DOQ`, `DOH3` or `UDP`
+/// - `DNS_PROXY_LOG_LEVEL`
+///
+/// The result still needs [`AppConfig::validate`].
+pub fn apply_env_overrides(config: &mut AppConfig) -> Result<()> {
+    let mut invalid = Vec::new();
+    override_list("BASE_DOMAINS", &mut config.rewrite.base_domains);
+    override_value(
+        "TARGET_SUFFIX",
+        &mut config.rewrite.target_suffix,
+        &mut invalid,
+    );
+
+    let servers = &mut config.servers;
+    for (name, server) in [
+        ("DOT", &mut servers.dot),
+        ("DOH", &mut servers.doh),
+        ("DOQ", &mut servers.doq),
+        ("DOH3", &mut servers.doh3),
+        ("UDP", &mut servers.udp),
+        ("TCP_DNS", &mut servers.tcp_dns),
+    ] {
+        override_value(
+            &format!("{}_ENABLED", name),
+            &mut server.enabled,
+            &mut invalid,
+        );
+        override_list(&format!("{}_BIND_ADDRESS", name), &mut server.bind_address);
+        override_value(&format!("{}_PORT", name), &mut server.port, &mut invalid);
+    }
+    let healthcheck = &mut servers.healthcheck;
+    override_value(
+        "HEALTHCHECK_ENABLED",
+        &mut healthcheck.enabled,
+        &mut invalid,
+    );
+    override_list("HEALTHCHECK_BIND_ADDRESS", &mut healthcheck.bind_address);
+    override_value("HEALTHCHECK_PORT", &mut healthcheck.port, &mut invalid);
+
+    let upstream = &mut config.upstream;
+    for (name, upstreams) in [
+        ("DEFAULT", &mut upstream.default),
+        ("DOT", &mut upstream.dot),
+        ("DOH", &mut upstream.doh),
+        ("DOQ", &mut upstream.doq),
+        ("DOH3", &mut upstream.doh3),
+        ("UDP", &mut upstream.udp),
+    ] {
+        override_list(&format!("UPSTREAM_{}", name), upstreams);
+    }
+
+    override_value("LOG_LEVEL", &mut config.logging.level, &mut invalid);
+
+    if !invalid.is_empty() {
+        anyhow::bail!("{}", invalid.join("; "));
+    }
+    Ok(())
+}
+
+fn env_override(name: &str) -> Option<(String, String)> {
+    let var = format!("{}{}", ENV_PREFIX, name);
+    let value = std::env::var(&var).ok()?;
+    Some((var, value))
+}
+
+/// Replace `target` with the parsed value of `DNS_PROXY_<name>`, if set;
+/// an unparseable value is added to `invalid` and leaves `target` unchanged
+fn override_value<T>(name: &str, target: &mut T, invalid: &mut Vec<String>)
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    let Some((var, value)) = env_override(name) else {
+        return;
+    };
+    match value.trim().parse() {
+        Ok(parsed) => *target = parsed,
+        Err(e) => invalid.push(format!("invalid value {:?} for {}: {}", value, var, e)),
+    }
+}
+
+/// Replace `target` with the comma-separated entries of `DNS_PROXY_<name>`,
+/// if set
+fn override_list(name: &str, target: &mut Vec<String>) {
+    if let Some((_, value)) = env_override(name) {
+        *target = value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(String::from)
+            .collect();
+    }
+}
@@ -0,0 +1,63 @@
+//! Minimal DER reader for the few ASN.1 structures parsed by hand (OCSP
+//! responses, encrypted private keys)
+
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_ENUMERATED: u8 = 0x0a;
+pub const TAG_GENERALIZED_TIME: u8 = 0x18;
+pub const TAG_SEQUENCE: u8 = 0x30;
+
+/// Read the next element of `input`, returning its tag and contents
+pub fn read_tlv<'a>(input: &mut &'a [u8]) -> Result<(u8, &'a [u8]), String> {
+    let truncated = || "truncated DER element".to_string();
+    let (&tag, rest) = input.split_first().ok_or_else(truncated)?;
+    let (&first, mut rest) = rest.split_first().ok_or_else(truncated)?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        // Long form: the low bits count the length bytes that follow
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return Err("invalid DER length".to_string());
+        }
+        let (len_bytes, after) = rest.split_at(count);
+        rest = after;
+        len_bytes
+            .iter()
+            .fold(0usize, |len, &byte| (len << 8) | byte as usize)
+    };
+    if rest.len() < len {
+        return Err(truncated());
+    }
+    let (contents, rest) = rest.split_at(len);
+    *input = rest;
+    Ok((tag, contents))
+}
+
+/// Read the next element of `input`, which must be tagged `tag`; `field`
+/// names it in the error
+pub fn expect<'a>(input: &mut &'a [u8], tag: u8, field: &str) -> Result<&'a [u8], String> {
+    match read_tlv(input)? {
+        (found, contents) if found == tag => Ok(contents),
+        _ => Err(format!("malformed {}", field)),
+    }
+}
+
+/// Value of a non-negative `INTEGER` that fits in a `u32`
+pub fn read_u32(contents: &[u8]) -> Result<u32, String> {
+    // A leading zero byte only marks the value as positive
+    let digits = match contents {
+        [0, rest @ ..] => rest,
+        _ if contents.first().is_some_and(|&byte| byte & 0x80 != 0) => {
+            return Err("negative INTEGER".to_string());
+        }
+        _ => contents,
+    };
+    if digits.len() > 4 {
+        return Err("INTEGER out of range".to_string());
+    }
+    Ok(digits
+        .iter()
+        .fold(0u32, |value, &byte| (value << 8) | byte as u32))
+}
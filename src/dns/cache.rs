@@ -0,0 +1,258 @@
+//! Response cache for the UDP, TCP, DoQ, DoH and DoH3 listeners
+//!
+//! Answers are keyed by the query's first question (QNAME, QTYPE, QCLASS), its
+//! DO and CD bits and whether it has an OPT record, since upstreams answer
+//! those differently (signatures, unvalidated data, EDNS). The key is taken
+//! from the wire-format message, so a DoH query shares its entry whether it
+//! came as a `GET` or a `POST`, and EDNS padding does not split entries. They are kept for
+//! the smallest TTL among their records (for SOA records, also their MINIMUM
+//! field, so negative answers expire per RFC 2308). A cached answer is served
+//! with the client's message ID and question, and every TTL reduced by the
+//! time it spent in the cache.
+
+use super::{
+    FLAG_CD, HEADER_LEN, Question, SectionCounts, edns, parse_question, read_u16, skip_name,
+};
+use crate::config::CacheConfig;
+use crate::error::DnsProxyResult;
+use crate::metrics::Metrics;
+use bytes::Bytes;
+use dashmap::DashMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// SOA resource record type
+const SOA_RR_TYPE: u16 = 6;
+
+/// NXDOMAIN response code
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// What a cached answer is looked up by
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CacheKey {
+    /// Upstream the answer came from, for listeners whose upstream depends
+    /// on more than the question; empty otherwise
+    scope: String,
+    question: Question,
+    /// Whether the query has an OPT record
+    edns: bool,
+    /// DO (DNSSEC OK) bit of the query's OPT record
+    dnssec_ok: bool,
+    /// CD (checking disabled) header flag of the query
+    checking_disabled: bool,
+}
+
+impl CacheKey {
+    /// Key of a query with the given first question, answered by `scope`
+    fn of(scope: &str, query: &[u8], question: Question) -> Self {
+        let opt = edns::find_opt(query);
+        Self {
+            scope: scope.to_string(),
+            question,
+            edns: opt.is_some(),
+            dnssec_ok: opt.is_some_and(|opt| edns::do_bit(query, &opt)),
+            checking_disabled: query[3] & FLAG_CD != 0,
+        }
+    }
+}
+
+/// A cached upstream answer
+struct CacheEntry {
+    response: Vec<u8>,
+    stored: Instant,
+    ttl: Duration,
+}
+
+/// Upstream answers keyed by question, expiring with their TTL
+pub struct ResponseCache {
+    entries: DashMap<CacheKey, CacheEntry>,
+    enabled: bool,
+    max_entries: usize,
+    no_cache_qtypes: Vec<u16>,
+}
+
+impl ResponseCache {
+    /// Create a cache from the `[cache]` config section
+    ///
+    /// Invalid `no_cache_qtypes` are skipped; `AppConfig::validate` rejects them.
+    pub fn from_config(config: &CacheConfig) -> Self {
+        Self {
+            entries: DashMap::new(),
+            enabled: config.enabled,
+            max_entries: config.max_entries,
+            no_cache_qtypes: config.no_cache_qtype_codes().unwrap_or_default(),
+        }
+    }
+
+    /// Answer `query` from the cache, or run `forward` and cache its answer
+    ///
+    /// Hits and misses are counted in `metrics`; queries the cache doesn't
+    /// handle (cache disabled, no question, a `no_cache_qtypes` type) are
+    /// forwarded without counting either.
+    pub async fn get_or_forward<F>(
+        &self,
+        query: &[u8],
+        metrics: &Metrics,
+        forward: F,
+    ) -> DnsProxyResult<Bytes>
+    where
+        F: Future<Output = DnsProxyResult<Bytes>>,
+    {
+        self.get_or_forward_from("", query, metrics, forward).await
+    }
+
+    /// [`get_or_forward`](Self::get_or_forward) for a query sent to
+    /// `upstream`, whose answers are kept apart from other upstreams'
+    ///
+    /// Errors of `forward` are passed through and not cached.
+    pub async fn get_or_forward_from<F, E>(
+        &self,
+        upstream: &str,
+        query: &[u8],
+        metrics: &Metrics,
+        forward: F,
+    ) -> Result<Bytes, E>
+    where
+        F: Future<Output = Result<Bytes, E>>,
+    {
+        let Some(key) = self.cacheable_key(upstream, query) else {
+            return forward.await;
+        };
+        if let Some(response) = self.lookup(&key, query, Instant::now()) {
+            debug!(
+                "Answering {} (type {}) from the response cache",
+                key.question.qname, key.question.qtype
+            );
+            metrics.record_cache_hit();
+            return Ok(Bytes::from(response));
+        }
+
+        metrics.record_cache_miss();
+        let response = forward.await?;
+        self.store(key, &response, Instant::now());
+        Ok(response)
+    }
+
+    /// The key to cache `query` under, if it may be cached
+    fn cacheable_key(&self, scope: &str, query: &[u8]) -> Option<CacheKey> {
+        if !self.enabled {
+            return None;
+        }
+        let question = parse_question(query).ok()?;
+        (!self.no_cache_qtypes.contains(&question.qtype))
+            .then(|| CacheKey::of(scope, query, question))
+    }
+
+    /// Cached answer for `key` as of `now`, rewritten for `query`
+    fn lookup(&self, key: &CacheKey, query: &[u8], now: Instant) -> Option<Vec<u8>> {
+        let (mut response, age) = {
+            let entry = self.entries.get(key)?;
+            let age = now.saturating_duration_since(entry.stored);
+            if age >= entry.ttl {
+                drop(entry);
+                self.entries
+                    .remove_if(key, |_, entry| now >= entry.stored + entry.ttl);
+                return None;
+            }
+            (entry.response.clone(), age)
+        };
+
+        response[..2].copy_from_slice(&query[..2]);
+        // Echo the client's question: its QNAME may differ in case from the
+        // one the answer was cached for
+        let question_end = skip_name(query, HEADER_LEN)? + 4;
+        if skip_name(&response, HEADER_LEN)? + 4 == question_end {
+            response[HEADER_LEN..question_end].copy_from_slice(&query[HEADER_LEN..question_end]);
+        }
+        let elapsed = u32::try_from(age.as_secs()).unwrap_or(u32::MAX);
+        for offset in record_ttl_offsets(&response)? {
+            let ttl = read_u32(&response, offset)?.saturating_sub(elapsed);
+            response[offset..offset + 4].copy_from_slice(&ttl.to_be_bytes());
+        }
+        Some(response)
+    }
+
+    /// Cache a successful (NOERROR or NXDOMAIN), untruncated answer
+    fn store(&self, key: CacheKey, response: &[u8], now: Instant) {
+        if response.len() < HEADER_LEN
+            || response[2] & 0x80 == 0
+            || response[2] & super::FLAG_TC != 0
+            || !matches!(response[3] & 0x0F, 0 | RCODE_NXDOMAIN)
+        {
+            return;
+        }
+        let Some(ttl) = min_ttl(response).filter(|ttl| *ttl > 0) else {
+            return;
+        };
+
+        if self.entries.len() >= self.max_entries && !self.entries.contains_key(&key) {
+            self.entries
+                .retain(|_, entry| now < entry.stored + entry.ttl);
+            if self.entries.len() >= self.max_entries {
+                // Still full of live answers: make room by dropping any one
+                let victim = self.entries.iter().next().map(|entry| entry.key().clone());
+                if let Some(victim) = victim {
+                    self.entries.remove(&victim);
+                }
+            }
+        }
+
+        self.entries.insert(
+            key,
+            CacheEntry {
+                response: response.to_vec(),
+                stored: now,
+                ttl: Duration::from_secs(ttl.into()),
+            },
+        );
+    }
+}
+
+/// Smallest TTL in a response, counting SOA MINIMUM fields; `None` when it
+/// has no records or cannot be parsed
+fn min_ttl(msg: &[u8]) -> Option<u32> {
+    let mut min = None;
+    for offset in record_ttl_offsets(msg)? {
+        let mut ttl = read_u32(msg, offset)?;
+        if read_u16(msg, offset - 4)? == SOA_RR_TYPE {
+            // MINIMUM is the last field of the SOA RDATA
+            let rdata_end = offset + 6 + read_u16(msg, offset + 4)? as usize;
+            ttl = ttl.min(read_u32(msg, rdata_end.checked_sub(4)?)?);
+        }
+        min = Some(min.map_or(ttl, |min: u32| min.min(ttl)));
+    }
+    min
+}
+
+/// Offsets of the TTL field of every record in a message except OPT, whose
+/// TTL field holds EDNS flags
+fn record_ttl_offsets(msg: &[u8]) -> Option<Vec<usize>> {
+    let counts = SectionCounts::parse(msg)?;
+    let mut pos = HEADER_LEN;
+    for _ in 0..counts.qdcount {
+        pos = skip_name(msg, pos)? + 4;
+    }
+
+    let records = counts.ancount as u32 + counts.nscount as u32 + counts.arcount as u32;
+    let mut offsets = Vec::new();
+    for _ in 0..records {
+        pos = skip_name(msg, pos)?;
+        let rtype = read_u16(msg, pos)?;
+        let rdlength = read_u16(msg, pos + 8)? as usize;
+        if rtype != edns::OPT_RR_TYPE {
+            offsets.push(pos + 4);
+        }
+        pos += 10 + rdlength;
+        if pos > msg.len() {
+            return None;
+        }
+    }
+    Some(offsets)
+}
+
+/// Read a big-endian u32 at the given offset
+fn read_u32(msg: &[u8], pos: usize) -> Option<u32> {
+    let bytes = msg.get(pos..pos + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
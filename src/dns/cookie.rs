@@ -0,0 +1,238 @@
+//! DNS Cookies (RFC 7873) for the UDP listener
+//!
+//! The source address of a UDP query is easily spoofed. A valid server cookie
+//! proves the client received an earlier answer at that address, so requiring
+//! one keeps the proxy from being used to reflect answers at a victim.
+//!
+//! Server cookies use the interoperable format of RFC 9018: a version byte,
+//! three reserved bytes, a timestamp and a SipHash-2-4 of the client cookie,
+//! those fields and the client IP, keyed by a secret generated at startup.
+
+use super::edns::{self, EdnsOption, OPTION_COOKIE};
+use super::{RCODE_REFUSED, error_response};
+use siphasher::sip::SipHasher24;
+use std::hash::Hasher;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Length of a client cookie
+pub const CLIENT_COOKIE_LEN: usize = 8;
+
+/// Length of the server cookies the proxy issues (RFC 9018)
+pub const SERVER_COOKIE_LEN: usize = 16;
+
+/// Server cookie version defined by RFC 9018
+const COOKIE_VERSION: u8 = 1;
+
+/// Seconds a server cookie stays valid after it was issued
+const COOKIE_LIFETIME_SECS: u32 = 3600;
+
+/// Seconds a server cookie's timestamp may be ahead of the clock
+const MAX_CLOCK_SKEW_SECS: u32 = 300;
+
+/// FORMERR response code
+const RCODE_FORMERR: u8 = 1;
+
+/// BADCOOKIE extended response code
+const RCODE_BADCOOKIE: u16 = 23;
+
+/// Outcome of checking a query's cookie
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CookieCheck {
+    /// The query carries a valid server cookie. Forward `query`, whose COOKIE
+    /// option is removed since the server cookie was issued by the proxy, and
+    /// answer with the COOKIE option data `cookie`.
+    Valid { query: Vec<u8>, cookie: Vec<u8> },
+    /// Answer with `response` without forwarding the query
+    Rejected {
+        response: Vec<u8>,
+        reason: &'static str,
+    },
+}
+
+/// Issues and validates server cookies
+pub struct CookieValidator {
+    secret: (u64, u64),
+}
+
+impl CookieValidator {
+    /// Create a validator with a random secret
+    pub fn new() -> Self {
+        let random = rustls::crypto::aws_lc_rs::default_provider().secure_random;
+        let key = || {
+            let mut bytes = [0u8; 8];
+            random
+                .fill(&mut bytes)
+                .expect("Failed to generate the DNS cookie secret");
+            u64::from_le_bytes(bytes)
+        };
+        Self {
+            secret: (key(), key()),
+        }
+    }
+
+    /// Check the cookie of a query received from `client`
+    ///
+    /// Queries without a COOKIE option are refused, malformed ones get FORMERR,
+    /// and a missing, stale or forged server cookie gets BADCOOKIE carrying a
+    /// fresh server cookie the client can retry with.
+    pub fn check(&self, query: &[u8], client: IpAddr) -> CookieCheck {
+        let reject = |response, reason| CookieCheck::Rejected { response, reason };
+        let Some(opt) = edns::find_opt(query) else {
+            return reject(error_response(query, RCODE_REFUSED), "no cookie");
+        };
+        let Some(options) = edns::parse_options(query, &opt) else {
+            return reject(
+                error_response(query, RCODE_FORMERR),
+                "malformed EDNS options",
+            );
+        };
+        let Some(cookie) = options.iter().find(|o| o.code == OPTION_COOKIE) else {
+            return reject(error_response(query, RCODE_REFUSED), "no cookie");
+        };
+        // A client cookie alone, or followed by an 8 to 32 byte server cookie
+        let len = cookie.data.len();
+        if len != CLIENT_COOKIE_LEN
+            && !(CLIENT_COOKIE_LEN + 8..=CLIENT_COOKIE_LEN + 32).contains(&len)
+        {
+            return reject(error_response(query, RCODE_FORMERR), "malformed cookie");
+        }
+
+        let now = unix_time();
+        let (client_cookie, server_cookie) = cookie.data.split_at(CLIENT_COOKIE_LEN);
+        let reply = [
+            client_cookie,
+            &self.server_cookie(client_cookie, client, now),
+        ]
+        .concat();
+        if server_cookie.is_empty() {
+            return reject(badcookie_response(query, &reply), "no server cookie");
+        }
+        if !self.is_valid(client_cookie, server_cookie, client, now) {
+            return reject(badcookie_response(query, &reply), "invalid server cookie");
+        }
+
+        let kept: Vec<EdnsOption<'_>> = options
+            .iter()
+            .copied()
+            .filter(|o| o.code != OPTION_COOKIE)
+            .collect();
+        CookieCheck::Valid {
+            query: edns::replace_opt_rdata(query, &opt, &edns::encode_options(&kept)),
+            cookie: reply,
+        }
+    }
+
+    /// Whether a server cookie was issued by this validator to `client` and
+    /// is still fresh
+    fn is_valid(
+        &self,
+        client_cookie: &[u8],
+        server_cookie: &[u8],
+        client: IpAddr,
+        now: u32,
+    ) -> bool {
+        if server_cookie.len() != SERVER_COOKIE_LEN || server_cookie[0] != COOKIE_VERSION {
+            return false;
+        }
+        let timestamp = u32::from_be_bytes([
+            server_cookie[4],
+            server_cookie[5],
+            server_cookie[6],
+            server_cookie[7],
+        ]);
+        // Serial number arithmetic (RFC 1982), as RFC 9018 requires
+        let fresh = now.wrapping_sub(timestamp) <= COOKIE_LIFETIME_SECS
+            || timestamp.wrapping_sub(now) <= MAX_CLOCK_SKEW_SECS;
+        fresh && server_cookie[8..] == self.hash(client_cookie, &server_cookie[..8], client)
+    }
+
+    /// Server cookie for a client cookie and address, issued at `now`
+    fn server_cookie(
+        &self,
+        client_cookie: &[u8],
+        client: IpAddr,
+        now: u32,
+    ) -> [u8; SERVER_COOKIE_LEN] {
+        let mut cookie = [0u8; SERVER_COOKIE_LEN];
+        cookie[0] = COOKIE_VERSION;
+        cookie[4..8].copy_from_slice(&now.to_be_bytes());
+        let hash = self.hash(client_cookie, &cookie[..8], client);
+        cookie[8..].copy_from_slice(&hash);
+        cookie
+    }
+
+    /// SipHash-2-4 of the client cookie, the server cookie's version,
+    /// reserved and timestamp fields, and the client IP
+    fn hash(&self, client_cookie: &[u8], header: &[u8], client: IpAddr) -> [u8; 8] {
+        let mut hasher = SipHasher24::new_with_keys(self.secret.0, self.secret.1);
+        hasher.write(client_cookie);
+        hasher.write(header);
+        match client.to_canonical() {
+            IpAddr::V4(ip) => hasher.write(&ip.octets()),
+            IpAddr::V6(ip) => hasher.write(&ip.octets()),
+        }
+        hasher.finish().to_le_bytes()
+    }
+}
+
+impl Default for CookieValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Copy of a response with its COOKIE option set to `cookie`, adding an OPT
+/// record if it has none
+///
+/// Returns the response unchanged if it cannot be parsed.
+pub fn set_cookie(response: &[u8], cookie: &[u8]) -> Vec<u8> {
+    with_cookie(response, cookie).unwrap_or_else(|| response.to_vec())
+}
+
+fn with_cookie(msg: &[u8], cookie: &[u8]) -> Option<Vec<u8>> {
+    let appended;
+    let (msg, opt) = match edns::find_opt(msg) {
+        Some(opt) => (msg, opt),
+        None => {
+            appended = edns::append_opt(msg, 0)?;
+            let opt = edns::find_opt(&appended)?;
+            (appended.as_slice(), opt)
+        }
+    };
+
+    let mut options: Vec<EdnsOption<'_>> = edns::parse_options(msg, &opt)?
+        .into_iter()
+        .filter(|o| o.code != OPTION_COOKIE)
+        .collect();
+    options.push(EdnsOption {
+        code: OPTION_COOKIE,
+        data: cookie,
+    });
+    Some(edns::replace_opt_rdata(
+        msg,
+        &opt,
+        &edns::encode_options(&options),
+    ))
+}
+
+/// BADCOOKIE answer to a query, carrying the COOKIE option data `cookie`
+///
+/// BADCOOKIE is an extended RCODE: its upper bits go in the OPT record.
+fn badcookie_response(query: &[u8], cookie: &[u8]) -> Vec<u8> {
+    let response = error_response(query, (RCODE_BADCOOKIE & 0x0F) as u8);
+    let Some(mut response) = with_cookie(&response, cookie) else {
+        return error_response(query, RCODE_REFUSED);
+    };
+    if let Some(opt) = edns::find_opt(&response) {
+        response[opt.flags_offset() - 2] = (RCODE_BADCOOKIE >> 4) as u8;
+    }
+    response
+}
+
+/// Current time in seconds since the Unix epoch, as a serial number
+fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as u32)
+}
@@ -0,0 +1,251 @@
+//! EDNS(0) OPT pseudo-RR handling (RFC 6891)
+//!
+//! Locates the OPT record in the additional section of a query and rewrites
+//! its options and DO flag according to the configured policy before forwarding.
+
+use super::{SectionCounts, additional_section_offset, read_u16, skip_name, skip_record};
+use crate::config::EdnsConfig;
+use std::borrow::Cow;
+
+/// Resource record type of the OPT pseudo-RR
+pub const OPT_RR_TYPE: u16 = 41;
+
+/// DNSSEC OK flag in the OPT record's extended flags (RFC 3225)
+pub const DO_BIT: u16 = 0x8000;
+
+/// UDP payload size advertised by an OPT record the proxy adds itself
+pub const DEFAULT_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// DNS Cookie option (RFC 7873)
+pub const OPTION_COOKIE: u16 = 10;
+/// edns-tcp-keepalive option (RFC 7828)
+pub const OPTION_KEEPALIVE: u16 = 11;
+/// Padding option (RFC 7830)
+pub const OPTION_PADDING: u16 = 12;
+
+/// Option codes the proxy treats as well-known; everything else is "unknown"
+/// NSID, DAU, DHU, N3U, client subnet, expire, cookie, keepalive, padding,
+/// chain, key tag and extended DNS errors
+pub const KNOWN_OPTION_CODES: &[u16] = &[
+    3,
+    5,
+    6,
+    7,
+    8,
+    9,
+    OPTION_COOKIE,
+    OPTION_KEEPALIVE,
+    OPTION_PADDING,
+    13,
+    14,
+    15,
+];
+
+/// Location of the OPT record inside a DNS message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OptRecord {
+    /// Offset of the record's owner name
+    pub start: usize,
+    /// Offset of the RDATA (the option list)
+    pub rdata_start: usize,
+    /// Length of the RDATA in bytes
+    pub rdata_len: usize,
+}
+
+impl OptRecord {
+    /// Offset of the 16-bit extended flags field (the low half of the TTL)
+    pub fn flags_offset(&self) -> usize {
+        self.rdata_start - 4
+    }
+}
+
+/// A single EDNS option borrowed from a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdnsOption<'a> {
+    pub code: u16,
+    pub data: &'a [u8],
+}
+
+/// Whether an option code is one the proxy knows about
+pub fn is_known_option(code: u16) -> bool {
+    KNOWN_OPTION_CODES.contains(&code)
+}
+
+/// Find the OPT record in the additional section, if any
+pub fn find_opt(msg: &[u8]) -> Option<OptRecord> {
+    let counts = SectionCounts::parse(msg)?;
+    let mut pos = additional_section_offset(msg, &counts)?;
+    for _ in 0..counts.arcount {
+        let name_end = skip_name(msg, pos)?;
+        let end = skip_record(msg, pos)?;
+        if read_u16(msg, name_end)? == OPT_RR_TYPE {
+            return Some(OptRecord {
+                start: pos,
+                rdata_start: name_end + 10,
+                rdata_len: end - (name_end + 10),
+            });
+        }
+        pos = end;
+    }
+    None
+}
+
+/// Parse the option list of an OPT record
+/// Returns `None` if the RDATA is malformed
+pub fn parse_options<'a>(msg: &'a [u8], opt: &OptRecord) -> Option<Vec<EdnsOption<'a>>> {
+    let rdata = msg.get(opt.rdata_start..opt.rdata_start + opt.rdata_len)?;
+    let mut options = Vec::new();
+    let mut pos = 0;
+    while pos < rdata.len() {
+        let code = read_u16(rdata, pos)?;
+        let len = read_u16(rdata, pos + 2)? as usize;
+        let data = rdata.get(pos + 4..pos + 4 + len)?;
+        options.push(EdnsOption { code, data });
+        pos += 4 + len;
+    }
+    Some(options)
+}
+
+/// Encode an option list back into OPT RDATA
+pub fn encode_options(options: &[EdnsOption<'_>]) -> Vec<u8> {
+    let mut rdata = Vec::with_capacity(options.iter().map(|o| 4 + o.data.len()).sum());
+    for option in options {
+        rdata.extend_from_slice(&option.code.to_be_bytes());
+        rdata.extend_from_slice(&(option.data.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(option.data);
+    }
+    rdata
+}
+
+/// Build a copy of `msg` with the OPT record's RDATA replaced
+pub fn replace_opt_rdata(msg: &[u8], opt: &OptRecord, rdata: &[u8]) -> Vec<u8> {
+    let rdlength_pos = opt.rdata_start - 2;
+    let mut out = Vec::with_capacity(msg.len() - opt.rdata_len + rdata.len());
+    out.extend_from_slice(&msg[..rdlength_pos]);
+    out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    out.extend_from_slice(rdata);
+    out.extend_from_slice(&msg[opt.rdata_start + opt.rdata_len..]);
+    out
+}
+
+/// Whether the query's OPT record has the DO bit set
+/// Returns `false` when there is no OPT record
+pub fn do_bit(msg: &[u8], opt: &OptRecord) -> bool {
+    read_u16(msg, opt.flags_offset()).is_some_and(|flags| flags & DO_BIT != 0)
+}
+
+/// Set or clear the DO bit of a query
+///
+/// Setting the bit on a query without an OPT record appends one advertising
+/// [`DEFAULT_UDP_PAYLOAD_SIZE`]; clearing it on such a query is a no-op.
+/// Returns the message unchanged (borrowed) when the bit already has the
+/// requested value or the message cannot be parsed.
+pub fn set_do_bit(msg: &[u8], enabled: bool) -> Cow<'_, [u8]> {
+    let Some(opt) = find_opt(msg) else {
+        return if enabled {
+            append_opt(msg, DO_BIT).map_or(Cow::Borrowed(msg), Cow::Owned)
+        } else {
+            Cow::Borrowed(msg)
+        };
+    };
+    if do_bit(msg, &opt) == enabled {
+        return Cow::Borrowed(msg);
+    }
+
+    let offset = opt.flags_offset();
+    let flags = read_u16(msg, offset).unwrap_or(0);
+    let flags = if enabled {
+        flags | DO_BIT
+    } else {
+        flags & !DO_BIT
+    };
+    let mut out = msg.to_vec();
+    out[offset..offset + 2].copy_from_slice(&flags.to_be_bytes());
+    Cow::Owned(out)
+}
+
+/// Append an empty OPT record with the given flags and bump ARCOUNT
+/// Returns `None` if the message is malformed or has trailing data
+pub(super) fn append_opt(msg: &[u8], flags: u16) -> Option<Vec<u8>> {
+    let counts = SectionCounts::parse(msg)?;
+    let mut pos = additional_section_offset(msg, &counts)?;
+    for _ in 0..counts.arcount {
+        pos = skip_record(msg, pos)?;
+    }
+    if pos != msg.len() {
+        return None;
+    }
+    let arcount = counts.arcount.checked_add(1)?;
+
+    let mut out = Vec::with_capacity(msg.len() + 11);
+    out.extend_from_slice(msg);
+    out[10..12].copy_from_slice(&arcount.to_be_bytes());
+    out.push(0); // Root owner name
+    out.extend_from_slice(&OPT_RR_TYPE.to_be_bytes());
+    out.extend_from_slice(&DEFAULT_UDP_PAYLOAD_SIZE.to_be_bytes());
+    out.extend_from_slice(&[0, 0]); // Extended RCODE, version
+    out.extend_from_slice(&flags.to_be_bytes());
+    out.extend_from_slice(&[0, 0]); // Empty RDATA
+    Some(out)
+}
+
+/// Apply the configured EDNS policy to a client query before forwarding
+///
+/// Unknown options are stripped first, then the DO bit is forced or cleared.
+/// Returns the message unchanged (borrowed) when no rewrite is needed or the
+/// message cannot be parsed; malformed input is left for the upstream to reject.
+///
+/// Forcing DO can make answers larger than the client asked for. Every
+/// listener is a stream transport (DoT, DoH, DoQ), so those answers are
+/// delivered whole rather than truncated to a UDP buffer.
+pub fn rewrite_query<'a>(msg: &'a [u8], config: &EdnsConfig) -> Cow<'a, [u8]> {
+    let msg = strip_unknown_options(msg, config);
+    if config.force_do_bit {
+        and_then(msg, |m| set_do_bit(m, true))
+    } else if config.clear_do_bit {
+        and_then(msg, |m| set_do_bit(m, false))
+    } else {
+        msg
+    }
+}
+
+/// Apply a further rewrite to a possibly-rewritten message without copying
+/// when neither step changes it
+fn and_then<'a>(msg: Cow<'a, [u8]>, f: impl FnOnce(&[u8]) -> Cow<'_, [u8]>) -> Cow<'a, [u8]> {
+    match msg {
+        Cow::Borrowed(msg) => f(msg),
+        Cow::Owned(msg) => match f(&msg) {
+            Cow::Borrowed(_) => Cow::Owned(msg),
+            Cow::Owned(rewritten) => Cow::Owned(rewritten),
+        },
+    }
+}
+
+/// Remove unknown options when `unknown_options = "strip"`
+fn strip_unknown_options<'a>(msg: &'a [u8], config: &EdnsConfig) -> Cow<'a, [u8]> {
+    if config.unknown_options != "strip" {
+        return Cow::Borrowed(msg);
+    }
+
+    let Some(opt) = find_opt(msg) else {
+        return Cow::Borrowed(msg);
+    };
+    let Some(options) = parse_options(msg, &opt) else {
+        return Cow::Borrowed(msg);
+    };
+
+    let kept: Vec<EdnsOption<'_>> = options
+        .iter()
+        .copied()
+        .filter(|o| is_known_option(o.code))
+        .collect();
+    if kept.len() == options.len() {
+        return Cow::Borrowed(msg);
+    }
+
+    tracing::debug!(
+        "Stripped {} unknown EDNS option(s) from query",
+        options.len() - kept.len()
+    );
+    Cow::Owned(replace_opt_rdata(msg, &opt, &encode_options(&kept)))
+}
@@ -0,0 +1,83 @@
+//! Length-prefixed DNS message framing
+//!
+//! DNS over TCP, DoT (RFC 7858) and DoQ (RFC 9250) prefix every message with
+//! its length as a 2-byte big-endian integer.
+
+use crate::error::{DnsProxyError, DnsProxyResult};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Read one length-prefixed DNS message
+///
+/// Returns `Ok(None)` when the peer closed the stream cleanly before sending
+/// another length prefix, and an `UnexpectedEof` error if it closed mid-message.
+pub async fn read_framed<R>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(len) = read_length(reader).await? else {
+        return Ok(None);
+    };
+    read_message(reader, len).await.map(Some)
+}
+
+/// Read one length-prefixed DNS message from a client, refusing any whose
+/// length prefix exceeds `limit`
+///
+/// An oversized message is rejected with [`DnsProxyError::MessageTooLarge`]
+/// before its body is read or any buffer is allocated for it.
+pub async fn read_framed_limited<R>(reader: &mut R, limit: usize) -> DnsProxyResult<Option<Vec<u8>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(len) = read_length(reader).await? else {
+        return Ok(None);
+    };
+    if len > limit {
+        return Err(DnsProxyError::MessageTooLarge { size: len, limit });
+    }
+    Ok(Some(read_message(reader, len).await?))
+}
+
+/// Read a 2-byte length prefix, or `None` if the stream ended before it
+async fn read_length<R>(reader: &mut R) -> std::io::Result<Option<usize>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut len_buf = [0u8; 2];
+    match reader.read_exact(&mut len_buf[..1]).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    reader.read_exact(&mut len_buf[1..]).await?;
+    Ok(Some(u16::from_be_bytes(len_buf) as usize))
+}
+
+/// Read a message body of `len` bytes
+async fn read_message<R>(reader: &mut R, len: usize) -> std::io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut message = vec![0u8; len];
+    reader.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Write one length-prefixed DNS message and flush the writer
+pub async fn write_framed<W>(writer: &mut W, message: &[u8]) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let len = u16::try_from(message.len()).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("DNS message too large to frame: {} bytes", message.len()),
+        )
+    })?;
+
+    let mut framed = Vec::with_capacity(2 + message.len());
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(message);
+    writer.write_all(&framed).await?;
+    writer.flush().await
+}
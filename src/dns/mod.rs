@@ -0,0 +1,366 @@
+//! DNS wire-format helpers
+//!
+//! Minimal parsing of DNS messages for the forward path. Only the pieces the
+//! proxy needs are implemented; this is not a general purpose DNS library.
+
+pub mod cache;
+pub mod cookie;
+pub mod edns;
+pub mod framing;
+
+use crate::config::FilterConfig;
+use crate::error::{DnsProxyError, DnsProxyResult};
+use std::borrow::Cow;
+
+/// Size of the fixed DNS message header in bytes
+pub const HEADER_LEN: usize = 12;
+
+/// Largest DNS response a UDP client accepts without EDNS (RFC 1035)
+pub const MIN_UDP_PAYLOAD_SIZE: usize = 512;
+
+/// TC (truncated) flag in the third header byte
+pub const FLAG_TC: u8 = 0x02;
+
+/// AD (authenticated data) flag in the fourth header byte
+pub const FLAG_AD: u8 = 0x20;
+
+/// CD (checking disabled) flag in the fourth header byte
+pub const FLAG_CD: u8 = 0x10;
+
+/// NOERROR response code
+const RCODE_NOERROR: u8 = 0;
+
+/// FORMERR response code
+const RCODE_FORMERR: u8 = 1;
+
+/// SERVFAIL response code
+const RCODE_SERVFAIL: u8 = 2;
+
+/// REFUSED response code
+const RCODE_REFUSED: u8 = 5;
+
+/// QUERY and NOTIFY opcodes
+const OPCODE_QUERY: u8 = 0;
+const OPCODE_NOTIFY: u8 = 4;
+
+/// Longest domain name in wire format (RFC 1035 section 2.3.4)
+const MAX_NAME_LEN: usize = 255;
+
+/// First entry of a message's question section
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Question {
+    /// Queried name, lowercased and without the trailing dot ("" for the root)
+    pub qname: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// Check that a received message is long enough to hold a DNS header
+///
+/// Every server calls this before handling a message, so shorter ones (empty
+/// ones included) are never forwarded upstream.
+pub fn validate_message(msg: &[u8]) -> DnsProxyResult<()> {
+    if msg.len() < HEADER_LEN {
+        return Err(DnsProxyError::InvalidInput(format!(
+            "DNS message of {} bytes is shorter than the {} byte header",
+            msg.len(),
+            HEADER_LEN
+        )));
+    }
+    Ok(())
+}
+
+/// Parse the QNAME, QTYPE and QCLASS of the first question in a message
+///
+/// The question is the first name in a message, so there is nothing for a
+/// compression pointer to refer back to; pointers are rejected as malformed.
+pub fn parse_question(msg: &[u8]) -> DnsProxyResult<Question> {
+    let malformed =
+        |reason: &str| DnsProxyError::InvalidInput(format!("Malformed DNS question: {}", reason));
+
+    let counts = SectionCounts::parse(msg).ok_or_else(|| malformed("message too short"))?;
+    if counts.qdcount == 0 {
+        return Err(malformed("no question"));
+    }
+
+    let mut labels = Vec::new();
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *msg.get(pos).ok_or_else(|| malformed("truncated name"))? as usize;
+        if len & 0xC0 != 0 {
+            return Err(malformed("compressed or reserved label in question"));
+        }
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        let label = msg
+            .get(pos..pos + len)
+            .ok_or_else(|| malformed("truncated label"))?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += len;
+        if pos - HEADER_LEN > MAX_NAME_LEN {
+            return Err(malformed("name too long"));
+        }
+    }
+
+    let qtype = read_u16(msg, pos).ok_or_else(|| malformed("missing QTYPE"))?;
+    let qclass = read_u16(msg, pos + 2).ok_or_else(|| malformed("missing QCLASS"))?;
+    Ok(Question {
+        qname: labels.join("."),
+        qtype,
+        qclass,
+    })
+}
+
+/// Parse a query type from its mnemonic (e.g. "TXT"), its RFC 3597 generic
+/// form (e.g. "TYPE65") or a plain number
+pub fn qtype_from_name(name: &str) -> Option<u16> {
+    let upper = name.trim().to_ascii_uppercase();
+    let code = match upper.as_str() {
+        "A" => 1,
+        "NS" => 2,
+        "CNAME" => 5,
+        "SOA" => 6,
+        "PTR" => 12,
+        "MX" => 15,
+        "TXT" => 16,
+        "AAAA" => 28,
+        "SRV" => 33,
+        "NAPTR" => 35,
+        "DS" => 43,
+        "RRSIG" => 46,
+        "NSEC" => 47,
+        "DNSKEY" => 48,
+        "NSEC3" => 50,
+        "TLSA" => 52,
+        "SVCB" => 64,
+        "HTTPS" => 65,
+        "IXFR" => 251,
+        "AXFR" => 252,
+        "ANY" => 255,
+        "CAA" => 257,
+        other => other.strip_prefix("TYPE").unwrap_or(other).parse().ok()?,
+    };
+    Some(code)
+}
+
+/// Largest response a UDP client accepts for this query
+///
+/// This is the OPT record's advertised UDP payload size, or 512 bytes when the
+/// query carries no OPT record. Values below 512 are treated as 512 (RFC 6891).
+pub fn udp_payload_limit(query: &[u8]) -> usize {
+    edns::find_opt(query)
+        .and_then(|opt| read_u16(query, skip_name(query, opt.start)? + 2))
+        .map_or(MIN_UDP_PAYLOAD_SIZE, |size| {
+            (size as usize).max(MIN_UDP_PAYLOAD_SIZE)
+        })
+}
+
+/// Truncated copy of a response: header and question only, with TC set
+///
+/// Clients receiving it over UDP retry the query over TCP.
+pub fn truncate_response(response: &[u8]) -> Vec<u8> {
+    let mut out = header_and_question(response);
+    if let Some(flags) = out.get_mut(2) {
+        *flags |= FLAG_TC;
+    }
+    out
+}
+
+/// Mnemonic of a response's RCODE (e.g. "NOERROR"), for logging
+pub fn rcode_name(response: &[u8]) -> Cow<'static, str> {
+    if response.len() < HEADER_LEN {
+        return Cow::Borrowed("-");
+    }
+    Cow::Borrowed(match response[3] & 0x0F {
+        0 => "NOERROR",
+        RCODE_FORMERR => "FORMERR",
+        RCODE_SERVFAIL => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        RCODE_REFUSED => "REFUSED",
+        rcode => return Cow::Owned(format!("RCODE{}", rcode)),
+    })
+}
+
+/// Whether a response has the AD (authenticated data) bit set, i.e. a
+/// validating upstream checked its DNSSEC signatures
+pub fn is_authenticated(response: &[u8]) -> bool {
+    response.len() >= HEADER_LEN && response[3] & FLAG_AD != 0
+}
+
+/// Whether a query's OPT record has the DO (DNSSEC OK) bit set
+pub fn is_dnssec_ok(query: &[u8]) -> bool {
+    edns::find_opt(query).is_some_and(|opt| edns::do_bit(query, &opt))
+}
+
+/// Whether a message may be processed when received as replayable 0-RTT
+/// data: only QUERY and NOTIFY messages (RFC 9250 section 4.5)
+pub fn is_replayable(msg: &[u8]) -> bool {
+    msg.len() >= HEADER_LEN && matches!((msg[2] >> 3) & 0x0f, OPCODE_QUERY | OPCODE_NOTIFY)
+}
+
+/// FORMERR answer to a malformed query, echoing its ID and question
+///
+/// A query shorter than the header is answered with a bare header carrying
+/// as much of its ID as it had.
+pub fn formerr_response(query: &[u8]) -> Vec<u8> {
+    if query.len() >= HEADER_LEN {
+        return error_response(query, RCODE_FORMERR);
+    }
+    let mut out = vec![0u8; HEADER_LEN];
+    let id_len = query.len().min(2);
+    out[..id_len].copy_from_slice(&query[..id_len]);
+    out[2] = 0x80; // QR
+    out[3] = RCODE_FORMERR;
+    out
+}
+
+/// SERVFAIL answer to a query, echoing its ID and question
+pub fn servfail_response(query: &[u8]) -> Vec<u8> {
+    error_response(query, RCODE_SERVFAIL)
+}
+
+/// REFUSED answer to a query, echoing its ID and question
+pub fn refused_response(query: &[u8]) -> Vec<u8> {
+    error_response(query, RCODE_REFUSED)
+}
+
+/// NOERROR answer to a query without any records, echoing its ID and question
+pub fn empty_response(query: &[u8]) -> Vec<u8> {
+    error_response(query, RCODE_NOERROR)
+}
+
+/// REFUSED answer when the domain filter denies the queried name
+///
+/// A message without a parseable question is only let through when the
+/// filter has no allow-list.
+pub fn refuse_if_denied(query: &[u8], filter: &FilterConfig) -> Option<Vec<u8>> {
+    if filter.allow_domains.is_empty() && filter.deny_domains.is_empty() {
+        return None;
+    }
+    let allowed = match parse_question(query) {
+        Ok(question) => filter.is_allowed(&question.qname),
+        Err(_) => filter.allow_domains.is_empty(),
+    };
+    (!allowed).then(|| refused_response(query))
+}
+
+/// Answer to a query with the given response code and no records
+fn error_response(query: &[u8], rcode: u8) -> Vec<u8> {
+    let mut out = header_and_question(query);
+    if out.len() >= HEADER_LEN {
+        out[2] |= 0x80; // QR
+        out[3] = (out[3] & 0xF0) | rcode;
+    }
+    out
+}
+
+/// Copy of a message's header and question section with the answer,
+/// authority and additional counts cleared
+fn header_and_question(msg: &[u8]) -> Vec<u8> {
+    let Some(counts) = SectionCounts::parse(msg) else {
+        return msg.to_vec();
+    };
+    let mut end = HEADER_LEN;
+    let mut qdcount = 0u16;
+    for _ in 0..counts.qdcount {
+        match skip_name(msg, end).map(|pos| pos + 4) {
+            Some(pos) if pos <= msg.len() => {
+                end = pos;
+                qdcount += 1;
+            }
+            _ => break,
+        }
+    }
+
+    let mut out = msg[..end].to_vec();
+    out[4..6].copy_from_slice(&qdcount.to_be_bytes());
+    out[6..12].fill(0);
+    out
+}
+
+/// Read a big-endian u16 at the given offset
+pub(crate) fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    let bytes = msg.get(pos..pos + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Skip over a (possibly compressed) domain name starting at `pos`
+/// Returns the offset of the first byte after the name
+pub(crate) fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len & 0xC0 {
+            // Uncompressed label (or the root terminator)
+            0x00 => {
+                pos += 1;
+                if len == 0 {
+                    return Some(pos);
+                }
+                pos += len;
+                if pos > msg.len() {
+                    return None;
+                }
+            }
+            // Compression pointer always terminates the name
+            0xC0 => {
+                msg.get(pos + 1)?;
+                return Some(pos + 2);
+            }
+            // Reserved label types
+            _ => return None,
+        }
+    }
+}
+
+/// Skip over a resource record starting at `pos`
+/// Returns the offset of the first byte after the record
+pub(crate) fn skip_record(msg: &[u8], pos: usize) -> Option<usize> {
+    let pos = skip_name(msg, pos)?;
+    let rdlength = read_u16(msg, pos + 8)? as usize;
+    let end = pos + 10 + rdlength;
+    if end > msg.len() {
+        return None;
+    }
+    Some(end)
+}
+
+/// Section record counts from the DNS header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SectionCounts {
+    pub qdcount: u16,
+    pub ancount: u16,
+    pub nscount: u16,
+    pub arcount: u16,
+}
+
+impl SectionCounts {
+    pub(crate) fn parse(msg: &[u8]) -> Option<Self> {
+        if msg.len() < HEADER_LEN {
+            return None;
+        }
+        Some(Self {
+            qdcount: read_u16(msg, 4)?,
+            ancount: read_u16(msg, 6)?,
+            nscount: read_u16(msg, 8)?,
+            arcount: read_u16(msg, 10)?,
+        })
+    }
+}
+
+/// Return the offset where the additional section begins
+pub(crate) fn additional_section_offset(msg: &[u8], counts: &SectionCounts) -> Option<usize> {
+    let mut pos = HEADER_LEN;
+    for _ in 0..counts.qdcount {
+        pos = skip_name(msg, pos)? + 4;
+        if pos > msg.len() {
+            return None;
+        }
+    }
+    for _ in 0..(counts.ancount as u32 + counts.nscount as u32) {
+        pos = skip_record(msg, pos)?;
+    }
+    Some(pos)
+}
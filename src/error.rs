@@ -35,14 +35,64 @@ pub enum DnsProxyError {
     /// Invalid input errors
     #[error("Invalid input: {0}")]
     InvalidInput(String),
+
+    /// A client announced a DNS message larger than `servers.max_message_size`
+    #[error("DNS message of {size} bytes exceeds the {limit} byte limit")]
+    MessageTooLarge { size: usize, limit: usize },
+
+    /// A server's listen address is already held by another socket
+    #[error("{server} server cannot listen on {addr}: address already in use")]
+    AddrInUse {
+        server: String,
+        addr: std::net::SocketAddr,
+    },
+
+    /// A server stopped before it was listening
+    #[error("{server} server failed to start: {reason}")]
+    ServerStart { server: String, reason: String },
 }
 
 /// SNI rewrite specific errors
 #[derive(Error, Debug)]
 pub enum SniRewriteError {
+    /// The client sent an empty SNI or Host
+    #[error("Empty SNI provided for rewrite")]
+    EmptySni,
+
+    /// The base rewriter has no base domains to match
+    #[error("No base domains configured for SNI rewriting")]
+    NoBaseDomains,
+
+    /// The base rewriter's target suffix does not start with '.'
+    #[error("Invalid target suffix: {suffix} (must start with '.')")]
+    InvalidTargetSuffix { suffix: String },
+
     /// No matching base domain found
     #[error("No matching base domain found for hostname: {hostname}")]
     NoMatchingBaseDomain { hostname: String },
+
+    /// The hostname is a base domain itself, with no prefix to keep
+    #[error("No prefix before the base domain in hostname: {hostname}")]
+    NoPrefix { hostname: String },
+
+    /// No regex rule or mapping entry matches the hostname
+    #[error("No rewrite rule matches hostname: {hostname}")]
+    NoMatchingRule { hostname: String },
+}
+
+impl SniRewriteError {
+    /// Label of this failure in the `reason` of
+    /// `dns_proxy_sni_rewrite_failures_total`
+    pub fn reason(&self) -> &'static str {
+        match self {
+            SniRewriteError::EmptySni => "empty_sni",
+            SniRewriteError::NoBaseDomains => "no_base_domains",
+            SniRewriteError::InvalidTargetSuffix { .. } => "invalid_target_suffix",
+            SniRewriteError::NoMatchingBaseDomain { .. } => "no_matching_base_domain",
+            SniRewriteError::NoPrefix { .. } => "no_prefix",
+            SniRewriteError::NoMatchingRule { .. } => "no_matching_rule",
+        }
+    }
 }
 
 /// Certificate-related errors
@@ -52,6 +102,10 @@ pub enum CertificateError {
     #[error("Failed to load certificate from {path}: {reason}")]
     LoadFailed { path: String, reason: String },
 
+    /// Certificate, key or CA file does not exist
+    #[error("Certificate file not found: {path}")]
+    FileNotFound { path: String },
+
     /// Certificate not configured for domain
     #[error("No certificate configured for domain: {domain}")]
     NotConfigured { domain: String },
@@ -63,6 +117,10 @@ pub enum CertificateError {
     /// Private key error
     #[error("Private key error: {reason}")]
     PrivateKey { reason: String },
+
+    /// Client certificate missing or not issued by the configured CA
+    #[error("Client certificate rejected: {reason}")]
+    ClientCertRejected { reason: String },
 }
 
 /// Upstream connection errors
@@ -75,6 +133,29 @@ pub enum UpstreamError {
     /// Request failed
     #[error("Upstream request failed to {upstream}: {reason}")]
     RequestFailed { upstream: String, reason: String },
+
+    /// Upstream did not respond within the configured timeout
+    #[error("Upstream request to {upstream} timed out after {timeout_ms}ms")]
+    Timeout { upstream: String, timeout_ms: u64 },
+
+    /// Upstream's circuit breaker is open after repeated failures
+    #[error("Circuit breaker open for upstream {upstream}, not forwarding")]
+    CircuitOpen { upstream: String },
+}
+
+impl DnsProxyError {
+    /// Whether this error is an upstream timeout
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, DnsProxyError::Upstream(UpstreamError::Timeout { .. }))
+    }
+
+    /// Whether this error is a request refused by an open circuit breaker
+    pub fn is_circuit_open(&self) -> bool {
+        matches!(
+            self,
+            DnsProxyError::Upstream(UpstreamError::CircuitOpen { .. })
+        )
+    }
 }
 
 /// Result type alias for convenience
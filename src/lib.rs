@@ -1,14 +1,23 @@
 pub mod app;
 pub mod config;
+pub mod der;
+pub mod dns;
 pub mod error;
+pub mod logging;
 pub mod metrics;
+pub mod ocsp;
+pub mod private_key;
 pub mod proxy;
+pub mod proxy_protocol;
 pub mod quic;
+pub mod ratelimit;
 pub mod readers;
 pub mod rewrite;
 pub mod rewriters;
 pub mod server;
 pub mod sni;
+#[cfg(feature = "test-util")]
+pub mod testing;
 pub mod tls_utils;
 pub mod upstream;
 pub mod utils;
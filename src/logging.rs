@@ -1,8 +1,23 @@
 use crate::config::LoggingConfig;
-use anyhow::{Context, Result};
+use crate::dns;
+use crate::metrics::{Metrics, RejectReason};
+use anyhow::{Context as _, Result};
+use std::io::Write;
+use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::field::{Empty, Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Span, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::fmt::format::Writer;
 use tracing_subscriber::fmt::time::ChronoUtc;
+use tracing_subscriber::fmt::time::FormatTime;
+use tracing_subscriber::layer::Context;
 use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::{LookupSpan, Registry};
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{EnvFilter, Layer};
 
@@ -16,6 +31,13 @@ pub fn init_logging(
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
         EnvFilter::from_str(&log_level).unwrap_or_else(|_| EnvFilter::new("info"))
     });
+    // Access lines only go to the access log
+    let env_filter = env_filter.add_directive(
+        format!("{}=off", ACCESS_TARGET)
+            .parse()
+            .context("Failed to build the access log filter")?,
+    );
+    let access_layer = access_layer(config)?;
 
     let mut guard: Option<tracing_appender::non_blocking::WorkerGuard> = None;
 
@@ -56,6 +78,7 @@ pub fn init_logging(
                     .with_filter(env_filter);
 
                 tracing_subscriber::registry()
+                    .with(access_layer)
                     .with(file_layer)
                     .with(console_layer)
                     .init();
@@ -79,6 +102,7 @@ pub fn init_logging(
                     .with_filter(env_filter);
 
                 tracing_subscriber::registry()
+                    .with(access_layer)
                     .with(file_layer)
                     .with(console_layer)
                     .init();
@@ -112,6 +136,7 @@ pub fn init_logging(
                     .with_filter(env_filter);
 
                 tracing_subscriber::registry()
+                    .with(access_layer)
                     .with(file_layer)
                     .with(console_layer)
                     .init();
@@ -135,6 +160,7 @@ pub fn init_logging(
                     .with_filter(env_filter);
 
                 tracing_subscriber::registry()
+                    .with(access_layer)
                     .with(file_layer)
                     .with(console_layer)
                     .init();
@@ -143,24 +169,380 @@ pub fn init_logging(
     } else {
         // Console logging only
         if config.json {
-            tracing_subscriber::fmt()
+            let console_layer = tracing_subscriber::fmt::layer()
                 .with_target(true)
                 .with_file(true)
                 .with_line_number(true)
                 .with_timer(ChronoUtc::rfc_3339())
                 .json()
-                .with_env_filter(env_filter)
+                .with_filter(env_filter);
+
+            tracing_subscriber::registry()
+                .with(access_layer)
+                .with(console_layer)
                 .init();
         } else {
-            tracing_subscriber::fmt()
+            let console_layer = tracing_subscriber::fmt::layer()
                 .with_target(true)
                 .with_file(true)
                 .with_line_number(true)
                 .with_timer(ChronoUtc::rfc_3339())
-                .with_env_filter(env_filter)
+                .with_filter(env_filter);
+
+            tracing_subscriber::registry()
+                .with(access_layer)
+                .with(console_layer)
                 .init();
         }
     }
 
     Ok(guard)
 }
+
+/// Dedicated access log layer, writing to `access_log_file` or stdout
+fn access_layer(config: &LoggingConfig) -> Result<Option<Box<dyn Layer<Registry> + Send + Sync>>> {
+    if !config.access_log {
+        return Ok(None);
+    }
+    let json = config.access_log_format == "json";
+    let layer = match &config.access_log_file {
+        Some(log_file) if config.rotation => {
+            let path = std::path::Path::new(log_file);
+            let appender = tracing_appender::rolling::daily(
+                path.parent().unwrap_or_else(|| std::path::Path::new(".")),
+                path.file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or("access.log"),
+            );
+            access_log_layer(json, appender).boxed()
+        }
+        Some(log_file) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(log_file)
+                .with_context(|| format!("Failed to open access log file: {}", log_file))?;
+            access_log_layer(json, Mutex::new(file)).boxed()
+        }
+        None => access_log_layer(json, std::io::stdout).boxed(),
+    };
+    Ok(Some(layer))
+}
+
+/// Record a rejected connection and emit a sampled structured log event
+///
+/// Every rejection is counted in `dns_proxy_rejected_connections_total{reason}`.
+/// The `connection_rejected` event is logged for one in every
+/// `rejected_log_sample_rate` rejections of the same reason.
+pub fn log_rejected_connection(
+    config: &LoggingConfig,
+    metrics: &Metrics,
+    protocol: &str,
+    peer: SocketAddr,
+    reason: RejectReason,
+    detail: &dyn std::fmt::Display,
+) {
+    let count = metrics.record_rejected_connection(reason);
+    let sample_rate = config.rejected_log_sample_rate;
+    if sample_rate == 0 || !(count - 1).is_multiple_of(sample_rate) {
+        return;
+    }
+
+    tracing::warn!(
+        event = "connection_rejected",
+        protocol,
+        peer = %peer,
+        reason = reason.as_str(),
+        count,
+        detail = %detail,
+        "Rejected {} connection from {}: {}",
+        protocol,
+        peer,
+        reason
+    );
+}
+
+/// Span covering one client connection
+///
+/// The TLS SNI is recorded with [`record_sni`] once the handshake completes.
+pub fn connection_span(protocol: &'static str, client: SocketAddr) -> Span {
+    tracing::info_span!("connection", protocol, client = %client, sni = Empty)
+}
+
+/// Record the server name a client asked for (TLS SNI, or the Host header of
+/// an HTTP request) on the current span
+pub fn record_sni(sni: Option<&str>) {
+    if let Some(sni) = sni {
+        Span::current().record("sni", sni);
+    }
+}
+
+/// Span covering one request, with a fresh request ID
+///
+/// Opened inside a [`connection_span`], so every event logged while handling
+/// the request also carries the protocol and client address. Where the
+/// request was sent is recorded with [`record_target`].
+pub fn request_span() -> (String, Span) {
+    let request_id = next_request_id();
+    let span = tracing::info_span!(
+        "request",
+        request_id = %request_id,
+        sni = Empty,
+        target = Empty,
+        dnssec_ok = Empty,
+        authenticated = Empty
+    );
+    (request_id, span)
+}
+
+/// Record the rewritten target or upstream of the current request
+pub fn record_target(target: &dyn std::fmt::Display) {
+    Span::current().record("target", tracing::field::display(target));
+}
+
+/// Record whether the client of the current request set the DO (DNSSEC OK)
+/// bit in its query
+pub fn record_dnssec_ok(query: &[u8]) {
+    Span::current().record("dnssec_ok", dns::is_dnssec_ok(query));
+}
+
+/// Record whether the response to the current request has the AD
+/// (authenticated data) bit set
+pub fn record_authenticated(response: &[u8]) {
+    Span::current().record("authenticated", dns::is_authenticated(response));
+}
+
+/// Request IDs count up from the process start time, so they are unique
+/// within a process and unlikely to repeat across restarts
+fn next_request_id() -> String {
+    static NEXT: LazyLock<AtomicU64> = LazyLock::new(|| {
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or_default();
+        AtomicU64::new(start)
+    });
+    format!("{:016x}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Target of access log events (see [`log_access`])
+pub const ACCESS_TARGET: &str = "access";
+
+/// Emit the access log event of a completed request
+///
+/// Called inside the request span: the access log takes the client, protocol,
+/// host, target and request ID from the enclosing spans.
+pub fn log_access(
+    status: &dyn std::fmt::Display,
+    bytes_in: u64,
+    bytes_out: u64,
+    duration: Duration,
+) {
+    tracing::info!(
+        target: ACCESS_TARGET,
+        status = %status,
+        bytes_in,
+        bytes_out,
+        duration_ms = duration.as_millis() as u64,
+        "access"
+    );
+}
+
+/// Layer writing one line per [`log_access`] event to `make_writer`, as JSON or
+/// in a combined-log-like text format
+pub fn access_log_layer<S, W>(json: bool, make_writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    AccessLogLayer { json, make_writer }.with_filter(tracing_subscriber::filter::filter_fn(
+        |metadata| {
+            metadata.target() == ACCESS_TARGET
+                || (metadata.is_span() && metadata.target() == module_path!())
+        },
+    ))
+}
+
+struct AccessLogLayer<W> {
+    json: bool,
+    make_writer: W,
+}
+
+/// Fields of an access log line, collected from the request's spans and the
+/// access event
+#[derive(Default)]
+struct AccessFields {
+    request_id: Option<String>,
+    protocol: Option<String>,
+    client: Option<String>,
+    sni: Option<String>,
+    target: Option<String>,
+    status: Option<String>,
+    /// DO bit of the query, for DNS transports
+    dnssec_ok: Option<bool>,
+    /// AD bit of the response, for DNS transports
+    authenticated: Option<bool>,
+    bytes_in: u64,
+    bytes_out: u64,
+    duration_ms: u64,
+}
+
+impl AccessFields {
+    /// Take the fields set in `other`, which belongs to a more specific span
+    fn merge(&mut self, other: &AccessFields) {
+        for (field, value) in [
+            (&mut self.request_id, &other.request_id),
+            (&mut self.protocol, &other.protocol),
+            (&mut self.client, &other.client),
+            (&mut self.sni, &other.sni),
+            (&mut self.target, &other.target),
+        ] {
+            if value.is_some() {
+                field.clone_from(value);
+            }
+        }
+        for (field, value) in [
+            (&mut self.dnssec_ok, other.dnssec_ok),
+            (&mut self.authenticated, other.authenticated),
+        ] {
+            if value.is_some() {
+                *field = value;
+            }
+        }
+    }
+
+    fn set(&mut self, name: &str, value: String) {
+        let field = match name {
+            "request_id" => &mut self.request_id,
+            "protocol" => &mut self.protocol,
+            "client" => &mut self.client,
+            "sni" => &mut self.sni,
+            "target" => &mut self.target,
+            "status" => &mut self.status,
+            _ => return,
+        };
+        *field = Some(value);
+    }
+
+    /// Client IP, without the port of the client address
+    fn client_ip(&self) -> Option<String> {
+        let client = self.client.as_deref()?;
+        Some(match client.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip().to_string(),
+            Err(_) => client.to_string(),
+        })
+    }
+
+    fn to_json(&self, timestamp: &str) -> String {
+        serde_json::json!({
+            "timestamp": timestamp,
+            "client": self.client_ip(),
+            "protocol": self.protocol,
+            "host": self.sni,
+            "target": self.target,
+            "status": self.status,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "duration_ms": self.duration_ms,
+            "request_id": self.request_id,
+            "dnssec_ok": self.dnssec_ok,
+            "authenticated": self.authenticated,
+        })
+        .to_string()
+    }
+
+    /// `client - - [time] "protocol host target" status bytes_in bytes_out duration request_id`
+    fn to_combined(&self, timestamp: &str) -> String {
+        let or_dash = |value: Option<&str>| value.unwrap_or("-").to_string();
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {} {} {}ms {}",
+            or_dash(self.client_ip().as_deref()),
+            timestamp,
+            or_dash(self.protocol.as_deref()),
+            or_dash(self.sni.as_deref()),
+            or_dash(self.target.as_deref()),
+            or_dash(self.status.as_deref()),
+            self.bytes_in,
+            self.bytes_out,
+            self.duration_ms,
+            or_dash(self.request_id.as_deref()),
+        )
+    }
+}
+
+impl Visit for AccessFields {
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        match field.name() {
+            "dnssec_ok" => self.dnssec_ok = Some(value),
+            "authenticated" => self.authenticated = Some(value),
+            name => self.set(name, value.to_string()),
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), value.to_string());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "bytes_in" => self.bytes_in = value,
+            "bytes_out" => self.bytes_out = value,
+            "duration_ms" => self.duration_ms = value,
+            name => self.set(name, value.to_string()),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        // Display fields (`%value`) format through Debug without quotes
+        self.set(field.name(), format!("{:?}", value));
+    }
+}
+
+impl<S, W> Layer<S> for AccessLogLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut fields = AccessFields::default();
+            attrs.record(&mut fields);
+            span.extensions_mut().insert(fields);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id)
+            && let Some(fields) = span.extensions_mut().get_mut::<AccessFields>()
+        {
+            values.record(fields);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if event.metadata().target() != ACCESS_TARGET {
+            return;
+        }
+        let mut fields = AccessFields::default();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(span_fields) = span.extensions().get::<AccessFields>() {
+                    fields.merge(span_fields);
+                }
+            }
+        }
+        event.record(&mut fields);
+
+        let mut timestamp = String::new();
+        let line = if self.json {
+            let _ = ChronoUtc::rfc_3339().format_time(&mut Writer::new(&mut timestamp));
+            fields.to_json(&timestamp)
+        } else {
+            let _ = ChronoUtc::new("%d/%b/%Y:%H:%M:%S %z".to_string())
+                .format_time(&mut Writer::new(&mut timestamp));
+            fields.to_combined(&timestamp)
+        };
+        let mut writer = self.make_writer.make_writer();
+        let _ = writeln!(writer, "{}", line);
+    }
+}
@@ -1,22 +1,47 @@
-mod app;
-mod config;
-mod error;
-mod logging;
-mod metrics;
-mod proxy;
-mod quic;
-mod readers;
-mod rewrite;
-mod rewriters;
-mod server;
-mod sni;
-mod tls_utils;
-mod upstream;
-mod utils;
-
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
+use dns_ingress::{app, config, logging};
 use tracing::info;
 
+/// Config file read from the working directory unless `--config` names another
+const CONFIG_PATH: &str = "config.toml";
+
+/// Command-line flags
+#[derive(Debug, Default)]
+struct CliArgs {
+    /// `--config <path>`: config file to load instead of `config.toml`
+    config: Option<String>,
+    /// `--require-config`: fail instead of falling back to defaults when the
+    /// config file is missing
+    require_config: bool,
+    /// `--print-config`: print the effective config and exit
+    print_config: bool,
+    /// `--validate`: load and validate the config, then exit
+    validate: bool,
+}
+
+impl CliArgs {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut cli = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => match args.next() {
+                    Some(path) => cli.config = Some(path),
+                    None => bail!("--config requires a path"),
+                },
+                "--require-config" => cli.require_config = true,
+                "--print-config" => cli.print_config = true,
+                "--validate" => cli.validate = true,
+                _ => match arg.strip_prefix("--config=") {
+                    Some(path) => cli.config = Some(path.to_string()),
+                    None => bail!("Unknown argument: {}", arg),
+                },
+            }
+        }
+        Ok(cli)
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize rustls crypto provider before any TLS operations
@@ -24,13 +49,35 @@ async fn main() -> Result<()> {
         .install_default()
         .map_err(|e| anyhow::anyhow!("Failed to install default crypto provider: {:?}", e))?;
 
+    let cli = CliArgs::parse(std::env::args().skip(1))?;
+
     // Load config first (before logging init) to get logging config
-    let config = config::AppConfig::load_or_default("config.toml");
+    // A missing config file is fatal instead of falling back to defaults when
+    // it was named with --config, or with --require-config or --validate
+    let config_path = cli.config.as_deref().unwrap_or(CONFIG_PATH);
+    let config = if cli.config.is_some() || cli.require_config || cli.validate {
+        config::AppConfig::load(config_path, true)?
+    } else {
+        config::AppConfig::load_or_default(config_path)
+    };
 
     // Validate configuration before starting
     config
         .validate()
-        .context("Configuration validation failed")?;
+        .with_context(|| format!("Configuration validation failed: {}", config_path))?;
+
+    // With --validate, stop here; failures above exit with a nonzero code
+    if cli.validate {
+        println!("Configuration {} is valid", config_path);
+        return Ok(());
+    }
+
+    // With --print-config, dump the effective config (file, defaults and
+    // environment overrides) and exit
+    if cli.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
 
     // Initialize logging system
     let _guard =
@@ -42,19 +89,11 @@ async fn main() -> Result<()> {
         config.logging.level, config.logging.file, config.logging.json
     );
 
-    // Create and start app
-    let mut app = app::App::new(config);
-    app.start().context("Failed to start DNS Proxy Server")?;
-
-    info!("DNS Proxy Server started successfully. Press Ctrl+C to shutdown.");
-
-    // Wait for shutdown signal
-    tokio::signal::ctrl_c()
+    // Run until Ctrl+C or SIGTERM, then shut down gracefully
+    app::App::new(config)
+        .run()
         .await
-        .context("Failed to listen for shutdown signal")?;
-
-    info!("Shutdown signal received, shutting down gracefully...");
-    app.wait_for_shutdown().await;
+        .context("DNS Proxy Server failed")?;
 
     Ok(())
 }
@@ -1,5 +1,14 @@
-use prometheus::{Histogram, HistogramOpts, IntCounter, Opts, Registry};
+use crate::dns;
+use crate::error::SniRewriteError;
+use crate::sni::RewriteResult;
+use dashmap::DashMap;
+use prometheus::core::Collector;
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+};
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 
@@ -14,14 +23,91 @@ pub struct Metrics {
     failed_requests: IntCounter,
     bytes_received: IntCounter,
     bytes_sent: IntCounter,
+    upstream_bytes_sent: IntCounter,
+    upstream_bytes_received: IntCounter,
     sni_rewrites: IntCounter,
+    rewrite_hits: IntCounter,
+    rewrite_misses: IntCounter,
+    rewrite_failures: IntCounterVec,
+    rewrite_passthroughs: IntCounter,
+    rewrite_passthrough_warnings: IntCounterVec,
+    responses_authenticated: IntCounter,
     upstream_errors: IntCounter,
+    upstream_retries: IntCounter,
+    circuit_open: IntCounter,
+    blocked_requests: IntCounter,
+    rate_limited: IntCounter,
+    oversized_rejected: IntCounter,
+    cookie_rejected: IntCounter,
+    dry_run_requests: IntCounter,
+    cache_hits: IntCounter,
+    cache_misses: IntCounter,
+    rejected_connections: IntCounterVec,
+    tls_handshake_errors: IntCounterVec,
+    in_flight_connections: IntGaugeVec,
     processing_time: Histogram,
 
+    // Requests per rewrite target, capped at `MAX_TRACKED_TARGETS`
+    rewrite_targets: Arc<DashMap<String, TargetCount>>,
+    target_clock: Arc<AtomicU64>,
+
+    // SNIs with their own passthrough_warn label, capped at
+    // `MAX_TRACKED_PASSTHROUGH_SNIS`
+    passthrough_snis: Arc<DashMap<String, ()>>,
+
     // Cached snapshot to avoid repeated reads
     cached_snapshot: Arc<RwLock<Option<CachedSnapshot>>>,
 }
 
+/// Reason an incoming connection was rejected before any DNS message was handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    /// TLS or QUIC handshake failed
+    HandshakeFailed,
+    /// TLS or QUIC handshake did not complete in time
+    HandshakeTimeout,
+    /// Client certificate missing or not issued by the configured CA
+    ClientCertRejected,
+    /// PROXY protocol header missing, malformed or not sent in time
+    ProxyProtocolInvalid,
+}
+
+impl RejectReason {
+    /// Label value used for the `reason` metric label and log field
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::HandshakeFailed => "handshake_failed",
+            Self::HandshakeTimeout => "handshake_timeout",
+            Self::ClientCertRejected => "client_cert_rejected",
+            Self::ProxyProtocolInvalid => "proxy_protocol_invalid",
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Most rewrite targets counted at once; the least recently rewritten target
+/// is forgotten to make room for a new one
+pub const MAX_TRACKED_TARGETS: usize = 1024;
+
+/// Most SNIs counted under their own label by the passthrough_warn strategy;
+/// later ones are counted under `OTHER_PASSTHROUGH_SNI`
+pub const MAX_TRACKED_PASSTHROUGH_SNIS: usize = 256;
+
+/// `sni` label of passed-through SNIs beyond `MAX_TRACKED_PASSTHROUGH_SNIS`
+pub const OTHER_PASSTHROUGH_SNI: &str = "other";
+
+/// Requests rewritten to one target and when the last one was
+struct TargetCount {
+    requests: AtomicU64,
+    /// `target_clock` tick of the most recent rewrite
+    last_seen: AtomicU64,
+}
+
 /// Cached snapshot with timestamp
 #[derive(Clone, Debug)]
 struct CachedSnapshot {
@@ -68,18 +154,153 @@ impl Metrics {
             IntCounter::with_opts(Opts::new("dns_proxy_bytes_sent_total", "Total bytes sent"))
                 .expect("Failed to create bytes_sent metric");
 
+        let upstream_bytes_sent = IntCounter::with_opts(Opts::new(
+            "dns_proxy_upstream_bytes_sent_total",
+            "Total bytes of DNS messages sent to upstreams",
+        ))
+        .expect("Failed to create upstream_bytes_sent metric");
+
+        let upstream_bytes_received = IntCounter::with_opts(Opts::new(
+            "dns_proxy_upstream_bytes_received_total",
+            "Total bytes of DNS messages received from upstreams",
+        ))
+        .expect("Failed to create upstream_bytes_received metric");
+
         let sni_rewrites = IntCounter::with_opts(Opts::new(
             "dns_proxy_sni_rewrites_total",
-            "Total number of SNI rewrites",
+            "Total number of SNI rewrites: hits, misses and passthroughs together",
         ))
         .expect("Failed to create sni_rewrites metric");
 
+        let rewrite_hits = IntCounter::with_opts(Opts::new(
+            "dns_proxy_sni_rewrite_hits_total",
+            "Total number of SNIs rewritten to a target hostname",
+        ))
+        .expect("Failed to create rewrite_hits metric");
+
+        let rewrite_misses = IntCounter::with_opts(Opts::new(
+            "dns_proxy_sni_rewrite_misses_total",
+            "Total number of SNIs the rewriter found no target for",
+        ))
+        .expect("Failed to create rewrite_misses metric");
+
+        let rewrite_failures = IntCounterVec::new(
+            Opts::new(
+                "dns_proxy_sni_rewrite_failures_total",
+                "Total number of SNIs the rewriter found no target for, by reason",
+            ),
+            &["reason"],
+        )
+        .expect("Failed to create rewrite_failures metric");
+
+        let rewrite_passthroughs = IntCounter::with_opts(Opts::new(
+            "dns_proxy_sni_rewrite_passthroughs_total",
+            "Total number of SNIs passed through unchanged after a failed rewrite",
+        ))
+        .expect("Failed to create rewrite_passthroughs metric");
+
+        let rewrite_passthrough_warnings = IntCounterVec::new(
+            Opts::new(
+                "dns_proxy_sni_rewrite_passthrough_warnings_total",
+                "Total number of SNIs passed through unchanged by the passthrough_warn strategy, by SNI",
+            ),
+            &["sni"],
+        )
+        .expect("Failed to create rewrite_passthrough_warnings metric");
+
+        let responses_authenticated = IntCounter::with_opts(Opts::new(
+            "dns_proxy_responses_authenticated_total",
+            "Total number of responses with the AD (authenticated data) bit set",
+        ))
+        .expect("Failed to create responses_authenticated metric");
+
         let upstream_errors = IntCounter::with_opts(Opts::new(
             "dns_proxy_upstream_errors_total",
             "Total number of upstream errors",
         ))
         .expect("Failed to create upstream_errors metric");
 
+        let upstream_retries = IntCounter::with_opts(Opts::new(
+            "dns_proxy_upstream_retries_total",
+            "Total number of upstream request retries",
+        ))
+        .expect("Failed to create upstream_retries metric");
+
+        let circuit_open = IntCounter::with_opts(Opts::new(
+            "dns_proxy_circuit_open_total",
+            "Total number of upstream requests failed at once by an open circuit breaker",
+        ))
+        .expect("Failed to create circuit_open metric");
+
+        let blocked_requests = IntCounter::with_opts(Opts::new(
+            "dns_proxy_blocked_requests_total",
+            "Total number of requests refused by the domain filter",
+        ))
+        .expect("Failed to create blocked_requests metric");
+
+        let rate_limited = IntCounter::with_opts(Opts::new(
+            "dns_proxy_rate_limited_total",
+            "Total number of requests or connections rejected by the rate limiter",
+        ))
+        .expect("Failed to create rate_limited metric");
+
+        let oversized_rejected = IntCounter::with_opts(Opts::new(
+            "dns_proxy_oversized_rejected_total",
+            "Total number of client messages rejected for exceeding max_message_size",
+        ))
+        .expect("Failed to create oversized_rejected metric");
+
+        let cookie_rejected = IntCounter::with_opts(Opts::new(
+            "dns_proxy_cookie_rejected_total",
+            "Total number of UDP queries answered without forwarding for lacking a valid DNS cookie",
+        ))
+        .expect("Failed to create cookie_rejected metric");
+
+        let dry_run_requests = IntCounter::with_opts(Opts::new(
+            "dns_proxy_dry_run_requests_total",
+            "Total number of requests answered without forwarding in dry-run mode",
+        ))
+        .expect("Failed to create dry_run_requests metric");
+
+        let cache_hits = IntCounter::with_opts(Opts::new(
+            "dns_proxy_cache_hits_total",
+            "Total number of queries answered from the response cache",
+        ))
+        .expect("Failed to create cache_hits metric");
+
+        let cache_misses = IntCounter::with_opts(Opts::new(
+            "dns_proxy_cache_misses_total",
+            "Total number of cacheable queries not found in the response cache",
+        ))
+        .expect("Failed to create cache_misses metric");
+
+        let rejected_connections = IntCounterVec::new(
+            Opts::new(
+                "dns_proxy_rejected_connections_total",
+                "Total number of rejected connections by reason",
+            ),
+            &["reason"],
+        )
+        .expect("Failed to create rejected_connections metric");
+
+        let tls_handshake_errors = IntCounterVec::new(
+            Opts::new(
+                "dns_proxy_tls_handshake_errors_total",
+                "Total number of TLS or QUIC handshakes with clients that failed or timed out, by protocol",
+            ),
+            &["protocol"],
+        )
+        .expect("Failed to create tls_handshake_errors metric");
+
+        let in_flight_connections = IntGaugeVec::new(
+            Opts::new(
+                "dns_proxy_in_flight_connections",
+                "Number of connections (UDP: queries) each server is currently handling",
+            ),
+            &["server"],
+        )
+        .expect("Failed to create in_flight_connections metric");
+
         let processing_time = Histogram::with_opts(
             HistogramOpts::new(
                 "dns_proxy_processing_time_seconds",
@@ -107,12 +328,72 @@ impl Metrics {
         registry
             .register(Box::new(bytes_sent.clone()))
             .expect("Failed to register bytes_sent metric");
+        registry
+            .register(Box::new(upstream_bytes_sent.clone()))
+            .expect("Failed to register upstream_bytes_sent metric");
+        registry
+            .register(Box::new(upstream_bytes_received.clone()))
+            .expect("Failed to register upstream_bytes_received metric");
         registry
             .register(Box::new(sni_rewrites.clone()))
             .expect("Failed to register sni_rewrites metric");
+        registry
+            .register(Box::new(rewrite_hits.clone()))
+            .expect("Failed to register rewrite_hits metric");
+        registry
+            .register(Box::new(rewrite_misses.clone()))
+            .expect("Failed to register rewrite_misses metric");
+        registry
+            .register(Box::new(rewrite_failures.clone()))
+            .expect("Failed to register rewrite_failures metric");
+        registry
+            .register(Box::new(rewrite_passthroughs.clone()))
+            .expect("Failed to register rewrite_passthroughs metric");
+        registry
+            .register(Box::new(rewrite_passthrough_warnings.clone()))
+            .expect("Failed to register rewrite_passthrough_warnings metric");
+        registry
+            .register(Box::new(responses_authenticated.clone()))
+            .expect("Failed to register responses_authenticated metric");
         registry
             .register(Box::new(upstream_errors.clone()))
             .expect("Failed to register upstream_errors metric");
+        registry
+            .register(Box::new(upstream_retries.clone()))
+            .expect("Failed to register upstream_retries metric");
+        registry
+            .register(Box::new(circuit_open.clone()))
+            .expect("Failed to register circuit_open metric");
+        registry
+            .register(Box::new(blocked_requests.clone()))
+            .expect("Failed to register blocked_requests metric");
+        registry
+            .register(Box::new(rate_limited.clone()))
+            .expect("Failed to register rate_limited metric");
+        registry
+            .register(Box::new(oversized_rejected.clone()))
+            .expect("Failed to register oversized_rejected metric");
+        registry
+            .register(Box::new(cookie_rejected.clone()))
+            .expect("Failed to register cookie_rejected metric");
+        registry
+            .register(Box::new(dry_run_requests.clone()))
+            .expect("Failed to register dry_run_requests metric");
+        registry
+            .register(Box::new(cache_hits.clone()))
+            .expect("Failed to register cache_hits metric");
+        registry
+            .register(Box::new(cache_misses.clone()))
+            .expect("Failed to register cache_misses metric");
+        registry
+            .register(Box::new(rejected_connections.clone()))
+            .expect("Failed to register rejected_connections metric");
+        registry
+            .register(Box::new(tls_handshake_errors.clone()))
+            .expect("Failed to register tls_handshake_errors metric");
+        registry
+            .register(Box::new(in_flight_connections.clone()))
+            .expect("Failed to register in_flight_connections metric");
         registry
             .register(Box::new(processing_time.clone()))
             .expect("Failed to register processing_time metric");
@@ -124,9 +405,32 @@ impl Metrics {
             failed_requests,
             bytes_received,
             bytes_sent,
+            upstream_bytes_sent,
+            upstream_bytes_received,
             sni_rewrites,
+            rewrite_hits,
+            rewrite_misses,
+            rewrite_failures,
+            rewrite_passthroughs,
+            rewrite_passthrough_warnings,
+            responses_authenticated,
             upstream_errors,
+            upstream_retries,
+            circuit_open,
+            blocked_requests,
+            rate_limited,
+            oversized_rejected,
+            cookie_rejected,
+            dry_run_requests,
+            cache_hits,
+            cache_misses,
+            rejected_connections,
+            tls_handshake_errors,
+            in_flight_connections,
             processing_time,
+            rewrite_targets: Arc::new(DashMap::new()),
+            target_clock: Arc::new(AtomicU64::new(0)),
+            passthrough_snis: Arc::new(DashMap::new()),
             cached_snapshot: Arc::new(RwLock::new(None)),
         }
     }
@@ -151,9 +455,141 @@ impl Metrics {
         self.processing_time.observe(duration.as_secs_f64());
     }
 
+    /// Record one exchange with an upstream: the bytes of the message sent to
+    /// it and of the response it returned
+    ///
+    /// Unlike the client-facing byte counters of [`Self::record_request`],
+    /// these count each answered upstream attempt, so retried 5xx answers add
+    /// up while cached answers and health probes don't count at all.
+    pub fn record_upstream_bytes(&self, sent: u64, received: u64) {
+        self.upstream_bytes_sent.inc_by(sent);
+        self.upstream_bytes_received.inc_by(received);
+    }
+
     /// Record an SNI rewrite
+    #[deprecated(note = "use `record_rewrite_hit` or `record_rewrite`")]
     pub fn record_sni_rewrite(&self) {
+        self.record_rewrite_hit();
+    }
+
+    /// Record an SNI rewritten to a target hostname
+    pub fn record_rewrite_hit(&self) {
+        self.sni_rewrites.inc();
+        self.rewrite_hits.inc();
+    }
+
+    /// Record an SNI the rewriter found no target for
+    pub fn record_rewrite_miss(&self) {
         self.sni_rewrites.inc();
+        self.rewrite_misses.inc();
+    }
+
+    /// Record an SNI the rewriter found no target for, counting it under
+    /// the failure's reason as well as a miss
+    pub fn record_rewrite_failure(&self, error: &SniRewriteError) {
+        self.record_rewrite_miss();
+        self.rewrite_failures
+            .with_label_values(&[error.reason()])
+            .inc();
+    }
+
+    /// Record an SNI passed through unchanged by the passthrough strategy
+    pub fn record_rewrite_passthrough(&self) {
+        self.sni_rewrites.inc();
+        self.rewrite_passthroughs.inc();
+    }
+
+    /// Count an SNI passed through by the passthrough_warn strategy under its
+    /// own label, or under `OTHER_PASSTHROUGH_SNI` once
+    /// `MAX_TRACKED_PASSTHROUGH_SNIS` SNIs have one
+    pub fn record_rewrite_passthrough_warning(&self, sni: &str) {
+        let sni = sni.to_ascii_lowercase();
+        let tracked = self.passthrough_snis.contains_key(&sni)
+            || (self.passthrough_snis.len() < MAX_TRACKED_PASSTHROUGH_SNIS
+                && self.passthrough_snis.insert(sni.clone(), ()).is_none());
+        let label = if tracked {
+            sni.as_str()
+        } else {
+            OTHER_PASSTHROUGH_SNI
+        };
+        self.rewrite_passthrough_warnings
+            .with_label_values(&[label])
+            .inc();
+    }
+
+    /// Record the outcome of an SNI rewrite: a hit, a passthrough, or a miss
+    /// when `result` is `None`
+    ///
+    /// Hits and passthroughs also count a request for their target hostname,
+    /// and passthrough_warn passthroughs their SNI.
+    pub fn record_rewrite(&self, result: Option<&RewriteResult>) {
+        match result {
+            Some(result) if result.passthrough => {
+                self.record_rewrite_passthrough();
+                if result.warn {
+                    self.record_rewrite_passthrough_warning(&result.original);
+                }
+            }
+            Some(_) => self.record_rewrite_hit(),
+            None => self.record_rewrite_miss(),
+        }
+        if let Some(result) = result {
+            self.record_rewrite_target(&result.target_hostname);
+        }
+    }
+
+    /// Count a request rewritten to `target`
+    ///
+    /// Once `MAX_TRACKED_TARGETS` targets are counted, a new target replaces
+    /// the least recently rewritten one.
+    pub fn record_rewrite_target(&self, target: &str) {
+        let tick = self.target_clock.fetch_add(1, Ordering::Relaxed);
+        if let Some(count) = self.rewrite_targets.get(target) {
+            count.requests.fetch_add(1, Ordering::Relaxed);
+            count.last_seen.store(tick, Ordering::Relaxed);
+            return;
+        }
+
+        if self.rewrite_targets.len() >= MAX_TRACKED_TARGETS {
+            let least_recent = self
+                .rewrite_targets
+                .iter()
+                .min_by_key(|entry| entry.last_seen.load(Ordering::Relaxed))
+                .map(|entry| entry.key().clone());
+            if let Some(least_recent) = least_recent {
+                self.rewrite_targets.remove(&least_recent);
+            }
+        }
+        let count = self
+            .rewrite_targets
+            .entry(target.to_string())
+            .or_insert_with(|| TargetCount {
+                requests: AtomicU64::new(0),
+                last_seen: AtomicU64::new(tick),
+            });
+        count.requests.fetch_add(1, Ordering::Relaxed);
+        count.last_seen.store(tick, Ordering::Relaxed);
+    }
+
+    /// The `n` rewrite targets with the most requests, busiest first (ties
+    /// ordered by hostname)
+    pub fn top_rewrite_targets(&self, n: usize) -> Vec<(String, u64)> {
+        let mut targets: Vec<(String, u64)> = self
+            .rewrite_targets
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.requests.load(Ordering::Relaxed)))
+            .collect();
+        targets.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        targets.truncate(n);
+        targets
+    }
+
+    /// Record a response sent to a client, counting it when a validating
+    /// upstream set its AD (authenticated data) bit
+    pub fn record_response(&self, response: &[u8]) {
+        if dns::is_authenticated(response) {
+            self.responses_authenticated.inc();
+        }
     }
 
     /// Record an upstream error
@@ -161,6 +597,245 @@ impl Metrics {
         self.upstream_errors.inc();
     }
 
+    /// Record a retried upstream request
+    pub fn record_upstream_retry(&self) {
+        self.upstream_retries.inc();
+    }
+
+    /// Record an upstream request failed at once by an open circuit breaker
+    pub fn record_circuit_open(&self) {
+        self.circuit_open.inc();
+    }
+
+    /// Record a request refused by the domain filter
+    pub fn record_blocked_request(&self) {
+        self.blocked_requests.inc();
+    }
+
+    /// Record a request or connection rejected by the rate limiter
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.inc();
+    }
+
+    /// Record a client message rejected for exceeding `servers.max_message_size`
+    pub fn record_oversized_rejected(&self) {
+        self.oversized_rejected.inc();
+    }
+
+    /// Record a UDP query answered without forwarding for lacking a valid DNS cookie
+    pub fn record_cookie_rejected(&self) {
+        self.cookie_rejected.inc();
+    }
+
+    /// Record a request answered without forwarding in dry-run mode
+    pub fn record_dry_run(&self) {
+        self.dry_run_requests.inc();
+    }
+
+    /// Record a query answered from the response cache
+    pub fn record_cache_hit(&self) {
+        self.cache_hits.inc();
+    }
+
+    /// Record a cacheable query that had to be forwarded
+    pub fn record_cache_miss(&self) {
+        self.cache_misses.inc();
+    }
+
+    /// Record a rejected connection
+    /// Returns the running count of rejections for this reason
+    pub fn record_rejected_connection(&self, reason: RejectReason) -> u64 {
+        let counter = self
+            .rejected_connections
+            .with_label_values(&[reason.as_str()]);
+        counter.inc();
+        self.rejected_connections(reason)
+    }
+
+    /// Record a client TLS or QUIC handshake that failed or timed out
+    ///
+    /// `protocol` is the server's config name (e.g. "dot").
+    pub fn record_tls_handshake_error(&self, protocol: &str) {
+        self.tls_handshake_errors
+            .with_label_values(&[protocol])
+            .inc();
+    }
+
+    /// Prometheus registry holding every metric, for host applications
+    /// gathering them into their own exporter
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Gauge of the connections a server is currently handling
+    ///
+    /// `server` is the server's config name (e.g. "dot").
+    pub fn in_flight_gauge(&self, server: &str) -> IntGauge {
+        self.in_flight_connections.with_label_values(&[server])
+    }
+
+    /// Total number of DNS requests handled
+    pub fn total_requests(&self) -> u64 {
+        self.total_requests.get()
+    }
+
+    /// Number of successful DNS requests
+    pub fn successful_requests(&self) -> u64 {
+        self.successful_requests.get()
+    }
+
+    /// Number of failed DNS requests
+    pub fn failed_requests(&self) -> u64 {
+        self.failed_requests.get()
+    }
+
+    /// Total bytes received from clients
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.get()
+    }
+
+    /// Total bytes sent to clients
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.get()
+    }
+
+    /// Total bytes sent to upstreams
+    pub fn upstream_bytes_sent(&self) -> u64 {
+        self.upstream_bytes_sent.get()
+    }
+
+    /// Total bytes received from upstreams
+    pub fn upstream_bytes_received(&self) -> u64 {
+        self.upstream_bytes_received.get()
+    }
+
+    /// Total number of SNI rewrites: hits, misses and passthroughs together
+    pub fn sni_rewrites(&self) -> u64 {
+        self.sni_rewrites.get()
+    }
+
+    /// Total number of SNIs rewritten to a target hostname
+    pub fn rewrite_hits(&self) -> u64 {
+        self.rewrite_hits.get()
+    }
+
+    /// Total number of SNIs the rewriter found no target for
+    pub fn rewrite_misses(&self) -> u64 {
+        self.rewrite_misses.get()
+    }
+
+    /// Number of SNIs the rewriter found no target for because of `reason`
+    /// (e.g. "no_matching_base_domain")
+    pub fn rewrite_failures(&self, reason: &str) -> u64 {
+        self.rewrite_failures.with_label_values(&[reason]).get()
+    }
+
+    /// Number of responses with the AD (authenticated data) bit set
+    pub fn responses_authenticated(&self) -> u64 {
+        self.responses_authenticated.get()
+    }
+
+    /// Total number of SNIs passed through unchanged
+    pub fn rewrite_passthroughs(&self) -> u64 {
+        self.rewrite_passthroughs.get()
+    }
+
+    /// Number of times the passthrough_warn strategy passed `sni` through
+    /// (or `OTHER_PASSTHROUGH_SNI` for SNIs beyond the tracked ones)
+    pub fn rewrite_passthrough_warnings(&self, sni: &str) -> u64 {
+        self.rewrite_passthrough_warnings
+            .with_label_values(&[sni])
+            .get()
+    }
+
+    /// Total number of upstream errors
+    pub fn upstream_errors(&self) -> u64 {
+        self.upstream_errors.get()
+    }
+
+    /// Total number of upstream request retries
+    pub fn upstream_retries(&self) -> u64 {
+        self.upstream_retries.get()
+    }
+
+    /// Total number of upstream requests failed at once by an open circuit breaker
+    pub fn circuit_open(&self) -> u64 {
+        self.circuit_open.get()
+    }
+
+    /// Total number of requests refused by the domain filter
+    pub fn blocked_requests(&self) -> u64 {
+        self.blocked_requests.get()
+    }
+
+    /// Total number of requests or connections rejected by the rate limiter
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.get()
+    }
+
+    /// Total number of client messages rejected for exceeding `servers.max_message_size`
+    pub fn oversized_rejected(&self) -> u64 {
+        self.oversized_rejected.get()
+    }
+
+    /// Total number of UDP queries answered without forwarding for lacking a valid DNS cookie
+    pub fn cookie_rejected(&self) -> u64 {
+        self.cookie_rejected.get()
+    }
+
+    /// Total number of requests answered without forwarding in dry-run mode
+    pub fn dry_run_requests(&self) -> u64 {
+        self.dry_run_requests.get()
+    }
+
+    /// Total number of queries answered from the response cache
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits.get()
+    }
+
+    /// Total number of cacheable queries not found in the response cache
+    pub fn cache_misses(&self) -> u64 {
+        self.cache_misses.get()
+    }
+
+    /// Number of connections rejected for the given reason
+    pub fn rejected_connections(&self, reason: RejectReason) -> u64 {
+        self.rejected_connections
+            .with_label_values(&[reason.as_str()])
+            .get()
+    }
+
+    /// Number of failed client handshakes of `protocol` (e.g. "dot")
+    pub fn tls_handshake_errors(&self, protocol: &str) -> u64 {
+        self.tls_handshake_errors
+            .with_label_values(&[protocol])
+            .get()
+    }
+
+    /// Number of failed client handshakes of all protocols
+    pub fn total_tls_handshake_errors(&self) -> u64 {
+        self.tls_handshake_errors
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .map(|metric| metric.get_counter().value() as u64)
+            .sum()
+    }
+
+    /// Connections (UDP: queries) each server is currently handling, by the
+    /// server's config name
+    pub fn active_connections(&self) -> BTreeMap<String, u64> {
+        self.in_flight_connections
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .filter_map(|metric| {
+                let server = metric.get_label().first()?.value().to_string();
+                Some((server, metric.get_gauge().value().max(0.0) as u64))
+            })
+            .collect()
+    }
+
     /// Export metrics in Prometheus text format
     pub fn export_prometheus(&self) -> String {
         use prometheus::Encoder;
@@ -200,10 +875,11 @@ impl Metrics {
     }
 
     /// Generate a snapshot from Prometheus metrics
+    #[allow(deprecated)]
     fn generate_snapshot(&self) -> MetricsSnapshot {
-        let total = self.total_requests.get();
-        let successful = self.successful_requests.get();
-        let failed = self.failed_requests.get();
+        let total = self.total_requests();
+        let successful = self.successful_requests();
+        let failed = self.failed_requests();
 
         // Calculate derived metrics
         let success_rate = if total > 0 {
@@ -225,10 +901,27 @@ impl Metrics {
             total_requests: total,
             successful_requests: successful,
             failed_requests: failed,
-            bytes_received: self.bytes_received.get(),
-            bytes_sent: self.bytes_sent.get(),
-            sni_rewrites: self.sni_rewrites.get(),
-            upstream_errors: self.upstream_errors.get(),
+            bytes_received: self.bytes_received(),
+            bytes_sent: self.bytes_sent(),
+            upstream_bytes_sent: self.upstream_bytes_sent(),
+            upstream_bytes_received: self.upstream_bytes_received(),
+            sni_rewrites: self.sni_rewrites(),
+            rewrite_hits: self.rewrite_hits(),
+            rewrite_misses: self.rewrite_misses(),
+            rewrite_passthroughs: self.rewrite_passthroughs(),
+            upstream_errors: self.upstream_errors(),
+            upstream_retries: self.upstream_retries(),
+            circuit_open: self.circuit_open(),
+            blocked_requests: self.blocked_requests(),
+            rate_limited: self.rate_limited(),
+            oversized_rejected: self.oversized_rejected(),
+            cookie_rejected: self.cookie_rejected(),
+            dry_run_requests: self.dry_run_requests(),
+            cache_hits: self.cache_hits(),
+            cache_misses: self.cache_misses(),
+            tls_handshake_errors: self.total_tls_handshake_errors(),
+            responses_authenticated: self.responses_authenticated(),
+            active_connections: self.active_connections(),
             average_processing_time_ms: avg_latency_ms,
             success_rate,
             throughput_requests_per_sec: total as f64,
@@ -244,8 +937,30 @@ pub struct MetricsSnapshot {
     pub failed_requests: u64,
     pub bytes_received: u64,
     pub bytes_sent: u64,
+    pub upstream_bytes_sent: u64,
+    pub upstream_bytes_received: u64,
+    /// Hits, misses and passthroughs together
+    #[deprecated(note = "use `rewrite_hits`, `rewrite_misses` and `rewrite_passthroughs`")]
     pub sni_rewrites: u64,
+    pub rewrite_hits: u64,
+    pub rewrite_misses: u64,
+    pub rewrite_passthroughs: u64,
     pub upstream_errors: u64,
+    pub upstream_retries: u64,
+    pub circuit_open: u64,
+    pub blocked_requests: u64,
+    pub rate_limited: u64,
+    pub oversized_rejected: u64,
+    pub cookie_rejected: u64,
+    pub dry_run_requests: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// Failed client TLS or QUIC handshakes, all protocols together
+    pub tls_handshake_errors: u64,
+    /// Responses with the AD (authenticated data) bit set
+    pub responses_authenticated: u64,
+    /// Connections (UDP: queries) each server is currently handling
+    pub active_connections: BTreeMap<String, u64>,
     pub average_processing_time_ms: f64,
     pub success_rate: f64,
     /// Estimated requests per second
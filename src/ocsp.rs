@@ -0,0 +1,136 @@
+//! OCSP responses stapled to served certificates
+//!
+//! A certificate's `ocsp_file` holds a DER-encoded OCSP response (RFC 6960),
+//! typically fetched by a cron job with `openssl ocsp -respout`. The response
+//! is sent to clients in the TLS handshake so they don't have to ask the CA's
+//! responder themselves. Its signature is left to clients to verify; the
+//! proxy only checks that it is a successful, current `good` answer, since a
+//! stale staple makes strict clients reject the connection.
+
+use crate::der::{
+    TAG_ENUMERATED, TAG_GENERALIZED_TIME, TAG_OCTET_STRING, TAG_OID, TAG_SEQUENCE, expect, read_tlv,
+};
+use std::time::{Duration, SystemTime};
+
+/// Clock skew tolerated between the responder and this host
+pub const CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+/// `id-pkix-ocsp-basic` (1.3.6.1.5.5.7.48.1.1), DER-encoded
+const ID_PKIX_OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+
+/// Context-specific constructed tags `[0]`, `[1]` and `[2]`
+const TAG_EXPLICIT_0: u8 = 0xa0;
+const TAG_EXPLICIT_1: u8 = 0xa1;
+const TAG_EXPLICIT_2: u8 = 0xa2;
+/// `CertStatus` choices: `good [0] IMPLICIT NULL`, `revoked [1] IMPLICIT
+/// RevokedInfo` and `unknown [2] IMPLICIT UnknownInfo`
+const STATUS_GOOD: u8 = 0x80;
+const STATUS_REVOKED: u8 = 0xa1;
+const STATUS_UNKNOWN: u8 = 0x82;
+
+/// Validity window of an OCSP response's (first) certificate status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OcspValidity {
+    pub this_update: SystemTime,
+    pub next_update: SystemTime,
+}
+
+/// Check that `response` is a successful OCSP response reporting the
+/// certificate as good and current at `now`
+pub fn check_response(response: &[u8], now: SystemTime) -> Result<OcspValidity, String> {
+    let validity = parse_response(response)?;
+    if validity.this_update > now + CLOCK_SKEW {
+        return Err("OCSP response is not valid yet (thisUpdate is in the future)".to_string());
+    }
+    if validity.next_update + CLOCK_SKEW <= now {
+        return Err("OCSP response has expired (nextUpdate has passed)".to_string());
+    }
+    Ok(validity)
+}
+
+/// Validity window of a successful OCSP response reporting a good status
+pub fn parse_response(response: &[u8]) -> Result<OcspValidity, String> {
+    let mut input = response;
+    let mut ocsp_response = expect(&mut input, TAG_SEQUENCE, "OCSPResponse")?;
+    let status = expect(&mut ocsp_response, TAG_ENUMERATED, "responseStatus")?;
+    if status != [0] {
+        return Err(format!(
+            "OCSP responder did not answer successfully (status {})",
+            status.first().copied().unwrap_or_default()
+        ));
+    }
+    let mut response_bytes = expect(&mut ocsp_response, TAG_EXPLICIT_0, "responseBytes")?;
+    let mut response_bytes = expect(&mut response_bytes, TAG_SEQUENCE, "ResponseBytes")?;
+    if expect(&mut response_bytes, TAG_OID, "responseType")? != ID_PKIX_OCSP_BASIC {
+        return Err("not a basic OCSP response".to_string());
+    }
+    let mut basic = expect(&mut response_bytes, TAG_OCTET_STRING, "response")?;
+    let mut basic = expect(&mut basic, TAG_SEQUENCE, "BasicOCSPResponse")?;
+    let mut data = expect(&mut basic, TAG_SEQUENCE, "ResponseData")?;
+    if data.first() == Some(&TAG_EXPLICIT_0) {
+        expect(&mut data, TAG_EXPLICIT_0, "version")?;
+    }
+    match data.first() {
+        Some(&TAG_EXPLICIT_1) => expect(&mut data, TAG_EXPLICIT_1, "responderID")?,
+        _ => expect(&mut data, TAG_EXPLICIT_2, "responderID")?,
+    };
+    expect(&mut data, TAG_GENERALIZED_TIME, "producedAt")?;
+
+    let mut responses = expect(&mut data, TAG_SEQUENCE, "responses")?;
+    let mut single = expect(&mut responses, TAG_SEQUENCE, "SingleResponse")?;
+    expect(&mut single, TAG_SEQUENCE, "certID")?;
+    let (status, _) = read_tlv(&mut single)?;
+    match status {
+        STATUS_GOOD => {}
+        STATUS_REVOKED => return Err("OCSP response reports the certificate as revoked".into()),
+        STATUS_UNKNOWN => return Err("OCSP responder does not know the certificate".into()),
+        _ => return Err("malformed certStatus in OCSP response".to_string()),
+    }
+    let this_update = generalized_time(expect(&mut single, TAG_GENERALIZED_TIME, "thisUpdate")?)?;
+    if single.first() != Some(&TAG_EXPLICIT_0) {
+        return Err("OCSP response has no nextUpdate, so its freshness can't be checked".into());
+    }
+    let mut next_update = expect(&mut single, TAG_EXPLICIT_0, "nextUpdate")?;
+    let next_update = generalized_time(expect(
+        &mut next_update,
+        TAG_GENERALIZED_TIME,
+        "nextUpdate",
+    )?)?;
+    Ok(OcspValidity {
+        this_update,
+        next_update,
+    })
+}
+
+/// Parse a DER `GeneralizedTime` (`YYYYMMDDHHMMSSZ`)
+fn generalized_time(value: &[u8]) -> Result<SystemTime, String> {
+    let invalid = || "invalid time in OCSP response".to_string();
+    let text = std::str::from_utf8(value).map_err(|_| invalid())?;
+    if text.len() != 15 || !text.ends_with('Z') || !text[..14].bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let field = |range: std::ops::Range<usize>| text[range].parse::<u64>().unwrap();
+    let (year, month, day) = (field(0..4), field(4..6), field(6..8));
+    let (hour, minute, second) = (field(8..10), field(10..12), field(12..14));
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+    if hour > 23 || minute > 59 || second > 60 {
+        return Err(invalid());
+    }
+    let days = days_since_epoch(year, month, day);
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // Count years from March, so the leap day ends a year
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let month_from_march = (month + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
@@ -1,107 +1,226 @@
+use crate::config::{AppConfig, FilterConfig, ServersConfig, UpstreamConfig};
+use crate::dns::cache::ResponseCache;
+use crate::dns::{self, edns};
+use crate::error::{DnsProxyError, SniRewriteError};
+use crate::logging::{log_access, record_sni, record_target};
 use crate::metrics::{Metrics, Timer};
 use crate::rewrite::SniRewriterType;
-use crate::sni::SniRewriter;
-use crate::upstream::http::forward_http_request;
+use crate::tls_utils::CertificateResolver;
+use crate::upstream::circuit_breaker::record_circuit_open;
+use crate::upstream::dry_run::dry_run_response;
+use crate::upstream::health::UpstreamHealth;
+use crate::upstream::http::{
+    StreamingBody, buffered_body, forward_http_request_streaming, gateway_timeout_response,
+    is_transient_response, upstream_path_and_query, without_proxy_headers,
+};
+use crate::upstream::ladder::UpstreamProtocol;
 use crate::upstream::pool::ConnectionPool;
-use anyhow::{Context, Result};
+use crate::upstream::retry::with_retries;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use bytes::Bytes;
-use http_body_util::BodyExt;
+use http_body_util::{BodyExt, LengthLimitError, Limited};
 use hyper::body::Incoming;
-use hyper::{Method, Request, Response};
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE, HeaderMap, HeaderValue};
+use hyper::{Method, Request, Response, StatusCode, Uri};
 use std::sync::Arc;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
+
+/// Response header carrying the request ID of DoH and DoH3 responses
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Media type of RFC 8484 DNS messages
+pub const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
 
 /// Handle HTTP request with SNI rewriting and upstream forwarding
+///
+/// Only RFC 8484 queries to `servers.doh_path` are served: a `GET` with a
+/// base64url `dns` parameter or a `POST` of an `application/dns-message` body.
+/// Either way the DNS message is sent upstream as a `POST` body, so the EDNS
+/// policy applies to both. With `servers.doh.upstream_protocol` set, the
+/// message goes to that protocol's upstream instead and its answer is
+/// returned as an `application/dns-message` body.
+///
+/// Answers are cached in `cache` by the decoded DNS message, so a `GET` and a
+/// `POST` of the same query share one entry.
+///
+/// Failures are answered with an HTTP error status: 400 for a missing or
+/// invalid Host header or DNS message, 404 for another path, 405 for another
+/// method, 413 for a message over `servers.max_message_size`, 415 for a `POST`
+/// of another content type, 421 when the Host has no rewrite target, 431 for
+/// headers over `servers.max_header_count` or `servers.max_header_bytes`, 502
+/// when the upstream fails and 504 when it times out.
+///
+/// Over HTTP/2 the request's `:authority` stands in for a missing Host header.
+///
+/// The Host header and rewritten target are recorded on the current request
+/// span (see [`crate::logging::request_span`]).
 pub async fn handle_http_request(
     req: Request<Incoming>,
     rewriter: SniRewriterType,
     pool: &ConnectionPool,
+    health: &UpstreamHealth,
+    cache: &ResponseCache,
+    config: &AppConfig,
     metrics: Arc<Metrics>,
-) -> Result<Response<http_body_util::Full<hyper::body::Bytes>>> {
+) -> Response<StreamingBody> {
     let timer = Timer::start();
     let method = req.method().clone();
     let uri = req.uri().clone();
 
-    let host = req
-        .headers()
-        .get("host")
-        .and_then(|h| h.to_str().ok())
-        .ok_or_else(|| {
-            anyhow::anyhow!(
-                "Missing or invalid Host header in {} request to {}",
-                method,
-                uri
-            )
-        })
-        .context("Failed to extract Host header from request")?;
+    if let Some(response) = reject_non_doh_request(&req, &config.servers) {
+        debug!(
+            "Rejecting {} request to {}: {}",
+            method,
+            uri,
+            response.status()
+        );
+        log_access(&response.status().as_u16(), 0, 0, timer.elapsed());
+        return response.map(buffered_body);
+    }
+
+    let Some(host) = request_host(&req) else {
+        debug!(
+            "Rejecting {} request to {}: missing or invalid Host header",
+            method, uri
+        );
+        let response = error_response(StatusCode::BAD_REQUEST, "Missing or invalid Host header");
+        log_access(&response.status().as_u16(), 0, 0, timer.elapsed());
+        return response.map(buffered_body);
+    };
+    record_sni(Some(host));
 
     debug!("Processing {} request for host: {}", method, host);
 
-    let rewrite_result = rewriter
-        .rewrite(host)
-        .await
-        .ok_or_else(|| {
-            anyhow::anyhow!(
-                "SNI rewrite failed for hostname: {} (no matching base domain found)",
-                host
-            )
-        })
-        .context("SNI rewrite operation failed")?;
-
-    // Record SNI rewrite
-    metrics.record_sni_rewrite();
-
-    info!(
-        "HTTP request: {} {} -> SNI rewrite: {} -> {} -> Target: {}",
-        method,
-        uri.path(),
-        rewrite_result.original,
-        rewrite_result.prefix,
-        rewrite_result.target_hostname
-    );
+    if let Some(response) = forbidden_if_denied(host, &config.filter) {
+        debug!(
+            "Refusing {} request for {} denied by the domain filter",
+            method, host
+        );
+        metrics.record_blocked_request();
+        log_access(&response.status().as_u16(), 0, 0, timer.elapsed());
+        return response.map(buffered_body);
+    }
 
-    // Build upstream URI without unnecessary allocation
-    let path_and_query = req
-        .uri()
-        .path_and_query()
-        .map(|pq| pq.as_str())
-        .unwrap_or("/");
+    // With an upstream_protocol, every query goes to that protocol's
+    // upstream instead of the host its Host header rewrites to
+    let target = match UpstreamProtocol::of_server(&config.servers.doh) {
+        Some(protocol) => {
+            record_target(&protocol);
+            info!(
+                "HTTP request: {} {} -> {} upstream",
+                method,
+                uri.path(),
+                protocol
+            );
+            DohTarget::Protocol(protocol)
+        }
+        None => {
+            let rewrite_result = match rewriter.rewrite_with_reason(host).await {
+                Ok(rewrite_result) => rewrite_result,
+                Err(e) => {
+                    metrics.record_rewrite_failure(&e);
+                    warn!("Rejecting {} request for {}: {}", method, host, e);
+                    let response = rewrite_error_response(&e);
+                    log_access(&response.status().as_u16(), 0, 0, timer.elapsed());
+                    return response.map(buffered_body);
+                }
+            };
 
-    let upstream_uri = format!(
-        "https://{}{}",
-        rewrite_result.target_hostname, path_and_query
-    );
+            // Record SNI rewrite
+            metrics.record_rewrite(Some(&rewrite_result));
+            record_target(&rewrite_result.target_hostname);
 
-    debug!("Forwarding request to upstream: {}", upstream_uri);
+            info!(
+                "HTTP request: {} {} -> SNI rewrite: {} -> {} -> Target: {}",
+                method,
+                uri.path(),
+                rewrite_result.original,
+                rewrite_result.prefix,
+                rewrite_result.target_hostname
+            );
 
-    // Extract headers before consuming request
-    let headers = req.headers().clone();
+            // Build upstream URI, taking the path from servers.doh_upstream_path
+            // or upstream.doh when either has one
+            let path_and_query = doh_upstream_path_and_query(
+                &uri,
+                &config.servers,
+                config.upstream.doh.first().map(String::as_str),
+            );
+            let upstream_uri = format!(
+                "https://{}{}",
+                rewrite_result.target_hostname, path_and_query
+            );
 
-    // Extract body if POST (zerocopy: reuse bytes when possible)
-    let body = if method == Method::POST {
-        req.into_body()
-            .collect()
-            .await
-            .context("Failed to read request body")?
-            .to_bytes()
+            debug!("Forwarding request to upstream: {}", upstream_uri);
+            DohTarget::Rewritten {
+                hostname: rewrite_result.target_hostname,
+                uri: upstream_uri,
+            }
+        }
+    };
+
+    let headers = forwarded_headers(req.headers(), &config.upstream);
+
+    // Extract the DNS message (zerocopy: reuse bytes when possible)
+    let post_body = if method == Method::POST {
+        // Stop reading as soon as the body passes servers.max_message_size
+        // rather than buffering all of it first
+        let limited = Limited::new(req.into_body(), config.servers.max_message_size);
+        match limited.collect().await {
+            Ok(collected) => Some(collected.to_bytes()),
+            Err(e) if e.is::<LengthLimitError>() => {
+                debug!(
+                    "Rejecting {} request to {}: body over {} bytes",
+                    method, uri, config.servers.max_message_size
+                );
+                metrics.record_oversized_rejected();
+                let status = StatusCode::PAYLOAD_TOO_LARGE;
+                let response = error_response(status, status.canonical_reason().unwrap_or(""));
+                log_access(&status.as_u16(), 0, 0, timer.elapsed());
+                return response.map(buffered_body);
+            }
+            Err(e)
+                if e.downcast_ref::<hyper::Error>()
+                    .is_some_and(is_client_disconnect) =>
+            {
+                // The client went away mid-upload; nothing to forward and no
+                // one to answer, so don't count it as a proxy failure
+                debug!("Client disconnected while sending {} body: {}", uri, e);
+                return error_response(StatusCode::BAD_REQUEST, "").map(buffered_body);
+            }
+            Err(e) => {
+                debug!("Failed to read {} request body: {}", uri, e);
+                let response = error_response(StatusCode::BAD_REQUEST, "Unreadable request body");
+                log_access(&response.status().as_u16(), 0, 0, timer.elapsed());
+                return response.map(buffered_body);
+            }
+        }
     } else {
-        Bytes::new()
+        None
+    };
+    let body = match doh_query_message(&uri, post_body, config) {
+        Ok(body) => body,
+        Err(status) => {
+            debug!("Rejecting {} request to {}: {}", method, uri, status);
+            if status == StatusCode::PAYLOAD_TOO_LARGE {
+                metrics.record_oversized_rejected();
+            }
+            let response = error_response(status, status.canonical_reason().unwrap_or(""));
+            log_access(&status.as_u16(), 0, 0, timer.elapsed());
+            return response.map(buffered_body);
+        }
     };
 
     debug!("Request body size: {} bytes", body.len());
 
     let bytes_received = body.len() as u64;
 
-    // Forward request using connection pool for connection reuse
-    let result = forward_http_request(
-        pool,
-        &upstream_uri,
-        &rewrite_result.target_hostname,
-        method,
-        &headers,
-        body,
-    )
-    .await;
+    // Forward request using connection pool for connection reuse,
+    // retrying connection errors and 5xx responses
+    let result = target
+        .forward(pool, health, cache, config, &headers, body, &metrics)
+        .await;
 
     let duration = timer.elapsed();
 
@@ -109,18 +228,470 @@ pub async fn handle_http_request(
     match result {
         Ok((response, bytes_sent)) => {
             metrics.record_request(true, bytes_received, bytes_sent, duration);
-            Ok(response)
+            log_access(
+                &response.status().as_u16(),
+                bytes_received,
+                bytes_sent,
+                duration,
+            );
+            response
         }
         Err(e) => {
-            debug!("HTTP request failed: {}", e);
+            error!(
+                "Failed to forward HTTP request to upstream {}: {}",
+                target.upstream(),
+                e
+            );
             metrics.record_request(false, bytes_received, 0, duration);
             metrics.record_upstream_error();
-            Err(e).with_context(|| {
-                format!(
-                    "Failed to forward HTTP request to upstream: {}",
-                    upstream_uri
-                )
+            let response = gateway_timeout_response(&e).unwrap_or_else(|| {
+                error_response(StatusCode::BAD_GATEWAY, "Upstream request failed")
+            });
+            log_access(&response.status().as_u16(), bytes_received, 0, duration);
+            response.map(buffered_body)
+        }
+    }
+}
+
+/// Where a DoH or DoH3 request is forwarded
+pub enum DohTarget {
+    /// Proxied over HTTP to the host its Host header rewrites to
+    Rewritten { hostname: String, uri: String },
+    /// Sent as a wire-format message over the server's `upstream_protocol`
+    Protocol(UpstreamProtocol),
+}
+
+impl DohTarget {
+    /// Upstream URI or protocol name, for logs
+    pub fn upstream(&self) -> &str {
+        match self {
+            Self::Rewritten { uri, .. } => uri,
+            Self::Protocol(protocol) => protocol.as_str(),
+        }
+    }
+
+    /// Forward a DNS message, returning the response for the client and the
+    /// size of its body
+    ///
+    /// Proxied requests retry connection errors and 5xx responses, and a
+    /// response body too large to be a DNS message is streamed to the client
+    /// as the upstream sends it (see [`forward_http_request_streaming`]); its
+    /// size is taken from its `Content-Length`. Answers are served from and
+    /// stored in `cache`, kept apart per upstream host or protocol; proxied
+    /// responses other than a buffered 200 are passed on uncached. In dry-run
+    /// mode the message is answered with an empty NOERROR response instead.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn forward(
+        &self,
+        pool: &ConnectionPool,
+        health: &UpstreamHealth,
+        cache: &ResponseCache,
+        config: &AppConfig,
+        headers: &HeaderMap,
+        message: Bytes,
+        metrics: &Metrics,
+    ) -> anyhow::Result<(Response<StreamingBody>, u64)> {
+        if config.proxy.dry_run {
+            let response = dry_run_response(&message, self.upstream(), metrics);
+            let bytes_sent = response.len() as u64;
+            return Ok((
+                dns_message_response(response).map(buffered_body),
+                bytes_sent,
+            ));
+        }
+        match self {
+            Self::Rewritten { hostname, uri } => {
+                // Headers of the upstream's answer when it was not cached
+                let mut head = None;
+                let answer = cache
+                    .get_or_forward_from(hostname, &message, metrics, async {
+                        let (response, size) =
+                            proxy_message(pool, hostname, uri, config, headers, &message, metrics)
+                                .await
+                                .map_err(Uncached::Failed)?;
+                        if size.is_none() || response.status() != StatusCode::OK {
+                            return Err(Uncached::Response(response, size));
+                        }
+                        let (parts, body) = response.into_parts();
+                        let body = body
+                            .collect()
+                            .await
+                            .map_err(|e| Uncached::Failed(e.into()))?
+                            .to_bytes();
+                        head = Some(parts);
+                        Ok(body)
+                    })
+                    .await;
+                match answer {
+                    Ok(answer) => {
+                        let bytes_sent = answer.len() as u64;
+                        let response = match head {
+                            Some(parts) => {
+                                Response::from_parts(parts, http_body_util::Full::new(answer))
+                            }
+                            None => dns_message_response(answer),
+                        };
+                        Ok((response.map(buffered_body), bytes_sent))
+                    }
+                    Err(Uncached::Response(response, size)) => {
+                        let bytes_sent = size.unwrap_or_else(|| content_length(&response));
+                        Ok((response, bytes_sent))
+                    }
+                    Err(Uncached::Failed(e)) => Err(e),
+                }
+            }
+            Self::Protocol(protocol) => {
+                let response = cache
+                    .get_or_forward_from(
+                        protocol.as_str(),
+                        &message,
+                        metrics,
+                        protocol.forward(config, pool, health, &message, metrics),
+                    )
+                    .await?;
+                let bytes_sent = response.len() as u64;
+                Ok((
+                    dns_message_response(response).map(buffered_body),
+                    bytes_sent,
+                ))
+            }
+        }
+    }
+}
+
+/// POST a DNS message to `uri` on the rewritten `hostname`, retrying
+/// connection errors and 5xx responses
+///
+/// Returns the response and, when it was buffered rather than streamed, the
+/// size of its body.
+async fn proxy_message(
+    pool: &ConnectionPool,
+    hostname: &str,
+    uri: &str,
+    config: &AppConfig,
+    headers: &HeaderMap,
+    message: &Bytes,
+    metrics: &Metrics,
+) -> anyhow::Result<(Response<StreamingBody>, Option<u64>)> {
+    with_retries(
+        config.upstream.max_retries,
+        uri,
+        metrics,
+        is_transient_response,
+        || async {
+            forward_http_request_streaming(
+                pool,
+                uri,
+                hostname,
+                Method::POST,
+                headers,
+                message.clone(),
+                config.upstream.timeout(),
+            )
+            .await
+            .inspect(|(response, size)| {
+                let received = size.unwrap_or_else(|| content_length(response));
+                metrics.record_upstream_bytes(message.len() as u64, received);
             })
+        },
+    )
+    .await
+    .inspect_err(|e| {
+        if let Some(e) = e.downcast_ref::<DnsProxyError>() {
+            record_circuit_open(e, metrics);
+        }
+    })
+}
+
+/// Why a proxied DoH answer was not cached
+enum Uncached {
+    /// The upstream answered with something other than a buffered 200,
+    /// passed on to the client as is
+    Response(Response<StreamingBody>, Option<u64>),
+    /// The request failed
+    Failed(anyhow::Error),
+}
+
+/// `Content-Length` of a response, or 0 without a valid one
+fn content_length<B>(response: &Response<B>) -> u64 {
+    response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// 200 response carrying a DNS message from a wire-format upstream
+fn dns_message_response(message: Bytes) -> Response<http_body_util::Full<hyper::body::Bytes>> {
+    let mut response = Response::new(http_body_util::Full::new(message));
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(DNS_MESSAGE_CONTENT_TYPE),
+    );
+    response
+}
+
+/// Response with an error status and a short plain-text reason
+fn error_response(
+    status: StatusCode,
+    reason: &'static str,
+) -> Response<http_body_util::Full<hyper::body::Bytes>> {
+    let mut response = Response::new(http_body_util::Full::new(Bytes::from_static(
+        reason.as_bytes(),
+    )));
+    *response.status_mut() = status;
+    response
+}
+
+/// Error response for a request whose Host could not be rewritten
+fn rewrite_error_response(
+    error: &SniRewriteError,
+) -> Response<http_body_util::Full<hyper::body::Bytes>> {
+    match error {
+        SniRewriteError::EmptySni => error_response(StatusCode::BAD_REQUEST, "Empty Host header"),
+        SniRewriteError::NoBaseDomains | SniRewriteError::InvalidTargetSuffix { .. } => {
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "SNI rewriting is misconfigured",
+            )
+        }
+        SniRewriteError::NoPrefix { .. } => error_response(
+            StatusCode::MISDIRECTED_REQUEST,
+            "Host has no prefix before its base domain",
+        ),
+        SniRewriteError::NoMatchingBaseDomain { .. } | SniRewriteError::NoMatchingRule { .. } => {
+            error_response(
+                StatusCode::MISDIRECTED_REQUEST,
+                "No upstream serves this host",
+            )
+        }
+    }
+}
+
+/// Error response for a request with more headers than `servers` allows or
+/// that is not an RFC 8484 query to `servers.doh_path`
+pub fn reject_non_doh_request<B>(
+    req: &Request<B>,
+    servers: &ServersConfig,
+) -> Option<Response<http_body_util::Full<hyper::body::Bytes>>> {
+    let headers = req.headers();
+    let header_bytes: usize = headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum();
+    if headers.len() > servers.max_header_count || header_bytes > servers.max_header_bytes {
+        return Some(error_response(
+            StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            "Too many or too large request headers",
+        ));
+    }
+    if req.uri().path() != servers.doh_path {
+        return Some(error_response(StatusCode::NOT_FOUND, "Not a DoH endpoint"));
+    }
+    match *req.method() {
+        Method::GET => None,
+        Method::POST if is_dns_message(req.headers()) => None,
+        Method::POST => Some(error_response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "Content-Type must be application/dns-message",
+        )),
+        _ => Some(error_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Only GET and POST are supported",
+        )),
+    }
+}
+
+/// Whether a request's Content-Type is `application/dns-message`, ignoring
+/// any parameters
+fn is_dns_message(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|media_type| {
+            media_type
+                .trim()
+                .eq_ignore_ascii_case(DNS_MESSAGE_CONTENT_TYPE)
+        })
+}
+
+/// Decode the base64url `dns` parameter of an RFC 8484 `GET` request
+/// Returns `None` when the query has no such parameter or it is malformed
+pub fn decode_dns_param(query: Option<&str>) -> Option<Vec<u8>> {
+    let value = query?
+        .split('&')
+        .find_map(|param| param.strip_prefix("dns="))?;
+    // RFC 8484 omits the padding; tolerate clients that send it anyway
+    URL_SAFE_NO_PAD.decode(value.trim_end_matches('=')).ok()
+}
+
+/// The DNS message of an RFC 8484 request, rewritten by the EDNS policy
+///
+/// `post_body` is the body of a `POST`; a `GET` carries the message in its
+/// `dns` parameter. Fails with 400 when there is no well-formed message and
+/// 413 when it is longer than `servers.max_message_size`.
+pub fn doh_query_message(
+    uri: &Uri,
+    post_body: Option<Bytes>,
+    config: &AppConfig,
+) -> Result<Bytes, StatusCode> {
+    let message = match post_body {
+        Some(body) => body,
+        None => decode_dns_param(uri.query())
+            .map(Bytes::from)
+            .ok_or(StatusCode::BAD_REQUEST)?,
+    };
+    if message.len() > config.servers.max_message_size {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    dns::validate_message(&message).map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok(match edns::rewrite_query(&message, &config.edns) {
+        std::borrow::Cow::Borrowed(_) => message,
+        std::borrow::Cow::Owned(rewritten) => Bytes::from(rewritten),
+    })
+}
+
+/// Headers to forward upstream with a DNS message
+///
+/// The message is always sent as a `POST` body, whose length may change with
+/// the EDNS policy. `Forwarded` and `X-Forwarded-*` headers are dropped unless
+/// `upstream.forward_proxy_headers` is set; hop-by-hop headers are dropped by
+/// [`forward_http_request`](crate::upstream::http::forward_http_request).
+pub fn forwarded_headers(headers: &HeaderMap, upstream: &UpstreamConfig) -> HeaderMap {
+    let mut headers = if upstream.forward_proxy_headers {
+        headers.clone()
+    } else {
+        without_proxy_headers(headers)
+    };
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(DNS_MESSAGE_CONTENT_TYPE),
+    );
+    headers.remove(CONTENT_LENGTH);
+    headers
+}
+
+/// A request's path and query without the `dns` parameter, whose message is
+/// forwarded as the body instead
+pub fn path_and_query_without_dns(uri: &Uri) -> String {
+    let params: Vec<&str> = uri
+        .query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .filter(|param| !param.is_empty() && param.split('=').next() != Some("dns"))
+        .collect();
+    if params.is_empty() {
+        uri.path().to_string()
+    } else {
+        format!("{}?{}", uri.path(), params.join("&"))
+    }
+}
+
+/// Path and query to request from the upstream a DoH or DoH3 query's Host
+/// rewrites to
+///
+/// The `dns` parameter is dropped and the path is `servers.doh_upstream_path`
+/// when set; otherwise [`upstream_path_and_query`] takes it from
+/// `upstream_url` or the client's request.
+pub fn doh_upstream_path_and_query(
+    uri: &Uri,
+    servers: &ServersConfig,
+    upstream_url: Option<&str>,
+) -> String {
+    let client = path_and_query_without_dns(uri);
+    let Some(path) = &servers.doh_upstream_path else {
+        return upstream_path_and_query(&client, upstream_url);
+    };
+    match client.split_once('?') {
+        Some((_, query)) => format!("{}?{}", path, query),
+        None => path.clone(),
+    }
+}
+
+/// 403 response when the domain filter denies a request's Host header
+pub fn forbidden_if_denied(
+    host: &str,
+    filter: &FilterConfig,
+) -> Option<Response<http_body_util::Full<hyper::body::Bytes>>> {
+    if filter.is_allowed(host_name(host)) {
+        return None;
+    }
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(http_body_util::Full::new(Bytes::new()))
+        .ok()
+}
+
+/// 421 response for a request whose Host may not be served on a connection
+/// whose TLS handshake asked for `server_name` (see
+/// [`CertificateResolver::allows_host`])
+pub fn misdirected_if_host_not_allowed<B>(
+    req: &Request<B>,
+    server_name: Option<&str>,
+    tls_resolver: &CertificateResolver,
+) -> Option<Response<http_body_util::Full<hyper::body::Bytes>>> {
+    let host = host_name(request_host(req)?);
+    if tls_resolver.allows_host(server_name, host) {
+        return None;
+    }
+    Some(error_response(
+        StatusCode::MISDIRECTED_REQUEST,
+        "Host is not served on this connection",
+    ))
+}
+
+/// Host a request is for, from its Host header or, as HTTP/2 and HTTP/3
+/// clients send it, its `:authority`
+fn request_host<B>(req: &Request<B>) -> Option<&str> {
+    req.headers()
+        .get("host")
+        .and_then(|h| h.to_str().ok())
+        .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+}
+
+/// `host` without its port, keeping bracketed IPv6 literals intact
+fn host_name(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    }
+}
+
+/// Tag a response with the ID that correlates the request's log lines
+pub fn set_request_id(headers: &mut HeaderMap, request_id: &str) {
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        headers.insert(REQUEST_ID_HEADER, value);
+    }
+}
+
+/// 429 response for clients over their rate limit
+pub fn too_many_requests_response() -> Response<http_body_util::Full<hyper::body::Bytes>> {
+    let mut response = Response::new(http_body_util::Full::new(Bytes::new()));
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    response
+}
+
+/// Whether a request body error means the client disconnected during upload
+/// rather than the proxy failing
+fn is_client_disconnect(error: &hyper::Error) -> bool {
+    if error.is_incomplete_message() || error.is_canceled() || error.is_closed() {
+        return true;
+    }
+
+    let mut source = std::error::Error::source(error);
+    while let Some(err) = source {
+        if let Some(io_err) = err.downcast_ref::<std::io::Error>() {
+            return matches!(
+                io_err.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::UnexpectedEof
+            );
         }
+        source = err.source();
     }
+    false
 }
@@ -0,0 +1,165 @@
+//! PROXY protocol (v1 and v2) headers sent by TCP load balancers
+//!
+//! A load balancer speaking the PROXY protocol opens each connection with a
+//! header carrying the address of the client it accepted. Servers with
+//! `proxy_protocol` enabled read it before the TLS handshake or any DNS/HTTP
+//! data, and use that address wherever the peer address would be used.
+
+use crate::config::LoggingConfig;
+use crate::logging::log_rejected_connection;
+use crate::metrics::{Metrics, RejectReason};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Time allowed for the load balancer to send the header
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// First bytes of every v2 header
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header, including its CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// Read the PROXY protocol header at the start of `stream`, leaving the
+/// client's data after it unread
+///
+/// Returns the client address the header carries, or `None` when it has none
+/// (a v1 `UNKNOWN` header, a v2 `LOCAL` command such as a health check, or a
+/// non-IP address family), in which case the peer address applies.
+pub async fn read_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    // Both versions' headers are at least 15 bytes long
+    let mut start = [0u8; 12];
+    stream.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+/// Client address of a connection accepted from `peer`, taken from its
+/// PROXY protocol header when `enabled`
+///
+/// A missing, malformed or late header rejects the connection: it is counted
+/// and logged like a failed handshake, and `None` is returned.
+pub async fn client_addr<S>(
+    stream: &mut S,
+    peer: SocketAddr,
+    enabled: bool,
+    protocol: &str,
+    config: &LoggingConfig,
+    metrics: &Metrics,
+) -> Option<SocketAddr>
+where
+    S: AsyncRead + Unpin,
+{
+    if !enabled {
+        return Some(peer);
+    }
+    let error = match tokio::time::timeout(HEADER_TIMEOUT, read_header(stream)).await {
+        Ok(Ok(addr)) => return Some(addr.unwrap_or(peer)),
+        Ok(Err(e)) => e.to_string(),
+        Err(e) => e.to_string(),
+    };
+    log_rejected_connection(
+        config,
+        metrics,
+        protocol,
+        peer,
+        RejectReason::ProxyProtocolInvalid,
+        &error,
+    );
+    None
+}
+
+/// Read the rest of a v1 header, whose first bytes are `start`
+async fn read_v1<S>(stream: &mut S, start: &[u8]) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("PROXY protocol v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY protocol v1 header is not ASCII"))?;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        [
+            "PROXY",
+            family @ ("TCP4" | "TCP6"),
+            source,
+            _,
+            source_port,
+            _,
+        ] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol v1 source address"))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(invalid(
+                    "PROXY protocol v1 address does not match its family",
+                ));
+            }
+            let port: u16 = source_port
+                .parse()
+                .map_err(|_| invalid("invalid PROXY protocol v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY protocol v1 header")),
+    }
+}
+
+/// Read the rest of a v2 header, after its signature
+async fn read_v2<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await? as usize;
+    // TLVs after the addresses are read and ignored
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL: sent by the load balancer itself, e.g. for health checks
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported PROXY protocol v2 command")),
+    }
+    match family >> 4 {
+        1 if len >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&payload[..4]).unwrap());
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        2 if len >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&payload[..16]).unwrap());
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        1 | 2 => Err(invalid("PROXY protocol v2 address block too short")),
+        // Unspecified or Unix socket addresses
+        _ => Ok(None),
+    }
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
@@ -1,26 +1,62 @@
+use crate::config::DoqPoolConfig;
 use anyhow::{Context, Result};
 use quinn::crypto::rustls::QuicClientConfig;
 use quinn::rustls::{ClientConfig, RootCertStore};
-use quinn::{ClientConfig as QuinnClientConfig, Connection, Endpoint};
+use quinn::{
+    ClientConfig as QuinnClientConfig, Connection, Endpoint, IdleTimeout, TransportConfig,
+};
 use std::net::SocketAddr;
 use std::sync::Arc;
 
+/// ALPN identifier for DNS over QUIC (RFC 9250)
+pub const ALPN_DOQ: &[u8] = b"doq";
+
+/// ALPN identifier for HTTP/3
+pub const ALPN_H3: &[u8] = b"h3";
+
+/// QUIC transport settings for upstream connections from `[upstream.doq_pool]`
+///
+/// An idle timeout too large for QUIC to express leaves connections without one.
+pub fn upstream_transport_config(config: &DoqPoolConfig) -> TransportConfig {
+    let mut transport = TransportConfig::default();
+    transport.max_idle_timeout(IdleTimeout::try_from(config.idle_timeout()).ok());
+    transport.keep_alive_interval(config.keep_alive_interval());
+    transport
+}
+
 /// Create a QUIC client connection to upstream server
-pub async fn connect_quic_upstream(addr: SocketAddr, server_name: &str) -> Result<Connection> {
+/// `alpn` selects the application protocol (e.g. `ALPN_DOQ` or `ALPN_H3`)
+pub async fn connect_quic_upstream(
+    addr: SocketAddr,
+    server_name: &str,
+    alpn: &[u8],
+    transport: Arc<TransportConfig>,
+) -> Result<Connection> {
     // Create client TLS config with native root certificates
     let mut root_store = RootCertStore::empty();
     let cert_result = rustls_native_certs::load_native_certs();
-    for cert in cert_result.certs {
-        root_store.add(cert)?;
-    }
+    root_store.add_parsable_certificates(cert_result.certs);
 
     let client_crypto = ClientConfig::builder()
         .with_root_certificates(root_store)
         .with_no_client_auth();
+    connect_quic_with_tls(addr, server_name, client_crypto, alpn, transport).await
+}
+
+/// Create a QUIC client connection that verifies the upstream with `client_crypto`
+pub async fn connect_quic_with_tls(
+    addr: SocketAddr,
+    server_name: &str,
+    mut client_crypto: ClientConfig,
+    alpn: &[u8],
+    transport: Arc<TransportConfig>,
+) -> Result<Connection> {
+    client_crypto.alpn_protocols = vec![alpn.to_vec()];
 
     let quic_client_config =
         QuicClientConfig::try_from(client_crypto).context("Failed to create QuicClientConfig")?;
-    let client_config = QuinnClientConfig::new(Arc::new(quic_client_config));
+    let mut client_config = QuinnClientConfig::new(Arc::new(quic_client_config));
+    client_config.transport_config(transport);
 
     let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
     endpoint.set_default_client_config(client_config);
@@ -1,26 +1,120 @@
-use crate::config::AppConfig;
-use crate::tls_utils;
+use crate::config::{AppConfig, QuicConfig};
+use crate::error::DnsProxyResult;
+use crate::metrics::RejectReason;
+use crate::server::bind_udp_socket;
+use crate::tls_utils::{self, CertificateResolver};
 use anyhow::{Context, Result};
-use quinn::crypto::rustls::QuicServerConfig;
-use quinn::{Endpoint, ServerConfig};
+use futures::future::select_all;
+use quinn::crypto::rustls::{HandshakeData, QuicServerConfig};
+use quinn::{
+    Endpoint, EndpointConfig, IdleTimeout, Incoming, ServerConfig, TokioRuntime, TransportConfig,
+    VarInt,
+};
+use rustls::pki_types::CertificateDer;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
-/// Create a QUIC server endpoint from application config
-pub async fn create_quic_server_endpoint(
+/// Create the TLS configuration of a QUIC server endpoint, accepting 0-RTT
+/// data when `early_data` is set
+pub async fn create_quic_tls_config(
     config: &AppConfig,
-    bind_addr: SocketAddr,
-) -> Result<Endpoint> {
-    // Create TLS server configuration
-    let rustls_config = tls_utils::create_server_config(config)
+    alpn: &[String],
+    early_data: bool,
+) -> Result<(rustls::ServerConfig, Arc<CertificateResolver>)> {
+    let (mut rustls_config, resolver) = tls_utils::create_server_config(config, alpn)
         .await
         .context("Failed to create TLS server config")?;
+    // QUIC only allows no early data or an unlimited amount
+    rustls_config.max_early_data_size = if early_data { u32::MAX } else { 0 };
+    Ok((rustls_config, resolver))
+}
 
-    // rustls::ServerConfig is already compatible with quinn::rustls::ServerConfig
+/// Create a QUIC server endpoint on each of `bind_addrs` from application
+/// config, negotiating the ALPN protocols in `alpn` (e.g. `config.tls.alpn.doq`)
+///
+/// A wildcard IPv6 address is bound dual-stack. 0-RTT data is accepted when
+/// `early_data` is set; the server must then refuse what is unsafe to replay
+/// (see [`QuicConfig::allow_0rtt`]). Accepted connections must be checked
+/// with [`verify_quic_client`] using the returned resolver.
+pub async fn create_quic_server_endpoint(
+    config: &AppConfig,
+    bind_addrs: &[SocketAddr],
+    alpn: &[String],
+    early_data: bool,
+) -> Result<(Vec<Endpoint>, Arc<CertificateResolver>)> {
+    // Create TLS server configuration
+    let (rustls_config, resolver) = create_quic_tls_config(config, alpn, early_data).await?;
+
+    // quinn re-exports the rustls crate we build against, so this is the same
+    // type; the checked conversion only verifies TLS 1.3 and QUIC support
     let rustls_config_arc = Arc::new(rustls_config);
     let quic_server_config = QuicServerConfig::try_from(rustls_config_arc)
         .context("Failed to create QuicServerConfig")?;
-    let quinn_server_config = ServerConfig::with_crypto(Arc::new(quic_server_config));
+    let mut quinn_server_config = ServerConfig::with_crypto(Arc::new(quic_server_config));
+    quinn_server_config.transport_config(Arc::new(server_transport_config(&config.quic)));
+
+    let endpoints = bind_addrs
+        .iter()
+        .map(|addr| {
+            let socket = bind_udp_socket(*addr)?;
+            Endpoint::new(
+                EndpointConfig::default(),
+                Some(quinn_server_config.clone()),
+                socket,
+                Arc::new(TokioRuntime),
+            )
+        })
+        .collect::<std::io::Result<_>>()
+        .context("Failed to create QUIC endpoint")?;
+    Ok((endpoints, resolver))
+}
+
+/// QUIC transport parameters of server endpoints from the `[quic]` section
+pub fn server_transport_config(config: &QuicConfig) -> TransportConfig {
+    let mut transport = TransportConfig::default();
+    // Validation keeps the timeout within what QUIC can express
+    transport.max_idle_timeout(IdleTimeout::try_from(config.max_idle_timeout()).ok());
+    transport.max_concurrent_bidi_streams(VarInt::from_u32(config.max_concurrent_bidi_streams));
+    transport.keep_alive_interval(config.keep_alive_interval());
+    transport
+}
+
+/// Accept the next incoming connection of whichever endpoint has one first
+///
+/// Returns `None` once any of the endpoints is closed.
+pub async fn accept_incoming(endpoints: &[Endpoint]) -> Option<Incoming> {
+    let accepts = endpoints.iter().map(|endpoint| Box::pin(endpoint.accept()));
+    select_all(accepts).await.0
+}
+
+/// Apply the client certificate policy of the certificate served on an
+/// established QUIC connection
+pub fn verify_quic_client(
+    resolver: &CertificateResolver,
+    connection: &quinn::Connection,
+) -> DnsProxyResult<()> {
+    let server_name = quic_server_name(connection);
+    let client_certs = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok());
+    resolver.verify_client(
+        server_name.as_deref(),
+        client_certs.as_deref().map(Vec::as_slice),
+    )
+}
+
+/// Server name (SNI) a QUIC client asked for in its handshake
+pub fn quic_server_name(connection: &quinn::Connection) -> Option<String> {
+    connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<HandshakeData>().ok())
+        .and_then(|data| data.server_name)
+}
 
-    Endpoint::server(quinn_server_config, bind_addr).context("Failed to create QUIC endpoint")
+/// Classify a failed incoming QUIC handshake for rejected-connection reporting
+pub fn handshake_reject_reason(error: &quinn::ConnectionError) -> RejectReason {
+    match error {
+        quinn::ConnectionError::TimedOut => RejectReason::HandshakeTimeout,
+        _ => RejectReason::HandshakeFailed,
+    }
 }
@@ -0,0 +1,119 @@
+//! Per-client rate limiting for the ingress servers
+
+use crate::config::RateLimitConfig;
+use dashmap::DashMap;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::debug;
+
+/// How often buckets of clients that went quiet are dropped
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token bucket of a single client
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token-bucket rate limiter keyed by client IP
+///
+/// Each client starts with `burst` tokens, refilled at `requests_per_sec`, and
+/// every accepted request or connection takes one. Allowlisted IPs are never
+/// limited, and a rate of 0 disables limiting.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    allowlist: HashSet<IpAddr>,
+    buckets: DashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    /// Create a limiter; a `burst` of 0 defaults to `requests_per_sec`
+    pub fn new(requests_per_sec: u32, burst: u32) -> Self {
+        let burst = if burst == 0 { requests_per_sec } else { burst };
+        Self {
+            rate: requests_per_sec as f64,
+            burst: burst as f64,
+            allowlist: HashSet::new(),
+            buckets: DashMap::new(),
+        }
+    }
+
+    /// Exempt the given client IPs from limiting
+    pub fn with_allowlist(mut self, ips: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.allowlist.extend(ips);
+        self
+    }
+
+    /// Create a limiter from the `[ratelimit]` config
+    ///
+    /// Invalid allowlist entries are skipped; `AppConfig::validate` rejects them.
+    pub fn from_config(config: &RateLimitConfig) -> Self {
+        Self::new(config.requests_per_sec, config.burst)
+            .with_allowlist(config.allowlist_ips().unwrap_or_default())
+    }
+
+    /// Take a token for `ip`, returning false when the client is over its limit
+    pub fn check(&self, ip: IpAddr) -> bool {
+        self.check_at(ip, Instant::now())
+    }
+
+    /// [`check`](Self::check) as of `now`
+    pub fn check_at(&self, ip: IpAddr, now: Instant) -> bool {
+        if self.rate <= 0.0 || self.allowlist.contains(&ip) {
+            return true;
+        }
+
+        let mut bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Drop buckets that have refilled completely as of `now`; such clients
+    /// are indistinguishable from new ones
+    /// Returns the number of dropped buckets
+    pub fn evict_idle(&self, now: Instant) -> usize {
+        let before = self.buckets.len();
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.rate < self.burst
+        });
+        let evicted = before.saturating_sub(self.buckets.len());
+        if evicted > 0 {
+            debug!("Evicted {} idle rate limiter bucket(s)", evicted);
+        }
+        evicted
+    }
+
+    /// Spawn a background task that drops idle buckets periodically
+    ///
+    /// The task holds only a weak reference and exits once the limiter is dropped.
+    pub fn spawn_reaper(self: &Arc<Self>) -> JoinHandle<()> {
+        let limiter: Weak<Self> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REAP_INTERVAL);
+            // The first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let Some(limiter) = limiter.upgrade() else {
+                    break;
+                };
+                limiter.evict_idle(Instant::now());
+            }
+        })
+    }
+}
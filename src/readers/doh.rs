@@ -1,37 +1,177 @@
-use crate::config::AppConfig;
-use crate::error::DnsProxyResult;
-use crate::metrics::Metrics;
-use crate::proxy::handle_http_request;
+use crate::config::{AppConfig, ServerPortConfig};
+use crate::dns::cache::ResponseCache;
+use crate::error::{DnsProxyError, DnsProxyResult};
+use crate::logging::{connection_span, log_rejected_connection, record_sni, request_span};
+use crate::metrics::{Metrics, RejectReason};
+use crate::proxy::{
+    handle_http_request, misdirected_if_host_not_allowed, set_request_id,
+    too_many_requests_response,
+};
+use crate::proxy_protocol;
+use crate::ratelimit::RateLimiter;
+use crate::readers::{Protocol, ProtocolServer};
 use crate::rewrite::SniRewriterType;
+use crate::server::{
+    AcceptBackoff, ConnectionTracker, ReadySignal, ServerResources, TcpListeners, join_addrs,
+};
+use crate::tls_utils::{self, CertificateResolver};
 use crate::upstream::create_connection_pool;
+use crate::upstream::health::UpstreamHealth;
+use crate::upstream::http::buffered_body;
 use crate::upstream::pool::ConnectionPool;
-use crate::utils::backoff::BackoffCounter;
-use hyper::server::conn::http1;
 use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use std::future::Future;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::net::{IpAddr, Ipv4Addr};
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::net::TcpListener;
-use tracing::{error, info};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+use tokio_util::either::Either;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info};
+
+/// Time allowed for a client to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Client address of connections on the Unix socket, which all come from a
+/// reverse proxy on the same host; rate limits and logs see them as one
+/// local client
+#[cfg(unix)]
+const UDS_PEER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Listener of the DoH server: TCP sockets on every bind address, or a Unix
+/// domain socket for a reverse proxy in front of it
+enum DohListener {
+    Tcp(TcpListeners),
+    #[cfg(unix)]
+    Unix {
+        listener: tokio::net::UnixListener,
+        path: PathBuf,
+    },
+}
+
+/// Connection accepted by a [`DohListener`]
+enum Accepted {
+    Tcp(TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+impl DohListener {
+    /// Bind `uds_path` when set, every `bind_address` at `port` otherwise
+    fn bind(config: &ServerPortConfig) -> DnsProxyResult<Self> {
+        match &config.uds_path {
+            #[cfg(unix)]
+            Some(path) => {
+                use std::os::unix::fs::FileTypeExt;
+
+                // A socket left behind by a previous run would fail the bind
+                let path = PathBuf::from(path);
+                if std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    std::fs::remove_file(&path)?;
+                }
+                let listener = tokio::net::UnixListener::bind(&path)?;
+                Ok(Self::Unix { listener, path })
+            }
+            #[cfg(not(unix))]
+            Some(_) => Err(DnsProxyError::Config(
+                "servers.doh.uds_path is only supported on Unix".to_string(),
+            )),
+            None => {
+                let bind_addrs = config
+                    .socket_addrs()
+                    .map_err(|e| DnsProxyError::Config(e.to_string()))?;
+                Ok(Self::Tcp(TcpListeners::bind(&bind_addrs)?))
+            }
+        }
+    }
+
+    /// Where the listener accepts connections, for log messages
+    fn describe(&self) -> String {
+        match self {
+            Self::Tcp(listeners) => format!("TCP {}", join_addrs(&listeners.local_addrs())),
+            #[cfg(unix)]
+            Self::Unix { path, .. } => format!("Unix socket {}", path.display()),
+        }
+    }
+
+    /// Accept the next connection; cancel safe
+    async fn accept(&self) -> std::io::Result<Accepted> {
+        match self {
+            Self::Tcp(listeners) => {
+                let (stream, addr) = listeners.accept().await?;
+                Ok(Accepted::Tcp(stream, addr))
+            }
+            #[cfg(unix)]
+            Self::Unix { listener, .. } => {
+                let (stream, _) = listener.accept().await?;
+                Ok(Accepted::Unix(stream))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for DohListener {
+    fn drop(&mut self) {
+        if let Self::Unix { path, .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
 
 pub struct DoHServer {
     config: Arc<AppConfig>,
     rewriter: SniRewriterType,
     pool: Arc<ConnectionPool>,
-    backoff: Arc<BackoffCounter>,
+    health: Arc<UpstreamHealth>,
+    cache: Arc<ResponseCache>,
+    limiter: Arc<RateLimiter>,
     metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+    ready: ReadySignal,
 }
 
 impl DoHServer {
     pub fn new(config: Arc<AppConfig>, rewriter: SniRewriterType, metrics: Arc<Metrics>) -> Self {
-        Self {
-            config,
-            rewriter,
-            pool: create_connection_pool(),
-            backoff: Arc::new(BackoffCounter::new()),
-            metrics,
-        }
+        Self::from_resources(ServerResources::standalone(config, rewriter, metrics))
     }
 
+    /// Pick `upstream_protocol` upstreams using the health reported by a
+    /// shared prober
+    pub fn with_upstream_health(mut self, health: Arc<UpstreamHealth>) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Share a response cache with other listeners
+    pub fn with_response_cache(mut self, cache: Arc<ResponseCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Stop accepting and drain open connections once `shutdown` is cancelled
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Report on `ready` once listening
+    pub fn with_ready(mut self, ready: ReadySignal) -> Self {
+        self.ready = ready;
+        self
+    }
+}
+
+impl DoHServer {
     pub async fn start(&self) -> DnsProxyResult<()> {
         let server_config = &self.config.servers.doh;
         if !server_config.enabled {
@@ -39,52 +179,261 @@ impl DoHServer {
             return Ok(());
         }
 
-        let bind_addr = format!("{}:{}", server_config.bind_address, server_config.port);
-        let listener = TcpListener::bind(&bind_addr).await?;
+        // Without `tls`, plain HTTP is served to a TLS-terminating proxy
+        let tls = if server_config.tls {
+            let (server_tls_config, tls_resolver) =
+                tls_utils::create_server_config(self.config.as_ref(), &self.config.tls.alpn.doh)
+                    .await
+                    .map_err(|e| DnsProxyError::Tls(e.to_string()))?;
+            Some((TlsAcceptor::from(Arc::new(server_tls_config)), tls_resolver))
+        } else {
+            None
+        };
+
+        let listener = DohListener::bind(server_config)?;
+        let bind_addr = listener.describe();
+
+        if tls.is_some() {
+            info!("DoH server listening on {}", bind_addr);
+        } else {
+            info!("DoH server listening on {} (plain HTTP)", bind_addr);
+        }
+        self.ready.listening();
+
+        // Drop pooled clients for rewrite targets that stop receiving queries
+        let _reaper = self.pool.spawn_reaper();
+        let _limiter_reaper = self.limiter.spawn_reaper();
+
+        let mut connections = ConnectionTracker::new().with_limit(
+            self.config.servers.doh.max_concurrent_connections,
+            self.metrics.in_flight_gauge("doh"),
+        );
+
+        let mut backoff = AcceptBackoff::from_config(&self.config.servers, self.shutdown.clone());
+        loop {
+            // At the limit, leave new connections queued until one finishes
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = connections.reserve() => {}
+            }
+            let accepted = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                accepted = listener.accept() => accepted,
+            };
+            match accepted {
+                Ok(Accepted::Tcp(stream, addr)) => {
+                    backoff.succeeded();
+                    connections.spawn(self.serve_connection(stream, addr, tls.clone()));
+                }
+                #[cfg(unix)]
+                Ok(Accepted::Unix(stream)) => {
+                    backoff.succeeded();
+                    connections.spawn(self.serve_connection(stream, UDS_PEER_ADDR, tls.clone()));
+                }
+                Err(e) => {
+                    backoff.failed("DoH", &e).await;
+                }
+            }
+        }
 
-        info!("DoH server listening on TCP {}", bind_addr);
+        drop(listener);
+        connections.drain("DoH").await;
+        Ok(())
+    }
 
+    /// Serve HTTP on an accepted connection from `peer`, reading its PROXY
+    /// protocol header and completing the TLS handshake first when enabled
+    fn serve_connection<S>(
+        &self,
+        mut stream: S,
+        peer: SocketAddr,
+        tls: Option<(TlsAcceptor, Arc<CertificateResolver>)>,
+    ) -> impl Future<Output = ()> + Send + 'static
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
         let rewriter = Arc::clone(&self.rewriter);
         let pool = Arc::clone(&self.pool);
+        let health = Arc::clone(&self.health);
+        let cache = Arc::clone(&self.cache);
         let metrics = Arc::clone(&self.metrics);
-
-        loop {
-            match listener.accept().await {
-                Ok((stream, addr)) => {
+        let config = Arc::clone(&self.config);
+        let limiter = Arc::clone(&self.limiter);
+        let shutdown = self.shutdown.clone();
+        let proxy_protocol = self.config.servers.doh.proxy_protocol;
+        async move {
+            let Some(addr) = proxy_protocol::client_addr(
+                &mut stream,
+                peer,
+                proxy_protocol,
+                "DoH",
+                &config.logging,
+                &metrics,
+            )
+            .await
+            else {
+                return;
+            };
+            async move {
+                // The connection's SNI and resolver, to check each request's
+                // Host against the certificate the handshake was made for
+                let (stream, handshake) = match tls {
+                    Some((acceptor, tls_resolver)) => {
+                        let accepted = Self::accept_tls(
+                            &acceptor,
+                            &tls_resolver,
+                            stream,
+                            addr,
+                            &config,
+                            &metrics,
+                        )
+                        .await;
+                        match accepted {
+                            Some(tls_stream) => {
+                                let server_name =
+                                    tls_stream.get_ref().1.server_name().map(str::to_string);
+                                (Either::Right(tls_stream), Some((tls_resolver, server_name)))
+                            }
+                            None => return,
+                        }
+                    }
+                    None => (Either::Left(stream), None),
+                };
+                let io = TokioIo::new(stream);
+                let service = service_fn(move |req| {
                     let rewriter = Arc::clone(&rewriter);
                     let pool = Arc::clone(&pool);
+                    let health = Arc::clone(&health);
+                    let cache = Arc::clone(&cache);
                     let metrics = Arc::clone(&metrics);
-                    tokio::spawn(async move {
-                        let io = TokioIo::new(stream);
-                        let service = service_fn(move |req| {
-                            let rewriter = Arc::clone(&rewriter);
-                            let pool = Arc::clone(&pool);
-                            let metrics = Arc::clone(&metrics);
-                            let client_addr = addr;
-                            async move {
-                                handle_http_request(req, rewriter, &pool, metrics)
-                                    .await
-                                    .map_err(|e| {
-                                        error!("DoH handler error from {}: {}", client_addr, e);
-                                        std::io::Error::other(e.to_string())
-                                    })
+                    let config = Arc::clone(&config);
+                    let limiter = Arc::clone(&limiter);
+                    let handshake = handshake.clone();
+                    let client_addr = addr;
+                    async move {
+                        let (request_id, span) = request_span();
+                        let mut response = async {
+                            if !limiter.check(client_addr.ip()) {
+                                tracing::debug!(
+                                    "DoH client {} is over its rate limit",
+                                    client_addr
+                                );
+                                metrics.record_rate_limited();
+                                return too_many_requests_response().map(buffered_body);
                             }
-                        });
-
-                        if let Err(e) = http1::Builder::new().serve_connection(io, service).await {
-                            error!("DoH connection error from {}: {}", addr, e);
-                        } else {
-                            tracing::debug!("DoH connection from {} completed", addr);
+                            if let Some((tls_resolver, server_name)) = &handshake
+                                && let Some(response) = misdirected_if_host_not_allowed(
+                                    &req,
+                                    server_name.as_deref(),
+                                    tls_resolver,
+                                )
+                            {
+                                tracing::debug!(
+                                    "DoH client {} asked for a Host not served on its connection",
+                                    client_addr
+                                );
+                                return response.map(buffered_body);
+                            }
+                            handle_http_request(
+                                req, rewriter, &pool, &health, &cache, &config, metrics,
+                            )
+                            .await
                         }
-                    });
+                        .instrument(span)
+                        .await;
+                        set_request_id(response.headers_mut(), &request_id);
+                        Ok::<_, std::io::Error>(response)
+                    }
+                });
+
+                // Serve HTTP/1.1 or HTTP/2, picked from the client preface
+                let builder = auto::Builder::new(TokioExecutor::new());
+                let conn = builder.serve_connection(io, service);
+                tokio::pin!(conn);
+                // On shutdown, finish the request in flight but close
+                // the connection instead of waiting for the next one
+                let result = tokio::select! {
+                    result = conn.as_mut() => result,
+                    _ = shutdown.cancelled() => {
+                        conn.as_mut().graceful_shutdown();
+                        conn.await
+                    }
+                };
+                if let Err(e) = result {
+                    let incomplete = e
+                        .downcast_ref::<hyper::Error>()
+                        .is_some_and(hyper::Error::is_incomplete_message);
+                    if incomplete {
+                        // Client hung up mid-request; not a server fault
+                        tracing::debug!("DoH client {} disconnected: {}", addr, e);
+                    } else {
+                        error!("DoH connection error from {}: {}", addr, e);
+                    }
+                } else {
+                    tracing::debug!("DoH connection from {} completed", addr);
                 }
-                Err(e) => {
-                    error!("DoH accept error on {}: {}", bind_addr, e);
-                    // Use exponential backoff to prevent tight error loop
-                    let delay = self.backoff.next_delay(100, 5000);
-                    tokio::time::sleep(delay).await;
+            }
+            .instrument(connection_span("DoH", addr))
+            .await
+        }
+    }
+
+    /// Complete the TLS handshake of an accepted connection, returning
+    /// `None` (and counting the rejection) when it fails, times out or the
+    /// client certificate is refused
+    async fn accept_tls<S>(
+        acceptor: &TlsAcceptor,
+        tls_resolver: &CertificateResolver,
+        stream: S,
+        addr: SocketAddr,
+        config: &AppConfig,
+        metrics: &Metrics,
+    ) -> Option<TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let handshake = tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+        let (reason, error) = match handshake.await {
+            Ok(Ok(tls_stream)) => {
+                let (_, session) = tls_stream.get_ref();
+                record_sni(session.server_name());
+                match tls_resolver.verify_client(session.server_name(), session.peer_certificates())
+                {
+                    Ok(()) => return Some(tls_stream),
+                    Err(e) => (RejectReason::ClientCertRejected, e.to_string()),
                 }
             }
+            Ok(Err(e)) => (RejectReason::HandshakeFailed, e.to_string()),
+            Err(e) => (RejectReason::HandshakeTimeout, e.to_string()),
+        };
+        if reason != RejectReason::ClientCertRejected {
+            metrics.record_tls_handshake_error("doh");
         }
+        log_rejected_connection(&config.logging, metrics, "DoH", addr, reason, &error);
+        None
+    }
+}
+
+impl ProtocolServer for DoHServer {
+    const PROTOCOL: Protocol = Protocol::Doh;
+
+    fn from_resources(resources: ServerResources) -> Self {
+        let pool = create_connection_pool(&resources.config.upstream);
+        let limiter = Arc::new(RateLimiter::from_config(&resources.config.ratelimit));
+        Self {
+            config: resources.config,
+            rewriter: resources.rewriter,
+            pool,
+            health: resources.upstream_health,
+            cache: resources.response_cache,
+            limiter,
+            metrics: resources.metrics,
+            shutdown: resources.shutdown,
+            ready: resources.ready,
+        }
+    }
+
+    async fn run(&self) -> DnsProxyResult<()> {
+        self.start().await
     }
 }
@@ -1,35 +1,84 @@
 use crate::config::AppConfig;
+use crate::dns::cache::ResponseCache;
 use crate::error::{DnsProxyError, DnsProxyResult};
-use crate::metrics::{Metrics, Timer};
-use crate::quic::create_quic_server_endpoint;
+use crate::logging::{
+    connection_span, log_access, log_rejected_connection, record_sni, record_target, request_span,
+};
+use crate::metrics::{Metrics, RejectReason, Timer};
+use crate::proxy::http::{
+    DohTarget, doh_query_message, doh_upstream_path_and_query, forbidden_if_denied,
+    forwarded_headers, misdirected_if_host_not_allowed, reject_non_doh_request, set_request_id,
+};
+use crate::quic::{
+    accept_incoming, create_quic_server_endpoint, handshake_reject_reason, quic_server_name,
+    verify_quic_client,
+};
+use crate::ratelimit::RateLimiter;
+use crate::readers::{Protocol, ProtocolServer};
 use crate::rewrite::SniRewriterType;
-use crate::sni::SniRewriter;
+use crate::server::{ConnectionTracker, ReadySignal, ServerResources, join_addrs};
+use crate::tls_utils::CertificateResolver;
+use crate::upstream::create_connection_pool;
+use crate::upstream::health::UpstreamHealth;
+use crate::upstream::http::gateway_timeout_response;
+use crate::upstream::ladder::UpstreamProtocol;
 use crate::upstream::pool::ConnectionPool;
-use crate::upstream::{create_connection_pool, forward_http_request};
 use bytes::{Buf, Bytes};
+use h3::error::Code;
 use h3::server::Connection as H3ServerConnection;
-use hyper::Method;
-use std::net::SocketAddr;
+use hyper::{Method, Response, StatusCode};
 use std::sync::Arc;
-use tracing::{debug, error, info};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, debug, error, info, warn};
+
+/// HTTP/3 error code (RFC 9114) used to close a connection with a rejected client
+const H3_GENERAL_PROTOCOL_ERROR: u32 = 0x101;
 
 pub struct DoH3Server {
     config: Arc<AppConfig>,
     rewriter: SniRewriterType,
     pool: Arc<ConnectionPool>,
+    health: Arc<UpstreamHealth>,
+    cache: Arc<ResponseCache>,
+    limiter: Arc<RateLimiter>,
     metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+    ready: ReadySignal,
 }
 
 impl DoH3Server {
     pub fn new(config: Arc<AppConfig>, rewriter: SniRewriterType, metrics: Arc<Metrics>) -> Self {
-        Self {
-            config,
-            rewriter,
-            pool: create_connection_pool(),
-            metrics,
-        }
+        Self::from_resources(ServerResources::standalone(config, rewriter, metrics))
     }
 
+    /// Pick `upstream_protocol` upstreams using the health reported by a
+    /// shared prober
+    pub fn with_upstream_health(mut self, health: Arc<UpstreamHealth>) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Share a response cache with other listeners
+    pub fn with_response_cache(mut self, cache: Arc<ResponseCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Stop accepting and drain open connections once `shutdown` is cancelled
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Report on `ready` once listening
+    pub fn with_ready(mut self, ready: ReadySignal) -> Self {
+        self.ready = ready;
+        self
+    }
+}
+
+impl DoH3Server {
     pub async fn start(&self) -> DnsProxyResult<()> {
         let server_config = &self.config.servers.doh3;
         if !server_config.enabled {
@@ -37,33 +86,98 @@ impl DoH3Server {
             return Ok(());
         }
 
-        let bind_addr = format!("{}:{}", server_config.bind_address, server_config.port);
-        let addr: SocketAddr = bind_addr
-            .parse()
-            .map_err(|e| DnsProxyError::InvalidInput(format!("Invalid bind address: {}", e)))?;
+        let bind_addrs = server_config
+            .socket_addrs()
+            .map_err(|e| DnsProxyError::Config(e.to_string()))?;
 
-        let endpoint = create_quic_server_endpoint(self.config.as_ref(), addr).await?;
-        info!("DoH3 server listening on UDP {}", addr);
+        let (endpoints, tls_resolver) = create_quic_server_endpoint(
+            self.config.as_ref(),
+            &bind_addrs,
+            &self.config.tls.alpn.doh3,
+            false,
+        )
+        .await?;
+        info!("DoH3 server listening on UDP {}", join_addrs(&bind_addrs));
+        self.ready.listening();
+
+        // Drop pooled clients for rewrite targets that stop receiving queries
+        let _reaper = self.pool.spawn_reaper();
+        let _limiter_reaper = self.limiter.spawn_reaper();
 
-        let rewriter = Arc::clone(&self.rewriter);
-        let pool = Arc::clone(&self.pool);
-        let metrics = Arc::clone(&self.metrics);
+        let context = RequestContext {
+            rewriter: Arc::clone(&self.rewriter),
+            pool: Arc::clone(&self.pool),
+            health: Arc::clone(&self.health),
+            cache: Arc::clone(&self.cache),
+            config: Arc::clone(&self.config),
+            metrics: Arc::clone(&self.metrics),
+            tls_resolver: Arc::clone(&tls_resolver),
+            server_name: None,
+        };
 
-        while let Some(conn) = endpoint.accept().await {
-            let rewriter = Arc::clone(&rewriter);
-            let pool = Arc::clone(&pool);
-            let metrics = Arc::clone(&metrics);
-            tokio::spawn(async move {
+        let mut connections = ConnectionTracker::new().with_limit(
+            self.config.servers.doh3.max_concurrent_connections,
+            self.metrics.in_flight_gauge("doh3"),
+        );
+        loop {
+            // At the limit, leave new connections queued until one finishes
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = connections.reserve() => {}
+            }
+            let conn = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                conn = accept_incoming(&endpoints) => match conn {
+                    Some(conn) => conn,
+                    // quinn has no transient accept errors (failed handshakes
+                    // end in the connection's task): an endpoint was closed,
+                    // which no retry recovers from
+                    None => {
+                        error!("DoH3 endpoint closed, no longer accepting connections");
+                        break;
+                    }
+                },
+            };
+            if !self.limiter.check(conn.remote_address().ip()) {
+                tracing::debug!(
+                    "DoH3 client {} is over its rate limit",
+                    conn.remote_address()
+                );
+                self.metrics.record_rate_limited();
+                conn.refuse();
+                continue;
+            }
+            let context = context.clone();
+            let metrics = Arc::clone(&self.metrics);
+            let config = Arc::clone(&self.config);
+            let tls_resolver = Arc::clone(&tls_resolver);
+            let peer = conn.remote_address();
+            let connection = async move {
                 match conn.await {
                     Ok(connection) => {
+                        let context = RequestContext {
+                            server_name: quic_server_name(&connection),
+                            ..context
+                        };
+                        record_sni(context.server_name.as_deref());
+                        if let Err(e) = verify_quic_client(&tls_resolver, &connection) {
+                            log_rejected_connection(
+                                &config.logging,
+                                &metrics,
+                                "DoH3",
+                                peer,
+                                RejectReason::ClientCertRejected,
+                                &e,
+                            );
+                            connection
+                                .close(H3_GENERAL_PROTOCOL_ERROR.into(), b"client certificate");
+                            return;
+                        }
                         let remote_addr = connection.remote_address();
                         info!("New DoH3 connection from {}", remote_addr);
-                        let metrics_clone = Arc::clone(&metrics);
-                        if let Err(e) =
-                            Self::handle_connection(connection, rewriter, pool, metrics).await
-                        {
+                        if let Err(e) = Self::handle_connection(connection, context).await {
                             error!("DoH3 connection handling error from {}: {}", remote_addr, e);
-                            metrics_clone.record_upstream_error();
+                            metrics.record_upstream_error();
                         } else {
                             debug!(
                                 "DoH3 connection from {} completed successfully",
@@ -72,20 +186,28 @@ impl DoH3Server {
                         }
                     }
                     Err(e) => {
-                        error!("DoH3 connection establishment error: {}", e);
+                        metrics.record_tls_handshake_error("doh3");
+                        log_rejected_connection(
+                            &config.logging,
+                            &metrics,
+                            "DoH3",
+                            peer,
+                            handshake_reject_reason(&e),
+                            &e,
+                        );
                     }
                 }
-            });
+            };
+            connections.spawn(connection.instrument(connection_span("DoH3", peer)));
         }
 
+        connections.drain("DoH3").await;
         Ok(())
     }
 
     async fn handle_connection(
         connection: quinn::Connection,
-        rewriter: SniRewriterType,
-        pool: Arc<ConnectionPool>,
-        metrics: Arc<Metrics>,
+        context: RequestContext,
     ) -> DnsProxyResult<()> {
         // Create H3 connection from quinn connection
         let mut conn = H3ServerConnection::new(h3_quinn::Connection::new(connection))
@@ -94,18 +216,47 @@ impl DoH3Server {
                 DnsProxyError::Protocol(format!("Failed to create H3 connection: {}", e))
             })?;
 
+        let limits = &context.config.servers.doh3;
+        let max_requests = limits.max_requests_per_connection;
+        let streams = (limits.max_concurrent_streams_per_connection > 0)
+            .then(|| Arc::new(Semaphore::new(limits.max_concurrent_streams_per_connection)));
+        let mut accepted = 0usize;
+
         loop {
             match conn.accept().await {
                 Ok(Some(resolver)) => {
-                    let rewriter = Arc::clone(&rewriter);
-                    let pool = Arc::clone(&pool);
-                    let metrics = Arc::clone(&metrics);
-                    tokio::spawn(async move {
+                    accepted += 1;
+                    if max_requests > 0 && accepted == max_requests {
+                        // Ask the client to move on; h3 refuses any request
+                        // opened after this one with H3_REQUEST_REJECTED
+                        debug!(
+                            "DoH3 connection reached {} requests, sending GOAWAY",
+                            max_requests
+                        );
+                        conn.shutdown(0).await.map_err(|e| {
+                            DnsProxyError::Protocol(format!("Failed to send GOAWAY: {}", e))
+                        })?;
+                    }
+                    // A request holds its permit until its response is sent
+                    let permit = match &streams {
+                        Some(streams) => match Arc::clone(streams).try_acquire_owned() {
+                            Ok(permit) => Some(permit),
+                            Err(_) => {
+                                tokio::spawn(Self::reject_request(resolver));
+                                continue;
+                            }
+                        },
+                        None => None,
+                    };
+                    let context = context.clone();
+                    let (request_id, span) = request_span();
+                    let request = async move {
+                        let _permit = permit;
                         // Resolve the request
                         match resolver.resolve_request().await {
                             Ok((req, stream)) => {
                                 if let Err(e) =
-                                    Self::handle_request(req, stream, rewriter, pool, metrics).await
+                                    Self::handle_request(req, stream, &request_id, &context).await
                                 {
                                     error!("DoH3 request handling error: {}", e);
                                 } else {
@@ -116,7 +267,8 @@ impl DoH3Server {
                                 error!("DoH3 request resolution error: {}", e);
                             }
                         }
-                    });
+                    };
+                    tokio::spawn(request.instrument(span));
                 }
                 Ok(None) => {
                     // Connection closed
@@ -133,18 +285,61 @@ impl DoH3Server {
         Ok(())
     }
 
+    /// Refuse a request over the connection's concurrent stream limit
+    async fn reject_request(resolver: h3::server::RequestResolver<h3_quinn::Connection, Bytes>) {
+        match resolver.resolve_request().await {
+            Ok((_, mut stream)) => {
+                warn!("DoH3 concurrent stream limit reached, rejecting request");
+                stream.stop_sending(Code::H3_REQUEST_REJECTED);
+                stream.stop_stream(Code::H3_REQUEST_REJECTED);
+            }
+            Err(e) => debug!("DoH3 rejected request resolution error: {}", e),
+        }
+    }
+
     async fn handle_request(
         req: hyper::Request<()>,
         mut stream: h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
-        rewriter: SniRewriterType,
-        pool: Arc<ConnectionPool>,
-        metrics: Arc<Metrics>,
+        request_id: &str,
+        context: &RequestContext,
     ) -> DnsProxyResult<()> {
+        let RequestContext {
+            rewriter,
+            pool,
+            health,
+            cache,
+            config,
+            metrics,
+            tls_resolver,
+            server_name,
+        } = context;
         let timer = Timer::start();
         let method = req.method().clone();
         let uri = req.uri().clone();
         info!("New DoH3 request: {} {}", method, uri);
 
+        if let Some(response) = reject_non_doh_request(&req, &config.servers) {
+            debug!(
+                "Rejecting DoH3 {} request to {}: {}",
+                method,
+                uri,
+                response.status()
+            );
+            log_access(&response.status().as_u16(), 0, 0, timer.elapsed());
+            return Self::send_response(&mut stream, response.map(|_| ()), request_id).await;
+        }
+
+        if let Some(response) =
+            misdirected_if_host_not_allowed(&req, server_name.as_deref(), tls_resolver)
+        {
+            debug!(
+                "Rejecting DoH3 {} request to {}: Host not served on this connection",
+                method, uri
+            );
+            log_access(&response.status().as_u16(), 0, 0, timer.elapsed());
+            return Self::send_response(&mut stream, response.map(|_| ()), request_id).await;
+        }
+
         let host = req
             .headers()
             .get("host")
@@ -156,110 +351,265 @@ impl DoH3Server {
                 ))
             })?;
 
+        record_sni(Some(host));
         debug!("Processing DoH3 request for host: {}", host);
 
-        let rewrite_result = rewriter.rewrite(host).await.ok_or_else(|| {
-            DnsProxyError::SniRewrite(crate::error::SniRewriteError::NoMatchingBaseDomain {
-                hostname: host.to_string(),
-            })
-        })?;
-
-        // Record SNI rewrite
-        metrics.record_sni_rewrite();
-
-        info!(
-            "DoH3 request: {} {} -> SNI rewrite: {} -> {} -> Target: {}",
-            method,
-            uri.path(),
-            rewrite_result.original,
-            rewrite_result.prefix,
-            rewrite_result.target_hostname
-        );
-
-        // Build upstream URI without unnecessary allocation
-        let path_and_query = req
-            .uri()
-            .path_and_query()
-            .map(|pq| pq.as_str())
-            .unwrap_or("/");
-        let upstream_uri = format!(
-            "https://{}{}",
-            rewrite_result.target_hostname, path_and_query
-        );
+        if let Some(response) = forbidden_if_denied(host, &config.filter) {
+            debug!(
+                "Refusing DoH3 request for {} denied by the domain filter",
+                host
+            );
+            metrics.record_blocked_request();
+            log_access(&response.status().as_u16(), 0, 0, timer.elapsed());
+            return Self::send_response(&mut stream, response.map(|_| ()), request_id).await;
+        }
 
-        debug!("Forwarding DoH3 request to upstream: {}", upstream_uri);
-
-        // Read request body if POST (zerocopy where possible)
-        let body = if *req.method() == Method::POST {
-            let mut body_data = Vec::new();
-            loop {
-                match stream.recv_data().await {
-                    Ok(Some(mut chunk)) => {
-                        while chunk.has_remaining() {
-                            body_data.extend_from_slice(chunk.chunk());
-                            chunk.advance(chunk.chunk().len());
-                        }
-                    }
-                    Ok(None) => break,
+        // With an upstream_protocol, every query goes to that protocol's
+        // upstream instead of the host its Host header rewrites to
+        let target = match UpstreamProtocol::of_server(&config.servers.doh3) {
+            Some(protocol) => {
+                record_target(&protocol);
+                info!(
+                    "DoH3 request: {} {} -> {} upstream",
+                    method,
+                    uri.path(),
+                    protocol
+                );
+                DohTarget::Protocol(protocol)
+            }
+            None => {
+                let rewrite_result = match rewriter.rewrite_with_reason(host).await {
+                    Ok(rewrite_result) => rewrite_result,
                     Err(e) => {
-                        return Err(DnsProxyError::Protocol(format!(
-                            "Failed to read DoH3 request body: {}",
-                            e
-                        )));
+                        metrics.record_rewrite_failure(&e);
+                        return Err(DnsProxyError::SniRewrite(e));
                     }
+                };
+                metrics.record_rewrite(Some(&rewrite_result));
+
+                record_target(&rewrite_result.target_hostname);
+
+                info!(
+                    "DoH3 request: {} {} -> SNI rewrite: {} -> {} -> Target: {}",
+                    method,
+                    uri.path(),
+                    rewrite_result.original,
+                    rewrite_result.prefix,
+                    rewrite_result.target_hostname
+                );
+
+                // Build upstream URI, taking the path from servers.doh_upstream_path
+                // or upstream.doh3 when either has one
+                let path_and_query = doh_upstream_path_and_query(
+                    &uri,
+                    &config.servers,
+                    config.upstream.doh3.first().map(String::as_str),
+                );
+                let upstream_uri = format!(
+                    "https://{}{}",
+                    rewrite_result.target_hostname, path_and_query
+                );
+
+                debug!("Forwarding DoH3 request to upstream: {}", upstream_uri);
+                DohTarget::Rewritten {
+                    hostname: rewrite_result.target_hostname,
+                    uri: upstream_uri,
+                }
+            }
+        };
+
+        // Read the body of a POST; a client that stalls past the body
+        // timeout has its stream cancelled
+        let post_body = if method == Method::POST {
+            let body_timeout = config.servers.request_body_timeout();
+            match tokio::time::timeout(
+                body_timeout,
+                Self::read_body(&mut stream, config.servers.max_message_size),
+            )
+            .await
+            {
+                Ok(body) => Some(body?),
+                Err(_) => {
+                    let duration = timer.elapsed();
+                    metrics.record_request(false, 0, 0, duration);
+                    log_access(&"timeout", 0, 0, duration);
+                    // The receive side is left to be dropped with the
+                    // stream: h3 can't stop it while a read was cut short
+                    stream.stop_stream(Code::H3_REQUEST_CANCELLED);
+                    return Err(DnsProxyError::Protocol(format!(
+                        "DoH3 request body not received within {}ms",
+                        body_timeout.as_millis()
+                    )));
                 }
             }
-            debug!("Read DoH3 request body: {} bytes", body_data.len());
-            Bytes::from(body_data)
         } else {
-            Bytes::new()
+            None
+        };
+        let body = match doh_query_message(&uri, post_body, config) {
+            Ok(body) => body,
+            Err(status) => {
+                debug!("Rejecting DoH3 {} request to {}: {}", method, uri, status);
+                if status == StatusCode::PAYLOAD_TOO_LARGE {
+                    metrics.record_oversized_rejected();
+                }
+                log_access(&status.as_u16(), 0, 0, timer.elapsed());
+                let mut response = Response::new(());
+                *response.status_mut() = status;
+                return Self::send_response(&mut stream, response, request_id).await;
+            }
         };
+        let headers = forwarded_headers(req.headers(), &config.upstream);
 
         let bytes_received = body.len() as u64;
 
-        // Forward request to upstream using connection pool for connection reuse
-        let result = forward_http_request(
-            &pool,
-            &upstream_uri,
-            &rewrite_result.target_hostname,
-            req.method().clone(),
-            req.headers(),
-            body,
+        // Forward request to upstream using connection pool for connection
+        // reuse; the whole upstream leg, retries included, is bounded by the
+        // upstream timeout
+        let result = match tokio::time::timeout(
+            config.upstream.timeout(),
+            target.forward(pool, health, cache, config, &headers, body, metrics),
         )
-        .await;
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => {
+                let duration = timer.elapsed();
+                metrics.record_request(false, bytes_received, 0, duration);
+                metrics.record_upstream_error();
+                log_access(&"timeout", bytes_received, 0, duration);
+                stream.stop_stream(Code::H3_REQUEST_CANCELLED);
+                return Err(DnsProxyError::Upstream(
+                    crate::error::UpstreamError::Timeout {
+                        upstream: target.upstream().to_string(),
+                        timeout_ms: config.upstream.upstream_timeout_ms,
+                    },
+                ));
+            }
+        };
 
         let duration = timer.elapsed();
 
         let response = match result {
             Ok((resp, bytes_sent)) => {
                 metrics.record_request(true, bytes_received, bytes_sent, duration);
-                resp
+                log_access(
+                    &resp.status().as_u16(),
+                    bytes_received,
+                    bytes_sent,
+                    duration,
+                );
+                resp.map(|_| ())
             }
             Err(e) => {
                 debug!("DoH3 upstream request failed: {}", e);
                 metrics.record_request(false, bytes_received, 0, duration);
                 metrics.record_upstream_error();
-                return Err(DnsProxyError::Upstream(
-                    crate::error::UpstreamError::RequestFailed {
-                        upstream: upstream_uri,
-                        reason: e.to_string(),
-                    },
-                ));
+                if let Some(timeout_response) = gateway_timeout_response(&e) {
+                    log_access(
+                        &timeout_response.status().as_u16(),
+                        bytes_received,
+                        0,
+                        duration,
+                    );
+                    timeout_response.map(|_| ())
+                } else {
+                    log_access(&"error", bytes_received, 0, duration);
+                    return Err(DnsProxyError::Upstream(
+                        crate::error::UpstreamError::RequestFailed {
+                            upstream: target.upstream().to_string(),
+                            reason: e.to_string(),
+                        },
+                    ));
+                }
             }
         };
 
         debug!("Received response from upstream, sending to DoH3 client");
 
         // Send response back to client
+        Self::send_response(&mut stream, response, request_id).await
+    }
+
+    /// Read a POST body, stopping once it is larger than `max_message_size`
+    async fn read_body(
+        stream: &mut h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+        max_message_size: usize,
+    ) -> DnsProxyResult<Bytes> {
+        let mut body_data = Vec::new();
+        while body_data.len() <= max_message_size {
+            match stream.recv_data().await {
+                Ok(Some(mut chunk)) => {
+                    while chunk.has_remaining() {
+                        body_data.extend_from_slice(chunk.chunk());
+                        chunk.advance(chunk.chunk().len());
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    return Err(DnsProxyError::Protocol(format!(
+                        "Failed to read DoH3 request body: {}",
+                        e
+                    )));
+                }
+            }
+        }
+        debug!("Read DoH3 request body: {} bytes", body_data.len());
+        Ok(Bytes::from(body_data))
+    }
+
+    /// Send a response tagged with the request ID and finish the stream
+    async fn send_response(
+        stream: &mut h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+        mut response: Response<()>,
+        request_id: &str,
+    ) -> DnsProxyResult<()> {
+        set_request_id(response.headers_mut(), request_id);
         stream
-            .send_response(response.map(|_| ()))
+            .send_response(response)
             .await
             .map_err(|e| DnsProxyError::Protocol(format!("Failed to send DoH3 response: {}", e)))?;
 
-        stream.finish().await.map_err(|e| {
-            DnsProxyError::Protocol(format!("Failed to finish DoH3 response: {}", e))
-        })?;
+        stream
+            .finish()
+            .await
+            .map_err(|e| DnsProxyError::Protocol(format!("Failed to finish DoH3 response: {}", e)))
+    }
+}
+
+impl ProtocolServer for DoH3Server {
+    const PROTOCOL: Protocol = Protocol::Doh3;
 
-        Ok(())
+    fn from_resources(resources: ServerResources) -> Self {
+        let pool = create_connection_pool(&resources.config.upstream);
+        let limiter = Arc::new(RateLimiter::from_config(&resources.config.ratelimit));
+        Self {
+            config: resources.config,
+            rewriter: resources.rewriter,
+            pool,
+            health: resources.upstream_health,
+            cache: resources.response_cache,
+            limiter,
+            metrics: resources.metrics,
+            shutdown: resources.shutdown,
+            ready: resources.ready,
+        }
+    }
+
+    async fn run(&self) -> DnsProxyResult<()> {
+        self.start().await
     }
 }
+
+/// Shared state DoH3 requests are served with
+#[derive(Clone)]
+struct RequestContext {
+    rewriter: SniRewriterType,
+    pool: Arc<ConnectionPool>,
+    health: Arc<UpstreamHealth>,
+    cache: Arc<ResponseCache>,
+    config: Arc<AppConfig>,
+    metrics: Arc<Metrics>,
+    tls_resolver: Arc<CertificateResolver>,
+    /// SNI of the connection the request arrived on
+    server_name: Option<String>,
+}
@@ -1,28 +1,84 @@
 use crate::config::AppConfig;
-use crate::error::DnsProxyResult;
-use crate::metrics::{Metrics, Timer};
-use crate::quic::create_quic_server_endpoint;
+use crate::dns::cache::ResponseCache;
+use crate::dns::{self, edns};
+use crate::error::{DnsProxyError, DnsProxyResult};
+use crate::logging::{
+    connection_span, log_access, log_rejected_connection, record_authenticated, record_dnssec_ok,
+    record_sni, record_target, request_span,
+};
+use crate::metrics::{Metrics, RejectReason, Timer};
+use crate::quic::{
+    accept_incoming, create_quic_server_endpoint, handshake_reject_reason, quic_server_name,
+    verify_quic_client,
+};
+use crate::ratelimit::RateLimiter;
+use crate::readers::{Protocol, ProtocolServer};
 use crate::rewrite::SniRewriterType;
-use crate::upstream::forward_quic_stream;
+use crate::server::{ConnectionTracker, ReadySignal, ServerResources, join_addrs};
+use crate::upstream::create_connection_pool;
+use crate::upstream::dry_run::dry_run_response;
+use crate::upstream::health::UpstreamHealth;
+use crate::upstream::ladder::{forward_message, message_route};
+use crate::upstream::pool::ConnectionPool;
+use crate::upstream::{
+    forward_quic_stream, read_quic_stream, refuse_early_data, write_quic_stream,
+};
+use bytes::Bytes;
+use quinn::{RecvStream, SendStream};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tracing::{error, info};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info, warn};
+
+/// DoQ error code (RFC 9250) used to close a connection with a rejected client
+const DOQ_PROTOCOL_ERROR: u32 = 0x2;
 
 pub struct DoQServer {
     config: Arc<AppConfig>,
-    rewriter: SniRewriterType,
+    pool: Arc<ConnectionPool>,
+    health: Arc<UpstreamHealth>,
+    cache: Arc<ResponseCache>,
+    limiter: Arc<RateLimiter>,
     metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+    ready: ReadySignal,
 }
 
 impl DoQServer {
+    /// Create a DoQ server
+    ///
+    /// DoQ queries go to the `upstream.doq` upstreams whatever the client's
+    /// SNI, so the rewriter is not used.
     pub fn new(config: Arc<AppConfig>, rewriter: SniRewriterType, metrics: Arc<Metrics>) -> Self {
-        Self {
-            config,
-            rewriter,
-            metrics,
-        }
+        Self::from_resources(ServerResources::standalone(config, rewriter, metrics))
+    }
+
+    /// Pick upstreams using the health reported by a shared prober
+    pub fn with_upstream_health(mut self, health: Arc<UpstreamHealth>) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Share a response cache with other listeners
+    pub fn with_response_cache(mut self, cache: Arc<ResponseCache>) -> Self {
+        self.cache = cache;
+        self
     }
 
+    /// Stop accepting and drain open connections once `shutdown` is cancelled
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Report on `ready` once listening
+    pub fn with_ready(mut self, ready: ReadySignal) -> Self {
+        self.ready = ready;
+        self
+    }
+}
+
+impl DoQServer {
     pub async fn start(&self) -> DnsProxyResult<()> {
         let server_config = &self.config.servers.doq;
         if !server_config.enabled {
@@ -30,37 +86,105 @@ impl DoQServer {
             return Ok(());
         }
 
-        let bind_addr = format!("{}:{}", server_config.bind_address, server_config.port);
-        let addr: SocketAddr = bind_addr.parse().map_err(|e| {
-            crate::error::DnsProxyError::InvalidInput(format!("Invalid bind address: {}", e))
-        })?;
-
-        let endpoint = create_quic_server_endpoint(self.config.as_ref(), addr).await?;
-        info!("DoQ server listening on UDP {}", addr);
+        if self.health.doq.is_empty() {
+            return Err(crate::error::DnsProxyError::Config(
+                "No DoQ upstream configured".to_string(),
+            ));
+        }
 
-        let upstream = self
-            .config
-            .doq_upstream()
+        let bind_addrs = server_config
+            .socket_addrs()
             .map_err(|e| crate::error::DnsProxyError::Config(e.to_string()))?;
-        let upstream_hostname = self.config.dot_upstream_hostname(); // Reuse the same method
-        let rewriter = Arc::clone(&self.rewriter);
+
+        let (endpoints, tls_resolver) = create_quic_server_endpoint(
+            self.config.as_ref(),
+            &bind_addrs,
+            &self.config.tls.alpn.doq,
+            self.config.quic.allow_0rtt,
+        )
+        .await?;
+        info!("DoQ server listening on UDP {}", join_addrs(&bind_addrs));
+        self.ready.listening();
 
         let metrics = Arc::clone(&self.metrics);
-        while let Some(conn) = endpoint.accept().await {
-            let rewriter = Arc::clone(&rewriter);
-            let upstream_addr = upstream;
-            let upstream_host = upstream_hostname.clone();
+        let _reaper = self.limiter.spawn_reaper();
+        let _pool_reaper = self.pool.spawn_reaper();
+        let mut connections = ConnectionTracker::new().with_limit(
+            self.config.servers.doq.max_concurrent_connections,
+            self.metrics.in_flight_gauge("doq"),
+        );
+        loop {
+            // At the limit, leave new connections queued until one finishes
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = connections.reserve() => {}
+            }
+            let conn = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                conn = accept_incoming(&endpoints) => match conn {
+                    Some(conn) => conn,
+                    // quinn has no transient accept errors (failed handshakes
+                    // end in the connection's task): an endpoint was closed,
+                    // which no retry recovers from
+                    None => {
+                        error!("DoQ endpoint closed, no longer accepting connections");
+                        break;
+                    }
+                },
+            };
+            if !self.limiter.check(conn.remote_address().ip()) {
+                tracing::debug!(
+                    "DoQ client {} is over its rate limit",
+                    conn.remote_address()
+                );
+                self.metrics.record_rate_limited();
+                conn.refuse();
+                continue;
+            }
+            let upstream_addr = match self.health.doq.pick() {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    error!(
+                        "DoQ connection from {} has no upstream: {}",
+                        conn.remote_address(),
+                        e
+                    );
+                    conn.refuse();
+                    continue;
+                }
+            };
+            let cache = Arc::clone(&self.cache);
             let metrics = Arc::clone(&metrics);
-            tokio::spawn(async move {
+            let config = Arc::clone(&self.config);
+            let pool = Arc::clone(&self.pool);
+            let health = Arc::clone(&self.health);
+            let tls_resolver = Arc::clone(&tls_resolver);
+            let peer = conn.remote_address();
+            let connection = async move {
                 match conn.await {
                     Ok(connection) => {
+                        record_sni(quic_server_name(&connection).as_deref());
+                        if let Err(e) = verify_quic_client(&tls_resolver, &connection) {
+                            log_rejected_connection(
+                                &config.logging,
+                                &metrics,
+                                "DoQ",
+                                peer,
+                                RejectReason::ClientCertRejected,
+                                &e,
+                            );
+                            connection.close(DOQ_PROTOCOL_ERROR.into(), b"client certificate");
+                            return;
+                        }
                         info!("New DoQ connection from {}", connection.remote_address());
                         let remote_addr = connection.remote_address();
                         if let Err(e) = Self::handle_connection(
                             connection,
                             upstream_addr,
-                            rewriter,
-                            &upstream_host,
+                            &config,
+                            &pool,
+                            &health,
+                            &cache,
                             &metrics,
                         )
                         .await
@@ -75,62 +199,99 @@ impl DoQServer {
                         }
                     }
                     Err(e) => {
-                        error!("DoQ connection establishment error: {}", e);
+                        metrics.record_tls_handshake_error("doq");
+                        log_rejected_connection(
+                            &config.logging,
+                            &metrics,
+                            "DoQ",
+                            peer,
+                            handshake_reject_reason(&e),
+                            &e,
+                        );
                     }
                 }
-            });
+            };
+            connections.spawn(connection.instrument(connection_span("DoQ", peer)));
         }
 
+        connections.drain("DoQ").await;
         Ok(())
     }
 
     async fn handle_connection(
         connection: quinn::Connection,
         upstream: SocketAddr,
-        _rewriter: SniRewriterType,
-        upstream_hostname: &str,
+        config: &AppConfig,
+        pool: &ConnectionPool,
+        health: &UpstreamHealth,
+        cache: &ResponseCache,
         metrics: &Metrics,
     ) -> DnsProxyResult<()> {
+        let upstream_hostname = &upstream.ip().to_string();
         loop {
             let timer = Timer::start();
             match connection.accept_bi().await {
                 Ok((send, recv)) => {
-                    // Forward stream using zerocopy where possible
-                    let result = forward_quic_stream(send, recv, upstream, upstream_hostname).await;
-                    let duration = timer.elapsed();
-
-                    // Estimate bytes (QUIC streams don't easily expose byte counts)
-                    // We'll use a reasonable estimate based on typical DNS message sizes
-                    let estimated_bytes = 512u64; // Typical DNS query/response size
+                    let (_, span) = request_span();
+                    async {
+                        // Forward stream using zerocopy where possible
+                        let result = if config.upstream.protocol_ladder.is_empty()
+                            && config.servers.doq.upstream_protocol.is_none()
+                        {
+                            record_target(&upstream);
+                            forward_quic_stream(send, recv, upstream, config, pool, cache, metrics)
+                                .await
+                        } else {
+                            Self::forward_stream_elsewhere(
+                                send, recv, config, pool, health, cache, metrics,
+                            )
+                            .await
+                        };
+                        let duration = timer.elapsed();
 
-                    match result {
-                        Ok(_) => {
-                            tracing::debug!(
-                                "DoQ stream forwarded successfully to {} (SNI: {})",
-                                upstream,
-                                upstream_hostname
-                            );
-                            metrics.record_request(
-                                true,
-                                estimated_bytes,
-                                estimated_bytes,
-                                duration,
-                            );
-                        }
-                        Err(e) => {
-                            error!(
-                                "DoQ stream forwarding error to upstream {} (SNI: {}): {}",
-                                upstream, upstream_hostname, e
-                            );
-                            metrics.record_request(false, estimated_bytes, 0, duration);
-                            metrics.record_upstream_error();
+                        match result {
+                            Ok((bytes_received, response)) => {
+                                tracing::debug!(
+                                    "DoQ stream forwarded successfully to {} (SNI: {})",
+                                    upstream,
+                                    upstream_hostname
+                                );
+                                let (bytes_received, bytes_sent) =
+                                    (bytes_received as u64, response.len() as u64);
+                                metrics.record_request(true, bytes_received, bytes_sent, duration);
+                                metrics.record_response(&response);
+                                record_authenticated(&response);
+                                log_access(
+                                    &dns::rcode_name(&response),
+                                    bytes_received,
+                                    bytes_sent,
+                                    duration,
+                                );
+                            }
+                            Err(e @ DnsProxyError::MessageTooLarge { .. }) => {
+                                warn!("Closing DoQ connection: {}", e);
+                                metrics.record_oversized_rejected();
+                                connection.close(DOQ_PROTOCOL_ERROR.into(), b"message too large");
+                            }
+                            Err(e) => {
+                                error!(
+                                    "DoQ stream forwarding error to upstream {} (SNI: {}): {}",
+                                    upstream, upstream_hostname, e
+                                );
+                                metrics.record_request(false, 0, 0, duration);
+                                metrics.record_upstream_error();
+                                log_access(&"error", 0, 0, duration);
+                            }
                         }
                     }
+                    .instrument(span)
+                    .await;
                 }
                 Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
                     info!("DoQ connection closed");
                     break;
                 }
+                Err(quinn::ConnectionError::LocallyClosed) => break,
                 Err(e) => {
                     error!("DoQ stream error: {}", e);
                     metrics.record_upstream_error();
@@ -141,4 +302,81 @@ impl DoQServer {
 
         Ok(())
     }
+
+    /// Forward one DoQ stream over the server's `upstream_protocol` or
+    /// through the upstream protocol ladder
+    async fn forward_stream_elsewhere(
+        mut send: SendStream,
+        mut recv: RecvStream,
+        config: &AppConfig,
+        pool: &ConnectionPool,
+        health: &UpstreamHealth,
+        cache: &ResponseCache,
+        metrics: &Metrics,
+    ) -> DnsProxyResult<(usize, Bytes)> {
+        let buffer = read_quic_stream(&mut recv, config.servers.max_message_size).await?;
+        if let Err(e) = dns::validate_message(&buffer) {
+            tracing::debug!("Answering malformed DoQ query with FORMERR: {}", e);
+            let formerr = dns::formerr_response(&buffer);
+            write_quic_stream(&mut send, &formerr).await?;
+            return Ok((buffer.len(), Bytes::from(formerr)));
+        }
+        record_dnssec_ok(&buffer);
+
+        if let Some(refused) = refuse_early_data(&recv, &buffer) {
+            write_quic_stream(&mut send, &refused).await?;
+            return Ok((buffer.len(), Bytes::from(refused)));
+        }
+
+        if let Some(refused) = dns::refuse_if_denied(&buffer, &config.filter) {
+            metrics.record_blocked_request();
+            write_quic_stream(&mut send, &refused).await?;
+            return Ok((buffer.len(), Bytes::from(refused)));
+        }
+
+        if config.proxy.dry_run {
+            let route = message_route(&config.servers.doq);
+            let response = dry_run_response(&buffer, route, metrics);
+            write_quic_stream(&mut send, &response).await?;
+            return Ok((buffer.len(), response));
+        }
+
+        let response = cache
+            .get_or_forward(&buffer, metrics, async {
+                let query = edns::rewrite_query(&buffer, &config.edns);
+                let (response, protocol) =
+                    forward_message(config, pool, health, &config.servers.doq, &query, metrics)
+                        .await?;
+                record_target(&protocol);
+                tracing::debug!("DoQ query forwarded via {} upstream", protocol);
+                Ok(response)
+            })
+            .await?;
+
+        write_quic_stream(&mut send, &response).await?;
+        Ok((buffer.len(), response))
+    }
+}
+
+impl ProtocolServer for DoQServer {
+    const PROTOCOL: Protocol = Protocol::Doq;
+
+    fn from_resources(resources: ServerResources) -> Self {
+        let pool = create_connection_pool(&resources.config.upstream);
+        let limiter = Arc::new(RateLimiter::from_config(&resources.config.ratelimit));
+        Self {
+            config: resources.config,
+            pool,
+            health: resources.upstream_health,
+            cache: resources.response_cache,
+            limiter,
+            metrics: resources.metrics,
+            shutdown: resources.shutdown,
+            ready: resources.ready,
+        }
+    }
+
+    async fn run(&self) -> DnsProxyResult<()> {
+        self.start().await
+    }
 }
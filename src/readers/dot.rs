@@ -1,33 +1,74 @@
 use crate::config::AppConfig;
+use crate::dns::framing::{read_framed_limited, write_framed};
+use crate::dns::{self, edns};
 use crate::error::{DnsProxyError, DnsProxyResult};
-use crate::metrics::{Metrics, Timer};
+use crate::logging::{
+    connection_span, log_access, log_rejected_connection, record_authenticated, record_dnssec_ok,
+    record_sni, record_target, request_span,
+};
+use crate::metrics::{Metrics, RejectReason, Timer};
+use crate::proxy_protocol;
+use crate::ratelimit::RateLimiter;
+use crate::readers::{Protocol, ProtocolServer};
 use crate::rewrite::SniRewriterType;
+use crate::server::{
+    AcceptBackoff, ConnectionTracker, ReadySignal, ServerResources, TcpListeners, join_addrs,
+};
 use crate::tls_utils;
-use crate::utils::backoff::BackoffCounter;
-use rustls::pki_types::ServerName;
+use crate::upstream::circuit_breaker::record_circuit_open;
+use crate::upstream::create_connection_pool;
+use crate::upstream::dry_run::dry_run_response;
+use crate::upstream::health::UpstreamHealth;
+use crate::upstream::ladder::{forward_message, message_route};
+use crate::upstream::pool::ConnectionPool;
+use crate::upstream::retry::{is_transient_error, with_retries};
+use crate::upstream::timeout::with_timeout;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::{TlsAcceptor, TlsConnector};
-use tracing::{error, info};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info, warn};
+
+/// Time allowed for a client to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct DoTServer {
     config: Arc<AppConfig>,
     rewriter: SniRewriterType,
-    backoff: Arc<BackoffCounter>,
+    pool: Arc<ConnectionPool>,
+    health: Arc<UpstreamHealth>,
+    limiter: Arc<RateLimiter>,
     metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+    ready: ReadySignal,
 }
 
 impl DoTServer {
     pub fn new(config: Arc<AppConfig>, rewriter: SniRewriterType, metrics: Arc<Metrics>) -> Self {
-        Self {
-            config,
-            rewriter,
-            backoff: Arc::new(BackoffCounter::new()),
-            metrics,
-        }
+        Self::from_resources(ServerResources::standalone(config, rewriter, metrics))
+    }
+
+    /// Pick upstreams using the health reported by a shared prober
+    pub fn with_upstream_health(mut self, health: Arc<UpstreamHealth>) -> Self {
+        self.health = health;
+        self
     }
 
+    /// Stop accepting and drain open connections once `shutdown` is cancelled
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Report on `ready` once listening
+    pub fn with_ready(mut self, ready: ReadySignal) -> Self {
+        self.ready = ready;
+        self
+    }
+}
+
+impl DoTServer {
     pub async fn start(&self) -> DnsProxyResult<()> {
         let server_config = &self.config.servers.dot;
         if !server_config.enabled {
@@ -35,170 +76,335 @@ impl DoTServer {
             return Ok(());
         }
 
-        let server_tls_config = tls_utils::create_server_config(self.config.as_ref())
-            .await
-            .map_err(|e| DnsProxyError::Tls(e.to_string()))?;
+        if self.health.dot.is_empty() {
+            return Err(DnsProxyError::Config(
+                "No DoT upstream configured".to_string(),
+            ));
+        }
+
+        let (server_tls_config, tls_resolver) =
+            tls_utils::create_server_config(self.config.as_ref(), &self.config.tls.alpn.dot)
+                .await
+                .map_err(|e| DnsProxyError::Tls(e.to_string()))?;
         let acceptor = TlsAcceptor::from(Arc::new(server_tls_config));
 
-        let bind_addr = format!("{}:{}", server_config.bind_address, server_config.port);
-        let listener = TcpListener::bind(&bind_addr).await?;
+        let bind_addrs = server_config
+            .socket_addrs()
+            .map_err(|e| DnsProxyError::Config(e.to_string()))?;
+        let listener = TcpListeners::bind(&bind_addrs)?;
+        let bind_addr = join_addrs(&listener.local_addrs());
 
         info!("DoT server listening on TCP {}", bind_addr);
+        self.ready.listening();
 
-        let upstream = self
-            .config
-            .dot_upstream()
-            .map_err(|e| DnsProxyError::Config(e.to_string()))?;
-        let upstream_hostname = self.config.dot_upstream_hostname();
         let rewriter = Arc::clone(&self.rewriter);
+        let _reaper = self.limiter.spawn_reaper();
+        let mut connections = ConnectionTracker::new().with_limit(
+            self.config.servers.dot.max_concurrent_connections,
+            self.metrics.in_flight_gauge("dot"),
+        );
 
+        let mut backoff = AcceptBackoff::from_config(&self.config.servers, self.shutdown.clone());
         loop {
-            match listener.accept().await {
-                Ok((stream, addr)) => {
-                    info!("New DoT connection from {}", addr);
+            // At the limit, leave new connections queued until one finishes
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = connections.reserve() => {}
+            }
+            let accepted = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                accepted = listener.accept() => accepted,
+            };
+            match accepted {
+                Ok((mut stream, peer)) => {
+                    backoff.succeeded();
                     let acceptor = acceptor.clone();
                     let rewriter = Arc::clone(&rewriter);
-                    let upstream_addr = upstream;
-                    let upstream_host = upstream_hostname.clone();
                     let metrics = Arc::clone(&self.metrics);
-                    tokio::spawn(async move {
-                        match acceptor.accept(stream).await {
-                            Ok(tls_stream) => {
-                                if let Err(e) = Self::handle_connection(
-                                    tls_stream,
-                                    rewriter,
-                                    upstream_addr,
-                                    &upstream_host,
-                                    &metrics,
-                                )
-                                .await
-                                {
-                                    error!("DoT connection handling error from {}: {}", addr, e);
-                                    metrics.record_upstream_error();
-                                } else {
-                                    tracing::debug!(
-                                        "DoT connection from {} completed successfully",
-                                        addr
+                    let config = Arc::clone(&self.config);
+                    let pool = Arc::clone(&self.pool);
+                    let health = Arc::clone(&self.health);
+                    let limiter = Arc::clone(&self.limiter);
+                    let tls_resolver = Arc::clone(&tls_resolver);
+                    let connection = async move {
+                        let Some(addr) = proxy_protocol::client_addr(
+                            &mut stream,
+                            peer,
+                            config.servers.dot.proxy_protocol,
+                            "DoT",
+                            &config.logging,
+                            &metrics,
+                        )
+                        .await
+                        else {
+                            return;
+                        };
+                        async move {
+                            if !limiter.check(addr.ip()) {
+                                // Dropping the stream closes the connection
+                                tracing::debug!("DoT client {} is over its rate limit", addr);
+                                metrics.record_rate_limited();
+                                return;
+                            }
+                            let upstream_addr = match health.dot.pick() {
+                                Ok(upstream) => upstream,
+                                Err(e) => {
+                                    error!("DoT connection from {} has no upstream: {}", addr, e);
+                                    return;
+                                }
+                            };
+                            info!("New DoT connection from {}", addr);
+                            let handshake = tokio::time::timeout(
+                                TLS_HANDSHAKE_TIMEOUT,
+                                acceptor.accept(stream),
+                            );
+                            match handshake.await {
+                                Ok(Ok(tls_stream)) => {
+                                    let (_, session) = tls_stream.get_ref();
+                                    record_sni(session.server_name());
+                                    if let Err(e) = tls_resolver.verify_client(
+                                        session.server_name(),
+                                        session.peer_certificates(),
+                                    ) {
+                                        // Dropping the stream closes the connection
+                                        log_rejected_connection(
+                                            &config.logging,
+                                            &metrics,
+                                            "DoT",
+                                            addr,
+                                            RejectReason::ClientCertRejected,
+                                            &e,
+                                        );
+                                        return;
+                                    }
+                                    if let Err(e) = Self::handle_connection(
+                                        tls_stream,
+                                        rewriter,
+                                        upstream_addr,
+                                        &config,
+                                        &pool,
+                                        &health,
+                                        &metrics,
+                                    )
+                                    .await
+                                    {
+                                        if let DnsProxyError::MessageTooLarge { .. } = e {
+                                            warn!("Closing DoT connection from {}: {}", addr, e);
+                                            metrics.record_oversized_rejected();
+                                        } else {
+                                            error!(
+                                                "DoT connection handling error from {}: {}",
+                                                addr, e
+                                            );
+                                            metrics.record_upstream_error();
+                                        }
+                                    } else {
+                                        tracing::debug!(
+                                            "DoT connection from {} completed successfully",
+                                            addr
+                                        );
+                                    }
+                                }
+                                Ok(Err(e)) => {
+                                    metrics.record_tls_handshake_error("dot");
+                                    log_rejected_connection(
+                                        &config.logging,
+                                        &metrics,
+                                        "DoT",
+                                        addr,
+                                        RejectReason::HandshakeFailed,
+                                        &e,
+                                    );
+                                }
+                                Err(e) => {
+                                    metrics.record_tls_handshake_error("dot");
+                                    log_rejected_connection(
+                                        &config.logging,
+                                        &metrics,
+                                        "DoT",
+                                        addr,
+                                        RejectReason::HandshakeTimeout,
+                                        &e,
                                     );
                                 }
-                            }
-                            Err(e) => {
-                                error!("DoT TLS handshake error from {}: {}", addr, e);
                             }
                         }
-                    });
+                        .instrument(connection_span("DoT", addr))
+                        .await
+                    };
+                    connections.spawn(connection);
                 }
                 Err(e) => {
-                    error!("DoT accept error on {}: {}", bind_addr, e);
-                    // Use exponential backoff to prevent tight error loop
-                    let delay = self.backoff.next_delay(100, 5000);
-                    tokio::time::sleep(delay).await;
+                    backoff.failed("DoT", &e).await;
                 }
             }
         }
+
+        drop(listener);
+        connections.drain("DoT").await;
+        Ok(())
     }
 
-    async fn handle_connection(
-        stream: tokio_rustls::server::TlsStream<TcpStream>,
+    /// Serve one DoT client connection
+    ///
+    /// Messages are framed with the RFC 7858 2-byte length prefix, so a client
+    /// may send several (pipelined) queries on the same connection. Queries go
+    /// out on the pooled DoT connections of `pool` (see `[upstream.dot_pool]`),
+    /// shared with every other client; their TLS server name is the upstream's
+    /// IP address.
+    pub async fn handle_connection<S>(
+        stream: S,
         _rewriter: SniRewriterType,
         upstream: std::net::SocketAddr,
-        upstream_hostname: &str,
+        config: &AppConfig,
+        pool: &ConnectionPool,
+        health: &UpstreamHealth,
         metrics: &Metrics,
-    ) -> DnsProxyResult<()> {
+    ) -> DnsProxyResult<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
         use tracing::debug;
 
-        let timer = Timer::start();
         let (mut reader, mut writer) = tokio::io::split(stream);
+        let upstream_hostname = &upstream.ip().to_string();
 
-        // Read DNS message from client (zerocopy: use Bytes directly)
-        let mut buffer = Vec::with_capacity(4096);
-        reader.read_to_end(&mut buffer).await?;
+        while let Some(message) =
+            read_framed_limited(&mut reader, config.servers.max_message_size).await?
+        {
+            let (_, span) = request_span();
+            async {
+                let timer = Timer::start();
+                let bytes_received = message.len() as u64;
 
-        if buffer.is_empty() {
-            debug!("Received empty DNS message, closing connection");
-            return Ok(());
-        }
+                if let Err(e) = dns::validate_message(&message) {
+                    debug!("Answering malformed DoT query with FORMERR: {}", e);
+                    let formerr = dns::formerr_response(&message);
+                    write_framed(&mut writer, &formerr).await?;
+                    log_access(
+                        &dns::rcode_name(&formerr),
+                        bytes_received,
+                        formerr.len() as u64,
+                        timer.elapsed(),
+                    );
+                    return Ok(());
+                }
 
-        let bytes_received = buffer.len() as u64;
+                if let Some(refused) = dns::refuse_if_denied(&message, &config.filter) {
+                    debug!("Refusing DoT query denied by the domain filter");
+                    metrics.record_blocked_request();
+                    write_framed(&mut writer, &refused).await?;
+                    log_access(
+                        &dns::rcode_name(&refused),
+                        bytes_received,
+                        refused.len() as u64,
+                        timer.elapsed(),
+                    );
+                    return Ok(());
+                }
 
-        debug!(
-            "Received DNS message: {} bytes, forwarding to upstream {} (SNI: {})",
-            bytes_received, upstream, upstream_hostname
-        );
+                debug!(
+                    "Received DNS message: {} bytes, forwarding to upstream {} (SNI: {})",
+                    bytes_received, upstream, upstream_hostname
+                );
 
-        // Connect to upstream
-        let upstream_stream = TcpStream::connect(upstream).await.map_err(|e| {
-            DnsProxyError::Upstream(crate::error::UpstreamError::ConnectionFailed {
-                upstream: upstream.to_string(),
-                reason: format!("Failed to connect: {}", e),
-            })
-        })?;
-
-        let client_config =
-            create_client_config().map_err(|e| DnsProxyError::Tls(e.to_string()))?;
-        let connector = TlsConnector::from(Arc::new(client_config));
-        let sni_name = ServerName::try_from(upstream_hostname.to_string()).map_err(|e| {
-            DnsProxyError::InvalidInput(format!(
-                "Failed to create ServerName for upstream connection: {}",
-                e
-            ))
-        })?;
-
-        let upstream_tls = connector
-            .connect(sni_name, upstream_stream)
-            .await
-            .map_err(|e| {
-                DnsProxyError::Upstream(crate::error::UpstreamError::ConnectionFailed {
-                    upstream: upstream.to_string(),
-                    reason: format!("Failed to establish TLS connection: {}", e),
-                })
-            })?;
-        let (mut up_reader, mut up_writer) = tokio::io::split(upstream_tls);
-
-        // Forward message (zerocopy: use slice reference)
-        up_writer.write_all(&buffer).await?;
-        up_writer.flush().await?;
-
-        // Read response (zerocopy: reuse buffer)
-        buffer.clear();
-        buffer.reserve(4096);
-        up_reader.read_to_end(&mut buffer).await?;
-
-        debug!(
-            "Received DNS response: {} bytes, sending to client",
-            buffer.len()
-        );
+                record_dnssec_ok(&message);
+
+                // Forward message (zerocopy: only copies when the EDNS policy rewrites it)
+                let query = edns::rewrite_query(&message, &config.edns);
+                let server = &config.servers.dot;
+                let direct = config.upstream.protocol_ladder.is_empty()
+                    && server.upstream_protocol.is_none();
+                let response = if config.proxy.dry_run {
+                    let route = if direct {
+                        upstream.to_string()
+                    } else {
+                        message_route(server)
+                    };
+                    dry_run_response(&query, route, metrics).to_vec()
+                } else if direct {
+                    let upstream_str = upstream.to_string();
+                    record_target(&upstream_str);
+                    let response = with_retries(
+                        config.upstream.max_retries,
+                        &upstream_str,
+                        metrics,
+                        is_transient_error,
+                        || {
+                            with_timeout(
+                                config.upstream.timeout(),
+                                &upstream_str,
+                                pool.dot().forward(
+                                    upstream,
+                                    upstream_hostname,
+                                    &query,
+                                    &config.upstream,
+                                ),
+                            )
+                        },
+                    )
+                    .await
+                    .inspect_err(|e| record_circuit_open(e, metrics))?;
+                    metrics.record_upstream_bytes(query.len() as u64, response.len() as u64);
+                    response.to_vec()
+                } else {
+                    let (response, protocol) =
+                        forward_message(config, pool, health, server, &query, metrics).await?;
+                    record_target(&protocol);
+                    debug!("DoT query forwarded via {} upstream", protocol);
+                    response.to_vec()
+                };
+
+                debug!(
+                    "Received DNS response: {} bytes, sending to client",
+                    response.len()
+                );
 
-        // Send response back (zerocopy: use slice reference)
-        let bytes_sent = buffer.len() as u64;
-        writer.write_all(&buffer).await?;
-        writer.flush().await?;
+                // Send response back with its length prefix
+                let bytes_sent = response.len() as u64;
+                write_framed(&mut writer, &response).await?;
 
-        // Record metrics
-        let duration = timer.elapsed();
-        metrics.record_request(true, bytes_received, bytes_sent, duration);
+                // Record metrics
+                let duration = timer.elapsed();
+                metrics.record_request(true, bytes_received, bytes_sent, duration);
+                metrics.record_response(&response);
+                record_authenticated(&response);
+                log_access(
+                    &dns::rcode_name(&response),
+                    bytes_received,
+                    bytes_sent,
+                    duration,
+                );
+                Ok::<_, DnsProxyError>(())
+            }
+            .instrument(span)
+            .await?;
+        }
 
+        debug!("DoT client closed connection");
         Ok(())
     }
 }
 
-/// Create TLS client configuration for upstream connections
-/// Uses system root certificates for proper TLS verification
-fn create_client_config() -> DnsProxyResult<rustls::ClientConfig> {
-    let mut root_store = rustls::RootCertStore::empty();
-
-    // Load system root certificates
-    let cert_result = rustls_native_certs::load_native_certs();
-    for cert in cert_result.certs {
-        root_store.add(cert).map_err(|e| {
-            DnsProxyError::Certificate(crate::error::CertificateError::LoadFailed {
-                path: "system".to_string(),
-                reason: format!("Failed to add root certificate: {}", e),
-            })
-        })?;
+impl ProtocolServer for DoTServer {
+    const PROTOCOL: Protocol = Protocol::Dot;
+
+    fn from_resources(resources: ServerResources) -> Self {
+        let pool = create_connection_pool(&resources.config.upstream);
+        let limiter = Arc::new(RateLimiter::from_config(&resources.config.ratelimit));
+        Self {
+            config: resources.config,
+            rewriter: resources.rewriter,
+            pool,
+            health: resources.upstream_health,
+            limiter,
+            metrics: resources.metrics,
+            shutdown: resources.shutdown,
+            ready: resources.ready,
+        }
     }
 
-    Ok(rustls::ClientConfig::builder()
-        .with_root_certificates(root_store)
-        .with_no_client_auth())
+    async fn run(&self) -> DnsProxyResult<()> {
+        self.start().await
+    }
 }
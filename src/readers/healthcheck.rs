@@ -1,6 +1,13 @@
-use crate::config::AppConfig;
-use crate::error::DnsProxyResult;
+use crate::config::{AppConfig, HealthcheckConfig};
+use crate::error::{DnsProxyError, DnsProxyResult};
 use crate::metrics::Metrics;
+use crate::proxy::too_many_requests_response;
+use crate::ratelimit::RateLimiter;
+use crate::readers;
+use crate::server::{
+    AcceptBackoff, ConnectionTracker, ReadySignal, ServerResources, TcpListeners, join_addrs,
+};
+use crate::upstream::health::UpstreamHealth;
 use http_body_util::Full;
 use hyper::body::Bytes;
 use hyper::server::conn::http1;
@@ -8,17 +15,64 @@ use hyper::service::service_fn;
 use hyper::{Method, Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use std::sync::Arc;
-use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
 
 pub struct HealthcheckServer {
     config: Arc<AppConfig>,
+    limiter: Arc<RateLimiter>,
     metrics: Arc<Metrics>,
+    upstream_health: Arc<UpstreamHealth>,
+    shutdown: CancellationToken,
+    ready: ReadySignal,
 }
 
 impl HealthcheckServer {
     pub fn new(config: Arc<AppConfig>, metrics: Arc<Metrics>) -> Self {
-        Self { config, metrics }
+        let limiter = Arc::new(RateLimiter::from_config(&config.ratelimit));
+        let upstream_health = Arc::new(UpstreamHealth::from_config(&config));
+        Self {
+            config,
+            limiter,
+            metrics,
+            upstream_health,
+            shutdown: CancellationToken::new(),
+            ready: ReadySignal::default(),
+        }
+    }
+
+    /// Report the upstream health tracked by a shared prober
+    pub fn with_upstream_health(mut self, upstream_health: Arc<UpstreamHealth>) -> Self {
+        self.upstream_health = upstream_health;
+        self
+    }
+
+    /// Stop accepting and drain open connections once `shutdown` is cancelled
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Report on `ready` once listening
+    pub fn with_ready(mut self, ready: ReadySignal) -> Self {
+        self.ready = ready;
+        self
+    }
+}
+
+impl HealthcheckServer {
+    /// Create a server reporting the metrics and upstream health shared by
+    /// the other servers
+    pub fn from_resources(resources: ServerResources) -> Self {
+        let limiter = Arc::new(RateLimiter::from_config(&resources.config.ratelimit));
+        Self {
+            config: resources.config,
+            limiter,
+            metrics: resources.metrics,
+            upstream_health: resources.upstream_health,
+            shutdown: resources.shutdown,
+            ready: resources.ready,
+        }
     }
 
     pub async fn start(&self) -> DnsProxyResult<()> {
@@ -28,54 +82,102 @@ impl HealthcheckServer {
             return Ok(());
         }
 
-        let bind_addr = format!("{}:{}", server_config.bind_address, server_config.port);
-        let listener = TcpListener::bind(&bind_addr).await?;
+        let bind_addrs = server_config
+            .socket_addrs()
+            .map_err(|e| DnsProxyError::Config(e.to_string()))?;
+        let listener = TcpListeners::bind(&bind_addrs)?;
+        let bind_addr = join_addrs(&listener.local_addrs());
 
         info!(
-            "Healthcheck server listening on {}:{} at path {}",
-            server_config.bind_address, server_config.port, server_config.path
+            "Healthcheck server listening on {} at paths {} and {}",
+            bind_addr, server_config.path, server_config.ready_path
         );
+        self.ready.listening();
 
-        let healthcheck_path = server_config.path.clone();
+        let config = Arc::clone(&self.config);
         let metrics = Arc::clone(&self.metrics);
+        let version = Arc::new(build_info(&self.config).to_string());
+        let _reaper = self.limiter.spawn_reaper();
+        let mut connections =
+            ConnectionTracker::new().with_limit(0, self.metrics.in_flight_gauge("healthcheck"));
 
+        let mut backoff = AcceptBackoff::from_config(&self.config.servers, self.shutdown.clone());
         loop {
-            match listener.accept().await {
+            let accepted = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                accepted = listener.accept() => accepted,
+            };
+            match accepted {
                 Ok((stream, addr)) => {
-                    let path = healthcheck_path.clone();
+                    backoff.succeeded();
+                    let config = Arc::clone(&config);
+                    let version = Arc::clone(&version);
                     let client_addr = addr;
                     let metrics = Arc::clone(&metrics);
-                    tokio::spawn(async move {
+                    let upstream_health = Arc::clone(&self.upstream_health);
+                    let limiter = Arc::clone(&self.limiter);
+                    let shutdown = self.shutdown.clone();
+                    connections.spawn(async move {
                         let io = TokioIo::new(stream);
                         let service = service_fn(move |req| {
-                            let path = path.clone();
+                            let config = Arc::clone(&config);
+                            let version = Arc::clone(&version);
                             let addr = client_addr;
                             let metrics = Arc::clone(&metrics);
+                            let upstream_health = Arc::clone(&upstream_health);
+                            let limiter = Arc::clone(&limiter);
                             async move {
-                                handle_healthcheck(req, &path, &metrics).await.map_err(|e| {
+                                if !limiter.check(addr.ip()) {
+                                    metrics.record_rate_limited();
+                                    return Ok(too_many_requests_response());
+                                }
+                                handle_healthcheck(
+                                    req,
+                                    &config.servers.healthcheck,
+                                    &metrics,
+                                    &upstream_health,
+                                    &version,
+                                )
+                                .await
+                                .map_err(|e| {
                                     error!("Healthcheck handler error from {}: {}", addr, e);
                                     std::io::Error::other(e.to_string())
                                 })
                             }
                         });
 
-                        if let Err(e) = http1::Builder::new().serve_connection(io, service).await {
+                        let conn = http1::Builder::new().serve_connection(io, service);
+                        tokio::pin!(conn);
+                        let result = tokio::select! {
+                            result = conn.as_mut() => result,
+                            _ = shutdown.cancelled() => {
+                                conn.as_mut().graceful_shutdown();
+                                conn.await
+                            }
+                        };
+                        if let Err(e) = result {
                             error!("Healthcheck connection error from {}: {}", client_addr, e);
                         }
                     });
                 }
                 Err(e) => {
-                    error!("Healthcheck accept error: {}", e);
+                    backoff.failed("Healthcheck", &e).await;
                 }
             }
         }
+
+        drop(listener);
+        connections.drain("Healthcheck").await;
+        Ok(())
     }
 }
 
 async fn handle_healthcheck(
     req: Request<hyper::body::Incoming>,
-    healthcheck_path: &str,
+    healthcheck: &HealthcheckConfig,
     metrics: &Metrics,
+    upstream_health: &UpstreamHealth,
+    version: &str,
 ) -> Result<Response<Full<Bytes>>, std::io::Error> {
     // Only handle GET requests
     if req.method() != Method::GET {
@@ -109,8 +211,27 @@ async fn handle_healthcheck(
             "failed_requests": snapshot.failed_requests,
             "bytes_received": snapshot.bytes_received,
             "bytes_sent": snapshot.bytes_sent,
-            "sni_rewrites": snapshot.sni_rewrites,
+            "upstream_bytes_sent": snapshot.upstream_bytes_sent,
+            "upstream_bytes_received": snapshot.upstream_bytes_received,
+            "sni_rewrites": snapshot.rewrite_hits
+                + snapshot.rewrite_misses
+                + snapshot.rewrite_passthroughs,
+            "rewrite_hits": snapshot.rewrite_hits,
+            "rewrite_misses": snapshot.rewrite_misses,
+            "rewrite_passthroughs": snapshot.rewrite_passthroughs,
             "upstream_errors": snapshot.upstream_errors,
+            "upstream_retries": snapshot.upstream_retries,
+            "circuit_open": snapshot.circuit_open,
+            "blocked_requests": snapshot.blocked_requests,
+            "rate_limited": snapshot.rate_limited,
+            "oversized_rejected": snapshot.oversized_rejected,
+            "cookie_rejected": snapshot.cookie_rejected,
+            "dry_run_requests": snapshot.dry_run_requests,
+            "cache_hits": snapshot.cache_hits,
+            "cache_misses": snapshot.cache_misses,
+            "tls_handshake_errors": snapshot.tls_handshake_errors,
+            "responses_authenticated": snapshot.responses_authenticated,
+            "active_connections": snapshot.active_connections,
             "average_processing_time_ms": snapshot.average_processing_time_ms,
             "success_rate": snapshot.success_rate,
             "throughput_requests_per_sec": snapshot.throughput_requests_per_sec
@@ -123,14 +244,69 @@ async fn handle_healthcheck(
             .map_err(std::io::Error::other);
     }
 
-    if path != healthcheck_path {
+    // Build information, to confirm which build is deployed
+    if path == "/version" {
+        return Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(version.to_owned())))
+            .map_err(std::io::Error::other);
+    }
+
+    // Busiest rewrite targets, `?n=` of them (default: 20)
+    if path == "/metrics/top" {
+        let n = match top_targets_param(req.uri().query()) {
+            Some(n) => n,
+            None => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Full::new(Bytes::from("Invalid n parameter")))
+                    .map_err(std::io::Error::other);
+            }
+        };
+        let targets: Vec<serde_json::Value> = metrics
+            .top_rewrite_targets(n)
+            .into_iter()
+            .map(|(target, requests)| serde_json::json!({ "target": target, "requests": requests }))
+            .collect();
+        let response = serde_json::json!({ "targets": targets });
+
+        return Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(response.to_string())))
+            .map_err(std::io::Error::other);
+    }
+
+    // Readiness: at least one upstream must be passing its health probe
+    if path == healthcheck.ready_path {
+        let ready = upstream_health.any_healthy();
+        let response = serde_json::json!({
+            "status": if ready { "ready" } else { "not ready" },
+            "service": "dns-proxy",
+            "upstreams": upstream_health.to_json()
+        });
+        let status = if ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+
+        return Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(response.to_string())))
+            .map_err(std::io::Error::other);
+    }
+
+    if path != healthcheck.path {
         return Response::builder()
             .status(StatusCode::NOT_FOUND)
             .body(Full::new(Bytes::from("Not found")))
             .map_err(std::io::Error::other);
     }
 
-    // Return healthy status
+    // Liveness: the process is up, whatever the state of the upstreams
     let response = serde_json::json!({
         "status": "healthy",
         "service": "dns-proxy"
@@ -142,3 +318,47 @@ async fn handle_healthcheck(
         .body(Full::new(Bytes::from(response.to_string())))
         .map_err(std::io::Error::other)
 }
+
+/// `/version` response: the crate version, the git commit and time it was
+/// built from (embedded by `build.rs`) and the servers `config` enables
+pub fn build_info(config: &AppConfig) -> serde_json::Value {
+    let servers: Vec<serde_json::Value> = readers::all()
+        .iter()
+        .map(|reader| reader.protocol())
+        .filter_map(|protocol| {
+            let server = protocol.listen_config(config);
+            if !server.enabled {
+                return None;
+            }
+            Some(match &server.uds_path {
+                Some(path) => serde_json::json!({ "name": protocol.name(), "uds_path": path }),
+                None => serde_json::json!({
+                    "name": protocol.name(),
+                    "bind_address": server.bind_address,
+                    "port": server.port
+                }),
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_hash": env!("DNS_PROXY_GIT_HASH"),
+        "build_timestamp": env!("DNS_PROXY_BUILD_TIMESTAMP"),
+        "servers": servers
+    })
+}
+
+/// Number of targets `/metrics/top` lists when the query has no `n`
+const DEFAULT_TOP_TARGETS: usize = 20;
+
+/// `n` of a `/metrics/top` query string, or `None` when it is not a number
+fn top_targets_param(query: Option<&str>) -> Option<usize> {
+    let n = query
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("n="));
+    match n {
+        Some(n) => n.parse().ok(),
+        None => Some(DEFAULT_TOP_TARGETS),
+    }
+}
@@ -3,9 +3,124 @@ pub mod doh3;
 pub mod doq;
 pub mod dot;
 pub mod healthcheck;
+pub mod tcp;
+pub mod udp;
 
 pub use doh::DoHServer;
 pub use doh3::DoH3Server;
 pub use doq::DoQServer;
 pub use dot::DoTServer;
 pub use healthcheck::HealthcheckServer;
+pub use tcp::TcpDnsServer;
+pub use udp::UdpServer;
+
+use crate::config::{AppConfig, ServerPortConfig};
+use crate::error::DnsProxyResult;
+use crate::server::ServerResources;
+use std::future::Future;
+use std::marker::PhantomData;
+use tokio_util::sync::CancellationToken;
+
+/// Protocol a DNS server listens for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    Dot,
+    Doh,
+    Doq,
+    Doh3,
+    Udp,
+    TcpDns,
+}
+
+impl Protocol {
+    /// Name of the server in log messages and startup errors
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Dot => "DoT",
+            Self::Doh => "DoH",
+            Self::Doq => "DoQ",
+            Self::Doh3 => "DoH3",
+            Self::Udp => "UDP DNS",
+            Self::TcpDns => "TCP DNS",
+        }
+    }
+
+    /// Whether the server listens on UDP sockets (QUIC or plain UDP) rather
+    /// than TCP
+    pub fn is_udp(self) -> bool {
+        matches!(self, Self::Doq | Self::Doh3 | Self::Udp)
+    }
+
+    /// Listener section of the server in `config`
+    pub fn listen_config(self, config: &AppConfig) -> &ServerPortConfig {
+        let servers = &config.servers;
+        match self {
+            Self::Dot => &servers.dot,
+            Self::Doh => &servers.doh,
+            Self::Doq => &servers.doq,
+            Self::Doh3 => &servers.doh3,
+            Self::Udp => &servers.udp,
+            Self::TcpDns => &servers.tcp_dns,
+        }
+    }
+}
+
+/// A DNS server, started from the resources shared by all servers
+#[async_trait::async_trait]
+pub trait Reader: Send + Sync {
+    fn protocol(&self) -> Protocol;
+
+    /// Serve until `shutdown` is cancelled, or return at once when the
+    /// server is disabled
+    async fn start(
+        &self,
+        resources: ServerResources,
+        shutdown: CancellationToken,
+    ) -> DnsProxyResult<()>;
+}
+
+/// Server of a [`Reader`], built from the shared [`ServerResources`] each
+/// time it is started
+pub trait ProtocolServer: Sized + Send + Sync + 'static {
+    const PROTOCOL: Protocol;
+
+    fn from_resources(resources: ServerResources) -> Self;
+
+    /// Serve until shutdown, or return at once when the server is disabled
+    fn run(&self) -> impl Future<Output = DnsProxyResult<()>> + Send;
+}
+
+/// [`Reader`] starting servers of type `S`
+struct ServerReader<S>(PhantomData<fn() -> S>);
+
+#[async_trait::async_trait]
+impl<S: ProtocolServer> Reader for ServerReader<S> {
+    fn protocol(&self) -> Protocol {
+        S::PROTOCOL
+    }
+
+    async fn start(
+        &self,
+        mut resources: ServerResources,
+        shutdown: CancellationToken,
+    ) -> DnsProxyResult<()> {
+        resources.shutdown = shutdown;
+        S::from_resources(resources).run().await
+    }
+}
+
+fn reader<S: ProtocolServer>() -> Box<dyn Reader> {
+    Box::new(ServerReader::<S>(PhantomData))
+}
+
+/// Readers of every DNS server, in the order they are started
+pub fn all() -> Vec<Box<dyn Reader>> {
+    vec![
+        reader::<DoTServer>(),
+        reader::<DoHServer>(),
+        reader::<DoQServer>(),
+        reader::<DoH3Server>(),
+        reader::<UdpServer>(),
+        reader::<TcpDnsServer>(),
+    ]
+}
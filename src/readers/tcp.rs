@@ -0,0 +1,252 @@
+use crate::config::AppConfig;
+use crate::dns;
+use crate::dns::cache::ResponseCache;
+use crate::dns::framing::{read_framed_limited, write_framed};
+use crate::error::{DnsProxyError, DnsProxyResult};
+use crate::metrics::{Metrics, Timer};
+use crate::proxy_protocol;
+use crate::ratelimit::RateLimiter;
+use crate::readers::{Protocol, ProtocolServer};
+use crate::rewrite::SniRewriterType;
+use crate::server::{
+    AcceptBackoff, ConnectionTracker, ReadySignal, ServerResources, TcpListeners, join_addrs,
+};
+use crate::upstream::create_connection_pool;
+use crate::upstream::default_upstream::{DefaultUpstream, DnsUpstream, forward_by_qname};
+use crate::upstream::pool::ConnectionPool;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+/// Plain DNS over TCP (port 53)
+///
+/// Like UDP, plain TCP queries carry no SNI, so they are routed by their
+/// queried name (see [`forward_by_qname`]).
+pub struct TcpDnsServer {
+    config: Arc<AppConfig>,
+    rewriter: SniRewriterType,
+    upstream: Arc<dyn DnsUpstream>,
+    /// DoT connections of queries routed by name, shared with the default
+    /// upstream unless one is injected
+    pool: Arc<ConnectionPool>,
+    cache: Arc<ResponseCache>,
+    limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+    ready: ReadySignal,
+}
+
+impl TcpDnsServer {
+    pub fn new(config: Arc<AppConfig>, rewriter: SniRewriterType, metrics: Arc<Metrics>) -> Self {
+        Self::from_resources(ServerResources::standalone(config, rewriter, metrics))
+    }
+
+    /// Stop accepting and drain open connections once `shutdown` is cancelled
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Report on `ready` once listening
+    pub fn with_ready(mut self, ready: ReadySignal) -> Self {
+        self.ready = ready;
+        self
+    }
+
+    /// Send unrouted queries to `upstream` instead of the configured default
+    pub fn with_upstream(mut self, upstream: Arc<dyn DnsUpstream>) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    /// Share a response cache with other listeners
+    pub fn with_response_cache(mut self, cache: Arc<ResponseCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+}
+
+impl TcpDnsServer {
+    pub async fn start(&self) -> DnsProxyResult<()> {
+        let server_config = &self.config.servers.tcp_dns;
+        if !server_config.enabled {
+            info!("TCP DNS server is disabled");
+            return Ok(());
+        }
+
+        let bind_addrs = server_config
+            .socket_addrs()
+            .map_err(|e| DnsProxyError::Config(e.to_string()))?;
+        let listener = TcpListeners::bind(&bind_addrs)?;
+        let bind_addr = join_addrs(&listener.local_addrs());
+        info!("TCP DNS server listening on TCP {}", bind_addr);
+        self.ready.listening();
+        let _reaper = self.limiter.spawn_reaper();
+        let mut connections = ConnectionTracker::new().with_limit(
+            self.config.servers.tcp_dns.max_concurrent_connections,
+            self.metrics.in_flight_gauge("tcp_dns"),
+        );
+
+        let mut backoff = AcceptBackoff::from_config(&self.config.servers, self.shutdown.clone());
+        loop {
+            // At the limit, leave new connections queued until one finishes
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = connections.reserve() => {}
+            }
+            let accepted = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                accepted = listener.accept() => accepted,
+            };
+            match accepted {
+                Ok((mut stream, peer)) => {
+                    backoff.succeeded();
+                    let config = Arc::clone(&self.config);
+                    let rewriter = Arc::clone(&self.rewriter);
+                    let upstream = Arc::clone(&self.upstream);
+                    let pool = Arc::clone(&self.pool);
+                    let cache = Arc::clone(&self.cache);
+                    let metrics = Arc::clone(&self.metrics);
+                    let limiter = Arc::clone(&self.limiter);
+                    connections.spawn(async move {
+                        let Some(addr) = proxy_protocol::client_addr(
+                            &mut stream,
+                            peer,
+                            config.servers.tcp_dns.proxy_protocol,
+                            "TCP DNS",
+                            &config.logging,
+                            &metrics,
+                        )
+                        .await
+                        else {
+                            return;
+                        };
+                        if !limiter.check(addr.ip()) {
+                            debug!("TCP DNS client {} is over its rate limit", addr);
+                            metrics.record_rate_limited();
+                            return;
+                        }
+                        debug!("New TCP DNS connection from {}", addr);
+                        if let Err(e) = Self::handle_connection(
+                            stream,
+                            &rewriter,
+                            upstream.as_ref(),
+                            &pool,
+                            &cache,
+                            &config,
+                            &metrics,
+                        )
+                        .await
+                        {
+                            if let DnsProxyError::MessageTooLarge { .. } = e {
+                                warn!("Closing TCP DNS connection from {}: {}", addr, e);
+                                metrics.record_oversized_rejected();
+                            } else {
+                                error!("TCP DNS connection handling error from {}: {}", addr, e);
+                            }
+                        }
+                    });
+                }
+                Err(e) => {
+                    backoff.failed("TCP DNS", &e).await;
+                }
+            }
+        }
+
+        drop(listener);
+        connections.drain("TCP DNS").await;
+        Ok(())
+    }
+
+    /// Serve one TCP client connection
+    ///
+    /// Messages are length-prefixed and the client may send several queries on
+    /// the same connection; they are answered in order until it disconnects.
+    pub async fn handle_connection<S>(
+        stream: S,
+        rewriter: &SniRewriterType,
+        upstream: &dyn DnsUpstream,
+        pool: &ConnectionPool,
+        cache: &ResponseCache,
+        config: &AppConfig,
+        metrics: &Metrics,
+    ) -> DnsProxyResult<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+
+        while let Some(query) =
+            read_framed_limited(&mut reader, config.servers.max_message_size).await?
+        {
+            if let Err(e) = dns::validate_message(&query) {
+                debug!("Answering malformed TCP DNS query with FORMERR: {}", e);
+                write_framed(&mut writer, &dns::formerr_response(&query)).await?;
+                continue;
+            }
+
+            if let Some(refused) = dns::refuse_if_denied(&query, &config.filter) {
+                debug!("Refusing TCP DNS query denied by the domain filter");
+                metrics.record_blocked_request();
+                write_framed(&mut writer, &refused).await?;
+                continue;
+            }
+
+            let timer = Timer::start();
+            let bytes_received = query.len() as u64;
+            let response =
+                match forward_by_qname(&query, rewriter, upstream, pool, cache, config, metrics)
+                    .await
+                {
+                    Ok(response) => response,
+                    Err(e) => {
+                        metrics.record_request(false, bytes_received, 0, timer.elapsed());
+                        metrics.record_upstream_error();
+                        return Err(e);
+                    }
+                };
+
+            write_framed(&mut writer, &response).await?;
+            metrics.record_request(true, bytes_received, response.len() as u64, timer.elapsed());
+            metrics.record_response(&response);
+        }
+
+        debug!("TCP DNS client closed connection");
+        Ok(())
+    }
+}
+
+impl ProtocolServer for TcpDnsServer {
+    const PROTOCOL: Protocol = Protocol::TcpDns;
+
+    fn from_resources(resources: ServerResources) -> Self {
+        let pool = create_connection_pool(&resources.config.upstream);
+        let upstream = resources.upstream.unwrap_or_else(|| {
+            Arc::new(
+                DefaultUpstream::new(
+                    Arc::clone(&resources.config),
+                    Arc::clone(&resources.metrics),
+                )
+                .with_health(resources.upstream_health)
+                .with_pool(Arc::clone(&pool)),
+            )
+        });
+        let limiter = Arc::new(RateLimiter::from_config(&resources.config.ratelimit));
+        Self {
+            config: resources.config,
+            rewriter: resources.rewriter,
+            upstream,
+            pool,
+            cache: resources.response_cache,
+            limiter,
+            metrics: resources.metrics,
+            shutdown: resources.shutdown,
+            ready: resources.ready,
+        }
+    }
+
+    async fn run(&self) -> DnsProxyResult<()> {
+        self.start().await
+    }
+}
@@ -0,0 +1,320 @@
+use crate::config::AppConfig;
+use crate::dns;
+use crate::dns::cache::ResponseCache;
+use crate::dns::cookie::{self, CookieCheck, CookieValidator};
+use crate::error::{DnsProxyError, DnsProxyResult};
+use crate::metrics::{Metrics, Timer};
+use crate::ratelimit::RateLimiter;
+use crate::readers::{Protocol, ProtocolServer};
+use crate::rewrite::SniRewriterType;
+use crate::server::{
+    AcceptBackoff, ConnectionTracker, ReadySignal, ServerResources, UdpSockets, join_addrs,
+};
+use crate::upstream::create_connection_pool;
+use crate::upstream::default_upstream::{DefaultUpstream, DnsUpstream, forward_by_qname};
+use crate::upstream::pool::ConnectionPool;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+/// Plain DNS over UDP (port 53)
+///
+/// UDP queries carry no SNI, so they are routed by their queried name (see
+/// [`forward_by_qname`]).
+pub struct UdpServer {
+    config: Arc<AppConfig>,
+    rewriter: SniRewriterType,
+    upstream: Arc<dyn DnsUpstream>,
+    /// DoT connections of queries routed by name, shared with the default
+    /// upstream unless one is injected
+    pool: Arc<ConnectionPool>,
+    cache: Arc<ResponseCache>,
+    /// Set when `require_cookies` is on
+    cookies: Option<CookieValidator>,
+    limiter: Arc<RateLimiter>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+    ready: ReadySignal,
+}
+
+impl UdpServer {
+    pub fn new(config: Arc<AppConfig>, rewriter: SniRewriterType, metrics: Arc<Metrics>) -> Self {
+        Self::from_resources(ServerResources::standalone(config, rewriter, metrics))
+    }
+
+    /// Stop receiving and drain pending queries once `shutdown` is cancelled
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Report on `ready` once listening
+    pub fn with_ready(mut self, ready: ReadySignal) -> Self {
+        self.ready = ready;
+        self
+    }
+
+    /// Send unrouted queries to `upstream` instead of the configured default
+    pub fn with_upstream(mut self, upstream: Arc<dyn DnsUpstream>) -> Self {
+        self.upstream = upstream;
+        self
+    }
+
+    /// Share a response cache with other listeners
+    pub fn with_response_cache(mut self, cache: Arc<ResponseCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+}
+
+impl UdpServer {
+    pub async fn start(&self) -> DnsProxyResult<()> {
+        let server_config = &self.config.servers.udp;
+        if !server_config.enabled {
+            info!("UDP DNS server is disabled");
+            return Ok(());
+        }
+
+        let bind_addrs = server_config
+            .socket_addrs()
+            .map_err(|e| DnsProxyError::Config(e.to_string()))?;
+        let sockets = UdpSockets::bind(&bind_addrs)?;
+        info!(
+            "UDP DNS server listening on UDP {}",
+            join_addrs(&sockets.local_addrs())
+        );
+        self.ready.listening();
+
+        self.serve(sockets).await
+    }
+
+    /// Answer queries arriving on already bound sockets, each answered on the
+    /// socket it arrived on
+    pub async fn serve(&self, sockets: UdpSockets) -> DnsProxyResult<()> {
+        // One spare byte shows whether a datagram was longer than the limit
+        let max_message_size = self.config.servers.max_message_size;
+        let mut buf = vec![0u8; max_message_size + 1];
+        let _reaper = self.limiter.spawn_reaper();
+        let mut queries = ConnectionTracker::new().with_limit(
+            self.config.servers.udp.max_concurrent_connections,
+            self.metrics.in_flight_gauge("udp"),
+        );
+
+        let mut backoff = AcceptBackoff::from_config(&self.config.servers, self.shutdown.clone());
+        loop {
+            // At the limit, leave new queries queued until one finishes
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = queries.reserve() => {}
+            }
+            let received = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                received = sockets.recv_from(&mut buf) => received,
+            };
+            let (len, peer, socket) = match received {
+                Ok(received) => {
+                    backoff.succeeded();
+                    received
+                }
+                Err(e) => {
+                    backoff.failed("UDP DNS", &e).await;
+                    continue;
+                }
+            };
+            if let Err(e) = dns::validate_message(&buf[..len]) {
+                // No FORMERR either, so a spoofed source can't be used for reflection
+                debug!("Dropping datagram from {}: {}", peer, e);
+                continue;
+            }
+            if len > max_message_size {
+                warn!(
+                    "Dropping datagram from {}: longer than the {} byte limit",
+                    peer, max_message_size
+                );
+                self.metrics.record_oversized_rejected();
+                continue;
+            }
+            if !self.limiter.check(peer.ip()) {
+                // No answer at all, so a spoofed source can't be used for reflection
+                debug!("Dropping query from {}: over its rate limit", peer);
+                self.metrics.record_rate_limited();
+                continue;
+            }
+
+            let query = match &self.cookies {
+                None => ClientQuery {
+                    peer,
+                    message: buf[..len].to_vec(),
+                    cookie: None,
+                },
+                Some(cookies) => match cookies.check(&buf[..len], peer.ip()) {
+                    CookieCheck::Valid { query, cookie } => ClientQuery {
+                        peer,
+                        message: query,
+                        cookie: Some(cookie),
+                    },
+                    CookieCheck::Rejected { response, reason } => {
+                        // Answered without forwarding; the answer is about the
+                        // size of the query, so it is of no use for amplification
+                        debug!(
+                            "Answering query from {} without forwarding: {}",
+                            peer, reason
+                        );
+                        self.metrics.record_cookie_rejected();
+                        if let Err(e) = socket.send_to(&response, peer).await {
+                            error!("Failed to send UDP DNS response to {}: {}", peer, e);
+                        }
+                        continue;
+                    }
+                },
+            };
+            let config = Arc::clone(&self.config);
+            let rewriter = Arc::clone(&self.rewriter);
+            let upstream = Arc::clone(&self.upstream);
+            let pool = Arc::clone(&self.pool);
+            let cache = Arc::clone(&self.cache);
+            let metrics = Arc::clone(&self.metrics);
+            queries.spawn(async move {
+                let response = Self::handle_query(
+                    &query,
+                    &rewriter,
+                    upstream.as_ref(),
+                    &pool,
+                    &cache,
+                    &config,
+                    &metrics,
+                )
+                .await;
+                if let Err(e) = socket.send_to(&response, peer).await {
+                    error!("Failed to send UDP DNS response to {}: {}", peer, e);
+                }
+            });
+        }
+
+        queries.drain("UDP DNS").await;
+        Ok(())
+    }
+
+    /// Forward one query and return the answer (or SERVFAIL) to send back to
+    /// the client
+    ///
+    /// Queries denied by the domain filter are answered with REFUSED.
+    async fn handle_query(
+        client_query: &ClientQuery,
+        rewriter: &SniRewriterType,
+        upstream: &dyn DnsUpstream,
+        pool: &ConnectionPool,
+        cache: &ResponseCache,
+        config: &AppConfig,
+        metrics: &Metrics,
+    ) -> Vec<u8> {
+        let ClientQuery {
+            peer,
+            message: query,
+            ..
+        } = client_query;
+        if let Some(refused) = dns::refuse_if_denied(query, &config.filter) {
+            debug!(
+                "Refusing UDP DNS query from {} denied by the domain filter",
+                peer
+            );
+            metrics.record_blocked_request();
+            return client_query.answer(&refused);
+        }
+
+        let timer = Timer::start();
+        let bytes_received = query.len() as u64;
+
+        let (response, success) =
+            match forward_by_qname(query, rewriter, upstream, pool, cache, config, metrics).await {
+                Ok(response) => {
+                    let response = client_query.answer(&response);
+                    // Oversized answers are truncated so the client retries over TCP
+                    let limit = dns::udp_payload_limit(query);
+                    if response.len() > limit {
+                        debug!(
+                            "Truncating {} byte response to {} (limit {} bytes)",
+                            response.len(),
+                            peer,
+                            limit
+                        );
+                        (dns::truncate_response(&response), true)
+                    } else {
+                        (response, true)
+                    }
+                }
+                Err(e) => {
+                    error!("UDP DNS query from {} failed: {}", peer, e);
+                    metrics.record_upstream_error();
+                    (client_query.answer(&dns::servfail_response(query)), false)
+                }
+            };
+
+        let bytes_sent = if success { response.len() as u64 } else { 0 };
+        metrics.record_request(success, bytes_received, bytes_sent, timer.elapsed());
+        if success {
+            metrics.record_response(&response);
+        }
+        response
+    }
+}
+
+impl ProtocolServer for UdpServer {
+    const PROTOCOL: Protocol = Protocol::Udp;
+
+    fn from_resources(resources: ServerResources) -> Self {
+        let pool = create_connection_pool(&resources.config.upstream);
+        let upstream = resources.upstream.unwrap_or_else(|| {
+            Arc::new(
+                DefaultUpstream::new(
+                    Arc::clone(&resources.config),
+                    Arc::clone(&resources.metrics),
+                )
+                .with_health(resources.upstream_health)
+                .with_pool(Arc::clone(&pool)),
+            )
+        });
+        let limiter = Arc::new(RateLimiter::from_config(&resources.config.ratelimit));
+        let cookies = resources
+            .config
+            .servers
+            .udp
+            .require_cookies
+            .then(CookieValidator::new);
+        Self {
+            config: resources.config,
+            rewriter: resources.rewriter,
+            upstream,
+            pool,
+            cache: resources.response_cache,
+            cookies,
+            limiter,
+            metrics: resources.metrics,
+            shutdown: resources.shutdown,
+            ready: resources.ready,
+        }
+    }
+
+    async fn run(&self) -> DnsProxyResult<()> {
+        self.start().await
+    }
+}
+
+/// A query received from a UDP client
+struct ClientQuery {
+    peer: SocketAddr,
+    message: Vec<u8>,
+    /// COOKIE option data to answer with, when `require_cookies` is on
+    cookie: Option<Vec<u8>>,
+}
+
+impl ClientQuery {
+    /// Answer to send back, carrying the client's cookie if it sent one
+    fn answer(&self, response: &[u8]) -> Vec<u8> {
+        match &self.cookie {
+            Some(data) => cookie::set_cookie(response, data),
+            None => response.to_vec(),
+        }
+    }
+}
@@ -1,9 +1,14 @@
 use crate::config::RewriteConfig;
-use crate::rewriters::BaseSniRewriter;
+use crate::error::DnsProxyResult;
+use crate::rewriters::{BaseSniRewriter, RegexSniRewriter, StaticMapRewriter};
+use crate::sni::SniRewriter;
 use std::sync::Arc;
+use tracing::error;
 
 /// Type alias for the SNI rewriter used throughout the application
-pub type SniRewriterType = Arc<BaseSniRewriter>;
+///
+/// Any [`SniRewriter`] can be used, so embedders may supply their own.
+pub type SniRewriterType = Arc<dyn SniRewriter>;
 
 /// Create a new SNI rewriter instance from the given configuration
 ///
@@ -13,7 +18,19 @@ pub type SniRewriterType = Arc<BaseSniRewriter>;
 ///
 /// # Returns
 ///
-/// Returns an `Arc`-wrapped `BaseSniRewriter` that can be shared across tasks
+/// Returns an `Arc`-wrapped rewriter that can be shared across tasks: a
+/// `RegexSniRewriter` when `rewriter_type` is "regex", a `StaticMapRewriter`
+/// when it is "static_map", otherwise a `BaseSniRewriter`. Invalid regex
+/// rules or an unreadable mapping file fall back to the base rewriter;
+/// `AppConfig::validate` rejects them.
 pub fn create_rewriter(config: RewriteConfig) -> SniRewriterType {
-    Arc::new(BaseSniRewriter::new(config))
+    let rewriter: DnsProxyResult<SniRewriterType> = match config.rewriter_type.as_str() {
+        "regex" => RegexSniRewriter::new(&config).map(|r| Arc::new(r) as SniRewriterType),
+        "static_map" => StaticMapRewriter::new(&config).map(|r| Arc::new(r) as SniRewriterType),
+        _ => return Arc::new(BaseSniRewriter::new(config)),
+    };
+    rewriter.unwrap_or_else(|e| {
+        error!("{}; falling back to the base rewriter", e);
+        Arc::new(BaseSniRewriter::new(config))
+    })
 }
@@ -1,12 +1,22 @@
 use crate::config::RewriteConfig;
+use crate::error::SniRewriteError;
 use crate::sni::{RewriteResult, SniRewriter};
 use dashmap::DashMap;
 use std::sync::Arc;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
+
+/// Most rewritten SNIs a [`BaseSniRewriter`] caches; clients pick the SNIs,
+/// so later ones are rewritten without being cached
+pub const MAX_CACHED_REWRITES: usize = 10_000;
 
 pub struct BaseSniRewriter {
     config: RewriteConfig,
-    pub sni_map: Arc<DashMap<String, String>>,
+    /// Rewritten SNIs by [`RewriteResult::cache_key`], consulted before
+    /// extracting a prefix
+    ///
+    /// Passthroughs aren't cached, and at most [`MAX_CACHED_REWRITES`]
+    /// rewrites are.
+    pub sni_map: Arc<DashMap<String, RewriteResult>>,
 }
 
 impl BaseSniRewriter {
@@ -17,98 +27,197 @@ impl BaseSniRewriter {
         }
     }
 
+    /// Prefix of an SNI before the first base domain it ends with
     pub fn extract_prefix(&self, sni: &str) -> Option<String> {
-        for base_domain in &self.config.base_domains {
+        self.match_base_domain(sni).map(|(prefix, _)| prefix)
+    }
+
+    /// Prefix of an SNI and the target suffix of the base domain it matched
+    fn match_base_domain(&self, sni: &str) -> Option<(String, &str)> {
+        for (base_domain, target_suffix) in self.config.base_domain_targets() {
             if let Some(rest) = sni.strip_suffix(base_domain)
                 && !rest.is_empty()
                 && rest.ends_with('.')
             {
                 let prefix = rest.strip_suffix('.').unwrap_or(rest);
                 if !prefix.is_empty() {
-                    return Some(prefix.to_string());
+                    return Some((prefix.to_string(), target_suffix));
                 }
             }
         }
         None
     }
 
-    pub fn build_target_hostname(&self, prefix: &str) -> String {
-        format!("{}{}", prefix, self.config.target_suffix)
+    /// Check that the config can rewrite anything, warning when it can't
+    fn check_config(&self) -> Result<(), SniRewriteError> {
+        let mut targets = self.config.base_domain_targets().peekable();
+        let error = if targets.peek().is_none() {
+            SniRewriteError::NoBaseDomains
+        } else if let Some((_, suffix)) = targets.find(|(_, suffix)| !suffix.starts_with('.')) {
+            SniRewriteError::InvalidTargetSuffix {
+                suffix: suffix.to_string(),
+            }
+        } else {
+            return Ok(());
+        };
+        warn!("{}", error);
+        Err(error)
     }
-}
-
-#[async_trait::async_trait]
-impl SniRewriter for BaseSniRewriter {
-    async fn rewrite(&self, sni: &str) -> Option<RewriteResult> {
-        // Validate input
-        if sni.is_empty() {
-            warn!("Empty SNI provided for rewrite");
-            return None;
-        }
-
-        // Check if base domains are configured
-        if self.config.base_domains.is_empty() {
-            warn!("No base domains configured for SNI rewriting");
-            return None;
-        }
 
-        // Validate target suffix
-        if !self.config.target_suffix.starts_with('.') {
-            warn!(
-                "Invalid target suffix: {} (must start with '.')",
-                self.config.target_suffix
-            );
-            return None;
+    /// Prefix of an SNI and its target suffix, or why it has none
+    fn prefix_of(&self, sni: &str) -> Result<(String, &str), SniRewriteError> {
+        if let Some(matched) = self.match_base_domain(sni) {
+            return Ok(matched);
         }
+        let is_base_domain = self.config.base_domain_targets().any(|(base_domain, _)| {
+            sni.strip_suffix(base_domain)
+                .is_some_and(|rest| rest.is_empty() || rest == ".")
+        });
+        let hostname = sni.to_string();
+        Err(if is_base_domain {
+            SniRewriteError::NoPrefix { hostname }
+        } else {
+            SniRewriteError::NoMatchingBaseDomain { hostname }
+        })
+    }
 
-        // Try to extract prefix
-        let prefix = match self.extract_prefix(sni) {
-            Some(p) => p,
-            None => {
-                // Handle rewrite failure based on strategy
-                match self.config.rewrite_failure_strategy.as_str() {
-                    "passthrough" => {
-                        warn!(
-                            "SNI rewrite failed for '{}', using passthrough strategy",
-                            sni
-                        );
-                        // Return result with original hostname as target
-                        return Some(RewriteResult {
-                            original: sni.to_string(),
-                            prefix: String::new(),
-                            target_hostname: sni.to_string(),
-                        });
-                    }
-                    _ => {
-                        // Default: return None (error strategy)
-                        return None;
-                    }
-                }
+    /// Rewrite an SNI without consulting or filling the cache
+    fn rewrite_uncached(&self, sni: &str) -> Result<RewriteResult, SniRewriteError> {
+        let (prefix, target_suffix) = match self.prefix_of(sni) {
+            Ok(matched) => matched,
+            // Handle rewrite failure based on strategy
+            Err(e) if self.config.rewrite_failure_strategy == "passthrough" => {
+                warn!(
+                    "SNI rewrite failed for '{}' ({}), using passthrough strategy",
+                    sni, e
+                );
+                // Return result with original hostname as target
+                return Ok(RewriteResult {
+                    original: sni.to_string(),
+                    prefix: String::new(),
+                    target_hostname: sni.to_string(),
+                    passthrough: true,
+                    warn: false,
+                });
             }
+            Err(e) if self.config.rewrite_failure_strategy == "passthrough_warn" => {
+                warn!(
+                    "SNI '{}' passed through unrewritten ({}); add its base domain to rewrite.base_domains to rewrite it",
+                    sni, e
+                );
+                return Ok(RewriteResult {
+                    original: sni.to_string(),
+                    prefix: String::new(),
+                    target_hostname: sni.to_string(),
+                    passthrough: true,
+                    warn: true,
+                });
+            }
+            Err(e) => return Err(e),
         };
 
-        let target_hostname = self.build_target_hostname(&prefix);
-
-        // Cache the mapping for future lookups (lock-free with DashMap)
-        self.sni_map
-            .insert(sni.to_string(), target_hostname.clone());
+        let target_hostname = format!("{}{}", prefix, target_suffix);
 
         info!(
             "SNI Rewrite: {} -> Prefix: {} -> Target: {}",
             sni, prefix, target_hostname
         );
 
-        Some(RewriteResult {
+        Ok(RewriteResult {
             original: sni.to_string(),
             prefix,
             target_hostname,
+            passthrough: false,
+            warn: false,
         })
     }
+
+    /// Previously rewritten result for an SNI
+    fn cached(&self, sni: &str) -> Option<RewriteResult> {
+        let cached = self.sni_map.get(sni)?;
+        debug!(
+            "SNI Rewrite (cached): {} -> Target: {}",
+            sni, cached.target_hostname
+        );
+        Some(cached.clone())
+    }
+
+    /// Cache a rewritten SNI for future lookups, unless it was passed
+    /// through or the cache is full
+    fn remember(&self, result: &RewriteResult) {
+        self.remember_all(std::iter::once(result));
+    }
+
+    /// Cache a batch of rewritten SNIs in one pass, checking the cache's
+    /// size once rather than once per SNI
+    fn remember_all<'a>(&self, results: impl IntoIterator<Item = &'a RewriteResult>) {
+        let room = MAX_CACHED_REWRITES.saturating_sub(self.sni_map.len());
+        for result in results
+            .into_iter()
+            .filter(|result| !result.passthrough)
+            .take(room)
+        {
+            self.sni_map
+                .insert(result.cache_key().to_string(), result.clone());
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl SniRewriter for BaseSniRewriter {
+    async fn rewrite_with_reason(&self, sni: &str) -> Result<RewriteResult, SniRewriteError> {
+        // Validate input
+        if sni.is_empty() {
+            warn!("Empty SNI provided for rewrite");
+            return Err(SniRewriteError::EmptySni);
+        }
+        self.check_config()?;
+
+        // Serve a previously rewritten SNI from the cache
+        if let Some(cached) = self.cached(sni) {
+            return Ok(cached);
+        }
+
+        let result = self.rewrite_uncached(sni)?;
+        self.remember(&result);
+        Ok(result)
+    }
+
+    /// Validates the config once for the whole batch instead of once per
+    /// name, and caches the batch's new rewrites together at the end
+    async fn rewrite_many(&self, names: &[&str]) -> Vec<Option<RewriteResult>> {
+        if self.check_config().is_err() {
+            return vec![None; names.len()];
+        }
+
+        let mut fresh = Vec::new();
+        let results = names
+            .iter()
+            .map(|sni| {
+                if sni.is_empty() {
+                    warn!("Empty SNI provided for rewrite");
+                    return None;
+                }
+                if let Some(cached) = self.cached(sni) {
+                    return Some(cached);
+                }
+                let result = self.rewrite_uncached(sni).ok()?;
+                fresh.push(result.clone());
+                Some(result)
+            })
+            .collect();
+        self.remember_all(&fresh);
+        results
+    }
 }
 
 #[async_trait::async_trait]
 impl SniRewriter for std::sync::Arc<BaseSniRewriter> {
-    async fn rewrite(&self, sni: &str) -> Option<RewriteResult> {
-        self.as_ref().rewrite(sni).await
+    async fn rewrite_with_reason(&self, sni: &str) -> Result<RewriteResult, SniRewriteError> {
+        self.as_ref().rewrite_with_reason(sni).await
+    }
+
+    async fn rewrite_many(&self, names: &[&str]) -> Vec<Option<RewriteResult>> {
+        self.as_ref().rewrite_many(names).await
     }
 }
@@ -0,0 +1,193 @@
+use crate::config::RewriteConfig;
+use crate::error::{DnsProxyError, DnsProxyResult, SniRewriteError};
+use crate::sni::{RewriteResult, SniRewriter};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+/// A loaded mapping table and the file modification time it was loaded at
+struct Mapping {
+    entries: HashMap<String, String>,
+    modified: Option<SystemTime>,
+}
+
+/// Rewrites hostnames with an explicit `source_sni -> target_hostname` table
+///
+/// The table is read from `mapping_file`: a CSV file (`.csv`) with one
+/// `source,target` pair per line, or otherwise a TOML file of
+/// `"source" = "target"` entries. Sources match case-insensitively and the
+/// target is returned exactly as written. The file is reloaded when its
+/// modification time changes; when reloading fails the previously loaded
+/// table keeps being used.
+pub struct StaticMapRewriter {
+    path: PathBuf,
+    mapping: RwLock<Arc<Mapping>>,
+    rewrite_failure_strategy: String,
+}
+
+impl StaticMapRewriter {
+    /// Load the mapping file of a rewrite config
+    pub fn new(config: &RewriteConfig) -> DnsProxyResult<Self> {
+        let path = config.mapping_file.as_deref().ok_or_else(|| {
+            DnsProxyError::Config(
+                "rewrite.mapping_file must be set for the static_map rewriter".to_string(),
+            )
+        })?;
+        let path = PathBuf::from(path);
+        let mapping = load_mapping(&path)?;
+        Ok(Self {
+            path,
+            mapping: RwLock::new(Arc::new(mapping)),
+            rewrite_failure_strategy: config.rewrite_failure_strategy.clone(),
+        })
+    }
+
+    /// Re-read the mapping file, keeping the current table if it fails to load
+    pub fn reload(&self) -> DnsProxyResult<()> {
+        let mapping = load_mapping(&self.path)?;
+        info!(
+            "Reloaded {} SNI mappings from {}",
+            mapping.entries.len(),
+            self.path.display()
+        );
+        *self.mapping.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(mapping);
+        Ok(())
+    }
+
+    /// Current table, reloaded first if the file changed since it was read
+    async fn current(&self) -> Arc<Mapping> {
+        let mapping = Arc::clone(&self.mapping.read().unwrap_or_else(|e| e.into_inner()));
+        let modified = tokio::fs::metadata(&self.path)
+            .await
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified.is_none() || modified == mapping.modified {
+            return mapping;
+        }
+        match self.reload() {
+            Ok(()) => Arc::clone(&self.mapping.read().unwrap_or_else(|e| e.into_inner())),
+            Err(e) => {
+                warn!(
+                    "Failed to reload SNI mappings, keeping the loaded ones: {}",
+                    e
+                );
+                mapping
+            }
+        }
+    }
+}
+
+/// Read a CSV or TOML mapping file into a table keyed by lowercase source SNI
+fn load_mapping(path: &Path) -> DnsProxyResult<Mapping> {
+    let invalid = |reason: String| {
+        DnsProxyError::Config(format!(
+            "Invalid rewrite.mapping_file {}: {}",
+            path.display(),
+            reason
+        ))
+    };
+    let modified = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    let content = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+
+    let is_csv = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+    let entries = if is_csv {
+        parse_csv(&content).map_err(invalid)?
+    } else {
+        parse_toml(&content).map_err(invalid)?
+    };
+    Ok(Mapping {
+        entries: entries
+            .into_iter()
+            .map(|(source, target)| (source.to_ascii_lowercase(), target))
+            .collect(),
+        modified,
+    })
+}
+
+/// Parse `source,target` lines, skipping blank lines and `#` comments
+fn parse_csv(content: &str) -> Result<Vec<(String, String)>, String> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            match fields.as_slice() {
+                [source, target] if !source.is_empty() && !target.is_empty() => {
+                    Ok((source.to_string(), target.to_string()))
+                }
+                _ => Err(format!("line {}: expected source,target", number)),
+            }
+        })
+        .collect()
+}
+
+/// Parse a table of `"source" = "target"` string entries
+fn parse_toml(content: &str) -> Result<Vec<(String, String)>, String> {
+    let table: toml::Table = toml::from_str(content).map_err(|e| e.to_string())?;
+    table
+        .into_iter()
+        .map(|(source, target)| match target {
+            toml::Value::String(target) if !target.is_empty() => Ok((source, target)),
+            _ => Err(format!("{}: target must be a non-empty string", source)),
+        })
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl SniRewriter for StaticMapRewriter {
+    async fn rewrite_with_reason(&self, sni: &str) -> Result<RewriteResult, SniRewriteError> {
+        if sni.is_empty() {
+            warn!("Empty SNI provided for rewrite");
+            return Err(SniRewriteError::EmptySni);
+        }
+
+        let mapping = self.current().await;
+        if let Some(target_hostname) = mapping.entries.get(&sni.to_ascii_lowercase()) {
+            info!("SNI Rewrite: {} -> Mapped: {}", sni, target_hostname);
+            return Ok(RewriteResult {
+                original: sni.to_string(),
+                prefix: String::new(),
+                target_hostname: target_hostname.clone(),
+                passthrough: false,
+                warn: false,
+            });
+        }
+
+        let warn = match self.rewrite_failure_strategy.as_str() {
+            "passthrough" => {
+                warn!(
+                    "SNI rewrite failed for '{}', using passthrough strategy",
+                    sni
+                );
+                false
+            }
+            "passthrough_warn" => {
+                warn!(
+                    "SNI '{}' passed through unrewritten; add it to rewrite.mapping_file to rewrite it",
+                    sni
+                );
+                true
+            }
+            _ => {
+                return Err(SniRewriteError::NoMatchingRule {
+                    hostname: sni.to_string(),
+                });
+            }
+        };
+        Ok(RewriteResult {
+            original: sni.to_string(),
+            prefix: String::new(),
+            target_hostname: sni.to_string(),
+            passthrough: true,
+            warn,
+        })
+    }
+}
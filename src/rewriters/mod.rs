@@ -1,3 +1,7 @@
 pub mod base;
+pub mod map;
+pub mod regex;
 
 pub use base::BaseSniRewriter;
+pub use map::StaticMapRewriter;
+pub use regex::RegexSniRewriter;
@@ -0,0 +1,108 @@
+use crate::config::RewriteConfig;
+use crate::error::{DnsProxyError, DnsProxyResult, SniRewriteError};
+use crate::sni::{RewriteResult, SniRewriter};
+use regex::Regex;
+use tracing::{info, warn};
+
+/// Rewrites hostnames with `[[rewrite.rules]]` regex rules
+///
+/// Rules are tried in order and the first whose pattern matches builds the
+/// target hostname from its replacement, expanding `$1`, `${name}` etc. to
+/// the pattern's capture groups. Patterns are not anchored implicitly, so
+/// use `^` and `$` to match whole hostnames.
+pub struct RegexSniRewriter {
+    rules: Vec<(Regex, String)>,
+    rewrite_failure_strategy: String,
+}
+
+impl RegexSniRewriter {
+    /// Compile the rules of a rewrite config
+    ///
+    /// Fails on the first pattern that is not a valid regex.
+    pub fn new(config: &RewriteConfig) -> DnsProxyResult<Self> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                let regex = Regex::new(&rule.pattern).map_err(|e| {
+                    DnsProxyError::Config(format!(
+                        "Invalid pattern in rewrite.rules: {}: {}",
+                        rule.pattern, e
+                    ))
+                })?;
+                Ok((regex, rule.replacement.clone()))
+            })
+            .collect::<DnsProxyResult<_>>()?;
+        Ok(Self {
+            rules,
+            rewrite_failure_strategy: config.rewrite_failure_strategy.clone(),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl SniRewriter for RegexSniRewriter {
+    async fn rewrite_with_reason(&self, sni: &str) -> Result<RewriteResult, SniRewriteError> {
+        if sni.is_empty() {
+            warn!("Empty SNI provided for rewrite");
+            return Err(SniRewriteError::EmptySni);
+        }
+
+        for (regex, replacement) in &self.rules {
+            let Some(captures) = regex.captures(sni) else {
+                continue;
+            };
+            let mut target_hostname = String::new();
+            captures.expand(replacement, &mut target_hostname);
+            if target_hostname.is_empty() {
+                continue;
+            }
+            let prefix = captures
+                .get(1)
+                .map_or_else(String::new, |m| m.as_str().to_string());
+
+            info!(
+                "SNI Rewrite: {} -> Rule: {} -> Target: {}",
+                sni,
+                regex.as_str(),
+                target_hostname
+            );
+            return Ok(RewriteResult {
+                original: sni.to_string(),
+                prefix,
+                target_hostname,
+                passthrough: false,
+                warn: false,
+            });
+        }
+
+        let warn = match self.rewrite_failure_strategy.as_str() {
+            "passthrough" => {
+                warn!(
+                    "SNI rewrite failed for '{}', using passthrough strategy",
+                    sni
+                );
+                false
+            }
+            "passthrough_warn" => {
+                warn!(
+                    "SNI '{}' passed through unrewritten; add a rewrite.rules entry to rewrite it",
+                    sni
+                );
+                true
+            }
+            _ => {
+                return Err(SniRewriteError::NoMatchingRule {
+                    hostname: sni.to_string(),
+                });
+            }
+        };
+        Ok(RewriteResult {
+            original: sni.to_string(),
+            prefix: String::new(),
+            target_hostname: sni.to_string(),
+            passthrough: true,
+            warn,
+        })
+    }
+}
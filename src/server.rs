@@ -1,23 +1,41 @@
 /// Common server startup utilities
-use crate::config::{AppConfig, ServerPortConfig};
-use crate::error::DnsProxyResult;
+use crate::config::{AppConfig, ServerPortConfig, ServersConfig};
+use crate::dns::cache::ResponseCache;
+use crate::error::{DnsProxyError, DnsProxyResult};
 use crate::metrics::Metrics;
+use crate::readers::Reader;
 use crate::rewrite::SniRewriterType;
-use std::sync::Arc;
-use tokio::task::JoinHandle;
-use tracing::{error, info};
+use crate::upstream::default_upstream::DnsUpstream;
+use crate::upstream::health::UpstreamHealth;
+use crate::utils::backoff::exponential_backoff;
+use futures::future::select_all;
+use prometheus::IntGauge;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, oneshot};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
 /// Common server startup helper
 pub struct ServerStarter;
 
 impl ServerStarter {
     /// Start a server with a closure that receives cloned resources
+    ///
+    /// The returned [`ServerReady`] resolves once the server reports it is
+    /// listening through `resources.ready`, or with the error it stopped on.
     pub fn start_server<F, Fut>(
         name: &str,
         config: &ServerPortConfig,
-        resources: ServerResources,
+        mut resources: ServerResources,
         server_future: F,
-    ) -> Option<JoinHandle<()>>
+    ) -> Option<(JoinHandle<()>, ServerReady)>
     where
         F: FnOnce(ServerResources) -> Fut + Send + 'static,
         Fut: std::future::Future<Output = DnsProxyResult<()>> + Send + 'static,
@@ -27,17 +45,108 @@ impl ServerStarter {
             return None;
         }
 
-        let bind_addr = format!("{}:{}", config.bind_address, config.port);
+        let bind_addr = match &config.uds_path {
+            Some(path) => format!("unix:{}", path),
+            None => format!("{}:{}", config.bind_address.join(", "), config.port),
+        };
+        let (signal, ready) = ReadySignal::channel(name);
+        resources.ready = signal.clone();
         let name_for_log = name.to_string(); // For final log message
         let name = name.to_string(); // Convert to owned String for 'static lifetime
         let handle = tokio::spawn(async move {
-            if let Err(e) = server_future(resources).await {
+            if let Err(e) = server_future(resources).await
+                && let Some(e) = signal.failed(e)
+            {
                 error!("{} server error: {}", name, e);
             }
         });
 
         info!("{} server started on {}", name_for_log, bind_addr);
-        Some(handle)
+        Some((handle, ready))
+    }
+
+    /// Start the server of `reader` with `resources`
+    pub fn start(
+        reader: Box<dyn Reader>,
+        resources: ServerResources,
+    ) -> Option<(JoinHandle<()>, ServerReady)> {
+        let protocol = reader.protocol();
+        let config = Arc::clone(&resources.config);
+        Self::start_server(
+            protocol.name(),
+            protocol.listen_config(&config),
+            resources,
+            |resources| async move {
+                let shutdown = resources.shutdown.clone();
+                reader.start(resources, shutdown).await
+            },
+        )
+    }
+}
+
+/// Reported to by a server once it is listening on all its addresses
+///
+/// Clones share the report; only the first one is delivered. A default
+/// signal reports to nobody.
+#[derive(Clone, Default)]
+pub struct ReadySignal {
+    sender: Arc<Mutex<Option<oneshot::Sender<DnsProxyResult<()>>>>>,
+}
+
+impl ReadySignal {
+    /// A signal for the server `name` and the [`ServerReady`] it reports to
+    pub fn channel(name: &str) -> (Self, ServerReady) {
+        let (sender, receiver) = oneshot::channel();
+        let signal = Self {
+            sender: Arc::new(Mutex::new(Some(sender))),
+        };
+        let ready = ServerReady {
+            name: name.to_string(),
+            receiver,
+        };
+        (signal, ready)
+    }
+
+    /// Report that the server is listening
+    pub fn listening(&self) {
+        if let Some(sender) = self.take() {
+            let _ = sender.send(Ok(()));
+        }
+    }
+
+    /// Report that the server stopped on `e`, handing `e` back when it can't
+    /// be delivered because the server was already listening
+    pub fn failed(&self, e: DnsProxyError) -> Option<DnsProxyError> {
+        match self.take() {
+            Some(sender) => sender.send(Err(e)).err().and_then(Result::err),
+            None => Some(e),
+        }
+    }
+
+    fn take(&self) -> Option<oneshot::Sender<DnsProxyResult<()>>> {
+        self.sender.lock().unwrap_or_else(|e| e.into_inner()).take()
+    }
+}
+
+/// Resolves once a started server is listening
+pub struct ServerReady {
+    name: String,
+    receiver: oneshot::Receiver<DnsProxyResult<()>>,
+}
+
+impl ServerReady {
+    /// Wait until the server is listening, or for the error that stopped it
+    /// before it was
+    pub async fn wait(self) -> DnsProxyResult<()> {
+        let reason = match self.receiver.await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "server task ended before listening".to_string(),
+        };
+        Err(DnsProxyError::ServerStart {
+            server: self.name,
+            reason,
+        })
     }
 }
 
@@ -47,14 +156,459 @@ pub struct ServerResources {
     pub config: Arc<AppConfig>,
     pub rewriter: SniRewriterType,
     pub metrics: Arc<Metrics>,
+    pub upstream_health: Arc<UpstreamHealth>,
+    /// Answers cached for the UDP, TCP, DoQ, DoH and DoH3 listeners
+    pub response_cache: Arc<ResponseCache>,
+    /// Cancelled when the servers should stop accepting and drain
+    pub shutdown: CancellationToken,
+    /// Told once the server is listening
+    pub ready: ReadySignal,
+    /// Replaces the configured default upstream of the plain UDP/TCP
+    /// listeners
+    pub upstream: Option<Arc<dyn DnsUpstream>>,
 }
 
 impl ServerResources {
-    pub fn new(config: Arc<AppConfig>, rewriter: SniRewriterType, metrics: Arc<Metrics>) -> Self {
+    pub fn new(
+        config: Arc<AppConfig>,
+        rewriter: SniRewriterType,
+        metrics: Arc<Metrics>,
+        upstream_health: Arc<UpstreamHealth>,
+        response_cache: Arc<ResponseCache>,
+        shutdown: CancellationToken,
+    ) -> Self {
         Self {
             config,
             rewriter,
             metrics,
+            upstream_health,
+            response_cache,
+            shutdown,
+            ready: ReadySignal::default(),
+            upstream: None,
+        }
+    }
+
+    /// Resources for a server running on its own, with upstream health and
+    /// a response cache of its own and nothing to stop it
+    pub fn standalone(
+        config: Arc<AppConfig>,
+        rewriter: SniRewriterType,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let upstream_health = Arc::new(UpstreamHealth::from_config(&config));
+        let response_cache = Arc::new(ResponseCache::from_config(&config.cache));
+        Self::new(
+            config,
+            rewriter,
+            metrics,
+            upstream_health,
+            response_cache,
+            CancellationToken::new(),
+        )
+    }
+
+    /// Send unrouted queries of the plain UDP/TCP listeners to `upstream`
+    pub fn with_upstream(mut self, upstream: Arc<dyn DnsUpstream>) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+}
+
+/// In-flight connection (or query) tasks of one server
+///
+/// Tasks are aborted when the tracker is dropped, so aborting a server's
+/// accept loop also stops connections that did not drain in time.
+#[derive(Default)]
+pub struct ConnectionTracker {
+    tasks: JoinSet<()>,
+    /// One permit per allowed in-flight task, when the server has a limit
+    slots: Option<Arc<Semaphore>>,
+    /// Permit taken by [`ConnectionTracker::reserve`] for the next task
+    reserved: Option<OwnedSemaphorePermit>,
+    in_flight: Option<IntGauge>,
+}
+
+/// Held by a running task; frees its slot and updates the gauge when the task
+/// ends or is aborted
+struct TaskSlot {
+    _permit: Option<OwnedSemaphorePermit>,
+    in_flight: Option<IntGauge>,
+}
+
+impl Drop for TaskSlot {
+    fn drop(&mut self) {
+        if let Some(in_flight) = &self.in_flight {
+            in_flight.dec();
+        }
+    }
+}
+
+impl ConnectionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow at most `max_concurrent` tasks in flight (0 = unlimited) and
+    /// report how many are running on `in_flight`
+    pub fn with_limit(mut self, max_concurrent: usize, in_flight: IntGauge) -> Self {
+        self.slots = (max_concurrent > 0).then(|| Arc::new(Semaphore::new(max_concurrent)));
+        self.in_flight = Some(in_flight);
+        self
+    }
+
+    /// Wait until another task may be spawned
+    ///
+    /// Accept loops call this before accepting, so a saturated server leaves
+    /// new connections queued (in the listen backlog or socket buffer) until a
+    /// running task finishes. Returns immediately when there is no limit.
+    /// Cancel safe.
+    pub async fn reserve(&mut self) {
+        if self.reserved.is_some() {
+            return;
+        }
+        if let Some(slots) = &self.slots {
+            // The semaphore is never closed
+            self.reserved = Arc::clone(slots).acquire_owned().await.ok();
+        }
+    }
+
+    /// Spawn a connection task, first reaping the ones that already finished
+    ///
+    /// The task takes the slot claimed by the last [`ConnectionTracker::reserve`].
+    pub fn spawn<F>(&mut self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        while self.tasks.try_join_next().is_some() {}
+        if let Some(in_flight) = &self.in_flight {
+            in_flight.inc();
+        }
+        let slot = TaskSlot {
+            _permit: self.reserved.take(),
+            in_flight: self.in_flight.clone(),
+        };
+        self.tasks.spawn(async move {
+            let _slot = slot;
+            task.await;
+        });
+    }
+
+    /// Wait for every in-flight task once the server has stopped accepting
+    ///
+    /// How many tasks were drained, and how many were aborted when the server
+    /// did not drain in time, is logged once draining ends.
+    pub async fn drain(mut self, name: &str) {
+        while self.tasks.try_join_next().is_some() {}
+        let mut report = DrainReport {
+            name,
+            in_flight: self.tasks.len(),
+            drained: 0,
+            started: Instant::now(),
+            finished: false,
+        };
+        if report.in_flight > 0 {
+            info!(
+                "{} server draining {} in-flight connection(s)",
+                name, report.in_flight
+            );
+        }
+        while self.tasks.join_next().await.is_some() {
+            report.drained += 1;
+        }
+        report.finished = true;
+    }
+}
+
+/// Drain statistics of a stopping server, logged when draining finishes or
+/// is cut short by the server being aborted
+struct DrainReport<'a> {
+    name: &'a str,
+    /// Tasks running when draining started
+    in_flight: usize,
+    /// Tasks that finished since
+    drained: usize,
+    started: Instant,
+    finished: bool,
+}
+
+impl Drop for DrainReport<'_> {
+    fn drop(&mut self) {
+        let aborted = self.in_flight - self.drained;
+        let drain_ms = self.started.elapsed().as_millis() as u64;
+        if self.finished {
+            info!(
+                event = "server_drained",
+                server = self.name,
+                in_flight = self.in_flight,
+                drained = self.drained,
+                aborted,
+                drain_ms,
+                "{} server stopped, drained {} in-flight connection(s) in {}ms",
+                self.name,
+                self.drained,
+                drain_ms
+            );
+        } else {
+            warn!(
+                event = "server_drained",
+                server = self.name,
+                in_flight = self.in_flight,
+                drained = self.drained,
+                aborted,
+                drain_ms,
+                "{} server stopped, aborted {} of {} in-flight connection(s) after draining for {}ms",
+                self.name,
+                aborted,
+                self.in_flight,
+                drain_ms
+            );
+        }
+    }
+}
+
+/// Pause of a server's accept (or receive) loop after a failed accept
+///
+/// Failures such as running out of file descriptors never stop the server:
+/// each one in a row waits twice as long as the last, from
+/// `servers.accept_backoff_base_ms` up to `servers.accept_backoff_max_ms`,
+/// and an accepted connection starts over. A pause ends early on shutdown.
+pub struct AcceptBackoff {
+    base: Duration,
+    max: Duration,
+    /// Failed accepts since the last successful one
+    failures: u32,
+    shutdown: CancellationToken,
+}
+
+impl AcceptBackoff {
+    pub fn new(base: Duration, max: Duration, shutdown: CancellationToken) -> Self {
+        Self {
+            base,
+            max,
+            failures: 0,
+            shutdown,
+        }
+    }
+
+    /// Backoff of a server of `config`, cut short by `shutdown`
+    pub fn from_config(config: &ServersConfig, shutdown: CancellationToken) -> Self {
+        Self::new(
+            config.accept_backoff_base(),
+            config.accept_backoff_max(),
+            shutdown,
+        )
+    }
+
+    /// Pause after the next failed accept
+    pub fn next_delay(&self) -> Duration {
+        exponential_backoff(
+            self.failures,
+            self.base.as_millis() as u64,
+            self.max.as_millis() as u64,
+        )
+    }
+
+    /// Record an accepted connection
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Log a failed accept of `server` and wait before the next one,
+    /// returning the pause
+    pub async fn failed(&mut self, server: &str, error: &io::Error) -> Duration {
+        let delay = self.next_delay();
+        self.failures = self.failures.saturating_add(1);
+        error!(
+            event = "accept_error",
+            server,
+            failures = self.failures,
+            delay_ms = delay.as_millis() as u64,
+            "{} accept error: {}, retrying in {:?}",
+            server,
+            error,
+            delay
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = self.shutdown.cancelled() => {}
+        }
+        delay
+    }
+}
+
+/// Addresses a server listens on, for log messages
+pub fn join_addrs(addrs: &[SocketAddr]) -> String {
+    addrs
+        .iter()
+        .map(SocketAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Listen backlog of TCP listeners, as used by `tokio::net::TcpListener::bind`
+const TCP_BACKLOG: i32 = 1024;
+
+/// Create a non-blocking socket for `addr`
+///
+/// A wildcard IPv6 address (`::`) is made dual-stack by clearing
+/// `IPV6_V6ONLY`, so IPv4 clients reach it as IPv4-mapped addresses whatever
+/// the system default is.
+fn new_socket(addr: SocketAddr, socket_type: Type, protocol: Protocol) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), socket_type, Some(protocol))?;
+    if addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Name the address in a bind error
+fn bind_error(addr: SocketAddr, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), format!("Failed to bind {}: {}", addr, e))
+}
+
+/// Bind a TCP listener to `addr` (dual-stack for `::`)
+pub fn bind_tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let listener = (|| {
+        let socket = new_socket(addr, Type::STREAM, Protocol::TCP)?;
+        #[cfg(not(windows))]
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(TCP_BACKLOG)?;
+        Ok(std::net::TcpListener::from(socket))
+    })()
+    .map_err(|e| bind_error(addr, e))?;
+    TcpListener::from_std(listener)
+}
+
+/// Bind a UDP socket to `addr` (dual-stack for `::`)
+pub fn bind_udp_socket(addr: SocketAddr) -> io::Result<std::net::UdpSocket> {
+    let socket = new_socket(addr, Type::DGRAM, Protocol::UDP).map_err(|e| bind_error(addr, e))?;
+    socket.bind(&addr.into()).map_err(|e| bind_error(addr, e))?;
+    Ok(socket.into())
+}
+
+/// Check that `server` can listen on each of `addrs` by binding and
+/// releasing them, so a taken port fails startup instead of only being
+/// logged by the server task
+pub fn check_bind(server: &str, addrs: &[SocketAddr], udp: bool) -> DnsProxyResult<()> {
+    for addr in addrs {
+        let result = if udp {
+            bind_udp_socket(*addr).map(drop)
+        } else {
+            (|| {
+                let socket = new_socket(*addr, Type::STREAM, Protocol::TCP)?;
+                #[cfg(not(windows))]
+                socket.set_reuse_address(true)?;
+                socket.bind(&(*addr).into())
+            })()
+            .map_err(|e| bind_error(*addr, e))
+        };
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                return Err(DnsProxyError::AddrInUse {
+                    server: server.to_string(),
+                    addr: *addr,
+                });
+            }
+            Err(e) => return Err(e.into()),
+            Ok(()) => {}
+        }
+    }
+    Ok(())
+}
+
+/// TCP listeners on every address of a server, accepted from as one
+pub struct TcpListeners {
+    listeners: Vec<TcpListener>,
+}
+
+impl TcpListeners {
+    /// Bind a listener to each of `addrs`
+    pub fn bind(addrs: &[SocketAddr]) -> io::Result<Self> {
+        let listeners = addrs
+            .iter()
+            .map(|addr| bind_tcp_listener(*addr))
+            .collect::<io::Result<_>>()?;
+        Ok(Self { listeners })
+    }
+
+    /// Addresses the listeners are bound to
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+
+    /// Accept a connection from whichever listener has one first
+    ///
+    /// Cancel safe, like `TcpListener::accept`.
+    pub async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let accepts = self
+            .listeners
+            .iter()
+            .map(|listener| Box::pin(listener.accept()));
+        select_all(accepts).await.0
+    }
+}
+
+/// UDP sockets on every address of a server, received from as one
+pub struct UdpSockets {
+    sockets: Vec<Arc<UdpSocket>>,
+}
+
+impl UdpSockets {
+    /// Bind a socket to each of `addrs`
+    pub fn bind(addrs: &[SocketAddr]) -> io::Result<Self> {
+        let sockets = addrs
+            .iter()
+            .map(|addr| {
+                let socket = bind_udp_socket(*addr)?;
+                Ok(Arc::new(UdpSocket::from_std(socket)?))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { sockets })
+    }
+
+    /// Addresses the sockets are bound to
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets
+            .iter()
+            .filter_map(|socket| socket.local_addr().ok())
+            .collect()
+    }
+
+    /// Receive a datagram from whichever socket has one first, returning the
+    /// socket to answer it on
+    ///
+    /// Cancel safe, like `UdpSocket::recv_from`.
+    pub async fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Arc<UdpSocket>)> {
+        loop {
+            let readable = self
+                .sockets
+                .iter()
+                .map(|socket| Box::pin(socket.readable()));
+            let (ready, index, _) = select_all(readable).await;
+            ready?;
+            let socket = &self.sockets[index];
+            match socket.try_recv_from(buf) {
+                Ok((len, peer)) => return Ok((len, peer, Arc::clone(socket))),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl From<UdpSocket> for UdpSockets {
+    fn from(socket: UdpSocket) -> Self {
+        Self {
+            sockets: vec![Arc::new(socket)],
         }
     }
 }
@@ -1,9 +1,18 @@
+use crate::error::SniRewriteError;
+
 /// Trait for rewriting SNI (Server Name Indication) values
 ///
 /// Implementations of this trait extract information from the SNI
 /// and rewrite it to a target hostname for upstream forwarding.
+///
+/// This is the extension point for custom routing: an embedder implements it
+/// (e.g. with a database lookup) and hands it to [`crate::app::App::with_rewriter`].
+/// The built-in implementations live in [`crate::rewriters`].
+///
+/// Implementations provide [`rewrite_with_reason`](Self::rewrite_with_reason);
+/// [`rewrite`](Self::rewrite) defaults to it with the reason dropped.
 #[async_trait::async_trait]
-pub trait SniRewriter {
+pub trait SniRewriter: Send + Sync {
     /// Rewrite the given SNI to a target hostname
     ///
     /// # Arguments
@@ -13,8 +22,31 @@ pub trait SniRewriter {
     /// # Returns
     ///
     /// Returns `Some(RewriteResult)` if the SNI was successfully rewritten,
-    /// or `None` if the SNI doesn't match any configured pattern.
-    async fn rewrite(&self, sni: &str) -> Option<RewriteResult>;
+    /// or `None` if the SNI doesn't match any configured pattern. The
+    /// default implementation is [`rewrite_with_reason`](Self::rewrite_with_reason)
+    /// with the reason dropped.
+    async fn rewrite(&self, sni: &str) -> Option<RewriteResult> {
+        self.rewrite_with_reason(sni).await.ok()
+    }
+
+    /// Rewrite the given SNI to a target hostname, or say why it can't be
+    ///
+    /// Readers answer with an error picked by the [`SniRewriteError`] and
+    /// count the failure under it; report a hostname nothing matches as
+    /// [`SniRewriteError::NoMatchingRule`].
+    async fn rewrite_with_reason(&self, sni: &str) -> Result<RewriteResult, SniRewriteError>;
+
+    /// Rewrite a batch of SNIs, e.g. to pre-warm or validate mappings
+    ///
+    /// Returns one result per name, in the order of `names`. The default
+    /// implementation calls [`SniRewriter::rewrite`] for each name.
+    async fn rewrite_many(&self, names: &[&str]) -> Vec<Option<RewriteResult>> {
+        let mut results = Vec::with_capacity(names.len());
+        for name in names {
+            results.push(self.rewrite(name).await);
+        }
+        results
+    }
 }
 
 /// Result of an SNI rewrite operation
@@ -26,4 +58,17 @@ pub struct RewriteResult {
     pub prefix: String,
     /// The target hostname to forward to (e.g., "www.example.cn")
     pub target_hostname: String,
+    /// Whether no rule matched and the passthrough strategy kept the original
+    /// hostname as the target
+    pub passthrough: bool,
+    /// Whether the passthrough was made by the passthrough_warn strategy and
+    /// should be counted per SNI
+    pub warn: bool,
+}
+
+impl RewriteResult {
+    /// Key a rewriter caches this result under: the SNI it was rewritten from
+    pub fn cache_key(&self) -> &str {
+        &self.original
+    }
 }
@@ -0,0 +1,89 @@
+//! Test utilities, enabled by the `test-util` feature
+//!
+//! [`MockUpstream`] answers queries from scripted responses, so integration
+//! tests can drive the full ingress path without any upstream servers.
+
+use crate::app::App;
+use crate::dns;
+use crate::error::{DnsProxyError, DnsProxyResult, UpstreamError};
+use crate::upstream::default_upstream::DnsUpstream;
+use bytes::Bytes;
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Upstream that answers from scripted responses
+///
+/// Responses are keyed by queried name and type, and the query's ID is copied
+/// into the response. Unscripted queries fail like an unreachable upstream.
+#[derive(Default)]
+pub struct MockUpstream {
+    responses: DashMap<(String, u16), Bytes>,
+    queries: AtomicUsize,
+    delay: Duration,
+}
+
+impl MockUpstream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Script the response to queries for `qname` with type `qtype`
+    pub fn with_response(self, qname: &str, qtype: u16, response: impl Into<Bytes>) -> Self {
+        let qname = qname.trim_end_matches('.').to_ascii_lowercase();
+        self.responses.insert((qname, qtype), response.into());
+        self
+    }
+
+    /// Wait `delay` before answering each query, simulating a slow upstream
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Number of queries forwarded to this upstream so far
+    pub fn query_count(&self) -> usize {
+        self.queries.load(Ordering::Relaxed)
+    }
+}
+
+#[async_trait::async_trait]
+impl DnsUpstream for MockUpstream {
+    async fn forward(&self, query: &[u8]) -> DnsProxyResult<Bytes> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+        let question = dns::parse_question(query)?;
+
+        let scripted = self
+            .responses
+            .get(&(question.qname.clone(), question.qtype))
+            .map(|response| response.clone())
+            .ok_or_else(|| {
+                DnsProxyError::Upstream(UpstreamError::RequestFailed {
+                    upstream: "mock".to_string(),
+                    reason: format!(
+                        "No scripted response for {} (type {})",
+                        question.qname, question.qtype
+                    ),
+                })
+            })?;
+
+        let mut response = scripted.to_vec();
+        if response.len() >= 2 {
+            response[..2].copy_from_slice(&query[..2]);
+        }
+        Ok(Bytes::from(response))
+    }
+}
+
+impl App {
+    /// Send the plain UDP/TCP listeners' unrouted queries to `upstream`
+    /// instead of the configured default upstream
+    pub fn with_upstream(mut self, upstream: Arc<dyn DnsUpstream>) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+}
@@ -1,15 +1,58 @@
-use crate::config::{AppConfig, CertificateConfig};
+use crate::config::{AppConfig, CertificateConfig, TlsConfig};
 use crate::error::{CertificateError, DnsProxyError, DnsProxyResult};
+use crate::ocsp;
+use crate::private_key;
 use dashmap::DashMap;
-use rustls::server::{ClientHello, ResolvesServerCert, ServerConfig as RustlsServerConfig};
+use rustls::RootCertStore;
+use rustls::crypto::GetRandomFailed;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, UnixTime};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{
+    ClientHello, NoServerSessionStorage, ProducesTickets, ResolvesServerCert,
+    ServerConfig as RustlsServerConfig, WebPkiClientVerifier,
+};
 use rustls::sign::CertifiedKey;
+use rustls::ticketer::TicketRotator;
 use std::io::BufReader;
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
 use tokio::fs;
+use tokio::task::JoinHandle;
 
+/// Modification times of a certificate's cert, key and OCSP files
+type FileTimes = (Option<SystemTime>, Option<SystemTime>, Option<SystemTime>);
+
+/// A loaded certificate and the file modification times it was loaded at
+pub struct CachedCert {
+    pub cert: Arc<CertifiedKey>,
+    /// `None` once the entry is invalidated, so the next lookup reloads it
+    loaded_at: Option<FileTimes>,
+}
+
+/// Cache key of the `tls.default` certificate
+const DEFAULT_CERT_KEY: &str = "*";
+
+/// Client certificate policy of a certificate that sets `ca_file`
+struct ClientAuth {
+    roots: Arc<RootCertStore>,
+    verifier: Arc<dyn ClientCertVerifier>,
+    required: bool,
+}
+
+/// Loads and caches certificates per configured domain
+///
+/// Every SNI without a `tls.certs` entry shares the `tls.default`
+/// certificate, or with `tls.generate_self_signed` a generated one. A
+/// cached certificate is reloaded when its cert, key or OCSP file's
+/// modification time changes. When reloading fails the previously loaded
+/// certificate keeps being served.
+///
+/// Certificates that set `ca_file` also verify client certificates against
+/// it, and reject clients without one when `require_client_cert` is set.
 pub struct CertificateResolver {
     config: AppConfig,
-    pub cert_cache: Arc<DashMap<String, Arc<CertifiedKey>>>,
+    pub cert_cache: Arc<DashMap<String, CachedCert>>,
+    client_auth: DashMap<String, ClientAuth>,
 }
 
 impl CertificateResolver {
@@ -17,25 +60,25 @@ impl CertificateResolver {
         Self {
             config,
             cert_cache: Arc::new(DashMap::new()),
+            client_auth: DashMap::new(),
         }
     }
 
     pub async fn load_certificate(
         cert_config: &CertificateConfig,
     ) -> DnsProxyResult<Arc<CertifiedKey>> {
-        let cert_bytes = fs::read(&cert_config.cert_file).await.map_err(|e| {
-            DnsProxyError::Certificate(CertificateError::LoadFailed {
-                path: cert_config.cert_file.clone(),
-                reason: format!("Failed to read: {}", e),
-            })
-        })?;
+        let cert_bytes = fs::read(&cert_config.cert_file)
+            .await
+            .map_err(|e| read_error(&cert_config.cert_file, e))?;
 
-        let key_bytes = fs::read(&cert_config.key_file).await.map_err(|e| {
-            DnsProxyError::Certificate(CertificateError::LoadFailed {
-                path: cert_config.key_file.clone(),
-                reason: format!("Failed to read: {}", e),
-            })
-        })?;
+        let key_bytes = fs::read(&cert_config.key_file)
+            .await
+            .map_err(|e| read_error(&cert_config.key_file, e))?;
+
+        let ocsp = match &cert_config.ocsp_file {
+            Some(ocsp_file) => load_ocsp_response(ocsp_file).await,
+            None => None,
+        };
 
         let mut cert_reader = BufReader::new(cert_bytes.as_slice());
         let certs_iter = rustls_pemfile::certs(&mut cert_reader);
@@ -55,23 +98,18 @@ impl CertificateResolver {
             ));
         }
 
-        let mut key_reader = BufReader::new(key_bytes.as_slice());
-        let mut keys_iter = rustls_pemfile::pkcs8_private_keys(&mut key_reader);
-
-        let key_bytes = keys_iter
-            .next()
-            .ok_or_else(|| {
-                DnsProxyError::Certificate(CertificateError::PrivateKey {
-                    reason: "No private key found in key file".to_string(),
-                })
-            })?
-            .map_err(|e| {
+        let passphrase = cert_config.resolved_key_passphrase().map_err(|e| {
+            DnsProxyError::Certificate(CertificateError::PrivateKey {
+                reason: format!("{:#}", e),
+            })
+        })?;
+        let key = private_key::parse_private_key(&key_bytes, passphrase.as_deref()).map_err(
+            |reason| {
                 DnsProxyError::Certificate(CertificateError::PrivateKey {
-                    reason: format!("Failed to parse private key: {}", e),
+                    reason: format!("{}: {}", cert_config.key_file, reason),
                 })
-            })?;
-
-        let key = rustls::pki_types::PrivateKeyDer::from(key_bytes);
+            },
+        )?;
         let signing_key =
             rustls::crypto::aws_lc_rs::sign::any_supported_type(&key).map_err(|e| {
                 DnsProxyError::Certificate(CertificateError::PrivateKey {
@@ -79,18 +117,242 @@ impl CertificateResolver {
                 })
             })?;
 
-        let certified_key = CertifiedKey::new(certs, signing_key);
+        let mut certified_key = CertifiedKey::new(certs, signing_key);
 
+        // Catch a renewal caught half-written (new cert, old key)
+        if let Err(rustls::Error::InconsistentKeys(e)) = certified_key.keys_match() {
+            return Err(DnsProxyError::Certificate(CertificateError::PrivateKey {
+                reason: format!("Private key does not match certificate: {:?}", e),
+            }));
+        }
+
+        certified_key.ocsp = ocsp;
         Ok(Arc::new(certified_key))
     }
 
-    pub async fn get_cert_for_domain(&self, domain: &str) -> DnsProxyResult<Arc<CertifiedKey>> {
-        // Check cache first (fast path, lock-free with DashMap)
-        if let Some(cert) = self.cert_cache.get(domain) {
-            return Ok(Arc::clone(cert.value()));
+    /// Load a `ca_file` of trusted client certificate issuers
+    pub async fn load_client_roots(ca_file: &str) -> DnsProxyResult<RootCertStore> {
+        let ca_bytes = fs::read(ca_file)
+            .await
+            .map_err(|e| read_error(ca_file, e))?;
+
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_pemfile::certs(&mut BufReader::new(ca_bytes.as_slice())) {
+            let cert = cert.map_err(|e| {
+                DnsProxyError::Certificate(CertificateError::InvalidFormat {
+                    reason: format!("Failed to parse CA certificate: {}", e),
+                })
+            })?;
+            roots.add(cert).map_err(|e| {
+                DnsProxyError::Certificate(CertificateError::InvalidFormat {
+                    reason: format!("Invalid CA certificate in {}: {}", ca_file, e),
+                })
+            })?;
+        }
+
+        if roots.is_empty() {
+            return Err(DnsProxyError::Certificate(
+                CertificateError::InvalidFormat {
+                    reason: format!("No CA certificates found in {}", ca_file),
+                },
+            ));
+        }
+        Ok(roots)
+    }
+
+    /// Load every configured certificate into the cache, along with the
+    /// client CAs of those that set `ca_file`
+    ///
+    /// TLS handshakes only read the cache, so this must run before the
+    /// server accepts connections.
+    pub async fn preload(&self) -> DnsProxyResult<()> {
+        if self.serves_generated_cert() && !self.cert_cache.contains_key(DEFAULT_CERT_KEY) {
+            let cert = generate_self_signed(&self.config)?;
+            self.cert_cache.insert(
+                DEFAULT_CERT_KEY.to_string(),
+                CachedCert {
+                    cert,
+                    loaded_at: None,
+                },
+            );
+        }
+
+        let configured = self
+            .config
+            .tls
+            .certs
+            .iter()
+            .map(|(domain, cert_config)| (domain.as_str(), cert_config))
+            .chain(
+                self.config
+                    .tls
+                    .default
+                    .as_ref()
+                    .map(|cert_config| (DEFAULT_CERT_KEY, cert_config)),
+            );
+        for (key, cert_config) in configured {
+            self.get_cert_for_domain(key).await?;
+
+            let Some(ca_file) = &cert_config.ca_file else {
+                if cert_config.require_client_cert {
+                    return Err(DnsProxyError::Config(format!(
+                        "require_client_cert is set for {} but no ca_file is configured",
+                        key
+                    )));
+                }
+                continue;
+            };
+            let roots = Arc::new(Self::load_client_roots(ca_file).await?);
+            let verifier = WebPkiClientVerifier::builder(Arc::clone(&roots))
+                .allow_unauthenticated()
+                .build()
+                .map_err(|e| {
+                    DnsProxyError::Certificate(CertificateError::InvalidFormat {
+                        reason: format!("Invalid client CA {}: {}", ca_file, e),
+                    })
+                })?;
+            self.client_auth.insert(
+                key.to_string(),
+                ClientAuth {
+                    roots,
+                    verifier,
+                    required: cert_config.require_client_cert,
+                },
+            );
         }
+        Ok(())
+    }
+
+    /// Client certificate verifier for a server config covering every
+    /// configured certificate, or `None` when none of them sets `ca_file`
+    ///
+    /// rustls runs it before the SNI is known, so it trusts the CAs of all
+    /// certificates and only insists on a client certificate when every
+    /// certificate requires one. [`verify_client`](Self::verify_client)
+    /// then applies the CA and requirement of the certificate served.
+    pub fn client_cert_verifier(&self) -> DnsProxyResult<Option<Arc<dyn ClientCertVerifier>>> {
+        if self.client_auth.is_empty() {
+            return Ok(None);
+        }
+
+        let mut roots = RootCertStore::empty();
+        for auth in self.client_auth.iter() {
+            roots.extend(auth.roots.roots.iter().cloned());
+        }
+        let tls = &self.config.tls;
+        let all_required = tls
+            .certs
+            .values()
+            .chain(tls.default.as_ref())
+            .all(|cert_config| cert_config.require_client_cert)
+            && !self.serves_generated_cert();
 
-        // Load certificate from configuration
+        let builder = WebPkiClientVerifier::builder(Arc::new(roots));
+        let builder = if all_required {
+            builder
+        } else {
+            builder.allow_unauthenticated()
+        };
+        let verifier = builder.build().map_err(|e| {
+            DnsProxyError::Certificate(CertificateError::InvalidFormat {
+                reason: format!("Invalid client CAs: {}", e),
+            })
+        })?;
+        Ok(Some(verifier))
+    }
+
+    /// Check a completed handshake's client certificate against the CA and
+    /// `require_client_cert` of the certificate served for `server_name`
+    pub fn verify_client(
+        &self,
+        server_name: Option<&str>,
+        client_certs: Option<&[CertificateDer<'static>]>,
+    ) -> DnsProxyResult<()> {
+        let Some(key) = self.cache_key(server_name.unwrap_or(DEFAULT_CERT_KEY)) else {
+            return Ok(());
+        };
+        let Some(auth) = self.client_auth.get(key) else {
+            return Ok(());
+        };
+
+        match client_certs.and_then(|certs| certs.split_first()) {
+            Some((end_entity, intermediates)) => auth
+                .verifier
+                .verify_client_cert(end_entity, intermediates, UnixTime::now())
+                .map(|_| ())
+                .map_err(|e| {
+                    DnsProxyError::Certificate(CertificateError::ClientCertRejected {
+                        reason: e.to_string(),
+                    })
+                }),
+            None if auth.required => Err(DnsProxyError::Certificate(
+                CertificateError::ClientCertRejected {
+                    reason: "No client certificate presented".to_string(),
+                },
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a request for `host` may be served on a connection whose
+    /// handshake asked for `server_name`
+    ///
+    /// Client certificates are checked against the SNI, so a Host served by
+    /// another certificate entry is refused when either entry has client
+    /// authentication; otherwise a request could reach a domain requiring a
+    /// client certificate over a connection made for one that doesn't.
+    pub fn allows_host(&self, server_name: Option<&str>, host: &str) -> bool {
+        let host = host.to_ascii_lowercase();
+        let connection_key = self.cache_key(server_name.unwrap_or(DEFAULT_CERT_KEY));
+        let host_key = self.cache_key(&host);
+        if connection_key == host_key {
+            return true;
+        }
+        let has_client_auth =
+            |key: Option<&str>| key.is_some_and(|key| self.client_auth.contains_key(key));
+        !has_client_auth(connection_key) && !has_client_auth(host_key)
+    }
+
+    /// Cache entry serving `domain`: its own `tls.certs` entry, or the default
+    fn cache_key<'a>(&self, domain: &'a str) -> Option<&'a str> {
+        if self.config.tls.certs.contains_key(domain) {
+            Some(domain)
+        } else if self.config.tls.default.is_some() || self.serves_generated_cert() {
+            Some(DEFAULT_CERT_KEY)
+        } else {
+            None
+        }
+    }
+
+    /// Whether a generated self-signed certificate stands in for the
+    /// missing `tls.default`
+    fn serves_generated_cert(&self) -> bool {
+        self.config.tls.generate_self_signed && self.config.tls.default.is_none()
+    }
+
+    /// Certificate for `domain` if it is already loaded, without touching
+    /// the filesystem
+    pub fn cached_cert(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+        let key = self.cache_key(domain)?;
+        self.cert_cache
+            .get(key)
+            .map(|entry| Arc::clone(&entry.cert))
+    }
+
+    pub async fn get_cert_for_domain(&self, domain: &str) -> DnsProxyResult<Arc<CertifiedKey>> {
+        let key = self.cache_key(domain).ok_or_else(|| {
+            DnsProxyError::Certificate(CertificateError::NotConfigured {
+                domain: domain.to_string(),
+            })
+        })?;
+        // The generated certificate has no files to reload it from
+        if key == DEFAULT_CERT_KEY && self.serves_generated_cert() {
+            return self.cached_cert(key).ok_or_else(|| {
+                DnsProxyError::Certificate(CertificateError::NotConfigured {
+                    domain: domain.to_string(),
+                })
+            });
+        }
         let cert_config = self
             .config
             .tls
@@ -101,19 +363,206 @@ impl CertificateResolver {
                 })
             })?;
 
-        let cert = Self::load_certificate(cert_config).await.map_err(|e| {
-            DnsProxyError::Certificate(CertificateError::LoadFailed {
-                path: cert_config.cert_file.clone(),
-                reason: format!("Failed to load for domain {}: {}", domain, e),
-            })
-        })?;
+        // Serve the cached certificate while its files are unchanged
+        let file_times = file_times(cert_config).await;
+        let cached = self.cert_cache.get(key).map(|entry| {
+            let current = entry.loaded_at == Some(file_times);
+            (Arc::clone(&entry.cert), current)
+        });
+        if let Some((cert, true)) = cached {
+            return Ok(cert);
+        }
 
-        // Update cache (lock-free)
-        self.cert_cache
-            .insert(domain.to_string(), Arc::clone(&cert));
+        match Self::load_certificate(cert_config).await {
+            Ok(cert) => {
+                if cached.is_some() {
+                    tracing::info!(
+                        "Reloaded certificate for {} from {}",
+                        domain,
+                        cert_config.cert_file
+                    );
+                }
+                self.cert_cache.insert(
+                    key.to_string(),
+                    CachedCert {
+                        cert: Arc::clone(&cert),
+                        loaded_at: Some(file_times),
+                    },
+                );
+                Ok(cert)
+            }
+            Err(e) => match cached {
+                Some((cert, _)) => {
+                    tracing::warn!(
+                        "Failed to reload certificate for {}, keeping the cached one: {}",
+                        domain,
+                        e
+                    );
+                    Ok(cert)
+                }
+                None => Err(DnsProxyError::Certificate(CertificateError::LoadFailed {
+                    path: cert_config.cert_file.clone(),
+                    reason: format!("Failed to load for domain {}: {}", domain, e),
+                })),
+            },
+        }
+    }
 
-        Ok(cert)
+    /// Reload `domain`'s certificate on its next lookup even if its files
+    /// look unchanged
+    pub fn invalidate(&self, domain: &str) {
+        let Some(key) = self.cache_key(domain) else {
+            return;
+        };
+        if let Some(mut entry) = self.cert_cache.get_mut(key) {
+            entry.loaded_at = None;
+        }
     }
+
+    /// Reload every cached certificate from disk, whether or not its files'
+    /// modification times changed
+    pub async fn refresh(&self) {
+        let domains: Vec<String> = self
+            .cert_cache
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        for domain in domains {
+            self.invalidate(&domain);
+            if let Err(e) = self.get_cert_for_domain(&domain).await {
+                tracing::warn!("Failed to refresh certificate for {}: {}", domain, e);
+            }
+        }
+    }
+
+    /// Call [`refresh`](Self::refresh) every `interval` until the resolver
+    /// is dropped
+    pub fn spawn_refresher(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let resolver: Weak<Self> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(resolver) = resolver.upgrade() else {
+                    break;
+                };
+                resolver.refresh().await;
+            }
+        })
+    }
+}
+
+/// Load every configured certificate and client CA once, so a missing or
+/// unparsable file fails startup instead of the first handshakes
+pub async fn check_certificates(tls: &TlsConfig) -> DnsProxyResult<()> {
+    for cert_config in tls.certs.values().chain(tls.default.as_ref()) {
+        CertificateResolver::load_certificate(cert_config).await?;
+        if let Some(ca_file) = &cert_config.ca_file {
+            CertificateResolver::load_client_roots(ca_file).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Error for a certificate, key or CA file that could not be read
+fn read_error(path: &str, e: std::io::Error) -> DnsProxyError {
+    let error = match e.kind() {
+        std::io::ErrorKind::NotFound => CertificateError::FileNotFound {
+            path: path.to_string(),
+        },
+        _ => CertificateError::LoadFailed {
+            path: path.to_string(),
+            reason: format!("Failed to read: {}", e),
+        },
+    };
+    DnsProxyError::Certificate(error)
+}
+
+/// In-memory self-signed certificate for `tls.generate_self_signed`,
+/// covering localhost and every rewrite base domain with its subdomains
+fn generate_self_signed(config: &AppConfig) -> DnsProxyResult<Arc<CertifiedKey>> {
+    let mut names = vec![
+        "localhost".to_string(),
+        "127.0.0.1".to_string(),
+        "::1".to_string(),
+    ];
+    for (domain, _) in config.rewrite.base_domain_targets() {
+        names.push(domain.to_string());
+        names.push(format!("*.{}", domain));
+    }
+    let generation_failed = |reason: String| {
+        DnsProxyError::Certificate(CertificateError::InvalidFormat {
+            reason: format!("Failed to generate self-signed certificate: {}", reason),
+        })
+    };
+    let certified = rcgen::generate_simple_self_signed(names.clone())
+        .map_err(|e| generation_failed(e.to_string()))?;
+    let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(
+        certified.signing_key.serialize_der(),
+    ));
+    let signing_key = rustls::crypto::aws_lc_rs::sign::any_supported_type(&key)
+        .map_err(|e| generation_failed(e.to_string()))?;
+
+    tracing::warn!(
+        "tls.generate_self_signed is set: serving a generated self-signed certificate for {}. \
+         Clients will not trust it; configure tls.default outside local testing",
+        names.join(", ")
+    );
+    Ok(Arc::new(CertifiedKey::new(
+        vec![certified.cert.der().clone()],
+        signing_key,
+    )))
+}
+
+async fn file_times(cert_config: &CertificateConfig) -> FileTimes {
+    let ocsp = match &cert_config.ocsp_file {
+        Some(ocsp_file) => modified(ocsp_file).await,
+        None => None,
+    };
+    (
+        modified(&cert_config.cert_file).await,
+        modified(&cert_config.key_file).await,
+        ocsp,
+    )
+}
+
+/// OCSP response to staple from `ocsp_file`
+///
+/// A response that can't be read or is not a current `good` answer is
+/// dropped with a warning, and the certificate is served without a staple:
+/// clients then check revocation themselves, whereas a stale staple would
+/// make strict ones reject the connection.
+async fn load_ocsp_response(ocsp_file: &str) -> Option<Vec<u8>> {
+    let response = match fs::read(ocsp_file).await {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::warn!(
+                "Not stapling OCSP response {}: failed to read: {}",
+                ocsp_file,
+                e
+            );
+            return None;
+        }
+    };
+    match ocsp::check_response(&response, SystemTime::now()) {
+        Ok(validity) => {
+            tracing::debug!(
+                "Stapling OCSP response {}, valid until {:?}",
+                ocsp_file,
+                validity.next_update
+            );
+            Some(response)
+        }
+        Err(e) => {
+            tracing::warn!("Not stapling OCSP response {}: {}", ocsp_file, e);
+            None
+        }
+    }
+}
+
+async fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).await.ok()?.modified().ok()
 }
 
 pub struct DynamicCertResolver {
@@ -133,47 +582,117 @@ impl std::fmt::Debug for DynamicCertResolver {
 }
 
 impl ResolvesServerCert for DynamicCertResolver {
+    /// Pick the preloaded certificate for the client's SNI
+    ///
+    /// This runs inside the handshake, possibly off any Tokio runtime, so it
+    /// only reads the cache. A client without SNI or with an unconfigured one
+    /// gets the default certificate. When called on a runtime, a background
+    /// task checks the certificate's files so a renewal is served from the
+    /// next handshake on.
     fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
-        let sni = match client_hello.server_name() {
-            Some(sni) => sni,
-            None => {
-                tracing::warn!("TLS handshake without SNI, cannot select certificate");
-                return None;
-            }
-        };
+        let sni = client_hello.server_name().unwrap_or(DEFAULT_CERT_KEY);
+        tracing::debug!("Resolving certificate for SNI: {}", sni);
 
-        let resolver = self.resolver.clone();
-        let sni_str = sni.to_string();
-
-        tracing::debug!("Resolving certificate for SNI: {}", sni_str);
+        let cert = self.resolver.cached_cert(sni);
+        if cert.is_none() {
+            tracing::warn!("No certificate loaded for SNI {}", sni);
+        }
 
-        let rt = tokio::runtime::Handle::try_current();
-        if let Ok(handle) = rt {
-            match handle.block_on(resolver.get_cert_for_domain(&sni_str)) {
-                Ok(cert) => {
-                    tracing::debug!("Successfully loaded certificate for SNI: {}", sni_str);
-                    Some(cert)
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let resolver = Arc::clone(&self.resolver);
+            let sni = sni.to_string();
+            handle.spawn(async move {
+                if let Err(e) = resolver.get_cert_for_domain(&sni).await {
+                    tracing::debug!("Certificate check for SNI {} failed: {}", sni, e);
                 }
-                Err(e) => {
-                    tracing::error!("Failed to load certificate for SNI {}: {}", sni_str, e);
-                    None
-                }
-            }
-        } else {
-            tracing::error!(
-                "No tokio runtime available for certificate loading (SNI: {})",
-                sni_str
-            );
-            None
+            });
         }
+
+        cert
     }
 }
 
-pub async fn create_server_config(config: &AppConfig) -> DnsProxyResult<RustlsServerConfig> {
+/// Build the TLS server config of the DoT, DoQ or DoH3 server, negotiating
+/// the ALPN protocols in `alpn` (e.g. `config.tls.alpn.dot`)
+///
+/// The returned resolver must check each completed handshake with
+/// [`CertificateResolver::verify_client`].
+pub async fn create_server_config(
+    config: &AppConfig,
+    alpn: &[String],
+) -> DnsProxyResult<(RustlsServerConfig, Arc<CertificateResolver>)> {
     let resolver = Arc::new(CertificateResolver::new(config.clone()));
-    let cert_resolver = Arc::new(DynamicCertResolver::new(resolver));
+    resolver.preload().await?;
+    if config.tls.reload_interval_secs > 0 {
+        let interval = Duration::from_secs(config.tls.reload_interval_secs);
+        let _refresher = resolver.spawn_refresher(interval);
+    }
+    let cert_resolver = Arc::new(DynamicCertResolver::new(Arc::clone(&resolver)));
+
+    let builder = RustlsServerConfig::builder();
+    let mut server_config = match resolver.client_cert_verifier()? {
+        Some(verifier) => builder
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(cert_resolver),
+        None => builder
+            .with_no_client_auth()
+            .with_cert_resolver(cert_resolver),
+    };
+    server_config.alpn_protocols = alpn
+        .iter()
+        .map(|protocol| protocol.clone().into_bytes())
+        .collect();
+    configure_resumption(&mut server_config, &config.tls)?;
+    Ok((server_config, resolver))
+}
 
-    Ok(RustlsServerConfig::builder()
-        .with_no_client_auth()
-        .with_cert_resolver(cert_resolver))
+/// Enable TLS 1.3 session tickets (and TLS 1.2 session IDs) or disable
+/// resumption entirely, per `tls.session_resumption`
+///
+/// Ticket keys are replaced every half `session_ticket_lifetime_secs`, so a
+/// ticket is accepted for at most that long. A resumed session skips the
+/// certificate resolver; rustls only resumes it for the same SNI.
+fn configure_resumption(
+    server_config: &mut RustlsServerConfig,
+    tls: &TlsConfig,
+) -> DnsProxyResult<()> {
+    if !tls.session_resumption {
+        server_config.session_storage = Arc::new(NoServerSessionStorage {});
+        server_config.send_tls13_tickets = 0;
+        return Ok(());
+    }
+
+    let rotation = (tls.session_ticket_lifetime_secs / 2).max(1);
+    let ticketer = TicketRotator::new(rotation, ticket_generator)
+        .map_err(|e| DnsProxyError::Tls(format!("Failed to create session ticketer: {}", e)))?;
+    server_config.ticketer = Arc::new(ticketer);
+    Ok(())
+}
+
+/// Fresh ticket keys for each rotation of the session ticketer
+fn ticket_generator() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+    let ticketer = rustls::crypto::aws_lc_rs::Ticketer::new().map_err(|_| GetRandomFailed)?;
+    Ok(Box::new(SharedTicketer(ticketer)))
+}
+
+/// Boxes the `Arc` that rustls hands out ticketers in
+#[derive(Debug)]
+struct SharedTicketer(Arc<dyn ProducesTickets>);
+
+impl ProducesTickets for SharedTicketer {
+    fn enabled(&self) -> bool {
+        self.0.enabled()
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.0.lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.0.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.0.decrypt(cipher)
+    }
 }
@@ -0,0 +1,205 @@
+//! Per-upstream circuit breaker
+//!
+//! Forwarding to an upstream that keeps failing only makes clients wait for
+//! the same error. Each `(upstream, target)` pair has a circuit that opens
+//! after `failure_threshold` failures in a row within `failure_window_secs`.
+//! While open, requests to it fail at once with
+//! [`UpstreamError::CircuitOpen`]. Once `cooldown_secs` have passed the
+//! circuit is half-open: a single request is let through to probe the
+//! upstream, closing the circuit when it succeeds and opening it for another
+//! cool-down when it fails.
+
+use crate::config::CircuitBreakerConfig;
+use crate::error::{DnsProxyError, DnsProxyResult, UpstreamError};
+use crate::metrics::Metrics;
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// State of one upstream's circuit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Circuit {
+    /// Forwarding; `failures` in a row so far, the first at `since`
+    Closed { failures: u32, since: Instant },
+    /// Failing requests at once until the cool-down ends
+    Open { until: Instant },
+    /// One request probing the upstream since `since`
+    HalfOpen { since: Instant },
+}
+
+/// Circuits of the upstreams a connection pool forwards to
+pub struct CircuitBreaker {
+    /// Circuits keyed by upstream and target; closed circuits without
+    /// failures are not stored
+    circuits: DashMap<(String, String), Circuit>,
+    /// Failures in a row that open a circuit (0 = never open)
+    failure_threshold: u32,
+    failure_window: Duration,
+    cooldown: Duration,
+}
+
+impl CircuitBreaker {
+    /// A breaker whose circuits never open
+    pub fn disabled() -> Self {
+        Self::from_config(&CircuitBreakerConfig::default())
+    }
+
+    /// Create a breaker from the `[upstream.circuit_breaker]` config section
+    pub fn from_config(config: &CircuitBreakerConfig) -> Self {
+        Self {
+            circuits: DashMap::new(),
+            failure_threshold: config.failure_threshold,
+            failure_window: config.failure_window(),
+            cooldown: config.cooldown(),
+        }
+    }
+
+    /// Whether circuits can open at all
+    pub fn is_enabled(&self) -> bool {
+        self.failure_threshold > 0
+    }
+
+    /// Whether a request to `upstream` for `target` may be forwarded at `now`
+    ///
+    /// After the cool-down of an open circuit, the first caller is let
+    /// through as the probe and the circuit turns half-open; others keep
+    /// failing until the probe is recorded, or for another cool-down if it
+    /// never is.
+    pub fn allow(&self, upstream: &str, target: &str, now: Instant) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+        let key = (upstream.to_string(), target.to_string());
+        let Some(mut circuit) = self.circuits.get_mut(&key) else {
+            return true;
+        };
+        match *circuit {
+            Circuit::Closed { .. } => true,
+            Circuit::Open { until } if now >= until => {
+                info!(
+                    "Circuit breaker half-open for upstream {} ({}), probing",
+                    upstream, target
+                );
+                *circuit = Circuit::HalfOpen { since: now };
+                true
+            }
+            Circuit::HalfOpen { since }
+                if now.saturating_duration_since(since) >= self.cooldown =>
+            {
+                *circuit = Circuit::HalfOpen { since: now };
+                true
+            }
+            Circuit::Open { .. } | Circuit::HalfOpen { .. } => false,
+        }
+    }
+
+    /// Record the outcome of a request forwarded to `upstream` for `target`
+    /// at `now`, opening its circuit when it failed once too often
+    pub fn record(&self, upstream: &str, target: &str, succeeded: bool, now: Instant) {
+        if !self.is_enabled() {
+            return;
+        }
+        let key = (upstream.to_string(), target.to_string());
+        if succeeded {
+            if let Some((_, Circuit::HalfOpen { .. })) = self.circuits.remove(&key) {
+                info!(
+                    "Circuit breaker closed for upstream {} ({}), it recovered",
+                    upstream, target
+                );
+            }
+            return;
+        }
+
+        let mut circuit = self.circuits.entry(key).or_insert(Circuit::Closed {
+            failures: 0,
+            since: now,
+        });
+        let failures = match *circuit {
+            Circuit::Closed { failures, since }
+                if now.saturating_duration_since(since) < self.failure_window =>
+            {
+                failures + 1
+            }
+            Circuit::Closed { .. } => {
+                *circuit = Circuit::Closed {
+                    failures: 0,
+                    since: now,
+                };
+                1
+            }
+            Circuit::HalfOpen { .. } => self.failure_threshold,
+            // Requests forwarded before the circuit opened
+            Circuit::Open { .. } => return,
+        };
+        if failures >= self.failure_threshold {
+            warn!(
+                "Circuit breaker open for upstream {} ({}) after {} failure(s), not forwarding for {:?}",
+                upstream, target, failures, self.cooldown
+            );
+            *circuit = Circuit::Open {
+                until: now + self.cooldown,
+            };
+        } else if let Circuit::Closed { since, .. } = *circuit {
+            *circuit = Circuit::Closed { failures, since };
+        }
+    }
+
+    /// Start forwarding a request to `upstream` for `target`, failing with
+    /// [`UpstreamError::CircuitOpen`] when its circuit is open
+    ///
+    /// The returned attempt must be finished with the request's outcome; one
+    /// dropped before that (e.g. cut off by the upstream timeout) counts as a
+    /// failure.
+    pub fn start<'a>(&'a self, upstream: &str, target: &str) -> DnsProxyResult<Attempt<'a>> {
+        if !self.allow(upstream, target, Instant::now()) {
+            return Err(DnsProxyError::Upstream(UpstreamError::CircuitOpen {
+                upstream: upstream.to_string(),
+            }));
+        }
+        Ok(Attempt {
+            breaker: self,
+            upstream: upstream.to_string(),
+            target: target.to_string(),
+            finished: false,
+        })
+    }
+}
+
+/// A request let through by a [`CircuitBreaker`], recorded once finished
+pub struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    upstream: String,
+    target: String,
+    finished: bool,
+}
+
+impl Attempt<'_> {
+    /// Record whether the request succeeded
+    pub fn finish(mut self, succeeded: bool) {
+        self.finished = true;
+        self.breaker
+            .record(&self.upstream, &self.target, succeeded, Instant::now());
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.breaker
+                .record(&self.upstream, &self.target, false, Instant::now());
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::disabled()
+    }
+}
+
+/// Count a request refused by an open circuit in the `circuit_open` metric
+pub fn record_circuit_open(error: &DnsProxyError, metrics: &Metrics) {
+    if error.is_circuit_open() {
+        metrics.record_circuit_open();
+    }
+}
@@ -0,0 +1,250 @@
+use crate::config::AppConfig;
+use crate::dns::cache::ResponseCache;
+use crate::dns::{self, edns};
+use crate::error::{DnsProxyError, DnsProxyResult, UpstreamError};
+use crate::metrics::Metrics;
+use crate::rewrite::SniRewriterType;
+use crate::upstream::circuit_breaker::record_circuit_open;
+use crate::upstream::create_connection_pool;
+use crate::upstream::dry_run::dry_run_response;
+use crate::upstream::health::UpstreamHealth;
+use crate::upstream::ladder::forward_with_ladder;
+use crate::upstream::pool::ConnectionPool;
+use crate::upstream::retry::{is_transient_error, with_retries};
+use crate::upstream::timeout::with_timeout;
+use crate::upstream::udp::forward_udp_dns;
+use bytes::Bytes;
+use std::sync::Arc;
+use tracing::debug;
+
+/// Upstream that answers the queries of listeners without SNI
+///
+/// [`DefaultUpstream`] is the real implementation; tests can substitute a
+/// scripted one (see `testing::MockUpstream` behind the `test-util` feature).
+#[async_trait::async_trait]
+pub trait DnsUpstream: Send + Sync {
+    /// Forward one DNS query and return the upstream's response
+    async fn forward(&self, query: &[u8]) -> DnsProxyResult<Bytes>;
+}
+
+/// The configured default upstream (see [`forward_to_default_upstream`])
+pub struct DefaultUpstream {
+    config: Arc<AppConfig>,
+    pool: Arc<ConnectionPool>,
+    health: Arc<UpstreamHealth>,
+    metrics: Arc<Metrics>,
+}
+
+impl DefaultUpstream {
+    pub fn new(config: Arc<AppConfig>, metrics: Arc<Metrics>) -> Self {
+        let pool = create_connection_pool(&config.upstream);
+        let health = Arc::new(UpstreamHealth::from_config(&config));
+        Self {
+            config,
+            pool,
+            health,
+            metrics,
+        }
+    }
+
+    /// Pick upstreams using the health reported by a shared prober
+    pub fn with_health(mut self, health: Arc<UpstreamHealth>) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Forward through `pool`, sharing its connections and circuit breaker,
+    /// instead of a pool of its own
+    pub fn with_pool(mut self, pool: Arc<ConnectionPool>) -> Self {
+        self.pool = pool;
+        self
+    }
+}
+
+#[async_trait::async_trait]
+impl DnsUpstream for DefaultUpstream {
+    async fn forward(&self, query: &[u8]) -> DnsProxyResult<Bytes> {
+        forward_to_default_upstream(query, &self.config, &self.pool, &self.health, &self.metrics)
+            .await
+    }
+}
+
+/// Forward a query from a listener without SNI (plain UDP or TCP), routing
+/// it by the queried name
+///
+/// The QNAME is fed through the SNI rewriter. A match is forwarded over DoT to
+/// the rewritten target on the DoT upstream's port, on the pooled connections
+/// of `pool`; anything else (including passthrough results and unparseable
+/// questions) goes to `default_upstream`.
+/// Answers are served from and stored in `cache` when it is enabled.
+///
+/// In dry-run mode the query is answered with an empty NOERROR response
+/// instead, bypassing the cache so every query logs its route.
+pub async fn forward_by_qname(
+    query: &[u8],
+    rewriter: &SniRewriterType,
+    default_upstream: &dyn DnsUpstream,
+    pool: &ConnectionPool,
+    cache: &ResponseCache,
+    config: &AppConfig,
+    metrics: &Metrics,
+) -> DnsProxyResult<Bytes> {
+    if config.proxy.dry_run {
+        let upstream = rewritten_qname(query, rewriter, metrics).await;
+        let upstream = upstream.as_deref().unwrap_or("default upstream");
+        return Ok(dry_run_response(query, upstream, metrics));
+    }
+    cache
+        .get_or_forward(
+            query,
+            metrics,
+            route_by_qname(query, rewriter, default_upstream, pool, config, metrics),
+        )
+        .await
+}
+
+/// Route a query by its name without consulting the cache
+async fn route_by_qname(
+    query: &[u8],
+    rewriter: &SniRewriterType,
+    default_upstream: &dyn DnsUpstream,
+    pool: &ConnectionPool,
+    config: &AppConfig,
+    metrics: &Metrics,
+) -> DnsProxyResult<Bytes> {
+    let Some(target) = rewritten_qname(query, rewriter, metrics).await else {
+        return default_upstream.forward(query).await;
+    };
+
+    let port = config
+        .dot_upstream()
+        .map_err(|e| DnsProxyError::Config(e.to_string()))?
+        .port();
+    let upstream = tokio::net::lookup_host((target.as_str(), port))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| {
+            DnsProxyError::Upstream(UpstreamError::ConnectionFailed {
+                upstream: format!("{}:{}", target, port),
+                reason: format!("Failed to resolve {}", target),
+            })
+        })?;
+    debug!("Routing query to {} ({})", target, upstream);
+
+    let query = edns::rewrite_query(query, &config.edns);
+    let upstream_str = upstream.to_string();
+    with_retries(
+        config.upstream.max_retries,
+        &upstream_str,
+        metrics,
+        is_transient_error,
+        || {
+            with_timeout(
+                config.upstream.timeout(),
+                &upstream_str,
+                pool.dot()
+                    .forward(upstream, &target, &query, &config.upstream),
+            )
+        },
+    )
+    .await
+    .inspect_err(|e| record_circuit_open(e, metrics))
+    .inspect(|response| metrics.record_upstream_bytes(query.len() as u64, response.len() as u64))
+}
+
+/// Target hostname a query's name rewrites to, or `None` when it belongs to
+/// the default upstream (no match, a passthrough result or no parseable
+/// question)
+async fn rewritten_qname(
+    query: &[u8],
+    rewriter: &SniRewriterType,
+    metrics: &Metrics,
+) -> Option<String> {
+    let question = match dns::parse_question(query) {
+        Ok(question) => question,
+        Err(e) => {
+            debug!("Routing query to default upstream: {}", e);
+            return None;
+        }
+    };
+
+    match rewriter.rewrite_with_reason(&question.qname).await {
+        Ok(result) => {
+            metrics.record_rewrite(Some(&result));
+            (!result.passthrough).then_some(result.target_hostname)
+        }
+        Err(e) => {
+            metrics.record_rewrite_failure(&e);
+            debug!("Routing query to default upstream: {}", e);
+            None
+        }
+    }
+}
+
+/// Forward a query from a listener without SNI (plain UDP or TCP) to the
+/// configured default upstream
+///
+/// Uses `upstream.udp` over plain DNS when set, otherwise the protocol ladder
+/// when configured, otherwise the DoT upstream, each time picking the next
+/// healthy upstream of the protocol. The EDNS policy is applied to the query
+/// first.
+pub async fn forward_to_default_upstream(
+    query: &[u8],
+    config: &AppConfig,
+    pool: &ConnectionPool,
+    health: &UpstreamHealth,
+    metrics: &Metrics,
+) -> DnsProxyResult<Bytes> {
+    let query = edns::rewrite_query(query, &config.edns);
+
+    if !health.udp.is_empty() {
+        let upstream = health.udp.pick()?;
+        let upstream_str = upstream.to_string();
+        return with_retries(
+            config.upstream.max_retries,
+            &upstream_str,
+            metrics,
+            is_transient_error,
+            || {
+                with_timeout(
+                    config.upstream.timeout(),
+                    &upstream_str,
+                    forward_udp_dns(upstream, &query),
+                )
+            },
+        )
+        .await
+        .inspect(|response| {
+            metrics.record_upstream_bytes(query.len() as u64, response.len() as u64)
+        });
+    }
+
+    if !config.upstream.protocol_ladder.is_empty() {
+        let (response, protocol) =
+            forward_with_ladder(config, pool, health, &query, metrics).await?;
+        debug!("UDP query forwarded via {} upstream", protocol);
+        return Ok(response);
+    }
+
+    let upstream = health.dot.pick()?;
+    let upstream_str = upstream.to_string();
+    let upstream_hostname = upstream.ip().to_string();
+    with_retries(
+        config.upstream.max_retries,
+        &upstream_str,
+        metrics,
+        is_transient_error,
+        || {
+            with_timeout(
+                config.upstream.timeout(),
+                &upstream_str,
+                pool.dot()
+                    .forward(upstream, &upstream_hostname, &query, &config.upstream),
+            )
+        },
+    )
+    .await
+    .inspect_err(|e| record_circuit_open(e, metrics))
+    .inspect(|response| metrics.record_upstream_bytes(query.len() as u64, response.len() as u64))
+}
@@ -0,0 +1,186 @@
+//! Pooled TLS connections to DoT upstreams
+//!
+//! A pooled connection carries one query at a time: it is taken out of the
+//! pool for a query and put back once the response has been read, so queries
+//! to the same upstream skip the TCP and TLS handshakes. Up to
+//! `max_per_upstream` idle connections are kept per upstream, each for at most
+//! the idle timeout. A query on a pooled connection the upstream has closed
+//! in the meantime is sent again on a new connection.
+//!
+//! Queries to an upstream whose circuit is open fail without connecting (see
+//! [`CircuitBreaker`]).
+
+use crate::config::{DotPoolConfig, UpstreamConfig};
+use crate::error::DnsProxyResult;
+use crate::upstream::circuit_breaker::CircuitBreaker;
+use crate::upstream::tls::{SharedClientConfig, connect_dot_upstream_with_tls, exchange_dot_dns};
+use bytes::Bytes;
+use dashmap::DashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tracing::debug;
+
+/// An idle connection and when it was put back
+struct IdleConnection {
+    tls: TlsStream<TcpStream>,
+    idle_since: Instant,
+}
+
+/// Idle DoT connections keyed by upstream address and TLS server name
+pub struct DotConnectionPool {
+    idle: DashMap<(SocketAddr, String), Vec<IdleConnection>>,
+    idle_timeout: Duration,
+    max_per_upstream: usize,
+    circuit_breaker: Arc<CircuitBreaker>,
+    /// Verifies the upstreams of every new connection
+    tls: SharedClientConfig,
+}
+
+impl DotConnectionPool {
+    /// Create a pool with the default `[upstream.dot_pool]` settings
+    pub fn new() -> Self {
+        Self::from_config(&DotPoolConfig::default())
+    }
+
+    /// Create a pool from the `[upstream.dot_pool]` config section
+    pub fn from_config(config: &DotPoolConfig) -> Self {
+        Self {
+            idle: DashMap::new(),
+            idle_timeout: config.idle_timeout(),
+            max_per_upstream: config.max_per_upstream,
+            circuit_breaker: Arc::new(CircuitBreaker::disabled()),
+            tls: SharedClientConfig::default(),
+        }
+    }
+
+    /// Stop forwarding to failing upstreams with `circuit_breaker`
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        self.circuit_breaker = circuit_breaker;
+        self
+    }
+
+    /// Verify upstreams with `tls_config` instead of building a config from
+    /// the `UpstreamConfig` of the first connection
+    pub fn with_tls_config(mut self, tls_config: Arc<rustls::ClientConfig>) -> Self {
+        self.tls = SharedClientConfig::new(tls_config);
+        self
+    }
+
+    /// TLS config new connections verify upstreams with
+    pub fn tls_config(&self, config: &UpstreamConfig) -> DnsProxyResult<Arc<rustls::ClientConfig>> {
+        self.tls.get_or_create(config)
+    }
+
+    /// How long an idle connection is kept
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Idle connections kept per upstream
+    pub fn max_per_upstream(&self) -> usize {
+        self.max_per_upstream
+    }
+
+    /// Forward a DNS message on an idle pooled connection, or a new one when
+    /// there is none or the pooled one fails
+    pub async fn forward(
+        &self,
+        upstream: SocketAddr,
+        server_name: &str,
+        message: &[u8],
+        config: &UpstreamConfig,
+    ) -> DnsProxyResult<Bytes> {
+        let attempt = self
+            .circuit_breaker
+            .start(&upstream.to_string(), server_name)?;
+        let result = self.exchange(upstream, server_name, message, config).await;
+        attempt.finish(result.is_ok());
+        result
+    }
+
+    /// Exchange a DNS message on a pooled or new connection
+    async fn exchange(
+        &self,
+        upstream: SocketAddr,
+        server_name: &str,
+        message: &[u8],
+        config: &UpstreamConfig,
+    ) -> DnsProxyResult<Bytes> {
+        let key = (upstream, server_name.to_string());
+        if let Some(mut tls) = self.take(&key) {
+            match exchange_dot_dns(&mut tls, upstream, message).await {
+                Ok(response) => {
+                    self.put(key, tls);
+                    return Ok(response);
+                }
+                Err(e) => debug!(
+                    "Pooled DoT connection to {} failed, reconnecting: {}",
+                    upstream, e
+                ),
+            }
+        }
+
+        debug!("Opening DoT connection to {} ({})", upstream, server_name);
+        let mut tls =
+            connect_dot_upstream_with_tls(upstream, server_name, self.tls_config(config)?).await?;
+        let response = exchange_dot_dns(&mut tls, upstream, message).await?;
+        self.put(key, tls);
+        Ok(response)
+    }
+
+    /// Take the most recently used idle connection that has not timed out
+    fn take(&self, key: &(SocketAddr, String)) -> Option<TlsStream<TcpStream>> {
+        let mut idle = self.idle.get_mut(key)?;
+        let now = Instant::now();
+        idle.retain(|connection| {
+            now.saturating_duration_since(connection.idle_since) < self.idle_timeout
+        });
+        idle.pop().map(|connection| connection.tls)
+    }
+
+    /// Keep a connection for the next query unless the upstream has enough
+    fn put(&self, key: (SocketAddr, String), tls: TlsStream<TcpStream>) {
+        if self.max_per_upstream() == 0 {
+            return;
+        }
+        let mut idle = self.idle.entry(key).or_default();
+        if idle.len() < self.max_per_upstream() {
+            idle.push(IdleConnection {
+                tls,
+                idle_since: Instant::now(),
+            });
+        }
+    }
+
+    /// Number of idle connections
+    pub fn len(&self) -> usize {
+        self.idle.iter().map(|idle| idle.len()).sum()
+    }
+
+    /// Whether the pool holds no connections
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Close connections idle for longer than the idle timeout as of `now`
+    /// Returns the number of evicted connections
+    pub fn evict_idle(&self, now: Instant) -> usize {
+        let before = self.len();
+        self.idle.retain(|_, idle| {
+            idle.retain(|connection| {
+                now.saturating_duration_since(connection.idle_since) < self.idle_timeout
+            });
+            !idle.is_empty()
+        });
+        before.saturating_sub(self.len())
+    }
+}
+
+impl Default for DotConnectionPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
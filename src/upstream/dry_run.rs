@@ -0,0 +1,20 @@
+//! Dry-run mode
+//!
+//! With `proxy.dry_run` set, every listener still filters and rewrites its
+//! queries but answers them itself instead of forwarding them, logging the
+//! upstream each one would have gone to. This shows what a config would do
+//! in production without sending it any traffic.
+
+use crate::dns;
+use crate::metrics::Metrics;
+use bytes::Bytes;
+use std::fmt::Display;
+use tracing::info;
+
+/// Empty NOERROR answer to a query that would have been forwarded to
+/// `upstream`, counted in the dry-run metric
+pub fn dry_run_response(query: &[u8], upstream: impl Display, metrics: &Metrics) -> Bytes {
+    info!("Dry run: would forward query to {}", upstream);
+    metrics.record_dry_run();
+    Bytes::from(dns::empty_response(query))
+}
@@ -0,0 +1,356 @@
+//! Active upstream health probing and failover
+//!
+//! Each upstream protocol may list several upstreams. [`UpstreamHealth`]
+//! periodically sends every one of them a known query (`. NS`) and marks it up
+//! or down; forwarders then pick round-robin among the upstreams that are up.
+//! When none of a protocol's upstreams is up they are all tried anyway, since a
+//! failed probe is better than refusing every query.
+//!
+//! An upstream that keeps failing its probes is probed less and less often:
+//! its probe interval doubles with each consecutive failure, up to
+//! `upstream.health_check_max_interval_secs`, and drops back to
+//! `upstream.health_check_interval_secs` once a probe succeeds.
+
+use crate::config::{AppConfig, CircuitBreakerConfig, UpstreamConfig};
+use crate::error::{DnsProxyError, DnsProxyResult};
+use crate::upstream::create_connection_pool;
+use crate::upstream::http::forward_doh_dns;
+use crate::upstream::http3::forward_doh3_dns;
+use crate::upstream::pool::ConnectionPool;
+use crate::upstream::quic::forward_doq_dns;
+use crate::upstream::timeout::with_timeout;
+use crate::upstream::tls::{connect_dot_upstream_with_tls, exchange_dot_dns};
+use crate::upstream::udp::forward_udp_dns;
+use crate::utils::backoff::exponential_backoff;
+use bytes::Bytes;
+use futures::future::join_all;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+/// Probe query: `. IN NS` with recursion desired
+const PROBE_QUERY: [u8; 17] = [
+    0x48, 0x43, // ID
+    0x01, 0x00, // RD
+    0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // QDCOUNT = 1
+    0x00, // root name
+    0x00, 0x02, 0x00, 0x01, // NS, IN
+];
+
+/// One configured upstream and the result of its last probes
+struct UpstreamState<T> {
+    upstream: T,
+    healthy: AtomicBool,
+    /// Probes failed in a row since the last successful one
+    failures: AtomicU32,
+    /// When the prober should probe the upstream next (`None` = right away)
+    next_probe: Mutex<Option<Instant>>,
+}
+
+/// The upstreams configured for one protocol
+pub struct UpstreamGroup<T> {
+    protocol: &'static str,
+    upstreams: Vec<UpstreamState<T>>,
+    next: AtomicUsize,
+}
+
+impl<T: Clone + Display + PartialEq> UpstreamGroup<T> {
+    /// Create a group; every upstream starts out healthy
+    pub fn new(protocol: &'static str, upstreams: impl IntoIterator<Item = T>) -> Self {
+        Self {
+            protocol,
+            upstreams: upstreams
+                .into_iter()
+                .map(|upstream| UpstreamState {
+                    upstream,
+                    healthy: AtomicBool::new(true),
+                    failures: AtomicU32::new(0),
+                    next_probe: Mutex::new(None),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Config name of the group's protocol (e.g. "dot")
+    pub fn protocol(&self) -> &'static str {
+        self.protocol
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upstreams.is_empty()
+    }
+
+    /// Next upstream to use, round-robin over the healthy ones (or over all of
+    /// them when none is healthy)
+    pub fn pick(&self) -> DnsProxyResult<T> {
+        let healthy: Vec<&T> = self
+            .upstreams
+            .iter()
+            .filter(|state| state.healthy.load(Ordering::Relaxed))
+            .map(|state| &state.upstream)
+            .collect();
+        let candidates = if healthy.is_empty() {
+            self.upstreams.iter().map(|state| &state.upstream).collect()
+        } else {
+            healthy
+        };
+        if candidates.is_empty() {
+            return Err(DnsProxyError::Config(format!(
+                "No {} upstream configured",
+                self.protocol
+            )));
+        }
+
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % candidates.len();
+        Ok(candidates[index].clone())
+    }
+
+    /// Record the result of a probe, logging when an upstream changes state
+    pub fn set_healthy(&self, upstream: &T, healthy: bool) {
+        for state in self.upstreams.iter().filter(|s| &s.upstream == upstream) {
+            if healthy {
+                state.failures.store(0, Ordering::Relaxed);
+            } else {
+                state.failures.fetch_add(1, Ordering::Relaxed);
+            }
+            let was_healthy = state.healthy.swap(healthy, Ordering::Relaxed);
+            if was_healthy && !healthy {
+                warn!("{} upstream {} is down", self.protocol, upstream);
+            } else if !was_healthy && healthy {
+                info!("{} upstream {} is up again", self.protocol, upstream);
+            }
+        }
+    }
+
+    /// Interval until `upstream` is probed again: `base` while its probes
+    /// succeed, doubled for each probe it failed in a row, at most `max`
+    pub fn probe_interval(&self, upstream: &T, base: Duration, max: Duration) -> Duration {
+        let failures = self
+            .upstreams
+            .iter()
+            .find(|state| &state.upstream == upstream)
+            .map_or(0, |state| state.failures.load(Ordering::Relaxed));
+        exponential_backoff(failures, base.as_millis() as u64, max.as_millis() as u64)
+    }
+
+    /// Schedule the next probe of `upstream` one probe interval after `probed_at`
+    fn schedule_probe(&self, upstream: &T, probed_at: Instant, base: Duration, max: Duration) {
+        let next_probe = probed_at + self.probe_interval(upstream, base, max);
+        for state in self.upstreams.iter().filter(|s| &s.upstream == upstream) {
+            *state.next_probe.lock().unwrap_or_else(|e| e.into_inner()) = Some(next_probe);
+        }
+    }
+
+    /// Whether `upstream` is due for a probe at `now`
+    fn probe_due(&self, upstream: &T, now: Instant) -> bool {
+        self.upstreams
+            .iter()
+            .filter(|state| &state.upstream == upstream)
+            .any(|state| {
+                state
+                    .next_probe
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .is_none_or(|next_probe| next_probe <= now)
+            })
+    }
+
+    fn any_healthy(&self) -> bool {
+        self.upstreams
+            .iter()
+            .any(|state| state.healthy.load(Ordering::Relaxed))
+    }
+
+    fn upstreams(&self) -> impl Iterator<Item = &T> {
+        self.upstreams.iter().map(|state| &state.upstream)
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        self.upstreams
+            .iter()
+            .map(|state| {
+                serde_json::json!({
+                    "upstream": state.upstream.to_string(),
+                    "healthy": state.healthy.load(Ordering::Relaxed),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Health of every configured upstream, shared by all servers
+pub struct UpstreamHealth {
+    pub dot: UpstreamGroup<SocketAddr>,
+    pub doq: UpstreamGroup<SocketAddr>,
+    pub udp: UpstreamGroup<SocketAddr>,
+    pub doh: UpstreamGroup<String>,
+    pub doh3: UpstreamGroup<String>,
+}
+
+impl UpstreamHealth {
+    /// Create the upstream groups from the `[upstream]` config
+    ///
+    /// Invalid addresses are skipped; `AppConfig::validate` rejects them.
+    pub fn from_config(config: &AppConfig) -> Self {
+        Self {
+            dot: UpstreamGroup::new("dot", config.dot_upstreams().unwrap_or_default()),
+            doq: UpstreamGroup::new("doq", config.doq_upstreams().unwrap_or_default()),
+            udp: UpstreamGroup::new("udp", config.udp_upstreams().unwrap_or_default()),
+            doh: UpstreamGroup::new("doh", config.upstream.doh.clone()),
+            doh3: UpstreamGroup::new("doh3", config.upstream.doh3.clone()),
+        }
+    }
+
+    /// Probe the upstreams whose probe interval has elapsed (every upstream
+    /// on the first call), record the results and schedule their next probes
+    pub async fn probe_due(&self, config: &AppConfig, pool: &ConnectionPool) {
+        let timeout = config.upstream.timeout();
+        let base = config.upstream.health_check_interval();
+        let max = config.upstream.health_check_max_interval();
+        let now = Instant::now();
+        let socket_probes = [&self.dot, &self.doq, &self.udp]
+            .into_iter()
+            .flat_map(|group| group.upstreams().map(move |addr| (group, *addr)))
+            .filter(|(group, addr)| group.probe_due(addr, now))
+            .map(|(group, addr)| async move {
+                let result = with_timeout(
+                    timeout,
+                    &addr.to_string(),
+                    probe_socket_upstream(group.protocol(), addr, &config.upstream, pool),
+                )
+                .await;
+                group.set_healthy(&addr, probe_succeeded(group.protocol(), &addr, result));
+                group.schedule_probe(&addr, now, base, max);
+            });
+        let url_probes = [&self.doh, &self.doh3]
+            .into_iter()
+            .flat_map(|group| group.upstreams().map(move |url| (group, url)))
+            .filter(|(group, url)| group.probe_due(url, now))
+            .map(|(group, url)| async move {
+                let result = with_timeout(
+                    timeout,
+                    url,
+                    probe_url_upstream(group.protocol(), url, pool, timeout),
+                )
+                .await;
+                group.set_healthy(url, probe_succeeded(group.protocol(), url, result));
+                group.schedule_probe(url, now, base, max);
+            });
+
+        futures::future::join(join_all(socket_probes), join_all(url_probes)).await;
+    }
+
+    /// Probe the upstreams every `upstream.health_check_interval_secs`, or
+    /// less often while they keep failing, until `shutdown` is cancelled
+    ///
+    /// Returns `None` when probing is disabled (an interval of 0) or the proxy
+    /// runs in dry-run mode, which never contacts upstreams.
+    pub fn spawn_prober(
+        self: &Arc<Self>,
+        config: Arc<AppConfig>,
+        shutdown: CancellationToken,
+    ) -> Option<JoinHandle<()>> {
+        let interval_secs = config.upstream.health_check_interval_secs;
+        if interval_secs == 0 || config.proxy.dry_run {
+            return None;
+        }
+
+        let health = Arc::clone(self);
+        Some(tokio::spawn(async move {
+            // Probes bypass the circuit breaker so they see an upstream recover
+            let pool = create_connection_pool(&UpstreamConfig {
+                circuit_breaker: CircuitBreakerConfig::default(),
+                ..config.upstream.clone()
+            });
+            let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = interval.tick() => {}
+                }
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = health.probe_due(&config, &pool) => {}
+                }
+            }
+        }))
+    }
+
+    /// Whether at least one configured upstream passed its last probe
+    pub fn any_healthy(&self) -> bool {
+        self.dot.any_healthy()
+            || self.doq.any_healthy()
+            || self.udp.any_healthy()
+            || self.doh.any_healthy()
+            || self.doh3.any_healthy()
+    }
+
+    /// Health of every upstream, grouped by protocol, for the readiness endpoint
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "dot": self.dot.to_json(),
+            "doq": self.doq.to_json(),
+            "udp": self.udp.to_json(),
+            "doh": self.doh.to_json(),
+            "doh3": self.doh3.to_json(),
+        })
+    }
+}
+
+/// Send the probe query to a DoT, DoQ or plain UDP upstream
+///
+/// DoT probes open a fresh connection, verified with the TLS config of `pool`.
+async fn probe_socket_upstream(
+    protocol: &str,
+    addr: SocketAddr,
+    upstream: &UpstreamConfig,
+    pool: &ConnectionPool,
+) -> DnsProxyResult<Bytes> {
+    let server_name = addr.ip().to_string();
+    match protocol {
+        "dot" => {
+            let tls_config = pool.dot().tls_config(upstream)?;
+            let mut tls = connect_dot_upstream_with_tls(addr, &server_name, tls_config).await?;
+            exchange_dot_dns(&mut tls, addr, &PROBE_QUERY).await
+        }
+        "doq" => forward_doq_dns(addr, &server_name, &PROBE_QUERY).await,
+        _ => forward_udp_dns(addr, &PROBE_QUERY).await,
+    }
+}
+
+/// Send the probe query to a DoH or DoH3 upstream
+async fn probe_url_upstream(
+    protocol: &str,
+    url: &str,
+    pool: &ConnectionPool,
+    timeout: Duration,
+) -> DnsProxyResult<Bytes> {
+    match protocol {
+        "doh" => forward_doh_dns(pool, url, &PROBE_QUERY, timeout).await,
+        _ => forward_doh3_dns(url, &PROBE_QUERY).await,
+    }
+}
+
+/// Whether a probe got an answer to the probe query
+fn probe_succeeded(protocol: &str, upstream: &impl Display, result: DnsProxyResult<Bytes>) -> bool {
+    match result {
+        Ok(response) if response.starts_with(&PROBE_QUERY[..2]) => true,
+        Ok(_) => {
+            debug!(
+                "{} upstream {} answered the probe with a mismatched ID",
+                protocol, upstream
+            );
+            false
+        }
+        Err(e) => {
+            debug!("{} upstream {} failed its probe: {}", protocol, upstream, e);
+            false
+        }
+    }
+}
@@ -1,26 +1,156 @@
+use crate::config::UpstreamConfig;
+use crate::error::{DnsProxyError, DnsProxyResult, UpstreamError};
+use crate::upstream::circuit_breaker::CircuitBreaker;
+use crate::upstream::dot_pool::DotConnectionPool;
 use crate::upstream::pool::ConnectionPool;
+use crate::upstream::quic_pool::QuicConnectionPool;
+use crate::upstream::tls::create_client_config;
 use anyhow::{Context, Result};
-use bytes::Bytes;
-use http_body_util::{BodyExt, Full};
-use hyper::{Method, Request, Response, StatusCode};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, BodyStream, Full, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode, Uri};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::OwnedSemaphorePermit;
 use tracing::{debug, error, warn};
 
-/// Default timeout for upstream requests (30 seconds)
-const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(30);
-
 /// Create a new connection pool instance
-/// This is a convenience function that creates a pool with default settings
-pub fn create_connection_pool() -> Arc<ConnectionPool> {
-    Arc::new(ConnectionPool::new())
+/// This is a convenience function that applies the `[upstream.pool]`,
+/// `[upstream.dot_pool]`, `[upstream.doq_pool]` and `[upstream.circuit_breaker]`
+/// settings, the per-host request cap, `extra_headers` and `max_redirects`,
+/// and loads the DoT and DoQ upstream TLS config once for all connections
+pub fn create_connection_pool(config: &UpstreamConfig) -> Arc<ConnectionPool> {
+    // Checked by `AppConfig::validate`; only unvalidated configs can fail here
+    let extra_headers = config.resolved_extra_headers().unwrap_or_else(|e| {
+        error!("Ignoring upstream.extra_headers: {:#}", e);
+        HeaderMap::new()
+    });
+    let pool = ConnectionPool::from_config(&config.pool)
+        .with_max_conns_per_host(config.doh_max_conns_per_host)
+        .with_dot_pool(DotConnectionPool::from_config(&config.dot_pool))
+        .with_quic_pool(QuicConnectionPool::from_config(&config.doq_pool))
+        .with_circuit_breaker(CircuitBreaker::from_config(&config.circuit_breaker))
+        .with_extra_headers(extra_headers)
+        .with_max_redirects(config.max_redirects);
+    // Without a process-wide crypto provider (e.g. an embedder that only
+    // serves plain DNS so far) the config is left to the first connection.
+    // Only an upstream CA file that became unreadable since validation fails
+    // here; connections then report the error until it can be loaded
+    if rustls::crypto::CryptoProvider::get_default().is_none() {
+        return Arc::new(pool);
+    }
+    match create_client_config(config) {
+        Ok(tls_config) => Arc::new(pool.with_tls_config(Arc::new(tls_config))),
+        Err(e) => {
+            error!("Failed to load upstream TLS config: {}", e);
+            Arc::new(pool)
+        }
+    }
+}
+
+/// Build the path and query to request from the upstream
+///
+/// When `upstream_url` (the configured `upstream.doh`/`upstream.doh3`) has an
+/// explicit path, that path replaces the client's so upstreams serving DoH on
+/// `/resolve` or a custom path are reached correctly. The client's query string
+/// is always forwarded, appended to any query the upstream URL carries. Without an upstream path, the
+/// client's path and query are used unchanged.
+pub fn upstream_path_and_query(client_path_and_query: &str, upstream_url: Option<&str>) -> String {
+    let upstream = upstream_url
+        .and_then(|url| url.parse::<Uri>().ok())
+        .and_then(|uri| uri.path_and_query().cloned())
+        .filter(|pq| pq.path() != "/");
+    let Some(upstream) = upstream else {
+        return client_path_and_query.to_string();
+    };
+
+    let client_query = client_path_and_query
+        .split_once('?')
+        .map(|(_, query)| query)
+        .filter(|query| !query.is_empty());
+    match (upstream.query(), client_query) {
+        (Some(upstream_query), Some(client_query)) => {
+            format!("{}?{}&{}", upstream.path(), upstream_query, client_query)
+        }
+        (Some(query), None) | (None, Some(query)) => format!("{}?{}", upstream.path(), query),
+        (None, None) => upstream.path().to_string(),
+    }
+}
+
+/// Hop-by-hop headers (RFC 9110 section 7.6.1), which apply to a single
+/// connection and are never forwarded
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Copy of `headers` without `Host` and hop-by-hop headers, including any
+/// the client named in its `Connection` header
+pub fn end_to_end_headers(headers: &HeaderMap) -> HeaderMap {
+    let connection_options: Vec<String> = headers
+        .get_all(hyper::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .collect();
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            name != "host"
+                && !HOP_BY_HOP_HEADERS.contains(&name)
+                && !connection_options.iter().any(|option| option == name)
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Copy of `headers` without `Forwarded` and `X-Forwarded-*` headers, which
+/// would pass client addresses and proxy details on to the upstream
+pub fn without_proxy_headers(headers: &HeaderMap) -> HeaderMap {
+    headers
+        .iter()
+        .filter(|(name, _)| {
+            let name = name.as_str();
+            name != "forwarded" && !name.starts_with("x-forwarded-")
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
 }
 
 /// Forward HTTP request to upstream server with timeout control
 /// Returns the response and the body size in bytes for metrics
+/// An expired `timeout` is reported as `UpstreamError::Timeout`
 ///
 /// This function uses a connection pool to reuse connections for the same SNI,
-/// enabling keepalive and avoiding repeated TLS handshakes.
+/// enabling keepalive and avoiding repeated TLS handshakes. When the pool caps
+/// in-flight requests per host, the request queues for a slot; time spent
+/// queueing counts against `timeout`, so excess requests are shed once it expires.
+///
+/// The upstream sees the authority of `upstream_uri`, which callers build
+/// from the target hostname: as the `Host` header over HTTP/1 and as the
+/// `:authority` pseudo-header over HTTP/2. A client's `Host` and hop-by-hop
+/// headers are not forwarded.
+///
+/// Requests fail with `UpstreamError::CircuitOpen` while the circuit of the
+/// upstream's authority and `target_hostname` is open; a timeout, connection
+/// error or 5xx response counts as a failure towards opening it.
+///
+/// When the pool follows redirects, a 3xx response with a `Location` is
+/// followed with the same method, headers and body, each hop reaching the
+/// authority of its own URL. The redirect is returned as is once the pool's
+/// `max_redirects` are used up or it leads back to a URL already requested.
 pub async fn forward_http_request(
     pool: &ConnectionPool,
     upstream_uri: &str,
@@ -28,10 +158,341 @@ pub async fn forward_http_request(
     method: Method,
     headers: &hyper::HeaderMap,
     body: Bytes,
+    timeout: Duration,
 ) -> Result<(Response<Full<Bytes>>, u64)> {
+    let upstream = upstream_uri
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| uri.authority().map(|authority| authority.to_string()))
+        .unwrap_or_else(|| upstream_uri.to_string());
+    let attempt = pool.circuit_breaker().start(&upstream, target_hostname)?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut uri = upstream_uri.to_string();
+    let mut hostname = target_hostname.to_string();
+    let mut requested = vec![uri.clone()];
+    let result = loop {
+        let result = send_http_request(
+            pool,
+            &uri,
+            &hostname,
+            method.clone(),
+            headers,
+            body.clone(),
+            deadline.saturating_duration_since(tokio::time::Instant::now()),
+        )
+        .await;
+        let Some(next) = result.as_ref().ok().and_then(|(response, _)| {
+            next_redirect(pool, upstream_uri, &uri, &mut requested, response)
+        }) else {
+            break result;
+        };
+        hostname = redirect_hostname(&next).unwrap_or(hostname);
+        uri = next;
+    };
+    attempt.finish(matches!(&result, Ok((response, _)) if !response.status().is_server_error()));
+    result
+}
+
+/// URL to follow a response to a request for `uri` to, which is added to
+/// the URLs `requested` so far
+///
+/// `None` when the response is returned as is: it is not a redirect, the
+/// pool follows no more redirects or it leads back to a URL already
+/// requested.
+fn next_redirect<B>(
+    pool: &ConnectionPool,
+    upstream_uri: &str,
+    uri: &str,
+    requested: &mut Vec<String>,
+    response: &Response<B>,
+) -> Option<String> {
+    if pool.max_redirects() == 0 {
+        return None;
+    }
+    let next = redirect_target(uri, response)?;
+    if requested.contains(&next) {
+        warn!(
+            "Upstream redirect loop: {} -> {}, returning the redirect",
+            uri, next
+        );
+        return None;
+    }
+    if requested.len() > pool.max_redirects() as usize {
+        warn!(
+            "Upstream {} redirected more than {} time(s), returning the redirect",
+            upstream_uri,
+            pool.max_redirects()
+        );
+        return None;
+    }
+    debug!("Following upstream redirect: {} -> {}", uri, next);
+    requested.push(next.clone());
+    Some(next)
+}
+
+/// Host a redirect to `next` reaches, and the pooled client it is sent on
+fn redirect_hostname(next: &str) -> Option<String> {
+    next.parse::<Uri>()
+        .ok()
+        .and_then(|next| next.host().map(str::to_string))
+}
+
+/// Absolute URL a redirect response sent to a request for `uri` points at
+///
+/// Only `Location`s that are absolute `http`/`https` URLs or absolute paths
+/// on the same authority are followed.
+fn redirect_target<B>(uri: &str, response: &Response<B>) -> Option<String> {
+    if !matches!(response.status().as_u16(), 301 | 302 | 303 | 307 | 308) {
+        return None;
+    }
+    let location = response
+        .headers()
+        .get(hyper::header::LOCATION)?
+        .to_str()
+        .ok()?;
+    if location.starts_with('/') && !location.starts_with("//") {
+        let uri = uri.parse::<Uri>().ok()?;
+        return Some(format!(
+            "{}://{}{}",
+            uri.scheme_str()?,
+            uri.authority()?,
+            location
+        ));
+    }
+    let location = location.parse::<Uri>().ok()?;
+    matches!(location.scheme_str(), Some("http" | "https"))
+        .then(|| location.authority().map(|_| location.to_string()))
+        .flatten()
+}
+
+/// Body of a response from [`forward_http_request_streaming`]
+pub type StreamingBody = BoxBody<Bytes, hyper::Error>;
+
+/// Largest response body [`forward_http_request_streaming`] buffers; bodies
+/// beyond it are streamed to the client (the largest DNS message fits)
+pub const STREAMING_THRESHOLD: usize = 65_535;
+
+/// A buffered body as a [`StreamingBody`]
+pub fn buffered_body(body: Full<Bytes>) -> StreamingBody {
+    body.map_err(|never| match never {}).boxed()
+}
+
+/// Forward HTTP request to upstream server, streaming large response bodies
+///
+/// Like [`forward_http_request`], except that a response body longer than
+/// [`STREAMING_THRESHOLD`] is not held in memory: once that much has arrived,
+/// the response is returned and the rest of the body is passed on as the
+/// upstream sends it. Smaller bodies are buffered, so their size is known and
+/// returned for metrics; it is `None` for a streamed body. `timeout` covers
+/// the buffered part of the body, and the per-host request slot is held
+/// until the streamed body is dropped.
+///
+/// Redirects are followed as by [`forward_http_request`]; the body of a
+/// redirect that is followed is discarded.
+pub async fn forward_http_request_streaming(
+    pool: &ConnectionPool,
+    upstream_uri: &str,
+    target_hostname: &str,
+    method: Method,
+    headers: &hyper::HeaderMap,
+    body: Bytes,
+    timeout: Duration,
+) -> Result<(Response<StreamingBody>, Option<u64>)> {
+    let upstream = upstream_uri
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| uri.authority().map(|authority| authority.to_string()))
+        .unwrap_or_else(|| upstream_uri.to_string());
+    let attempt = pool.circuit_breaker().start(&upstream, target_hostname)?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut uri = upstream_uri.to_string();
+    let mut hostname = target_hostname.to_string();
+    let mut requested = vec![uri.clone()];
+    let result = loop {
+        let started = start_http_request(
+            pool,
+            &uri,
+            &hostname,
+            method.clone(),
+            headers,
+            body.clone(),
+            deadline.saturating_duration_since(tokio::time::Instant::now()),
+        )
+        .await;
+        let (response, permit) = match started {
+            Ok((Ok(response), permit)) => (response, permit),
+            Ok((Err(e), _)) => {
+                break bad_gateway_response(&uri, &e)
+                    .map(|(response, size)| (response.map(buffered_body), Some(size)));
+            }
+            Err(e) => break Err(e),
+        };
+        if let Some(next) = next_redirect(pool, upstream_uri, &uri, &mut requested, &response) {
+            hostname = redirect_hostname(&next).unwrap_or(hostname);
+            uri = next;
+            continue;
+        }
+        break tokio::time::timeout_at(deadline, stream_or_buffer(response, permit, &uri))
+            .await
+            .unwrap_or_else(|_| {
+                Err(DnsProxyError::Upstream(UpstreamError::Timeout {
+                    upstream: upstream_uri.to_string(),
+                    timeout_ms: timeout.as_millis() as u64,
+                })
+                .into())
+            });
+    };
+    attempt.finish(matches!(&result, Ok((response, _)) if !response.status().is_server_error()));
+    result
+}
+
+/// Buffer a response body up to [`STREAMING_THRESHOLD`], returning it whole
+/// when it ends there and as a stream of the buffered and remaining frames
+/// otherwise
+async fn stream_or_buffer(
+    response: Response<Incoming>,
+    permit: Option<OwnedSemaphorePermit>,
+    upstream_uri: &str,
+) -> Result<(Response<StreamingBody>, Option<u64>)> {
+    let (parts, mut body) = response.into_parts();
+    let mut frames = Vec::new();
+    let mut buffered = 0;
+    while buffered <= STREAMING_THRESHOLD {
+        let Some(frame) = body.frame().await else {
+            let mut body_bytes = BytesMut::with_capacity(buffered);
+            for data in frames.iter().filter_map(Frame::<Bytes>::data_ref) {
+                body_bytes.extend_from_slice(data);
+            }
+            let body_bytes = body_bytes.freeze();
+            let body_size = body_bytes.len() as u64;
+            debug!("Response body size: {} bytes", body_size);
+            let body = buffered_body(Full::new(body_bytes));
+            return Ok((Response::from_parts(parts, body), Some(body_size)));
+        };
+        let frame = frame.with_context(|| {
+            format!(
+                "Failed to read response body from upstream: {}",
+                upstream_uri
+            )
+        })?;
+        buffered += frame.data_ref().map_or(0, Bytes::len);
+        frames.push(frame);
+    }
+
+    debug!(
+        "Streaming response body from upstream: {} (over {} bytes)",
+        upstream_uri, STREAMING_THRESHOLD
+    );
+    let rest = BodyStream::new(body).map(move |frame| {
+        // The request slot is released once the client is done with the body
+        let _permit = &permit;
+        frame
+    });
+    let stream = futures::stream::iter(frames.into_iter().map(Ok)).chain(rest);
+    Ok((
+        Response::from_parts(parts, BodyExt::boxed(StreamBody::new(stream))),
+        None,
+    ))
+}
+
+/// Send an HTTP request on the pooled client of `target_hostname` (see
+/// [`forward_http_request`])
+async fn send_http_request(
+    pool: &ConnectionPool,
+    upstream_uri: &str,
+    target_hostname: &str,
+    method: Method,
+    headers: &hyper::HeaderMap,
+    body: Bytes,
+    timeout: Duration,
+) -> Result<(Response<Full<Bytes>>, u64)> {
+    let (response, _permit) = start_http_request(
+        pool,
+        upstream_uri,
+        target_hostname,
+        method,
+        headers,
+        body,
+        timeout,
+    )
+    .await?;
+    let response = match response {
+        Ok(response) => response,
+        Err(e) => return bad_gateway_response(upstream_uri, &e),
+    };
+
+    let status = response.status();
+    let (parts, body) = response.into_parts();
+    let body_bytes = body
+        .collect()
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to read response body from upstream: {}",
+                upstream_uri
+            )
+        })?
+        .to_bytes();
+
+    let body_size = body_bytes.len() as u64;
+    debug!("Response body size: {} bytes", body_size);
+
+    if !status.is_success() {
+        warn!(
+            "Upstream returned non-success status: {} {} (body: {} bytes)",
+            status, upstream_uri, body_size
+        );
+    }
+
+    Ok((
+        Response::from_parts(parts, Full::new(body_bytes)),
+        body_size,
+    ))
+}
+
+/// Send an HTTP request on the pooled client of `target_hostname` and wait
+/// for the response head
+///
+/// Returns the response, or the error of a request that did not reach the
+/// upstream, together with the per-host request slot it holds. Fails when
+/// `timeout` expires first.
+async fn start_http_request(
+    pool: &ConnectionPool,
+    upstream_uri: &str,
+    target_hostname: &str,
+    method: Method,
+    headers: &hyper::HeaderMap,
+    body: Bytes,
+    timeout: Duration,
+) -> Result<(
+    Result<Response<Incoming>, hyper_util::client::legacy::Error>,
+    Option<OwnedSemaphorePermit>,
+)> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let timed_out = || -> anyhow::Error {
+        error!(
+            "HTTP upstream request timeout: {} {} (target: {}, timeout: {:?})",
+            method, upstream_uri, target_hostname, timeout
+        );
+        DnsProxyError::Upstream(UpstreamError::Timeout {
+            upstream: upstream_uri.to_string(),
+            timeout_ms: timeout.as_millis() as u64,
+        })
+        .into()
+    };
+
     // Get or create a client for this SNI (target_hostname)
     // This ensures connection reuse for the same target
     let client = pool.get_client(target_hostname);
+    let permit = tokio::time::timeout_at(deadline, pool.acquire_permit(target_hostname))
+        .await
+        .map_err(|_| {
+            warn!(
+                "Too many in-flight requests to upstream host {}, shedding request",
+                target_hostname
+            );
+            timed_out()
+        })?;
     let mut req = Request::builder()
         .method(method.clone())
         .uri(upstream_uri)
@@ -43,111 +504,159 @@ pub async fn forward_http_request(
             )
         })?;
 
-    // Copy headers efficiently - only copy necessary headers
-    // Skip headers that will be overwritten or aren't needed
-    let skip_headers = ["host", "connection", "keep-alive", "transfer-encoding"];
-    for (key, value) in headers {
-        let key_str = key.as_str();
-        if !skip_headers.contains(&key_str) {
-            // Use reference to avoid cloning when possible
-            req.headers_mut().insert(key, value.clone());
-        }
+    *req.headers_mut() = end_to_end_headers(headers);
+    // Values may be secrets, so they are marked sensitive and never logged
+    for (name, value) in pool.extra_headers() {
+        req.headers_mut().insert(name, value.clone());
     }
 
-    req.headers_mut().insert(
-        "host",
-        target_hostname
-            .parse()
-            .with_context(|| format!("Invalid target hostname: {}", target_hostname))?,
-    );
-
     debug!(
-        "Sending {} request to upstream: {} (Host: {}, SNI: {})",
-        method, upstream_uri, target_hostname, target_hostname
+        "Sending {} request to upstream: {} (authority: {}, SNI: {})",
+        method,
+        upstream_uri,
+        req.uri()
+            .authority()
+            .map_or("", |authority| authority.as_str()),
+        target_hostname
     );
 
     // Add timeout control to prevent hanging requests
     // The client from the pool will reuse existing connections when possible
     let request_future = client.request(req);
-    let timeout_future = tokio::time::timeout(DEFAULT_UPSTREAM_TIMEOUT, request_future);
+    let timeout_future = tokio::time::timeout_at(deadline, request_future);
 
     match timeout_future.await {
         Ok(Ok(resp)) => {
             let status = resp.status();
-            let (parts, body) = resp.into_parts();
+            let (mut parts, body) = resp.into_parts();
+            // Never echo the configured headers back to the client, should
+            // the upstream reflect them
+            for name in pool.extra_headers().keys() {
+                parts.headers.remove(name);
+            }
 
             debug!(
                 "Received response from upstream: {} {}",
                 status, upstream_uri
             );
-
-            let body_bytes = body
-                .collect()
-                .await
-                .with_context(|| {
-                    format!(
-                        "Failed to read response body from upstream: {}",
-                        upstream_uri
-                    )
-                })?
-                .to_bytes();
-
-            let body_size = body_bytes.len() as u64;
-            debug!("Response body size: {} bytes", body_size);
-
-            if !status.is_success() {
-                warn!(
-                    "Upstream returned non-success status: {} {} (body: {} bytes)",
-                    status, upstream_uri, body_size
-                );
-            }
-
-            Ok((
-                Response::from_parts(parts, Full::new(body_bytes)),
-                body_size,
-            ))
+            Ok((Ok(Response::from_parts(parts, body)), permit))
         }
         Ok(Err(e)) => {
             error!(
                 "HTTP upstream request failed: {} {} -> {} (target: {})",
                 method, upstream_uri, e, target_hostname
             );
-
-            // Return a proper error response instead of panicking
-            let error_msg = format!("Upstream error: {}", e);
-            let error_body = Full::new(error_msg.clone().into());
-            let error_size = error_msg.len() as u64;
-            Response::builder()
-                .status(StatusCode::BAD_GATEWAY)
-                .body(error_body)
-                .map(|resp| (resp, error_size))
-                .with_context(|| {
-                    format!(
-                        "Failed to create error response for upstream failure: {}",
-                        upstream_uri
-                    )
-                })
+            Ok((Err(e), permit))
         }
-        Err(_) => {
-            error!(
-                "HTTP upstream request timeout: {} {} (target: {}, timeout: {:?})",
-                method, upstream_uri, target_hostname, DEFAULT_UPSTREAM_TIMEOUT
-            );
+        Err(_) => Err(timed_out()),
+    }
+}
 
-            // Return timeout error response
-            let error_msg = format!("Upstream timeout after {:?}", DEFAULT_UPSTREAM_TIMEOUT);
-            let error_body = Full::new(error_msg.clone().into());
-            let error_size = error_msg.len() as u64;
-            Response::builder()
-                .status(StatusCode::GATEWAY_TIMEOUT)
-                .body(error_body)
-                .map(|resp| (resp, error_size))
-                .with_context(|| {
-                    format!(
-                        "Failed to create timeout response for upstream: {}",
-                        upstream_uri
-                    )
-                })
-        }
+/// 502 response standing in for a request that did not reach the upstream,
+/// and the size of its body
+fn bad_gateway_response(
+    upstream_uri: &str,
+    e: &hyper_util::client::legacy::Error,
+) -> Result<(Response<Full<Bytes>>, u64)> {
+    // Return a proper error response instead of panicking
+    let error_msg = format!("Upstream error: {}", e);
+    let error_body = Full::new(error_msg.clone().into());
+    let error_size = error_msg.len() as u64;
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(error_body)
+        .map(|resp| (resp, error_size))
+        .with_context(|| {
+            format!(
+                "Failed to create error response for upstream failure: {}",
+                upstream_uri
+            )
+        })
+}
+
+/// Whether an HTTP forwarding result is a transient failure worth retrying
+///
+/// Connection errors surface as 502 responses from `forward_http_request`
+/// and `forward_http_request_streaming`, so any 5xx is retried. 4xx,
+/// successful responses and timeouts are not.
+pub fn is_transient_response<B, S>(result: &Result<(Response<B>, S)>) -> bool {
+    matches!(result, Ok((response, _)) if response.status().is_server_error())
+}
+
+/// Build the 504 response returned to DoH clients when the upstream timed out
+/// Returns `None` for any other error
+pub fn gateway_timeout_response(error: &anyhow::Error) -> Option<Response<Full<Bytes>>> {
+    let error = error.downcast_ref::<DnsProxyError>()?;
+    if !error.is_timeout() {
+        return None;
+    }
+
+    let error_msg = error.to_string();
+    Response::builder()
+        .status(StatusCode::GATEWAY_TIMEOUT)
+        .body(Full::new(error_msg.into()))
+        .ok()
+}
+
+/// Forward a DNS wire-format message to a DoH upstream as an RFC 8484 POST
+/// Non-success HTTP statuses are reported as upstream errors
+pub async fn forward_doh_dns(
+    pool: &ConnectionPool,
+    upstream_url: &str,
+    message: &[u8],
+    timeout: Duration,
+) -> DnsProxyResult<Bytes> {
+    let request_failed = |reason: String| {
+        DnsProxyError::Upstream(UpstreamError::RequestFailed {
+            upstream: upstream_url.to_string(),
+            reason,
+        })
+    };
+
+    let uri: Uri = upstream_url.parse().map_err(|e| {
+        DnsProxyError::InvalidInput(format!("Invalid DoH upstream URL {}: {}", upstream_url, e))
+    })?;
+    let host = uri.authority().map(|a| a.as_str()).ok_or_else(|| {
+        DnsProxyError::InvalidInput(format!("DoH upstream URL has no host: {}", upstream_url))
+    })?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "content-type",
+        hyper::header::HeaderValue::from_static("application/dns-message"),
+    );
+    headers.insert(
+        "accept",
+        hyper::header::HeaderValue::from_static("application/dns-message"),
+    );
+
+    let (response, _) = forward_http_request(
+        pool,
+        upstream_url,
+        host,
+        Method::POST,
+        &headers,
+        Bytes::copy_from_slice(message),
+        timeout,
+    )
+    .await
+    .map_err(|e| match e.downcast::<DnsProxyError>() {
+        Ok(e) => e,
+        Err(e) => request_failed(e.to_string()),
+    })?;
+
+    if !response.status().is_success() {
+        return Err(request_failed(format!(
+            "Upstream returned status {}",
+            response.status()
+        )));
     }
+
+    let body = response
+        .into_body()
+        .collect()
+        .await
+        .map_err(|e| request_failed(format!("Failed to read response body: {}", e)))?
+        .to_bytes();
+    Ok(body)
 }
@@ -0,0 +1,106 @@
+use crate::error::{DnsProxyError, DnsProxyResult, UpstreamError};
+use crate::quic::client::{ALPN_H3, connect_quic_upstream};
+use bytes::{Buf, Bytes};
+use hyper::{Request, Uri};
+use std::sync::Arc;
+use tracing::debug;
+
+/// Forward a DNS wire-format message to a DoH3 upstream as an RFC 8484 POST
+pub async fn forward_doh3_dns(upstream_url: &str, message: &[u8]) -> DnsProxyResult<Bytes> {
+    let request_failed = |reason: String| {
+        DnsProxyError::Upstream(UpstreamError::RequestFailed {
+            upstream: upstream_url.to_string(),
+            reason,
+        })
+    };
+
+    let uri: Uri = upstream_url.parse().map_err(|e| {
+        DnsProxyError::InvalidInput(format!("Invalid DoH3 upstream URL {}: {}", upstream_url, e))
+    })?;
+    let host = uri.host().ok_or_else(|| {
+        DnsProxyError::InvalidInput(format!("DoH3 upstream URL has no host: {}", upstream_url))
+    })?;
+    let port = uri.port_u16().unwrap_or(443);
+
+    let addr = tokio::net::lookup_host((host, port))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| {
+            DnsProxyError::Upstream(UpstreamError::ConnectionFailed {
+                upstream: upstream_url.to_string(),
+                reason: format!("Failed to resolve {}:{}", host, port),
+            })
+        })?;
+
+    let connection = connect_quic_upstream(addr, host, ALPN_H3, Arc::default())
+        .await
+        .map_err(|e| {
+            DnsProxyError::Upstream(UpstreamError::ConnectionFailed {
+                upstream: upstream_url.to_string(),
+                reason: e.to_string(),
+            })
+        })?;
+
+    let (mut driver, mut send_request) = h3::client::new(h3_quinn::Connection::new(connection))
+        .await
+        .map_err(|e| request_failed(format!("Failed to create H3 connection: {}", e)))?;
+    let driver_task = tokio::spawn(async move {
+        let _ = futures::future::poll_fn(|cx| driver.poll_close(cx)).await;
+    });
+
+    let result = async {
+        let request = Request::post(uri.clone())
+            .header("content-type", "application/dns-message")
+            .header("accept", "application/dns-message")
+            .body(())
+            .map_err(|e| request_failed(format!("Failed to build DoH3 request: {}", e)))?;
+
+        let mut stream = send_request
+            .send_request(request)
+            .await
+            .map_err(|e| request_failed(format!("Failed to send DoH3 request: {}", e)))?;
+        stream
+            .send_data(Bytes::copy_from_slice(message))
+            .await
+            .map_err(|e| request_failed(format!("Failed to send DoH3 body: {}", e)))?;
+        stream
+            .finish()
+            .await
+            .map_err(|e| request_failed(format!("Failed to finish DoH3 request: {}", e)))?;
+
+        let response = stream
+            .recv_response()
+            .await
+            .map_err(|e| request_failed(format!("Failed to receive DoH3 response: {}", e)))?;
+        if !response.status().is_success() {
+            return Err(request_failed(format!(
+                "Upstream returned status {}",
+                response.status()
+            )));
+        }
+
+        let mut body = Vec::new();
+        while let Some(mut chunk) = stream
+            .recv_data()
+            .await
+            .map_err(|e| request_failed(format!("Failed to read DoH3 response body: {}", e)))?
+        {
+            while chunk.has_remaining() {
+                body.extend_from_slice(chunk.chunk());
+                chunk.advance(chunk.chunk().len());
+            }
+        }
+
+        debug!(
+            "Received DoH3 response from {}: {} bytes",
+            upstream_url,
+            body.len()
+        );
+        Ok(Bytes::from(body))
+    }
+    .await;
+
+    driver_task.abort();
+    result
+}
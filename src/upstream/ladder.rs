@@ -0,0 +1,203 @@
+//! Protocol downgrade ladder
+//!
+//! Forwards a wire-format DNS message by trying each protocol listed in
+//! `upstream.protocol_ladder` in order (e.g. DoH3 -> DoQ -> DoT), falling back
+//! to the next one when an attempt fails. This keeps queries resolving on
+//! networks that block UDP/QUIC while preferring the most private transport.
+//!
+//! A server's `upstream_protocol` instead sends all of its queries over one
+//! protocol, whatever protocol they arrived on (e.g. DoH in, DoT out).
+
+use crate::config::{AppConfig, ServerPortConfig};
+use crate::error::{DnsProxyError, DnsProxyResult};
+use crate::metrics::Metrics;
+use crate::upstream::circuit_breaker::record_circuit_open;
+use crate::upstream::health::UpstreamHealth;
+use crate::upstream::http::forward_doh_dns;
+use crate::upstream::http3::forward_doh3_dns;
+use crate::upstream::pool::ConnectionPool;
+use crate::upstream::retry::{is_transient_error, with_retries};
+use crate::upstream::timeout::with_timeout;
+use bytes::Bytes;
+use tracing::{debug, info, warn};
+
+/// Encrypted upstream transports that can appear in a protocol ladder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamProtocol {
+    Doh3,
+    Doq,
+    Dot,
+    Doh,
+}
+
+impl UpstreamProtocol {
+    /// Parse a protocol name as used in the config file
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "doh3" => Some(Self::Doh3),
+            "doq" => Some(Self::Doq),
+            "dot" => Some(Self::Dot),
+            "doh" => Some(Self::Doh),
+            _ => None,
+        }
+    }
+
+    /// Config file name of the protocol
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Doh3 => "doh3",
+            Self::Doq => "doq",
+            Self::Dot => "dot",
+            Self::Doh => "doh",
+        }
+    }
+
+    /// The `upstream_protocol` a server forwards its queries over, if any
+    pub fn of_server(server: &ServerPortConfig) -> Option<Self> {
+        server.upstream_protocol.as_deref().and_then(Self::parse)
+    }
+
+    /// Forward a DNS message to the next healthy upstream of this protocol
+    ///
+    /// Each attempt is bounded by `upstream.upstream_timeout_ms`; connection
+    /// failures are retried up to `upstream.max_retries` times.
+    pub async fn forward(
+        self,
+        config: &AppConfig,
+        pool: &ConnectionPool,
+        health: &UpstreamHealth,
+        message: &[u8],
+        metrics: &Metrics,
+    ) -> DnsProxyResult<Bytes> {
+        with_retries(
+            config.upstream.max_retries,
+            self.as_str(),
+            metrics,
+            is_transient_error,
+            || {
+                with_timeout(
+                    config.upstream.timeout(),
+                    self.as_str(),
+                    forward_via(config, pool, health, self, message, metrics),
+                )
+            },
+        )
+        .await
+    }
+}
+
+impl std::fmt::Display for UpstreamProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Forward a DNS message using the configured protocol ladder
+///
+/// Each attempt goes to the next healthy upstream of its protocol (see
+/// [`UpstreamHealth`]) and is bounded by `upstream.upstream_timeout_ms`. Returns the
+/// upstream response together with the protocol that produced it, or the last
+/// error if every protocol in the ladder failed.
+pub async fn forward_with_ladder(
+    config: &AppConfig,
+    pool: &ConnectionPool,
+    health: &UpstreamHealth,
+    message: &[u8],
+    metrics: &Metrics,
+) -> DnsProxyResult<(Bytes, UpstreamProtocol)> {
+    let mut last_error = None;
+
+    for name in &config.upstream.protocol_ladder {
+        let Some(protocol) = UpstreamProtocol::parse(name) else {
+            warn!("Skipping unknown protocol in upstream ladder: {}", name);
+            continue;
+        };
+
+        debug!("Forwarding query via {} upstream", protocol);
+        let attempt = forward_via(config, pool, health, protocol, message, metrics);
+        match with_timeout(config.upstream.timeout(), protocol.as_str(), attempt).await {
+            Ok(response) => {
+                if last_error.is_some() {
+                    info!("Upstream query resolved after downgrade to {}", protocol);
+                }
+                return Ok((response, protocol));
+            }
+            Err(e) => {
+                warn!(
+                    "{} upstream attempt failed, trying next protocol: {}",
+                    protocol, e
+                );
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        DnsProxyError::Config("upstream.protocol_ladder has no usable protocols".to_string())
+    }))
+}
+
+/// Forward a DNS message over a server's `upstream_protocol` when it has one,
+/// otherwise down the protocol ladder
+///
+/// Returns the upstream response together with the protocol that produced it.
+pub async fn forward_message(
+    config: &AppConfig,
+    pool: &ConnectionPool,
+    health: &UpstreamHealth,
+    server: &ServerPortConfig,
+    message: &[u8],
+    metrics: &Metrics,
+) -> DnsProxyResult<(Bytes, UpstreamProtocol)> {
+    match UpstreamProtocol::of_server(server) {
+        Some(protocol) => {
+            let response = protocol
+                .forward(config, pool, health, message, metrics)
+                .await?;
+            Ok((response, protocol))
+        }
+        None => forward_with_ladder(config, pool, health, message, metrics).await,
+    }
+}
+
+/// Where [`forward_message`] sends a server's queries, for logs
+pub fn message_route(server: &ServerPortConfig) -> String {
+    match UpstreamProtocol::of_server(server) {
+        Some(protocol) => format!("{} upstream", protocol),
+        None => "upstream protocol ladder".to_string(),
+    }
+}
+
+/// Forward a DNS message over a single upstream protocol, counting the
+/// exchange in the upstream byte metrics
+async fn forward_via(
+    config: &AppConfig,
+    pool: &ConnectionPool,
+    health: &UpstreamHealth,
+    protocol: UpstreamProtocol,
+    message: &[u8],
+    metrics: &Metrics,
+) -> DnsProxyResult<Bytes> {
+    let response = match protocol {
+        UpstreamProtocol::Doh3 => forward_doh3_dns(&health.doh3.pick()?, message).await,
+        UpstreamProtocol::Doq => {
+            let addr = health.doq.pick()?;
+            pool.quic()
+                .forward(addr, &addr.ip().to_string(), message, &config.upstream)
+                .await
+        }
+        UpstreamProtocol::Dot => {
+            let addr = health.dot.pick()?;
+            pool.dot()
+                .forward(addr, &addr.ip().to_string(), message, &config.upstream)
+                .await
+        }
+        UpstreamProtocol::Doh => {
+            let url = health.doh.pick()?;
+            forward_doh_dns(pool, &url, message, config.upstream.timeout()).await
+        }
+    }
+    .inspect_err(|e| record_circuit_open(e, metrics))?;
+    metrics.record_upstream_bytes(message.len() as u64, response.len() as u64);
+    Ok(response)
+}
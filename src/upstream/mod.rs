@@ -1,6 +1,19 @@
+pub mod circuit_breaker;
+pub mod default_upstream;
+pub mod dot_pool;
+pub mod dry_run;
+pub mod health;
 pub mod http;
+pub mod http3;
+pub mod ladder;
 pub mod pool;
 pub mod quic;
+pub mod quic_pool;
+pub mod retry;
+pub mod tcp;
+pub mod timeout;
+pub mod tls;
+pub mod udp;
 
 pub use http::*;
 #[allow(unused_imports)]
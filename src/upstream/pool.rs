@@ -1,12 +1,19 @@
+use crate::config::UpstreamPoolConfig;
+use crate::upstream::circuit_breaker::CircuitBreaker;
+use crate::upstream::dot_pool::DotConnectionPool;
+use crate::upstream::quic_pool::QuicConnectionPool;
 use dashmap::DashMap;
 use http_body_util::Full;
+use hyper::HeaderMap;
 use hyper::body::Bytes;
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use hyper_util::client::legacy::Client;
 use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::rt::TokioExecutor;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
 use tracing::debug;
 
 /// Default keepalive timeout (60 seconds)
@@ -21,17 +28,46 @@ const DEFAULT_MAX_IDLE_CONNECTIONS: usize = 10;
 /// HTTP client type with HTTPS support
 pub type HttpClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
 
+/// Pooled HTTP client together with the time it was last handed out
+struct PoolEntry {
+    client: Arc<HttpClient>,
+    last_used: Instant,
+    /// Limits in-flight requests to this target when a per-host cap is set
+    permits: Option<Arc<Semaphore>>,
+}
+
 /// Connection pool manager that maintains separate HTTP clients for each SNI
 /// This allows connection reuse and keepalive for the same target hostname
+///
+/// Clients idle for longer than the keepalive timeout are evicted by
+/// [`ConnectionPool::evict_idle`], which [`ConnectionPool::spawn_reaper`] runs periodically.
+///
+/// The pool also holds the TLS connections to DoT upstreams (see
+/// [`ConnectionPool::dot`]) and the QUIC connections to DoQ upstreams (see
+/// [`ConnectionPool::quic`]). All three share one [`CircuitBreaker`].
 pub struct ConnectionPool {
     /// Map from SNI (target hostname) to HTTP client
-    clients: Arc<DashMap<String, Arc<HttpClient>>>,
+    clients: Arc<DashMap<String, PoolEntry>>,
     /// Keepalive timeout duration
     keepalive_timeout: Duration,
     /// Connection timeout duration
     connection_timeout: Duration,
     /// Max idle connections per SNI
     max_idle_connections: usize,
+    /// Max in-flight requests per SNI (0 = unlimited)
+    max_conns_per_host: usize,
+    /// Pooled TLS connections to DoT upstreams
+    dot: DotConnectionPool,
+    /// Pooled QUIC connections to DoQ upstreams
+    quic: QuicConnectionPool,
+    /// Circuits of the upstreams forwarded to through this pool
+    circuit_breaker: Arc<CircuitBreaker>,
+    /// Verifies DoT and DoQ upstreams, shared by both connection pools
+    tls_config: Option<Arc<rustls::ClientConfig>>,
+    /// Headers added to every HTTP request sent through this pool
+    extra_headers: HeaderMap,
+    /// Redirects followed for a single HTTP request (0 = none)
+    max_redirects: u32,
 }
 
 impl ConnectionPool {
@@ -55,16 +91,97 @@ impl ConnectionPool {
             keepalive_timeout,
             connection_timeout,
             max_idle_connections,
+            max_conns_per_host: 0,
+            dot: DotConnectionPool::new(),
+            quic: QuicConnectionPool::new(),
+            circuit_breaker: Arc::new(CircuitBreaker::disabled()),
+            tls_config: None,
+            extra_headers: HeaderMap::new(),
+            max_redirects: 0,
         }
     }
 
+    /// Limit the number of in-flight requests to each SNI (0 = unlimited)
+    pub fn with_max_conns_per_host(mut self, max_conns_per_host: usize) -> Self {
+        self.max_conns_per_host = max_conns_per_host;
+        self
+    }
+
+    /// Pool DoT connections with `dot` instead of the default settings,
+    /// sharing this pool's circuit breaker and TLS config
+    pub fn with_dot_pool(mut self, dot: DotConnectionPool) -> Self {
+        let dot = dot.with_circuit_breaker(Arc::clone(&self.circuit_breaker));
+        self.dot = match &self.tls_config {
+            Some(tls_config) => dot.with_tls_config(Arc::clone(tls_config)),
+            None => dot,
+        };
+        self
+    }
+
+    /// Pool DoQ connections with `quic` instead of the default settings,
+    /// sharing this pool's circuit breaker and TLS config
+    pub fn with_quic_pool(mut self, quic: QuicConnectionPool) -> Self {
+        let quic = quic.with_circuit_breaker(Arc::clone(&self.circuit_breaker));
+        self.quic = match &self.tls_config {
+            Some(tls_config) => quic.with_tls_config(Arc::clone(tls_config)),
+            None => quic,
+        };
+        self
+    }
+
+    /// Verify DoT and DoQ upstreams with `tls_config`, built once for every
+    /// connection, instead of building one per connection pool on first use
+    pub fn with_tls_config(mut self, tls_config: Arc<rustls::ClientConfig>) -> Self {
+        self.dot = self.dot.with_tls_config(Arc::clone(&tls_config));
+        self.quic = self.quic.with_tls_config(Arc::clone(&tls_config));
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    /// Stop forwarding to failing upstreams with `circuit_breaker` instead
+    /// of never opening circuits
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Arc::new(circuit_breaker);
+        self.dot = self
+            .dot
+            .with_circuit_breaker(Arc::clone(&self.circuit_breaker));
+        self.quic = self
+            .quic
+            .with_circuit_breaker(Arc::clone(&self.circuit_breaker));
+        self
+    }
+
+    /// Add `extra_headers` to every HTTP request sent through this pool,
+    /// replacing any of the same name
+    pub fn with_extra_headers(mut self, extra_headers: HeaderMap) -> Self {
+        self.extra_headers = extra_headers;
+        self
+    }
+
+    /// Follow up to `max_redirects` redirects for each HTTP request instead
+    /// of returning 3xx responses
+    pub fn with_max_redirects(mut self, max_redirects: u32) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    /// Create a new connection pool from the `[upstream.pool]` config section
+    pub fn from_config(config: &UpstreamPoolConfig) -> Self {
+        Self::with_config(
+            Duration::from_secs(config.keepalive_secs),
+            Duration::from_secs(config.connect_timeout_secs),
+            config.max_idle_per_host,
+        )
+    }
+
     /// Get or create an HTTP client for the given SNI (target hostname)
     /// This ensures that requests to the same SNI reuse connections
     pub fn get_client(&self, sni: &str) -> Arc<HttpClient> {
         // Fast path: check if client already exists
-        if let Some(client) = self.clients.get(sni) {
+        if let Some(mut entry) = self.clients.get_mut(sni) {
             debug!("Reusing existing HTTP client for SNI: {}", sni);
-            return Arc::clone(client.value());
+            entry.last_used = Instant::now();
+            return Arc::clone(&entry.client);
         }
 
         // Slow path: create new client for this SNI
@@ -74,21 +191,141 @@ impl ConnectionPool {
 
         // Insert into map (may race with another thread, but that's okay)
         // We'll use the first one that gets inserted
-        self.clients
+        // Return the client from the map (could be ours or another thread's)
+        let entry = self
+            .clients
             .entry(sni.to_string())
-            .or_insert_with(|| Arc::clone(&client_arc));
+            .or_insert_with(|| PoolEntry {
+                client: Arc::clone(&client_arc),
+                last_used: Instant::now(),
+                permits: (self.max_conns_per_host > 0)
+                    .then(|| Arc::new(Semaphore::new(self.max_conns_per_host))),
+            });
+        Arc::clone(&entry.client)
+    }
 
-        // Return the client from the map (could be ours or another thread's)
-        self.clients
-            .get(sni)
-            .map(|entry| Arc::clone(entry.value()))
-            .unwrap_or(client_arc)
+    /// Wait for a free request slot for the given SNI
+    ///
+    /// Returns `None` immediately when no per-host cap is configured. Otherwise
+    /// the returned permit holds one of the `max_conns_per_host` slots until
+    /// dropped; callers bound the wait with their own timeout.
+    pub async fn acquire_permit(&self, sni: &str) -> Option<OwnedSemaphorePermit> {
+        if self.max_conns_per_host == 0 {
+            return None;
+        }
+        let permits = match self.clients.get(sni) {
+            Some(entry) => entry.permits.clone(),
+            None => {
+                self.get_client(sni);
+                self.clients
+                    .get(sni)
+                    .and_then(|entry| entry.permits.clone())
+            }
+        }?;
+        // The semaphore is never closed, so acquiring only fails if it is dropped
+        permits.acquire_owned().await.ok()
+    }
+
+    /// Pooled TLS connections to DoT upstreams
+    pub fn dot(&self) -> &DotConnectionPool {
+        &self.dot
+    }
+
+    /// Pooled QUIC connections to DoQ upstreams
+    pub fn quic(&self) -> &QuicConnectionPool {
+        &self.quic
+    }
+
+    /// Circuits of the upstreams forwarded to through this pool
+    pub fn circuit_breaker(&self) -> &CircuitBreaker {
+        &self.circuit_breaker
+    }
+
+    /// Headers added to every HTTP request sent through this pool
+    pub fn extra_headers(&self) -> &HeaderMap {
+        &self.extra_headers
+    }
+
+    /// Redirects followed for a single HTTP request (0 = none)
+    pub fn max_redirects(&self) -> u32 {
+        self.max_redirects
+    }
+
+    /// Idle timeout for pooled connections and per-SNI clients
+    pub fn keepalive_timeout(&self) -> Duration {
+        self.keepalive_timeout
+    }
+
+    /// Max idle connections kept per SNI
+    pub fn max_idle_connections(&self) -> usize {
+        self.max_idle_connections
+    }
+
+    /// Number of SNI targets with a pooled client
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Whether the pool holds no clients
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Remove clients not used within the keepalive timeout as of `now`
+    /// Returns the number of evicted clients
+    pub fn evict_idle(&self, now: Instant) -> usize {
+        let before = self.len();
+        self.clients.retain(|_, entry| {
+            now.saturating_duration_since(entry.last_used) < self.keepalive_timeout
+        });
+        let evicted = before.saturating_sub(self.len());
+        if evicted > 0 {
+            debug!(
+                "Evicted {} idle HTTP client(s) from connection pool",
+                evicted
+            );
+        }
+        evicted
+    }
+
+    /// Spawn a background task that evicts idle clients and connections
+    /// every keepalive or idle timeout, whichever is shortest
+    ///
+    /// The task holds only a weak reference and exits once the pool is dropped.
+    pub fn spawn_reaper(self: &Arc<Self>) -> JoinHandle<()> {
+        let pool: Weak<Self> = Arc::downgrade(self);
+        let period = self
+            .keepalive_timeout()
+            .min(self.dot.idle_timeout())
+            .min(self.quic.idle_timeout());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            // The first tick completes immediately
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let Some(pool) = pool.upgrade() else {
+                    break;
+                };
+                if !pool.is_empty() {
+                    pool.evict_idle(Instant::now());
+                }
+                if !pool.dot.is_empty() {
+                    pool.dot.evict_idle(Instant::now());
+                }
+                if !pool.quic.is_empty() {
+                    pool.quic.evict_closed();
+                }
+            }
+        })
     }
 
     /// Create a new HTTP client with HTTPS support and keepalive configuration
     fn create_client(&self) -> HttpClient {
         // Create HTTP connector with keepalive settings
+        // https:// URIs must reach the TLS wrapper, so don't enforce the http scheme here
         let mut http_connector = HttpConnector::new();
+        http_connector.enforce_http(false);
         http_connector.set_keepalive(Some(self.keepalive_timeout));
         http_connector.set_connect_timeout(Some(self.connection_timeout));
 
@@ -103,9 +340,11 @@ impl ConnectionPool {
 
         // Build HTTP client with connection pool settings
         Client::builder(TokioExecutor::new())
-            .pool_max_idle_per_host(self.max_idle_connections)
+            .pool_max_idle_per_host(self.max_idle_connections())
             .pool_idle_timeout(self.keepalive_timeout)
-            .set_host(false) // Don't set Host header automatically, we'll do it manually
+            // Derive Host from the request URI on HTTP/1 connections;
+            // HTTP/2 carries the same authority as `:authority`
+            .set_host(true)
             .build(https_connector)
     }
 }
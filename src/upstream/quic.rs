@@ -1,106 +1,190 @@
-use crate::error::{DnsProxyError, DnsProxyResult};
-use crate::quic::client::connect_quic_upstream;
+use crate::config::AppConfig;
+use crate::dns::cache::ResponseCache;
+use crate::dns::framing::{read_framed, read_framed_limited, write_framed};
+use crate::dns::{self, edns};
+use crate::error::{DnsProxyError, DnsProxyResult, UpstreamError};
+use crate::logging::record_dnssec_ok;
+use crate::metrics::Metrics;
+use crate::quic::client::{ALPN_DOQ, connect_quic_upstream};
+use crate::upstream::circuit_breaker::record_circuit_open;
+use crate::upstream::dry_run::dry_run_response;
+use crate::upstream::pool::ConnectionPool;
+use crate::upstream::retry::{is_transient_error, with_retries};
+use crate::upstream::timeout::with_timeout;
 use bytes::Bytes;
 use quinn::{Connection, RecvStream, SendStream};
 use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::AsyncRead;
+use tracing::debug;
 
 /// Forward DNS message over QUIC connection
+/// Messages are framed with the RFC 9250 2-byte length prefix
 pub async fn forward_quic_dns(connection: &Connection, message: &[u8]) -> DnsProxyResult<Bytes> {
-    let (mut send, mut recv) = connection.open_bi().await.map_err(|e| {
-        DnsProxyError::Upstream(crate::error::UpstreamError::RequestFailed {
+    let request_failed = |reason: String| {
+        DnsProxyError::Upstream(UpstreamError::RequestFailed {
             upstream: connection.remote_address().to_string(),
-            reason: format!("Failed to open bidirectional stream: {}", e),
+            reason,
         })
-    })?;
+    };
+
+    let (mut send, mut recv) = connection
+        .open_bi()
+        .await
+        .map_err(|e| request_failed(format!("Failed to open bidirectional stream: {}", e)))?;
 
     // Send DNS message to upstream
-    send.write_all(message).await.map_err(|e| {
-        DnsProxyError::Upstream(crate::error::UpstreamError::RequestFailed {
-            upstream: connection.remote_address().to_string(),
-            reason: format!("Failed to write to upstream: {}", e),
-        })
-    })?;
-    send.finish().map_err(|e| {
-        DnsProxyError::Upstream(crate::error::UpstreamError::RequestFailed {
-            upstream: connection.remote_address().to_string(),
-            reason: format!("Failed to finish upstream stream: {}", e),
-        })
-    })?;
+    write_framed(&mut send, message)
+        .await
+        .map_err(|e| request_failed(format!("Failed to write to upstream: {}", e)))?;
+    send.finish()
+        .map_err(|e| request_failed(format!("Failed to finish upstream stream: {}", e)))?;
 
     // Read response from upstream
-    let mut response = Vec::with_capacity(4096);
-    loop {
-        let mut chunk = vec![0u8; 4096];
-        match recv.read(&mut chunk).await {
-            Ok(Some(n)) => {
-                if n > 0 {
-                    response.extend_from_slice(&chunk[..n]);
-                } else {
-                    break;
-                }
-            }
-            Ok(None) => break,
-            Err(e) => {
-                return Err(DnsProxyError::Upstream(
-                    crate::error::UpstreamError::RequestFailed {
-                        upstream: connection.remote_address().to_string(),
-                        reason: format!("Failed to read from upstream: {}", e),
-                    },
-                ));
-            }
+    let response = read_framed(&mut recv)
+        .await
+        .map_err(|e| request_failed(format!("Failed to read from upstream: {}", e)))?
+        .ok_or_else(|| request_failed("Upstream closed stream without response".to_string()))?;
+
+    Ok(Bytes::from(response))
+}
+
+/// Forward a single DNS message to a DoQ upstream over a new connection
+pub async fn forward_doq_dns(
+    upstream: SocketAddr,
+    server_name: &str,
+    message: &[u8],
+) -> DnsProxyResult<Bytes> {
+    let connection = connect_quic_upstream(upstream, server_name, ALPN_DOQ, Arc::default())
+        .await
+        .map_err(|e| {
+            DnsProxyError::Upstream(UpstreamError::ConnectionFailed {
+                upstream: upstream.to_string(),
+                reason: e.to_string(),
+            })
+        })?;
+    forward_quic_dns(&connection, message).await
+}
+
+/// Read a complete length-prefixed DNS message from a client QUIC stream
+///
+/// The message may arrive across several reads; it is reassembled before being
+/// returned. A stream that finishes without sending anything yields an empty
+/// message, while one that closes part way through is an error so a truncated
+/// query is never forwarded. A message longer than `max_message_size` is
+/// rejected with [`DnsProxyError::MessageTooLarge`].
+pub async fn read_quic_stream<R>(
+    client_recv: &mut R,
+    max_message_size: usize,
+) -> DnsProxyResult<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    match read_framed_limited(client_recv, max_message_size).await {
+        Ok(Some(message)) => Ok(message),
+        Ok(None) => Ok(Vec::new()),
+        Err(DnsProxyError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Err(DnsProxyError::Protocol(
+                "DoQ stream closed before the full DNS message was received".to_string(),
+            ))
         }
+        Err(e @ DnsProxyError::MessageTooLarge { .. }) => Err(e),
+        Err(e) => Err(DnsProxyError::Protocol(format!(
+            "Failed to read from client: {}",
+            e
+        ))),
     }
+}
 
-    Ok(Bytes::from(response))
+/// REFUSED answer to a message that arrived as 0-RTT data on `client_recv`
+/// but is not safe to replay (see [`dns::is_replayable`])
+pub fn refuse_early_data(client_recv: &RecvStream, message: &[u8]) -> Option<Vec<u8>> {
+    if !client_recv.is_0rtt() || dns::is_replayable(message) {
+        return None;
+    }
+    debug!("Refusing non-replayable message received as 0-RTT data");
+    Some(dns::refused_response(message))
+}
+
+/// Send a length-prefixed DNS response back on a client QUIC stream and finish the stream
+pub async fn write_quic_stream(
+    client_send: &mut SendStream,
+    response: &[u8],
+) -> DnsProxyResult<()> {
+    write_framed(client_send, response)
+        .await
+        .map_err(|e| DnsProxyError::Protocol(format!("Failed to write to client: {}", e)))?;
+    client_send
+        .finish()
+        .map_err(|e| DnsProxyError::Protocol(format!("Failed to finish client stream: {}", e)))?;
+    Ok(())
 }
 
 /// Forward DNS message between two QUIC streams (zerocopy where possible)
+///
+/// Returns the size of the client's query and the response sent back (a
+/// FORMERR when the query is shorter than a DNS header).
 pub async fn forward_quic_stream(
     mut client_send: SendStream,
     mut client_recv: RecvStream,
     upstream_addr: SocketAddr,
-    server_name: &str,
-) -> DnsProxyResult<()> {
+    config: &AppConfig,
+    pool: &ConnectionPool,
+    cache: &ResponseCache,
+    metrics: &Metrics,
+) -> DnsProxyResult<(usize, Bytes)> {
     // Read DNS message from client
-    let mut buffer = Vec::with_capacity(4096);
-    loop {
-        let mut chunk = vec![0u8; 4096];
-        match client_recv.read(&mut chunk).await {
-            Ok(Some(n)) => {
-                if n > 0 {
-                    buffer.extend_from_slice(&chunk[..n]);
-                } else {
-                    break;
-                }
-            }
-            Ok(None) => break,
-            Err(e) => {
-                return Err(DnsProxyError::Protocol(format!(
-                    "Failed to read from client: {}",
-                    e
-                )));
-            }
-        }
+    let buffer = read_quic_stream(&mut client_recv, config.servers.max_message_size).await?;
+
+    if let Err(e) = dns::validate_message(&buffer) {
+        debug!("Answering malformed DoQ query with FORMERR: {}", e);
+        let formerr = dns::formerr_response(&buffer);
+        write_quic_stream(&mut client_send, &formerr).await?;
+        return Ok((buffer.len(), Bytes::from(formerr)));
     }
+    record_dnssec_ok(&buffer);
 
-    if buffer.is_empty() {
-        return Ok(());
+    if let Some(refused) = refuse_early_data(&client_recv, &buffer) {
+        write_quic_stream(&mut client_send, &refused).await?;
+        return Ok((buffer.len(), Bytes::from(refused)));
+    }
+    if let Some(refused) = dns::refuse_if_denied(&buffer, &config.filter) {
+        metrics.record_blocked_request();
+        write_quic_stream(&mut client_send, &refused).await?;
+        return Ok((buffer.len(), Bytes::from(refused)));
     }
 
-    // Connect to upstream
-    let upstream_conn = connect_quic_upstream(upstream_addr, server_name).await?;
+    if config.proxy.dry_run {
+        let response = dry_run_response(&buffer, upstream_addr, metrics);
+        write_quic_stream(&mut client_send, &response).await?;
+        return Ok((buffer.len(), response));
+    }
 
-    // Forward message
-    let response = forward_quic_dns(&upstream_conn, &buffer).await?;
+    // Forward on the pooled upstream connection, retrying failures on a new
+    // one; its TLS server name is the upstream's IP address
+    let query = edns::rewrite_query(&buffer, &config.edns);
+    let upstream = upstream_addr.to_string();
+    let server_name = upstream_addr.ip().to_string();
+    let forward = with_retries(
+        config.upstream.max_retries,
+        &upstream,
+        metrics,
+        is_transient_error,
+        || {
+            with_timeout(config.upstream.timeout(), &upstream, async {
+                pool.quic()
+                    .forward(upstream_addr, &server_name, &query, &config.upstream)
+                    .await
+                    .inspect(|response| {
+                        metrics.record_upstream_bytes(query.len() as u64, response.len() as u64)
+                    })
+                    .inspect_err(|e| record_circuit_open(e, metrics))
+            })
+        },
+    );
+    let response = cache.get_or_forward(&buffer, metrics, forward).await?;
 
     // Send response back to client
-    client_send
-        .write_all(&response)
-        .await
-        .map_err(|e| DnsProxyError::Protocol(format!("Failed to write to client: {}", e)))?;
-    client_send
-        .finish()
-        .map_err(|e| DnsProxyError::Protocol(format!("Failed to finish client stream: {}", e)))?;
-
-    Ok(())
+    write_quic_stream(&mut client_send, &response).await?;
+    Ok((buffer.len(), response))
 }
@@ -0,0 +1,211 @@
+//! Pooled QUIC connections to DoQ upstreams
+//!
+//! A QUIC connection multiplexes many streams, so queries to the same upstream
+//! each open a new bidirectional stream on a cached connection instead of
+//! paying for a QUIC and TLS handshake per query. Up to `max_per_upstream`
+//! connections are opened per upstream and queries are spread across them. A
+//! connection that has closed (the upstream went away, it sat idle past the
+//! QUIC idle timeout, or a path migration failed) or that failed a query is
+//! replaced on the next query.
+//!
+//! Queries to an upstream whose circuit is open fail without connecting (see
+//! [`CircuitBreaker`]).
+
+use crate::config::{DoqPoolConfig, UpstreamConfig};
+use crate::error::{DnsProxyError, DnsProxyResult, UpstreamError};
+use crate::quic::client::{ALPN_DOQ, connect_quic_with_tls, upstream_transport_config};
+use crate::upstream::circuit_breaker::CircuitBreaker;
+use crate::upstream::quic::forward_quic_dns;
+use crate::upstream::tls::SharedClientConfig;
+use bytes::Bytes;
+use dashmap::DashMap;
+use quinn::{Connection, TransportConfig};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tracing::debug;
+
+/// Live QUIC connections keyed by upstream address and TLS server name
+pub struct QuicConnectionPool {
+    connections: DashMap<(SocketAddr, String), Vec<Connection>>,
+    /// Connections opened per upstream
+    max_per_upstream: usize,
+    /// Idle timeout and keep-alive of new connections
+    transport: Arc<TransportConfig>,
+    idle_timeout: Duration,
+    /// Round-robin position for picking among an upstream's connections
+    next: AtomicUsize,
+    circuit_breaker: Arc<CircuitBreaker>,
+    /// Verifies the upstreams of every new connection
+    tls: SharedClientConfig,
+}
+
+impl QuicConnectionPool {
+    /// Create a pool with the default `[upstream.doq_pool]` settings
+    pub fn new() -> Self {
+        Self::from_config(&DoqPoolConfig::default())
+    }
+
+    /// Create a pool from the `[upstream.doq_pool]` config section
+    pub fn from_config(config: &DoqPoolConfig) -> Self {
+        Self {
+            connections: DashMap::new(),
+            max_per_upstream: config.max_per_upstream.max(1),
+            transport: Arc::new(upstream_transport_config(config)),
+            idle_timeout: config.idle_timeout(),
+            next: AtomicUsize::new(0),
+            circuit_breaker: Arc::new(CircuitBreaker::disabled()),
+            tls: SharedClientConfig::default(),
+        }
+    }
+
+    /// Stop forwarding to failing upstreams with `circuit_breaker`
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Arc<CircuitBreaker>) -> Self {
+        self.circuit_breaker = circuit_breaker;
+        self
+    }
+
+    /// Verify upstreams with `tls_config` instead of building a config from
+    /// the `UpstreamConfig` of the first connection
+    pub fn with_tls_config(mut self, tls_config: Arc<rustls::ClientConfig>) -> Self {
+        self.tls = SharedClientConfig::new(tls_config);
+        self
+    }
+
+    /// TLS config new connections verify upstreams with
+    pub fn tls_config(&self, config: &UpstreamConfig) -> DnsProxyResult<Arc<rustls::ClientConfig>> {
+        self.tls.get_or_create(config)
+    }
+
+    /// QUIC idle timeout of pooled connections
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Connections opened per upstream
+    pub fn max_per_upstream(&self) -> usize {
+        self.max_per_upstream
+    }
+
+    /// Get a pooled connection to an upstream, connecting while it has fewer
+    /// than `max_per_upstream` open ones
+    pub async fn get(
+        &self,
+        upstream: SocketAddr,
+        server_name: &str,
+        config: &UpstreamConfig,
+    ) -> DnsProxyResult<Connection> {
+        let key = (upstream, server_name.to_string());
+        if let Some(mut connections) = self.connections.get_mut(&key) {
+            connections.retain(|connection| {
+                let open = connection.close_reason().is_none();
+                if !open {
+                    debug!(
+                        "Pooled QUIC connection to {} closed: {:?}",
+                        upstream,
+                        connection.close_reason()
+                    );
+                }
+                open
+            });
+            if connections.len() >= self.max_per_upstream() {
+                let index = self.next.fetch_add(1, Ordering::Relaxed) % connections.len();
+                return Ok(connections[index].clone());
+            }
+        }
+
+        debug!("Opening QUIC connection to {} ({})", upstream, server_name);
+        let client_crypto = self.tls_config(config)?.as_ref().clone();
+        let connection = connect_quic_with_tls(
+            upstream,
+            server_name,
+            client_crypto,
+            ALPN_DOQ,
+            Arc::clone(&self.transport),
+        )
+        .await
+        .map_err(|e| {
+            DnsProxyError::Upstream(UpstreamError::ConnectionFailed {
+                upstream: upstream.to_string(),
+                reason: e.to_string(),
+            })
+        })?;
+
+        // Concurrent queries may have connected too; the oldest connections
+        // beyond the limit leave the pool and close once their queries are done
+        let mut connections = self.connections.entry(key).or_default();
+        connections.push(connection.clone());
+        let excess = connections.len().saturating_sub(self.max_per_upstream());
+        connections.drain(..excess);
+        Ok(connection)
+    }
+
+    /// Forward a DNS message on a new stream of a pooled connection
+    ///
+    /// A failed query evicts the connection it used, so the retry reconnects.
+    pub async fn forward(
+        &self,
+        upstream: SocketAddr,
+        server_name: &str,
+        message: &[u8],
+        config: &UpstreamConfig,
+    ) -> DnsProxyResult<Bytes> {
+        let attempt = self
+            .circuit_breaker
+            .start(&upstream.to_string(), server_name)?;
+        let result = self.exchange(upstream, server_name, message, config).await;
+        attempt.finish(result.is_ok());
+        result
+    }
+
+    /// Forward a DNS message on a pooled connection, evicting it on failure
+    async fn exchange(
+        &self,
+        upstream: SocketAddr,
+        server_name: &str,
+        message: &[u8],
+        config: &UpstreamConfig,
+    ) -> DnsProxyResult<Bytes> {
+        let connection = self.get(upstream, server_name, config).await?;
+        let result = forward_quic_dns(&connection, message).await;
+        if result.is_err()
+            && let Some(mut connections) = self
+                .connections
+                .get_mut(&(upstream, server_name.to_string()))
+        {
+            connections.retain(|pooled| pooled.stable_id() != connection.stable_id());
+        }
+        result
+    }
+
+    /// Number of pooled connections
+    pub fn len(&self) -> usize {
+        self.connections
+            .iter()
+            .map(|connections| connections.len())
+            .sum()
+    }
+
+    /// Whether the pool holds no connections
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop connections that have closed
+    /// Returns the number of evicted connections
+    pub fn evict_closed(&self) -> usize {
+        let before = self.len();
+        self.connections.retain(|_, connections| {
+            connections.retain(|connection| connection.close_reason().is_none());
+            !connections.is_empty()
+        });
+        before.saturating_sub(self.len())
+    }
+}
+
+impl Default for QuicConnectionPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
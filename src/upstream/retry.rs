@@ -0,0 +1,64 @@
+use crate::error::{DnsProxyError, DnsProxyResult, UpstreamError};
+use crate::metrics::Metrics;
+use crate::utils::BackoffCounter;
+use std::future::Future;
+use tracing::warn;
+
+/// Delay before the first retry in milliseconds
+const RETRY_BASE_DELAY_MS: u64 = 50;
+
+/// Maximum delay between retries in milliseconds
+const RETRY_MAX_DELAY_MS: u64 = 1000;
+
+/// Fraction of each retry delay randomized so concurrent retries spread out
+const RETRY_JITTER: f64 = 0.5;
+
+/// Run an upstream operation, retrying up to `max_retries` times while
+/// `is_transient` reports the result as a transient failure
+///
+/// Attempts are spaced with jittered exponential backoff and every retry is recorded
+/// in the `upstream_retries` metric.
+pub async fn with_retries<T, E, F, Fut>(
+    max_retries: u32,
+    upstream: &str,
+    metrics: &Metrics,
+    is_transient: impl Fn(&Result<T, E>) -> bool,
+    mut operation: F,
+) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let backoff = BackoffCounter::new();
+    let mut retries = 0;
+
+    loop {
+        let result = operation().await;
+        if retries >= max_retries || !is_transient(&result) {
+            return result;
+        }
+
+        retries += 1;
+        metrics.record_upstream_retry();
+        let delay =
+            backoff.next_delay_with_jitter(RETRY_BASE_DELAY_MS, RETRY_MAX_DELAY_MS, RETRY_JITTER);
+        warn!(
+            "Transient failure from upstream {}, retrying in {:?} (retry {}/{})",
+            upstream, delay, retries, max_retries
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Whether a DNS forwarding result failed with a connection-level error worth retrying
+///
+/// Timeouts are not retried; the configured timeout already bounds how long
+/// the client waits for an answer.
+pub fn is_transient_error<T>(result: &DnsProxyResult<T>) -> bool {
+    matches!(
+        result,
+        Err(DnsProxyError::Upstream(
+            UpstreamError::ConnectionFailed { .. }
+        )) | Err(DnsProxyError::Io(_))
+    )
+}
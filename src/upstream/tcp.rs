@@ -0,0 +1,35 @@
+use crate::dns::framing::{read_framed, write_framed};
+use crate::error::{DnsProxyError, DnsProxyResult, UpstreamError};
+use bytes::Bytes;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// Forward a single DNS message to a plain TCP upstream and return its response
+/// Messages are framed with the 2-byte length prefix (RFC 1035 section 4.2.2)
+pub async fn forward_tcp_dns(upstream: SocketAddr, message: &[u8]) -> DnsProxyResult<Bytes> {
+    let connection_failed = |reason: String| {
+        DnsProxyError::Upstream(UpstreamError::ConnectionFailed {
+            upstream: upstream.to_string(),
+            reason,
+        })
+    };
+
+    let mut stream = TcpStream::connect(upstream)
+        .await
+        .map_err(|e| connection_failed(format!("Failed to connect: {}", e)))?;
+    write_framed(&mut stream, message)
+        .await
+        .map_err(|e| connection_failed(format!("Failed to write to upstream: {}", e)))?;
+    let response = read_framed(&mut stream)
+        .await
+        .map_err(|e| connection_failed(format!("Failed to read from upstream: {}", e)))?
+        .ok_or_else(|| connection_failed("Upstream closed connection without response".into()))?;
+
+    debug!(
+        "Received TCP response from {}: {} bytes",
+        upstream,
+        response.len()
+    );
+    Ok(Bytes::from(response))
+}
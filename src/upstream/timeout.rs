@@ -0,0 +1,29 @@
+use crate::error::{DnsProxyError, DnsProxyResult, UpstreamError};
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+/// Run an upstream operation, failing with `UpstreamError::Timeout` if it
+/// does not complete within `timeout`
+pub async fn with_timeout<T, F>(
+    timeout: Duration,
+    upstream: &str,
+    operation: F,
+) -> DnsProxyResult<T>
+where
+    F: Future<Output = DnsProxyResult<T>>,
+{
+    match tokio::time::timeout(timeout, operation).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!(
+                "Upstream request to {} timed out after {:?}",
+                upstream, timeout
+            );
+            Err(DnsProxyError::Upstream(UpstreamError::Timeout {
+                upstream: upstream.to_string(),
+                timeout_ms: timeout.as_millis() as u64,
+            }))
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use crate::config::UpstreamConfig;
+use crate::dns::framing::{read_framed, write_framed};
+use crate::error::{CertificateError, DnsProxyError, DnsProxyResult, UpstreamError};
+use bytes::Bytes;
+use rustls::DigitallySignedStruct;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use std::net::SocketAddr;
+use std::sync::{Arc, OnceLock};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tracing::debug;
+
+/// Create TLS client configuration for upstream connections
+/// Uses system root certificates plus `upstream.upstream_ca_file` for proper
+/// TLS verification, unless `upstream.danger_accept_invalid_certs` is set
+pub fn create_client_config(upstream: &UpstreamConfig) -> DnsProxyResult<rustls::ClientConfig> {
+    if upstream.danger_accept_invalid_certs {
+        return Ok(rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptInvalidCerts::new()))
+            .with_no_client_auth());
+    }
+
+    let mut root_store = rustls::RootCertStore::empty();
+
+    // Load system root certificates, skipping any this rustls can't parse
+    let cert_result = rustls_native_certs::load_native_certs();
+    let (_, ignored) = root_store.add_parsable_certificates(cert_result.certs);
+    if ignored > 0 {
+        debug!("Ignored {} unparsable system root certificate(s)", ignored);
+    }
+
+    if let Some(ca_file) = &upstream.upstream_ca_file {
+        let ca_bytes = std::fs::read(ca_file).map_err(|e| {
+            DnsProxyError::Certificate(CertificateError::LoadFailed {
+                path: ca_file.clone(),
+                reason: format!("Failed to read: {}", e),
+            })
+        })?;
+        for cert in rustls_pemfile::certs(&mut ca_bytes.as_slice()) {
+            let added = cert
+                .map_err(|e| e.to_string())
+                .and_then(|cert| root_store.add(cert).map_err(|e| e.to_string()));
+            added.map_err(|reason| {
+                DnsProxyError::Certificate(CertificateError::LoadFailed {
+                    path: ca_file.clone(),
+                    reason: format!("Failed to add CA certificate: {}", reason),
+                })
+            })?;
+        }
+    }
+
+    Ok(rustls::ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_no_client_auth())
+}
+
+/// Upstream TLS client config shared by every connection of a pool
+///
+/// Loading the system roots and `upstream.upstream_ca_file` is blocking I/O,
+/// so it happens once: when the pool is created, or on the first connection
+/// of a pool created without a config.
+#[derive(Default)]
+pub struct SharedClientConfig {
+    config: OnceLock<Arc<rustls::ClientConfig>>,
+}
+
+impl SharedClientConfig {
+    /// Share an already built config
+    pub fn new(config: Arc<rustls::ClientConfig>) -> Self {
+        Self {
+            config: OnceLock::from(config),
+        }
+    }
+
+    /// The shared config, built from `upstream` if there is none yet
+    pub fn get_or_create(
+        &self,
+        upstream: &UpstreamConfig,
+    ) -> DnsProxyResult<Arc<rustls::ClientConfig>> {
+        if let Some(config) = self.config.get() {
+            return Ok(Arc::clone(config));
+        }
+        let config = Arc::new(create_client_config(upstream)?);
+        Ok(Arc::clone(self.config.get_or_init(|| config)))
+    }
+}
+
+/// Certificate verifier for `upstream.danger_accept_invalid_certs`: accepts
+/// any certificate but still checks handshake signatures
+#[derive(Debug)]
+struct AcceptInvalidCerts {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl AcceptInvalidCerts {
+    fn new() -> Self {
+        Self {
+            algorithms: rustls::crypto::aws_lc_rs::default_provider()
+                .signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for AcceptInvalidCerts {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Open a TLS connection to a DoT upstream
+pub async fn connect_dot_upstream(
+    upstream: SocketAddr,
+    server_name: &str,
+    config: &UpstreamConfig,
+) -> DnsProxyResult<TlsStream<TcpStream>> {
+    let client_config = Arc::new(create_client_config(config)?);
+    connect_dot_upstream_with_tls(upstream, server_name, client_config).await
+}
+
+/// Open a TLS connection to a DoT upstream that verifies it with
+/// `client_config`
+pub async fn connect_dot_upstream_with_tls(
+    upstream: SocketAddr,
+    server_name: &str,
+    client_config: Arc<rustls::ClientConfig>,
+) -> DnsProxyResult<TlsStream<TcpStream>> {
+    let stream = TcpStream::connect(upstream).await.map_err(|e| {
+        DnsProxyError::Upstream(UpstreamError::ConnectionFailed {
+            upstream: upstream.to_string(),
+            reason: format!("Failed to connect: {}", e),
+        })
+    })?;
+
+    let connector = TlsConnector::from(client_config);
+    let sni_name = ServerName::try_from(server_name.to_string()).map_err(|e| {
+        DnsProxyError::InvalidInput(format!(
+            "Failed to create ServerName for upstream connection: {}",
+            e
+        ))
+    })?;
+
+    connector.connect(sni_name, stream).await.map_err(|e| {
+        DnsProxyError::Upstream(UpstreamError::ConnectionFailed {
+            upstream: upstream.to_string(),
+            reason: format!("Failed to establish TLS connection: {}", e),
+        })
+    })
+}
+
+/// Forward a single DNS message to a DoT upstream and return its response
+/// Messages are framed with the RFC 7858 2-byte length prefix
+pub async fn forward_dot_dns(
+    upstream: SocketAddr,
+    server_name: &str,
+    message: &[u8],
+    config: &UpstreamConfig,
+) -> DnsProxyResult<Bytes> {
+    let mut tls = connect_dot_upstream(upstream, server_name, config).await?;
+    exchange_dot_dns(&mut tls, upstream, message).await
+}
+
+/// Send a DNS message on an open DoT upstream connection and read its response
+pub async fn exchange_dot_dns(
+    tls: &mut TlsStream<TcpStream>,
+    upstream: SocketAddr,
+    message: &[u8],
+) -> DnsProxyResult<Bytes> {
+    let request_failed = |reason: String| {
+        DnsProxyError::Upstream(UpstreamError::RequestFailed {
+            upstream: upstream.to_string(),
+            reason,
+        })
+    };
+
+    write_framed(tls, message)
+        .await
+        .map_err(|e| request_failed(format!("Failed to write to upstream: {}", e)))?;
+
+    let response = read_framed(tls)
+        .await
+        .map_err(|e| request_failed(format!("Failed to read from upstream: {}", e)))?
+        .ok_or_else(|| request_failed("Upstream closed connection without response".into()))?;
+
+    debug!(
+        "Received DoT response from {}: {} bytes",
+        upstream,
+        response.len()
+    );
+    Ok(Bytes::from(response))
+}
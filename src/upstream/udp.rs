@@ -0,0 +1,62 @@
+use crate::dns::FLAG_TC;
+use crate::error::{DnsProxyError, DnsProxyResult, UpstreamError};
+use crate::upstream::tcp::forward_tcp_dns;
+use bytes::Bytes;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+/// Largest DNS message that fits in a UDP datagram
+pub const MAX_UDP_MESSAGE_SIZE: usize = 65535;
+
+/// Forward a DNS message to a plain UDP upstream and return its response
+///
+/// Each query uses a fresh ephemeral socket connected to the upstream, so only
+/// datagrams from that address are accepted. Datagrams whose ID doesn't match
+/// the query are ignored. A truncated (TC) answer is retried over TCP so the
+/// caller always gets the full response. The caller bounds the wait with a timeout.
+pub async fn forward_udp_dns(upstream: SocketAddr, message: &[u8]) -> DnsProxyResult<Bytes> {
+    let connection_failed = |reason: String| {
+        DnsProxyError::Upstream(UpstreamError::ConnectionFailed {
+            upstream: upstream.to_string(),
+            reason,
+        })
+    };
+
+    let bind_addr: SocketAddr = if upstream.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind_addr)
+        .await
+        .map_err(|e| connection_failed(format!("Failed to bind UDP socket: {}", e)))?;
+    socket
+        .connect(upstream)
+        .await
+        .map_err(|e| connection_failed(format!("Failed to connect UDP socket: {}", e)))?;
+    socket
+        .send(message)
+        .await
+        .map_err(|e| connection_failed(format!("Failed to send query: {}", e)))?;
+
+    let mut buf = vec![0u8; MAX_UDP_MESSAGE_SIZE];
+    loop {
+        let len = socket
+            .recv(&mut buf)
+            .await
+            .map_err(|e| connection_failed(format!("Failed to receive response: {}", e)))?;
+        if len >= 3 && message.len() >= 2 && buf[..2] == message[..2] {
+            debug!("Received UDP response from {}: {} bytes", upstream, len);
+            if buf[2] & FLAG_TC != 0 {
+                debug!(
+                    "UDP response from {} truncated, retrying over TCP",
+                    upstream
+                );
+                return forward_tcp_dns(upstream, message).await;
+            }
+            return Ok(Bytes::copy_from_slice(&buf[..len]));
+        }
+        debug!("Ignoring UDP datagram from {} with mismatched ID", upstream);
+    }
+}
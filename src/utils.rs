@@ -3,3 +3,6 @@
 //! Contains exponential backoff utilities and other helper functions.
 
 pub mod backoff;
+
+// Backoff helpers are also reachable as `utils::BackoffCounter` etc.
+pub use backoff::*;
@@ -2,6 +2,8 @@
 //!
 //! Provides thread-safe backoff counting and delay calculation for retry logic.
 
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::sync::atomic::{AtomicU32, Ordering};
 
 /// Calculate exponential backoff delay for a given attempt number.
@@ -36,6 +38,41 @@ pub fn exponential_backoff(
     std::time::Duration::from_millis(delay_ms)
 }
 
+/// Calculate an exponential backoff delay with random jitter applied.
+///
+/// The delay is computed as in [`exponential_backoff`], then reduced by a
+/// random amount of up to `jitter` (a fraction between 0.0 and 1.0) of itself,
+/// so concurrent clients retrying the same failure don't wake up in lockstep.
+/// A `jitter` of 0.0 returns the plain exponential delay.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+/// use dns_ingress::utils::backoff::exponential_backoff_with_jitter;
+///
+/// let delay = exponential_backoff_with_jitter(3, 100, 5000, 0.5); // 400ms..=800ms
+/// assert!(delay >= Duration::from_millis(400) && delay <= Duration::from_millis(800));
+/// ```
+pub fn exponential_backoff_with_jitter(
+    attempt: u32,
+    base_delay_ms: u64,
+    max_delay_ms: u64,
+    jitter: f64,
+) -> std::time::Duration {
+    let delay = exponential_backoff(attempt, base_delay_ms, max_delay_ms);
+    let jitter = jitter.clamp(0.0, 1.0);
+    if jitter == 0.0 {
+        return delay;
+    }
+
+    // RandomState is seeded per instance, which is enough randomness to
+    // spread retries without pulling in an RNG dependency
+    let random = RandomState::new().build_hasher().finish();
+    let fraction = (random as f64 / u64::MAX as f64) * jitter;
+    delay.mul_f64(1.0 - fraction)
+}
+
 /// Thread-safe exponential backoff counter.
 ///
 /// Automatically tracks retry attempts and calculates delays using
@@ -89,4 +126,21 @@ impl BackoffCounter {
         }
         delay
     }
+
+    /// Like [`BackoffCounter::next_delay`], with random jitter applied.
+    ///
+    /// See [`exponential_backoff_with_jitter`] for the meaning of `jitter`.
+    pub fn next_delay_with_jitter(
+        &self,
+        base_delay_ms: u64,
+        max_delay_ms: u64,
+        jitter: f64,
+    ) -> std::time::Duration {
+        let attempt = self.counter.fetch_add(1, Ordering::Relaxed);
+        let delay = exponential_backoff_with_jitter(attempt, base_delay_ms, max_delay_ms, jitter);
+        if attempt >= 10 {
+            self.counter.store(0, Ordering::Relaxed); // Reset after max attempts
+        }
+        delay
+    }
 }
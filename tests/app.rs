@@ -9,6 +9,16 @@ fn test_app_new() {
     assert!(Arc::strong_count(&app.rewriter) >= 1);
 }
 
+#[test]
+fn test_app_with_rewriter() {
+    use dns_ingress::rewrite::{SniRewriterType, create_rewriter};
+
+    let config = AppConfig::default();
+    let rewriter: SniRewriterType = create_rewriter(config.rewrite.clone());
+    let app = App::with_rewriter(config, Arc::clone(&rewriter));
+    assert!(Arc::ptr_eq(&app.rewriter, &rewriter));
+}
+
 #[tokio::test]
 async fn test_app_start_with_all_disabled() {
     let mut config = AppConfig::default();
@@ -19,12 +29,13 @@ async fn test_app_start_with_all_disabled() {
     config.servers.healthcheck.enabled = false;
 
     let mut app = App::new(config);
-    let result = app.start();
+    let result = app.start().await;
     assert!(result.is_ok());
 }
 
 #[tokio::test]
 async fn test_app_start_with_some_enabled() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
     let mut config = AppConfig::default();
     config.servers.dot.enabled = true;
     config.servers.doh.enabled = false;
@@ -33,6 +44,503 @@ async fn test_app_start_with_some_enabled() {
     config.servers.healthcheck.enabled = false;
 
     let mut app = App::new(config);
-    let result = app.start();
+    let result = app.start().await;
     assert!(result.is_ok());
 }
+
+#[tokio::test]
+async fn test_app_start_fails_on_missing_cert_file() {
+    use dns_ingress::config::CertificateConfig;
+    use dns_ingress::error::{CertificateError, DnsProxyError};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing-cert.pem");
+    let mut config = AppConfig::default();
+    config.servers.dot.enabled = true;
+    config.servers.doh.enabled = false;
+    config.servers.doq.enabled = false;
+    config.servers.doh3.enabled = false;
+    config.servers.healthcheck.enabled = false;
+    config.tls.default = Some(CertificateConfig {
+        cert_file: missing.to_string_lossy().into_owned(),
+        key_file: dir.path().join("key.pem").to_string_lossy().into_owned(),
+        key_passphrase: None,
+        ca_file: None,
+        require_client_cert: false,
+        ocsp_file: None,
+    });
+
+    let mut app = App::new(config.clone());
+    let err = app.start().await.unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            DnsProxyError::Certificate(CertificateError::FileNotFound { path })
+                if *path == missing.to_string_lossy()
+        ),
+        "{:?}",
+        err
+    );
+    assert!(err.to_string().contains("missing-cert.pem"));
+
+    // Certificates are not checked when no enabled server terminates TLS
+    config.servers.dot.enabled = false;
+    config.servers.doh.enabled = true;
+    config.servers.doh.tls = false;
+    config.servers.doh.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.doh.port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    config.validate().unwrap();
+    let mut app = App::new(config);
+    app.start().await.unwrap();
+    app.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_app_metrics_snapshot() {
+    use dns_ingress::metrics::RejectReason;
+    use std::time::Duration;
+
+    let app = App::new(AppConfig::default());
+    let metrics = app.metrics();
+    metrics.record_request(true, 100, 200, Duration::from_millis(5));
+    metrics.record_request(false, 50, 0, Duration::from_millis(5));
+    metrics.record_rewrite_hit();
+    metrics.record_upstream_error();
+    metrics.record_rejected_connection(RejectReason::HandshakeFailed);
+
+    let snapshot = app.metrics_snapshot().await;
+    assert_eq!(snapshot.total_requests, 2);
+    assert_eq!(snapshot.successful_requests, 1);
+    assert_eq!(snapshot.failed_requests, 1);
+    assert_eq!(snapshot.bytes_received, 150);
+    assert_eq!(snapshot.bytes_sent, 200);
+    assert_eq!(snapshot.rewrite_hits, 1);
+    assert_eq!(snapshot.upstream_errors, 1);
+
+    assert_eq!(metrics.total_requests(), 2);
+    assert_eq!(metrics.successful_requests(), 1);
+    assert_eq!(metrics.failed_requests(), 1);
+    assert_eq!(metrics.bytes_received(), 150);
+    assert_eq!(metrics.bytes_sent(), 200);
+    assert_eq!(metrics.rewrite_hits(), 1);
+    assert_eq!(metrics.upstream_errors(), 1);
+    assert_eq!(
+        metrics.rejected_connections(RejectReason::HandshakeFailed),
+        1
+    );
+    assert_eq!(
+        metrics.rejected_connections(RejectReason::HandshakeTimeout),
+        0
+    );
+}
+
+#[tokio::test]
+async fn test_app_answers_from_mock_upstream() {
+    use dns_ingress::testing::MockUpstream;
+    use std::time::Duration;
+
+    let (mut query, answer) = example_net_exchange();
+    let mock = Arc::new(MockUpstream::new().with_response("www.example.net", 1, answer.clone()));
+
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut config = AppConfig::default();
+    config.servers.dot.enabled = false;
+    config.servers.doh.enabled = false;
+    config.servers.doq.enabled = false;
+    config.servers.doh3.enabled = false;
+    config.servers.healthcheck.enabled = false;
+    config.servers.udp.enabled = true;
+    config.servers.udp.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.udp.port = port;
+
+    let mut app = App::new(config).with_upstream(mock.clone());
+    app.start().await.unwrap();
+
+    // The listener is bound once start returns
+    let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    query[..2].copy_from_slice(&[0xbe, 0xef]);
+    client.send_to(&query, ("127.0.0.1", port)).await.unwrap();
+    let mut buf = vec![0u8; 512];
+    let (len, _) = tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+        .await
+        .expect("UDP listener should answer")
+        .unwrap();
+    app.shutdown().await;
+
+    let response = &buf[..len];
+    assert_eq!(&response[..2], &[0xbe, 0xef]);
+    assert_eq!(&response[2..], &answer[2..]);
+    assert!(mock.query_count() >= 1);
+}
+
+/// Query for www.example.net (A) and its scripted answer, 192.0.2.1
+fn example_net_exchange() -> (Vec<u8>, Vec<u8>) {
+    let mut query = vec![0x00, 0x00, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in ["www", "example", "net"] {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[0, 0, 1, 0, 1]);
+    let mut answer = query.clone();
+    answer[2] |= 0x80;
+    answer[7] = 1; // ANCOUNT
+    answer.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]);
+    (query, answer)
+}
+
+/// Config with only the plain TCP listener enabled, on a free port
+fn tcp_only_config() -> (AppConfig, u16) {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut config = AppConfig::default();
+    config.servers.dot.enabled = false;
+    config.servers.doh.enabled = false;
+    config.servers.doq.enabled = false;
+    config.servers.doh3.enabled = false;
+    config.servers.healthcheck.enabled = false;
+    config.servers.tcp_dns.enabled = true;
+    config.servers.tcp_dns.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.tcp_dns.port = port;
+    (config, port)
+}
+
+async fn connect_with_retry(port: u16) -> tokio::net::TcpStream {
+    for _ in 0..50 {
+        if let Ok(stream) = tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+            return stream;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!("TCP listener did not come up");
+}
+
+#[tokio::test]
+async fn test_app_shutdown_drains_in_flight_request() {
+    use dns_ingress::testing::MockUpstream;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (mut query, answer) = example_net_exchange();
+    query[..2].copy_from_slice(&[0xca, 0xfe]);
+    let mock = Arc::new(
+        MockUpstream::new()
+            .with_response("www.example.net", 1, answer.clone())
+            .with_delay(Duration::from_millis(500)),
+    );
+    let (config, port) = tcp_only_config();
+    let mut app = App::new(config).with_upstream(mock.clone());
+    app.start().await.unwrap();
+
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    stream.write_u16(query.len() as u16).await.unwrap();
+    stream.write_all(&query).await.unwrap();
+    // Give the query time to reach the (slow) upstream
+    while mock.query_count() == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let client = tokio::spawn(async move {
+        let len = stream.read_u16().await.unwrap() as usize;
+        let mut response = vec![0u8; len];
+        stream.read_exact(&mut response).await.unwrap();
+        response
+    });
+
+    let started = Instant::now();
+    app.shutdown().await;
+    assert!(started.elapsed() < Duration::from_secs(5));
+
+    let response = client.await.unwrap();
+    assert_eq!(&response[..2], &[0xca, 0xfe]);
+    assert_eq!(&response[2..], &answer[2..]);
+
+    // The listener is closed once shutdown returns
+    assert!(
+        tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_app_shutdown_aborts_stragglers() {
+    use dns_ingress::testing::MockUpstream;
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (query, answer) = example_net_exchange();
+    let mock = MockUpstream::new().with_response("www.example.net", 1, answer);
+    let (config, port) = tcp_only_config();
+    let mut app = App::new(config).with_upstream(Arc::new(mock));
+    app.start().await.unwrap();
+
+    // A client that finishes one exchange, then idles without closing
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    stream.write_u16(query.len() as u16).await.unwrap();
+    stream.write_all(&query).await.unwrap();
+    let len = stream.read_u16().await.unwrap() as usize;
+    let mut response = vec![0u8; len];
+    stream.read_exact(&mut response).await.unwrap();
+
+    let started = Instant::now();
+    app.shutdown_with_timeout(Duration::from_millis(200)).await;
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(200));
+    assert!(elapsed < Duration::from_secs(5));
+
+    // The aborted connection is closed
+    let mut buf = [0u8; 1];
+    let read = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf))
+        .await
+        .expect("connection should be closed");
+    assert!(matches!(read, Ok(0) | Err(_)));
+}
+
+#[tokio::test]
+#[allow(deprecated)]
+async fn test_app_wait_for_shutdown_still_shuts_down() {
+    let (config, port) = tcp_only_config();
+    let mut app = App::new(config);
+    app.start().await.unwrap();
+
+    app.wait_for_shutdown().await;
+
+    assert!(
+        tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_err()
+    );
+}
+
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_app_shutdown_logs_drain_statistics() {
+    use std::time::Duration;
+
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (mut config, port) = tcp_only_config();
+    config.upstream.health_check_interval_secs = 0;
+    let mut app = App::new(config);
+    app.start().await.unwrap();
+
+    // Held open without a query until the drain timeout aborts it
+    let _stream = connect_with_retry(port).await;
+    let metrics = Arc::clone(app.metrics());
+    let gauge = metrics.in_flight_gauge("tcp_dns");
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while gauge.get() != 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("connection should be in flight");
+
+    app.shutdown_with_timeout(Duration::from_millis(100)).await;
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(
+        logs.contains("TCP DNS server draining 1 in-flight connection(s)"),
+        "{}",
+        logs
+    );
+    let report = logs
+        .lines()
+        .find(|line| line.contains("event=\"server_drained\"") && line.contains("TCP DNS"))
+        .unwrap_or_else(|| panic!("no drain report in {}", logs));
+    assert!(report.contains("in_flight=1"), "{}", report);
+    assert!(report.contains("drained=0"), "{}", report);
+    assert!(report.contains("aborted=1"), "{}", report);
+    assert_eq!(gauge.get(), 0);
+}
+
+#[tokio::test]
+async fn test_app_counts_active_connections() {
+    use std::time::Duration;
+
+    let (mut config, port) = tcp_only_config();
+    config.upstream.health_check_interval_secs = 0;
+    let mut app = App::new(config);
+    app.start().await.unwrap();
+    let metrics = Arc::clone(app.metrics());
+    let active = || {
+        metrics
+            .active_connections()
+            .get("tcp_dns")
+            .copied()
+            .unwrap_or(0)
+    };
+    let wait_for = |count: u64| {
+        tokio::time::timeout(Duration::from_secs(5), async move {
+            while active() != count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+    };
+
+    let mut streams = Vec::new();
+    for _ in 0..3 {
+        streams.push(connect_with_retry(port).await);
+    }
+    wait_for(3).await.expect("all connections should be active");
+    let snapshot = metrics.snapshot().await;
+    assert_eq!(snapshot.active_connections.get("tcp_dns"), Some(&3));
+    assert!(
+        metrics
+            .export_prometheus()
+            .contains("dns_proxy_in_flight_connections{server=\"tcp_dns\"} 3")
+    );
+
+    drop(streams);
+    wait_for(0)
+        .await
+        .expect("closed connections should not count");
+
+    app.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_app_records_into_injected_metrics() {
+    use dns_ingress::metrics::Metrics;
+    use std::time::Duration;
+
+    let metrics = Arc::new(Metrics::new());
+    metrics.record_rewrite_hit();
+    let (mut config, port) = tcp_only_config();
+    config.upstream.health_check_interval_secs = 0;
+    let mut app = App::new_with_metrics(config, Arc::clone(&metrics));
+    assert!(Arc::ptr_eq(&app.metrics_handle(), &metrics));
+    assert_eq!(app.metrics_snapshot().await.rewrite_hits, 1);
+
+    app.start().await.unwrap();
+    let _stream = connect_with_retry(port).await;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while metrics.active_connections().get("tcp_dns") != Some(&1) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the server should count its connection in the injected metrics");
+
+    // An embedder's exporter gathers the same families from the registry
+    assert!(
+        metrics
+            .registry()
+            .gather()
+            .iter()
+            .any(|family| family.name() == "dns_proxy_in_flight_connections")
+    );
+
+    app.shutdown().await;
+}
+
+#[tokio::test]
+async fn test_app_run_until_returns_after_signal() {
+    use std::time::Duration;
+
+    let (config, port) = tcp_only_config();
+    let (signal, received) = tokio::sync::oneshot::channel::<()>();
+    let app = App::new(config);
+    let run = tokio::spawn(app.run_until(async move {
+        let _ = received.await;
+        Ok(())
+    }));
+
+    drop(connect_with_retry(port).await);
+    signal.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), run)
+        .await
+        .expect("run_until should return after the signal")
+        .unwrap()
+        .unwrap();
+
+    // The listener is closed once run_until returns
+    assert!(
+        tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_app_run_until_shuts_down_when_signal_fails() {
+    use dns_ingress::error::DnsProxyError;
+
+    let (config, port) = tcp_only_config();
+    let result = App::new(config)
+        .run_until(async {
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            Err(std::io::Error::other("no signal handler"))
+        })
+        .await;
+
+    assert!(matches!(result, Err(DnsProxyError::Io(_))));
+    assert!(
+        tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_app_start_fails_when_port_in_use() {
+    use dns_ingress::error::DnsProxyError;
+
+    let occupied = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = occupied.local_addr().unwrap().port();
+
+    let mut config = AppConfig::default();
+    config.servers.dot.enabled = false;
+    config.servers.doh.enabled = false;
+    config.servers.doq.enabled = false;
+    config.servers.doh3.enabled = false;
+    config.servers.healthcheck.enabled = false;
+    config.servers.tcp_dns.enabled = true;
+    config.servers.tcp_dns.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.tcp_dns.port = port;
+
+    let mut app = App::new(config);
+    let err = app.start().await.unwrap_err();
+    assert!(
+        matches!(&err, DnsProxyError::AddrInUse { server, addr }
+            if server == "TCP DNS" && addr.port() == port),
+        "unexpected error: {}",
+        err
+    );
+    assert!(err.to_string().contains("address already in use"));
+}
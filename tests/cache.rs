@@ -0,0 +1,241 @@
+use bytes::Bytes;
+use dns_ingress::config::CacheConfig;
+use dns_ingress::dns::cache::ResponseCache;
+use dns_ingress::error::DnsProxyResult;
+use dns_ingress::metrics::Metrics;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+fn build_query(id: u16, qtype: u16) -> Vec<u8> {
+    let mut msg = id.to_be_bytes().to_vec();
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    msg.extend_from_slice(b"\x03www\x07example\x03com\x00");
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&[0, 1]);
+    msg
+}
+
+/// Answer `query` with one A record of the given TTL and response code
+fn build_response(query: &[u8], ttl: u32, rcode: u8) -> Bytes {
+    let mut msg = query.to_vec();
+    msg[2] |= 0x80;
+    msg[3] = (msg[3] & 0xF0) | rcode;
+    msg[7] = 1;
+    msg.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1]);
+    msg.extend_from_slice(&ttl.to_be_bytes());
+    msg.extend_from_slice(&[0, 4, 192, 0, 2, 1]);
+    Bytes::from(msg)
+}
+
+fn answer_ttl(response: &[u8]) -> u32 {
+    let end = response.len();
+    u32::from_be_bytes(response[end - 10..end - 6].try_into().unwrap())
+}
+
+fn enabled_cache() -> ResponseCache {
+    ResponseCache::from_config(&CacheConfig {
+        enabled: true,
+        ..CacheConfig::default()
+    })
+}
+
+/// Run a query through the cache, counting upstream forwards
+async fn query(
+    cache: &ResponseCache,
+    metrics: &Metrics,
+    forwards: &AtomicUsize,
+    query: &[u8],
+    ttl: u32,
+    rcode: u8,
+) -> DnsProxyResult<Bytes> {
+    cache
+        .get_or_forward(query, metrics, async {
+            forwards.fetch_add(1, Ordering::SeqCst);
+            Ok(build_response(query, ttl, rcode))
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_repeated_question_is_served_from_cache() {
+    let cache = enabled_cache();
+    let metrics = Metrics::new();
+    let forwards = AtomicUsize::new(0);
+
+    let first = query(&cache, &metrics, &forwards, &build_query(1, 1), 300, 0)
+        .await
+        .unwrap();
+    let second = query(&cache, &metrics, &forwards, &build_query(2, 1), 300, 0)
+        .await
+        .unwrap();
+
+    assert_eq!(forwards.load(Ordering::SeqCst), 1);
+    assert_eq!(&first[..2], &[0, 1]);
+    // The cached answer carries the second client's message ID
+    assert_eq!(&second[..2], &[0, 2]);
+    assert_eq!(&second[2..], &first[2..]);
+    assert_eq!(metrics.cache_misses(), 1);
+    assert_eq!(metrics.cache_hits(), 1);
+}
+
+#[tokio::test]
+async fn test_cached_ttls_count_down_and_expire() {
+    let cache = enabled_cache();
+    let metrics = Metrics::new();
+    let forwards = AtomicUsize::new(0);
+
+    query(&cache, &metrics, &forwards, &build_query(1, 1), 2, 0)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    let aged = query(&cache, &metrics, &forwards, &build_query(2, 1), 2, 0)
+        .await
+        .unwrap();
+    assert_eq!(forwards.load(Ordering::SeqCst), 1);
+    assert_eq!(answer_ttl(&aged), 1);
+
+    tokio::time::sleep(Duration::from_millis(1000)).await;
+    let refreshed = query(&cache, &metrics, &forwards, &build_query(3, 1), 2, 0)
+        .await
+        .unwrap();
+    assert_eq!(forwards.load(Ordering::SeqCst), 2);
+    assert_eq!(answer_ttl(&refreshed), 2);
+}
+
+#[tokio::test]
+async fn test_uncacheable_answers_are_not_stored() {
+    let cache = enabled_cache();
+    let metrics = Metrics::new();
+    let forwards = AtomicUsize::new(0);
+
+    // Zero TTL
+    for id in 1..=2 {
+        query(&cache, &metrics, &forwards, &build_query(id, 1), 0, 0)
+            .await
+            .unwrap();
+    }
+    assert_eq!(forwards.load(Ordering::SeqCst), 2);
+
+    // SERVFAIL
+    for id in 3..=4 {
+        query(&cache, &metrics, &forwards, &build_query(id, 28), 300, 2)
+            .await
+            .unwrap();
+    }
+    assert_eq!(forwards.load(Ordering::SeqCst), 4);
+    assert_eq!(metrics.cache_hits(), 0);
+}
+
+#[tokio::test]
+async fn test_bypassed_queries_are_not_counted() {
+    let metrics = Metrics::new();
+    let forwards = AtomicUsize::new(0);
+
+    let disabled = ResponseCache::from_config(&CacheConfig::default());
+    let bypassing = ResponseCache::from_config(&CacheConfig {
+        enabled: true,
+        no_cache_qtypes: vec!["TXT".to_string()],
+        ..CacheConfig::default()
+    });
+    for id in 1..=2 {
+        query(&disabled, &metrics, &forwards, &build_query(id, 1), 300, 0)
+            .await
+            .unwrap();
+        query(
+            &bypassing,
+            &metrics,
+            &forwards,
+            &build_query(id, 16),
+            300,
+            0,
+        )
+        .await
+        .unwrap();
+    }
+
+    assert_eq!(forwards.load(Ordering::SeqCst), 4);
+    assert_eq!(metrics.cache_hits(), 0);
+    assert_eq!(metrics.cache_misses(), 0);
+}
+
+#[tokio::test]
+async fn test_full_cache_makes_room() {
+    let cache = ResponseCache::from_config(&CacheConfig {
+        enabled: true,
+        max_entries: 1,
+        ..CacheConfig::default()
+    });
+    let metrics = Metrics::new();
+    let forwards = AtomicUsize::new(0);
+
+    query(&cache, &metrics, &forwards, &build_query(1, 1), 300, 0)
+        .await
+        .unwrap();
+    query(&cache, &metrics, &forwards, &build_query(2, 28), 300, 0)
+        .await
+        .unwrap();
+    // The AAAA answer replaced the A answer
+    query(&cache, &metrics, &forwards, &build_query(3, 28), 300, 0)
+        .await
+        .unwrap();
+    query(&cache, &metrics, &forwards, &build_query(4, 1), 300, 0)
+        .await
+        .unwrap();
+
+    assert_eq!(forwards.load(Ordering::SeqCst), 3);
+    assert_eq!(metrics.cache_hits(), 1);
+}
+
+/// [`build_query`] with an OPT record, its DO bit set when `dnssec_ok`
+fn build_edns_query(id: u16, dnssec_ok: bool) -> Vec<u8> {
+    let mut msg = build_query(id, 1);
+    msg[11] = 1;
+    msg.extend_from_slice(&[0, 0, 41, 0x04, 0xD0, 0, 0]);
+    msg.extend_from_slice(&[if dnssec_ok { 0x80 } else { 0 }, 0, 0, 0]);
+    msg
+}
+
+#[tokio::test]
+async fn test_edns_do_and_cd_get_answers_of_their_own() {
+    let cache = enabled_cache();
+    let metrics = Metrics::new();
+    let forwards = AtomicUsize::new(0);
+    let mut checking_disabled = build_query(4, 1);
+    checking_disabled[3] |= 0x10;
+    let queries = [
+        build_query(1, 1),
+        build_edns_query(2, false),
+        build_edns_query(3, true),
+        checking_disabled,
+    ];
+
+    for _ in 0..2 {
+        for q in &queries {
+            let response = query(&cache, &metrics, &forwards, q, 300, 0).await.unwrap();
+            assert_eq!(&response[..2], &q[..2]);
+        }
+    }
+
+    // Each variant was forwarded once, then answered from its own entry
+    assert_eq!(forwards.load(Ordering::SeqCst), queries.len());
+    assert_eq!(metrics.cache_hits(), queries.len() as u64);
+}
+
+#[tokio::test]
+async fn test_cached_answer_echoes_question_case() {
+    let cache = enabled_cache();
+    let metrics = Metrics::new();
+    let forwards = AtomicUsize::new(0);
+    let mut mixed_case = build_query(2, 1);
+    mixed_case[12..29].copy_from_slice(b"\x03WwW\x07ExAmPlE\x03CoM\x00");
+
+    query(&cache, &metrics, &forwards, &build_query(1, 1), 300, 0)
+        .await
+        .unwrap();
+    let response = query(&cache, &metrics, &forwards, &mixed_case, 300, 0)
+        .await
+        .unwrap();
+
+    assert_eq!(forwards.load(Ordering::SeqCst), 1);
+    assert_eq!(&response[12..33], &mixed_case[12..33]);
+}
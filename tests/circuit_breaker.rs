@@ -0,0 +1,154 @@
+use dns_ingress::config::{AppConfig, CircuitBreakerConfig};
+use dns_ingress::error::{DnsProxyError, UpstreamError};
+use dns_ingress::metrics::Metrics;
+use dns_ingress::upstream::circuit_breaker::CircuitBreaker;
+use dns_ingress::upstream::create_connection_pool;
+use dns_ingress::upstream::health::UpstreamHealth;
+use dns_ingress::upstream::ladder::UpstreamProtocol;
+use std::time::{Duration, Instant};
+
+const UPSTREAM: &str = "192.0.2.1:853";
+const TARGET: &str = "dns.example.com";
+
+fn breaker(failure_threshold: u32) -> CircuitBreaker {
+    CircuitBreaker::from_config(&CircuitBreakerConfig {
+        failure_threshold,
+        failure_window_secs: 30,
+        cooldown_secs: 10,
+    })
+}
+
+#[test]
+fn test_circuit_opens_after_failures_then_recovers() {
+    let breaker = breaker(3);
+    let start = Instant::now();
+
+    for _ in 0..2 {
+        assert!(breaker.allow(UPSTREAM, TARGET, start));
+        breaker.record(UPSTREAM, TARGET, false, start);
+    }
+    assert!(breaker.allow(UPSTREAM, TARGET, start));
+    breaker.record(UPSTREAM, TARGET, false, start);
+
+    // Open: requests fail at once until the cool-down ends
+    assert!(!breaker.allow(UPSTREAM, TARGET, start));
+    assert!(!breaker.allow(UPSTREAM, TARGET, start + Duration::from_secs(9)));
+    // Other upstreams and targets are unaffected
+    assert!(breaker.allow(UPSTREAM, "other.example.com", start));
+
+    // Half-open: only one request probes the upstream
+    let after_cooldown = start + Duration::from_secs(10);
+    assert!(breaker.allow(UPSTREAM, TARGET, after_cooldown));
+    assert!(!breaker.allow(UPSTREAM, TARGET, after_cooldown));
+
+    // The probe succeeded, so the circuit closes
+    breaker.record(UPSTREAM, TARGET, true, after_cooldown);
+    assert!(breaker.allow(UPSTREAM, TARGET, after_cooldown));
+    assert!(breaker.allow(UPSTREAM, TARGET, after_cooldown));
+}
+
+#[test]
+fn test_failed_probe_reopens_circuit() {
+    let breaker = breaker(1);
+    let start = Instant::now();
+    breaker.record(UPSTREAM, TARGET, false, start);
+    assert!(!breaker.allow(UPSTREAM, TARGET, start));
+
+    let probe = start + Duration::from_secs(10);
+    assert!(breaker.allow(UPSTREAM, TARGET, probe));
+    breaker.record(UPSTREAM, TARGET, false, probe);
+    assert!(!breaker.allow(UPSTREAM, TARGET, probe + Duration::from_secs(9)));
+    assert!(breaker.allow(UPSTREAM, TARGET, probe + Duration::from_secs(10)));
+}
+
+#[test]
+fn test_only_failures_in_a_row_within_window_open_circuit() {
+    let breaker = breaker(2);
+    let start = Instant::now();
+
+    // A success in between starts the count over
+    breaker.record(UPSTREAM, TARGET, false, start);
+    breaker.record(UPSTREAM, TARGET, true, start);
+    breaker.record(UPSTREAM, TARGET, false, start);
+    assert!(breaker.allow(UPSTREAM, TARGET, start));
+
+    // So does a failure after the window of the first one
+    let later = start + Duration::from_secs(30);
+    breaker.record(UPSTREAM, TARGET, false, later);
+    assert!(breaker.allow(UPSTREAM, TARGET, later));
+
+    breaker.record(UPSTREAM, TARGET, false, later);
+    assert!(!breaker.allow(UPSTREAM, TARGET, later));
+}
+
+#[test]
+fn test_disabled_breaker_never_opens() {
+    let breaker = CircuitBreaker::disabled();
+    let now = Instant::now();
+    for _ in 0..100 {
+        breaker.record(UPSTREAM, TARGET, false, now);
+    }
+    assert!(breaker.allow(UPSTREAM, TARGET, now));
+}
+
+#[test]
+fn test_dropped_attempt_counts_as_failure() {
+    let breaker = breaker(1);
+    drop(breaker.start(UPSTREAM, TARGET).unwrap());
+    assert!(breaker.start(UPSTREAM, TARGET).is_err());
+}
+
+#[tokio::test]
+async fn test_open_circuit_stops_forwarding_to_failing_dot_upstream() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    // Nothing listens on the port once the listener is dropped
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = listener.local_addr().unwrap();
+    drop(listener);
+
+    let mut config = AppConfig::default();
+    config.upstream.dot = vec![upstream.to_string()];
+    config.upstream.max_retries = 0;
+    config.upstream.circuit_breaker.failure_threshold = 2;
+    let pool = create_connection_pool(&config.upstream);
+    let health = UpstreamHealth::from_config(&config);
+    let metrics = Metrics::new();
+    let query = [0x12, 0x34, 0x01, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    for _ in 0..2 {
+        let result = UpstreamProtocol::Dot
+            .forward(&config, &pool, &health, &query, &metrics)
+            .await;
+        assert!(matches!(
+            result,
+            Err(DnsProxyError::Upstream(
+                UpstreamError::ConnectionFailed { .. }
+            ))
+        ));
+    }
+    assert_eq!(metrics.circuit_open(), 0);
+
+    let result = UpstreamProtocol::Dot
+        .forward(&config, &pool, &health, &query, &metrics)
+        .await;
+    assert!(
+        matches!(&result, Err(e) if e.is_circuit_open()),
+        "{:?}",
+        result
+    );
+    assert_eq!(metrics.circuit_open(), 1);
+}
+
+#[test]
+fn test_circuit_breaker_validation() {
+    let mut config = AppConfig::default();
+    config.upstream.circuit_breaker.failure_threshold = 5;
+    config.validate().unwrap();
+
+    config.upstream.circuit_breaker.cooldown_secs = 0;
+    assert!(config.validate().is_err());
+
+    // Timings of a disabled breaker are not checked
+    config.upstream.circuit_breaker.failure_threshold = 0;
+    config.validate().unwrap();
+}
@@ -0,0 +1,72 @@
+use dns_ingress::config::AppConfig;
+use std::process::{Command, Output};
+
+/// Run the proxy binary with `args`, without environment overrides
+fn run(args: &[&str]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_dns-ingress"));
+    for (key, _) in std::env::vars().filter(|(key, _)| key.starts_with("DNS_PROXY_")) {
+        command.env_remove(key);
+    }
+    command.args(args).output().unwrap()
+}
+
+/// Write `config` as TOML to `name` in `dir`, returning its path
+fn write_config(dir: &tempfile::TempDir, name: &str, config: &AppConfig) -> String {
+    let path = dir.path().join(name);
+    std::fs::write(&path, config.to_toml().unwrap()).unwrap();
+    path.to_string_lossy().into_owned()
+}
+
+/// A valid config whose health check listens on a recognizable port
+fn valid_config() -> AppConfig {
+    let mut config = AppConfig::default();
+    config.servers.doq.enabled = false;
+    config.servers.healthcheck.port = 18999;
+    config.validate().unwrap();
+    config
+}
+
+#[test]
+fn test_validate_loads_explicit_config_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_config(&dir, "proxy.toml", &valid_config());
+
+    let output = run(&["--config", &path, "--validate"]);
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("is valid"), "{}", stdout);
+
+    // The file named with --config is the one loaded
+    let output = run(&[&format!("--config={}", path), "--print-config"]);
+    assert!(output.status.success(), "{:?}", output);
+    let printed: AppConfig = toml::from_str(&String::from_utf8_lossy(&output.stdout)).unwrap();
+    assert_eq!(printed.servers.healthcheck.port, 18999);
+}
+
+#[test]
+fn test_validate_fails_on_invalid_or_missing_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = valid_config();
+    config.servers.dot.tls = false;
+    let path = write_config(&dir, "invalid.toml", &config);
+
+    let output = run(&["--config", &path, "--validate"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("servers.dot.tls"), "{}", stderr);
+
+    // An explicitly named config file must exist
+    let missing = dir.path().join("missing.toml");
+    let output = run(&["--config", &missing.to_string_lossy(), "--validate"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_unknown_argument_is_rejected() {
+    let output = run(&["--frobnicate"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--frobnicate"));
+
+    let output = run(&["--config"]);
+    assert!(!output.status.success());
+}
@@ -61,7 +61,8 @@ doh = "https://cloudflare-dns.com/dns-query"
     let config = AppConfig::from_file(file.path()).unwrap();
     assert_eq!(config.rewrite.base_domains.len(), 2);
     assert_eq!(config.rewrite.target_suffix, ".test.cn");
-    assert_eq!(config.servers.dot.bind_address, "127.0.0.1");
+    assert_eq!(config.servers.healthcheck.ready_path, "/ready");
+    assert_eq!(config.servers.dot.bind_address, vec!["127.0.0.1"]);
     assert!(!config.servers.doh.enabled);
 }
 
@@ -72,8 +73,10 @@ fn test_tls_config_get_cert() {
     let cert_config = CertificateConfig {
         cert_file: "/path/to/cert.pem".to_string(),
         key_file: "/path/to/key.pem".to_string(),
+        key_passphrase: None,
         ca_file: None,
         require_client_cert: false,
+        ocsp_file: None,
     };
 
     tls_config
@@ -93,8 +96,10 @@ fn test_tls_config_get_cert_or_err() {
     let cert_config = CertificateConfig {
         cert_file: "/path/to/cert.pem".to_string(),
         key_file: "/path/to/key.pem".to_string(),
+        key_passphrase: None,
         ca_file: None,
         require_client_cert: false,
+        ocsp_file: None,
     };
 
     tls_config
@@ -112,8 +117,9 @@ fn test_upstream_config() {
     let dot_upstream = config.dot_upstream().unwrap();
     assert_eq!(dot_upstream.port(), 853);
 
-    let doq_upstream = config.doq_upstream().unwrap();
-    assert_eq!(doq_upstream.port(), 853);
+    let doq_upstreams = config.doq_upstreams().unwrap();
+    assert_eq!(doq_upstreams.len(), 1);
+    assert_eq!(doq_upstreams[0].port(), 853);
 }
 
 #[test]
@@ -121,3 +127,916 @@ fn test_load_or_default() {
     let config = AppConfig::load_or_default("/nonexistent/file.toml");
     assert_eq!(config.rewrite.base_domains.len(), 2);
 }
+
+#[test]
+fn test_cache_no_cache_qtypes() {
+    let cache: CacheConfig =
+        toml::from_str(r#"no_cache_qtypes = ["TXT", "SOA", "TYPE65"]"#).unwrap();
+    assert_eq!(cache.no_cache_qtype_codes().unwrap(), vec![16, 6, 65]);
+
+    let mut config = AppConfig::default();
+    config.servers.doq.enabled = false;
+    assert!(config.cache.no_cache_qtypes.is_empty());
+    config.cache.no_cache_qtypes = vec!["NOPE".to_string()];
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_cache_defaults_and_validation() {
+    let cache: CacheConfig = toml::from_str("enabled = true").unwrap();
+    assert!(cache.enabled);
+    assert_eq!(cache.max_entries, 10000);
+
+    let mut config = AppConfig::default();
+    config.servers.doq.enabled = false;
+    assert!(!config.cache.enabled);
+    config.cache.max_entries = 0;
+    assert!(config.validate().is_ok());
+    config.cache.enabled = true;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_validate_require_cookies_only_on_udp() {
+    let mut config = AppConfig::default();
+    config.servers.doq.enabled = false;
+    config.servers.udp.require_cookies = true;
+    assert!(config.validate().is_ok());
+
+    config.servers.tcp_dns.require_cookies = true;
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("require_cookies"));
+}
+
+#[test]
+fn test_validate_per_connection_limits_only_on_doh3() {
+    let mut config = AppConfig::default();
+    config.servers.doq.enabled = false;
+    config.servers.doh3.max_requests_per_connection = 100;
+    config.servers.doh3.max_concurrent_streams_per_connection = 10;
+    assert!(config.validate().is_ok());
+
+    config.servers.doh.max_concurrent_streams_per_connection = 10;
+    let err = config.validate().unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("max_concurrent_streams_per_connection")
+    );
+
+    config.servers.doh.max_concurrent_streams_per_connection = 0;
+    config.servers.doq.max_requests_per_connection = 100;
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("max_requests_per_connection"));
+}
+
+#[test]
+fn test_validate_request_body_timeout() {
+    let mut config = AppConfig::default();
+    config.servers.doq.enabled = false;
+    assert_eq!(config.servers.request_body_timeout_ms, 10000);
+    assert!(config.validate().is_ok());
+
+    config.servers.request_body_timeout_ms = 0;
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("request_body_timeout_ms"));
+}
+
+#[test]
+fn test_validate_accept_backoff() {
+    let mut config = AppConfig::default();
+    config.servers.doq.enabled = false;
+    assert_eq!(config.servers.accept_backoff_base_ms, 100);
+    assert_eq!(config.servers.accept_backoff_max_ms, 5000);
+    assert!(config.validate().is_ok());
+
+    config.servers.accept_backoff_base_ms = 0;
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("accept_backoff_base_ms"));
+
+    config.servers.accept_backoff_base_ms = 500;
+    config.servers.accept_backoff_max_ms = 100;
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("accept_backoff_max_ms"));
+}
+
+#[test]
+fn test_validate_header_limits() {
+    let mut config = AppConfig::default();
+    config.servers.doq.enabled = false;
+    assert!(config.validate().is_ok());
+
+    config.servers.max_header_count = 0;
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("max_header_count"));
+
+    config.servers.max_header_count = 64;
+    config.servers.max_header_bytes = 0;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_validate_tls_off_only_on_doh() {
+    let mut config = AppConfig::default();
+    config.servers.doq.enabled = false;
+    assert!(config.servers.doh.tls);
+    config.servers.doh.tls = false;
+    assert!(config.validate().is_ok());
+
+    config.servers.dot.tls = false;
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("servers.dot.tls"));
+}
+
+#[test]
+fn test_validate_detects_forwarding_loop() {
+    let mut config = AppConfig::default();
+    config.servers.doq.enabled = false;
+    assert!(config.validate().is_ok());
+
+    // DoT listener forwards to itself
+    config.servers.dot.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.dot.port = 8853;
+    config.upstream.dot = vec!["127.0.0.1:8853".to_string()];
+    let err = config.validate().unwrap_err();
+    assert!(err.to_string().contains("Forwarding loop"));
+
+    // Wildcard listener also accepts loopback traffic
+    config.servers.dot.bind_address = vec!["0.0.0.0".to_string()];
+    assert!(config.validate().is_err());
+
+    // DoH upstream URL pointing back at the DoH listener
+    let mut config = AppConfig::default();
+    config.servers.doq.enabled = false;
+    config.upstream.doh = vec!["https://127.0.0.1/dns-query".to_string()];
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_upstream_pool_config() {
+    let toml_content = r#"
+[rewrite]
+base_domains = ["test.com"]
+target_suffix = ".test.cn"
+
+[servers.dot]
+enabled = true
+bind_address = "127.0.0.1"
+port = 853
+
+[servers.doh]
+enabled = true
+bind_address = "0.0.0.0"
+port = 443
+
+[servers.doq]
+enabled = false
+bind_address = "0.0.0.0"
+port = 853
+
+[servers.doh3]
+enabled = false
+bind_address = "0.0.0.0"
+port = 443
+
+[upstream]
+default = "1.1.1.1:853"
+
+[upstream.pool]
+keepalive_secs = 30
+max_idle_per_host = 4
+"#;
+
+    let config: AppConfig = toml::from_str(toml_content).unwrap();
+    assert_eq!(config.upstream.pool.keepalive_secs, 30);
+    assert_eq!(config.upstream.pool.max_idle_per_host, 4);
+    // Unset fields keep their defaults
+    assert_eq!(config.upstream.pool.connect_timeout_secs, 10);
+    config.validate().unwrap();
+
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    assert_eq!(pool.max_idle_connections(), 4);
+    assert_eq!(pool.keepalive_timeout(), std::time::Duration::from_secs(30));
+
+    let mut config = config;
+    config.upstream.pool.connect_timeout_secs = 0;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_upstream_dot_and_doq_pool_config() {
+    use std::time::Duration;
+
+    let upstream = toml::from_str(
+        r#"
+default = "1.1.1.1:853"
+
+[dot_pool]
+idle_timeout_secs = 20
+max_per_upstream = 2
+
+[doq_pool]
+idle_timeout_secs = 45
+max_per_upstream = 3
+keep_alive_interval_secs = 15
+"#,
+    )
+    .unwrap();
+    let mut config = AppConfig {
+        upstream,
+        ..AppConfig::default()
+    };
+    config.validate().unwrap();
+
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    assert_eq!(pool.dot().idle_timeout(), Duration::from_secs(20));
+    assert_eq!(pool.dot().max_per_upstream(), 2);
+    assert_eq!(pool.quic().idle_timeout(), Duration::from_secs(45));
+    assert_eq!(pool.quic().max_per_upstream(), 3);
+    assert_eq!(
+        config.upstream.doq_pool.keep_alive_interval(),
+        Some(Duration::from_secs(15))
+    );
+
+    // Unset sections keep their defaults
+    let defaults = AppConfig::default().upstream;
+    assert_eq!(defaults.dot_pool.idle_timeout_secs, 30);
+    assert_eq!(defaults.dot_pool.max_per_upstream, 4);
+    assert_eq!(defaults.doq_pool.max_per_upstream, 1);
+    assert_eq!(defaults.doq_pool.keep_alive_interval(), None);
+
+    config.upstream.doq_pool.keep_alive_interval_secs = 45;
+    assert!(config.validate().is_err());
+    config.upstream.doq_pool.keep_alive_interval_secs = 0;
+    config.upstream.doq_pool.max_per_upstream = 0;
+    assert!(config.validate().is_err());
+    config.upstream.doq_pool.max_per_upstream = 1;
+    config.upstream.dot_pool.idle_timeout_secs = 0;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_validate_health_check_max_interval() {
+    let mut config = AppConfig::default();
+    config.upstream.health_check_interval_secs = 60;
+    config.upstream.health_check_max_interval_secs = 60;
+    config.validate().unwrap();
+
+    config.upstream.health_check_max_interval_secs = 30;
+    assert!(config.validate().is_err());
+
+    // Irrelevant while probing is disabled
+    config.upstream.health_check_interval_secs = 0;
+    config.validate().unwrap();
+}
+
+#[test]
+fn test_bind_address_accepts_one_or_many() {
+    let config: AppConfig = toml::from_str(
+        r#"
+[rewrite]
+base_domains = ["test.com"]
+target_suffix = ".test.cn"
+
+[servers.dot]
+enabled = true
+bind_address = ["127.0.0.1", "::1"]
+port = 853
+
+[servers.doh]
+enabled = true
+bind_address = "::"
+port = 443
+
+[servers.doq]
+enabled = false
+bind_address = "0.0.0.0"
+port = 853
+
+[servers.doh3]
+enabled = false
+bind_address = "0.0.0.0"
+port = 443
+
+[upstream]
+default = "1.1.1.1:853"
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.servers.dot.socket_addrs().unwrap(),
+        vec![
+            "127.0.0.1:853".parse::<std::net::SocketAddr>().unwrap(),
+            "[::1]:853".parse().unwrap()
+        ]
+    );
+    assert_eq!(
+        config.servers.doh.socket_addrs().unwrap(),
+        vec!["[::]:443".parse::<std::net::SocketAddr>().unwrap()]
+    );
+    config.validate().unwrap();
+
+    // IPv6 addresses may be written in brackets
+    let mut config = config;
+    config.servers.doh.bind_address = vec!["[::]".to_string()];
+    config.validate().unwrap();
+
+    // A dual-stack "::" listener also takes the IPv4 wildcard address
+    let mut conflicting = config.clone();
+    conflicting.servers.healthcheck.bind_address = vec!["0.0.0.0".to_string()];
+    conflicting.servers.healthcheck.port = 443;
+    assert!(conflicting.validate().is_err());
+
+    let mut invalid = config.clone();
+    invalid.servers.dot.bind_address.clear();
+    assert!(invalid.validate().is_err());
+
+    let mut invalid = config;
+    invalid.servers.dot.bind_address = vec!["localhost".to_string()];
+    assert!(invalid.validate().is_err());
+}
+
+#[test]
+fn test_load_requires_config_in_strict_mode() {
+    let err = AppConfig::load("/nonexistent/file.toml", true).unwrap_err();
+    assert!(format!("{:#}", err).contains("is required"));
+
+    // Lenient mode and load_or_default fall back to defaults
+    let config = AppConfig::load("/nonexistent/file.toml", false).unwrap();
+    assert_eq!(config.rewrite.base_domains.len(), 2);
+    let config = AppConfig::load_or_default("/nonexistent/file.toml");
+    assert_eq!(config.rewrite.base_domains.len(), 2);
+}
+
+#[test]
+fn test_validate_allows_tcp_and_udp_on_same_port() {
+    let mut config = AppConfig::default();
+    // DoT (TCP) and DoQ (UDP) both default to 853
+    config.validate().unwrap();
+
+    config.servers.udp.enabled = true;
+    config.servers.tcp_dns.enabled = true;
+    config.validate().unwrap();
+
+    // Two TCP listeners on one port still conflict
+    config.servers.tcp_dns.port = 853;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_validate_max_message_size() {
+    let mut config = AppConfig::default();
+    assert_eq!(config.servers.max_message_size, 65535);
+
+    config.servers.max_message_size = 512;
+    config.validate().unwrap();
+
+    // Smaller than a DNS header, or larger than a length prefix can express
+    config.servers.max_message_size = 11;
+    assert!(config.validate().is_err());
+    config.servers.max_message_size = 65536;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_validate_doh_path() {
+    let mut config = AppConfig::default();
+    assert_eq!(config.servers.doh_path, "/dns-query");
+
+    config.servers.doh_path = "/resolve".to_string();
+    config.validate().unwrap();
+
+    config.servers.doh_path = "dns-query".to_string();
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_validate_doh_upstream_path() {
+    let mut config = AppConfig::default();
+    assert_eq!(config.servers.doh_upstream_path, None);
+
+    config.servers.doh_upstream_path = Some("/dns-query".to_string());
+    config.validate().unwrap();
+
+    config.servers.doh_upstream_path = Some("dns-query".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_validate_doh_uds_path() {
+    let mut config = AppConfig::default();
+    config.servers.doh.enabled = true;
+    config.servers.doh.uds_path = Some("/run/dns-proxy/doh.sock".to_string());
+    // Either TCP or the Unix socket, not both
+    assert!(config.validate().is_err());
+
+    config.servers.doh.bind_address.clear();
+    config.servers.doh.port = 0;
+    config.validate().unwrap();
+
+    // Neither is not enough either
+    config.servers.doh.uds_path = None;
+    assert!(config.validate().is_err());
+
+    config.servers.dot.uds_path = Some("/run/dns-proxy/dot.sock".to_string());
+    config.servers.dot.bind_address.clear();
+    config.servers.dot.port = 0;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_parse_doh_uds_path_without_bind_address() {
+    let config: AppConfig = toml::from_str(
+        r#"
+[rewrite]
+base_domains = ["example.com"]
+target_suffix = ".example.cn"
+
+[servers.dot]
+enabled = false
+bind_address = "0.0.0.0"
+port = 853
+
+[servers.doh]
+enabled = true
+tls = false
+uds_path = "/run/dns-proxy/doh.sock"
+
+[servers.doq]
+enabled = false
+bind_address = "0.0.0.0"
+port = 853
+
+[servers.doh3]
+enabled = false
+bind_address = "0.0.0.0"
+port = 443
+
+[upstream]
+default = "1.1.1.1:853"
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        config.servers.doh.uds_path.as_deref(),
+        Some("/run/dns-proxy/doh.sock")
+    );
+    config.validate().unwrap();
+}
+
+#[test]
+fn test_validate_proxy_protocol() {
+    let mut config = AppConfig::default();
+    config.servers.dot.proxy_protocol = true;
+    config.servers.doh.proxy_protocol = true;
+    config.servers.tcp_dns.proxy_protocol = true;
+    config.validate().unwrap();
+
+    // QUIC and UDP servers have no TCP connection to read it from
+    config.servers.doq.proxy_protocol = true;
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_validate_upstream_extra_headers() {
+    let mut config = AppConfig::default();
+    config.upstream.extra_headers.insert(
+        "x-api-key".to_string(),
+        "${DNS_PROXY_TEST_UNSET_KEY}".to_string(),
+    );
+    // The error names the variable but not the header's value
+    let err = config.validate().unwrap_err();
+    assert!(format!("{:#}", err).contains("DNS_PROXY_TEST_UNSET_KEY"));
+
+    // SAFETY: no other test reads or writes this variable
+    unsafe { std::env::set_var("DNS_PROXY_TEST_API_KEY", "k3y") };
+    config.upstream.extra_headers.insert(
+        "x-api-key".to_string(),
+        "key=${DNS_PROXY_TEST_API_KEY};".to_string(),
+    );
+    config.validate().unwrap();
+    let headers = config.upstream.resolved_extra_headers().unwrap();
+    assert_eq!(headers["x-api-key"], "key=k3y;");
+    assert!(headers["x-api-key"].is_sensitive());
+
+    config
+        .upstream
+        .extra_headers
+        .insert("bad header".to_string(), "value".to_string());
+    assert!(config.validate().is_err());
+    config.upstream.extra_headers.remove("bad header");
+    config
+        .upstream
+        .extra_headers
+        .insert("x-unterminated".to_string(), "${OOPS".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_validate_upstream_protocol() {
+    let mut config = AppConfig::default();
+    assert_eq!(config.servers.doh.upstream_protocol, None);
+
+    config.servers.doh.upstream_protocol = Some("dot".to_string());
+    config.servers.dot.upstream_protocol = Some("doh".to_string());
+    config.upstream.doh = vec!["https://dns.example.com/dns-query".to_string()];
+    config.validate().unwrap();
+
+    // The target protocol needs an upstream
+    config.upstream.doh.clear();
+    assert!(config.validate().is_err());
+    config.servers.dot.upstream_protocol = Some("udp".to_string());
+    assert!(config.validate().is_err());
+
+    // Plain DNS servers route queries on their own
+    let mut config = AppConfig::default();
+    config.servers.udp.upstream_protocol = Some("dot".to_string());
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_validate_regex_rewriter() {
+    let config: AppConfig = toml::from_str(
+        r#"
+[rewrite]
+rewriter_type = "regex"
+
+[[rewrite.rules]]
+pattern = '^(\w+)\.example\.org$'
+replacement = "$1.example.cn"
+
+[servers.dot]
+enabled = true
+bind_address = "0.0.0.0"
+port = 853
+
+[servers.doh]
+enabled = true
+bind_address = "0.0.0.0"
+port = 443
+
+[servers.doq]
+enabled = true
+bind_address = "0.0.0.0"
+port = 853
+
+[servers.doh3]
+enabled = false
+bind_address = "0.0.0.0"
+port = 443
+
+[upstream]
+default = "1.1.1.1:853"
+"#,
+    )
+    .unwrap();
+    assert_eq!(config.rewrite.rules.len(), 1);
+    assert!(config.rewrite.base_domains.is_empty());
+    config.validate().unwrap();
+
+    let mut invalid = config.clone();
+    invalid.rewrite.rules[0].pattern = "^(unclosed".to_string();
+    assert!(invalid.validate().is_err());
+
+    let mut invalid = config.clone();
+    invalid.rewrite.rules.clear();
+    assert!(invalid.validate().is_err());
+
+    // static_map needs a readable mapping file
+    let mut invalid = config.clone();
+    invalid.rewrite.rewriter_type = "static_map".to_string();
+    assert!(invalid.validate().is_err());
+    invalid.rewrite.mapping_file = Some("/nonexistent/sni-map.toml".to_string());
+    assert!(invalid.validate().is_err());
+
+    let mut invalid = config;
+    invalid.rewrite.rewriter_type = "lookup".to_string();
+    assert!(invalid.validate().is_err());
+}
+
+#[test]
+fn test_validate_rewrite_failure_strategy() {
+    let mut config = AppConfig::default();
+    for strategy in ["error", "passthrough", "passthrough_warn"] {
+        config.rewrite.rewrite_failure_strategy = strategy.to_string();
+        config.validate().unwrap();
+    }
+
+    config.rewrite.rewrite_failure_strategy = "warn".to_string();
+    let err = config.validate().unwrap_err();
+    assert!(
+        err.to_string().contains("rewrite_failure_strategy"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_validate_rewrite_targets() {
+    let rewrite: RewriteConfig = toml::from_str(
+        r#"
+base_domains = ["example.com"]
+target_suffix = ".example.cn"
+
+[[targets]]
+base_domain = "example.org"
+target_suffix = ".example.net"
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        rewrite.base_domain_targets().collect::<Vec<_>>(),
+        vec![
+            ("example.org", ".example.net"),
+            ("example.com", ".example.cn")
+        ]
+    );
+    let config = AppConfig {
+        rewrite,
+        ..Default::default()
+    };
+    config.validate().unwrap();
+
+    // targets alone need no shared target_suffix
+    let mut targets_only = config.clone();
+    targets_only.rewrite.base_domains.clear();
+    targets_only.rewrite.target_suffix.clear();
+    targets_only.validate().unwrap();
+
+    let mut invalid = targets_only.clone();
+    invalid.rewrite.targets[0].target_suffix = "example.net".to_string();
+    let err = invalid.validate().unwrap_err();
+    assert!(err.to_string().contains("example.org"), "{}", err);
+
+    let mut invalid = targets_only;
+    invalid.rewrite.targets.clear();
+    assert!(invalid.validate().is_err());
+}
+
+fn filter(allow: &[&str], deny: &[&str]) -> FilterConfig {
+    FilterConfig {
+        allow_domains: allow.iter().map(|d| d.to_string()).collect(),
+        deny_domains: deny.iter().map(|d| d.to_string()).collect(),
+    }
+}
+
+#[test]
+fn test_filter_allow_only() {
+    let filter = filter(&["example.com", "*.example.org"], &[]);
+    assert!(filter.is_allowed("example.com"));
+    assert!(filter.is_allowed("WWW.Example.COM."));
+    assert!(filter.is_allowed("api.example.org"));
+    // "*." only matches subdomains
+    assert!(!filter.is_allowed("example.org"));
+    // Suffix matches stop at label boundaries
+    assert!(!filter.is_allowed("badexample.com"));
+    assert!(!filter.is_allowed("example.net"));
+
+    // Empty allow-list allows everything
+    assert!(self::filter(&[], &[]).is_allowed("anything.test"));
+}
+
+#[test]
+fn test_filter_deny_only() {
+    let filter = filter(&[], &["ads.example.com", "*.tracker.net"]);
+    assert!(!filter.is_allowed("ads.example.com"));
+    assert!(!filter.is_allowed("x.ads.example.com"));
+    assert!(!filter.is_allowed("a.tracker.net"));
+    assert!(filter.is_allowed("tracker.net"));
+    assert!(filter.is_allowed("www.example.com"));
+}
+
+#[test]
+fn test_filter_deny_takes_precedence() {
+    let filter = filter(&["example.com"], &["internal.example.com"]);
+    assert!(filter.is_allowed("www.example.com"));
+    assert!(!filter.is_allowed("internal.example.com"));
+    assert!(!filter.is_allowed("db.internal.example.com"));
+
+    let deny_all = self::filter(&["example.com"], &["*"]);
+    assert!(!deny_all.is_allowed("www.example.com"));
+}
+
+#[test]
+fn test_filter_config_from_toml_and_validation() {
+    let filter: FilterConfig = toml::from_str(
+        r#"
+allow_domains = ["example.com"]
+deny_domains = ["*.internal.example.com"]
+"#,
+    )
+    .unwrap();
+    let mut config = AppConfig {
+        filter,
+        ..Default::default()
+    };
+    config.validate().unwrap();
+
+    config.filter.deny_domains = vec!["ex*ample.com".to_string()];
+    assert!(config.validate().is_err());
+    config.filter.deny_domains = vec!["*.".to_string()];
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_upstreams_accept_one_or_many() {
+    let upstream: UpstreamConfig = toml::from_str(
+        r#"
+default = "1.1.1.1:853"
+dot = ["1.1.1.1:853", "9.9.9.9:853"]
+doh = "https://cloudflare-dns.com/dns-query"
+"#,
+    )
+    .unwrap();
+    assert_eq!(upstream.default, vec!["1.1.1.1:853"]);
+    assert_eq!(upstream.dot.len(), 2);
+    assert_eq!(upstream.doh, vec!["https://cloudflare-dns.com/dns-query"]);
+    assert!(upstream.doq.is_empty());
+    assert_eq!(upstream.health_check_interval_secs, 30);
+    assert_eq!(upstream.health_check_max_interval_secs, 300);
+
+    let config = AppConfig {
+        upstream,
+        ..Default::default()
+    };
+    assert_eq!(config.dot_upstreams().unwrap().len(), 2);
+    // Unset protocols fall back to the default upstreams
+    assert_eq!(
+        config.doq_upstreams().unwrap(),
+        vec!["1.1.1.1:853".parse::<std::net::SocketAddr>().unwrap()]
+    );
+
+    let mut config = config;
+    config.upstream.udp = vec!["8.8.8.8:53".to_string(), "not-an-address".to_string()];
+    assert!(config.validate().is_err());
+    config.upstream.udp.clear();
+    config.upstream.default.clear();
+    assert!(config.validate().is_err());
+}
+
+#[test]
+fn test_env_overrides_take_precedence_over_file() {
+    let toml_content = r#"
+[rewrite]
+base_domains = ["test.com"]
+target_suffix = ".test.cn"
+
+[servers.dot]
+enabled = true
+bind_address = "127.0.0.1"
+port = 853
+
+[servers.doh]
+enabled = false
+bind_address = "0.0.0.0"
+port = 443
+
+[servers.doq]
+enabled = false
+bind_address = "0.0.0.0"
+port = 853
+
+[servers.doh3]
+enabled = false
+bind_address = "0.0.0.0"
+port = 443
+
+[upstream]
+default = "1.1.1.1:853"
+doh = "https://cloudflare-dns.com/dns-query"
+"#;
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(toml_content.as_bytes()).unwrap();
+    file.flush().unwrap();
+
+    // SAFETY: no other test in this binary sets or reads these variables
+    // outside of std::env, which serializes access
+    unsafe {
+        std::env::set_var("DNS_PROXY_TARGET_SUFFIX", ".env.cn");
+        std::env::set_var("DNS_PROXY_DOT_PORT", "8853");
+        std::env::set_var(
+            "DNS_PROXY_UPSTREAM_DOH",
+            "https://a.test/dns-query, https://b.test/dns-query",
+        );
+    }
+    let config = AppConfig::load(file.path(), true).unwrap();
+    assert_eq!(config.rewrite.target_suffix, ".env.cn");
+    assert_eq!(config.servers.dot.port, 8853);
+    assert_eq!(
+        config.upstream.doh,
+        vec!["https://a.test/dns-query", "https://b.test/dns-query"]
+    );
+    // Values without an override come from the file
+    assert_eq!(config.rewrite.base_domains, vec!["test.com"]);
+    assert_eq!(config.servers.dot.bind_address, vec!["127.0.0.1"]);
+    config.validate().unwrap();
+
+    // An unparseable override fails strict loading and is skipped otherwise
+    unsafe { std::env::set_var("DNS_PROXY_DOT_PORT", "not-a-port") };
+    let err = AppConfig::load(file.path(), true).unwrap_err();
+    assert!(err.to_string().contains("DNS_PROXY_DOT_PORT"), "{:#}", err);
+    let config = AppConfig::load_or_default(file.path());
+    assert_eq!(config.servers.dot.port, 853);
+    assert_eq!(config.rewrite.target_suffix, ".env.cn");
+
+    // Overrides are validated like file values: DoH now shares DoT's port
+    unsafe {
+        std::env::set_var("DNS_PROXY_DOT_PORT", "443");
+        std::env::set_var("DNS_PROXY_DOH_ENABLED", "true");
+        std::env::set_var("DNS_PROXY_DOH_BIND_ADDRESS", "127.0.0.1");
+    }
+    let config = AppConfig::load(file.path(), true).unwrap();
+    assert!(config.servers.doh.enabled);
+    assert!(config.validate().is_err());
+
+    unsafe {
+        for name in [
+            "TARGET_SUFFIX",
+            "DOT_PORT",
+            "UPSTREAM_DOH",
+            "DOH_ENABLED",
+            "DOH_BIND_ADDRESS",
+        ] {
+            std::env::remove_var(format!("{}{}", ENV_PREFIX, name));
+        }
+    }
+}
+
+#[test]
+fn test_to_toml_round_trips() {
+    let mut populated = AppConfig::from_file("config.toml.example").unwrap();
+    populated.tls.certs.insert(
+        "example.com".to_string(),
+        CertificateConfig {
+            cert_file: "/path/to/cert.pem".to_string(),
+            key_file: "/path/to/key.pem".to_string(),
+            key_passphrase: None,
+            ca_file: Some("/path/to/ca.pem".to_string()),
+            require_client_cert: true,
+            ocsp_file: Some("/path/to/cert.ocsp".to_string()),
+        },
+    );
+    populated.logging.file = None;
+    populated.filter.deny_domains = vec!["*.ads.example.com".to_string()];
+
+    for config in [AppConfig::default(), populated] {
+        let dumped = config.to_toml().unwrap();
+        let parsed: AppConfig = toml::from_str(&dumped).unwrap();
+        assert_eq!(parsed, config, "{}", dumped);
+    }
+}
+
+#[test]
+fn test_validate_quic_transport_parameters() {
+    let config: AppConfig = toml::from_str(
+        r#"
+[rewrite]
+base_domains = ["test.com"]
+target_suffix = ".test.cn"
+
+[servers.dot]
+enabled = false
+bind_address = "0.0.0.0"
+port = 853
+
+[servers.doh]
+enabled = false
+bind_address = "0.0.0.0"
+port = 443
+
+[servers.doq]
+enabled = true
+bind_address = "0.0.0.0"
+port = 853
+
+[servers.doh3]
+enabled = false
+bind_address = "0.0.0.0"
+port = 443
+
+[upstream]
+default = "1.1.1.1:853"
+
+[quic]
+max_idle_timeout_ms = 10000
+max_concurrent_bidi_streams = 1000
+keep_alive_interval_ms = 5000
+"#,
+    )
+    .unwrap();
+    config.validate().unwrap();
+    assert_eq!(
+        config.quic.keep_alive_interval(),
+        Some(std::time::Duration::from_secs(5))
+    );
+    assert_eq!(AppConfig::default().quic.max_concurrent_bidi_streams, 256);
+
+    let mut invalid = config.clone();
+    invalid.quic.max_idle_timeout_ms = 500;
+    assert!(invalid.validate().is_err());
+    let mut invalid = config.clone();
+    invalid.quic.max_concurrent_bidi_streams = 0;
+    assert!(invalid.validate().is_err());
+    let mut invalid = config;
+    invalid.quic.keep_alive_interval_ms = 10000;
+    assert!(invalid.validate().is_err());
+}
@@ -0,0 +1,501 @@
+use dns_ingress::config::EdnsConfig;
+use dns_ingress::dns::cookie::{
+    CLIENT_COOKIE_LEN, CookieCheck, CookieValidator, SERVER_COOKIE_LEN, set_cookie,
+};
+use dns_ingress::dns::edns::{self, OPTION_COOKIE};
+use dns_ingress::dns::{
+    HEADER_LEN, Question, formerr_response, is_authenticated, is_dnssec_ok, parse_question,
+    refuse_if_denied, validate_message,
+};
+use std::borrow::Cow;
+
+/// Build an A query for www.example.com with an OPT record carrying the given options
+fn build_query_with_options(options: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut msg = vec![
+        0x12, 0x34, // ID
+        0x01, 0x00, // RD
+        0x00, 0x01, // QDCOUNT
+        0x00, 0x00, // ANCOUNT
+        0x00, 0x00, // NSCOUNT
+        0x00, 0x01, // ARCOUNT
+    ];
+    for label in ["www", "example", "com"] {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]); // QTYPE A, QCLASS IN
+
+    let mut rdata = Vec::new();
+    for (code, data) in options {
+        rdata.extend_from_slice(&code.to_be_bytes());
+        rdata.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(data);
+    }
+    msg.push(0); // Root owner name
+    msg.extend_from_slice(&[0x00, 0x29]); // TYPE OPT
+    msg.extend_from_slice(&[0x04, 0xD0]); // UDP payload size 1232
+    msg.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // Extended RCODE, version, flags
+    msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    msg.extend_from_slice(&rdata);
+    msg
+}
+
+fn option_codes(msg: &[u8]) -> Vec<u16> {
+    let opt = edns::find_opt(msg).expect("OPT record should be present");
+    edns::parse_options(msg, &opt)
+        .expect("OPT RDATA should parse")
+        .iter()
+        .map(|o| o.code)
+        .collect()
+}
+
+fn edns_config(unknown_options: &str) -> EdnsConfig {
+    EdnsConfig {
+        unknown_options: unknown_options.to_string(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_find_opt_and_parse_options() {
+    let cookie = [1u8, 2, 3, 4, 5, 6, 7, 8];
+    let msg = build_query_with_options(&[(OPTION_COOKIE, &cookie), (65001, b"xyz")]);
+
+    assert_eq!(option_codes(&msg), vec![OPTION_COOKIE, 65001]);
+}
+
+#[test]
+fn test_find_opt_absent() {
+    let mut msg = build_query_with_options(&[]);
+    // Drop the OPT record and clear ARCOUNT
+    msg.truncate(msg.len() - 11);
+    msg[11] = 0;
+    assert!(edns::find_opt(&msg).is_none());
+}
+
+#[test]
+fn test_unknown_options_forward() {
+    let cookie = [1u8, 2, 3, 4, 5, 6, 7, 8];
+    let msg = build_query_with_options(&[(OPTION_COOKIE, &cookie), (65001, b"xyz")]);
+
+    let rewritten = edns::rewrite_query(&msg, &edns_config("forward"));
+    assert!(matches!(rewritten, Cow::Borrowed(_)));
+    assert_eq!(rewritten.as_ref(), msg.as_slice());
+}
+
+#[test]
+fn test_unknown_options_strip() {
+    let cookie = [1u8, 2, 3, 4, 5, 6, 7, 8];
+    let msg = build_query_with_options(&[
+        (OPTION_COOKIE, &cookie),
+        (65001, b"xyz"),
+        (edns::OPTION_PADDING, &[0, 0, 0]),
+    ]);
+
+    let rewritten = edns::rewrite_query(&msg, &edns_config("strip"));
+    assert_eq!(
+        option_codes(&rewritten),
+        vec![OPTION_COOKIE, edns::OPTION_PADDING]
+    );
+    // Unknown option header (4 bytes) and payload (3 bytes) removed
+    assert_eq!(rewritten.len(), msg.len() - 7);
+
+    let opt = edns::find_opt(&rewritten).unwrap();
+    let options = edns::parse_options(&rewritten, &opt).unwrap();
+    assert_eq!(options[0].data, &cookie);
+}
+
+#[test]
+fn test_unknown_options_strip_without_unknown_is_noop() {
+    let cookie = [1u8, 2, 3, 4, 5, 6, 7, 8];
+    let msg = build_query_with_options(&[(OPTION_COOKIE, &cookie)]);
+
+    let rewritten = edns::rewrite_query(&msg, &edns_config("strip"));
+    assert!(matches!(rewritten, Cow::Borrowed(_)));
+}
+
+#[test]
+fn test_unknown_options_strip_malformed_is_untouched() {
+    let msg = vec![0u8; 5];
+    let rewritten = edns::rewrite_query(&msg, &edns_config("strip"));
+    assert_eq!(rewritten.as_ref(), msg.as_slice());
+}
+
+#[test]
+fn test_qtype_from_name() {
+    use dns_ingress::dns::qtype_from_name;
+
+    assert_eq!(qtype_from_name("TXT"), Some(16));
+    assert_eq!(qtype_from_name("soa"), Some(6));
+    assert_eq!(qtype_from_name("TYPE65"), Some(65));
+    assert_eq!(qtype_from_name("28"), Some(28));
+    assert_eq!(qtype_from_name("BOGUS"), None);
+    assert_eq!(qtype_from_name("TYPE70000"), None);
+}
+
+#[test]
+fn test_rcode_name() {
+    use dns_ingress::dns::{rcode_name, refused_response, servfail_response};
+
+    let query = build_query_with_options(&[]);
+    assert_eq!(rcode_name(&refused_response(&query)), "REFUSED");
+    assert_eq!(rcode_name(&servfail_response(&query)), "SERVFAIL");
+    let mut response = query.clone();
+    response[3] = 0x80 | 3;
+    assert_eq!(rcode_name(&response), "NXDOMAIN");
+    response[3] = 9;
+    assert_eq!(rcode_name(&response), "RCODE9");
+    assert_eq!(rcode_name(&query[..4]), "-");
+}
+
+#[test]
+fn test_only_query_and_notify_are_replayable() {
+    use dns_ingress::dns::is_replayable;
+
+    let mut query = build_query_with_options(&[]);
+    assert!(is_replayable(&query));
+    // NOTIFY
+    query[2] = 4 << 3;
+    assert!(is_replayable(&query));
+    // UPDATE
+    query[2] = 5 << 3;
+    assert!(!is_replayable(&query));
+    assert!(!is_replayable(&query[..4]));
+}
+
+#[test]
+fn test_force_do_bit_sets_flag() {
+    let msg = build_query_with_options(&[(OPTION_COOKIE, &[1, 2, 3, 4, 5, 6, 7, 8])]);
+    assert!(!edns::do_bit(&msg, &edns::find_opt(&msg).unwrap()));
+
+    let config = EdnsConfig {
+        force_do_bit: true,
+        ..Default::default()
+    };
+    let rewritten = edns::rewrite_query(&msg, &config);
+    assert_eq!(rewritten.len(), msg.len());
+    assert!(edns::do_bit(
+        &rewritten,
+        &edns::find_opt(&rewritten).unwrap()
+    ));
+    assert_eq!(option_codes(&rewritten), vec![OPTION_COOKIE]);
+
+    // Already set: nothing to copy
+    assert!(matches!(
+        edns::rewrite_query(&rewritten, &config),
+        Cow::Borrowed(_)
+    ));
+}
+
+#[test]
+fn test_force_do_bit_adds_opt_record() {
+    let mut msg = build_query_with_options(&[]);
+    msg.truncate(msg.len() - 11);
+    msg[11] = 0;
+
+    let rewritten = edns::set_do_bit(&msg, true);
+    assert_eq!(
+        rewritten[11], 1,
+        "ARCOUNT should include the new OPT record"
+    );
+    let opt = edns::find_opt(&rewritten).expect("OPT record should be added");
+    assert!(edns::do_bit(&rewritten, &opt));
+    assert_eq!(
+        &rewritten[opt.start + 3..opt.start + 5],
+        &edns::DEFAULT_UDP_PAYLOAD_SIZE.to_be_bytes()
+    );
+    assert_eq!(opt.rdata_len, 0);
+
+    // Clearing on a query without OPT leaves it alone
+    assert!(matches!(edns::set_do_bit(&msg, false), Cow::Borrowed(_)));
+}
+
+#[test]
+fn test_clear_do_bit_combined_with_strip() {
+    let msg = edns::set_do_bit(&build_query_with_options(&[(65001, b"xyz")]), true).into_owned();
+
+    let config = EdnsConfig {
+        unknown_options: "strip".to_string(),
+        clear_do_bit: true,
+        ..Default::default()
+    };
+    let rewritten = edns::rewrite_query(&msg, &config);
+    let opt = edns::find_opt(&rewritten).unwrap();
+    assert!(!edns::do_bit(&rewritten, &opt));
+    assert!(option_codes(&rewritten).is_empty());
+}
+
+#[test]
+fn test_udp_payload_limit() {
+    // OPT record advertises 1232 bytes
+    let msg = build_query_with_options(&[]);
+    assert_eq!(dns_ingress::dns::udp_payload_limit(&msg), 1232);
+
+    // Without OPT the classic 512-byte limit applies
+    let mut plain = msg.clone();
+    plain.truncate(plain.len() - 11);
+    plain[11] = 0;
+    assert_eq!(dns_ingress::dns::udp_payload_limit(&plain), 512);
+}
+
+#[test]
+fn test_truncate_and_servfail_keep_question() {
+    let msg = build_query_with_options(&[(OPTION_COOKIE, &[1, 2, 3, 4, 5, 6, 7, 8])]);
+    let question_end = msg.len() - 11 - 12;
+
+    let truncated = dns_ingress::dns::truncate_response(&msg);
+    assert_eq!(truncated.len(), question_end);
+    assert_ne!(truncated[2] & 0x02, 0);
+    assert_eq!(&truncated[4..12], &[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    let servfail = dns_ingress::dns::servfail_response(&msg);
+    assert_eq!(&servfail[..2], &msg[..2]);
+    assert_ne!(servfail[2] & 0x80, 0);
+    assert_eq!(servfail[3] & 0x0F, 2);
+    assert_eq!(&servfail[12..], &msg[12..question_end]);
+}
+
+/// Hand-built A query for www.example.com with ID 0xabcd and RD set
+fn www_example_com_query() -> Vec<u8> {
+    let mut msg = vec![0xab, 0xcd, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in ["www", "example", "com"] {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.extend_from_slice(&[0, 0, 1, 0, 1]);
+    msg
+}
+
+#[test]
+fn test_parse_question() {
+    let question = parse_question(&www_example_com_query()).unwrap();
+    assert_eq!(
+        question,
+        Question {
+            qname: "www.example.com".to_string(),
+            qtype: 1,
+            qclass: 1,
+        }
+    );
+
+    // Names are matched case-insensitively
+    let mut query = www_example_com_query();
+    query[13..16].copy_from_slice(b"WWW");
+    assert_eq!(parse_question(&query).unwrap().qname, "www.example.com");
+}
+
+#[test]
+fn test_parse_question_rejects_malformed_input() {
+    let query = www_example_com_query();
+
+    // Header only, or no question
+    assert!(parse_question(&query[..12]).is_err());
+    let mut no_question = query.clone();
+    no_question[5] = 0;
+    assert!(parse_question(&no_question).is_err());
+
+    // Truncated label, truncated QTYPE/QCLASS
+    assert!(parse_question(&query[..20]).is_err());
+    assert!(parse_question(&query[..query.len() - 1]).is_err());
+
+    // Compression pointer in the question (here pointing at itself)
+    let mut compressed = query[..12].to_vec();
+    compressed.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+    assert!(parse_question(&compressed).is_err());
+
+    // Name longer than 255 bytes
+    let mut long = query[..12].to_vec();
+    for _ in 0..5 {
+        long.push(63);
+        long.extend_from_slice(&[b'a'; 63]);
+    }
+    long.extend_from_slice(&[0, 0, 1, 0, 1]);
+    assert!(parse_question(&long).is_err());
+}
+
+#[test]
+fn test_refuse_if_denied() {
+    use dns_ingress::config::FilterConfig;
+
+    let query = www_example_com_query();
+    let mut filter = FilterConfig::default();
+    assert!(refuse_if_denied(&query, &filter).is_none());
+
+    filter.deny_domains = vec!["example.com".to_string()];
+    let refused = refuse_if_denied(&query, &filter).unwrap();
+    assert_eq!(&refused[..2], &query[..2]);
+    assert_ne!(refused[2] & 0x80, 0, "QR bit should be set");
+    assert_eq!(refused[3] & 0x0F, 5, "RCODE should be REFUSED");
+    assert_eq!(&refused[12..], &query[12..], "question should be echoed");
+
+    // A malformed question is only refused under an allow-list
+    let malformed = &query[..14];
+    assert!(refuse_if_denied(malformed, &filter).is_none());
+    filter.allow_domains = vec!["example.org".to_string()];
+    assert!(refuse_if_denied(&query, &filter).is_some());
+    assert!(refuse_if_denied(malformed, &filter).is_some());
+}
+
+/// COOKIE option data of a message, if any
+fn cookie_option(msg: &[u8]) -> Option<Vec<u8>> {
+    let opt = edns::find_opt(msg)?;
+    edns::parse_options(msg, &opt)?
+        .iter()
+        .find(|o| o.code == OPTION_COOKIE)
+        .map(|o| o.data.to_vec())
+}
+
+fn rejected_response(check: CookieCheck) -> Vec<u8> {
+    match check {
+        CookieCheck::Rejected { response, .. } => response,
+        CookieCheck::Valid { .. } => panic!("query should be rejected"),
+    }
+}
+
+#[test]
+fn test_cookie_absent_is_refused() {
+    let validator = CookieValidator::new();
+    let client = "192.0.2.1".parse().unwrap();
+
+    for query in [build_query_with_options(&[]), www_example_com_query()] {
+        let response = rejected_response(validator.check(&query, client));
+        assert_eq!(&response[..2], &query[..2]);
+        assert_eq!(response[3] & 0x0F, 5, "RCODE should be REFUSED");
+    }
+}
+
+#[test]
+fn test_client_cookie_gets_badcookie_with_server_cookie() {
+    let validator = CookieValidator::new();
+    let client = "192.0.2.1".parse().unwrap();
+    let client_cookie = [1u8, 2, 3, 4, 5, 6, 7, 8];
+    let query = build_query_with_options(&[(OPTION_COOKIE, &client_cookie)]);
+
+    let response = rejected_response(validator.check(&query, client));
+    assert_ne!(response[2] & 0x80, 0, "QR bit should be set");
+    // BADCOOKIE (23): low bits in the header, high bits in the OPT record
+    assert_eq!(response[3] & 0x0F, 7);
+    let opt = edns::find_opt(&response).unwrap();
+    assert_eq!(response[opt.flags_offset() - 2], 1);
+    let cookie = cookie_option(&response).unwrap();
+    assert_eq!(cookie.len(), CLIENT_COOKIE_LEN + SERVER_COOKIE_LEN);
+    assert_eq!(&cookie[..CLIENT_COOKIE_LEN], &client_cookie);
+}
+
+#[test]
+fn test_valid_server_cookie_is_accepted() {
+    let validator = CookieValidator::new();
+    let client = "192.0.2.1".parse().unwrap();
+    let first = build_query_with_options(&[(OPTION_COOKIE, &[1, 2, 3, 4, 5, 6, 7, 8])]);
+    let response = rejected_response(validator.check(&first, client));
+    let cookie = cookie_option(&response).unwrap();
+
+    let query = build_query_with_options(&[(OPTION_COOKIE, &cookie), (65001, b"xyz")]);
+    match validator.check(&query, client) {
+        CookieCheck::Valid {
+            query: forwarded,
+            cookie: reply,
+        } => {
+            // The proxy's cookie is not passed on to the upstream
+            assert_eq!(option_codes(&forwarded), vec![65001]);
+            assert_eq!(&reply[..CLIENT_COOKIE_LEN], &cookie[..CLIENT_COOKIE_LEN]);
+            assert_eq!(reply.len(), CLIENT_COOKIE_LEN + SERVER_COOKIE_LEN);
+        }
+        CookieCheck::Rejected { reason, .. } => panic!("cookie rejected: {}", reason),
+    }
+}
+
+#[test]
+fn test_invalid_server_cookie_is_rejected() {
+    let validator = CookieValidator::new();
+    let client = "192.0.2.1".parse().unwrap();
+    let first = build_query_with_options(&[(OPTION_COOKIE, &[1, 2, 3, 4, 5, 6, 7, 8])]);
+    let cookie = cookie_option(&rejected_response(validator.check(&first, client))).unwrap();
+
+    // Forged hash
+    let mut forged = cookie.clone();
+    *forged.last_mut().unwrap() ^= 0xFF;
+    let query = build_query_with_options(&[(OPTION_COOKIE, &forged)]);
+    let response = rejected_response(validator.check(&query, client));
+    assert_eq!(response[3] & 0x0F, 7, "RCODE should be BADCOOKIE");
+
+    // Issued to another address, or by another proxy
+    let query = build_query_with_options(&[(OPTION_COOKIE, &cookie)]);
+    let other = "192.0.2.2".parse().unwrap();
+    let response = rejected_response(validator.check(&query, other));
+    assert_eq!(response[3] & 0x0F, 7, "RCODE should be BADCOOKIE");
+    let response = rejected_response(CookieValidator::new().check(&query, client));
+    assert_eq!(response[3] & 0x0F, 7, "RCODE should be BADCOOKIE");
+
+    // Neither a client cookie alone nor followed by an 8-32 byte server cookie
+    for len in [4, 12, 41] {
+        let query = build_query_with_options(&[(OPTION_COOKIE, &vec![0u8; len])]);
+        let response = rejected_response(validator.check(&query, client));
+        assert_eq!(response[3] & 0x0F, 1, "RCODE should be FORMERR");
+    }
+}
+
+#[test]
+fn test_set_cookie() {
+    let cookie = [7u8; 24];
+
+    // Replaces an existing cookie, keeping other options
+    let with_opt = build_query_with_options(&[(OPTION_COOKIE, &[1; 8]), (65001, b"xyz")]);
+    let updated = set_cookie(&with_opt, &cookie);
+    assert_eq!(option_codes(&updated), vec![65001, OPTION_COOKIE]);
+    assert_eq!(cookie_option(&updated).unwrap(), cookie);
+
+    // Adds an OPT record to a message without one
+    let without_opt = www_example_com_query();
+    let updated = set_cookie(&without_opt, &cookie);
+    assert_eq!(&updated[10..12], &[0, 1], "ARCOUNT should be bumped");
+    assert_eq!(cookie_option(&updated).unwrap(), cookie);
+}
+
+#[test]
+fn test_validate_message_rejects_short_messages() {
+    let query = build_query_with_options(&[]);
+    validate_message(&query).unwrap();
+    validate_message(&query[..HEADER_LEN]).unwrap();
+
+    for short in [&query[..0], &query[..5]] {
+        let err = validate_message(short).unwrap_err();
+        assert!(
+            err.to_string().contains("shorter than the 12 byte header"),
+            "{}",
+            err
+        );
+
+        // A FORMERR still echoes whatever part of the ID there is
+        let formerr = formerr_response(short);
+        assert_eq!(formerr.len(), HEADER_LEN);
+        assert_eq!(formerr[2] & 0x80, 0x80, "QR should be set");
+        assert_eq!(formerr[3] & 0x0F, 1, "RCODE should be FORMERR");
+        assert_eq!(&formerr[..short.len().min(2)], &query[..short.len().min(2)]);
+    }
+    assert_eq!(&formerr_response(&[])[..2], &[0, 0]);
+
+    let formerr = formerr_response(&query);
+    assert_eq!(&formerr[..2], &query[..2]);
+    assert_eq!(formerr[3] & 0x0F, 1);
+    assert_eq!(parse_question(&formerr).unwrap().qname, "www.example.com");
+}
+
+#[test]
+fn test_dnssec_flags() {
+    let query = build_query_with_options(&[]);
+    assert!(!is_dnssec_ok(&query));
+    assert!(is_dnssec_ok(&edns::set_do_bit(&query, true)));
+    // Without an OPT record there is no DO bit
+    let mut no_opt = query.clone();
+    no_opt[11] = 0;
+    assert!(!is_dnssec_ok(&no_opt[..no_opt.len() - 11]));
+
+    let mut response = query.clone();
+    response[2] |= 0x80;
+    assert!(!is_authenticated(&response));
+    response[3] |= 0x20;
+    assert!(is_authenticated(&response));
+    assert!(!is_authenticated(&response[..5]));
+}
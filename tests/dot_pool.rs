@@ -0,0 +1,117 @@
+use dns_ingress::config::{AppConfig, DotPoolConfig};
+use dns_ingress::upstream::dot_pool::DotConnectionPool;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Start a DoT upstream with a self-signed certificate for 127.0.0.1 that
+/// echoes every query back with QR set. Returns its address and a count of the
+/// connections it has accepted.
+async fn start_mock_dot_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        certified.signing_key.serialize_der(),
+    ));
+    let server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&accepted);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(mut tls) = acceptor.accept(stream).await else {
+                    return;
+                };
+                while let Ok(len) = tls.read_u16().await {
+                    let mut query = vec![0u8; len as usize];
+                    if tls.read_exact(&mut query).await.is_err() {
+                        return;
+                    }
+                    query[2] |= 0x80;
+                    let _ = tls.write_u16(query.len() as u16).await;
+                    let _ = tls.write_all(&query).await;
+                    let _ = tls.flush().await;
+                }
+            });
+        }
+    });
+    (addr, accepted)
+}
+
+fn trusting_upstream_config() -> dns_ingress::config::UpstreamConfig {
+    let mut upstream = AppConfig::default().upstream;
+    upstream.danger_accept_invalid_certs = true;
+    upstream
+}
+
+const QUERY: [u8; 12] = [0x12, 0x34, 0x01, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+
+#[tokio::test]
+async fn test_queries_to_same_upstream_reuse_connection() {
+    let (addr, accepted) = start_mock_dot_upstream().await;
+    let upstream = trusting_upstream_config();
+    let pool = DotConnectionPool::new();
+
+    for _ in 0..3 {
+        let response = pool
+            .forward(addr, "127.0.0.1", &QUERY, &upstream)
+            .await
+            .unwrap();
+        assert_eq!(&response[..2], &QUERY[..2]);
+        assert_ne!(response[2] & 0x80, 0);
+    }
+
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    assert_eq!(pool.len(), 1);
+}
+
+#[tokio::test]
+async fn test_zero_max_per_upstream_disables_pooling() {
+    let (addr, accepted) = start_mock_dot_upstream().await;
+    let upstream = trusting_upstream_config();
+    let pool = DotConnectionPool::from_config(&DotPoolConfig {
+        max_per_upstream: 0,
+        ..DotPoolConfig::default()
+    });
+
+    for _ in 0..2 {
+        pool.forward(addr, "127.0.0.1", &QUERY, &upstream)
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    assert!(pool.is_empty());
+}
+
+#[tokio::test]
+async fn test_idle_connections_are_evicted_after_idle_timeout() {
+    let (addr, _) = start_mock_dot_upstream().await;
+    let upstream = trusting_upstream_config();
+    let pool = DotConnectionPool::from_config(&DotPoolConfig {
+        idle_timeout_secs: 5,
+        ..DotPoolConfig::default()
+    });
+    assert_eq!(pool.idle_timeout(), Duration::from_secs(5));
+
+    pool.forward(addr, "127.0.0.1", &QUERY, &upstream)
+        .await
+        .unwrap();
+    assert_eq!(pool.evict_idle(Instant::now()), 0);
+    assert_eq!(pool.evict_idle(Instant::now() + Duration::from_secs(6)), 1);
+    assert!(pool.is_empty());
+}
@@ -19,6 +19,15 @@ fn test_certificate_error_load_failed() {
     assert!(error.to_string().contains("/path/to/cert.pem"));
 }
 
+#[test]
+fn test_certificate_error_file_not_found() {
+    let error = CertificateError::FileNotFound {
+        path: "/path/to/cert.pem".to_string(),
+    };
+    assert!(error.to_string().contains("not found"));
+    assert!(error.to_string().contains("/path/to/cert.pem"));
+}
+
 #[test]
 fn test_certificate_error_not_configured() {
     let error = CertificateError::NotConfigured {
@@ -1,5 +1,6 @@
 /// Error scenario tests
 use dns_ingress::config::RewriteConfig;
+use dns_ingress::metrics::Metrics;
 use dns_ingress::rewriters::base::BaseSniRewriter;
 use dns_ingress::sni::SniRewriter;
 
@@ -8,7 +9,11 @@ async fn test_rewriter_error_scenario_no_match() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
     let result = rewriter.rewrite("other.com").await;
@@ -23,7 +28,11 @@ async fn test_rewriter_error_scenario_invalid_format() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
@@ -61,7 +70,11 @@ async fn test_rewriter_error_scenario_passthrough_fallback() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "passthrough".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
@@ -79,13 +92,51 @@ async fn test_rewriter_error_scenario_passthrough_fallback() {
     );
 }
 
+#[tokio::test]
+async fn test_rewriter_error_scenario_passthrough_warn() {
+    let config = RewriteConfig {
+        base_domains: vec!["example.com".to_string()],
+        target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
+        rewrite_failure_strategy: "passthrough_warn".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
+    };
+    let rewriter = BaseSniRewriter::new(config);
+    let metrics = Metrics::new();
+
+    let rewrite_result = rewriter.rewrite("other.com").await.unwrap();
+    assert_eq!(rewrite_result.target_hostname, "other.com");
+    assert!(rewrite_result.passthrough && rewrite_result.warn);
+    metrics.record_rewrite(Some(&rewrite_result));
+    metrics.record_rewrite(Some(&rewriter.rewrite("other.com").await.unwrap()));
+
+    // Matching hostnames are still rewritten and not counted
+    let rewritten = rewriter.rewrite("www.example.com").await.unwrap();
+    assert!(!rewritten.warn);
+    metrics.record_rewrite(Some(&rewritten));
+
+    assert_eq!(metrics.rewrite_passthrough_warnings("other.com"), 2);
+    assert_eq!(metrics.rewrite_passthroughs(), 2);
+    assert!(
+        metrics
+            .export_prometheus()
+            .contains(r#"dns_proxy_sni_rewrite_passthrough_warnings_total{sni="other.com"} 2"#)
+    );
+}
+
 #[tokio::test]
 async fn test_config_validation_empty_base_domains() {
     // Test that rewriter handles empty base domains gracefully
     let config = RewriteConfig {
         base_domains: vec![],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
@@ -103,7 +154,11 @@ async fn test_config_validation_invalid_target_suffix() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: "example.cn".to_string(), // Missing leading dot
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
@@ -119,7 +174,11 @@ async fn test_rewriter_error_scenario_malformed_hostname() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
@@ -148,7 +207,11 @@ async fn test_rewriter_error_scenario_very_long_hostname() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
@@ -170,7 +233,11 @@ async fn test_rewriter_error_scenario_unicode_hostname() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
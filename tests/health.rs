@@ -0,0 +1,206 @@
+use dns_ingress::app::App;
+use dns_ingress::config::AppConfig;
+use dns_ingress::metrics::Metrics;
+use dns_ingress::upstream::create_connection_pool;
+use dns_ingress::upstream::default_upstream::{DefaultUpstream, DnsUpstream};
+use dns_ingress::upstream::health::{UpstreamGroup, UpstreamHealth};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Start a UDP upstream that echoes each query back with QR set, counting
+/// the queries it answers
+async fn start_counting_udp_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let answered = Arc::new(AtomicUsize::new(0));
+    serve_udp_echo(socket, Arc::clone(&answered));
+    (addr, answered)
+}
+
+/// Answer every query on `socket` by echoing it back with QR set
+fn serve_udp_echo(socket: UdpSocket, counter: Arc<AtomicUsize>) {
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 512];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            let mut response = buf[..len].to_vec();
+            response[2] |= 0x80;
+            counter.fetch_add(1, Ordering::Relaxed);
+            let _ = socket.send_to(&response, peer).await;
+        }
+    });
+}
+
+fn build_query(id: u16) -> Vec<u8> {
+    let mut msg = id.to_be_bytes().to_vec();
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in ["www", "example", "net"] {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.extend_from_slice(&[0, 0, 1, 0, 1]);
+    msg
+}
+
+#[test]
+fn test_upstream_group_round_robins_healthy_upstreams() {
+    let group = UpstreamGroup::new("doh", ["a", "b", "c"].map(String::from));
+    let picks: Vec<String> = (0..3).map(|_| group.pick().unwrap()).collect();
+    assert_eq!(picks, ["a", "b", "c"]);
+
+    group.set_healthy(&"b".to_string(), false);
+    for _ in 0..4 {
+        assert_ne!(group.pick().unwrap(), "b");
+    }
+
+    // With every upstream down they are all tried rather than none
+    group.set_healthy(&"a".to_string(), false);
+    group.set_healthy(&"c".to_string(), false);
+    let picks: Vec<String> = (0..3).map(|_| group.pick().unwrap()).collect();
+    assert!(picks.contains(&"b".to_string()));
+
+    let empty: UpstreamGroup<String> = UpstreamGroup::new("doh3", Vec::new());
+    assert!(empty.is_empty());
+    assert!(empty.pick().is_err());
+}
+
+#[test]
+fn test_probe_interval_backs_off_for_failing_upstream() {
+    let base = Duration::from_secs(30);
+    let max = Duration::from_secs(300);
+    let group = UpstreamGroup::new("doh", ["a", "b"].map(String::from));
+    let a = "a".to_string();
+    assert_eq!(group.probe_interval(&a, base, max), base);
+
+    // Each failure in a row doubles the interval, up to the cap
+    let intervals: Vec<u64> = (0..5)
+        .map(|_| {
+            group.set_healthy(&a, false);
+            group.probe_interval(&a, base, max).as_secs()
+        })
+        .collect();
+    assert_eq!(intervals, [60, 120, 240, 300, 300]);
+    assert_eq!(group.probe_interval(&"b".to_string(), base, max), base);
+
+    // A successful probe resets it, and a new failure starts over
+    group.set_healthy(&a, true);
+    assert_eq!(group.probe_interval(&a, base, max), base);
+    group.set_healthy(&a, false);
+    assert_eq!(group.probe_interval(&a, base, max), base * 2);
+}
+
+#[tokio::test]
+async fn test_failover_to_second_upstream_when_first_is_unhealthy() {
+    // The first upstream never answers, the second one does
+    let blackhole = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let dead = blackhole.local_addr().unwrap();
+    let (alive, answered) = start_counting_udp_upstream().await;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    // Keep the other protocols' probes off the network
+    let mut config = AppConfig::default();
+    config.upstream.default = vec![dead.to_string()];
+    config.upstream.dot.clear();
+    config.upstream.doq.clear();
+    config.upstream.doh.clear();
+    config.upstream.doh3.clear();
+    config.upstream.udp = vec![dead.to_string(), alive.to_string()];
+    config.upstream.upstream_timeout_ms = 300;
+    config.upstream.max_retries = 0;
+    let config = Arc::new(config);
+
+    let health = Arc::new(UpstreamHealth::from_config(&config));
+    let pool = create_connection_pool(&config.upstream);
+    health.probe_due(&config, &pool).await;
+
+    let status = health.to_json();
+    assert_eq!(status["udp"][0]["upstream"], dead.to_string());
+    assert_eq!(status["udp"][0]["healthy"], false);
+    assert_eq!(status["udp"][1]["healthy"], true);
+    let probes = answered.load(Ordering::Relaxed);
+    assert_eq!(probes, 1);
+
+    // Neither upstream is due again until its probe interval elapses
+    health.probe_due(&config, &pool).await;
+    assert_eq!(answered.load(Ordering::Relaxed), probes);
+
+    let upstream =
+        DefaultUpstream::new(Arc::clone(&config), Arc::new(Metrics::new())).with_health(health);
+    for id in 0..4 {
+        let response = upstream.forward(&build_query(id)).await.unwrap();
+        assert_eq!(&response[..2], &id.to_be_bytes());
+    }
+    assert_eq!(answered.load(Ordering::Relaxed), probes + 4);
+}
+
+#[tokio::test]
+async fn test_prober_disabled_with_zero_interval() {
+    let mut config = AppConfig::default();
+    config.upstream.health_check_interval_secs = 0;
+    let health = Arc::new(UpstreamHealth::from_config(&config));
+    let prober = health.spawn_prober(Arc::new(config), tokio_util::sync::CancellationToken::new());
+    assert!(prober.is_none());
+}
+
+/// Poll `url` until it answers with `status`, returning the JSON body
+async fn wait_for_status(url: &str, status: u16) -> serde_json::Value {
+    let client = reqwest::Client::new();
+    for _ in 0..50 {
+        if let Ok(response) = client.get(url).send().await
+            && response.status().as_u16() == status
+        {
+            return serde_json::from_str(&response.text().await.unwrap()).unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("{} never answered {}", url, status);
+}
+
+#[tokio::test]
+async fn test_ready_tracks_upstream_health_while_health_stays_up() {
+    // The only upstream does not answer until it is revived below
+    let blackhole = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let upstream = blackhole.local_addr().unwrap();
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let mut config = AppConfig::default();
+    config.servers.dot.enabled = false;
+    config.servers.doh.enabled = false;
+    config.servers.doq.enabled = false;
+    config.servers.doh3.enabled = false;
+    config.servers.healthcheck.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.healthcheck.port = 18082;
+    config.upstream.default = vec![upstream.to_string()];
+    config.upstream.dot.clear();
+    config.upstream.doq.clear();
+    config.upstream.doh.clear();
+    config.upstream.doh3.clear();
+    config.upstream.udp = vec![upstream.to_string()];
+    config.upstream.upstream_timeout_ms = 300;
+    config.upstream.health_check_interval_secs = 1;
+    config.validate().unwrap();
+
+    let mut app = App::new(config);
+    app.start().await.unwrap();
+
+    // Every upstream fails its first probe: not ready, but still alive
+    let ready = wait_for_status("http://127.0.0.1:18082/ready", 503).await;
+    assert_eq!(ready["status"], "not ready");
+    assert_eq!(
+        ready["upstreams"]["udp"][0]["upstream"],
+        upstream.to_string()
+    );
+    assert_eq!(ready["upstreams"]["udp"][0]["healthy"], false);
+    let health = wait_for_status("http://127.0.0.1:18082/health", 200).await;
+    assert_eq!(health["status"], "healthy");
+
+    // Once the upstream answers the next probe the proxy is ready again
+    serve_udp_echo(blackhole, Arc::new(AtomicUsize::new(0)));
+    let ready = wait_for_status("http://127.0.0.1:18082/ready", 200).await;
+    assert_eq!(ready["status"], "ready");
+    assert_eq!(ready["upstreams"]["udp"][0]["healthy"], true);
+
+    app.shutdown().await;
+}
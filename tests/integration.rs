@@ -1,6 +1,5 @@
 use dns_ingress::app::App;
 use dns_ingress::config::AppConfig;
-use dns_ingress::sni::SniRewriter;
 use std::time::Duration;
 use tokio::time::timeout;
 
@@ -18,13 +17,10 @@ async fn test_app_start_all_disabled() {
     assert!(config.validate().is_ok());
 
     let mut app = App::new(config);
-    assert!(app.start().is_ok());
-
-    // Give servers a moment to start
-    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(app.start().await.is_ok());
 
     // Clean shutdown
-    app.wait_for_shutdown().await;
+    app.shutdown().await;
 }
 
 /// Integration test: Test configuration validation
@@ -79,14 +75,10 @@ fn test_upstream_config_parsing() {
     assert!(dot_upstream.is_ok());
     assert_eq!(dot_upstream.unwrap().port(), 853);
 
-    // Test DoQ upstream
-    let doq_upstream = config.doq_upstream();
-    assert!(doq_upstream.is_ok());
-    assert_eq!(doq_upstream.unwrap().port(), 853);
-
-    // Test hostname extraction
-    let hostname = config.dot_upstream_hostname();
-    assert!(!hostname.is_empty());
+    // Test DoQ upstreams
+    let doq_upstreams = config.doq_upstreams();
+    assert!(doq_upstreams.is_ok());
+    assert_eq!(doq_upstreams.unwrap()[0].port(), 853);
 }
 
 /// Integration test: Test that healthcheck server can be started independently
@@ -103,10 +95,7 @@ async fn test_healthcheck_server_start() {
     assert!(config.validate().is_ok());
 
     let mut app = App::new(config);
-    assert!(app.start().is_ok());
-
-    // Give server time to start
-    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(app.start().await.is_ok());
 
     // Test healthcheck endpoint
     let client = reqwest::Client::new();
@@ -116,7 +105,7 @@ async fn test_healthcheck_server_start() {
     let _result = timeout(Duration::from_secs(1), client.get(&url).send()).await;
 
     // Clean shutdown
-    app.wait_for_shutdown().await;
+    app.shutdown().await;
 
     // We don't assert on the result since the server might not be fully ready
     // The important thing is that it started without errors
@@ -140,38 +129,155 @@ async fn test_metrics_endpoint() {
     // Record some metrics before starting
     app.metrics
         .record_request(true, 100, 200, Duration::from_millis(50));
-    app.metrics.record_sni_rewrite();
+    app.metrics.record_rewrite_hit();
     app.metrics.record_upstream_error();
 
-    assert!(app.start().is_ok());
-
-    // Give server time to start
-    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(app.start().await.is_ok());
 
     // Test metrics endpoint (now returns Prometheus format)
     let client = reqwest::Client::new();
     let url = format!("http://127.0.0.1:{}/metrics", 18081);
 
     // Try to fetch metrics
-    if let Ok(result) = timeout(Duration::from_secs(2), client.get(&url).send()).await {
-        if let Ok(response) = result {
-            if response.status().is_success() {
-                let body = response.text().await.unwrap();
-                // Verify Prometheus format
-                assert!(body.contains("dns_proxy_requests_total"));
-                assert!(body.contains("dns_proxy_requests_success"));
-                assert!(body.contains("dns_proxy_requests_failed"));
-                assert!(body.contains("dns_proxy_bytes_received_total"));
-                assert!(body.contains("dns_proxy_bytes_sent_total"));
-                assert!(body.contains("dns_proxy_sni_rewrites_total"));
-                assert!(body.contains("dns_proxy_upstream_errors_total"));
-                assert!(body.contains("dns_proxy_processing_time_seconds"));
-            }
-        }
+    if let Ok(Ok(response)) = timeout(Duration::from_secs(2), client.get(&url).send()).await
+        && response.status().is_success()
+    {
+        let body = response.text().await.unwrap();
+        // Verify Prometheus format
+        assert!(body.contains("dns_proxy_requests_total"));
+        assert!(body.contains("dns_proxy_requests_success"));
+        assert!(body.contains("dns_proxy_requests_failed"));
+        assert!(body.contains("dns_proxy_bytes_received_total"));
+        assert!(body.contains("dns_proxy_bytes_sent_total"));
+        assert!(body.contains("dns_proxy_sni_rewrite_hits_total"));
+        assert!(body.contains("dns_proxy_upstream_errors_total"));
+        assert!(body.contains("dns_proxy_processing_time_seconds"));
     }
 
     // Clean shutdown
-    app.wait_for_shutdown().await;
+    app.shutdown().await;
+}
+
+/// Integration test: Test the build information endpoint
+#[tokio::test]
+async fn test_version_endpoint() {
+    let mut config = AppConfig::default();
+    config.servers.dot.enabled = false;
+    config.servers.doq.enabled = false;
+    config.servers.doh3.enabled = false;
+    config.servers.doh.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.doh.port = 18087;
+    config.servers.doh.tls = false;
+    config.servers.healthcheck.enabled = true;
+    config.servers.healthcheck.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.healthcheck.port = 18086;
+
+    let mut app = App::new(config);
+    assert!(app.start().await.is_ok());
+
+    let response = reqwest::get("http://127.0.0.1:18086/version")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["version"], env!("CARGO_PKG_VERSION"));
+    assert!(body["git_hash"].is_string());
+    assert!(body["build_timestamp"].as_str().unwrap().ends_with('Z'));
+    assert_eq!(
+        body["servers"],
+        serde_json::json!([{ "name": "DoH", "bind_address": ["127.0.0.1"], "port": 18087 }])
+    );
+
+    app.shutdown().await;
+}
+
+/// Integration test: Test the busiest rewrite targets endpoint
+#[tokio::test]
+async fn test_top_rewrite_targets_endpoint() {
+    let mut config = AppConfig::default();
+    config.servers.dot.enabled = false;
+    config.servers.doh.enabled = false;
+    config.servers.doq.enabled = false;
+    config.servers.doh3.enabled = false;
+    config.servers.healthcheck.enabled = true;
+    config.servers.healthcheck.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.healthcheck.port = 18083;
+
+    let mut app = App::new(config);
+    for (hostname, requests) in [
+        ("www.example.org", 2),
+        ("api.example.org", 4),
+        ("mail.example.org", 1),
+    ] {
+        for _ in 0..requests {
+            let result = app.rewriter.rewrite(hostname).await;
+            app.metrics.record_rewrite(result.as_ref());
+        }
+    }
+    assert!(app.start().await.is_ok());
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:18083/metrics/top?n=2")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(
+        body["targets"],
+        serde_json::json!([
+            { "target": "api.example.cn", "requests": 4 },
+            { "target": "www.example.cn", "requests": 2 },
+        ])
+    );
+
+    // Without n every target fits in the default of 20
+    let response = client
+        .get("http://127.0.0.1:18083/metrics/top")
+        .send()
+        .await
+        .unwrap();
+    let body: serde_json::Value = serde_json::from_str(&response.text().await.unwrap()).unwrap();
+    assert_eq!(body["targets"].as_array().unwrap().len(), 3);
+
+    let response = client
+        .get("http://127.0.0.1:18083/metrics/top?n=many")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 400);
+
+    app.shutdown().await;
+}
+
+/// Integration test: A server bound to `::` is reachable over IPv4
+#[tokio::test]
+async fn test_ipv6_wildcard_bind_accepts_ipv4_clients() {
+    let mut config = AppConfig::default();
+    config.servers.dot.enabled = false;
+    config.servers.doh.enabled = false;
+    config.servers.doq.enabled = false;
+    config.servers.doh3.enabled = false;
+    config.servers.healthcheck.enabled = true;
+    config.servers.healthcheck.bind_address = vec!["::".to_string()];
+    config.servers.healthcheck.port = 18084;
+    config.validate().unwrap();
+
+    let mut app = App::new(config);
+    assert!(app.start().await.is_ok());
+
+    let client = reqwest::Client::new();
+    for host in ["127.0.0.1", "[::1]"] {
+        let response = client
+            .get(format!("http://{}:18084/health", host))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    app.shutdown().await;
 }
 
 /// Integration test: Test metrics collection during app lifecycle
@@ -195,7 +301,7 @@ async fn test_metrics_collection() {
         .record_request(true, 100, 200, Duration::from_millis(50));
     app.metrics
         .record_request(false, 50, 0, Duration::from_millis(10));
-    app.metrics.record_sni_rewrite();
+    app.metrics.record_rewrite_hit();
     app.metrics.record_upstream_error();
 
     tokio::time::sleep(Duration::from_secs(1)).await;
@@ -207,7 +313,7 @@ async fn test_metrics_collection() {
     assert_eq!(snapshot.failed_requests, 1);
     assert_eq!(snapshot.bytes_received, 150);
     assert_eq!(snapshot.bytes_sent, 200);
-    assert_eq!(snapshot.sni_rewrites, 1);
+    assert_eq!(snapshot.rewrite_hits, 1);
     assert_eq!(snapshot.upstream_errors, 1);
     assert!(snapshot.success_rate > 0.0);
     assert!(snapshot.average_processing_time_ms > 0.0);
@@ -0,0 +1,129 @@
+use dns_ingress::config::AppConfig;
+use dns_ingress::metrics::Metrics;
+use dns_ingress::upstream::create_connection_pool;
+use dns_ingress::upstream::health::UpstreamHealth;
+use dns_ingress::upstream::ladder::{UpstreamProtocol, forward_with_ladder};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Once};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio_rustls::TlsAcceptor;
+
+static INIT: Once = Once::new();
+
+fn init_crypto_provider() {
+    INIT.call_once(|| {
+        rustls::crypto::aws_lc_rs::default_provider()
+            .install_default()
+            .expect("Failed to install default crypto provider");
+    });
+}
+
+/// Start a mock DoT upstream that answers one framed query by echoing it back
+/// with the QR bit set. The self-signed certificate is trusted via SSL_CERT_FILE.
+async fn start_mock_dot_upstream() -> (SocketAddr, tempfile::NamedTempFile) {
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+    let mut ca_file = tempfile::NamedTempFile::new().unwrap();
+    ca_file.write_all(certified.cert.pem().as_bytes()).unwrap();
+    ca_file.flush().unwrap();
+    // SAFETY: set once before any upstream TLS config is built in this test binary
+    unsafe { std::env::set_var("SSL_CERT_FILE", ca_file.path()) };
+
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        certified.signing_key.serialize_der(),
+    ));
+    let server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut tls = acceptor.accept(stream).await.unwrap();
+
+        let len = tls.read_u16().await.unwrap() as usize;
+        let mut query = vec![0u8; len];
+        tls.read_exact(&mut query).await.unwrap();
+
+        query[2] |= 0x80;
+        tls.write_u16(query.len() as u16).await.unwrap();
+        tls.write_all(&query).await.unwrap();
+        tls.flush().await.unwrap();
+    });
+
+    (addr, ca_file)
+}
+
+fn build_query() -> Vec<u8> {
+    let mut msg = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in ["www", "example", "com"] {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.extend_from_slice(&[0, 0, 1, 0, 1]);
+    msg
+}
+
+#[tokio::test]
+async fn test_ladder_falls_back_to_dot_when_quic_unavailable() {
+    init_crypto_provider();
+    let (dot_addr, _ca_file) = start_mock_dot_upstream().await;
+
+    // A UDP socket that never answers simulates a network blocking QUIC
+    let blackhole = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let doq_addr = blackhole.local_addr().unwrap();
+
+    let mut config = AppConfig::default();
+    config.upstream.dot = vec![dot_addr.to_string()];
+    config.upstream.doq = vec![doq_addr.to_string()];
+    config.upstream.protocol_ladder = vec!["doq".to_string(), "dot".to_string()];
+    config.upstream.upstream_timeout_ms = 1000;
+
+    let pool = create_connection_pool(&config.upstream);
+    let health = UpstreamHealth::from_config(&config);
+    let metrics = Metrics::new();
+    let query = build_query();
+    let (response, protocol) = forward_with_ladder(&config, &pool, &health, &query, &metrics)
+        .await
+        .expect("query should resolve via DoT fallback");
+
+    assert_eq!(protocol, UpstreamProtocol::Dot);
+    assert_eq!(&response[..2], &query[..2]);
+    assert_ne!(response[2] & 0x80, 0);
+    // Only the answered DoT attempt is counted
+    assert_eq!(metrics.upstream_bytes_sent(), query.len() as u64);
+    assert_eq!(metrics.upstream_bytes_received(), response.len() as u64);
+}
+
+#[tokio::test]
+async fn test_ladder_reports_error_when_all_protocols_fail() {
+    init_crypto_provider();
+    let mut config = AppConfig::default();
+    config.upstream.dot = vec!["not-an-address".to_string()];
+    config.upstream.protocol_ladder = vec!["dot".to_string()];
+
+    let pool = create_connection_pool(&config.upstream);
+    let health = UpstreamHealth::from_config(&config);
+    let metrics = Metrics::new();
+    let result = forward_with_ladder(&config, &pool, &health, &build_query(), &metrics).await;
+    assert!(result.is_err());
+    assert_eq!(metrics.upstream_bytes_sent(), 0);
+}
+
+#[test]
+fn test_protocol_ladder_validation() {
+    let mut config = AppConfig::default();
+    config.servers.doq.enabled = false;
+    config.upstream.protocol_ladder =
+        vec!["doh3".to_string(), "doq".to_string(), "dot".to_string()];
+    config.validate().unwrap();
+
+    config.upstream.protocol_ladder = vec!["udp".to_string()];
+    assert!(config.validate().is_err());
+}
@@ -0,0 +1,389 @@
+use dns_ingress::config::LoggingConfig;
+use dns_ingress::logging::{
+    access_log_layer, connection_span, log_access, log_rejected_connection, record_authenticated,
+    record_dnssec_ok, record_sni, record_target, request_span,
+};
+use dns_ingress::metrics::{Metrics, RejectReason};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// Writer that captures formatted log output for assertions
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedLogs {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+/// Reject `count` connections with `reason` while capturing log output
+fn reject_with_capture(
+    config: &LoggingConfig,
+    metrics: &Metrics,
+    reason: RejectReason,
+    count: usize,
+) -> String {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+
+    let peer: SocketAddr = "192.0.2.1:5353".parse().unwrap();
+    tracing::subscriber::with_default(subscriber, || {
+        for _ in 0..count {
+            log_rejected_connection(config, metrics, "DoT", peer, reason, &"test rejection");
+        }
+    });
+    logs.contents()
+}
+
+#[test]
+fn test_rejected_connection_reasons_produce_metric_and_log() {
+    let config = LoggingConfig::default();
+
+    for reason in [
+        RejectReason::HandshakeFailed,
+        RejectReason::HandshakeTimeout,
+        RejectReason::ProxyProtocolInvalid,
+    ] {
+        let metrics = Metrics::new();
+        let logs = reject_with_capture(&config, &metrics, reason, 1);
+
+        let expected_metric = format!(
+            "dns_proxy_rejected_connections_total{{reason=\"{}\"}} 1",
+            reason.as_str()
+        );
+        assert!(metrics.export_prometheus().contains(&expected_metric));
+        assert!(logs.contains("event=\"connection_rejected\""));
+        assert!(logs.contains(&format!("reason=\"{}\"", reason.as_str())));
+        assert!(logs.contains("192.0.2.1:5353"));
+    }
+}
+
+#[test]
+fn test_rejected_connection_log_sampling() {
+    let config = LoggingConfig {
+        rejected_log_sample_rate: 2,
+        ..LoggingConfig::default()
+    };
+    let metrics = Metrics::new();
+
+    let logs = reject_with_capture(&config, &metrics, RejectReason::HandshakeFailed, 3);
+
+    // Every rejection is counted, but only the 1st and 3rd are logged
+    assert!(
+        metrics
+            .export_prometheus()
+            .contains("dns_proxy_rejected_connections_total{reason=\"handshake_failed\"} 3")
+    );
+    assert_eq!(logs.matches("connection_rejected").count(), 2);
+}
+
+#[test]
+fn test_rejected_connection_logging_disabled() {
+    let config = LoggingConfig {
+        rejected_log_sample_rate: 0,
+        ..LoggingConfig::default()
+    };
+    let metrics = Metrics::new();
+
+    let logs = reject_with_capture(&config, &metrics, RejectReason::HandshakeTimeout, 2);
+
+    assert!(
+        metrics
+            .export_prometheus()
+            .contains("dns_proxy_rejected_connections_total{reason=\"handshake_timeout\"} 2")
+    );
+    assert!(logs.is_empty());
+}
+
+/// Subscriber writing DEBUG and above into `logs`
+fn capture_subscriber(logs: &CapturedLogs) -> impl tracing::Subscriber + Send + Sync + use<> {
+    let writer = logs.clone();
+    tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .finish()
+}
+
+#[test]
+fn test_request_span_fields_reach_nested_events() {
+    let logs = CapturedLogs::default();
+    let client: SocketAddr = "192.0.2.1:5353".parse().unwrap();
+
+    let request_id = tracing::subscriber::with_default(capture_subscriber(&logs), || {
+        let _connection = connection_span("DoT", client).entered();
+        record_sni(Some("dns.example.com"));
+        let (request_id, span) = request_span();
+        let _request = span.entered();
+        record_target(&"1.1.1.1:853");
+        tracing::info!("forwarded");
+        request_id
+    });
+
+    let logs = logs.contents();
+    assert_eq!(request_id.len(), 16);
+    assert!(
+        logs.contains(&format!("request_id={}", request_id)),
+        "{}",
+        logs
+    );
+    assert!(logs.contains("protocol=\"DoT\""), "{}", logs);
+    assert!(logs.contains("client=192.0.2.1:5353"), "{}", logs);
+    assert!(logs.contains("sni=\"dns.example.com\""), "{}", logs);
+    assert!(logs.contains("target=1.1.1.1:853"), "{}", logs);
+    assert!(logs.contains("forwarded"));
+
+    // Every request gets its own ID
+    let (other_id, _) = request_span();
+    assert_ne!(other_id, request_id);
+}
+
+/// Send one request for `host` to a DoH server listening on `port` (whose
+/// filter denies `blocked.test.com`) and return the raw response
+async fn doh_request(port: u16, host: &str) -> String {
+    doh_request_behind_proxy(port, host, None).await
+}
+
+/// [`doh_request`], preceded by `proxy_header` on a server reading PROXY
+/// protocol headers when one is given
+async fn doh_request_behind_proxy(port: u16, host: &str, proxy_header: Option<&[u8]>) -> String {
+    use dns_ingress::config::{AppConfig, RewriteConfig};
+    use dns_ingress::readers::DoHServer;
+    use dns_ingress::rewrite::create_rewriter;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut config = AppConfig::default();
+    config.servers.doh.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.doh.port = port;
+    config.servers.doh.tls = false;
+    config.servers.doh.proxy_protocol = proxy_header.is_some();
+    config.filter.deny_domains = vec!["blocked.test.com".to_string()];
+    let rewriter = create_rewriter(RewriteConfig {
+        base_domains: vec!["test.com".to_string()],
+        target_suffix: ".test.cn".to_string(),
+        targets: Vec::new(),
+        rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
+    });
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    let server = DoHServer::new(Arc::new(config), rewriter, Arc::new(Metrics::new()))
+        .with_shutdown(shutdown.clone());
+    let server = tokio::spawn(async move { server.start().await });
+
+    let mut stream = None;
+    for _ in 0..50 {
+        match tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+            Ok(connected) => {
+                stream = Some(connected);
+                break;
+            }
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(20)).await,
+        }
+    }
+    let mut stream = stream.expect("DoH server should start listening");
+    let request = format!(
+        "GET /dns-query HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        host
+    );
+    if let Some(proxy_header) = proxy_header {
+        stream.write_all(proxy_header).await.unwrap();
+    }
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_doh_response_carries_request_id_of_its_logs() {
+    let logs = CapturedLogs::default();
+    let _guard = tracing::subscriber::set_default(capture_subscriber(&logs));
+
+    let response = doh_request(18083, "blocked.test.com").await;
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+    let request_id = response
+        .lines()
+        .find_map(|line| line.strip_prefix("x-request-id: "))
+        .expect("response should carry X-Request-Id")
+        .to_string();
+
+    let logs = logs.contents();
+    let denied = logs
+        .lines()
+        .find(|line| line.contains("denied by the domain filter"))
+        .expect("denied request should be logged");
+    assert!(
+        denied.contains(&format!("request_id={}", request_id)),
+        "{}",
+        denied
+    );
+    assert!(denied.contains("protocol=\"DoH\""), "{}", denied);
+    assert!(denied.contains("client=127.0.0.1:"), "{}", denied);
+    assert!(denied.contains("sni=\"blocked.test.com\""), "{}", denied);
+}
+
+#[tokio::test]
+async fn test_doh_request_writes_one_access_log_line() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let access = CapturedLogs::default();
+    let writer = access.clone();
+    let subscriber =
+        tracing_subscriber::registry().with(access_log_layer(false, move || writer.clone()));
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let response = doh_request(18084, "blocked.test.com").await;
+    let request_id = response
+        .lines()
+        .find_map(|line| line.strip_prefix("x-request-id: "))
+        .unwrap()
+        .to_string();
+
+    let access = access.contents();
+    let lines: Vec<&str> = access.lines().collect();
+    assert_eq!(lines.len(), 1, "{}", access);
+    let line = lines[0];
+    assert!(line.starts_with("127.0.0.1 - - ["), "{}", line);
+    assert!(
+        line.contains("] \"DoH blocked.test.com -\" 403 0 0 "),
+        "{}",
+        line
+    );
+    assert!(line.ends_with(&format!("ms {}", request_id)), "{}", line);
+}
+
+#[tokio::test]
+async fn test_doh_logs_client_from_proxy_protocol_header() {
+    use tracing_subscriber::layer::{Layer, SubscriberExt};
+
+    let logs = CapturedLogs::default();
+    let access = CapturedLogs::default();
+    let writer = access.clone();
+    let log_writer = logs.clone();
+    let subscriber = tracing_subscriber::registry()
+        .with(access_log_layer(false, move || writer.clone()))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(move || log_writer.clone())
+                .with_ansi(false)
+                .with_filter(tracing::level_filters::LevelFilter::DEBUG),
+        );
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    // v2 header of a TCP connection from 203.0.113.7:5353 to 192.0.2.1:443
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+    header.extend_from_slice(&[203, 0, 113, 7, 192, 0, 2, 1]);
+    header.extend_from_slice(&5353u16.to_be_bytes());
+    header.extend_from_slice(&443u16.to_be_bytes());
+    let response = doh_request_behind_proxy(18085, "blocked.test.com", Some(&header)).await;
+    assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+
+    let access = access.contents();
+    assert!(access.starts_with("203.0.113.7 - - ["), "{}", access);
+    let logs = logs.contents();
+    let denied = logs
+        .lines()
+        .find(|line| line.contains("denied by the domain filter"))
+        .expect("denied request should be logged");
+    assert!(denied.contains("client=203.0.113.7:5353"), "{}", denied);
+    assert!(!logs.contains("client=127.0.0.1"), "{}", logs);
+}
+
+#[test]
+fn test_access_log_records_dnssec_flags() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let access = CapturedLogs::default();
+    let writer = access.clone();
+    let subscriber =
+        tracing_subscriber::registry().with(access_log_layer(true, move || writer.clone()));
+    // Query with an OPT record setting DO, answered with AD set
+    let mut query = vec![0, 1, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    query.extend_from_slice(&[0, 0, 41, 0x04, 0xd0, 0, 0, 0x80, 0, 0, 0]);
+    let mut response = query.clone();
+    response[2] |= 0x80;
+    response[3] |= 0x20;
+    let span_of_request = || request_span().1;
+
+    tracing::subscriber::with_default(subscriber, || {
+        span_of_request().in_scope(|| {
+            record_dnssec_ok(&query);
+            record_authenticated(&response);
+            log_access(&"NOERROR", 23, 23, std::time::Duration::from_millis(1));
+        });
+
+        // Requests without a DNS exchange leave both fields out
+        span_of_request().in_scope(|| {
+            log_access(&200, 0, 0, std::time::Duration::from_millis(1));
+        });
+    });
+
+    let access = access.contents();
+    let entries: Vec<serde_json::Value> = access
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(entries[0]["dnssec_ok"], true);
+    assert_eq!(entries[0]["authenticated"], true);
+    assert!(entries[1]["dnssec_ok"].is_null());
+    assert!(entries[1]["authenticated"].is_null());
+}
+
+#[test]
+fn test_access_log_json_format() {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let access = CapturedLogs::default();
+    let writer = access.clone();
+    let subscriber =
+        tracing_subscriber::registry().with(access_log_layer(true, move || writer.clone()));
+    let client: SocketAddr = "192.0.2.1:5353".parse().unwrap();
+
+    let request_id = tracing::subscriber::with_default(subscriber, || {
+        let _connection = connection_span("DoH3", client).entered();
+        let (request_id, span) = request_span();
+        let _request = span.entered();
+        record_sni(Some("www.test.com"));
+        record_target(&"www.test.cn");
+        // Ordinary events stay out of the access log
+        tracing::info!("forwarding");
+        log_access(&200, 33, 45, std::time::Duration::from_millis(12));
+        request_id
+    });
+
+    let access = access.contents();
+    assert_eq!(access.lines().count(), 1, "{}", access);
+    let entry: serde_json::Value = serde_json::from_str(access.trim()).unwrap();
+    assert_eq!(entry["client"], "192.0.2.1");
+    assert_eq!(entry["protocol"], "DoH3");
+    assert_eq!(entry["host"], "www.test.com");
+    assert_eq!(entry["target"], "www.test.cn");
+    assert_eq!(entry["status"], "200");
+    assert_eq!(entry["bytes_in"], 33);
+    assert_eq!(entry["bytes_out"], 45);
+    assert_eq!(entry["duration_ms"], 12);
+    assert_eq!(entry["request_id"], request_id);
+    assert!(entry["timestamp"].is_string());
+}
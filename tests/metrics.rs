@@ -1,4 +1,7 @@
-use dns_ingress::metrics::{Metrics, Timer};
+use dns_ingress::metrics::{
+    MAX_TRACKED_PASSTHROUGH_SNIS, MAX_TRACKED_TARGETS, Metrics, OTHER_PASSTHROUGH_SNI, Timer,
+};
+use dns_ingress::sni::RewriteResult;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -12,7 +15,9 @@ async fn test_metrics_snapshot_empty() {
     assert_eq!(snapshot.failed_requests, 0);
     assert_eq!(snapshot.bytes_received, 0);
     assert_eq!(snapshot.bytes_sent, 0);
-    assert_eq!(snapshot.sni_rewrites, 0);
+    assert_eq!(snapshot.upstream_bytes_sent, 0);
+    assert_eq!(snapshot.upstream_bytes_received, 0);
+    assert_eq!(snapshot.rewrite_hits, 0);
     assert_eq!(snapshot.upstream_errors, 0);
     assert_eq!(snapshot.average_processing_time_ms, 0.0);
     assert_eq!(snapshot.success_rate, 0.0);
@@ -26,7 +31,7 @@ async fn test_metrics_snapshot_with_data() {
     metrics.record_request(true, 100, 200, Duration::from_millis(50));
     metrics.record_request(true, 150, 250, Duration::from_millis(30));
     metrics.record_request(false, 50, 0, Duration::from_millis(10));
-    metrics.record_sni_rewrite();
+    metrics.record_rewrite_hit();
     metrics.record_upstream_error();
 
     let snapshot = metrics.snapshot().await;
@@ -36,7 +41,7 @@ async fn test_metrics_snapshot_with_data() {
     assert_eq!(snapshot.failed_requests, 1);
     assert_eq!(snapshot.bytes_received, 300);
     assert_eq!(snapshot.bytes_sent, 450);
-    assert_eq!(snapshot.sni_rewrites, 1);
+    assert_eq!(snapshot.rewrite_hits, 1);
     assert_eq!(snapshot.upstream_errors, 1);
 
     // Check success rate (2/3 * 100 = 66.67%)
@@ -48,6 +53,149 @@ async fn test_metrics_snapshot_with_data() {
     );
 }
 
+#[tokio::test]
+async fn test_metrics_upstream_bytes_tracked_separately() {
+    let metrics = Metrics::new();
+    metrics.record_request(true, 40, 120, Duration::from_millis(5));
+    // A retried query reaches the upstream twice
+    metrics.record_upstream_bytes(51, 131);
+    metrics.record_upstream_bytes(51, 131);
+
+    let snapshot = metrics.snapshot().await;
+    assert_eq!(snapshot.bytes_received, 40);
+    assert_eq!(snapshot.bytes_sent, 120);
+    assert_eq!(snapshot.upstream_bytes_sent, 102);
+    assert_eq!(snapshot.upstream_bytes_received, 262);
+
+    let exported = metrics.export_prometheus();
+    assert!(exported.contains("dns_proxy_bytes_received_total 40"));
+    assert!(exported.contains("dns_proxy_bytes_sent_total 120"));
+    assert!(exported.contains("dns_proxy_upstream_bytes_sent_total 102"));
+    assert!(exported.contains("dns_proxy_upstream_bytes_received_total 262"));
+}
+
+#[tokio::test]
+async fn test_metrics_rewrite_outcomes() {
+    let metrics = Metrics::new();
+    let result = |passthrough| RewriteResult {
+        original: "www.example.org".to_string(),
+        prefix: String::new(),
+        target_hostname: "www.example.cn".to_string(),
+        passthrough,
+        warn: false,
+    };
+
+    metrics.record_rewrite(Some(&result(false)));
+    metrics.record_rewrite(Some(&result(false)));
+    metrics.record_rewrite(Some(&result(true)));
+    metrics.record_rewrite(None);
+    metrics.record_rewrite_miss();
+    metrics.record_rewrite_miss();
+
+    assert_eq!(metrics.rewrite_hits(), 2);
+    assert_eq!(metrics.rewrite_passthroughs(), 1);
+    assert_eq!(metrics.rewrite_misses(), 3);
+
+    let snapshot = metrics.snapshot().await;
+    assert_eq!(snapshot.rewrite_hits, 2);
+    assert_eq!(snapshot.rewrite_passthroughs, 1);
+    assert_eq!(snapshot.rewrite_misses, 3);
+
+    let exported = metrics.export_prometheus();
+    assert!(exported.contains("dns_proxy_sni_rewrite_hits_total 2"));
+    assert!(exported.contains("dns_proxy_sni_rewrite_passthroughs_total 1"));
+    assert!(exported.contains("dns_proxy_sni_rewrite_misses_total 3"));
+    // The pre-split total is still exported for existing dashboards
+    assert_eq!(metrics.sni_rewrites(), 6);
+    assert!(exported.contains("dns_proxy_sni_rewrites_total 6"));
+}
+
+#[tokio::test]
+#[allow(deprecated)]
+async fn test_deprecated_sni_rewrite_aliases() {
+    let metrics = Metrics::new();
+
+    metrics.record_sni_rewrite();
+    metrics.record_rewrite_miss();
+
+    assert_eq!(metrics.rewrite_hits(), 1);
+    let snapshot = metrics.snapshot().await;
+    assert_eq!(snapshot.sni_rewrites, 2);
+}
+
+#[test]
+fn test_top_rewrite_targets() {
+    let metrics = Metrics::new();
+    let rewrite = |target: &str| {
+        metrics.record_rewrite(Some(&RewriteResult {
+            original: target.to_string(),
+            prefix: String::new(),
+            target_hostname: target.to_string(),
+            passthrough: false,
+            warn: false,
+        }))
+    };
+    for _ in 0..3 {
+        rewrite("www.example.cn");
+    }
+    rewrite("mail.example.cn");
+    for _ in 0..5 {
+        rewrite("api.example.cn");
+    }
+    rewrite("cdn.example.cn");
+    metrics.record_rewrite(None);
+
+    assert_eq!(
+        metrics.top_rewrite_targets(3),
+        vec![
+            ("api.example.cn".to_string(), 5),
+            ("www.example.cn".to_string(), 3),
+            ("cdn.example.cn".to_string(), 1),
+        ]
+    );
+    assert_eq!(metrics.top_rewrite_targets(10).len(), 4);
+    assert!(metrics.top_rewrite_targets(0).is_empty());
+}
+
+#[test]
+fn test_rewrite_targets_evict_least_recent() {
+    let metrics = Metrics::new();
+    for i in 0..MAX_TRACKED_TARGETS {
+        metrics.record_rewrite_target(&format!("host-{}.example.cn", i));
+    }
+    // Touch the oldest target so host-1 becomes the least recently rewritten
+    metrics.record_rewrite_target("host-0.example.cn");
+    metrics.record_rewrite_target("new.example.cn");
+
+    let targets = metrics.top_rewrite_targets(usize::MAX);
+    assert_eq!(targets.len(), MAX_TRACKED_TARGETS);
+    assert_eq!(targets[0], ("host-0.example.cn".to_string(), 2));
+    assert!(targets.iter().any(|(target, _)| target == "new.example.cn"));
+    assert!(
+        !targets
+            .iter()
+            .any(|(target, _)| target == "host-1.example.cn")
+    );
+}
+
+#[test]
+fn test_passthrough_warnings_bounded() {
+    let metrics = Metrics::new();
+    for i in 0..MAX_TRACKED_PASSTHROUGH_SNIS {
+        metrics.record_rewrite_passthrough_warning(&format!("host-{}.other.com", i));
+    }
+    metrics.record_rewrite_passthrough_warning("HOST-0.other.com");
+    metrics.record_rewrite_passthrough_warning("new.other.com");
+    metrics.record_rewrite_passthrough_warning("newer.other.com");
+
+    assert_eq!(metrics.rewrite_passthrough_warnings("host-0.other.com"), 2);
+    assert_eq!(
+        metrics.rewrite_passthrough_warnings(OTHER_PASSTHROUGH_SNI),
+        2
+    );
+    assert!(!metrics.export_prometheus().contains("new.other.com"));
+}
+
 #[tokio::test]
 async fn test_metrics_concurrent_updates() {
     use std::thread;
@@ -61,7 +209,7 @@ async fn test_metrics_concurrent_updates() {
         let handle = thread::spawn(move || {
             for _ in 0..100 {
                 metrics_clone.record_request(true, 10, 20, Duration::from_millis(1));
-                metrics_clone.record_sni_rewrite();
+                metrics_clone.record_rewrite_hit();
             }
         });
         handles.push(handle);
@@ -75,7 +223,7 @@ async fn test_metrics_concurrent_updates() {
     let snapshot = metrics.snapshot().await;
     assert_eq!(snapshot.total_requests, 1000);
     assert_eq!(snapshot.successful_requests, 1000);
-    assert_eq!(snapshot.sni_rewrites, 1000);
+    assert_eq!(snapshot.rewrite_hits, 1000);
     assert_eq!(snapshot.bytes_received, 10000);
     assert_eq!(snapshot.bytes_sent, 20000);
 }
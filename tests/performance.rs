@@ -9,7 +9,11 @@ async fn test_rewriter_performance_single() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
@@ -30,7 +34,11 @@ async fn test_rewriter_performance_concurrent() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = std::sync::Arc::new(BaseSniRewriter::new(config));
 
@@ -65,7 +73,11 @@ async fn test_rewriter_performance_sequential() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
@@ -96,7 +108,11 @@ async fn test_rewriter_performance_cache_hit() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
@@ -132,7 +148,11 @@ async fn test_rewriter_stress_many_domains() {
     let config = RewriteConfig {
         base_domains,
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
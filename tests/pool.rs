@@ -1,6 +1,7 @@
 use dns_ingress::upstream::pool::ConnectionPool;
 use std::sync::Arc;
 use std::sync::Once;
+use std::time::{Duration, Instant};
 
 static INIT: Once = Once::new();
 
@@ -53,3 +54,100 @@ fn test_connection_pool_with_config() {
     // Test that it works
     let _client = pool.get_client("example.com");
 }
+
+#[test]
+fn test_evict_idle_removes_stale_clients() {
+    init_crypto_provider();
+    let keepalive = Duration::from_secs(60);
+    let pool = ConnectionPool::with_config(keepalive, Duration::from_secs(5), 5);
+
+    let _stale = pool.get_client("stale.example.com");
+    std::thread::sleep(Duration::from_millis(20));
+    let checkpoint = Instant::now();
+    let _fresh = pool.get_client("fresh.example.com");
+    assert_eq!(pool.len(), 2);
+
+    // Nothing is idle yet
+    assert_eq!(pool.evict_idle(Instant::now()), 0);
+
+    // Advance the clock to just before the fresh client expires
+    let later = checkpoint + keepalive - Duration::from_millis(1);
+    assert_eq!(pool.evict_idle(later), 1);
+    assert_eq!(pool.len(), 1);
+
+    // The survivor is the recently used client: fetching it doesn't grow the pool
+    let _ = pool.get_client("fresh.example.com");
+    assert_eq!(pool.len(), 1);
+
+    assert_eq!(pool.evict_idle(Instant::now() + keepalive), 1);
+    assert!(pool.is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_reaper_stops_when_pool_dropped() {
+    init_crypto_provider();
+    let pool = Arc::new(ConnectionPool::with_config(
+        Duration::from_secs(1),
+        Duration::from_secs(1),
+        1,
+    ));
+    let reaper = pool.spawn_reaper();
+    drop(pool);
+
+    tokio::time::advance(Duration::from_secs(2)).await;
+    tokio::time::timeout(Duration::from_secs(5), reaper)
+        .await
+        .expect("reaper should exit after the pool is dropped")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_pool_from_config_reuses_client_per_target() {
+    use dns_ingress::config::UpstreamPoolConfig;
+    use dns_ingress::upstream::forward_http_request;
+    use http_body_util::Full;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{HeaderMap, Method, Response};
+    use hyper_util::rt::TokioIo;
+
+    init_crypto_provider();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let service = service_fn(|_req| async {
+                    Response::builder().body(Full::new(bytes::Bytes::from_static(b"answer")))
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    let pool = ConnectionPool::from_config(&UpstreamPoolConfig::default());
+    let uri = format!("http://{}/dns-query", addr);
+    for _ in 0..2 {
+        let (response, _) = forward_http_request(
+            &pool,
+            &uri,
+            "upstream.example.cn",
+            Method::POST,
+            &HeaderMap::new(),
+            bytes::Bytes::from_static(b"query"),
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+    }
+
+    // Both requests were keyed on the same target, so one client serves them
+    assert_eq!(pool.len(), 1);
+    let first = pool.get_client("upstream.example.cn");
+    let second = pool.get_client("upstream.example.cn");
+    assert!(Arc::ptr_eq(&first, &second));
+    assert_eq!(pool.len(), 1);
+}
@@ -1,13 +1,30 @@
-use dns_ingress::config::RewriteConfig;
-use dns_ingress::rewrite::create_rewriter;
+use bytes::Bytes;
+use dns_ingress::config::{AppConfig, RewriteConfig};
+use dns_ingress::dns::cache::ResponseCache;
+use dns_ingress::metrics::Metrics;
+use dns_ingress::proxy::handle_http_request;
+use dns_ingress::rewrite::{SniRewriterType, create_rewriter};
 use dns_ingress::sni::SniRewriter;
+use dns_ingress::upstream::create_connection_pool;
+use dns_ingress::upstream::health::UpstreamHealth;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
 
 #[tokio::test]
 async fn test_rewriter_integration() {
     let rewriter = create_rewriter(RewriteConfig {
         base_domains: vec!["test.com".to_string()],
         target_suffix: ".test.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     });
 
     // Test that the rewriter works correctly
@@ -24,7 +41,11 @@ async fn test_rewriter_no_match() {
     let rewriter = create_rewriter(RewriteConfig {
         base_domains: vec!["test.com".to_string()],
         target_suffix: ".test.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     });
 
     // Test with non-matching domain
@@ -38,3 +59,753 @@ fn test_proxy_module_imports() {
     // Verify the module structure exists (just check it compiles)
     let _ = create_rewriter;
 }
+
+#[tokio::test]
+async fn test_client_abort_during_body_upload_is_quiet() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let metrics = Arc::new(Metrics::new());
+    let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+
+    let server_metrics = Arc::clone(&metrics);
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let rewriter = create_rewriter(RewriteConfig {
+            base_domains: vec!["test.com".to_string()],
+            target_suffix: ".test.cn".to_string(),
+            targets: Vec::new(),
+            rewrite_failure_strategy: "error".to_string(),
+            rewriter_type: "base".to_string(),
+            mapping_file: None,
+            rules: Vec::new(),
+        });
+        let config = Arc::new(AppConfig::default());
+        let health = Arc::new(UpstreamHealth::from_config(&config));
+        let cache = Arc::new(ResponseCache::from_config(&config.cache));
+        let pool = create_connection_pool(&config.upstream);
+        let result_tx = Arc::new(std::sync::Mutex::new(Some(result_tx)));
+
+        let service = service_fn(move |req| {
+            let rewriter = Arc::clone(&rewriter);
+            let pool = Arc::clone(&pool);
+            let health = Arc::clone(&health);
+            let cache = Arc::clone(&cache);
+            let config = Arc::clone(&config);
+            let metrics = Arc::clone(&server_metrics);
+            let result_tx = Arc::clone(&result_tx);
+            async move {
+                let response =
+                    handle_http_request(req, rewriter, &pool, &health, &cache, &config, metrics)
+                        .await;
+                if let Some(tx) = result_tx.lock().unwrap().take() {
+                    let _ = tx.send(response.status());
+                }
+                Ok::<_, std::io::Error>(response)
+            }
+        });
+        let _ = http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    });
+
+    // Promise a 100-byte body, send a few bytes, then hang up
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(
+            b"POST /dns-query HTTP/1.1\r\nHost: www.test.com\r\n\
+              Content-Type: application/dns-message\r\nContent-Length: 100\r\n\r\nabc",
+        )
+        .await
+        .unwrap();
+    client.shutdown().await.unwrap();
+    drop(client);
+
+    let outcome = tokio::time::timeout(std::time::Duration::from_secs(5), result_rx)
+        .await
+        .expect("handler should finish once the client disconnects")
+        .unwrap();
+    assert_eq!(outcome, hyper::StatusCode::BAD_REQUEST);
+    assert_eq!(metrics.upstream_errors(), 0);
+    assert_eq!(metrics.failed_requests(), 0);
+}
+
+#[tokio::test]
+async fn test_denied_host_gets_forbidden() {
+    use tokio::io::AsyncReadExt;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let metrics = Arc::new(Metrics::new());
+
+    let server_metrics = Arc::clone(&metrics);
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let rewriter = create_rewriter(RewriteConfig {
+            base_domains: vec!["test.com".to_string()],
+            target_suffix: ".test.cn".to_string(),
+            targets: Vec::new(),
+            rewrite_failure_strategy: "error".to_string(),
+            rewriter_type: "base".to_string(),
+            mapping_file: None,
+            rules: Vec::new(),
+        });
+        let mut config = AppConfig::default();
+        config.filter.deny_domains = vec!["blocked.test.com".to_string()];
+        let config = Arc::new(config);
+        let health = Arc::new(UpstreamHealth::from_config(&config));
+        let cache = Arc::new(ResponseCache::from_config(&config.cache));
+        let pool = create_connection_pool(&config.upstream);
+
+        let service = service_fn(move |req| {
+            let rewriter = Arc::clone(&rewriter);
+            let pool = Arc::clone(&pool);
+            let health = Arc::clone(&health);
+            let cache = Arc::clone(&cache);
+            let config = Arc::clone(&config);
+            let metrics = Arc::clone(&server_metrics);
+            async move {
+                Ok::<_, std::io::Error>(
+                    handle_http_request(req, rewriter, &pool, &health, &cache, &config, metrics)
+                        .await,
+                )
+            }
+        });
+        let _ = http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    });
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client
+        .write_all(
+            b"GET /dns-query?dns=AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB HTTP/1.1\r\n\
+              Host: api.blocked.test.com:443\r\nConnection: close\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 403"), "got: {}", response);
+    assert_eq!(metrics.blocked_requests(), 1);
+    assert_eq!(metrics.rewrite_hits(), 0);
+}
+
+#[test]
+fn test_forbidden_if_denied_strips_port() {
+    use dns_ingress::config::FilterConfig;
+    use dns_ingress::proxy::http::forbidden_if_denied;
+
+    let filter = FilterConfig {
+        allow_domains: vec!["example.com".to_string()],
+        deny_domains: vec![],
+    };
+    assert!(forbidden_if_denied("www.example.com:8443", &filter).is_none());
+    assert!(forbidden_if_denied("www.example.com", &filter).is_none());
+
+    let response = forbidden_if_denied("www.example.org:443", &filter).unwrap();
+    assert_eq!(response.status(), hyper::StatusCode::FORBIDDEN);
+    assert!(forbidden_if_denied("[::1]", &filter).is_some());
+}
+
+/// Serve one connection with `handle_http_request` and return the raw HTTP
+/// response to `request`
+///
+/// "127.test.com" is rewritten to 127.0.0.1.
+async fn exchange(
+    config: AppConfig,
+    pool: Arc<dns_ingress::upstream::pool::ConnectionPool>,
+    request: &[u8],
+) -> (String, Arc<Metrics>) {
+    let rewriter = create_rewriter(RewriteConfig {
+        base_domains: vec!["test.com".to_string()],
+        target_suffix: ".0.0.1".to_string(),
+        targets: Vec::new(),
+        rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
+    });
+    exchange_with_rewriter(config, pool, rewriter, request).await
+}
+
+/// [`exchange`] with hostnames rewritten by `rewriter`
+async fn exchange_with_rewriter(
+    config: AppConfig,
+    pool: Arc<dns_ingress::upstream::pool::ConnectionPool>,
+    rewriter: SniRewriterType,
+    request: &[u8],
+) -> (String, Arc<Metrics>) {
+    use tokio::io::AsyncReadExt;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let metrics = Arc::new(Metrics::new());
+
+    let server_metrics = Arc::clone(&metrics);
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let config = Arc::new(config);
+        let health = Arc::new(UpstreamHealth::from_config(&config));
+        let cache = Arc::new(ResponseCache::from_config(&config.cache));
+        let service = service_fn(move |req| {
+            let rewriter = Arc::clone(&rewriter);
+            let pool = Arc::clone(&pool);
+            let health = Arc::clone(&health);
+            let cache = Arc::clone(&cache);
+            let config = Arc::clone(&config);
+            let metrics = Arc::clone(&server_metrics);
+            async move {
+                Ok::<_, std::io::Error>(
+                    handle_http_request(req, rewriter, &pool, &health, &cache, &config, metrics)
+                        .await,
+                )
+            }
+        });
+        let _ = http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    });
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(request).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(
+        std::time::Duration::from_secs(10),
+        client.read_to_end(&mut response),
+    )
+    .await
+    .expect("handler should answer")
+    .unwrap();
+    // DNS message bodies are binary; only the head needs to be readable
+    (String::from_utf8_lossy(&response).into_owned(), metrics)
+}
+
+fn test_pool(config: &AppConfig) -> Arc<dns_ingress::upstream::pool::ConnectionPool> {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    create_connection_pool(&config.upstream)
+}
+
+#[tokio::test]
+async fn test_missing_host_gets_bad_request() {
+    let config = AppConfig::default();
+    let pool = test_pool(&config);
+    let (response, metrics) = exchange(config, pool, b"GET /dns-query HTTP/1.0\r\n\r\n").await;
+
+    assert!(response.starts_with("HTTP/1.0 400"), "got: {}", response);
+    assert_eq!(metrics.upstream_errors(), 0);
+}
+
+#[tokio::test]
+async fn test_unrewritable_host_gets_misdirected_request() {
+    let config = AppConfig::default();
+    let pool = test_pool(&config);
+    let (response, metrics) = exchange(
+        config,
+        pool,
+        b"GET /dns-query HTTP/1.1\r\nHost: www.example.org\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+
+    assert!(response.starts_with("HTTP/1.1 421"), "got: {}", response);
+    assert_eq!(metrics.rewrite_hits(), 0);
+    assert_eq!(metrics.rewrite_misses(), 1);
+    assert_eq!(metrics.rewrite_failures("no_matching_base_domain"), 1);
+}
+
+#[tokio::test]
+async fn test_rewrite_failure_reason_picks_response() {
+    // A bare base domain has no prefix to keep
+    let config = AppConfig::default();
+    let pool = test_pool(&config);
+    let (response, metrics) = exchange(
+        config,
+        pool,
+        b"GET /dns-query HTTP/1.1\r\nHost: test.com\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 421"), "got: {}", response);
+    assert!(response.contains("no prefix"), "got: {}", response);
+    assert_eq!(metrics.rewrite_failures("no_prefix"), 1);
+    assert!(
+        metrics
+            .export_prometheus()
+            .contains("dns_proxy_sni_rewrite_failures_total{reason=\"no_prefix\"} 1")
+    );
+
+    // A rewriter that can't rewrite anything is the proxy's fault
+    let config = AppConfig::default();
+    let pool = test_pool(&config);
+    let rewriter = create_rewriter(RewriteConfig {
+        base_domains: Vec::new(),
+        target_suffix: ".0.0.1".to_string(),
+        targets: Vec::new(),
+        rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
+    });
+    let (response, metrics) = exchange_with_rewriter(
+        config,
+        pool,
+        rewriter,
+        b"GET /dns-query HTTP/1.1\r\nHost: www.example.com\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 500"), "got: {}", response);
+    assert_eq!(metrics.rewrite_failures("no_base_domains"), 1);
+}
+
+#[tokio::test]
+async fn test_upstream_failure_gets_bad_gateway() {
+    // Nothing listens on 127.0.0.1:443, so the upstream connection is refused
+    let mut config = AppConfig::default();
+    config.upstream.max_retries = 0;
+    let pool = test_pool(&config);
+    let (response, metrics) = exchange(
+        config,
+        pool,
+        format!(
+            "GET /dns-query?dns={} HTTP/1.1\r\nHost: 127.test.com\r\nConnection: close\r\n\r\n",
+            WWW_EXAMPLE_COM_DNS_PARAM
+        )
+        .as_bytes(),
+    )
+    .await;
+
+    assert!(response.starts_with("HTTP/1.1 502"), "got: {}", response);
+    assert_eq!(metrics.rewrite_hits(), 1);
+}
+
+#[tokio::test]
+async fn test_upstream_timeout_gets_gateway_timeout() {
+    // The only connection slot to the upstream is taken, so the request
+    // times out waiting for it
+    let mut config = AppConfig::default();
+    config.upstream.max_retries = 0;
+    config.upstream.upstream_timeout_ms = 200;
+    config.upstream.doh_max_conns_per_host = 1;
+    let pool = test_pool(&config);
+    let _slot = pool.acquire_permit("127.0.0.1").await;
+    let (response, metrics) = exchange(
+        config,
+        Arc::clone(&pool),
+        format!(
+            "GET /dns-query?dns={} HTTP/1.1\r\nHost: 127.test.com\r\nConnection: close\r\n\r\n",
+            WWW_EXAMPLE_COM_DNS_PARAM
+        )
+        .as_bytes(),
+    )
+    .await;
+
+    assert!(response.starts_with("HTTP/1.1 504"), "got: {}", response);
+    assert_eq!(metrics.upstream_errors(), 1);
+}
+
+/// Writer that captures formatted log output for assertions
+#[derive(Clone, Default)]
+struct CapturedLogs(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_dry_run_logs_rewrite_without_forwarding() {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    // Forwarding to 127.0.0.1:443 would be refused and answered with 502
+    let mut config = AppConfig::default();
+    config.upstream.max_retries = 0;
+    config.proxy.dry_run = true;
+    let pool = test_pool(&config);
+    let (response, metrics) = exchange(
+        config,
+        pool,
+        format!(
+            "GET /dns-query?dns={} HTTP/1.1\r\nHost: 127.test.com\r\nConnection: close\r\n\r\n",
+            WWW_EXAMPLE_COM_DNS_PARAM
+        )
+        .as_bytes(),
+    )
+    .await;
+
+    assert!(response.starts_with("HTTP/1.1 200"), "got: {}", response);
+    assert!(
+        response.contains("content-type: application/dns-message"),
+        "got: {}",
+        response
+    );
+    assert_eq!(metrics.rewrite_hits(), 1);
+    assert_eq!(metrics.dry_run_requests(), 1);
+    assert_eq!(metrics.upstream_bytes_sent(), 0);
+    assert_eq!(metrics.upstream_errors(), 0);
+
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(
+        logs.contains("SNI rewrite: 127.test.com -> 127 -> Target: 127.0.0.1"),
+        "{}",
+        logs
+    );
+    assert!(
+        logs.contains("Dry run: would forward query to https://127.0.0.1/dns-query"),
+        "{}",
+        logs
+    );
+}
+
+#[tokio::test]
+async fn test_custom_doh_path() {
+    let mut config = AppConfig::default();
+    config.upstream.max_retries = 0;
+    config.servers.doh_path = "/custom-dns".to_string();
+    for (path, status) in [("/custom-dns", "502"), ("/dns-query", "404")] {
+        let pool = test_pool(&config);
+        let (response, metrics) = exchange(
+            config.clone(),
+            pool,
+            format!(
+                "GET {}?dns={} HTTP/1.1\r\nHost: 127.test.com\r\nConnection: close\r\n\r\n",
+                path, WWW_EXAMPLE_COM_DNS_PARAM
+            )
+            .as_bytes(),
+        )
+        .await;
+        assert!(
+            response.starts_with(&format!("HTTP/1.1 {}", status)),
+            "{}: got {}",
+            path,
+            response
+        );
+        // Only the matching path is forwarded
+        assert_eq!(
+            metrics.rewrite_hits(),
+            u64::from(status == "502"),
+            "{}",
+            path
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_doh_upstream_path_replaces_client_path() {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let mut config = AppConfig::default();
+    config.proxy.dry_run = true;
+    config.servers.doh_path = "/custom-dns".to_string();
+    config.servers.doh_upstream_path = Some("/resolve".to_string());
+    let pool = test_pool(&config);
+    let (response, _metrics) = exchange(
+        config,
+        pool,
+        format!(
+            "GET /custom-dns?dns={}&ct=1 HTTP/1.1\r\nHost: 127.test.com\r\nConnection: close\r\n\r\n",
+            WWW_EXAMPLE_COM_DNS_PARAM
+        )
+        .as_bytes(),
+    )
+    .await;
+
+    assert!(response.starts_with("HTTP/1.1 200"), "got: {}", response);
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+    assert!(
+        logs.contains("Dry run: would forward query to https://127.0.0.1/resolve?ct=1"),
+        "{}",
+        logs
+    );
+}
+
+#[test]
+fn test_doh_upstream_path_and_query() {
+    use dns_ingress::proxy::http::doh_upstream_path_and_query;
+
+    let mut servers = AppConfig::default().servers;
+    let uri: Uri = "/custom-dns?dns=AAAB&ct=1".parse().unwrap();
+    // Without doh_upstream_path the upstream URL's path, else the client's, is used
+    assert_eq!(
+        doh_upstream_path_and_query(&uri, &servers, Some("https://dns.example/dns-query")),
+        "/dns-query?ct=1"
+    );
+    assert_eq!(
+        doh_upstream_path_and_query(&uri, &servers, None),
+        "/custom-dns?ct=1"
+    );
+
+    servers.doh_upstream_path = Some("/resolve".to_string());
+    assert_eq!(
+        doh_upstream_path_and_query(&uri, &servers, Some("https://dns.example/dns-query")),
+        "/resolve?ct=1"
+    );
+    let uri: Uri = "/custom-dns?dns=AAAB".parse().unwrap();
+    assert_eq!(
+        doh_upstream_path_and_query(&uri, &servers, None),
+        "/resolve"
+    );
+}
+
+/// RFC 8484 example: A query for www.example.com
+const WWW_EXAMPLE_COM_DNS_PARAM: &str = "AAABAAABAAAAAAAAA3d3dwdleGFtcGxlA2NvbQAAAQAB";
+
+fn www_example_com_query() -> Vec<u8> {
+    let mut msg = vec![0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    msg.extend_from_slice(b"\x03www\x07example\x03com\x00\x00\x01\x00\x01");
+    msg
+}
+
+#[test]
+fn test_decode_dns_param() {
+    use dns_ingress::proxy::http::decode_dns_param;
+
+    let query = www_example_com_query();
+    let param = format!("dns={}", WWW_EXAMPLE_COM_DNS_PARAM);
+    assert_eq!(decode_dns_param(Some(&param)), Some(query.clone()));
+    let padded = format!("ct=1&{}==", param);
+    assert_eq!(decode_dns_param(Some(&padded)), Some(query));
+
+    assert_eq!(decode_dns_param(None), None);
+    assert_eq!(decode_dns_param(Some("ct=1")), None);
+    assert_eq!(decode_dns_param(Some("dns=not+base64url")), None);
+}
+
+/// Upstream connections to 127.0.0.1:443 are refused, so a request that
+/// passes validation and is forwarded gets 502
+async fn doh_status(request: String) -> (String, Arc<Metrics>) {
+    let mut config = AppConfig::default();
+    config.upstream.max_retries = 0;
+    let pool = test_pool(&config);
+    exchange(config, pool, request.as_bytes()).await
+}
+
+#[tokio::test]
+async fn test_valid_post_is_forwarded() {
+    let query = www_example_com_query();
+    let mut request = format!(
+        "POST /dns-query HTTP/1.1\r\nHost: 127.test.com\r\nConnection: close\r\n\
+         Content-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+        query.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&query);
+    let mut config = AppConfig::default();
+    config.upstream.max_retries = 0;
+    let pool = test_pool(&config);
+    let (response, metrics) = exchange(config, pool, &request).await;
+
+    assert!(response.starts_with("HTTP/1.1 502"), "got: {}", response);
+    assert_eq!(metrics.rewrite_hits(), 1);
+}
+
+#[tokio::test]
+async fn test_post_with_wrong_content_type_is_rejected() {
+    let (response, metrics) = doh_status(
+        "POST /dns-query HTTP/1.1\r\nHost: 127.test.com\r\nConnection: close\r\n\
+         Content-Type: application/json\r\nContent-Length: 2\r\n\r\n{}"
+            .to_string(),
+    )
+    .await;
+
+    assert!(response.starts_with("HTTP/1.1 415"), "got: {}", response);
+    assert_eq!(metrics.rewrite_hits(), 0);
+}
+
+#[tokio::test]
+async fn test_malformed_doh_requests_are_rejected() {
+    let cases = [
+        ("GET /dns-query HTTP/1.1", "400"),
+        ("GET /dns-query?dns=%%% HTTP/1.1", "400"),
+        ("GET /dns-query?dns=AAAB HTTP/1.1", "400"),
+        ("GET /resolve?dns=AAAB HTTP/1.1", "404"),
+        ("PUT /dns-query HTTP/1.1", "405"),
+    ];
+    for (request_line, status) in cases {
+        let (response, metrics) = doh_status(format!(
+            "{}\r\nHost: 127.test.com\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+            request_line
+        ))
+        .await;
+        assert!(
+            response.starts_with(&format!("HTTP/1.1 {}", status)),
+            "{}: got {}",
+            request_line,
+            response
+        );
+        assert_eq!(metrics.upstream_errors(), 0);
+    }
+}
+
+#[test]
+fn test_doh_query_message() {
+    use dns_ingress::proxy::http::doh_query_message;
+
+    let config = AppConfig::default();
+    let query = www_example_com_query();
+
+    // DoH and DoH3 forward the decoded `dns` parameter of a GET as the body
+    let get: Uri = format!("/dns-query?dns={}", WWW_EXAMPLE_COM_DNS_PARAM)
+        .parse()
+        .unwrap();
+    assert_eq!(
+        doh_query_message(&get, None, &config).unwrap(),
+        Bytes::from(query.clone())
+    );
+    let post: Uri = "/dns-query".parse().unwrap();
+    assert_eq!(
+        doh_query_message(&post, Some(Bytes::from(query.clone())), &config).unwrap(),
+        Bytes::from(query.clone())
+    );
+
+    assert_eq!(
+        doh_query_message(&post, None, &config),
+        Err(StatusCode::BAD_REQUEST)
+    );
+    assert_eq!(
+        doh_query_message(&post, Some(Bytes::from_static(b"short")), &config),
+        Err(StatusCode::BAD_REQUEST)
+    );
+
+    let mut config = AppConfig::default();
+    config.servers.max_message_size = query.len() - 1;
+    assert_eq!(
+        doh_query_message(&get, None, &config),
+        Err(StatusCode::PAYLOAD_TOO_LARGE)
+    );
+}
+
+#[tokio::test]
+async fn test_oversized_get_is_rejected() {
+    let mut config = AppConfig::default();
+    config.servers.max_message_size = 16;
+    let pool = test_pool(&config);
+    let (response, metrics) = exchange(
+        config,
+        pool,
+        format!(
+            "GET /dns-query?dns={} HTTP/1.1\r\nHost: 127.test.com\r\nConnection: close\r\n\r\n",
+            WWW_EXAMPLE_COM_DNS_PARAM
+        )
+        .as_bytes(),
+    )
+    .await;
+
+    assert!(response.starts_with("HTTP/1.1 413"), "got: {}", response);
+    assert_eq!(metrics.oversized_rejected(), 1);
+}
+
+#[tokio::test]
+async fn test_oversized_post_is_rejected_before_body_ends() {
+    let mut config = AppConfig::default();
+    config.servers.max_message_size = 16;
+    let pool = test_pool(&config);
+    // Send one chunk over the limit and never finish the body; the handler
+    // must answer without waiting for the rest
+    let mut request = b"POST /dns-query HTTP/1.1\r\nHost: 127.test.com\r\nConnection: close\r\n\
+         Content-Type: application/dns-message\r\nTransfer-Encoding: chunked\r\n\r\n40\r\n"
+        .to_vec();
+    request.extend_from_slice(&[0u8; 0x40]);
+    request.extend_from_slice(b"\r\n");
+    let (response, metrics) = exchange(config, pool, &request).await;
+
+    assert!(response.starts_with("HTTP/1.1 413"), "got: {}", response);
+    assert_eq!(metrics.oversized_rejected(), 1);
+    assert_eq!(metrics.rewrite_hits(), 1);
+}
+
+#[tokio::test]
+async fn test_excessive_headers_are_rejected() {
+    let mut config = AppConfig::default();
+    config.servers.max_header_count = 8;
+    let pool = test_pool(&config);
+    let mut request = format!(
+        "GET /dns-query?dns={} HTTP/1.1\r\nHost: 127.test.com\r\nConnection: close\r\n",
+        WWW_EXAMPLE_COM_DNS_PARAM
+    );
+    for i in 0..10 {
+        request.push_str(&format!("X-Filler-{}: {}\r\n", i, i));
+    }
+    request.push_str("\r\n");
+    let (response, _) = exchange(config, pool, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 431"), "got: {}", response);
+
+    let mut config = AppConfig::default();
+    config.servers.max_header_bytes = 256;
+    let pool = test_pool(&config);
+    let request = format!(
+        "GET /dns-query?dns={} HTTP/1.1\r\nHost: 127.test.com\r\nConnection: close\r\nX-Filler: {}\r\n\r\n",
+        WWW_EXAMPLE_COM_DNS_PARAM,
+        "a".repeat(300)
+    );
+    let (response, _) = exchange(config, pool, request.as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 431"), "got: {}", response);
+}
+
+/// Rewriter that sends every hostname to one target
+struct FixedRewriter {
+    target: String,
+    calls: std::sync::atomic::AtomicUsize,
+}
+
+#[async_trait::async_trait]
+impl SniRewriter for FixedRewriter {
+    async fn rewrite_with_reason(
+        &self,
+        sni: &str,
+    ) -> Result<dns_ingress::sni::RewriteResult, dns_ingress::error::SniRewriteError> {
+        self.calls
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Ok(dns_ingress::sni::RewriteResult {
+            original: sni.to_string(),
+            prefix: String::new(),
+            target_hostname: self.target.clone(),
+            passthrough: false,
+            warn: false,
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_custom_rewriter_is_used() {
+    let rewriter = Arc::new(FixedRewriter {
+        target: "127.0.0.1".to_string(),
+        calls: Default::default(),
+    });
+    let mut config = AppConfig::default();
+    config.upstream.max_retries = 0;
+    let pool = test_pool(&config);
+    // No base domain matches this host, so the built-in rewriter would give 421
+    let (response, metrics) = exchange_with_rewriter(
+        config,
+        pool,
+        Arc::clone(&rewriter) as SniRewriterType,
+        format!(
+            "GET /dns-query?dns={} HTTP/1.1\r\nHost: dns.example.net\r\nConnection: close\r\n\r\n",
+            WWW_EXAMPLE_COM_DNS_PARAM
+        )
+        .as_bytes(),
+    )
+    .await;
+
+    // Forwarded to the fixed target, where nothing listens
+    assert!(response.starts_with("HTTP/1.1 502"), "got: {}", response);
+    assert_eq!(rewriter.calls.load(std::sync::atomic::Ordering::Relaxed), 1);
+    assert_eq!(metrics.rewrite_hits(), 1);
+}
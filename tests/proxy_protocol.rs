@@ -0,0 +1,102 @@
+use dns_ingress::proxy_protocol::read_header;
+use std::net::SocketAddr;
+use tokio::io::AsyncReadExt;
+
+/// v2 PROXY header for a TCP connection from `source` to `destination`
+fn v2_header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21".to_vec();
+    match (source, destination) {
+        (SocketAddr::V4(source), SocketAddr::V4(destination)) => {
+            header.extend_from_slice(&[0x11, 0, 12]);
+            header.extend_from_slice(&source.ip().octets());
+            header.extend_from_slice(&destination.ip().octets());
+        }
+        (SocketAddr::V6(source), SocketAddr::V6(destination)) => {
+            header.extend_from_slice(&[0x21, 0, 36]);
+            header.extend_from_slice(&source.ip().octets());
+            header.extend_from_slice(&destination.ip().octets());
+        }
+        _ => unreachable!("mixed address families"),
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+/// Parse the header at the start of `input` and return it with whatever
+/// followed it
+async fn parse(input: &[u8]) -> (std::io::Result<Option<SocketAddr>>, Vec<u8>) {
+    let mut stream = input;
+    let addr = read_header(&mut stream).await;
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).await.unwrap();
+    (addr, rest)
+}
+
+#[tokio::test]
+async fn test_read_v1_header() {
+    let (addr, rest) = parse(b"PROXY TCP4 203.0.113.7 192.0.2.1 5353 853\r\nquery").await;
+    assert_eq!(addr.unwrap(), Some("203.0.113.7:5353".parse().unwrap()));
+    assert_eq!(rest, b"query");
+
+    let (addr, _) = parse(b"PROXY TCP6 2001:db8::7 2001:db8::1 5353 443\r\n").await;
+    assert_eq!(addr.unwrap(), Some("[2001:db8::7]:5353".parse().unwrap()));
+
+    let (addr, rest) = parse(b"PROXY UNKNOWN\r\nquery").await;
+    assert_eq!(addr.unwrap(), None);
+    assert_eq!(rest, b"query");
+}
+
+#[tokio::test]
+async fn test_read_v2_header() {
+    let mut input = v2_header(
+        "203.0.113.7:5353".parse().unwrap(),
+        "192.0.2.1:853".parse().unwrap(),
+    );
+    input.extend_from_slice(b"query");
+    let (addr, rest) = parse(&input).await;
+    assert_eq!(addr.unwrap(), Some("203.0.113.7:5353".parse().unwrap()));
+    assert_eq!(rest, b"query");
+
+    let input = v2_header(
+        "[2001:db8::7]:5353".parse().unwrap(),
+        "[2001:db8::1]:443".parse().unwrap(),
+    );
+    let (addr, _) = parse(&input).await;
+    assert_eq!(addr.unwrap(), Some("[2001:db8::7]:5353".parse().unwrap()));
+
+    // LOCAL connections (load balancer health checks) carry no client
+    let mut local = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00".to_vec();
+    local.extend_from_slice(b"query");
+    let (addr, rest) = parse(&local).await;
+    assert_eq!(addr.unwrap(), None);
+    assert_eq!(rest, b"query");
+}
+
+#[tokio::test]
+async fn test_read_header_rejects_invalid_input() {
+    let mut truncated_v2 = v2_header(
+        "203.0.113.7:5353".parse().unwrap(),
+        "192.0.2.1:853".parse().unwrap(),
+    );
+    truncated_v2.truncate(20);
+    let mut unknown_version = v2_header(
+        "203.0.113.7:5353".parse().unwrap(),
+        "192.0.2.1:853".parse().unwrap(),
+    );
+    unknown_version[12] = 0x31;
+    let long_v1 = [b"PROXY ".as_slice(), &[b'x'; 200]].concat();
+
+    let inputs: [&[u8]; 6] = [
+        b"GET /dns-query HTTP/1.1\r\n\r\n",
+        b"PROXY TCP4 2001:db8::7 192.0.2.1 5353 853\r\n",
+        b"PROXY TCP4 203.0.113.7 192.0.2.1 70000 853\r\n",
+        &long_v1,
+        &truncated_v2,
+        &unknown_version,
+    ];
+    for input in inputs {
+        let (addr, _) = parse(input).await;
+        assert!(addr.is_err(), "{:?}", String::from_utf8_lossy(input));
+    }
+}
@@ -0,0 +1,172 @@
+//! DoH responses streamed from a TLS upstream through `handle_http_request`
+//!
+//! Kept in a test binary of its own: the upstream's certificate is trusted
+//! through `SSL_CERT_FILE`, which applies to every pool the process creates.
+
+use dns_ingress::config::AppConfig;
+use dns_ingress::dns::cache::ResponseCache;
+use dns_ingress::error::SniRewriteError;
+use dns_ingress::metrics::Metrics;
+use dns_ingress::proxy::handle_http_request;
+use dns_ingress::sni::{RewriteResult, SniRewriter};
+use dns_ingress::upstream::STREAMING_THRESHOLD;
+use dns_ingress::upstream::create_connection_pool;
+use dns_ingress::upstream::health::UpstreamHealth;
+use http_body_util::{BodyExt, Full, StreamBody};
+use hyper::body::Frame;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+/// Rewriter that sends every hostname to one target
+struct FixedRewriter(String);
+
+#[async_trait::async_trait]
+impl SniRewriter for FixedRewriter {
+    async fn rewrite_with_reason(&self, sni: &str) -> Result<RewriteResult, SniRewriteError> {
+        Ok(RewriteResult {
+            original: sni.to_string(),
+            prefix: String::new(),
+            target_hostname: self.0.clone(),
+            passthrough: false,
+            warn: false,
+        })
+    }
+}
+
+/// Start an HTTPS upstream for `localhost`, trusted through `SSL_CERT_FILE`,
+/// that answers with `chunks` chunks of `chunk_len` bytes and holds the last
+/// one back until `release` fires
+async fn start_streaming_https_upstream(
+    dir: &std::path::Path,
+    chunks: usize,
+    chunk_len: usize,
+    release: tokio::sync::oneshot::Receiver<()>,
+) -> u16 {
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let ca_file = dir.join("upstream-ca.pem");
+    std::fs::write(&ca_file, certified.cert.pem()).unwrap();
+    // SAFETY: set before any pool loads its roots, with no other test in
+    // this binary reading the environment
+    unsafe { std::env::set_var("SSL_CERT_FILE", &ca_file) };
+
+    let server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from(certified.cert.der().to_vec())],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+                certified.signing_key.serialize_der(),
+            )),
+        )
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let release = Arc::new(std::sync::Mutex::new(Some(release)));
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream = acceptor.accept(stream).await.unwrap();
+        let service = service_fn(move |_req| {
+            let release = release.lock().unwrap().take().unwrap();
+            let (tx, rx) =
+                tokio::sync::mpsc::channel::<Result<Frame<bytes::Bytes>, std::io::Error>>(chunks);
+            tokio::spawn(async move {
+                let chunk = || Ok(Frame::data(bytes::Bytes::from(vec![b'x'; chunk_len])));
+                for _ in 1..chunks {
+                    let _ = tx.send(chunk()).await;
+                }
+                let _ = release.await;
+                let _ = tx.send(chunk()).await;
+            });
+            let frames = futures::stream::unfold(rx, |mut rx| async move {
+                rx.recv().await.map(|frame| (frame, rx))
+            });
+            async move { hyper::Response::builder().body(StreamBody::new(frames)) }
+        });
+        let _ = http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    });
+
+    port
+}
+
+#[tokio::test]
+async fn test_large_upstream_response_is_streamed_to_doh_client() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let chunk_len = 32 * 1024;
+    let chunks = STREAMING_THRESHOLD / chunk_len + 3;
+    let (release, released) = tokio::sync::oneshot::channel();
+    let upstream_port =
+        start_streaming_https_upstream(dir.path(), chunks, chunk_len, released).await;
+
+    let mut config = AppConfig::default();
+    config.upstream.max_retries = 0;
+    let config = Arc::new(config);
+    let pool = create_connection_pool(&config.upstream);
+    let health = Arc::new(UpstreamHealth::from_config(&config));
+    let cache = Arc::new(ResponseCache::from_config(&config.cache));
+    let metrics = Arc::new(Metrics::new());
+    let rewriter: dns_ingress::rewrite::SniRewriterType =
+        Arc::new(FixedRewriter(format!("localhost:{}", upstream_port)));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server_metrics = Arc::clone(&metrics);
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let service = service_fn(move |req| {
+            let rewriter = Arc::clone(&rewriter);
+            let pool = Arc::clone(&pool);
+            let health = Arc::clone(&health);
+            let cache = Arc::clone(&cache);
+            let config = Arc::clone(&config);
+            let metrics = Arc::clone(&server_metrics);
+            async move {
+                Ok::<_, std::io::Error>(
+                    handle_http_request(req, rewriter, &pool, &health, &cache, &config, metrics)
+                        .await,
+                )
+            }
+        });
+        let _ = http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    });
+
+    let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    // Query for www.example.com A
+    let query: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+        \x03www\x07example\x03com\x00\x00\x01\x00\x01";
+    let request = hyper::Request::post("/dns-query")
+        .header("host", "dns.example.com")
+        .header("content-type", "application/dns-message")
+        .body(Full::new(bytes::Bytes::from_static(query)))
+        .unwrap();
+
+    // The upstream has not sent the whole body yet, so the response head
+    // only arrives if the body is streamed
+    let response = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        sender.send_request(request),
+    )
+    .await
+    .expect("response head should arrive before the upstream body ends")
+    .unwrap();
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+
+    release.send(()).unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body.len(), chunks * chunk_len);
+    assert_eq!(metrics.upstream_errors(), 0);
+}
@@ -1,4 +1,5 @@
-use dns_ingress::quic::create_quic_server_endpoint;
+use dns_ingress::config::{AppConfig, CertificateConfig};
+use dns_ingress::quic::{create_quic_server_endpoint, create_quic_tls_config};
 
 #[test]
 fn test_quic_module_imports() {
@@ -6,3 +7,170 @@ fn test_quic_module_imports() {
     // Verify the function exists (just check it compiles)
     let _ = create_quic_server_endpoint;
 }
+
+#[tokio::test]
+async fn test_create_quic_server_endpoint_from_config() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let cert_file = dir.path().join("cert.pem");
+    let key_file = dir.path().join("key.pem");
+    let certified = rcgen::generate_simple_self_signed(vec!["example.com".to_string()]).unwrap();
+    std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+    std::fs::write(&key_file, certified.signing_key.serialize_pem()).unwrap();
+
+    let mut config = AppConfig::default();
+    config.tls.default = Some(CertificateConfig {
+        cert_file: cert_file.to_string_lossy().into_owned(),
+        key_file: key_file.to_string_lossy().into_owned(),
+        key_passphrase: None,
+        ca_file: None,
+        require_client_cert: false,
+        ocsp_file: None,
+    });
+    let (endpoints, _resolver) = create_quic_server_endpoint(
+        &config,
+        &["127.0.0.1:0".parse().unwrap(), "[::]:0".parse().unwrap()],
+        &config.tls.alpn.doq,
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(endpoints.len(), 2);
+    for endpoint in &endpoints {
+        assert_ne!(endpoint.local_addr().unwrap().port(), 0);
+        endpoint.close(0u32.into(), b"");
+    }
+
+    // A certificate that cannot be loaded fails the endpoint
+    std::fs::remove_file(&key_file).unwrap();
+    assert!(
+        create_quic_server_endpoint(
+            &config,
+            &["127.0.0.1:0".parse().unwrap()],
+            &config.tls.alpn.doq,
+            false,
+        )
+        .await
+        .is_err()
+    );
+}
+
+#[tokio::test]
+async fn test_quic_server_endpoint_applies_transport_config() {
+    use quinn::crypto::rustls::QuicClientConfig;
+    use std::time::Duration;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let cert_file = dir.path().join("cert.pem");
+    let key_file = dir.path().join("key.pem");
+    let certified = rcgen::generate_simple_self_signed(vec!["example.com".to_string()]).unwrap();
+    std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+    std::fs::write(&key_file, certified.signing_key.serialize_pem()).unwrap();
+
+    let mut config = AppConfig::default();
+    config.tls.default = Some(CertificateConfig {
+        cert_file: cert_file.to_string_lossy().into_owned(),
+        key_file: key_file.to_string_lossy().into_owned(),
+        key_passphrase: None,
+        ca_file: None,
+        require_client_cert: false,
+        ocsp_file: None,
+    });
+    config.quic.max_idle_timeout_ms = 1_000;
+    config.quic.max_concurrent_bidi_streams = 1;
+    config.validate().unwrap();
+
+    let (endpoints, _resolver) = create_quic_server_endpoint(
+        &config,
+        &["127.0.0.1:0".parse().unwrap()],
+        &config.tls.alpn.doq,
+        false,
+    )
+    .await
+    .unwrap();
+    let server = endpoints[0].clone();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Some(incoming) = server.accept().await {
+            if let Ok(connection) = incoming.await {
+                tokio::spawn(async move { connection.closed().await });
+            }
+        }
+    });
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(rustls::pki_types::CertificateDer::from(
+            certified.cert.der().to_vec(),
+        ))
+        .unwrap();
+    let mut client_crypto = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    client_crypto.alpn_protocols = vec![b"doq".to_vec()];
+    let mut client = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    client.set_default_client_config(quinn::ClientConfig::new(std::sync::Arc::new(
+        QuicClientConfig::try_from(client_crypto).unwrap(),
+    )));
+    let connection = client
+        .connect(server_addr, "example.com")
+        .unwrap()
+        .await
+        .unwrap();
+
+    // Only one stream may be open at a time
+    let (mut first, _recv) = connection.open_bi().await.unwrap();
+    first.write_all(b"\x00\x00").await.unwrap();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(300), connection.open_bi())
+            .await
+            .is_err()
+    );
+
+    // The connection is closed once idle for longer than the idle timeout
+    let reason = tokio::time::timeout(Duration::from_secs(5), connection.closed())
+        .await
+        .expect("idle connection should time out");
+    assert!(matches!(reason, quinn::ConnectionError::TimedOut));
+    endpoints[0].close(0u32.into(), b"");
+}
+
+#[tokio::test]
+async fn test_quic_tls_config_reflects_0rtt_setting() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let cert_file = dir.path().join("cert.pem");
+    let key_file = dir.path().join("key.pem");
+    let certified = rcgen::generate_simple_self_signed(vec!["example.com".to_string()]).unwrap();
+    std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+    std::fs::write(&key_file, certified.signing_key.serialize_pem()).unwrap();
+
+    let mut config = AppConfig::default();
+    config.tls.default = Some(CertificateConfig {
+        cert_file: cert_file.to_string_lossy().into_owned(),
+        key_file: key_file.to_string_lossy().into_owned(),
+        key_passphrase: None,
+        ca_file: None,
+        require_client_cert: false,
+        ocsp_file: None,
+    });
+    assert!(!config.quic.allow_0rtt);
+
+    let (tls_config, _) = create_quic_tls_config(&config, &config.tls.alpn.doq, false)
+        .await
+        .unwrap();
+    assert_eq!(tls_config.max_early_data_size, 0);
+
+    config.quic.allow_0rtt = true;
+    let (tls_config, _) =
+        create_quic_tls_config(&config, &config.tls.alpn.doq, config.quic.allow_0rtt)
+            .await
+            .unwrap();
+    assert_eq!(tls_config.max_early_data_size, u32::MAX);
+
+    // Early data needs resumable sessions
+    config.validate().unwrap();
+    config.tls.session_resumption = false;
+    assert!(config.validate().is_err());
+}
@@ -0,0 +1,127 @@
+use dns_ingress::config::AppConfig;
+use dns_ingress::upstream::quic_pool::QuicConnectionPool;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Start a DoQ upstream with a self-signed certificate for 127.0.0.1 that
+/// echoes every query back with QR set. Returns its address and a count of the
+/// QUIC connections it has accepted.
+fn start_mock_doq_upstream() -> (SocketAddr, Arc<AtomicUsize>) {
+    use dns_ingress::dns::framing::{read_framed, write_framed};
+    use quinn::crypto::rustls::QuicServerConfig;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        certified.signing_key.serialize_der(),
+    ));
+    let mut tls_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    tls_config.alpn_protocols = vec![b"doq".to_vec()];
+    let quic_config = QuicServerConfig::try_from(tls_config).unwrap();
+    let endpoint = quinn::Endpoint::server(
+        quinn::ServerConfig::with_crypto(Arc::new(quic_config)),
+        "127.0.0.1:0".parse().unwrap(),
+    )
+    .unwrap();
+    let addr = endpoint.local_addr().unwrap();
+
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&accepted);
+    tokio::spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            let Ok(connection) = incoming.await else {
+                continue;
+            };
+            counter.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(async move {
+                while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                    if let Ok(Some(mut message)) = read_framed(&mut recv).await {
+                        message[2] |= 0x80;
+                        let _ = write_framed(&mut send, &message).await;
+                        let _ = send.finish();
+                    }
+                }
+            });
+        }
+    });
+    (addr, accepted)
+}
+
+fn trusting_upstream_config() -> dns_ingress::config::UpstreamConfig {
+    let mut upstream = AppConfig::default().upstream;
+    upstream.danger_accept_invalid_certs = true;
+    upstream
+}
+
+const QUERY: [u8; 12] = [0x12, 0x34, 0x01, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+
+#[tokio::test]
+async fn test_queries_to_same_upstream_reuse_connection() {
+    let (addr, accepted) = start_mock_doq_upstream();
+    let upstream = trusting_upstream_config();
+    let pool = QuicConnectionPool::new();
+
+    for _ in 0..2 {
+        let response = pool
+            .forward(addr, "127.0.0.1", &QUERY, &upstream)
+            .await
+            .unwrap();
+        assert_eq!(&response[..2], &QUERY[..2]);
+        assert_ne!(response[2] & 0x80, 0);
+    }
+
+    assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    assert_eq!(pool.len(), 1);
+}
+
+#[tokio::test]
+async fn test_closed_connection_is_replaced() {
+    let (addr, accepted) = start_mock_doq_upstream();
+    let upstream = trusting_upstream_config();
+    let pool = QuicConnectionPool::new();
+
+    let first = pool.get(addr, "127.0.0.1", &upstream).await.unwrap();
+    first.close(0u32.into(), b"test");
+    assert_eq!(pool.evict_closed(), 1);
+    assert!(pool.is_empty());
+
+    // A connection closed while still pooled is replaced on the next query too
+    let second = pool.get(addr, "127.0.0.1", &upstream).await.unwrap();
+    second.close(0u32.into(), b"test");
+    let response = pool
+        .forward(addr, "127.0.0.1", &QUERY, &upstream)
+        .await
+        .unwrap();
+    assert_eq!(&response[..2], &QUERY[..2]);
+    assert_eq!(accepted.load(Ordering::SeqCst), 3);
+    assert_eq!(pool.len(), 1);
+}
+
+#[tokio::test]
+async fn test_queries_spread_over_max_per_upstream_connections() {
+    use dns_ingress::config::DoqPoolConfig;
+
+    let (addr, accepted) = start_mock_doq_upstream();
+    let upstream = trusting_upstream_config();
+    let pool = QuicConnectionPool::from_config(&DoqPoolConfig {
+        max_per_upstream: 2,
+        ..DoqPoolConfig::default()
+    });
+
+    for _ in 0..4 {
+        let response = pool
+            .forward(addr, "127.0.0.1", &QUERY, &upstream)
+            .await
+            .unwrap();
+        assert_eq!(&response[..2], &QUERY[..2]);
+    }
+
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    assert_eq!(pool.len(), 2);
+}
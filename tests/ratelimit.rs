@@ -0,0 +1,100 @@
+use dns_ingress::config::{AppConfig, RateLimitConfig};
+use dns_ingress::ratelimit::RateLimiter;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn ip(addr: &str) -> IpAddr {
+    addr.parse().unwrap()
+}
+
+#[test]
+fn test_burst_then_reject() {
+    let limiter = RateLimiter::new(10, 5);
+    let now = Instant::now();
+    let client = ip("192.0.2.1");
+
+    let accepted = (0..8).filter(|_| limiter.check_at(client, now)).count();
+    assert_eq!(accepted, 5);
+
+    // Other clients have their own bucket
+    assert!(limiter.check_at(ip("192.0.2.2"), now));
+}
+
+#[test]
+fn test_tokens_refill_over_time() {
+    let limiter = RateLimiter::new(10, 2);
+    let start = Instant::now();
+    let client = ip("2001:db8::1");
+
+    assert!(limiter.check_at(client, start));
+    assert!(limiter.check_at(client, start));
+    assert!(!limiter.check_at(client, start));
+
+    // 10 per second refills one token every 100ms
+    assert!(limiter.check_at(client, start + Duration::from_millis(100)));
+    assert!(!limiter.check_at(client, start + Duration::from_millis(100)));
+
+    // Refill is capped at the burst size
+    let later = start + Duration::from_secs(60);
+    let accepted = (0..5).filter(|_| limiter.check_at(client, later)).count();
+    assert_eq!(accepted, 2);
+}
+
+#[test]
+fn test_allowlist_and_disabled_limiter() {
+    let limiter = RateLimiter::new(1, 1).with_allowlist([ip("127.0.0.1")]);
+    let now = Instant::now();
+    assert!((0..100).all(|_| limiter.check_at(ip("127.0.0.1"), now)));
+    assert!(limiter.check_at(ip("192.0.2.1"), now));
+    assert!(!limiter.check_at(ip("192.0.2.1"), now));
+
+    // A rate of 0 disables limiting
+    let disabled = RateLimiter::from_config(&RateLimitConfig::default());
+    assert!((0..100).all(|_| disabled.check(ip("192.0.2.1"))));
+}
+
+#[test]
+fn test_burst_defaults_to_rate() {
+    let limiter = RateLimiter::new(3, 0);
+    let now = Instant::now();
+    let accepted = (0..10)
+        .filter(|_| limiter.check_at(ip("192.0.2.1"), now))
+        .count();
+    assert_eq!(accepted, 3);
+}
+
+#[test]
+fn test_evict_idle_drops_refilled_buckets() {
+    let limiter = Arc::new(RateLimiter::new(10, 5));
+    let now = Instant::now();
+    limiter.check_at(ip("192.0.2.1"), now);
+    limiter.check_at(ip("192.0.2.2"), now + Duration::from_secs(1));
+
+    // The first bucket has refilled by then, the second not yet
+    assert_eq!(limiter.evict_idle(now + Duration::from_millis(1050)), 1);
+    assert_eq!(limiter.evict_idle(now + Duration::from_secs(5)), 1);
+}
+
+#[test]
+fn test_ratelimit_config() {
+    let config: RateLimitConfig = toml::from_str(
+        r#"
+requests_per_sec = 20
+burst = 40
+allowlist = ["10.0.0.1", "::1"]
+"#,
+    )
+    .unwrap();
+    assert_eq!(config.requests_per_sec, 20);
+    assert_eq!(config.burst, 40);
+    assert_eq!(config.allowlist_ips().unwrap().len(), 2);
+
+    let mut app_config = AppConfig {
+        ratelimit: config,
+        ..Default::default()
+    };
+    app_config.validate().unwrap();
+    app_config.ratelimit.allowlist = vec!["not-an-ip".to_string()];
+    assert!(app_config.validate().is_err());
+}
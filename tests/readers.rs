@@ -1,14 +1,22 @@
 use dns_ingress::config::{AppConfig, RewriteConfig};
+use dns_ingress::dns::cache::ResponseCache;
 use dns_ingress::metrics::Metrics;
-use dns_ingress::readers::{DoH3Server, DoHServer, DoQServer, DoTServer, HealthcheckServer};
+use dns_ingress::readers::{
+    DoH3Server, DoHServer, DoQServer, DoTServer, HealthcheckServer, TcpDnsServer, UdpServer,
+};
 use dns_ingress::rewrite::create_rewriter;
+use dns_ingress::upstream::health::UpstreamHealth;
 use std::sync::Arc;
 
 fn create_test_rewriter() -> dns_ingress::rewrite::SniRewriterType {
     create_rewriter(RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     })
 }
 
@@ -56,6 +64,55 @@ fn test_doh3_server_new() {
     // Just verify it can be created without panicking
 }
 
+#[test]
+fn test_readers_report_their_protocol() {
+    use dns_ingress::readers::{self, Protocol};
+
+    let config = AppConfig::default();
+    let servers = &config.servers;
+    let expected = [
+        (Protocol::Dot, "DoT", &servers.dot, false),
+        (Protocol::Doh, "DoH", &servers.doh, false),
+        (Protocol::Doq, "DoQ", &servers.doq, true),
+        (Protocol::Doh3, "DoH3", &servers.doh3, true),
+        (Protocol::Udp, "UDP DNS", &servers.udp, true),
+        (Protocol::TcpDns, "TCP DNS", &servers.tcp_dns, false),
+    ];
+    let readers = readers::all();
+    assert_eq!(readers.len(), expected.len());
+    for (reader, (protocol, name, listen_config, udp)) in readers.iter().zip(expected) {
+        assert_eq!(reader.protocol(), protocol);
+        assert_eq!(protocol.name(), name);
+        assert!(std::ptr::eq(protocol.listen_config(&config), listen_config));
+        assert_eq!(protocol.is_udp(), udp);
+    }
+}
+
+#[tokio::test]
+async fn test_reader_start_returns_when_disabled() {
+    use dns_ingress::readers;
+    use dns_ingress::server::ServerResources;
+    use tokio_util::sync::CancellationToken;
+
+    let mut config = AppConfig::default();
+    config.servers.dot.enabled = false;
+    config.servers.doh.enabled = false;
+    config.servers.doq.enabled = false;
+    config.servers.doh3.enabled = false;
+    config.servers.udp.enabled = false;
+    config.servers.tcp_dns.enabled = false;
+    let config = Arc::new(config);
+    for reader in readers::all() {
+        let resources = ServerResources::standalone(
+            Arc::clone(&config),
+            create_test_rewriter(),
+            Arc::new(Metrics::new()),
+        );
+        let result = reader.start(resources, CancellationToken::new()).await;
+        assert!(result.is_ok(), "{:?}", reader.protocol());
+    }
+}
+
 #[tokio::test]
 async fn test_healthcheck_server_start_disabled() {
     let mut config = AppConfig::default();
@@ -124,3 +181,1736 @@ async fn test_doh3_server_start_disabled() {
     let result = server.start().await;
     assert!(result.is_ok());
 }
+
+/// Self-signed certificate for 127.0.0.1 shared by every mock upstream in this
+/// test binary. It is trusted once via SSL_CERT_FILE so parallel tests agree.
+fn test_upstream_cert() -> &'static (rcgen::CertifiedKey<rcgen::KeyPair>, tempfile::NamedTempFile) {
+    use std::io::Write;
+    use std::sync::OnceLock;
+
+    static CERT: OnceLock<(rcgen::CertifiedKey<rcgen::KeyPair>, tempfile::NamedTempFile)> =
+        OnceLock::new();
+    CERT.get_or_init(|| {
+        let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        let mut ca_file = tempfile::NamedTempFile::new().unwrap();
+        ca_file.write_all(certified.cert.pem().as_bytes()).unwrap();
+        ca_file.flush().unwrap();
+        // SAFETY: set exactly once, before any upstream TLS config reads it
+        unsafe { std::env::set_var("SSL_CERT_FILE", ca_file.path()) };
+        (certified, ca_file)
+    })
+}
+
+/// Start a mock DoT upstream that answers every framed query on a connection
+/// by echoing it back with the QR bit set. The first `drop_connections`
+/// connections are closed before the TLS handshake to simulate a flaky upstream.
+async fn start_mock_dot_upstream(drop_connections: usize) -> std::net::SocketAddr {
+    start_counting_dot_upstream(drop_connections).await.0
+}
+
+/// [`start_mock_dot_upstream`], also counting the connections it accepts
+async fn start_counting_dot_upstream(
+    drop_connections: usize,
+) -> (std::net::SocketAddr, Arc<std::sync::atomic::AtomicUsize>) {
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (certified, _) = test_upstream_cert();
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        certified.signing_key.serialize_der(),
+    ));
+    let server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let connections = Arc::new(AtomicUsize::new(0));
+    let accepted = Arc::clone(&connections);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            if accepted.fetch_add(1, Ordering::SeqCst) < drop_connections {
+                drop(stream);
+                continue;
+            }
+
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let mut tls = acceptor.accept(stream).await.unwrap();
+                while let Ok(len) = tls.read_u16().await {
+                    let mut query = vec![0u8; len as usize];
+                    tls.read_exact(&mut query).await.unwrap();
+                    query[2] |= 0x80;
+                    tls.write_u16(query.len() as u16).await.unwrap();
+                    tls.write_all(&query).await.unwrap();
+                    tls.flush().await.unwrap();
+                }
+            });
+        }
+    });
+
+    (addr, connections)
+}
+
+fn build_query(id: u16) -> Vec<u8> {
+    build_query_for(id, "www.example.com")
+}
+
+fn build_query_for(id: u16, name: &str) -> Vec<u8> {
+    let mut msg = id.to_be_bytes().to_vec();
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.extend_from_slice(&[0, 0, 1, 0, 1]);
+    msg
+}
+
+#[tokio::test]
+async fn test_dot_handle_connection_multiple_framed_queries() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let upstream = start_mock_dot_upstream(0).await;
+
+    let config = AppConfig::default();
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    let health = UpstreamHealth::from_config(&config);
+    let metrics = Metrics::new();
+    let (mut client, server) = tokio::io::duplex(4096);
+
+    let handler = async {
+        DoTServer::handle_connection(
+            server,
+            create_test_rewriter(),
+            upstream,
+            &config,
+            &pool,
+            &health,
+            &metrics,
+        )
+        .await
+    };
+
+    let client_side = async {
+        // Pipeline both queries before reading any response
+        for id in [1u16, 2] {
+            let query = build_query(id);
+            client.write_u16(query.len() as u16).await.unwrap();
+            client.write_all(&query).await.unwrap();
+        }
+
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let len = client.read_u16().await.unwrap() as usize;
+            let mut response = vec![0u8; len];
+            client.read_exact(&mut response).await.unwrap();
+            assert_ne!(response[2] & 0x80, 0);
+            ids.push(u16::from_be_bytes([response[0], response[1]]));
+        }
+        client.shutdown().await.unwrap();
+        drop(client);
+        ids
+    };
+
+    let (result, ids) = tokio::join!(handler, client_side);
+    assert!(result.is_ok());
+    assert_eq!(ids, vec![1, 2]);
+    assert_eq!(metrics.snapshot().await.total_requests, 2);
+}
+
+/// Send one query on a new DoT client connection to `handle_connection` and
+/// return the response, or `None` when the connection failed
+async fn dot_exchange(
+    upstream: std::net::SocketAddr,
+    config: &AppConfig,
+    pool: &dns_ingress::upstream::pool::ConnectionPool,
+    metrics: &Metrics,
+    id: u16,
+) -> Option<Vec<u8>> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let health = UpstreamHealth::from_config(config);
+    let (mut client, server) = tokio::io::duplex(4096);
+    // Owns the client so it disconnects once answered
+    let client_side = async move {
+        let query = build_query(id);
+        client.write_u16(query.len() as u16).await.unwrap();
+        client.write_all(&query).await.unwrap();
+        let len = client.read_u16().await.ok()? as usize;
+        let mut response = vec![0u8; len];
+        client.read_exact(&mut response).await.ok()?;
+        Some(response)
+    };
+    let handler = async {
+        let result = DoTServer::handle_connection(
+            server,
+            create_test_rewriter(),
+            upstream,
+            config,
+            pool,
+            &health,
+            metrics,
+        )
+        .await;
+        result.is_ok()
+    };
+    let (response, handled) = tokio::join!(client_side, handler);
+    response.filter(|_| handled)
+}
+
+#[tokio::test]
+async fn test_dot_handle_connection_reuses_pooled_upstream_connection() {
+    use std::sync::atomic::Ordering;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let (upstream, connections) = start_counting_dot_upstream(0).await;
+    let config = AppConfig::default();
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    let metrics = Metrics::new();
+
+    // Two client connections, one after the other
+    for id in [1u16, 2] {
+        let response = dot_exchange(upstream, &config, &pool, &metrics, id)
+            .await
+            .unwrap();
+        assert_eq!(&response[..2], &id.to_be_bytes());
+    }
+
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+    assert_eq!(pool.dot().len(), 1);
+}
+
+#[tokio::test]
+async fn test_dot_handle_connection_opens_circuit_of_failing_upstream() {
+    use std::sync::atomic::Ordering;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    // Every connection is dropped before the TLS handshake
+    let (upstream, connections) = start_counting_dot_upstream(usize::MAX).await;
+    let mut config = AppConfig::default();
+    config.upstream.max_retries = 0;
+    config.upstream.circuit_breaker.failure_threshold = 1;
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    let metrics = Metrics::new();
+
+    assert!(
+        dot_exchange(upstream, &config, &pool, &metrics, 1)
+            .await
+            .is_none()
+    );
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+    // The open circuit fails the next query without connecting
+    assert!(
+        dot_exchange(upstream, &config, &pool, &metrics, 2)
+            .await
+            .is_none()
+    );
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+    assert_eq!(metrics.circuit_open(), 1);
+}
+
+/// Start a mock plain-HTTP DoH upstream that answers every POSTed
+/// `application/dns-message` query by echoing it back with the QR bit set,
+/// recording each query it receives
+async fn start_mock_doh_upstream() -> (String, Arc<std::sync::Mutex<Vec<Vec<u8>>>>) {
+    start_answering_doh_upstream(None).await
+}
+
+/// [`start_mock_doh_upstream`], adding an A record with `answer_ttl` to each
+/// answer when set
+async fn start_answering_doh_upstream(
+    answer_ttl: Option<u32>,
+) -> (String, Arc<std::sync::Mutex<Vec<Vec<u8>>>>) {
+    use http_body_util::{BodyExt, Full};
+    use hyper::service::service_fn;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let queries = Arc::clone(&received);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let queries = Arc::clone(&queries);
+            let service = service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
+                let queries = Arc::clone(&queries);
+                async move {
+                    assert_eq!(req.method(), hyper::Method::POST);
+                    assert_eq!(req.headers()["content-type"], "application/dns-message");
+                    let mut query = req.into_body().collect().await?.to_bytes().to_vec();
+                    queries.lock().unwrap().push(query.clone());
+                    query[2] |= 0x80;
+                    if let Some(ttl) = answer_ttl {
+                        query[7] = 1; // ANCOUNT
+                        query.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1]);
+                        query.extend_from_slice(&ttl.to_be_bytes());
+                        query.extend_from_slice(&[0, 4, 192, 0, 2, 1]);
+                    }
+                    Ok::<_, hyper::Error>(hyper::Response::new(Full::new(bytes::Bytes::from(
+                        query,
+                    ))))
+                }
+            });
+            tokio::spawn(
+                hyper::server::conn::http1::Builder::new()
+                    .serve_connection(hyper_util::rt::TokioIo::new(stream), service),
+            );
+        }
+    });
+
+    (format!("http://{}/dns-query", addr), received)
+}
+
+#[tokio::test]
+async fn test_dot_handle_connection_forwards_to_doh_upstream() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let (doh_url, received) = start_mock_doh_upstream().await;
+    let mut config = AppConfig::default();
+    config.servers.dot.upstream_protocol = Some("doh".to_string());
+    config.upstream.doh = vec![doh_url];
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    let health = UpstreamHealth::from_config(&config);
+    let metrics = Metrics::new();
+    let (mut client, server) = tokio::io::duplex(4096);
+
+    // Nothing listens on the DoT upstream; every query must go over DoH
+    let dot_upstream: std::net::SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let handler = async {
+        DoTServer::handle_connection(
+            server,
+            create_test_rewriter(),
+            dot_upstream,
+            &config,
+            &pool,
+            &health,
+            &metrics,
+        )
+        .await
+    };
+
+    let query = build_query(7);
+    let client_side = async {
+        client.write_u16(query.len() as u16).await.unwrap();
+        client.write_all(&query).await.unwrap();
+        let len = client.read_u16().await.unwrap() as usize;
+        let mut response = vec![0u8; len];
+        client.read_exact(&mut response).await.unwrap();
+        client.shutdown().await.unwrap();
+        drop(client);
+        response
+    };
+
+    let (result, response) = tokio::join!(handler, client_side);
+    assert!(result.is_ok(), "{:?}", result);
+    assert_eq!(u16::from_be_bytes([response[0], response[1]]), 7);
+    assert_ne!(response[2] & 0x80, 0);
+    assert_eq!(*received.lock().unwrap(), vec![query]);
+    assert_eq!(metrics.snapshot().await.total_requests, 1);
+}
+
+#[tokio::test]
+async fn test_dot_handle_connection_counts_upstream_bytes_separately() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let (doh_url, received) = start_mock_doh_upstream().await;
+    let mut config = AppConfig::default();
+    config.servers.dot.upstream_protocol = Some("doh".to_string());
+    config.upstream.doh = vec![doh_url];
+    // Adds an OPT record, so the upstream sees a larger query than the client sent
+    config.edns.force_do_bit = true;
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    let health = UpstreamHealth::from_config(&config);
+    let metrics = Metrics::new();
+    let (mut client, server) = tokio::io::duplex(4096);
+
+    let dot_upstream: std::net::SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let handler = DoTServer::handle_connection(
+        server,
+        create_test_rewriter(),
+        dot_upstream,
+        &config,
+        &pool,
+        &health,
+        &metrics,
+    );
+    let query = build_query(9);
+    let client_side = async {
+        client.write_u16(query.len() as u16).await.unwrap();
+        client.write_all(&query).await.unwrap();
+        let len = client.read_u16().await.unwrap() as usize;
+        let mut response = vec![0u8; len];
+        client.read_exact(&mut response).await.unwrap();
+        drop(client);
+        response
+    };
+
+    let (result, response) = tokio::join!(handler, client_side);
+    assert!(result.is_ok(), "{:?}", result);
+    let forwarded = received.lock().unwrap()[0].len() as u64;
+    assert!(forwarded > query.len() as u64);
+
+    let snapshot = metrics.snapshot().await;
+    assert_eq!(snapshot.bytes_received, query.len() as u64);
+    assert_eq!(snapshot.bytes_sent, response.len() as u64);
+    assert_eq!(snapshot.upstream_bytes_sent, forwarded);
+    assert_eq!(snapshot.upstream_bytes_received, forwarded);
+    assert_ne!(snapshot.upstream_bytes_sent, snapshot.bytes_received);
+}
+/// Start a DoH server on a free local port that forwards every query to the
+/// mock DoH upstream at `doh_url`, returning the port once it is listening
+async fn start_doh_server(
+    mut config: AppConfig,
+    doh_url: String,
+) -> (
+    u16,
+    tokio_util::sync::CancellationToken,
+    tokio::task::JoinHandle<dns_ingress::error::DnsProxyResult<()>>,
+) {
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    config.servers.doh.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.doh.port = port;
+    config.servers.doh.upstream_protocol = Some("doh".to_string());
+    config.upstream.doh = vec![doh_url];
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    let server = DoHServer::new(
+        Arc::new(config),
+        create_test_rewriter(),
+        Arc::new(Metrics::new()),
+    )
+    .with_shutdown(shutdown.clone());
+    let server = tokio::spawn(async move { server.start().await });
+
+    while tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .is_err()
+    {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    (port, shutdown, server)
+}
+
+/// POST one DoH query over an HTTP/2 connection on `io` and check that the
+/// mock upstream's answer comes back
+async fn assert_http2_doh_exchange<I>(io: I)
+where
+    I: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use http_body_util::{BodyExt, Full};
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+
+    let (mut sender, connection) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(io))
+            .await
+            .unwrap();
+    tokio::spawn(connection);
+
+    let request = hyper::Request::post("https://dns.example.com/dns-query")
+        .header("content-type", "application/dns-message")
+        .body(Full::new(bytes::Bytes::from(build_query(0x4242))))
+        .unwrap();
+    let response = sender.send_request(request).await.unwrap();
+    assert_eq!(response.version(), hyper::Version::HTTP_2);
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+    assert_eq!(
+        response.headers()["content-type"],
+        "application/dns-message"
+    );
+    let answer = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&answer[..2], &[0x42, 0x42]);
+    assert_ne!(answer[2] & 0x80, 0);
+}
+
+#[tokio::test]
+async fn test_doh_server_answers_http2_requests() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let (doh_url, received) = start_mock_doh_upstream().await;
+    let mut config = AppConfig::default();
+    config.servers.doh.tls = false;
+    let (port, shutdown, server) = start_doh_server(config, doh_url).await;
+
+    // Speak HTTP/2 with prior knowledge, as behind a TLS terminator that
+    // negotiated h2 over ALPN
+    let stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    assert_http2_doh_exchange(stream).await;
+    assert_eq!(received.lock().unwrap().len(), 1);
+
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_doh_server_caches_answers_across_get_and_post() {
+    use base64::Engine;
+    use http_body_util::{BodyExt, Full};
+    use hyper_util::rt::TokioIo;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let (doh_url, received) = start_answering_doh_upstream(Some(300)).await;
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut config = AppConfig::default();
+    config.cache.enabled = true;
+    config.servers.doh.tls = false;
+    config.servers.doh.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.doh.port = port;
+    config.servers.doh.upstream_protocol = Some("doh".to_string());
+    config.upstream.doh = vec![doh_url];
+    let metrics = Arc::new(Metrics::new());
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    let server = DoHServer::new(
+        Arc::new(config),
+        create_test_rewriter(),
+        Arc::clone(&metrics),
+    )
+    .with_shutdown(shutdown.clone());
+    let server = tokio::spawn(async move { server.start().await });
+    let stream = loop {
+        match tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+        }
+    };
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+
+    let get = hyper::Request::get(format!(
+        "/dns-query?dns={}",
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(build_query(0x1111))
+    ))
+    .header("host", "dns.example.com")
+    .body(Full::new(bytes::Bytes::new()))
+    .unwrap();
+    let post = hyper::Request::post("/dns-query")
+        .header("host", "dns.example.com")
+        .header("content-type", "application/dns-message")
+        .body(Full::new(bytes::Bytes::from(build_query(0x2222))))
+        .unwrap();
+    let mut answers = Vec::new();
+    for request in [get, post] {
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        answers.push(response.into_body().collect().await.unwrap().to_bytes());
+    }
+
+    // The POST is answered from the entry the GET stored, with its own ID
+    assert_eq!(received.lock().unwrap().len(), 1);
+    assert_eq!(metrics.cache_misses(), 1);
+    assert_eq!(metrics.cache_hits(), 1);
+    assert_eq!(&answers[0][..2], &[0x11, 0x11]);
+    assert_eq!(&answers[1][..2], &[0x22, 0x22]);
+    assert_eq!(answers[0][2..], answers[1][2..]);
+
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_doh_server_listens_on_unix_socket() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let (doh_url, received) = start_mock_doh_upstream().await;
+    let dir = tempfile::tempdir().unwrap();
+    let socket = dir.path().join("doh.sock");
+    let mut config = AppConfig::default();
+    config.servers.doh.tls = false;
+    config.servers.doh.bind_address.clear();
+    config.servers.doh.port = 0;
+    config.servers.doh.uds_path = Some(socket.to_string_lossy().into_owned());
+    config.servers.doh.upstream_protocol = Some("doh".to_string());
+    config.upstream.doh = vec![doh_url];
+    config.validate().unwrap();
+
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    let server = DoHServer::new(
+        Arc::new(config),
+        create_test_rewriter(),
+        Arc::new(Metrics::new()),
+    )
+    .with_shutdown(shutdown.clone());
+    let server = tokio::spawn(async move { server.start().await });
+
+    let stream = loop {
+        match tokio::net::UnixStream::connect(&socket).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+        }
+    };
+    assert_http2_doh_exchange(stream).await;
+    assert_eq!(received.lock().unwrap().len(), 1);
+
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+    // The socket file is removed once the server stops
+    assert!(!socket.exists());
+}
+
+#[tokio::test]
+async fn test_doh_server_terminates_tls() {
+    use dns_ingress::config::CertificateConfig;
+    use rustls::pki_types::{CertificateDer, ServerName};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let cert_file = dir.path().join("cert.pem");
+    let key_file = dir.path().join("key.pem");
+    let certified =
+        rcgen::generate_simple_self_signed(vec!["dns.example.com".to_string()]).unwrap();
+    std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+    std::fs::write(&key_file, certified.signing_key.serialize_pem()).unwrap();
+
+    let (doh_url, received) = start_mock_doh_upstream().await;
+    let mut config = AppConfig::default();
+    config.tls.default = Some(CertificateConfig {
+        cert_file: cert_file.to_string_lossy().into_owned(),
+        key_file: key_file.to_string_lossy().into_owned(),
+        key_passphrase: None,
+        ca_file: None,
+        require_client_cert: false,
+        ocsp_file: None,
+    });
+    let (port, shutdown, server) = start_doh_server(config, doh_url).await;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(CertificateDer::from(certified.cert.der().to_vec()))
+        .unwrap();
+    let mut client_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+
+    let stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    let server_name = ServerName::try_from("dns.example.com").unwrap();
+    let tls = connector.connect(server_name, stream).await.unwrap();
+    assert_eq!(tls.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    assert_http2_doh_exchange(tls).await;
+    assert_eq!(received.lock().unwrap().len(), 1);
+
+    // Plain HTTP is refused on the TLS port
+    let mut plain = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    let response = {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        plain
+            .write_all(b"GET /dns-query HTTP/1.1\r\nHost: dns.example.com\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = plain.read_to_end(&mut response).await;
+        response
+    };
+    assert!(!response.starts_with(b"HTTP/1.1"));
+
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_doh_server_refuses_host_needing_client_cert_over_other_sni() {
+    use dns_ingress::config::CertificateConfig;
+    use http_body_util::{BodyExt, Full};
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use rustls::pki_types::{CertificateDer, ServerName};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let cert_config = |name: &str, ca_file: Option<String>| {
+        let certified = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let cert_file = dir.path().join(format!("{}.pem", name));
+        let key_file = dir.path().join(format!("{}.key", name));
+        std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+        std::fs::write(&key_file, certified.signing_key.serialize_pem()).unwrap();
+        let config = CertificateConfig {
+            cert_file: cert_file.to_string_lossy().into_owned(),
+            key_file: key_file.to_string_lossy().into_owned(),
+            key_passphrase: None,
+            require_client_cert: ca_file.is_some(),
+            ca_file,
+            ocsp_file: None,
+        };
+        (config, certified.cert.der().to_vec())
+    };
+
+    // secure.example.com requires a client certificate, the default
+    // certificate for dns.example.com does not
+    let ca_file = dir.path().join("client-ca.pem");
+    let ca = rcgen::generate_simple_self_signed(vec!["client-ca.test".to_string()]).unwrap();
+    std::fs::write(&ca_file, ca.cert.pem()).unwrap();
+    let (default_cert, default_der) = cert_config("dns.example.com", None);
+    let (secure_cert, _) = cert_config(
+        "secure.example.com",
+        Some(ca_file.to_string_lossy().into_owned()),
+    );
+    let (doh_url, received) = start_mock_doh_upstream().await;
+    let mut config = AppConfig::default();
+    config.tls.default = Some(default_cert);
+    config
+        .tls
+        .certs
+        .insert("secure.example.com".to_string(), secure_cert);
+    let (port, shutdown, server) = start_doh_server(config, doh_url).await;
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from(default_der)).unwrap();
+    let mut client_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    client_config.alpn_protocols = vec![b"h2".to_vec()];
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+    let stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    let server_name = ServerName::try_from("dns.example.com").unwrap();
+    let tls = connector.connect(server_name, stream).await.unwrap();
+    let (mut sender, connection) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(tls))
+            .await
+            .unwrap();
+    tokio::spawn(connection);
+
+    // Without a client certificate, a connection made for dns.example.com
+    // can't reach secure.example.com through its :authority
+    for (authority, status) in [
+        ("secure.example.com", hyper::StatusCode::MISDIRECTED_REQUEST),
+        ("dns.example.com", hyper::StatusCode::OK),
+    ] {
+        let request = hyper::Request::post(format!("https://{}/dns-query", authority))
+            .header("content-type", "application/dns-message")
+            .body(Full::new(bytes::Bytes::from(build_query(0x4242))))
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        assert_eq!(response.status(), status, "{}", authority);
+        let _ = response.into_body().collect().await;
+    }
+    assert_eq!(received.lock().unwrap().len(), 1);
+
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+/// Server certificate verifier that trusts the certificate presented, as
+/// long as it is valid for the server name; for generated certificates not
+/// known in advance
+#[derive(Debug)]
+struct TrustPresentedCert;
+
+impl rustls::client::danger::ServerCertVerifier for TrustPresentedCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        server_name: &rustls::pki_types::ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(end_entity.clone().into_owned())?;
+        rustls::client::WebPkiServerVerifier::builder(Arc::new(roots))
+            .build()
+            .unwrap()
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        let algorithms =
+            rustls::crypto::aws_lc_rs::default_provider().signature_verification_algorithms;
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        let algorithms =
+            rustls::crypto::aws_lc_rs::default_provider().signature_verification_algorithms;
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        rustls::crypto::aws_lc_rs::default_provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[tokio::test]
+async fn test_doh_server_serves_generated_self_signed_cert() {
+    use rustls::pki_types::ServerName;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let (doh_url, received) = start_mock_doh_upstream().await;
+    let mut config = AppConfig::default();
+    config.tls.generate_self_signed = true;
+    let (port, shutdown, server) = start_doh_server(config, doh_url).await;
+
+    let mut client_config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(TrustPresentedCert))
+        .with_no_client_auth();
+    client_config.alpn_protocols = vec![b"h2".to_vec()];
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+
+    // The certificate covers subdomains of the rewrite base domains
+    let stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    let server_name = ServerName::try_from("dns.example.com").unwrap();
+    let tls = connector.connect(server_name, stream).await.unwrap();
+    assert_http2_doh_exchange(tls).await;
+    assert_eq!(received.lock().unwrap().len(), 1);
+
+    shutdown.cancel();
+    server.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn test_dot_server_counts_failed_handshake_as_rejected() {
+    use tokio::io::AsyncWriteExt;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let mut config = AppConfig::default();
+    config.servers.dot.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.dot.port = port;
+    let metrics = Arc::new(Metrics::new());
+    let server = DoTServer::new(
+        Arc::new(config),
+        create_test_rewriter(),
+        Arc::clone(&metrics),
+    );
+    let server_task = tokio::spawn(async move { server.start().await });
+
+    // Send plaintext instead of a TLS ClientHello
+    let mut client = loop {
+        match tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+        }
+    };
+    client.write_all(b"not a tls handshake\r\n").await.unwrap();
+
+    let expected = "dns_proxy_rejected_connections_total{reason=\"handshake_failed\"} 1";
+    let mut counted = false;
+    for _ in 0..100 {
+        if metrics.export_prometheus().contains(expected) {
+            counted = true;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    server_task.abort();
+    assert!(counted);
+}
+
+#[tokio::test]
+async fn test_dot_server_without_upstream_fails_before_listening() {
+    use dns_ingress::server::ReadySignal;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let mut config = AppConfig::default();
+    config.servers.dot.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.dot.port = 0;
+    config.upstream.default = Vec::new();
+    config.upstream.dot = Vec::new();
+    let (ready, listening) = ReadySignal::channel("DoT");
+    let server = DoTServer::new(
+        Arc::new(config),
+        create_test_rewriter(),
+        Arc::new(Metrics::new()),
+    )
+    .with_ready(ready);
+
+    let err = server.start().await.unwrap_err();
+    assert!(err.to_string().contains("No DoT upstream configured"));
+    // Never reported as listening
+    drop(server);
+    assert!(listening.wait().await.is_err());
+}
+
+#[tokio::test]
+async fn test_doh_server_counts_failed_tls_handshake_by_protocol() {
+    use tokio::io::AsyncWriteExt;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let mut config = AppConfig::default();
+    config.servers.doh.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.doh.port = port;
+    let metrics = Arc::new(Metrics::new());
+    let server = DoHServer::new(
+        Arc::new(config),
+        create_test_rewriter(),
+        Arc::clone(&metrics),
+    );
+    let server_task = tokio::spawn(async move { server.start().await });
+
+    // Send plaintext HTTP instead of a TLS ClientHello
+    let mut client = loop {
+        match tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+            Ok(stream) => break stream,
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+        }
+    };
+    client
+        .write_all(b"GET /dns-query HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+
+    for _ in 0..100 {
+        if metrics.tls_handshake_errors("doh") > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    server_task.abort();
+    assert_eq!(metrics.tls_handshake_errors("doh"), 1);
+    assert_eq!(metrics.tls_handshake_errors("dot"), 0);
+    assert_eq!(metrics.snapshot().await.tls_handshake_errors, 1);
+    assert!(
+        metrics
+            .export_prometheus()
+            .contains("dns_proxy_tls_handshake_errors_total{protocol=\"doh\"} 1")
+    );
+}
+
+#[tokio::test]
+async fn test_dot_handle_connection_upstream_timeout() {
+    use std::time::{Duration, Instant};
+    use tokio::io::AsyncWriteExt;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+
+    // Upstream accepts TCP but never completes the TLS handshake
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            held.push(stream);
+        }
+    });
+
+    let mut config = AppConfig::default();
+    config.upstream.upstream_timeout_ms = 200;
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    let health = UpstreamHealth::from_config(&config);
+    let metrics = Metrics::new();
+    let (mut client, server) = tokio::io::duplex(4096);
+
+    let query = build_query(7);
+    client.write_u16(query.len() as u16).await.unwrap();
+    client.write_all(&query).await.unwrap();
+
+    let started = Instant::now();
+    let result = DoTServer::handle_connection(
+        server,
+        create_test_rewriter(),
+        upstream,
+        &config,
+        &pool,
+        &health,
+        &metrics,
+    )
+    .await;
+    let elapsed = started.elapsed();
+
+    assert!(result.is_err_and(|e| e.is_timeout()));
+    assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn test_dot_handle_connection_retries_flaky_upstream() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    // First upstream connection is dropped, the retry succeeds
+    let upstream = start_mock_dot_upstream(1).await;
+
+    let config = AppConfig::default();
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    let health = UpstreamHealth::from_config(&config);
+    let metrics = Metrics::new();
+    let (mut client, server) = tokio::io::duplex(4096);
+
+    let client_side = async {
+        let query = build_query(9);
+        client.write_u16(query.len() as u16).await.unwrap();
+        client.write_all(&query).await.unwrap();
+
+        let len = client.read_u16().await.unwrap() as usize;
+        let mut response = vec![0u8; len];
+        client.read_exact(&mut response).await.unwrap();
+        drop(client);
+        response
+    };
+    let handler = DoTServer::handle_connection(
+        server,
+        create_test_rewriter(),
+        upstream,
+        &config,
+        &pool,
+        &health,
+        &metrics,
+    );
+
+    let (result, response) = tokio::join!(handler, client_side);
+    assert!(result.is_ok());
+    assert_eq!(&response[..2], &9u16.to_be_bytes());
+    assert_eq!(metrics.upstream_retries(), 1);
+}
+
+/// Start a plain UDP upstream that echoes each query back with QR set,
+/// padded with `padding` extra bytes
+async fn start_mock_udp_upstream(padding: usize) -> std::net::SocketAddr {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 65535];
+        while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+            let mut response = buf[..len].to_vec();
+            response[2] |= 0x80;
+            response.resize(len + padding, 0);
+            let _ = socket.send_to(&response, peer).await;
+        }
+    });
+    addr
+}
+
+/// Rewriter that the `www.example.com` test queries don't match, so plain
+/// UDP/TCP listeners send them to the default upstream
+fn create_unrouted_rewriter() -> dns_ingress::rewrite::SniRewriterType {
+    create_rewriter(RewriteConfig {
+        base_domains: vec!["example.org".to_string()],
+        target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
+        rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
+    })
+}
+
+async fn start_udp_server(config: AppConfig) -> (std::net::SocketAddr, Arc<Metrics>) {
+    start_udp_server_with_rewriter(config, create_unrouted_rewriter()).await
+}
+
+async fn start_udp_server_with_rewriter(
+    config: AppConfig,
+    rewriter: dns_ingress::rewrite::SniRewriterType,
+) -> (std::net::SocketAddr, Arc<Metrics>) {
+    let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let metrics = Arc::new(Metrics::new());
+    let server = UdpServer::new(Arc::new(config), rewriter, Arc::clone(&metrics));
+    tokio::spawn(async move { server.serve(socket.into()).await });
+    (addr, metrics)
+}
+
+async fn udp_exchange(server: std::net::SocketAddr, query: &[u8]) -> Vec<u8> {
+    let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(query, server).await.unwrap();
+    let mut buf = vec![0u8; 65535];
+    let (len, _) = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        client.recv_from(&mut buf),
+    )
+    .await
+    .expect("UDP server should answer")
+    .unwrap();
+    buf.truncate(len);
+    buf
+}
+
+#[tokio::test]
+async fn test_udp_server_forwards_to_udp_upstream() {
+    let upstream = start_mock_udp_upstream(0).await;
+    let mut config = AppConfig::default();
+    config.upstream.udp = vec![upstream.to_string()];
+    let (server, metrics) = start_udp_server(config).await;
+
+    let query = build_query(0x4242);
+    let response = udp_exchange(server, &query).await;
+
+    assert_eq!(&response[..2], &query[..2]);
+    assert_ne!(response[2] & 0x80, 0, "QR bit should be set by upstream");
+    assert_eq!(&response[3..], &query[3..]);
+    assert_eq!(metrics.successful_requests(), 1);
+}
+
+#[tokio::test]
+async fn test_udp_server_truncates_oversized_response() {
+    let upstream = start_mock_udp_upstream(600).await;
+    let mut config = AppConfig::default();
+    config.upstream.udp = vec![upstream.to_string()];
+    let (server, _metrics) = start_udp_server(config).await;
+
+    // No OPT record, so the client accepts at most 512 bytes
+    let query = build_query(0x0101);
+    let response = udp_exchange(server, &query).await;
+
+    assert_eq!(response.len(), query.len());
+    assert_ne!(response[2] & 0x02, 0, "TC bit should be set");
+}
+
+#[tokio::test]
+async fn test_udp_server_answers_servfail_when_upstream_fails() {
+    // Nothing answers on this port; the upstream timeout expires
+    let blackhole = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = AppConfig::default();
+    config.upstream.udp = vec![blackhole.local_addr().unwrap().to_string()];
+    config.upstream.upstream_timeout_ms = 200;
+    let (server, metrics) = start_udp_server(config).await;
+
+    let query = build_query(0x0202);
+    let response = udp_exchange(server, &query).await;
+
+    assert_eq!(&response[..2], &query[..2]);
+    assert_eq!(response[3] & 0x0F, 2, "RCODE should be SERVFAIL");
+    assert_eq!(metrics.failed_requests(), 1);
+    assert_eq!(metrics.upstream_errors(), 1);
+}
+
+#[tokio::test]
+async fn test_tcp_dns_handle_connection_multiple_queries() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let upstream = start_mock_udp_upstream(0).await;
+    let mut config = AppConfig::default();
+    config.upstream.udp = vec![upstream.to_string()];
+    let config = Arc::new(config);
+    let rewriter = create_unrouted_rewriter();
+    let metrics = Arc::new(Metrics::new());
+    let default_upstream = dns_ingress::upstream::default_upstream::DefaultUpstream::new(
+        Arc::clone(&config),
+        Arc::clone(&metrics),
+    );
+    let cache = ResponseCache::from_config(&config.cache);
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    let (mut client, server) = tokio::io::duplex(4096);
+
+    let client_task = async move {
+        // Send three queries back to back before reading any answer
+        for id in [1u16, 2, 3] {
+            let query = build_query(id);
+            client.write_u16(query.len() as u16).await.unwrap();
+            client.write_all(&query).await.unwrap();
+        }
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let len = client.read_u16().await.unwrap() as usize;
+            let mut response = vec![0u8; len];
+            client.read_exact(&mut response).await.unwrap();
+            assert_ne!(response[2] & 0x80, 0);
+            ids.push(u16::from_be_bytes([response[0], response[1]]));
+        }
+        drop(client);
+        ids
+    };
+
+    let (ids, result) = tokio::join!(
+        client_task,
+        TcpDnsServer::handle_connection(
+            server,
+            &rewriter,
+            &default_upstream,
+            &pool,
+            &cache,
+            &config,
+            &metrics,
+        )
+    );
+
+    result.unwrap();
+    assert_eq!(ids, vec![1, 2, 3]);
+    assert_eq!(metrics.successful_requests(), 3);
+}
+
+#[tokio::test]
+async fn test_tcp_dns_handle_connection_answers_short_messages_with_formerr() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let config = Arc::new(AppConfig::default());
+    let rewriter = create_unrouted_rewriter();
+    let metrics = Arc::new(Metrics::new());
+    let default_upstream = dns_ingress::upstream::default_upstream::DefaultUpstream::new(
+        Arc::clone(&config),
+        Arc::clone(&metrics),
+    );
+    let cache = ResponseCache::from_config(&config.cache);
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    let (mut client, server) = tokio::io::duplex(4096);
+
+    let client_task = async move {
+        let query = build_query(7);
+        let mut rcodes = Vec::new();
+        for message in [&query[..0], &query[..5]] {
+            client.write_u16(message.len() as u16).await.unwrap();
+            client.write_all(message).await.unwrap();
+            let len = client.read_u16().await.unwrap() as usize;
+            let mut response = vec![0u8; len];
+            client.read_exact(&mut response).await.unwrap();
+            rcodes.push(response[3] & 0x0F);
+        }
+        drop(client);
+        rcodes
+    };
+
+    let (rcodes, result) = tokio::join!(
+        client_task,
+        TcpDnsServer::handle_connection(
+            server,
+            &rewriter,
+            &default_upstream,
+            &pool,
+            &cache,
+            &config,
+            &metrics,
+        )
+    );
+
+    result.unwrap();
+    assert_eq!(rcodes, vec![1, 1], "both messages should get FORMERR");
+    assert_eq!(metrics.total_requests(), 0, "nothing should be forwarded");
+}
+
+#[tokio::test]
+async fn test_tcp_dns_handle_connection_counts_authenticated_responses() {
+    use dns_ingress::testing::MockUpstream;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A validating upstream's answer: QR and AD set
+    let mut answer = build_query(0);
+    answer[2] |= 0x80;
+    answer[3] |= 0x20;
+    let upstream = MockUpstream::new().with_response("www.example.com", 1, answer);
+    let config = Arc::new(AppConfig::default());
+    let rewriter = create_unrouted_rewriter();
+    let metrics = Arc::new(Metrics::new());
+    let cache = ResponseCache::from_config(&config.cache);
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    let (mut client, server) = tokio::io::duplex(4096);
+
+    let client_task = async move {
+        let query = build_query(5);
+        client.write_u16(query.len() as u16).await.unwrap();
+        client.write_all(&query).await.unwrap();
+        let len = client.read_u16().await.unwrap() as usize;
+        let mut response = vec![0u8; len];
+        client.read_exact(&mut response).await.unwrap();
+        drop(client);
+        response
+    };
+
+    let (response, result) = tokio::join!(
+        client_task,
+        TcpDnsServer::handle_connection(
+            server, &rewriter, &upstream, &pool, &cache, &config, &metrics
+        )
+    );
+
+    result.unwrap();
+    assert_ne!(response[3] & 0x20, 0, "AD should reach the client");
+    assert_eq!(metrics.responses_authenticated(), 1);
+    assert!(
+        metrics
+            .export_prometheus()
+            .contains("dns_proxy_responses_authenticated_total 1")
+    );
+}
+
+#[tokio::test]
+async fn test_tcp_dns_handle_connection_rejects_oversized_message() {
+    use dns_ingress::error::DnsProxyError;
+    use tokio::io::AsyncWriteExt;
+
+    let mut config = AppConfig::default();
+    config.servers.max_message_size = 512;
+    let config = Arc::new(config);
+    let rewriter = create_unrouted_rewriter();
+    let metrics = Arc::new(Metrics::new());
+    let default_upstream = dns_ingress::upstream::default_upstream::DefaultUpstream::new(
+        Arc::clone(&config),
+        Arc::clone(&metrics),
+    );
+    let cache = ResponseCache::from_config(&config.cache);
+    let pool = dns_ingress::upstream::create_connection_pool(&config.upstream);
+    let (mut client, server) = tokio::io::duplex(4096);
+
+    // Announce the largest possible message but never send its body: the
+    // connection must be rejected from the length prefix alone
+    client.write_u16(u16::MAX).await.unwrap();
+
+    let result = TcpDnsServer::handle_connection(
+        server,
+        &rewriter,
+        &default_upstream,
+        &pool,
+        &cache,
+        &config,
+        &metrics,
+    )
+    .await;
+
+    assert!(matches!(
+        result,
+        Err(DnsProxyError::MessageTooLarge {
+            size: 65535,
+            limit: 512
+        })
+    ));
+    assert_eq!(metrics.total_requests(), 0);
+}
+
+#[tokio::test]
+async fn test_udp_server_drops_oversized_datagram() {
+    let upstream = start_mock_udp_upstream(0).await;
+    let mut config = AppConfig::default();
+    config.upstream.udp = vec![upstream.to_string()];
+    config.servers.max_message_size = 64;
+    let (server, metrics) = start_udp_server(config).await;
+
+    let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut oversized = build_query(0x0101);
+    oversized.resize(1024, 0);
+    client.send_to(&oversized, server).await.unwrap();
+
+    let mut buf = vec![0u8; 512];
+    let answer = tokio::time::timeout(
+        std::time::Duration::from_millis(500),
+        client.recv_from(&mut buf),
+    )
+    .await;
+    assert!(answer.is_err(), "oversized datagram should not be answered");
+    assert_eq!(metrics.oversized_rejected(), 1);
+
+    // Queries within the limit are still answered
+    let query = build_query(0x0102);
+    let response = udp_exchange(server, &query).await;
+    assert_eq!(&response[..2], &query[..2]);
+}
+
+#[tokio::test]
+async fn test_udp_server_routes_by_query_name() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dot_upstream = start_mock_dot_upstream(0).await;
+
+    // The default upstream never answers, so only a routed query succeeds
+    let blackhole = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = AppConfig::default();
+    config.upstream.udp = vec![blackhole.local_addr().unwrap().to_string()];
+    config.upstream.dot = vec![dot_upstream.to_string()];
+    config.upstream.upstream_timeout_ms = 1000;
+
+    // "127.example.com" rewrites to "127.0.0.1", reached over DoT on the
+    // DoT upstream's port
+    let rewriter = create_rewriter(RewriteConfig {
+        base_domains: vec!["example.com".to_string()],
+        target_suffix: ".0.0.1".to_string(),
+        targets: Vec::new(),
+        rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
+    });
+    let (server, metrics) = start_udp_server_with_rewriter(config, rewriter).await;
+
+    let query = build_query_for(0x5353, "127.example.com");
+    let response = udp_exchange(server, &query).await;
+
+    assert_eq!(&response[..2], &query[..2]);
+    assert_ne!(response[2] & 0x80, 0, "QR bit should be set by upstream");
+    assert_eq!(response[3] & 0x0F, 0, "routed query should not SERVFAIL");
+    assert_eq!(metrics.rewrite_hits(), 1);
+    assert_eq!(metrics.successful_requests(), 1);
+}
+
+#[tokio::test]
+async fn test_udp_server_routed_queries_share_pooled_connection() {
+    use std::sync::atomic::Ordering;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let (dot_upstream, connections) = start_counting_dot_upstream(0).await;
+    let blackhole = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut config = AppConfig::default();
+    config.upstream.udp = vec![blackhole.local_addr().unwrap().to_string()];
+    config.upstream.dot = vec![dot_upstream.to_string()];
+    config.upstream.upstream_timeout_ms = 1000;
+    let rewriter = create_rewriter(RewriteConfig {
+        base_domains: vec!["example.com".to_string()],
+        target_suffix: ".0.0.1".to_string(),
+        targets: Vec::new(),
+        rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
+    });
+    let (server, metrics) = start_udp_server_with_rewriter(config, rewriter).await;
+
+    // Different names, so the second answer can't come from the cache
+    for (id, qtype) in [(0x5354u16, 1u8), (0x5355, 28)] {
+        let mut query = build_query_for(id, "127.example.com");
+        let qtype_at = query.len() - 3;
+        query[qtype_at] = qtype;
+        let response = udp_exchange(server, &query).await;
+        assert_eq!(response[3] & 0x0F, 0, "routed query should not SERVFAIL");
+    }
+
+    assert_eq!(metrics.successful_requests(), 2);
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_udp_server_refuses_denied_query() {
+    let upstream = start_mock_udp_upstream(0).await;
+    let mut config = AppConfig::default();
+    config.upstream.udp = vec![upstream.to_string()];
+    config.filter.deny_domains = vec!["example.com".to_string()];
+    let (server, metrics) = start_udp_server(config).await;
+
+    let query = build_query(0x0303);
+    let response = udp_exchange(server, &query).await;
+
+    assert_eq!(&response[..2], &query[..2]);
+    assert_eq!(response[3] & 0x0F, 5, "RCODE should be REFUSED");
+    assert_eq!(metrics.blocked_requests(), 1);
+    assert_eq!(metrics.total_requests(), 0);
+}
+
+/// `build_query` with an OPT record carrying a COOKIE option
+fn build_query_with_cookie(id: u16, cookie: &[u8]) -> Vec<u8> {
+    let mut msg = build_query(id);
+    msg[11] = 1; // ARCOUNT
+    msg.extend_from_slice(&[0, 0, 41, 0x04, 0xD0, 0, 0, 0, 0]);
+    msg.extend_from_slice(&(cookie.len() as u16 + 4).to_be_bytes());
+    msg.extend_from_slice(&10u16.to_be_bytes());
+    msg.extend_from_slice(&(cookie.len() as u16).to_be_bytes());
+    msg.extend_from_slice(cookie);
+    msg
+}
+
+fn cookie_option(msg: &[u8]) -> Option<Vec<u8>> {
+    use dns_ingress::dns::edns;
+
+    let opt = edns::find_opt(msg)?;
+    edns::parse_options(msg, &opt)?
+        .iter()
+        .find(|o| o.code == edns::OPTION_COOKIE)
+        .map(|o| o.data.to_vec())
+}
+
+#[tokio::test]
+async fn test_udp_server_requires_cookies() {
+    let upstream = start_mock_udp_upstream(0).await;
+    let mut config = AppConfig::default();
+    config.upstream.udp = vec![upstream.to_string()];
+    config.servers.udp.require_cookies = true;
+    let (server, metrics) = start_udp_server(config).await;
+
+    // No cookie at all
+    let response = udp_exchange(server, &build_query(0x0404)).await;
+    assert_eq!(response[3] & 0x0F, 5, "RCODE should be REFUSED");
+
+    // A client cookie alone gets BADCOOKIE with a server cookie to retry with
+    let client_cookie = [9u8; 8];
+    let response = udp_exchange(server, &build_query_with_cookie(0x0505, &client_cookie)).await;
+    assert_eq!(response[3] & 0x0F, 7, "RCODE should be BADCOOKIE");
+    let cookie = cookie_option(&response).expect("BADCOOKIE should carry a cookie");
+    assert_eq!(&cookie[..8], &client_cookie);
+    assert_eq!(metrics.cookie_rejected(), 2);
+    assert_eq!(metrics.total_requests(), 0);
+
+    // Retrying with the server cookie is forwarded and answered with a cookie
+    let response = udp_exchange(server, &build_query_with_cookie(0x0606, &cookie)).await;
+    assert_eq!(&response[..2], &[0x06, 0x06]);
+    assert_eq!(response[3] & 0x0F, 0, "RCODE should be NOERROR");
+    let answered = cookie_option(&response).expect("answer should carry a cookie");
+    assert_eq!(&answered[..8], &client_cookie);
+    assert_eq!(metrics.successful_requests(), 1);
+
+    // A forged server cookie is not
+    let mut forged = cookie.clone();
+    forged[20] ^= 0xFF;
+    let response = udp_exchange(server, &build_query_with_cookie(0x0707, &forged)).await;
+    assert_eq!(response[3] & 0x0F, 7, "RCODE should be BADCOOKIE");
+    assert_eq!(metrics.cookie_rejected(), 3);
+}
+
+#[tokio::test]
+async fn test_udp_server_rate_limits_burst() {
+    let upstream = start_mock_udp_upstream(0).await;
+    let mut config = AppConfig::default();
+    config.upstream.udp = vec![upstream.to_string()];
+    config.ratelimit.requests_per_sec = 1;
+    config.ratelimit.burst = 3;
+    let (server, metrics) = start_udp_server(config).await;
+
+    let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    for id in 0..10u16 {
+        client.send_to(&build_query(id), server).await.unwrap();
+    }
+
+    let mut answered = 0;
+    let mut buf = vec![0u8; 512];
+    while tokio::time::timeout(
+        std::time::Duration::from_millis(500),
+        client.recv_from(&mut buf),
+    )
+    .await
+    .is_ok()
+    {
+        answered += 1;
+    }
+
+    assert_eq!(answered, 3);
+    assert_eq!(metrics.rate_limited(), 7);
+}
+
+/// A DoH3 server under test and an HTTP/3 client connected to it
+struct Doh3Client {
+    send_request: h3::client::SendRequest<h3_quinn::OpenStreams, bytes::Bytes>,
+    connection: quinn::Connection,
+    shutdown: tokio_util::sync::CancellationToken,
+    server: tokio::task::JoinHandle<dns_ingress::error::DnsProxyResult<()>>,
+}
+
+impl Doh3Client {
+    /// Close the client connection and stop the server
+    async fn stop(self) {
+        self.connection.close(0u32.into(), b"done");
+        self.shutdown.cancel();
+        self.server.await.unwrap().unwrap();
+    }
+}
+
+/// Start a DoH3 server forwarding to `doh_url` and connect an HTTP/3 client
+async fn start_doh3_server(mut config: AppConfig, doh_url: String) -> Doh3Client {
+    use dns_ingress::config::CertificateConfig;
+    use dns_ingress::quic::client::{ALPN_H3, connect_quic_with_tls};
+    use dns_ingress::server::ReadySignal;
+    use rustls::pki_types::CertificateDer;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let cert_file = dir.path().join("cert.pem");
+    let key_file = dir.path().join("key.pem");
+    let certified =
+        rcgen::generate_simple_self_signed(vec!["dns.example.com".to_string()]).unwrap();
+    std::fs::write(&cert_file, certified.cert.pem()).unwrap();
+    std::fs::write(&key_file, certified.signing_key.serialize_pem()).unwrap();
+
+    let port = std::net::UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    config.tls.default = Some(CertificateConfig {
+        cert_file: cert_file.to_string_lossy().into_owned(),
+        key_file: key_file.to_string_lossy().into_owned(),
+        key_passphrase: None,
+        ca_file: None,
+        require_client_cert: false,
+        ocsp_file: None,
+    });
+    config.servers.doh3.enabled = true;
+    config.servers.doh3.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.doh3.port = port;
+    config.servers.doh3.upstream_protocol = Some("doh".to_string());
+    config.upstream.doh = vec![doh_url];
+
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    let (ready, listening) = ReadySignal::channel("DoH3");
+    let server = DoH3Server::new(
+        Arc::new(config),
+        create_test_rewriter(),
+        Arc::new(Metrics::new()),
+    )
+    .with_shutdown(shutdown.clone())
+    .with_ready(ready);
+    let server = tokio::spawn(async move { server.start().await });
+    listening.wait().await.unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(CertificateDer::from(certified.cert.der().to_vec()))
+        .unwrap();
+    let client_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connection = connect_quic_with_tls(
+        ([127, 0, 0, 1], port).into(),
+        "dns.example.com",
+        client_config,
+        ALPN_H3,
+        Arc::default(),
+    )
+    .await
+    .unwrap();
+    let (mut driver, send_request) = h3::client::new(h3_quinn::Connection::new(connection.clone()))
+        .await
+        .unwrap();
+    tokio::spawn(async move {
+        let _ = futures::future::poll_fn(|cx| driver.poll_close(cx)).await;
+    });
+    Doh3Client {
+        send_request,
+        connection,
+        shutdown,
+        server,
+    }
+}
+
+/// RFC 8484 POST to the DoH3 server under test, without its body
+fn doh3_post() -> hyper::Request<()> {
+    hyper::Request::post("https://dns.example.com/dns-query")
+        .header("host", "dns.example.com")
+        .header("content-type", "application/dns-message")
+        .body(())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_doh3_server_limits_requests_per_connection() {
+    let (doh_url, received) = start_mock_doh_upstream().await;
+    let mut config = AppConfig::default();
+    config.servers.doh3.max_concurrent_streams_per_connection = 1;
+    config.servers.doh3.max_requests_per_connection = 2;
+    let mut client = start_doh3_server(config, doh_url).await;
+    let send_request = &mut client.send_request;
+
+    // The first request holds the only stream slot until its body is sent
+    let mut first = send_request.send_request(doh3_post()).await.unwrap();
+
+    // A second request at the same time is refused
+    let mut second = send_request.send_request(doh3_post()).await.unwrap();
+    second.finish().await.unwrap();
+    match second.recv_response().await {
+        Err(h3::error::StreamError::RemoteTerminate { code, .. }) => {
+            assert_eq!(code, h3::error::Code::H3_REQUEST_REJECTED)
+        }
+        other => panic!("expected the request to be rejected, got {:?}", other),
+    }
+
+    // The held request still completes
+    first
+        .send_data(bytes::Bytes::from(build_query(0x1234)))
+        .await
+        .unwrap();
+    first.finish().await.unwrap();
+    let response = first.recv_response().await.unwrap();
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+
+    // The second request used up the connection's budget, so a third one
+    // is refused whether or not a stream slot is free
+    let third = async {
+        let mut third = send_request.send_request(doh3_post()).await?;
+        third.finish().await?;
+        third.recv_response().await
+    };
+    assert!(third.await.is_err());
+    assert_eq!(received.lock().unwrap().len(), 1);
+
+    client.stop().await;
+}
+
+#[tokio::test]
+async fn test_doh3_server_cancels_stalled_request_body() {
+    use std::time::{Duration, Instant};
+
+    let (doh_url, received) = start_mock_doh_upstream().await;
+    let mut config = AppConfig::default();
+    config.servers.request_body_timeout_ms = 200;
+    let mut client = start_doh3_server(config, doh_url).await;
+
+    // Send the headers and part of the body, then stall
+    let started = Instant::now();
+    let mut request = client.send_request.send_request(doh3_post()).await.unwrap();
+    request
+        .send_data(bytes::Bytes::from(build_query(0x1234)[..4].to_vec()))
+        .await
+        .unwrap();
+    let result = tokio::time::timeout(Duration::from_secs(5), request.recv_response())
+        .await
+        .expect("stalled request was not aborted");
+    match result {
+        Err(h3::error::StreamError::RemoteTerminate { code, .. }) => {
+            assert_eq!(code, h3::error::Code::H3_REQUEST_CANCELLED)
+        }
+        other => panic!("expected the request to be cancelled, got {:?}", other),
+    }
+    assert!(started.elapsed() >= Duration::from_millis(200));
+    assert!(received.lock().unwrap().is_empty());
+
+    client.stop().await;
+}
+
+#[tokio::test]
+async fn test_doh3_server_rejects_excessive_headers() {
+    let (doh_url, received) = start_mock_doh_upstream().await;
+    let mut config = AppConfig::default();
+    config.servers.max_header_count = 8;
+    let mut client = start_doh3_server(config, doh_url).await;
+
+    let mut request = doh3_post();
+    for i in 0..10 {
+        request.headers_mut().insert(
+            hyper::header::HeaderName::try_from(format!("x-filler-{}", i)).unwrap(),
+            hyper::header::HeaderValue::from(i),
+        );
+    }
+    let mut stream = client.send_request.send_request(request).await.unwrap();
+    stream
+        .send_data(bytes::Bytes::from(build_query(0x1234)))
+        .await
+        .unwrap();
+    stream.finish().await.unwrap();
+    let response = stream.recv_response().await.unwrap();
+    assert_eq!(
+        response.status(),
+        hyper::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE
+    );
+    assert!(received.lock().unwrap().is_empty());
+
+    client.stop().await;
+}
@@ -1,6 +1,5 @@
 use dns_ingress::config::RewriteConfig;
 use dns_ingress::rewrite::create_rewriter;
-use dns_ingress::sni::SniRewriter;
 use std::sync::Arc;
 
 #[test]
@@ -8,7 +7,11 @@ fn test_create_rewriter() {
     let config = RewriteConfig {
         base_domains: vec!["test.com".to_string()],
         target_suffix: ".test.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
 
     let rewriter = create_rewriter(config);
@@ -20,7 +23,11 @@ async fn test_create_rewriter_functionality() {
     let config = RewriteConfig {
         base_domains: vec!["test.com".to_string()],
         target_suffix: ".test.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
 
     let rewriter = create_rewriter(config);
@@ -1,13 +1,18 @@
 use dns_ingress::config::RewriteConfig;
-use dns_ingress::rewriters::base::BaseSniRewriter;
-use dns_ingress::sni::SniRewriter;
+use dns_ingress::error::SniRewriteError;
+use dns_ingress::rewriters::base::{BaseSniRewriter, MAX_CACHED_REWRITES};
+use dns_ingress::sni::{RewriteResult, SniRewriter};
 use std::sync::Arc;
 
 fn create_test_config() -> RewriteConfig {
     RewriteConfig {
         base_domains: vec!["example.com".to_string(), "example.org".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     }
 }
 
@@ -48,21 +53,6 @@ async fn test_extract_prefix_unknown_domain() {
     assert_eq!(rewriter.extract_prefix("test.net"), None);
 }
 
-#[tokio::test]
-async fn test_build_target_hostname() {
-    let config = create_test_config();
-    let rewriter = BaseSniRewriter::new(config);
-
-    assert_eq!(
-        rewriter.build_target_hostname("www"),
-        "www.example.cn".to_string()
-    );
-    assert_eq!(
-        rewriter.build_target_hostname("api"),
-        "api.example.cn".to_string()
-    );
-}
-
 #[tokio::test]
 async fn test_rewrite_sni() {
     let config = create_test_config();
@@ -101,11 +91,101 @@ async fn test_rewrite_sni_caching() {
     // Check cache using DashMap API
     assert!(rewriter.sni_map.contains_key("www.example.org"));
     assert_eq!(
-        rewriter.sni_map.get("www.example.org").map(|v| v.clone()),
+        rewriter
+            .sni_map
+            .get("www.example.org")
+            .map(|v| v.target_hostname.clone()),
         Some("www.example.cn".to_string())
     );
 }
 
+#[tokio::test]
+async fn test_rewrite_sni_reads_cache_before_computing() {
+    let config = create_test_config();
+    let rewriter = BaseSniRewriter::new(config);
+
+    let result1 = rewriter.rewrite("www.example.org").await.unwrap();
+    assert_eq!(result1.cache_key(), "www.example.org");
+    let cached = rewriter.sni_map.get("www.example.org").unwrap().clone();
+    assert_eq!(cached.prefix, "www");
+    assert_eq!(cached.target_hostname, "www.example.cn");
+
+    // Plant an entry no computation would produce: a cache hit returns it
+    rewriter.sni_map.insert(
+        "www.example.org".to_string(),
+        RewriteResult {
+            original: "www.example.org".to_string(),
+            prefix: "cached".to_string(),
+            target_hostname: "cached.example.net".to_string(),
+            passthrough: false,
+            warn: false,
+        },
+    );
+    let result2 = rewriter.rewrite("www.example.org").await.unwrap();
+    assert_eq!(result2.prefix, "cached");
+    assert_eq!(result2.target_hostname, "cached.example.net");
+    assert_eq!(rewriter.sni_map.len(), 1);
+
+    // Other SNIs are still computed and cached
+    let result3 = rewriter.rewrite("api.example.org").await.unwrap();
+    assert_eq!(result3.target_hostname, "api.example.cn");
+    assert_eq!(rewriter.sni_map.len(), 2);
+}
+
+#[tokio::test]
+async fn test_rewrite_cache_skips_passthroughs_and_is_bounded() {
+    let config = RewriteConfig {
+        rewrite_failure_strategy: "passthrough".to_string(),
+        ..create_test_config()
+    };
+    let rewriter = BaseSniRewriter::new(config);
+
+    // Passed-through SNIs are answered but never cached
+    let result = rewriter.rewrite("unknown.test").await.unwrap();
+    assert!(result.passthrough);
+    let results = rewriter
+        .rewrite_many(&["other.test", "www.example.org"])
+        .await;
+    assert!(results[0].as_ref().unwrap().passthrough);
+    assert!(!rewriter.sni_map.contains_key("unknown.test"));
+    assert!(!rewriter.sni_map.contains_key("other.test"));
+    assert_eq!(rewriter.sni_map.len(), 1);
+
+    // Once full, new rewrites are still computed but not cached
+    for i in rewriter.sni_map.len()..MAX_CACHED_REWRITES {
+        let sni = format!("host{}.example.com", i);
+        rewriter.rewrite(&sni).await.unwrap();
+    }
+    assert_eq!(rewriter.sni_map.len(), MAX_CACHED_REWRITES);
+    let result = rewriter.rewrite("api.example.org").await.unwrap();
+    assert_eq!(result.target_hostname, "api.example.cn");
+    assert!(!rewriter.sni_map.contains_key("api.example.org"));
+    assert_eq!(rewriter.sni_map.len(), MAX_CACHED_REWRITES);
+    let results = rewriter
+        .rewrite_many(&["mail.example.org", "ftp.example.org"])
+        .await;
+    assert!(results.iter().all(Option::is_some));
+    assert_eq!(rewriter.sni_map.len(), MAX_CACHED_REWRITES);
+}
+
+#[tokio::test]
+async fn test_rewrite_many_fills_cache_up_to_its_bound() {
+    let rewriter = BaseSniRewriter::new(create_test_config());
+    for i in 0..MAX_CACHED_REWRITES - 1 {
+        let sni = format!("host{}.example.com", i);
+        rewriter.rewrite(&sni).await.unwrap();
+    }
+
+    // Only as many of the batch's rewrites as fit are cached
+    let results = rewriter
+        .rewrite_many(&["www.example.org", "api.example.org"])
+        .await;
+    assert!(results.iter().all(Option::is_some));
+    assert!(rewriter.sni_map.contains_key("www.example.org"));
+    assert!(!rewriter.sni_map.contains_key("api.example.org"));
+    assert_eq!(rewriter.sni_map.len(), MAX_CACHED_REWRITES);
+}
+
 #[tokio::test]
 async fn test_rewrite_sni_no_match() {
     let config = create_test_config();
@@ -124,3 +204,116 @@ async fn test_rewrite_arc() {
     assert!(result.is_some());
     assert_eq!(result.unwrap().target_hostname, "www.example.cn");
 }
+
+#[tokio::test]
+async fn test_rewrite_many_preserves_order() {
+    let rewriter = BaseSniRewriter::new(create_test_config());
+    // Cache one name first so the batch mixes cached and fresh results
+    rewriter.rewrite("api.example.com").await.unwrap();
+
+    let results = rewriter
+        .rewrite_many(&["www.example.org", "", "api.example.com", "other.test"])
+        .await;
+    let targets: Vec<Option<String>> = results
+        .into_iter()
+        .map(|result| result.map(|result| result.target_hostname))
+        .collect();
+    assert_eq!(
+        targets,
+        vec![
+            Some("www.example.cn".to_string()),
+            None,
+            Some("api.example.cn".to_string()),
+            None,
+        ]
+    );
+    assert!(rewriter.sni_map.contains_key("www.example.org"));
+    assert_eq!(rewriter.sni_map.len(), 2);
+}
+
+#[tokio::test]
+async fn test_rewrite_many_default_matches_rewrite() {
+    /// Rewriter that only implements `rewrite_with_reason`
+    struct UpperRewriter;
+
+    #[async_trait::async_trait]
+    impl SniRewriter for UpperRewriter {
+        async fn rewrite_with_reason(&self, sni: &str) -> Result<RewriteResult, SniRewriteError> {
+            if sni.is_empty() {
+                return Err(SniRewriteError::EmptySni);
+            }
+            Ok(RewriteResult {
+                original: sni.to_string(),
+                prefix: String::new(),
+                target_hostname: sni.to_uppercase(),
+                passthrough: false,
+                warn: false,
+            })
+        }
+    }
+
+    let results = UpperRewriter.rewrite_many(&["b.test", "", "a.test"]).await;
+    let targets: Vec<Option<String>> = results
+        .into_iter()
+        .map(|result| result.map(|result| result.target_hostname))
+        .collect();
+    assert_eq!(
+        targets,
+        vec![Some("B.TEST".to_string()), None, Some("A.TEST".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn test_rewrite_default_drops_reason() {
+    /// Rewriter that only implements `rewrite_with_reason`, and never matches
+    struct NeverRewriter;
+
+    #[async_trait::async_trait]
+    impl SniRewriter for NeverRewriter {
+        async fn rewrite_with_reason(&self, sni: &str) -> Result<RewriteResult, SniRewriteError> {
+            Err(SniRewriteError::NoMatchingRule {
+                hostname: sni.to_string(),
+            })
+        }
+    }
+
+    assert!(NeverRewriter.rewrite("www.example.com").await.is_none());
+    assert!(matches!(
+        NeverRewriter.rewrite_with_reason("www.example.com").await,
+        Err(SniRewriteError::NoMatchingRule { hostname }) if hostname == "www.example.com"
+    ));
+}
+
+#[tokio::test]
+async fn test_rewrite_picks_target_suffix_by_base_domain() {
+    use dns_ingress::config::BaseDomainTarget;
+
+    let target = |base_domain: &str, target_suffix: &str| BaseDomainTarget {
+        base_domain: base_domain.to_string(),
+        target_suffix: target_suffix.to_string(),
+    };
+    let mut config = create_test_config();
+    config.base_domains = vec!["example.io".to_string()];
+    config.targets = vec![
+        target("example.com", ".example.cn"),
+        target("example.org", ".example.net"),
+    ];
+    let rewriter = BaseSniRewriter::new(config);
+
+    let rewritten = |sni: &'static str| {
+        let rewriter = &rewriter;
+        async move { rewriter.rewrite(sni).await.unwrap().target_hostname }
+    };
+    assert_eq!(rewritten("www.example.com").await, "www.example.cn");
+    assert_eq!(rewritten("api.example.org").await, "api.example.net");
+    // base_domains still use the shared target_suffix
+    assert_eq!(rewritten("www.example.io").await, "www.example.cn");
+    // Cached results keep their own suffix
+    assert_eq!(rewritten("api.example.org").await, "api.example.net");
+
+    assert!(matches!(
+        rewriter.rewrite_with_reason("example.org").await,
+        Err(dns_ingress::error::SniRewriteError::NoPrefix { .. })
+    ));
+    assert!(rewriter.rewrite("www.example.net").await.is_none());
+}
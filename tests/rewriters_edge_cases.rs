@@ -1,4 +1,5 @@
 use dns_ingress::config::RewriteConfig;
+use dns_ingress::error::SniRewriteError;
 use dns_ingress::rewriters::base::BaseSniRewriter;
 use dns_ingress::sni::SniRewriter;
 
@@ -7,7 +8,11 @@ async fn test_rewriter_empty_sni() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
     let result = rewriter.rewrite("").await;
@@ -19,7 +24,11 @@ async fn test_rewriter_empty_base_domains() {
     let config = RewriteConfig {
         base_domains: vec![],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
     let result = rewriter.rewrite("www.example.com").await;
@@ -31,7 +40,11 @@ async fn test_rewriter_invalid_target_suffix() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: "example.cn".to_string(), // Missing leading dot
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
     let _result = rewriter.rewrite("www.example.com").await;
@@ -44,7 +57,11 @@ async fn test_rewriter_no_prefix() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
     let result = rewriter.rewrite("example.com").await;
@@ -59,7 +76,11 @@ async fn test_rewriter_passthrough_strategy() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "passthrough".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
     let result = rewriter.rewrite("other.com").await;
@@ -84,7 +105,11 @@ async fn test_rewriter_multiple_base_domains() {
             "example.net".to_string(),
         ],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
@@ -109,7 +134,11 @@ async fn test_rewriter_long_prefix() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
     let result = rewriter.rewrite("very-long-prefix-name.example.com").await;
@@ -130,7 +159,11 @@ async fn test_rewriter_special_characters_in_prefix() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
     // Note: DNS hostnames typically don't allow special characters,
@@ -146,7 +179,11 @@ async fn test_rewriter_case_sensitivity() {
     let config = RewriteConfig {
         base_domains: vec!["Example.COM".to_string()], // Uppercase
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
     // DNS is case-insensitive, but our implementation is case-sensitive
@@ -160,7 +197,11 @@ async fn test_rewriter_cache_behavior() {
     let config = RewriteConfig {
         base_domains: vec!["example.com".to_string()],
         target_suffix: ".example.cn".to_string(),
+        targets: Vec::new(),
         rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
     };
     let rewriter = BaseSniRewriter::new(config);
 
@@ -172,12 +213,12 @@ async fn test_rewriter_cache_behavior() {
     let cached = rewriter.sni_map.get("www.example.com");
     assert!(cached.is_some(), "Should cache the mapping");
     assert_eq!(
-        cached.unwrap().value(),
+        cached.unwrap().target_hostname,
         "www.example.cn",
         "Cache should contain correct target"
     );
 
-    // Second rewrite should use cache (though we can't directly verify this)
+    // Second rewrite is served from the cache
     let result2 = rewriter.rewrite("www.example.com").await;
     assert!(result2.is_some());
     assert_eq!(
@@ -186,3 +227,68 @@ async fn test_rewriter_cache_behavior() {
         "Second rewrite should return same result"
     );
 }
+
+#[tokio::test]
+async fn test_rewrite_with_reason_maps_each_failure() {
+    let config = |base_domains: &[&str], target_suffix: &str| RewriteConfig {
+        base_domains: base_domains.iter().map(|d| d.to_string()).collect(),
+        target_suffix: target_suffix.to_string(),
+        targets: Vec::new(),
+        rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "base".to_string(),
+        mapping_file: None,
+        rules: Vec::new(),
+    };
+    let valid = BaseSniRewriter::new(config(&["example.com"], ".example.cn"));
+
+    let err = valid.rewrite_with_reason("").await.unwrap_err();
+    assert!(matches!(err, SniRewriteError::EmptySni), "{:?}", err);
+
+    let err = valid
+        .rewrite_with_reason("www.example.org")
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&err, SniRewriteError::NoMatchingBaseDomain { hostname } if hostname == "www.example.org"),
+        "{:?}",
+        err
+    );
+    assert_eq!(err.reason(), "no_matching_base_domain");
+
+    let err = valid.rewrite_with_reason("example.com").await.unwrap_err();
+    assert!(
+        matches!(&err, SniRewriteError::NoPrefix { hostname } if hostname == "example.com"),
+        "{:?}",
+        err
+    );
+    assert_eq!(err.reason(), "no_prefix");
+
+    let no_base_domains = BaseSniRewriter::new(config(&[], ".example.cn"));
+    let err = no_base_domains
+        .rewrite_with_reason("www.example.com")
+        .await
+        .unwrap_err();
+    assert!(matches!(err, SniRewriteError::NoBaseDomains), "{:?}", err);
+
+    let bad_suffix = BaseSniRewriter::new(config(&["example.com"], "example.cn"));
+    let err = bad_suffix
+        .rewrite_with_reason("www.example.com")
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(&err, SniRewriteError::InvalidTargetSuffix { suffix } if suffix == "example.cn"),
+        "{:?}",
+        err
+    );
+
+    // `rewrite` drops the reason
+    assert!(valid.rewrite("example.com").await.is_none());
+    assert_eq!(
+        valid
+            .rewrite_with_reason("www.example.com")
+            .await
+            .unwrap()
+            .target_hostname,
+        "www.example.cn"
+    );
+}
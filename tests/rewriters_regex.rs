@@ -0,0 +1,88 @@
+use dns_ingress::config::{RewriteConfig, RewriteRule};
+use dns_ingress::rewrite::create_rewriter;
+use dns_ingress::rewriters::RegexSniRewriter;
+use dns_ingress::sni::SniRewriter;
+
+fn create_test_config(rules: &[(&str, &str)]) -> RewriteConfig {
+    RewriteConfig {
+        base_domains: Vec::new(),
+        target_suffix: String::new(),
+        targets: Vec::new(),
+        rewrite_failure_strategy: "error".to_string(),
+        rewriter_type: "regex".to_string(),
+        mapping_file: None,
+        rules: rules
+            .iter()
+            .map(|(pattern, replacement)| RewriteRule {
+                pattern: pattern.to_string(),
+                replacement: replacement.to_string(),
+            })
+            .collect(),
+    }
+}
+
+#[tokio::test]
+async fn test_regex_rewrite_with_capture_groups() {
+    let config = create_test_config(&[
+        (r"^(\w+)\.eu\.example\.com$", "$1.eu-west.example.net"),
+        (
+            r"^(?<svc>\w+)\.(\w+)\.example\.com$",
+            "${svc}-$2.example.cn",
+        ),
+    ]);
+    let rewriter = RegexSniRewriter::new(&config).unwrap();
+
+    let result = rewriter.rewrite("api.eu.example.com").await.unwrap();
+    assert_eq!(result.original, "api.eu.example.com");
+    assert_eq!(result.prefix, "api");
+    assert_eq!(result.target_hostname, "api.eu-west.example.net");
+
+    // Rules are tried in order
+    let result = rewriter.rewrite("api.us.example.com").await.unwrap();
+    assert_eq!(result.target_hostname, "api-us.example.cn");
+}
+
+#[tokio::test]
+async fn test_regex_rewrite_no_match() {
+    let config = create_test_config(&[(r"^(\w+)\.example\.com$", "$1.example.cn")]);
+    let rewriter = RegexSniRewriter::new(&config).unwrap();
+
+    assert!(rewriter.rewrite("www.example.org").await.is_none());
+    assert!(rewriter.rewrite("a.b.example.com").await.is_none());
+    assert!(rewriter.rewrite("").await.is_none());
+    assert!(matches!(
+        rewriter.rewrite_with_reason("www.example.org").await,
+        Err(dns_ingress::error::SniRewriteError::NoMatchingRule { .. })
+    ));
+    assert!(matches!(
+        rewriter.rewrite_with_reason("").await,
+        Err(dns_ingress::error::SniRewriteError::EmptySni)
+    ));
+}
+
+#[tokio::test]
+async fn test_regex_rewrite_passthrough() {
+    let mut config = create_test_config(&[(r"^(\w+)\.example\.com$", "$1.example.cn")]);
+    config.rewrite_failure_strategy = "passthrough".to_string();
+    let rewriter = RegexSniRewriter::new(&config).unwrap();
+
+    let result = rewriter.rewrite("www.example.org").await.unwrap();
+    assert_eq!(result.target_hostname, "www.example.org");
+}
+
+#[test]
+fn test_regex_rewriter_rejects_invalid_pattern() {
+    let config = create_test_config(&[(r"^(\w+\.example\.com$", "$1.example.cn")]);
+    assert!(RegexSniRewriter::new(&config).is_err());
+}
+
+#[tokio::test]
+async fn test_create_rewriter_selects_regex() {
+    let rewriter = create_rewriter(create_test_config(&[(
+        r"^(\w+)\.example\.com$",
+        "$1.example.cn",
+    )]));
+
+    let result = rewriter.rewrite("www.example.com").await.unwrap();
+    assert_eq!(result.target_hostname, "www.example.cn");
+}
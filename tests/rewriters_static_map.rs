@@ -0,0 +1,129 @@
+use dns_ingress::config::RewriteConfig;
+use dns_ingress::rewrite::create_rewriter;
+use dns_ingress::rewriters::StaticMapRewriter;
+use dns_ingress::sni::SniRewriter;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+fn create_test_config(mapping_file: &Path, strategy: &str) -> RewriteConfig {
+    RewriteConfig {
+        base_domains: Vec::new(),
+        target_suffix: String::new(),
+        targets: Vec::new(),
+        rewrite_failure_strategy: strategy.to_string(),
+        rewriter_type: "static_map".to_string(),
+        mapping_file: Some(mapping_file.to_string_lossy().into_owned()),
+        rules: Vec::new(),
+    }
+}
+
+fn write_toml_mapping(dir: &Path) -> std::path::PathBuf {
+    let path = dir.join("sni-map.toml");
+    std::fs::write(
+        &path,
+        r#"
+"www.example.com" = "edge-1.example.cn"
+"API.example.org" = "api.internal.example.net"
+"#,
+    )
+    .unwrap();
+    path
+}
+
+#[tokio::test]
+async fn test_static_map_hit() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_toml_mapping(dir.path());
+    let rewriter = StaticMapRewriter::new(&create_test_config(&path, "error")).unwrap();
+
+    let result = rewriter.rewrite("www.example.com").await.unwrap();
+    assert_eq!(result.original, "www.example.com");
+    assert_eq!(result.target_hostname, "edge-1.example.cn");
+
+    // Sources match case-insensitively
+    let result = rewriter.rewrite("api.EXAMPLE.org").await.unwrap();
+    assert_eq!(result.original, "api.EXAMPLE.org");
+    assert_eq!(result.target_hostname, "api.internal.example.net");
+}
+
+#[tokio::test]
+async fn test_static_map_miss_with_error_strategy() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_toml_mapping(dir.path());
+    let rewriter = StaticMapRewriter::new(&create_test_config(&path, "error")).unwrap();
+
+    assert!(rewriter.rewrite("mail.example.com").await.is_none());
+    assert!(rewriter.rewrite("").await.is_none());
+    assert!(matches!(
+        rewriter.rewrite_with_reason("mail.example.com").await,
+        Err(dns_ingress::error::SniRewriteError::NoMatchingRule { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_static_map_miss_with_passthrough_strategy() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_toml_mapping(dir.path());
+    let rewriter = StaticMapRewriter::new(&create_test_config(&path, "passthrough")).unwrap();
+
+    let result = rewriter.rewrite("mail.example.com").await.unwrap();
+    assert_eq!(result.original, "mail.example.com");
+    assert_eq!(result.target_hostname, "mail.example.com");
+}
+
+#[tokio::test]
+async fn test_static_map_csv() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("sni-map.csv");
+    std::fs::write(
+        &path,
+        "# source,target\nwww.example.com, edge-1.example.cn\n\napi.example.org,api.example.net\n",
+    )
+    .unwrap();
+    let rewriter = StaticMapRewriter::new(&create_test_config(&path, "error")).unwrap();
+
+    let result = rewriter.rewrite("www.example.com").await.unwrap();
+    assert_eq!(result.target_hostname, "edge-1.example.cn");
+    let result = rewriter.rewrite("api.example.org").await.unwrap();
+    assert_eq!(result.target_hostname, "api.example.net");
+
+    std::fs::write(&path, "www.example.com\n").unwrap();
+    assert!(StaticMapRewriter::new(&create_test_config(&path, "error")).is_err());
+}
+
+#[tokio::test]
+async fn test_static_map_reloads_changed_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_toml_mapping(dir.path());
+    let rewriter = StaticMapRewriter::new(&create_test_config(&path, "error")).unwrap();
+    assert!(rewriter.rewrite("mail.example.com").await.is_none());
+
+    let set_modified = |offset: u64| {
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(offset))
+            .unwrap();
+    };
+
+    std::fs::write(&path, r#""mail.example.com" = "mx.example.cn""#).unwrap();
+    set_modified(60);
+    let result = rewriter.rewrite("mail.example.com").await.unwrap();
+    assert_eq!(result.target_hostname, "mx.example.cn");
+    assert!(rewriter.rewrite("www.example.com").await.is_none());
+
+    // A file that no longer parses keeps the loaded table
+    std::fs::write(&path, "not = [valid").unwrap();
+    set_modified(120);
+    let result = rewriter.rewrite("mail.example.com").await.unwrap();
+    assert_eq!(result.target_hostname, "mx.example.cn");
+    assert!(rewriter.reload().is_err());
+}
+
+#[tokio::test]
+async fn test_create_rewriter_selects_static_map() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = write_toml_mapping(dir.path());
+    let rewriter = create_rewriter(create_test_config(&path, "error"));
+
+    let result = rewriter.rewrite("www.example.com").await.unwrap();
+    assert_eq!(result.target_hostname, "edge-1.example.cn");
+}
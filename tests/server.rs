@@ -0,0 +1,289 @@
+use dns_ingress::error::DnsProxyError;
+use dns_ingress::metrics::Metrics;
+use dns_ingress::server::{
+    AcceptBackoff, ConnectionTracker, ReadySignal, TcpListeners, UdpSockets,
+};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::sync::CancellationToken;
+
+#[tokio::test]
+async fn test_connection_limit_queues_excess_tasks() {
+    let metrics = Metrics::new();
+    let in_flight = metrics.in_flight_gauge("tcp_dns");
+    let mut tracker = ConnectionTracker::new().with_limit(1, in_flight.clone());
+
+    let (release, released) = tokio::sync::oneshot::channel::<()>();
+    tracker.reserve().await;
+    tracker.spawn(async move {
+        let _ = released.await;
+    });
+    assert_eq!(in_flight.get(), 1);
+
+    // A second task has to wait for the first to finish
+    let waited = tokio::time::timeout(Duration::from_millis(100), tracker.reserve()).await;
+    assert!(waited.is_err(), "second reservation should wait for a slot");
+
+    release.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), tracker.reserve())
+        .await
+        .expect("slot should free once the first task ends");
+    assert_eq!(in_flight.get(), 0);
+
+    tracker.spawn(async {});
+    tracker.drain("test").await;
+    assert_eq!(in_flight.get(), 0);
+}
+
+#[tokio::test]
+async fn test_no_limit_never_waits() {
+    let metrics = Metrics::new();
+    let in_flight = metrics.in_flight_gauge("udp");
+    let mut tracker = ConnectionTracker::new().with_limit(0, in_flight.clone());
+
+    let mut releases = Vec::new();
+    for _ in 0..10 {
+        let (release, released) = tokio::sync::oneshot::channel::<()>();
+        releases.push(release);
+        tokio::time::timeout(Duration::from_millis(100), tracker.reserve())
+            .await
+            .expect("unlimited tracker should not wait");
+        tracker.spawn(async move {
+            let _ = released.await;
+        });
+    }
+    assert_eq!(in_flight.get(), 10);
+
+    drop(releases);
+    tracker.drain("test").await;
+    assert_eq!(in_flight.get(), 0);
+}
+
+#[test]
+fn test_in_flight_gauge_is_exported() {
+    let metrics = Metrics::new();
+    metrics.in_flight_gauge("dot").set(3);
+    assert!(
+        metrics
+            .export_prometheus()
+            .contains("dns_proxy_in_flight_connections{server=\"dot\"} 3")
+    );
+}
+
+/// `127.0.0.1` at the port of a listener bound to the IPv6 wildcard address
+fn ipv4_loopback(addr: SocketAddr) -> SocketAddr {
+    assert!(addr.is_ipv6());
+    SocketAddr::from(([127, 0, 0, 1], addr.port()))
+}
+
+#[tokio::test]
+async fn test_ipv6_wildcard_tcp_listener_accepts_ipv4() {
+    let listeners = TcpListeners::bind(&["[::]:0".parse().unwrap()]).unwrap();
+    let addr = listeners.local_addrs()[0];
+
+    let mut client = tokio::net::TcpStream::connect(ipv4_loopback(addr))
+        .await
+        .unwrap();
+    let (mut stream, peer) = listeners.accept().await.unwrap();
+    assert_eq!(peer.ip().to_canonical(), client.local_addr().unwrap().ip());
+
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[tokio::test]
+async fn test_tcp_listeners_accept_on_every_address() {
+    let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let listeners = TcpListeners::bind(&[addr, addr]).unwrap();
+    let addrs = listeners.local_addrs();
+    assert_eq!(addrs.len(), 2);
+
+    for addr in addrs {
+        let client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let (_stream, peer) = listeners.accept().await.unwrap();
+        assert_eq!(peer, client.local_addr().unwrap());
+    }
+}
+
+#[tokio::test]
+async fn test_ipv6_wildcard_udp_socket_receives_ipv4() {
+    let sockets = UdpSockets::bind(&["[::]:0".parse().unwrap()]).unwrap();
+    let addr = sockets.local_addrs()[0];
+
+    let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.send_to(b"ping", ipv4_loopback(addr)).await.unwrap();
+    let mut buf = [0u8; 16];
+    let (len, peer, socket) = sockets.recv_from(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    assert_eq!(peer.ip().to_canonical(), client.local_addr().unwrap().ip());
+
+    // Answers go back through the socket the datagram arrived on
+    socket.send_to(b"pong", peer).await.unwrap();
+    let len = client.recv(&mut buf).await.unwrap();
+    assert_eq!(&buf[..len], b"pong");
+}
+
+#[tokio::test]
+async fn test_ready_signal_reports_listening_once() {
+    let (signal, ready) = ReadySignal::channel("Test");
+    signal.listening();
+    // Later reports go nowhere; the error is handed back for logging
+    let e = signal.failed(DnsProxyError::Protocol("after".to_string()));
+    assert!(e.is_some());
+    ready.wait().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_ready_signal_reports_startup_error() {
+    let (signal, ready) = ReadySignal::channel("Test");
+    assert!(
+        signal
+            .failed(DnsProxyError::Config("no listener".to_string()))
+            .is_none()
+    );
+    let err = ready.wait().await.unwrap_err();
+    assert!(matches!(err, DnsProxyError::ServerStart { ref server, .. } if server == "Test"));
+    assert!(err.to_string().contains("no listener"));
+}
+
+#[tokio::test]
+async fn test_server_ready_fails_when_signal_dropped() {
+    let (signal, ready) = ReadySignal::channel("Test");
+    drop(signal);
+    let err = ready.wait().await.unwrap_err();
+    assert!(err.to_string().contains("ended before listening"));
+}
+
+fn reader(protocol: dns_ingress::readers::Protocol) -> Box<dyn dns_ingress::readers::Reader> {
+    dns_ingress::readers::all()
+        .into_iter()
+        .find(|reader| reader.protocol() == protocol)
+        .unwrap()
+}
+
+#[test]
+fn test_server_starter_skips_disabled_server() {
+    use dns_ingress::config::AppConfig;
+    use dns_ingress::readers::Protocol;
+    use dns_ingress::rewrite::create_rewriter;
+    use dns_ingress::server::{ServerResources, ServerStarter};
+    use std::sync::Arc;
+
+    let mut config = AppConfig::default();
+    config.servers.dot.enabled = false;
+    let rewriter = create_rewriter(config.rewrite.clone());
+    let resources =
+        ServerResources::standalone(Arc::new(config), rewriter, Arc::new(Metrics::new()));
+    assert!(ServerStarter::start(reader(Protocol::Dot), resources).is_none());
+}
+
+#[tokio::test]
+async fn test_server_starter_builds_server_from_resources() {
+    use dns_ingress::config::AppConfig;
+    use dns_ingress::readers::Protocol;
+    use dns_ingress::rewrite::create_rewriter;
+    use dns_ingress::server::{ServerResources, ServerStarter};
+    use dns_ingress::testing::MockUpstream;
+    use std::sync::Arc;
+
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut config = AppConfig::default();
+    config.servers.tcp_dns.enabled = true;
+    config.servers.tcp_dns.bind_address = vec!["127.0.0.1".to_string()];
+    config.servers.tcp_dns.port = port;
+
+    let mut query = vec![0xbe, 0xef, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in ["www", "example", "net"] {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[0, 0, 1, 0, 1]);
+    let mut answer = query.clone();
+    answer[2] |= 0x80;
+    let mock = Arc::new(MockUpstream::new().with_response("www.example.net", 1, answer.clone()));
+
+    let rewriter = create_rewriter(config.rewrite.clone());
+    let metrics = Arc::new(Metrics::new());
+    let resources = ServerResources::standalone(Arc::new(config), rewriter, Arc::clone(&metrics))
+        .with_upstream(mock.clone());
+    let shutdown = resources.shutdown.clone();
+    let (handle, ready) = ServerStarter::start(reader(Protocol::TcpDns), resources).unwrap();
+    ready.wait().await.unwrap();
+
+    let mut stream = tokio::net::TcpStream::connect(("127.0.0.1", port))
+        .await
+        .unwrap();
+    stream
+        .write_all(&(query.len() as u16).to_be_bytes())
+        .await
+        .unwrap();
+    stream.write_all(&query).await.unwrap();
+    let len = stream.read_u16().await.unwrap() as usize;
+    let mut response = vec![0u8; len];
+    stream.read_exact(&mut response).await.unwrap();
+    drop(stream);
+
+    assert_eq!(response, answer);
+    assert_eq!(mock.query_count(), 1);
+    assert_eq!(metrics.successful_requests(), 1);
+
+    shutdown.cancel();
+    tokio::time::timeout(Duration::from_secs(5), handle)
+        .await
+        .expect("server should stop once shut down")
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_accept_backoff_grows_and_loop_survives_errors() {
+    let mut backoff = AcceptBackoff::new(
+        Duration::from_millis(5),
+        Duration::from_millis(20),
+        CancellationToken::new(),
+    );
+    // An accept source failing like a process out of file descriptors, then
+    // recovering
+    let mut results = (0..5)
+        .map(|_| Err(std::io::Error::from_raw_os_error(24)))
+        .chain([Ok("connection")]);
+
+    let mut delays = Vec::new();
+    let accepted = loop {
+        match results.next().unwrap() {
+            Ok(conn) => {
+                backoff.succeeded();
+                break conn;
+            }
+            Err(e) => delays.push(backoff.failed("Test", &e).await),
+        }
+    };
+
+    assert_eq!(accepted, "connection");
+    let delays: Vec<u64> = delays.iter().map(|d| d.as_millis() as u64).collect();
+    assert_eq!(delays, [5, 10, 20, 20, 20]);
+    // An accepted connection starts the backoff over
+    assert_eq!(backoff.next_delay(), Duration::from_millis(5));
+}
+
+#[tokio::test]
+async fn test_accept_backoff_pause_ends_on_shutdown() {
+    let shutdown = CancellationToken::new();
+    let mut backoff = AcceptBackoff::new(
+        Duration::from_secs(60),
+        Duration::from_secs(60),
+        shutdown.clone(),
+    );
+    shutdown.cancel();
+
+    let error = std::io::Error::other("accept failed");
+    tokio::time::timeout(Duration::from_secs(5), backoff.failed("Test", &error))
+        .await
+        .expect("pause should end once shutdown starts");
+}
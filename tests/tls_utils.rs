@@ -1,5 +1,5 @@
 use dns_ingress::config::{AppConfig, CertificateConfig, TlsConfig};
-use dns_ingress::tls_utils::{CertificateResolver, DynamicCertResolver};
+use dns_ingress::tls_utils::{CertificateResolver, DynamicCertResolver, create_server_config};
 use std::sync::Arc;
 
 #[test]
@@ -17,8 +17,10 @@ async fn test_get_cert_for_domain_with_config() {
     let cert_config = CertificateConfig {
         cert_file: "/nonexistent/cert.pem".to_string(),
         key_file: "/nonexistent/key.pem".to_string(),
+        key_passphrase: None,
         ca_file: None,
         require_client_cert: false,
+        ocsp_file: None,
     };
 
     tls_config
@@ -62,8 +64,10 @@ async fn test_get_cert_for_domain_caching() {
     let cert_config = CertificateConfig {
         cert_file: "/nonexistent/cert.pem".to_string(),
         key_file: "/nonexistent/key.pem".to_string(),
+        key_passphrase: None,
         ca_file: None,
         require_client_cert: false,
+        ocsp_file: None,
     };
 
     tls_config
@@ -88,3 +92,934 @@ fn test_dynamic_cert_resolver_new() {
 
     assert!(Arc::strong_count(&dynamic_resolver.resolver) >= 1);
 }
+
+/// Write a fresh self-signed certificate for `domain` over `cert_file` and
+/// `key_file`, stamping both with `modified`. Returns the certificate's DER.
+fn write_cert(
+    domain: &str,
+    cert_file: &std::path::Path,
+    key_file: &std::path::Path,
+    modified: std::time::SystemTime,
+) -> Vec<u8> {
+    let certified = rcgen::generate_simple_self_signed(vec![domain.to_string()]).unwrap();
+    std::fs::write(cert_file, certified.cert.pem()).unwrap();
+    std::fs::write(key_file, certified.signing_key.serialize_pem()).unwrap();
+    for path in [cert_file, key_file] {
+        let file = std::fs::File::options().write(true).open(path).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+    certified.cert.der().to_vec()
+}
+
+#[tokio::test]
+async fn test_get_cert_for_domain_reloads_renewed_cert() {
+    use std::time::{Duration, SystemTime};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let cert_file = dir.path().join("cert.pem");
+    let key_file = dir.path().join("key.pem");
+    let issued = SystemTime::now() - Duration::from_secs(3600);
+    let first = write_cert("example.com", &cert_file, &key_file, issued);
+
+    let mut config = AppConfig::default();
+    config.tls.certs.insert(
+        "example.com".to_string(),
+        CertificateConfig {
+            cert_file: cert_file.to_string_lossy().into_owned(),
+            key_file: key_file.to_string_lossy().into_owned(),
+            key_passphrase: None,
+            ca_file: None,
+            require_client_cert: false,
+            ocsp_file: None,
+        },
+    );
+    let resolver = CertificateResolver::new(config);
+    let served = |cert: Arc<rustls::sign::CertifiedKey>| cert.cert[0].to_vec();
+    assert_eq!(
+        served(resolver.get_cert_for_domain("example.com").await.unwrap()),
+        first
+    );
+
+    // A renewal changes the files' modification time and is picked up
+    let renewed_at = issued + Duration::from_secs(60);
+    let renewed = write_cert("example.com", &cert_file, &key_file, renewed_at);
+    assert_eq!(
+        served(resolver.get_cert_for_domain("example.com").await.unwrap()),
+        renewed
+    );
+
+    // A broken renewal keeps the last good certificate
+    std::fs::write(&cert_file, "not a certificate").unwrap();
+    assert_eq!(
+        served(resolver.get_cert_for_domain("example.com").await.unwrap()),
+        renewed
+    );
+
+    // Files swapped without a new modification time need an invalidation
+    let swapped = write_cert("example.com", &cert_file, &key_file, renewed_at);
+    assert_eq!(
+        served(resolver.get_cert_for_domain("example.com").await.unwrap()),
+        renewed
+    );
+    resolver.invalidate("example.com");
+    assert_eq!(
+        served(resolver.get_cert_for_domain("example.com").await.unwrap()),
+        swapped
+    );
+}
+
+/// Client certificate chain and key presented during a handshake
+type ClientIdentity = (
+    Vec<rustls::pki_types::CertificateDer<'static>>,
+    rustls::pki_types::PrivateKeyDer<'static>,
+);
+
+/// Run a TLS handshake for `server_name` in memory, trusting only `trusted`,
+/// and return the certificate the server presented along with the server
+/// side of the connection
+fn handshake(
+    server_config: Arc<rustls::ServerConfig>,
+    server_name: &str,
+    trusted: &[u8],
+    client_identity: Option<ClientIdentity>,
+) -> Result<(Vec<u8>, rustls::ServerConnection), rustls::Error> {
+    let client_config = client_config(trusted, client_identity);
+    let (client, server) = handshake_with(server_config, client_config, server_name)?;
+    let presented = client.peer_certificates().unwrap()[0].to_vec();
+    Ok((presented, server))
+}
+
+/// Client config trusting only `trusted`, optionally presenting a client
+/// certificate
+fn client_config(
+    trusted: &[u8],
+    client_identity: Option<ClientIdentity>,
+) -> Arc<rustls::ClientConfig> {
+    use rustls::pki_types::CertificateDer;
+    use rustls::{ClientConfig, RootCertStore};
+
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from(trusted.to_vec())).unwrap();
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    Arc::new(match client_identity {
+        Some((chain, key)) => builder.with_client_auth_cert(chain, key).unwrap(),
+        None => builder.with_no_client_auth(),
+    })
+}
+
+/// Run a TLS handshake in memory with a given client config, which keeps any
+/// session tickets the server sends for later handshakes
+fn handshake_with(
+    server_config: Arc<rustls::ServerConfig>,
+    client_config: Arc<rustls::ClientConfig>,
+    server_name: &str,
+) -> Result<(rustls::ClientConnection, rustls::ServerConnection), rustls::Error> {
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConnection, ServerConnection};
+
+    let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+    let mut client = ClientConnection::new(client_config, server_name).unwrap();
+    let mut server = ServerConnection::new(server_config).unwrap();
+
+    // One flight past the handshake delivers TLS 1.3 session tickets
+    for _ in 0..10 {
+        let mut flight = Vec::new();
+        client.write_tls(&mut flight).unwrap();
+        if !flight.is_empty() {
+            server.read_tls(&mut flight.as_slice()).unwrap();
+            server.process_new_packets()?;
+        }
+        let mut flight = Vec::new();
+        server.write_tls(&mut flight).unwrap();
+        if !flight.is_empty() {
+            client.read_tls(&mut flight.as_slice()).unwrap();
+            client.process_new_packets()?;
+        } else if !client.is_handshaking() && !server.is_handshaking() {
+            break;
+        }
+    }
+    assert!(!client.is_handshaking());
+    Ok((client, server))
+}
+
+#[tokio::test]
+async fn test_session_resumption() {
+    use rustls::HandshakeKind;
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let cert_file = dir.path().join("example.com.pem");
+    let key_file = dir.path().join("example.com.key");
+    let cert = write_cert(
+        "example.com",
+        &cert_file,
+        &key_file,
+        std::time::SystemTime::now(),
+    );
+    let mut config = AppConfig::default();
+    config.tls.certs.insert(
+        "example.com".to_string(),
+        CertificateConfig {
+            cert_file: cert_file.to_string_lossy().into_owned(),
+            key_file: key_file.to_string_lossy().into_owned(),
+            key_passphrase: None,
+            ca_file: None,
+            require_client_cert: false,
+            ocsp_file: None,
+        },
+    );
+
+    // The second handshake from the same client resumes the first session
+    let (server_config, _resolver) = create_server_config(&config, &[]).await.unwrap();
+    let server_config = Arc::new(server_config);
+    let client = client_config(&cert, None);
+    let (first, _) = handshake_with(
+        Arc::clone(&server_config),
+        Arc::clone(&client),
+        "example.com",
+    )
+    .unwrap();
+    assert_eq!(first.handshake_kind(), Some(HandshakeKind::Full));
+    let (second, server) = handshake_with(server_config, client, "example.com").unwrap();
+    assert_eq!(second.handshake_kind(), Some(HandshakeKind::Resumed));
+    assert_eq!(server.server_name(), Some("example.com"));
+
+    // With resumption disabled every handshake is a full one
+    config.tls.session_resumption = false;
+    let (server_config, _resolver) = create_server_config(&config, &[]).await.unwrap();
+    let server_config = Arc::new(server_config);
+    let client = client_config(&cert, None);
+    for _ in 0..2 {
+        let (conn, _) = handshake_with(
+            Arc::clone(&server_config),
+            Arc::clone(&client),
+            "example.com",
+        )
+        .unwrap();
+        assert_eq!(conn.handshake_kind(), Some(HandshakeKind::Full));
+    }
+}
+
+#[tokio::test]
+async fn test_resolve_without_runtime_uses_preloaded_certs() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let modified = std::time::SystemTime::now();
+    let mut config = AppConfig::default();
+    let mut certs = Vec::new();
+    for name in ["example.com", "default.test"] {
+        let cert_file = dir.path().join(format!("{}.pem", name));
+        let key_file = dir.path().join(format!("{}.key", name));
+        certs.push(write_cert(name, &cert_file, &key_file, modified));
+        let cert_config = CertificateConfig {
+            cert_file: cert_file.to_string_lossy().into_owned(),
+            key_file: key_file.to_string_lossy().into_owned(),
+            key_passphrase: None,
+            ca_file: None,
+            require_client_cert: false,
+            ocsp_file: None,
+        };
+        if name == "example.com" {
+            config.tls.certs.insert(name.to_string(), cert_config);
+        } else {
+            config.tls.default = Some(cert_config);
+        }
+    }
+    let server_config = Arc::new(create_server_config(&config, &[]).await.unwrap().0);
+
+    // rustls calls the resolver on a thread with no Tokio runtime
+    let presented = std::thread::spawn(move || {
+        assert!(tokio::runtime::Handle::try_current().is_err());
+        (
+            handshake(Arc::clone(&server_config), "example.com", &certs[0], None)
+                .unwrap()
+                .0,
+            // An SNI without its own certificate gets the default one
+            handshake(server_config, "default.test", &certs[1], None)
+                .unwrap()
+                .0,
+            certs,
+        )
+    })
+    .join()
+    .unwrap();
+    assert_eq!(presented.0, presented.2[0]);
+    assert_eq!(presented.1, presented.2[1]);
+}
+
+#[tokio::test]
+async fn test_create_server_config_fails_on_unloadable_cert() {
+    let mut config = AppConfig::default();
+    config.tls.certs.insert(
+        "example.com".to_string(),
+        CertificateConfig {
+            cert_file: "/nonexistent/cert.pem".to_string(),
+            key_file: "/nonexistent/key.pem".to_string(),
+            key_passphrase: None,
+            ca_file: None,
+            require_client_cert: false,
+            ocsp_file: None,
+        },
+    );
+    assert!(create_server_config(&config, &[]).await.is_err());
+}
+
+/// A client CA written to `ca_file`, and a client certificate it issued
+fn client_ca(ca_file: &std::path::Path) -> ClientIdentity {
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedIssuer, ExtendedKeyUsagePurpose, IsCa,
+        KeyPair,
+    };
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+    std::fs::write(ca_file, ca.pem()).unwrap();
+
+    let client_key = KeyPair::generate().unwrap();
+    let mut client_params = CertificateParams::new(vec!["client.test".to_string()]).unwrap();
+    client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let client_cert = client_params.signed_by(&client_key, &ca).unwrap();
+    (
+        vec![client_cert.der().clone()],
+        PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(client_key.serialize_der())),
+    )
+}
+
+#[tokio::test]
+async fn test_client_cert_required_per_domain() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let ca_file = dir.path().join("client-ca.pem");
+    let client = client_ca(&ca_file);
+    let other_client = client_ca(&dir.path().join("other-ca.pem"));
+
+    // example.com requires a client certificate, the default certificate does not
+    let modified = std::time::SystemTime::now();
+    let mut config = AppConfig::default();
+    let mut certs = Vec::new();
+    for name in ["example.com", "default.test"] {
+        let cert_file = dir.path().join(format!("{}.pem", name));
+        let key_file = dir.path().join(format!("{}.key", name));
+        certs.push(write_cert(name, &cert_file, &key_file, modified));
+        let required = name == "example.com";
+        let cert_config = CertificateConfig {
+            cert_file: cert_file.to_string_lossy().into_owned(),
+            key_file: key_file.to_string_lossy().into_owned(),
+            key_passphrase: None,
+            ca_file: required.then(|| ca_file.to_string_lossy().into_owned()),
+            require_client_cert: required,
+            ocsp_file: None,
+        };
+        if required {
+            config.tls.certs.insert(name.to_string(), cert_config);
+        } else {
+            config.tls.default = Some(cert_config);
+        }
+    }
+    let (server_config, resolver) = create_server_config(&config, &[]).await.unwrap();
+    let server_config = Arc::new(server_config);
+    let verify = |server: &rustls::ServerConnection| {
+        resolver.verify_client(server.server_name(), server.peer_certificates())
+    };
+
+    // Refused without a client certificate, and during the handshake with
+    // one from an unknown CA
+    let (_, server) =
+        handshake(Arc::clone(&server_config), "example.com", &certs[0], None).unwrap();
+    assert!(verify(&server).is_err());
+    let untrusted = handshake(
+        Arc::clone(&server_config),
+        "example.com",
+        &certs[0],
+        Some(other_client),
+    );
+    assert!(untrusted.is_err());
+
+    // Accepted with a certificate issued by the configured CA
+    let (_, server) = handshake(
+        Arc::clone(&server_config),
+        "example.com",
+        &certs[0],
+        Some((client.0.clone(), client.1.clone_key())),
+    )
+    .unwrap();
+    verify(&server).unwrap();
+
+    // Accepted without a client certificate where none is required
+    let (_, server) = handshake(server_config, "default.test", &certs[1], None).unwrap();
+    verify(&server).unwrap();
+
+    // A Host served by another certificate entry is refused when either
+    // entry requires a client certificate
+    assert!(!resolver.allows_host(Some("default.test"), "example.com"));
+    assert!(!resolver.allows_host(Some("example.com"), "default.test"));
+    assert!(!resolver.allows_host(None, "example.com"));
+    assert!(resolver.allows_host(Some("example.com"), "Example.COM"));
+    assert!(resolver.allows_host(Some("default.test"), "other.test"));
+}
+
+#[tokio::test]
+async fn test_client_cert_required_everywhere_fails_handshake() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let ca_file = dir.path().join("client-ca.pem");
+    let client = client_ca(&ca_file);
+    let cert_file = dir.path().join("cert.pem");
+    let key_file = dir.path().join("key.pem");
+    let cert = write_cert(
+        "example.com",
+        &cert_file,
+        &key_file,
+        std::time::SystemTime::now(),
+    );
+
+    let mut config = AppConfig::default();
+    config.tls.default = Some(CertificateConfig {
+        cert_file: cert_file.to_string_lossy().into_owned(),
+        key_file: key_file.to_string_lossy().into_owned(),
+        key_passphrase: None,
+        ca_file: Some(ca_file.to_string_lossy().into_owned()),
+        require_client_cert: true,
+        ocsp_file: None,
+    });
+    let server_config = Arc::new(create_server_config(&config, &[]).await.unwrap().0);
+
+    // rustls itself refuses the handshake without a client certificate
+    assert!(handshake(Arc::clone(&server_config), "example.com", &cert, None).is_err());
+    handshake(server_config, "example.com", &cert, Some(client)).unwrap();
+
+    // Requiring a client certificate without a CA is a config error
+    config.tls.default.as_mut().unwrap().ca_file = None;
+    assert!(create_server_config(&config, &[]).await.is_err());
+}
+
+/// ALPN protocols of a server config built to negotiate `protocols`
+async fn server_alpn(config: &AppConfig, protocols: &[String]) -> Vec<Vec<u8>> {
+    create_server_config(config, protocols)
+        .await
+        .unwrap()
+        .0
+        .alpn_protocols
+}
+
+#[tokio::test]
+async fn test_create_server_config_sets_alpn() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let config = AppConfig::default();
+    let alpn = &config.tls.alpn;
+    assert_eq!(server_alpn(&config, &alpn.dot).await, vec![b"dot".to_vec()]);
+    assert_eq!(
+        server_alpn(&config, &alpn.doh).await,
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    );
+    assert_eq!(server_alpn(&config, &alpn.doq).await, vec![b"doq".to_vec()]);
+    assert_eq!(server_alpn(&config, &alpn.doh3).await, vec![b"h3".to_vec()]);
+
+    // Overridable per server in [tls.alpn]
+    let tls: TlsConfig = toml::from_str(
+        r#"
+[alpn]
+doh3 = ["h3", "h3-29"]
+"#,
+    )
+    .unwrap();
+    assert_eq!(tls.alpn.dot, vec!["dot"]);
+    assert_eq!(
+        server_alpn(&config, &tls.alpn.doh3).await,
+        vec![b"h3".to_vec(), b"h3-29".to_vec()]
+    );
+}
+
+/// DER element with `tag` around `contents`
+fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    match contents.len() {
+        len if len < 0x80 => element.push(len as u8),
+        len if len <= 0xff => element.extend([0x81, len as u8]),
+        len => element.extend([0x82, (len >> 8) as u8, len as u8]),
+    }
+    element.extend_from_slice(contents);
+    element
+}
+
+/// Unsigned OCSP response (RFC 6960) carrying one certificate status, with
+/// `GeneralizedTime` update times such as "20200101000000Z"
+fn ocsp_response(cert_status: &[u8], this_update: &str, next_update: &str) -> Vec<u8> {
+    let cert_id = der(
+        0x30,
+        &[
+            der(0x30, &der(0x06, &[0x2b, 0x0e, 0x03, 0x02, 0x1a])),
+            der(0x04, &[0; 20]),
+            der(0x04, &[0; 20]),
+            der(0x02, &[0x01]),
+        ]
+        .concat(),
+    );
+    let single = der(
+        0x30,
+        &[
+            cert_id,
+            cert_status.to_vec(),
+            der(0x18, this_update.as_bytes()),
+            der(0xa0, &der(0x18, next_update.as_bytes())),
+        ]
+        .concat(),
+    );
+    let data = der(
+        0x30,
+        &[
+            der(0xa2, &der(0x04, &[0; 20])),
+            der(0x18, this_update.as_bytes()),
+            der(0x30, &single),
+        ]
+        .concat(),
+    );
+    let basic = der(
+        0x30,
+        &[
+            data,
+            der(
+                0x30,
+                &der(0x06, &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02]),
+            ),
+            der(0x03, &[0x00, 0x00]),
+        ]
+        .concat(),
+    );
+    let response_bytes = der(
+        0x30,
+        &[
+            der(
+                0x06,
+                &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01],
+            ),
+            der(0x04, &basic),
+        ]
+        .concat(),
+    );
+    der(
+        0x30,
+        &[der(0x0a, &[0x00]), der(0xa0, &response_bytes)].concat(),
+    )
+}
+
+/// `good` certificate status
+const OCSP_GOOD: &[u8] = &[0x80, 0x00];
+
+#[test]
+fn test_check_ocsp_response() {
+    use dns_ingress::ocsp::check_response;
+    use std::time::{Duration, SystemTime};
+
+    // 2020-01-01 and 2099-01-01
+    let fresh = ocsp_response(OCSP_GOOD, "20200101000000Z", "20990101000000Z");
+    let validity = check_response(&fresh, SystemTime::now()).unwrap();
+    assert_eq!(
+        validity.this_update,
+        SystemTime::UNIX_EPOCH + Duration::from_secs(1_577_836_800)
+    );
+    assert_eq!(
+        validity.next_update,
+        SystemTime::UNIX_EPOCH + Duration::from_secs(4_070_908_800)
+    );
+
+    let expired = ocsp_response(OCSP_GOOD, "20200101000000Z", "20200108000000Z");
+    let err = check_response(&expired, SystemTime::now()).unwrap_err();
+    assert!(err.contains("expired"), "{}", err);
+
+    let future = ocsp_response(OCSP_GOOD, "20990101000000Z", "20990108000000Z");
+    let err = check_response(&future, SystemTime::now()).unwrap_err();
+    assert!(err.contains("not valid yet"), "{}", err);
+
+    let revoked = ocsp_response(
+        &der(0xa1, &der(0x18, b"20200101000000Z")),
+        "20200101000000Z",
+        "20990101000000Z",
+    );
+    let err = check_response(&revoked, SystemTime::now()).unwrap_err();
+    assert!(err.contains("revoked"), "{}", err);
+
+    // tryLater, without responseBytes
+    let unsuccessful = der(0x30, &der(0x0a, &[0x03]));
+    assert!(check_response(&unsuccessful, SystemTime::now()).is_err());
+    assert!(check_response(b"not an OCSP response", SystemTime::now()).is_err());
+    assert!(check_response(&fresh[..fresh.len() - 1], SystemTime::now()).is_err());
+}
+
+/// Server certificate verifier recording the OCSP response the server
+/// stapled
+#[derive(Debug)]
+struct StapleRecorder {
+    verifier: Arc<rustls::client::WebPkiServerVerifier>,
+    stapled: std::sync::Mutex<Option<Vec<u8>>>,
+}
+
+impl rustls::client::danger::ServerCertVerifier for StapleRecorder {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::pki_types::CertificateDer<'_>,
+        intermediates: &[rustls::pki_types::CertificateDer<'_>],
+        server_name: &rustls::pki_types::ServerName<'_>,
+        ocsp_response: &[u8],
+        now: rustls::pki_types::UnixTime,
+    ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error> {
+        *self.stapled.lock().unwrap() = Some(ocsp_response.to_vec());
+        self.verifier
+            .verify_server_cert(end_entity, intermediates, server_name, &[], now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &rustls::pki_types::CertificateDer<'_>,
+        dss: &rustls::DigitallySignedStruct,
+    ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+#[tokio::test]
+async fn test_certificate_served_with_ocsp_staple() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let cert_file = dir.path().join("cert.pem");
+    let key_file = dir.path().join("key.pem");
+    let ocsp_file = dir.path().join("cert.ocsp");
+    let cert = write_cert(
+        "example.com",
+        &cert_file,
+        &key_file,
+        std::time::SystemTime::now(),
+    );
+    let response = ocsp_response(OCSP_GOOD, "20200101000000Z", "20990101000000Z");
+    std::fs::write(&ocsp_file, &response).unwrap();
+
+    let mut config = AppConfig::default();
+    config.tls.certs.insert(
+        "example.com".to_string(),
+        CertificateConfig {
+            cert_file: cert_file.to_string_lossy().into_owned(),
+            key_file: key_file.to_string_lossy().into_owned(),
+            key_passphrase: None,
+            ca_file: None,
+            require_client_cert: false,
+            ocsp_file: Some(ocsp_file.to_string_lossy().into_owned()),
+        },
+    );
+    let (server_config, _resolver) = create_server_config(&config, &[]).await.unwrap();
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots
+        .add(rustls::pki_types::CertificateDer::from(cert))
+        .unwrap();
+    let recorder = Arc::new(StapleRecorder {
+        verifier: rustls::client::WebPkiServerVerifier::builder(Arc::new(roots))
+            .build()
+            .unwrap(),
+        stapled: std::sync::Mutex::new(None),
+    });
+    let client_config = rustls::ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(recorder.clone())
+        .with_no_client_auth();
+    handshake_with(
+        Arc::new(server_config),
+        Arc::new(client_config),
+        "example.com",
+    )
+    .unwrap();
+    assert_eq!(
+        recorder.stapled.lock().unwrap().as_deref(),
+        Some(&response[..])
+    );
+}
+
+#[tokio::test]
+async fn test_stale_ocsp_response_not_stapled_until_replaced() {
+    use std::time::{Duration, SystemTime};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let dir = tempfile::tempdir().unwrap();
+    let cert_file = dir.path().join("cert.pem");
+    let key_file = dir.path().join("key.pem");
+    let ocsp_file = dir.path().join("cert.ocsp");
+    let issued = SystemTime::now() - Duration::from_secs(3600);
+    write_cert("example.com", &cert_file, &key_file, issued);
+    let expired = ocsp_response(OCSP_GOOD, "20200101000000Z", "20200108000000Z");
+    std::fs::write(&ocsp_file, &expired).unwrap();
+
+    let mut config = AppConfig::default();
+    config.tls.certs.insert(
+        "example.com".to_string(),
+        CertificateConfig {
+            cert_file: cert_file.to_string_lossy().into_owned(),
+            key_file: key_file.to_string_lossy().into_owned(),
+            key_passphrase: None,
+            ca_file: None,
+            require_client_cert: false,
+            ocsp_file: Some(ocsp_file.to_string_lossy().into_owned()),
+        },
+    );
+    let resolver = CertificateResolver::new(config);
+
+    // An expired response is dropped, the certificate is still served
+    let cert = resolver.get_cert_for_domain("example.com").await.unwrap();
+    assert!(cert.ocsp.is_none());
+
+    // A refreshed response file is picked up like a renewed certificate
+    let fresh = ocsp_response(OCSP_GOOD, "20200101000000Z", "20990101000000Z");
+    std::fs::write(&ocsp_file, &fresh).unwrap();
+    let file = std::fs::File::options()
+        .write(true)
+        .open(&ocsp_file)
+        .unwrap();
+    file.set_modified(issued + Duration::from_secs(60)).unwrap();
+    let cert = resolver.get_cert_for_domain("example.com").await.unwrap();
+    assert_eq!(cert.ocsp.as_deref(), Some(&fresh[..]));
+}
+
+/// PEM section `label` around `der`
+fn pem(label: &str, der: &[u8]) -> String {
+    use base64::Engine;
+
+    let base64 = base64::engine::general_purpose::STANDARD.encode(der);
+    let lines: Vec<&str> = base64
+        .as_bytes()
+        .chunks(64)
+        .map(|line| std::str::from_utf8(line).unwrap())
+        .collect();
+    format!(
+        "-----BEGIN {label}-----\n{}\n-----END {label}-----\n",
+        lines.join("\n")
+    )
+}
+
+/// Load a certificate for "example.com" signed with the PKCS#8 key
+/// `pkcs8`, whose key file holds `key_pem` instead
+async fn load_with_key(
+    pkcs8: &[u8],
+    key_pem: String,
+    key_passphrase: Option<&str>,
+) -> dns_ingress::error::DnsProxyResult<Arc<rustls::sign::CertifiedKey>> {
+    let dir = tempfile::tempdir().unwrap();
+    let cert_file = dir.path().join("cert.pem");
+    let key_file = dir.path().join("key.pem");
+    let key_pair = rcgen::KeyPair::try_from(pkcs8).unwrap();
+    let cert = rcgen::CertificateParams::new(vec!["example.com".to_string()])
+        .unwrap()
+        .self_signed(&key_pair)
+        .unwrap();
+    std::fs::write(&cert_file, cert.pem()).unwrap();
+    std::fs::write(&key_file, key_pem).unwrap();
+
+    CertificateResolver::load_certificate(&CertificateConfig {
+        cert_file: cert_file.to_string_lossy().into_owned(),
+        key_file: key_file.to_string_lossy().into_owned(),
+        key_passphrase: key_passphrase.map(str::to_string),
+        ca_file: None,
+        require_client_cert: false,
+        ocsp_file: None,
+    })
+    .await
+}
+
+/// Contents of the DER element at the start of `input`, and what follows it
+fn der_contents(input: &[u8]) -> (&[u8], &[u8]) {
+    let (len, header) = match input[1] {
+        len if len < 0x80 => (len as usize, 2),
+        0x81 => (input[2] as usize, 3),
+        _ => (((input[2] as usize) << 8) | input[3] as usize, 4),
+    };
+    (&input[header..header + len], &input[header + len..])
+}
+
+#[tokio::test]
+async fn test_load_pkcs1_rsa_key() {
+    use aws_lc_rs::encoding::{AsDer, Pkcs8V1Der};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let key = aws_lc_rs::rsa::KeyPair::generate(aws_lc_rs::rsa::KeySize::Rsa2048).unwrap();
+    let pkcs8: Pkcs8V1Der = key.as_der().unwrap();
+    // PrivateKeyInfo: version, algorithm, then the PKCS#1 key as an OCTET STRING
+    let (info, _) = der_contents(pkcs8.as_ref());
+    let (_, rest) = der_contents(info);
+    let (_, rest) = der_contents(rest);
+    let (pkcs1, _) = der_contents(rest);
+
+    let key_pem = pem("RSA PRIVATE KEY", pkcs1);
+    let certified = load_with_key(pkcs8.as_ref(), key_pem, None).await.unwrap();
+    assert!(certified.keys_match().is_ok());
+}
+
+#[tokio::test]
+async fn test_load_sec1_ec_key() {
+    use aws_lc_rs::encoding::AsDer;
+    use aws_lc_rs::signature::{ECDSA_P256_SHA256_ASN1_SIGNING, EcdsaKeyPair};
+
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let key = EcdsaKeyPair::generate(&ECDSA_P256_SHA256_ASN1_SIGNING).unwrap();
+    let pkcs8 = key.to_pkcs8v1().unwrap();
+    let sec1 = key.private_key().as_der().unwrap();
+
+    let key_pem = pem("EC PRIVATE KEY", sec1.as_ref());
+    let certified = load_with_key(pkcs8.as_ref(), key_pem, None).await.unwrap();
+    assert!(certified.keys_match().is_ok());
+}
+
+/// `pkcs8` encrypted with `passphrase` as a PBES2 `EncryptedPrivateKeyInfo`
+/// (PBKDF2 with HMAC-SHA256, AES-256-CBC), as `openssl pkcs8 -topk8` writes
+fn encrypt_pkcs8(pkcs8: &[u8], passphrase: &str) -> Vec<u8> {
+    use aws_lc_rs::cipher::{
+        AES_256, EncryptionContext, PaddedBlockEncryptingKey, UnboundCipherKey,
+    };
+
+    let salt = [7u8; 16];
+    let iv = [9u8; 16];
+    let iterations = 2048u32;
+    let mut key = [0u8; 32];
+    aws_lc_rs::pbkdf2::derive(
+        aws_lc_rs::pbkdf2::PBKDF2_HMAC_SHA256,
+        std::num::NonZeroU32::new(iterations).unwrap(),
+        &salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    let mut data = pkcs8.to_vec();
+    PaddedBlockEncryptingKey::cbc_pkcs7(UnboundCipherKey::new(&AES_256, &key).unwrap())
+        .unwrap()
+        .less_safe_encrypt(&mut data, EncryptionContext::Iv128(iv.into()))
+        .unwrap();
+
+    let oid = |oid: &[u8]| der(0x06, oid);
+    let pbes2 = oid(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0d]);
+    let pbkdf2 = oid(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0c]);
+    let hmac_sha256 = oid(&[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x02, 0x09]);
+    let aes256_cbc = oid(&[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a]);
+    let kdf_params = der(
+        0x30,
+        &[
+            der(0x04, &salt),
+            der(0x02, &iterations.to_be_bytes()[2..]),
+            der(0x30, &[hmac_sha256, vec![0x05, 0x00]].concat()),
+        ]
+        .concat(),
+    );
+    let kdf = der(0x30, &[pbkdf2, kdf_params].concat());
+    let scheme = der(0x30, &[aes256_cbc, der(0x04, &iv)].concat());
+    let algorithm = der(0x30, &[pbes2, der(0x30, &[kdf, scheme].concat())].concat());
+    der(0x30, &[algorithm, der(0x04, &data)].concat())
+}
+
+#[tokio::test]
+async fn test_load_encrypted_pkcs8_key() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let key_pair = rcgen::KeyPair::generate().unwrap();
+    let pkcs8 = key_pair.serialized_der();
+    let encrypted = pem("ENCRYPTED PRIVATE KEY", &encrypt_pkcs8(pkcs8, "hunter2"));
+
+    let certified = load_with_key(pkcs8, encrypted.clone(), Some("hunter2"))
+        .await
+        .unwrap();
+    assert!(certified.keys_match().is_ok());
+
+    // The passphrase may come from the environment
+    unsafe { std::env::set_var("DNS_PROXY_TEST_KEY_PASSPHRASE", "hunter2") };
+    let from_env = Some("${DNS_PROXY_TEST_KEY_PASSPHRASE}");
+    assert!(
+        load_with_key(pkcs8, encrypted.clone(), from_env)
+            .await
+            .is_ok()
+    );
+
+    let err = load_with_key(pkcs8, encrypted.clone(), Some("hunter3"))
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("wrong key_passphrase"), "{}", err);
+
+    let err = load_with_key(pkcs8, encrypted, None).await.unwrap_err();
+    assert!(
+        err.to_string().contains("no key_passphrase is configured"),
+        "{}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn test_load_unsupported_key_format_names_it() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let key_pair = rcgen::KeyPair::generate().unwrap();
+    let dsa = pem("DSA PRIVATE KEY", &[0x30, 0x00]);
+
+    let err = load_with_key(key_pair.serialized_der(), dsa, None)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            &err,
+            dns_ingress::error::DnsProxyError::Certificate(
+                dns_ingress::error::CertificateError::PrivateKey { .. }
+            )
+        ),
+        "{:?}",
+        err
+    );
+    assert!(err.to_string().contains("\"DSA PRIVATE KEY\""), "{}", err);
+}
+
+#[tokio::test]
+async fn test_generated_self_signed_cert_covers_base_domains() {
+    let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+    let mut config = AppConfig::default();
+    config.rewrite.base_domains = vec!["example.com".to_string()];
+    config.tls.generate_self_signed = true;
+    let (server_config, resolver) = create_server_config(&config, &[]).await.unwrap();
+    let server_config = Arc::new(server_config);
+
+    // Every SNI, and clients without one, get the generated certificate
+    let cert = resolver.cached_cert("www.example.com").unwrap().cert[0].to_vec();
+    for name in ["example.com", "www.example.com", "localhost"] {
+        let (presented, _) = handshake(Arc::clone(&server_config), name, &cert, None).unwrap();
+        assert_eq!(presented, cert);
+    }
+    assert!(handshake(server_config, "example.org", &cert, None).is_err());
+
+    // A configured default certificate takes precedence
+    let dir = tempfile::tempdir().unwrap();
+    let cert_file = dir.path().join("cert.pem");
+    let key_file = dir.path().join("key.pem");
+    let configured = write_cert(
+        "example.com",
+        &cert_file,
+        &key_file,
+        std::time::SystemTime::now(),
+    );
+    config.tls.default = Some(CertificateConfig {
+        cert_file: cert_file.to_string_lossy().into_owned(),
+        key_file: key_file.to_string_lossy().into_owned(),
+        key_passphrase: None,
+        ca_file: None,
+        require_client_cert: false,
+        ocsp_file: None,
+    });
+    let (_, resolver) = create_server_config(&config, &[]).await.unwrap();
+    assert_eq!(
+        resolver.cached_cert("www.example.com").unwrap().cert[0].to_vec(),
+        configured
+    );
+}
@@ -1,6 +1,8 @@
+use dns_ingress::config::AppConfig;
 use dns_ingress::upstream::pool::{ConnectionPool, HttpClient};
 use dns_ingress::upstream::{create_connection_pool, forward_http_request};
-use std::sync::Once;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Once};
 
 static INIT: Once = Once::new();
 
@@ -15,7 +17,7 @@ fn init_crypto_provider() {
 #[test]
 fn test_create_connection_pool() {
     init_crypto_provider();
-    let _pool = create_connection_pool();
+    let _pool = create_connection_pool(&AppConfig::default().upstream);
 }
 
 #[test]
@@ -23,7 +25,7 @@ fn test_upstream_module_imports() {
     init_crypto_provider();
     // Test that upstream module exports are accessible
     // Verify the module structure exists
-    let pool = create_connection_pool();
+    let pool = create_connection_pool(&AppConfig::default().upstream);
     let _client = pool.get_client("example.com");
     assert!(std::any::type_name::<HttpClient>().contains("Client"));
     assert!(std::any::type_name::<ConnectionPool>().contains("ConnectionPool"));
@@ -36,7 +38,7 @@ async fn test_forward_http_request_invalid_uri() {
     use hyper::HeaderMap;
     use hyper::Method;
 
-    let pool = create_connection_pool();
+    let pool = create_connection_pool(&AppConfig::default().upstream);
     let headers = HeaderMap::new();
 
     // Test with invalid URI - should handle gracefully
@@ -47,6 +49,7 @@ async fn test_forward_http_request_invalid_uri() {
         Method::GET,
         &headers,
         Bytes::new(),
+        std::time::Duration::from_secs(5),
     )
     .await;
 
@@ -61,3 +64,1033 @@ async fn test_forward_http_request_invalid_uri() {
         }
     }
 }
+
+#[tokio::test]
+async fn test_read_quic_stream_reassembles_split_message() {
+    use dns_ingress::upstream::read_quic_stream;
+
+    let message = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x01";
+    let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(message);
+
+    // Deliver the length prefix plus part of the query, then the rest
+    let (first, second) = framed.split_at(7);
+    let mut stream = tokio_test::io::Builder::new()
+        .read(first)
+        .read(second)
+        .build();
+
+    let received = read_quic_stream(&mut stream, 65535).await.unwrap();
+    assert_eq!(received, message);
+}
+
+#[tokio::test]
+async fn test_read_quic_stream_rejects_truncated_message() {
+    use dns_ingress::upstream::read_quic_stream;
+
+    // Length prefix announces 17 bytes but the stream closes after 5
+    let mut stream = tokio_test::io::Builder::new()
+        .read(&[0x00, 0x11, 0x12, 0x34, 0x01, 0x00, 0x00])
+        .build();
+
+    assert!(read_quic_stream(&mut stream, 65535).await.is_err());
+}
+
+#[tokio::test]
+async fn test_read_quic_stream_empty_stream() {
+    use dns_ingress::upstream::read_quic_stream;
+
+    let mut stream = tokio_test::io::Builder::new().build();
+    let received = read_quic_stream(&mut stream, 65535).await.unwrap();
+    assert!(received.is_empty());
+}
+
+/// Accept TCP connections but never answer, simulating an unresponsive upstream
+async fn start_blackhole_upstream() -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((stream, _)) = listener.accept().await {
+            held.push(stream);
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_forward_http_request_times_out() {
+    init_crypto_provider();
+    use bytes::Bytes;
+    use dns_ingress::error::DnsProxyError;
+    use dns_ingress::upstream::http::gateway_timeout_response;
+    use hyper::{HeaderMap, Method, StatusCode};
+    use std::time::{Duration, Instant};
+
+    let addr = start_blackhole_upstream().await;
+    let pool = create_connection_pool(&AppConfig::default().upstream);
+    let timeout = Duration::from_millis(200);
+
+    let started = Instant::now();
+    let result = forward_http_request(
+        &pool,
+        &format!("https://{}/dns-query", addr),
+        "dns.example.com",
+        Method::POST,
+        &HeaderMap::new(),
+        Bytes::from_static(b"query"),
+        timeout,
+    )
+    .await;
+    let elapsed = started.elapsed();
+
+    let error = result.expect_err("blackhole upstream should time out");
+    assert!(
+        error
+            .downcast_ref::<DnsProxyError>()
+            .is_some_and(DnsProxyError::is_timeout)
+    );
+    assert!(elapsed >= timeout && elapsed < Duration::from_secs(2));
+
+    let response = gateway_timeout_response(&error).expect("timeout should map to 504");
+    assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+}
+
+#[tokio::test]
+async fn test_with_timeout_reports_upstream_timeout() {
+    use dns_ingress::error::{DnsProxyError, UpstreamError};
+    use dns_ingress::upstream::timeout::with_timeout;
+    use std::time::Duration;
+
+    let result: Result<(), _> = with_timeout(Duration::from_millis(50), "192.0.2.1:853", async {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        Ok(())
+    })
+    .await;
+
+    match result {
+        Err(DnsProxyError::Upstream(UpstreamError::Timeout {
+            upstream,
+            timeout_ms,
+        })) => {
+            assert_eq!(upstream, "192.0.2.1:853");
+            assert_eq!(timeout_ms, 50);
+        }
+        other => panic!("expected upstream timeout, got {:?}", other),
+    }
+}
+
+/// Start a plain HTTP upstream answering with `statuses` in order, then 200 OK
+async fn start_scripted_http_upstream(
+    statuses: Vec<u16>,
+) -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+    use http_body_util::Full;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&requests);
+    let statuses = Arc::new(statuses);
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let counter = Arc::clone(&counter);
+            let statuses = Arc::clone(&statuses);
+            tokio::spawn(async move {
+                let service = service_fn(move |_req| {
+                    let n = counter.fetch_add(1, Ordering::SeqCst);
+                    let status = statuses.get(n).copied().unwrap_or(200);
+                    async move {
+                        Response::builder()
+                            .status(StatusCode::from_u16(status).unwrap())
+                            .body(Full::new(bytes::Bytes::from_static(b"answer")))
+                    }
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    (addr, requests)
+}
+
+async fn forward_with_retries(
+    addr: std::net::SocketAddr,
+    metrics: &dns_ingress::metrics::Metrics,
+) -> anyhow::Result<(hyper::Response<http_body_util::Full<bytes::Bytes>>, u64)> {
+    use dns_ingress::upstream::http::is_transient_response;
+    use dns_ingress::upstream::retry::with_retries;
+    use hyper::{HeaderMap, Method};
+
+    let pool = create_connection_pool(&AppConfig::default().upstream);
+    let uri = format!("http://{}/dns-query", addr);
+    let headers = HeaderMap::new();
+    with_retries(2, &uri, metrics, is_transient_response, || {
+        forward_http_request(
+            &pool,
+            &uri,
+            "127.0.0.1",
+            Method::POST,
+            &headers,
+            bytes::Bytes::from_static(b"query"),
+            std::time::Duration::from_secs(5),
+        )
+    })
+    .await
+}
+
+#[tokio::test]
+async fn test_forward_http_request_retries_transient_failure() {
+    init_crypto_provider();
+    let (addr, requests) = start_scripted_http_upstream(vec![503]).await;
+    let metrics = dns_ingress::metrics::Metrics::new();
+
+    let (response, _) = forward_with_retries(addr, &metrics).await.unwrap();
+
+    assert!(response.status().is_success());
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+    assert_eq!(metrics.upstream_retries(), 1);
+}
+
+#[tokio::test]
+async fn test_forward_http_request_does_not_retry_client_error() {
+    init_crypto_provider();
+    let (addr, requests) = start_scripted_http_upstream(vec![400]).await;
+    let metrics = dns_ingress::metrics::Metrics::new();
+
+    let (response, _) = forward_with_retries(addr, &metrics).await.unwrap();
+
+    assert_eq!(response.status(), hyper::StatusCode::BAD_REQUEST);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+    assert_eq!(metrics.upstream_retries(), 0);
+}
+
+/// Start a plain HTTP upstream that answers on `/dns-query`, redirects
+/// `/moved` there and `/loop` to itself
+async fn start_redirecting_http_upstream() -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+    use http_body_util::Full;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Response, StatusCode};
+    use hyper_util::rt::TokioIo;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&requests);
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let counter = Arc::clone(&counter);
+            tokio::spawn(async move {
+                let service = service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    let response = match req.uri().path() {
+                        "/moved" => Response::builder()
+                            .status(StatusCode::FOUND)
+                            .header("location", "/dns-query"),
+                        "/loop" => Response::builder()
+                            .status(StatusCode::FOUND)
+                            .header("location", format!("http://{}/loop", addr)),
+                        _ => Response::builder(),
+                    };
+                    async move { response.body(Full::new(bytes::Bytes::from_static(b"answer"))) }
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    (addr, requests)
+}
+
+/// Forward to `path` on `addr` through a pool following `max_redirects`
+async fn forward_following_redirects(
+    addr: std::net::SocketAddr,
+    path: &str,
+    max_redirects: u32,
+) -> hyper::Response<http_body_util::Full<bytes::Bytes>> {
+    let mut config = AppConfig::default().upstream;
+    config.max_redirects = max_redirects;
+    let pool = create_connection_pool(&config);
+    let (response, _) = forward_http_request(
+        &pool,
+        &format!("http://{}{}", addr, path),
+        "127.0.0.1",
+        hyper::Method::POST,
+        &hyper::HeaderMap::new(),
+        bytes::Bytes::from_static(b"query"),
+        std::time::Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_forward_follows_redirect_when_enabled() {
+    use http_body_util::BodyExt;
+
+    init_crypto_provider();
+    let (addr, requests) = start_redirecting_http_upstream().await;
+
+    let response = forward_following_redirects(addr, "/moved", 1).await;
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"answer");
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_forward_passes_redirect_through_by_default() {
+    init_crypto_provider();
+    let (addr, requests) = start_redirecting_http_upstream().await;
+
+    let response = forward_following_redirects(addr, "/moved", 0).await;
+    assert_eq!(response.status(), hyper::StatusCode::FOUND);
+    assert_eq!(response.headers()["location"], "/dns-query");
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_forward_stops_at_redirect_loop() {
+    init_crypto_provider();
+    let (addr, requests) = start_redirecting_http_upstream().await;
+
+    let response = forward_following_redirects(addr, "/loop", 5).await;
+    assert_eq!(response.status(), hyper::StatusCode::FOUND);
+    assert_eq!(requests.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_forward_http_request_streaming_follows_redirect() {
+    use dns_ingress::upstream::forward_http_request_streaming;
+    use http_body_util::BodyExt;
+
+    init_crypto_provider();
+    let (addr, requests) = start_redirecting_http_upstream().await;
+    let mut config = AppConfig::default().upstream;
+    config.max_redirects = 1;
+    let pool = create_connection_pool(&config);
+
+    let (response, size) = forward_http_request_streaming(
+        &pool,
+        &format!("http://{}/moved", addr),
+        "127.0.0.1",
+        hyper::Method::POST,
+        &hyper::HeaderMap::new(),
+        bytes::Bytes::from_static(b"query"),
+        std::time::Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+    assert_eq!(size, Some(6));
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"answer");
+    assert_eq!(requests.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn test_upstream_path_and_query() {
+    use dns_ingress::upstream::http::upstream_path_and_query;
+
+    // No upstream URL, or one without a path: keep the client's path
+    assert_eq!(
+        upstream_path_and_query("/dns-query?dns=AAAB", None),
+        "/dns-query?dns=AAAB"
+    );
+    assert_eq!(
+        upstream_path_and_query("/dns-query", Some("https://dns.example")),
+        "/dns-query"
+    );
+
+    // Upstream path replaces the client's, client params are kept
+    assert_eq!(
+        upstream_path_and_query("/dns-query?dns=AAAB", Some("https://dns.example/resolve")),
+        "/resolve?dns=AAAB"
+    );
+    assert_eq!(
+        upstream_path_and_query("/dns-query", Some("https://dns.example/custom/path?ct=1")),
+        "/custom/path?ct=1"
+    );
+    assert_eq!(
+        upstream_path_and_query("/dns-query?dns=AAAB", Some("https://dns.example/q?ct=1")),
+        "/q?ct=1&dns=AAAB"
+    );
+}
+
+#[tokio::test]
+async fn test_forward_uses_upstream_path() {
+    use dns_ingress::upstream::http::upstream_path_and_query;
+    use http_body_util::Full;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{HeaderMap, Method, Response};
+    use hyper_util::rt::TokioIo;
+
+    init_crypto_provider();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (path_tx, path_rx) = tokio::sync::oneshot::channel();
+    let path_tx = Arc::new(std::sync::Mutex::new(Some(path_tx)));
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let service = service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
+            if let Some(tx) = path_tx.lock().unwrap().take() {
+                let _ = tx.send(req.uri().to_string());
+            }
+            async { Response::builder().body(Full::new(bytes::Bytes::from_static(b"answer"))) }
+        });
+        let _ = http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    });
+
+    // Client asked for the standard path, upstream serves DoH on /resolve
+    let path = upstream_path_and_query(
+        "/dns-query?dns=q80BAAABAAAAAAAA",
+        Some("https://dns.example/resolve"),
+    );
+    let pool = create_connection_pool(&AppConfig::default().upstream);
+    let (response, _) = forward_http_request(
+        &pool,
+        &format!("http://{}{}", addr, path),
+        "127.0.0.1",
+        Method::GET,
+        &HeaderMap::new(),
+        bytes::Bytes::new(),
+        std::time::Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+    assert_eq!(path_rx.await.unwrap(), "/resolve?dns=q80BAAABAAAAAAAA");
+}
+
+/// Start an HTTP upstream that answers with a body of `chunks` chunks of
+/// `chunk_len` bytes, holding the last one back until `release` fires
+async fn start_streaming_http_upstream(
+    chunks: usize,
+    chunk_len: usize,
+    release: tokio::sync::oneshot::Receiver<()>,
+) -> std::net::SocketAddr {
+    use http_body_util::StreamBody;
+    use hyper::Response;
+    use hyper::body::Frame;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let release = Arc::new(std::sync::Mutex::new(Some(release)));
+
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let service = service_fn(move |_req| {
+            let release = release.lock().unwrap().take().unwrap();
+            let (tx, rx) =
+                tokio::sync::mpsc::channel::<Result<Frame<bytes::Bytes>, std::io::Error>>(chunks);
+            tokio::spawn(async move {
+                for i in 0..chunks {
+                    if i == chunks - 1 {
+                        let _ = release.await;
+                        let chunk = bytes::Bytes::from(vec![b'x'; chunk_len]);
+                        let _ = tx.send(Ok(Frame::data(chunk))).await;
+                        break;
+                    }
+                    let chunk = bytes::Bytes::from(vec![b'x'; chunk_len]);
+                    let _ = tx.send(Ok(Frame::data(chunk))).await;
+                }
+            });
+            let frames = futures::stream::unfold(rx, |mut rx| async move {
+                rx.recv().await.map(|frame| (frame, rx))
+            });
+            let body = StreamBody::new(frames);
+            async move { Response::builder().body(body) }
+        });
+        let _ = http1::Builder::new()
+            .serve_connection(TokioIo::new(stream), service)
+            .await;
+    });
+
+    addr
+}
+
+#[tokio::test]
+async fn test_forward_http_request_streaming_streams_large_body() {
+    use dns_ingress::upstream::{STREAMING_THRESHOLD, forward_http_request_streaming};
+    use http_body_util::BodyExt;
+
+    init_crypto_provider();
+    let chunk_len = 32 * 1024;
+    let chunks = STREAMING_THRESHOLD / chunk_len + 3;
+    let (release, released) = tokio::sync::oneshot::channel();
+    let addr = start_streaming_http_upstream(chunks, chunk_len, released).await;
+    let pool = create_connection_pool(&AppConfig::default().upstream);
+
+    // The upstream has not sent the whole body yet, so a buffering forward
+    // could not return before the timeout
+    let (response, size) = forward_http_request_streaming(
+        &pool,
+        &format!("http://{}/large", addr),
+        "127.0.0.1",
+        hyper::Method::GET,
+        &hyper::HeaderMap::new(),
+        bytes::Bytes::new(),
+        std::time::Duration::from_secs(2),
+    )
+    .await
+    .unwrap();
+    assert!(response.status().is_success());
+    assert_eq!(size, None, "a streamed body has no size up front");
+
+    release.send(()).unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(body.len(), chunks * chunk_len);
+}
+
+#[tokio::test]
+async fn test_forward_http_request_streaming_buffers_small_body() {
+    use dns_ingress::upstream::forward_http_request_streaming;
+    use http_body_util::BodyExt;
+
+    init_crypto_provider();
+    let (addr, _) = start_scripted_http_upstream(Vec::new()).await;
+    let pool = create_connection_pool(&AppConfig::default().upstream);
+
+    let (response, size) = forward_http_request_streaming(
+        &pool,
+        &format!("http://{}/dns-query", addr),
+        "127.0.0.1",
+        hyper::Method::POST,
+        &hyper::HeaderMap::new(),
+        bytes::Bytes::from_static(b"query"),
+        std::time::Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(size, Some(6));
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(&body[..], b"answer");
+}
+
+/// Start an HTTP upstream that answers after `delay` and tracks the peak
+/// number of requests it was serving at once
+async fn start_slow_http_upstream(
+    delay: std::time::Duration,
+) -> (std::net::SocketAddr, Arc<AtomicUsize>) {
+    use http_body_util::Full;
+    use hyper::Response;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let peak = Arc::new(AtomicUsize::new(0));
+    let peak_out = Arc::clone(&peak);
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let in_flight = Arc::clone(&in_flight);
+            let peak = Arc::clone(&peak);
+            tokio::spawn(async move {
+                let service = service_fn(move |_req| {
+                    let in_flight = Arc::clone(&in_flight);
+                    let peak = Arc::clone(&peak);
+                    async move {
+                        let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(delay).await;
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                        Response::builder().body(Full::new(bytes::Bytes::from_static(b"answer")))
+                    }
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    (addr, peak_out)
+}
+
+async fn forward_to(
+    pool: &ConnectionPool,
+    addr: std::net::SocketAddr,
+    timeout: std::time::Duration,
+) -> anyhow::Result<(hyper::Response<http_body_util::Full<bytes::Bytes>>, u64)> {
+    forward_http_request(
+        pool,
+        &format!("http://{}/dns-query", addr),
+        "127.0.0.1",
+        hyper::Method::POST,
+        &hyper::HeaderMap::new(),
+        bytes::Bytes::from_static(b"query"),
+        timeout,
+    )
+    .await
+}
+
+#[tokio::test]
+async fn test_max_conns_per_host_queues_excess_requests() {
+    init_crypto_provider();
+    let delay = std::time::Duration::from_millis(200);
+    let (addr, peak) = start_slow_http_upstream(delay).await;
+    let pool = ConnectionPool::new().with_max_conns_per_host(2);
+
+    let timeout = std::time::Duration::from_secs(5);
+    let results = futures::future::join_all((0..5).map(|_| forward_to(&pool, addr, timeout))).await;
+
+    for result in results {
+        assert_eq!(result.unwrap().0.status(), hyper::StatusCode::OK);
+    }
+    assert_eq!(peak.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn test_max_conns_per_host_sheds_after_timeout() {
+    use dns_ingress::error::{DnsProxyError, UpstreamError};
+
+    init_crypto_provider();
+    let (addr, peak) = start_slow_http_upstream(std::time::Duration::from_millis(500)).await;
+    let pool = ConnectionPool::new().with_max_conns_per_host(1);
+
+    // The second request can't get a slot before its timeout expires
+    let timeout = std::time::Duration::from_millis(250);
+    let (first, second) = tokio::join!(
+        forward_to(&pool, addr, std::time::Duration::from_secs(5)),
+        async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            forward_to(&pool, addr, timeout).await
+        }
+    );
+
+    assert_eq!(first.unwrap().0.status(), hyper::StatusCode::OK);
+    let err = second.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<DnsProxyError>(),
+        Some(DnsProxyError::Upstream(UpstreamError::Timeout { .. }))
+    ));
+    assert_eq!(peak.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_udp_upstream_retries_truncated_response_over_tcp() {
+    use dns_ingress::upstream::udp::forward_udp_dns;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // UDP and TCP listeners on the same port, as a real DNS server would have
+    let tcp = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = tcp.local_addr().unwrap();
+    let udp = tokio::net::UdpSocket::bind(addr).await.unwrap();
+
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 512];
+        let (len, peer) = udp.recv_from(&mut buf).await.unwrap();
+        let mut truncated = buf[..len].to_vec();
+        truncated[2] |= 0x80 | 0x02; // QR + TC
+        udp.send_to(&truncated, peer).await.unwrap();
+    });
+    tokio::spawn(async move {
+        let (mut stream, _) = tcp.accept().await.unwrap();
+        let len = stream.read_u16().await.unwrap() as usize;
+        let mut query = vec![0u8; len];
+        stream.read_exact(&mut query).await.unwrap();
+        query[2] |= 0x80;
+        query.extend_from_slice(&[0xAA; 700]);
+        stream.write_u16(query.len() as u16).await.unwrap();
+        stream.write_all(&query).await.unwrap();
+    });
+
+    let query = [
+        0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 1,
+    ];
+    let response = forward_udp_dns(addr, &query).await.unwrap();
+
+    assert_eq!(&response[..2], &query[..2]);
+    assert_eq!(
+        response[2] & 0x02,
+        0,
+        "full TCP answer should not be truncated"
+    );
+    assert_eq!(response.len(), query.len() + 700);
+}
+
+/// Start a DoT upstream with a fresh self-signed certificate for 127.0.0.1
+/// that answers each framed query by echoing it back with QR set. Returns its
+/// address and the certificate in PEM format.
+async fn start_self_signed_dot_upstream() -> (std::net::SocketAddr, String) {
+    use dns_ingress::dns::framing::{read_framed, write_framed};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        certified.signing_key.serialize_der(),
+    ));
+    let server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(mut tls) = acceptor.accept(stream).await else {
+                    return;
+                };
+                while let Ok(Some(mut message)) = read_framed(&mut tls).await {
+                    message[2] |= 0x80;
+                    if write_framed(&mut tls, &message).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+    (addr, certified.cert.pem())
+}
+
+#[tokio::test]
+async fn test_dot_upstream_certificate_verification() {
+    use dns_ingress::upstream::tls::forward_dot_dns;
+    use std::io::Write;
+
+    init_crypto_provider();
+    let (addr, ca_pem) = start_self_signed_dot_upstream().await;
+    let query = [0x12, 0x34, 0x01, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut upstream = AppConfig::default().upstream;
+
+    // A certificate no trusted CA issued is rejected
+    let result = forward_dot_dns(addr, "127.0.0.1", &query, &upstream).await;
+    let err = result.unwrap_err().to_string();
+    assert!(
+        err.contains("Failed to establish TLS connection"),
+        "{}",
+        err
+    );
+
+    // Pinning the upstream's CA makes it verify
+    let mut ca_file = tempfile::NamedTempFile::new().unwrap();
+    ca_file.write_all(ca_pem.as_bytes()).unwrap();
+    upstream.upstream_ca_file = Some(ca_file.path().to_string_lossy().into_owned());
+    let response = forward_dot_dns(addr, "127.0.0.1", &query, &upstream)
+        .await
+        .unwrap();
+    assert_eq!(&response[..2], &[0x12, 0x34]);
+
+    // The verified name must still match the certificate
+    assert!(
+        forward_dot_dns(addr, "localhost", &query, &upstream)
+            .await
+            .is_err()
+    );
+
+    // Verification can only be skipped explicitly
+    upstream.upstream_ca_file = None;
+    upstream.danger_accept_invalid_certs = true;
+    let response = forward_dot_dns(addr, "127.0.0.1", &query, &upstream)
+        .await
+        .unwrap();
+    assert_eq!(&response[..2], &[0x12, 0x34]);
+}
+
+#[tokio::test]
+async fn test_pool_loads_upstream_tls_config_once() {
+    use std::io::Write;
+
+    init_crypto_provider();
+    let (addr, ca_pem) = start_self_signed_dot_upstream().await;
+    let query = [0x12, 0x34, 0x01, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut ca_file = tempfile::NamedTempFile::new().unwrap();
+    ca_file.write_all(ca_pem.as_bytes()).unwrap();
+    let mut upstream = AppConfig::default().upstream;
+    upstream.upstream_ca_file = Some(ca_file.path().to_string_lossy().into_owned());
+    let pool = create_connection_pool(&upstream);
+
+    // DoT and DoQ share the config loaded when the pool was created
+    let dot = pool.dot().tls_config(&upstream).unwrap();
+    let quic = pool.quic().tls_config(&upstream).unwrap();
+    assert!(Arc::ptr_eq(&dot, &quic));
+
+    // New connections don't read the CA file again
+    drop(ca_file);
+    let response = pool
+        .dot()
+        .forward(addr, "127.0.0.1", &query, &upstream)
+        .await
+        .unwrap();
+    assert_eq!(&response[..2], &[0x12, 0x34]);
+}
+
+/// Self-signed certificate for 127.0.0.1 trusted by the HTTP connection pool
+/// of this test binary. It is trusted once via SSL_CERT_FILE so parallel
+/// tests agree.
+fn trusted_upstream_cert() -> &'static (rcgen::CertifiedKey<rcgen::KeyPair>, tempfile::NamedTempFile)
+{
+    use std::io::Write;
+    use std::sync::OnceLock;
+
+    static CERT: OnceLock<(rcgen::CertifiedKey<rcgen::KeyPair>, tempfile::NamedTempFile)> =
+        OnceLock::new();
+    CERT.get_or_init(|| {
+        let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        let mut ca_file = tempfile::NamedTempFile::new().unwrap();
+        ca_file.write_all(certified.cert.pem().as_bytes()).unwrap();
+        ca_file.flush().unwrap();
+        // SAFETY: set exactly once, before any connection pool reads it
+        unsafe { std::env::set_var("SSL_CERT_FILE", ca_file.path()) };
+        (certified, ca_file)
+    })
+}
+
+/// What an upstream saw of a forwarded request
+#[derive(Debug)]
+struct SeenRequest {
+    version: hyper::Version,
+    authority: Option<String>,
+    headers: hyper::HeaderMap,
+}
+
+/// Start an HTTP/1.1 and HTTP/2 upstream, over TLS (offering `h2` over ALPN)
+/// when `tls` is set, that answers every request and reports what it saw
+async fn start_recording_http_upstream(
+    tls: bool,
+) -> (
+    std::net::SocketAddr,
+    tokio::sync::mpsc::UnboundedReceiver<SeenRequest>,
+) {
+    use http_body_util::Full;
+    use hyper::service::service_fn;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+    let (certified, _) = trusted_upstream_cert();
+    let cert = CertificateDer::from(certified.cert.der().to_vec());
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(
+        certified.signing_key.serialize_der(),
+    ));
+    let mut server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (seen_tx, seen_rx) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            let seen_tx = seen_tx.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
+                    let _ = seen_tx.send(SeenRequest {
+                        version: req.version(),
+                        authority: req.uri().authority().map(|a| a.to_string()),
+                        headers: req.headers().clone(),
+                    });
+                    // Reflect credentials back, as a careless upstream might
+                    let mut response = hyper::Response::builder();
+                    if let Some(authorization) = req.headers().get("authorization") {
+                        response = response.header("authorization", authorization);
+                    }
+                    async { response.body(Full::new(bytes::Bytes::from_static(b"answer"))) }
+                });
+                let builder = auto::Builder::new(TokioExecutor::new());
+                if tls {
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let _ = builder
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                } else {
+                    let _ = builder
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                }
+            });
+        }
+    });
+    (addr, seen_rx)
+}
+
+/// Client headers carrying its own Host and hop-by-hop headers
+fn client_headers() -> hyper::HeaderMap {
+    let mut headers = hyper::HeaderMap::new();
+    headers.insert("host", "www.example.com".parse().unwrap());
+    headers.insert("accept", "application/dns-message".parse().unwrap());
+    headers.insert("connection", "keep-alive, x-hop".parse().unwrap());
+    headers.insert("keep-alive", "timeout=5".parse().unwrap());
+    headers.insert("x-hop", "1".parse().unwrap());
+    headers.insert("upgrade", "websocket".parse().unwrap());
+    headers
+}
+
+/// Forward a request through a fresh pool to the upstream at `uri`
+async fn forward_with_client_headers(uri: &str) {
+    let pool = create_connection_pool(&AppConfig::default().upstream);
+    let (response, _) = forward_http_request(
+        &pool,
+        uri,
+        "127.0.0.1",
+        hyper::Method::POST,
+        &client_headers(),
+        bytes::Bytes::from_static(b"query"),
+        std::time::Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+}
+
+fn assert_end_to_end_headers_only(headers: &hyper::HeaderMap) {
+    assert_eq!(headers["accept"], "application/dns-message");
+    for hop_by_hop in ["connection", "keep-alive", "x-hop", "upgrade"] {
+        assert!(!headers.contains_key(hop_by_hop), "{}", hop_by_hop);
+    }
+}
+
+#[tokio::test]
+async fn test_forward_http2_carries_target_authority() {
+    init_crypto_provider();
+    let (addr, mut seen) = start_recording_http_upstream(true).await;
+    forward_with_client_headers(&format!("https://{}/dns-query", addr)).await;
+
+    let request = seen.recv().await.unwrap();
+    assert_eq!(request.version, hyper::Version::HTTP_2);
+    assert_eq!(request.authority.as_deref(), Some(&*addr.to_string()));
+    // The client's Host must not contradict :authority
+    assert!(!request.headers.contains_key("host"));
+    assert_end_to_end_headers_only(&request.headers);
+}
+
+#[tokio::test]
+async fn test_forward_http1_sets_host_from_target() {
+    init_crypto_provider();
+    let (addr, mut seen) = start_recording_http_upstream(false).await;
+    forward_with_client_headers(&format!("http://{}/dns-query", addr)).await;
+
+    let request = seen.recv().await.unwrap();
+    assert_eq!(request.version, hyper::Version::HTTP_11);
+    assert_eq!(request.headers["host"], addr.to_string());
+    assert_end_to_end_headers_only(&request.headers);
+}
+
+#[tokio::test]
+async fn test_forward_adds_extra_headers_without_echoing_them() {
+    init_crypto_provider();
+    let (addr, mut seen) = start_recording_http_upstream(false).await;
+    // SAFETY: no other test reads or writes this variable
+    unsafe { std::env::set_var("DNS_PROXY_TEST_UPSTREAM_TOKEN", "s3cret") };
+    let mut config = AppConfig::default().upstream;
+    config.extra_headers.insert(
+        "Authorization".to_string(),
+        "Bearer ${DNS_PROXY_TEST_UPSTREAM_TOKEN}".to_string(),
+    );
+    config
+        .extra_headers
+        .insert("x-resolver-tenant".to_string(), "edge".to_string());
+    let pool = create_connection_pool(&config);
+    let mut client = client_headers();
+    client.insert("authorization", "Bearer client".parse().unwrap());
+
+    let (response, _) = forward_http_request(
+        &pool,
+        &format!("http://{}/dns-query", addr),
+        "127.0.0.1",
+        hyper::Method::POST,
+        &client,
+        bytes::Bytes::from_static(b"query"),
+        std::time::Duration::from_secs(5),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.status(), hyper::StatusCode::OK);
+    assert!(!response.headers().contains_key("authorization"));
+
+    // Configured headers replace the client's, after the hop-by-hop strip
+    let headers = seen.recv().await.unwrap().headers;
+    assert_end_to_end_headers_only(&headers);
+    assert_eq!(headers["authorization"], "Bearer s3cret");
+    assert_eq!(headers["x-resolver-tenant"], "edge");
+}
+
+#[test]
+fn test_end_to_end_headers_strips_connection_options() {
+    use dns_ingress::upstream::http::end_to_end_headers;
+
+    let headers = end_to_end_headers(&client_headers());
+    assert!(!headers.contains_key("host"));
+    assert_end_to_end_headers_only(&headers);
+    assert_eq!(headers.len(), 1);
+}
+
+#[tokio::test]
+async fn test_forward_drops_proxy_headers_unless_allowed() {
+    use dns_ingress::proxy::http::forwarded_headers;
+
+    init_crypto_provider();
+    let (addr, mut seen) = start_recording_http_upstream(false).await;
+    let pool = create_connection_pool(&AppConfig::default().upstream);
+    let mut client = client_headers();
+    client.insert("proxy-connection", "keep-alive".parse().unwrap());
+    client.insert("transfer-encoding", "chunked".parse().unwrap());
+    client.insert("forwarded", "for=192.0.2.7".parse().unwrap());
+    client.insert("x-forwarded-for", "192.0.2.7".parse().unwrap());
+    client.insert("x-forwarded-proto", "https".parse().unwrap());
+
+    let mut upstream = AppConfig::default().upstream;
+    for allowed in [false, true] {
+        upstream.forward_proxy_headers = allowed;
+        let (response, _) = forward_http_request(
+            &pool,
+            &format!("http://{}/dns-query", addr),
+            "127.0.0.1",
+            hyper::Method::POST,
+            &forwarded_headers(&client, &upstream),
+            bytes::Bytes::from_static(b"query"),
+            std::time::Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+
+        let headers = seen.recv().await.unwrap().headers;
+        assert_end_to_end_headers_only(&headers);
+        assert_eq!(headers["content-type"], "application/dns-message");
+        assert!(!headers.contains_key("proxy-connection"));
+        assert!(!headers.contains_key("transfer-encoding"));
+        for proxy_header in ["forwarded", "x-forwarded-for", "x-forwarded-proto"] {
+            assert_eq!(
+                headers.contains_key(proxy_header),
+                allowed,
+                "{}",
+                proxy_header
+            );
+        }
+    }
+}
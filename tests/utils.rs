@@ -1,4 +1,6 @@
-use dns_ingress::utils::backoff::{BackoffCounter, exponential_backoff};
+use dns_ingress::utils::backoff::{
+    BackoffCounter, exponential_backoff, exponential_backoff_with_jitter,
+};
 use std::time::Duration;
 
 #[test]
@@ -44,6 +46,40 @@ fn test_exponential_backoff_cap_at_10() {
     assert!(delay <= Duration::from_millis(100000));
 }
 
+#[test]
+fn test_exponential_backoff_without_jitter() {
+    for attempt in 0..4 {
+        assert_eq!(
+            exponential_backoff_with_jitter(attempt, 100, 10000, 0.0),
+            exponential_backoff(attempt, 100, 10000)
+        );
+    }
+}
+
+#[test]
+fn test_exponential_backoff_jitter_bounds() {
+    for _ in 0..100 {
+        let delay = exponential_backoff_with_jitter(3, 100, 10000, 0.5);
+        assert!(delay >= Duration::from_millis(400), "{:?}", delay);
+        assert!(delay <= Duration::from_millis(800), "{:?}", delay);
+
+        // Out-of-range jitter is clamped to 1.0
+        let delay = exponential_backoff_with_jitter(3, 100, 10000, 5.0);
+        assert!(delay <= Duration::from_millis(800), "{:?}", delay);
+    }
+}
+
+#[test]
+fn test_backoff_counter_jitter_sequence() {
+    let counter = BackoffCounter::new();
+    for attempt in 0..3 {
+        let max = 100u64 << attempt;
+        let delay = counter.next_delay_with_jitter(100, 10000, 0.5);
+        assert!(delay >= Duration::from_millis(max / 2));
+        assert!(delay <= Duration::from_millis(max));
+    }
+}
+
 #[test]
 fn test_backoff_counter_new() {
     let counter = BackoffCounter::new();
@@ -140,3 +176,13 @@ fn test_backoff_counter_concurrent() {
     // The exact value depends on timing, but should be reasonable
     assert!(delay <= Duration::from_millis(10000));
 }
+
+#[test]
+fn test_backoff_reexported_from_utils() {
+    let counter = dns_ingress::utils::BackoffCounter::new();
+    assert_eq!(counter.next_delay(100, 10000), Duration::from_millis(100));
+    assert_eq!(
+        dns_ingress::utils::exponential_backoff(2, 100, 10000),
+        exponential_backoff(2, 100, 10000)
+    );
+}